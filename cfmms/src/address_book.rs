@@ -0,0 +1,413 @@
+//! Contract addresses of each chain the bot runs on
+//!
+//! An [AddressBook] holds the routers, factories, WETH, multicall3, Permit2 and flashloan providers
+//! of one chain. The books of mainnet and goerli are built in from `assets/address_book.json`,
+//! other chains or forks load a registry of the same shape with [load_registry]. A wrong address
+//! fails quietly much later, [AddressBook::validate] checks at startup that every contract has
+//! code, matching its pinned code hash if it has one. `qilin address-book pin` fills the hashes in
+//! from a node with [AddressBook::pin_code_hashes], contracts left unpinned are only checked for
+//! having code.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use ethers::{
+    providers::Middleware,
+    types::{Address, H256},
+    utils::keccak256,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use log::warn;
+
+const BUILTIN_REGISTRY: &str = include_str!("assets/address_book.json");
+
+#[derive(Error, Debug)]
+pub enum AddressBookError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    SerdeJson(#[from] serde_json::Error),
+    #[error("No address book for {0}")]
+    UnknownChain(String),
+    #[error("Address book of chain {expected} used on chain {actual}")]
+    ChainMismatch { expected: u64, actual: u64 },
+    #[error("No code at {address:?} ({name})")]
+    NoCode { name: String, address: Address },
+    #[error("Code hash of {name} at {address:?} is {actual:?}, expected {expected:?}")]
+    CodeHashMismatch {
+        name: String,
+        address: Address,
+        expected: H256,
+        actual: H256,
+    },
+    #[error("Provider error: {0}")]
+    Provider(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contract {
+    pub address: Address,
+    /// `keccak256` of the runtime code, see [AddressBook::validate]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code_hash: Option<H256>,
+    /// block the contract was deployed in, e.g. where syncing a factory's pools starts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deployed_at: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Factories {
+    pub uniswap_v2: Contract,
+    pub uniswap_v3: Contract,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sushi: Option<Contract>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Routers {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uniswap_universal: Option<Contract>,
+    /// `SwapRouter`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uniswap_v3: Option<Contract>,
+    /// `SwapRouter02`, which routes V2 trades as well
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uniswap_v3_02: Option<Contract>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uniswap_v2_01: Option<Contract>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uniswap_v2_02: Option<Contract>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sushi: Option<Contract>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub one_inch_v4: Option<Contract>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quoters {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uniswap_v3: Option<Contract>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uniswap_v3_v2: Option<Contract>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Flashloans {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balancer_vault: Option<Contract>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aave_v3_pool: Option<Contract>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddressBook {
+    pub chain_id: u64,
+    pub weth: Contract,
+    pub multicall3: Contract,
+    pub permit2: Contract,
+    pub factories: Factories,
+    #[serde(default)]
+    pub routers: Routers,
+    #[serde(default)]
+    pub quoters: Quoters,
+    #[serde(default)]
+    pub flashloans: Flashloans,
+    /// tokens by lowercase symbol
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tokens: BTreeMap<String, Contract>,
+    /// well known pools, e.g. for generating bindings
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pools: BTreeMap<String, Contract>,
+}
+
+/// Address books by network name
+pub type Registry = BTreeMap<String, AddressBook>;
+
+/// The registry built into the binary
+pub fn builtin_registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        serde_json::from_str(BUILTIN_REGISTRY).expect("built in address book is valid")
+    })
+}
+
+/// Built in book of `network` without copying it, e.g. for a single address on a hot path
+pub fn builtin_book(network: &str) -> Option<&'static AddressBook> {
+    builtin_registry().get(network)
+}
+
+/// Where the built in registry lives in the source tree
+pub fn builtin_registry_path() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("src/assets/address_book.json")
+}
+
+pub fn load_registry(path: impl AsRef<Path>) -> Result<Registry, AddressBookError> {
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+pub fn save_registry(registry: &Registry, path: impl AsRef<Path>) -> Result<(), AddressBookError> {
+    std::fs::write(path, serde_json::to_string_pretty(registry)? + "\n")?;
+    Ok(())
+}
+
+impl AddressBook {
+    pub fn mainnet() -> Self {
+        Self::builtin("mainnet").expect("mainnet is built in")
+    }
+
+    /// Built in book of `network`, e.g. `mainnet` or `goerli`
+    pub fn builtin(network: &str) -> Result<Self, AddressBookError> {
+        builtin_book(network)
+            .cloned()
+            .ok_or_else(|| AddressBookError::UnknownChain(network.to_string()))
+    }
+
+    /// [AddressBook::for_chain_id], mainnet's book for chains without one, e.g. a local fork
+    pub fn for_chain_id_or_mainnet(chain_id: u64) -> Self {
+        Self::for_chain_id(chain_id).unwrap_or_else(|e| {
+            warn!("{}, using mainnet's addresses", e);
+            Self::mainnet()
+        })
+    }
+
+    pub fn for_chain_id(chain_id: u64) -> Result<Self, AddressBookError> {
+        builtin_registry()
+            .values()
+            .find(|book| book.chain_id == chain_id)
+            .cloned()
+            .ok_or_else(|| AddressBookError::UnknownChain(chain_id.to_string()))
+    }
+
+    /// Every contract of the book by path, e.g. `factories.uniswap_v2` or `tokens.usdc`
+    pub fn contracts(&self) -> Vec<(String, Contract)> {
+        let optional = [
+            ("factories.sushi", self.factories.sushi),
+            ("routers.uniswap_universal", self.routers.uniswap_universal),
+            ("routers.uniswap_v3", self.routers.uniswap_v3),
+            ("routers.uniswap_v3_02", self.routers.uniswap_v3_02),
+            ("routers.uniswap_v2_01", self.routers.uniswap_v2_01),
+            ("routers.uniswap_v2_02", self.routers.uniswap_v2_02),
+            ("routers.sushi", self.routers.sushi),
+            ("routers.one_inch_v4", self.routers.one_inch_v4),
+            ("quoters.uniswap_v3", self.quoters.uniswap_v3),
+            ("quoters.uniswap_v3_v2", self.quoters.uniswap_v3_v2),
+            ("flashloans.balancer_vault", self.flashloans.balancer_vault),
+            ("flashloans.aave_v3_pool", self.flashloans.aave_v3_pool),
+        ];
+        let mut contracts: Vec<(String, Contract)> = [
+            ("weth", self.weth),
+            ("multicall3", self.multicall3),
+            ("permit2", self.permit2),
+            ("factories.uniswap_v2", self.factories.uniswap_v2),
+            ("factories.uniswap_v3", self.factories.uniswap_v3),
+        ]
+        .into_iter()
+        .chain(
+            optional
+                .into_iter()
+                .filter_map(|(name, contract)| Some((name, contract?))),
+        )
+        .map(|(name, contract)| (name.to_string(), contract))
+        .collect();
+        for (kind, contracts_of_kind) in [("tokens", &self.tokens), ("pools", &self.pools)] {
+            contracts.extend(
+                contracts_of_kind
+                    .iter()
+                    .map(|(name, contract)| (format!("{}.{}", kind, name), *contract)),
+            );
+        }
+        contracts
+    }
+
+    /// Check the book was made for the chain behind `provider` and every contract is deployed,
+    /// with the pinned code hash if any
+    pub async fn validate<M: Middleware>(&self, provider: &M) -> Result<(), AddressBookError> {
+        self.check_chain(provider).await?;
+        let contracts = self.contracts();
+        let unpinned = contracts
+            .iter()
+            .filter(|(_, contract)| contract.code_hash.is_none())
+            .count();
+        if unpinned > 0 {
+            warn!(
+                "{} of {} contracts of chain {} have no pinned code hash, only checking they have \
+                 code, see `qilin address-book pin`",
+                unpinned,
+                contracts.len(),
+                self.chain_id
+            );
+        }
+        for (name, contract) in contracts {
+            let actual = code_hash(provider, contract.address)
+                .await?
+                .ok_or_else(|| AddressBookError::NoCode {
+                    name: name.clone(),
+                    address: contract.address,
+                })?;
+            match contract.code_hash {
+                Some(expected) if expected != actual => {
+                    return Err(AddressBookError::CodeHashMismatch {
+                        name,
+                        address: contract.address,
+                        expected,
+                        actual,
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Pin the code hash of every contract to the code deployed on the chain behind `provider`,
+    /// failing on contracts without code
+    pub async fn pin_code_hashes<M: Middleware>(
+        &mut self,
+        provider: &M,
+    ) -> Result<(), AddressBookError> {
+        self.check_chain(provider).await?;
+        let mut hashes = BTreeMap::new();
+        for (name, contract) in self.contracts() {
+            let hash =
+                code_hash(provider, contract.address)
+                    .await?
+                    .ok_or(AddressBookError::NoCode {
+                        name,
+                        address: contract.address,
+                    })?;
+            hashes.insert(contract.address, hash);
+        }
+        for contract in self.contracts_mut() {
+            contract.code_hash = hashes.get(&contract.address).copied();
+        }
+        Ok(())
+    }
+
+    fn contracts_mut(&mut self) -> impl Iterator<Item = &mut Contract> {
+        [
+            &mut self.weth,
+            &mut self.multicall3,
+            &mut self.permit2,
+            &mut self.factories.uniswap_v2,
+            &mut self.factories.uniswap_v3,
+        ]
+        .into_iter()
+        .chain(
+            [
+                &mut self.factories.sushi,
+                &mut self.routers.uniswap_universal,
+                &mut self.routers.uniswap_v3,
+                &mut self.routers.uniswap_v3_02,
+                &mut self.routers.uniswap_v2_01,
+                &mut self.routers.uniswap_v2_02,
+                &mut self.routers.sushi,
+                &mut self.routers.one_inch_v4,
+                &mut self.quoters.uniswap_v3,
+                &mut self.quoters.uniswap_v3_v2,
+                &mut self.flashloans.balancer_vault,
+                &mut self.flashloans.aave_v3_pool,
+            ]
+            .into_iter()
+            .filter_map(Option::as_mut),
+        )
+        .chain(self.tokens.values_mut())
+        .chain(self.pools.values_mut())
+    }
+
+    async fn check_chain<M: Middleware>(&self, provider: &M) -> Result<(), AddressBookError> {
+        let chain_id = provider
+            .get_chainid()
+            .await
+            .map_err(|e| AddressBookError::Provider(e.to_string()))?
+            .as_u64();
+        if chain_id != self.chain_id {
+            return Err(AddressBookError::ChainMismatch {
+                expected: self.chain_id,
+                actual: chain_id,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// `keccak256` of the code at `address`, `None` without code
+async fn code_hash<M: Middleware>(
+    provider: &M,
+    address: Address,
+) -> Result<Option<H256>, AddressBookError> {
+    let code = provider
+        .get_code(address, None)
+        .await
+        .map_err(|e| AddressBookError::Provider(e.to_string()))?;
+    Ok((!code.is_empty()).then(|| H256::from(keccak256(&code))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::Provider;
+    use ethers::types::{Bytes, U256};
+
+    #[tokio::test]
+    async fn test_address_book() {
+        let mainnet = AddressBook::mainnet();
+        assert_eq!(mainnet.chain_id, 1);
+        assert_eq!(
+            mainnet.weth.address,
+            "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+                .parse::<Address>()
+                .unwrap()
+        );
+        assert_eq!(mainnet.factories.uniswap_v3.deployed_at, Some(12369621));
+        assert_eq!(AddressBook::for_chain_id(5).unwrap().chain_id, 5);
+        assert!(matches!(
+            AddressBook::builtin("sepolia"),
+            Err(AddressBookError::UnknownChain(_))
+        ));
+        let names: Vec<String> = mainnet.contracts().into_iter().map(|(n, _)| n).collect();
+        assert!(names.contains(&"tokens.usdc".to_string()));
+        assert!(names.contains(&"flashloans.balancer_vault".to_string()));
+
+        // a pinned hash that doesn't match the deployed code is refused
+        let code = Bytes::from(vec![0x60, 0x00]);
+        let mut book = AddressBook::for_chain_id(5).unwrap();
+        book.tokens.clear();
+        book.pools.clear();
+        book.weth.code_hash = Some(H256::zero());
+        let (provider, mock) = Provider::mocked();
+        // responses are popped last in first out
+        for _ in 0..book.contracts().len() {
+            mock.push::<Bytes, _>(code.clone()).unwrap();
+        }
+        mock.push(U256::from(5)).unwrap();
+        assert!(matches!(
+            book.validate(&provider).await,
+            Err(AddressBookError::CodeHashMismatch { name, .. }) if name == "weth"
+        ));
+
+        // pinned to the deployed code, the book validates
+        let contracts = book.contracts().len();
+        let push_chain = |mock: &ethers::providers::MockProvider| {
+            for _ in 0..contracts {
+                mock.push::<Bytes, _>(code.clone()).unwrap();
+            }
+            mock.push(U256::from(5)).unwrap();
+        };
+        push_chain(&mock);
+        book.pin_code_hashes(&provider).await.unwrap();
+        let deployed = H256::from(keccak256(&code));
+        assert!(book
+            .contracts()
+            .iter()
+            .all(|(_, contract)| contract.code_hash == Some(deployed)));
+        push_chain(&mock);
+        book.validate(&provider).await.unwrap();
+
+        // a local fork trades against mainnet's contracts
+        assert_eq!(AddressBook::for_chain_id_or_mainnet(31337).chain_id, 1);
+        assert_eq!(builtin_book("mainnet").unwrap().weth, mainnet.weth);
+    }
+}
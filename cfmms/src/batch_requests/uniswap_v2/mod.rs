@@ -111,7 +111,10 @@ pub async fn get_pool_data_batch_request<M: Middleware>(
                                             pool_data[4].to_owned().into_uint().unwrap().as_u128();
                                         uniswap_v2_pool.reserve_1 =
                                             pool_data[5].to_owned().into_uint().unwrap().as_u128();
-                                        uniswap_v2_pool.fee = 300;
+                                        // keep a fee detected for a fork of the pair
+                                        if uniswap_v2_pool.fee == 0 {
+                                            uniswap_v2_pool.fee = 300;
+                                        }
                                     }
                                     _ => {}
                                 }
@@ -166,7 +169,10 @@ pub async fn get_v2_pool_data_batch_request<M: Middleware>(
                         pool.reserve_0 = pool_data[4].to_owned().into_uint().unwrap().as_u128();
                         pool.reserve_1 = pool_data[5].to_owned().into_uint().unwrap().as_u128();
 
-                        pool.fee = 300;
+                        // keep a fee detected for a fork of the pair
+                        if pool.fee == 0 {
+                            pool.fee = 300;
+                        }
                     }
                 }
             }
@@ -10,6 +10,7 @@ use ethers::{
 };
 
 use crate::pool::{Pool, PoolType, PoolVariant};
+use serde::{Deserialize, Serialize};
 use cfmms::pool::UniswapV3Pool;
 
 use crate::errors::CFMMError;
@@ -195,7 +196,7 @@ pub async fn get_v3_pool_data_batch_request<M: Middleware>(
     Ok(())
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UniswapV3TickData {
     pub initialized: bool,
     pub tick: i32,
@@ -0,0 +1,411 @@
+//! How much a pool can take before the price moves too far
+//!
+//! A [DepthChart] samples amount in against price impact at doubling sizes, starting at 1 bp of
+//! what the pool holds of the input token. V2 pairs are computed exactly, V3 pools walk the
+//! initialized ticks (staying in the current range when none are known) and Curve pools solve the
+//! StableSwap invariant numerically. The charts are cheap, the point is throwing out pools too
+//! shallow to be worth an EVM simulation.
+
+use ethers::types::U256;
+
+use crate::{
+    batch_requests::uniswap_v3::UniswapV3TickData,
+    pool::{Pool, PoolType},
+};
+
+const BPS: f64 = 10_000.0;
+/// `2 ** 96`, the fixed point scale of V3's `sqrtPriceX96`
+const Q96: f64 = 79_228_162_514_264_337_593_543_950_336.0;
+const CURVE_FEE_DENOMINATOR: f64 = 1e10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DepthPoint {
+    pub amount_in: U256,
+    pub amount_out: U256,
+    /// how much worse than the spot price the trade executes, fees included
+    pub impact_bps: u64,
+}
+
+/// Points sorted by amount in. Sizes the pool can't fill are left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DepthChart {
+    pub points: Vec<DepthPoint>,
+}
+
+impl DepthChart {
+    /// Largest sampled amount in with at most `impact_bps` of impact
+    pub fn amount_within(&self, impact_bps: u64) -> U256 {
+        self.points
+            .iter()
+            .take_while(|point| point.impact_bps <= impact_bps)
+            .last()
+            .map_or(U256::zero(), |point| point.amount_in)
+    }
+
+    /// Whether less than `min_amount` can be traded within `impact_bps`
+    pub fn is_shallow(&self, min_amount: U256, impact_bps: u64) -> bool {
+        self.amount_within(impact_bps) < min_amount
+    }
+
+    fn sample(
+        levels: usize,
+        base: f64,
+        spot: f64,
+        amount_out: impl Fn(f64) -> Option<f64>,
+    ) -> Self {
+        let mut points = Vec::with_capacity(levels);
+        if base < 1.0 || spot <= 0.0 {
+            return Self { points };
+        }
+        for level in 0..levels {
+            let amount_in = base * 2f64.powi(level as i32);
+            let Some(out) = amount_out(amount_in) else {
+                break;
+            };
+            let impact = 1.0 - out / amount_in / spot;
+            points.push(DepthPoint {
+                amount_in: from_f64(amount_in),
+                amount_out: from_f64(out),
+                impact_bps: (impact * BPS).round().clamp(0.0, BPS) as u64,
+            });
+        }
+        Self { points }
+    }
+}
+
+impl Pool {
+    /// Depth of selling `token_0` (`zero_for_one`) or `token_1`, at `levels` sizes. V3 pools are
+    /// assumed to stay in their current range, see [Pool::depth_chart_with_ticks].
+    pub fn depth_chart(&self, zero_for_one: bool, levels: usize) -> DepthChart {
+        self.depth_chart_with_ticks(zero_for_one, levels, &[])
+    }
+
+    /// Same as [Pool::depth_chart], walking `ticks`, the initialized ticks of a V3 pool
+    pub fn depth_chart_with_ticks(
+        &self,
+        zero_for_one: bool,
+        levels: usize,
+        ticks: &[UniswapV3TickData],
+    ) -> DepthChart {
+        match self.pool_type {
+            PoolType::UniswapV2(pool) => {
+                let (reserve_in, reserve_out) = if zero_for_one {
+                    (pool.reserve_0, pool.reserve_1)
+                } else {
+                    (pool.reserve_1, pool.reserve_0)
+                };
+                v2_depth_chart(
+                    U256::from(reserve_in),
+                    U256::from(reserve_out),
+                    pool.fee,
+                    levels,
+                )
+            }
+            PoolType::UniswapV3(pool) => v3_depth_chart(
+                pool.sqrt_price,
+                pool.liquidity,
+                pool.tick,
+                pool.fee,
+                ticks,
+                zero_for_one,
+                levels,
+            ),
+        }
+    }
+
+    /// Output of selling `amount_in` of `token_0` (`zero_for_one`) or `token_1`, exact for V2
+    /// pairs and estimated walking `ticks` for V3 pools
+    pub fn quote_with_ticks(
+        &self,
+        zero_for_one: bool,
+        amount_in: U256,
+        ticks: &[UniswapV3TickData],
+    ) -> Option<U256> {
+        match self.pool_type {
+            PoolType::UniswapV2(pool) => {
+                let (reserve_in, reserve_out) = if zero_for_one {
+                    (pool.reserve_0, pool.reserve_1)
+                } else {
+                    (pool.reserve_1, pool.reserve_0)
+                };
+                if reserve_in == 0 || reserve_out == 0 {
+                    return None;
+                }
+                let amount_in = amount_in * U256::from(100_000u32.saturating_sub(pool.fee));
+                Some(
+                    amount_in * U256::from(reserve_out)
+                        / (U256::from(reserve_in) * 100_000 + amount_in),
+                )
+            }
+            PoolType::UniswapV3(pool) => v3_amount_out(
+                pool.sqrt_price,
+                pool.liquidity,
+                pool.tick,
+                pool.fee,
+                ticks,
+                zero_for_one,
+                amount_in,
+            ),
+        }
+    }
+}
+
+/// Exact for a V2 pair, `fee` in hundredths of a bp (300 is 0.3%) like cfmms' pools
+pub fn v2_depth_chart(reserve_in: U256, reserve_out: U256, fee: u32, levels: usize) -> DepthChart {
+    if reserve_in.is_zero() || reserve_out.is_zero() {
+        return DepthChart::default();
+    }
+    let spot = to_f64(reserve_out) / to_f64(reserve_in);
+    let fee_factor = U256::from(100_000u32.saturating_sub(fee));
+    DepthChart::sample(levels, to_f64(reserve_in) / BPS, spot, |amount_in| {
+        let amount_in = from_f64(amount_in) * fee_factor;
+        let out = amount_in * reserve_out / (reserve_in * 100_000 + amount_in);
+        Some(to_f64(out))
+    })
+}
+
+/// Walks the ticks from the current one, `fee` in hundredths of a bp (3000 is 0.3%)
+pub fn v3_depth_chart(
+    sqrt_price_x96: U256,
+    liquidity: u128,
+    tick: i32,
+    fee: u32,
+    ticks: &[UniswapV3TickData],
+    zero_for_one: bool,
+    levels: usize,
+) -> DepthChart {
+    let Some(curve) = V3Curve::new(sqrt_price_x96, liquidity, tick, fee, ticks, zero_for_one)
+    else {
+        return DepthChart::default();
+    };
+    let (sqrt_price, liquidity) = (curve.sqrt_price, curve.liquidity);
+    let (spot, base) = if zero_for_one {
+        (sqrt_price * sqrt_price, liquidity / sqrt_price / BPS)
+    } else {
+        (
+            1.0 / (sqrt_price * sqrt_price),
+            liquidity * sqrt_price / BPS,
+        )
+    };
+    DepthChart::sample(levels, base, spot, |amount_in| curve.amount_out(amount_in))
+}
+
+/// Output of swapping `amount_in` through a V3 pool walking `ticks`, as estimated by
+/// [v3_depth_chart]. `None` if the known liquidity can't fill it.
+pub fn v3_amount_out(
+    sqrt_price_x96: U256,
+    liquidity: u128,
+    tick: i32,
+    fee: u32,
+    ticks: &[UniswapV3TickData],
+    zero_for_one: bool,
+    amount_in: U256,
+) -> Option<U256> {
+    let curve = V3Curve::new(sqrt_price_x96, liquidity, tick, fee, ticks, zero_for_one)?;
+    curve.amount_out(to_f64(amount_in)).map(from_f64)
+}
+
+/// A V3 pool's liquidity in one swap direction
+struct V3Curve {
+    sqrt_price: f64,
+    liquidity: f64,
+    /// ticks to cross in swap direction, with the liquidity change when crossing them
+    crossed: Vec<(f64, f64)>,
+    fee_factor: f64,
+    zero_for_one: bool,
+}
+
+impl V3Curve {
+    fn new(
+        sqrt_price_x96: U256,
+        liquidity: u128,
+        tick: i32,
+        fee: u32,
+        ticks: &[UniswapV3TickData],
+        zero_for_one: bool,
+    ) -> Option<Self> {
+        let sqrt_price = to_f64(sqrt_price_x96) / Q96;
+        let liquidity = liquidity as f64;
+        if sqrt_price <= 0.0 || liquidity <= 0.0 {
+            return None;
+        }
+
+        let mut crossed: Vec<(f64, f64)> = ticks
+            .iter()
+            .filter(|t| t.initialized && (t.tick <= tick) == zero_for_one)
+            .map(|t| {
+                let sqrt_price = 1.0001f64.powf(t.tick as f64 / 2.0);
+                let net = t.liquidity_net as f64;
+                (sqrt_price, if zero_for_one { -net } else { net })
+            })
+            .collect();
+        crossed.sort_by(|a, b| {
+            let order = a.0.total_cmp(&b.0);
+            if zero_for_one {
+                order.reverse()
+            } else {
+                order
+            }
+        });
+        Some(Self {
+            sqrt_price,
+            liquidity,
+            crossed,
+            fee_factor: 1.0 - fee as f64 / 1e6,
+            zero_for_one,
+        })
+    }
+
+    fn amount_out(&self, amount_in: f64) -> Option<f64> {
+        let zero_for_one = self.zero_for_one;
+        let mut remaining = amount_in * self.fee_factor;
+        let (mut sqrt_price, mut liquidity, mut out) = (self.sqrt_price, self.liquidity, 0.0);
+        for (target, liquidity_delta) in self.crossed.iter().copied() {
+            let needed = if zero_for_one {
+                liquidity * (1.0 / target - 1.0 / sqrt_price)
+            } else {
+                liquidity * (target - sqrt_price)
+            };
+            if remaining <= needed {
+                break;
+            }
+            out += if zero_for_one {
+                liquidity * (sqrt_price - target)
+            } else {
+                liquidity * (1.0 / sqrt_price - 1.0 / target)
+            };
+            remaining -= needed;
+            sqrt_price = target;
+            liquidity += liquidity_delta;
+            if liquidity <= 0.0 {
+                // ran out of liquidity before filling the trade
+                return None;
+            }
+        }
+        out += if zero_for_one {
+            let next = 1.0 / (1.0 / sqrt_price + remaining / liquidity);
+            liquidity * (sqrt_price - next)
+        } else {
+            let next = sqrt_price + remaining / liquidity;
+            liquidity * (1.0 / sqrt_price - 1.0 / next)
+        };
+        Some(out)
+    }
+}
+
+/// Two coin Curve StableSwap pool, balances scaled to the same decimals. `amp` and `fee` as the
+/// pool returns them from `A()` and `fee()`, the fee over 1e10.
+pub fn stable_swap_depth_chart(
+    balance_in: U256,
+    balance_out: U256,
+    amp: u64,
+    fee: u64,
+    levels: usize,
+) -> DepthChart {
+    let (x, y) = (to_f64(balance_in), to_f64(balance_out));
+    if x <= 0.0 || y <= 0.0 || amp == 0 {
+        return DepthChart::default();
+    }
+    let ann = amp as f64 * 2.0;
+    let d = stable_swap_d(x, y, ann);
+    let fee_factor = 1.0 - fee as f64 / CURVE_FEE_DENOMINATOR;
+    // marginal price at the current balances, from a trade too small to move it
+    let epsilon = x * 1e-6;
+    let spot = (y - stable_swap_y(x + epsilon, d, ann)) / epsilon;
+    DepthChart::sample(levels, x / BPS, spot, |amount_in| {
+        let out = (y - stable_swap_y(x + amount_in, d, ann)) * fee_factor;
+        (out > 0.0).then_some(out)
+    })
+}
+
+/// `get_D` of the StableSwap invariant for two coins
+fn stable_swap_d(x: f64, y: f64, ann: f64) -> f64 {
+    let s = x + y;
+    let mut d = s;
+    for _ in 0..255 {
+        let d_p = d * d / (2.0 * x) * d / (2.0 * y);
+        let prev = d;
+        d = (ann * s + d_p * 2.0) * d / ((ann - 1.0) * d + 3.0 * d_p);
+        if (d - prev).abs() <= d * f64::EPSILON {
+            break;
+        }
+    }
+    d
+}
+
+/// `get_y`: balance of the other coin keeping the invariant at `d` when one holds `x`
+fn stable_swap_y(x: f64, d: f64, ann: f64) -> f64 {
+    let c = d * d / (2.0 * x) * d / (ann * 2.0);
+    let b = x + d / ann;
+    let mut y = d;
+    for _ in 0..255 {
+        let prev = y;
+        y = (y * y + c) / (2.0 * y + b - d);
+        if (y - prev).abs() <= y * f64::EPSILON {
+            break;
+        }
+    }
+    y
+}
+
+fn to_f64(value: U256) -> f64 {
+    value.0.iter().rev().fold(0.0, |acc, limb| {
+        acc * 18_446_744_073_709_551_616.0 + *limb as f64
+    })
+}
+
+fn from_f64(value: f64) -> U256 {
+    if value < u128::MAX as f64 {
+        U256::from(value.max(0.0) as u128)
+    } else {
+        U256::from_dec_str(&format!("{:.0}", value)).unwrap_or(U256::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfmms::pool::UniswapV2Pool;
+
+    #[test]
+    fn test_depth_charts() {
+        let reserve = U256::exp10(24);
+        let mut pool = Pool::default();
+        pool.pool_type = PoolType::UniswapV2(UniswapV2Pool {
+            reserve_0: 10u128.pow(24),
+            reserve_1: 10u128.pow(24),
+            fee: 300,
+            ..Default::default()
+        });
+        let v2 = pool.depth_chart(true, 8);
+        assert_eq!(v2.points.len(), 8);
+        // the fee alone at the smallest size, then growing with size
+        assert_eq!(v2.points[0].impact_bps, 31);
+        assert!(v2
+            .points
+            .windows(2)
+            .all(|w| w[0].impact_bps < w[1].impact_bps));
+        assert_eq!(v2.amount_within(31), reserve / 10_000);
+        assert!(v2.is_shallow(reserve, 100));
+
+        // a V3 pool whose range is as deep as the pair matches it, without the ticks
+        let sqrt_price = U256::from(2u128.pow(96));
+        let v3 = v3_depth_chart(sqrt_price, 10u128.pow(24), 0, 3000, &[], true, 8);
+        for (a, b) in v2.points.iter().zip(&v3.points) {
+            assert!(a.impact_bps.abs_diff(b.impact_bps) <= 1);
+        }
+        // the range ending right below the price can't fill anything past it
+        let edge = UniswapV3TickData {
+            initialized: true,
+            tick: -10,
+            liquidity_net: 10i128.pow(24),
+        };
+        let ranged = v3_depth_chart(sqrt_price, 10u128.pow(24), 0, 3000, &[edge], true, 8);
+        assert!(ranged.points.len() < v3.points.len());
+
+        // StableSwap is much flatter than constant product
+        let curve = stable_swap_depth_chart(reserve, reserve, 2_000, 4_000_000, 8);
+        assert_eq!(curve.points.len(), 8);
+        assert!(curve.points[7].impact_bps < v2.points[7].impact_bps / 10);
+    }
+}
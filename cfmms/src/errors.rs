@@ -2,7 +2,7 @@ use std::fmt;
 
 use ethers::prelude::{AbiError, ContractError};
 use ethers::providers::{Middleware, ProviderError};
-use ethers::types::{H160, U256};
+use ethers::types::{Bytes, H160, H256, U256};
 use thiserror::Error;
 use tokio::task::JoinError;
 
@@ -39,6 +39,34 @@ where
     NoLiquidityNet,
 }
 
+#[derive(Error, Debug)]
+pub enum V4PoolError {
+    #[error("Fork database error: {0}")]
+    DatabaseError(String),
+    #[error("EVM error: {0}")]
+    EvmError(String),
+    #[error("Pool {0:?} is not initialized in the PoolManager")]
+    PoolNotInitialized(H256),
+    #[error("Token {0:?} is not part of the pool key")]
+    TokenNotInPool(H160),
+    #[error("Swap simulation reverted")]
+    SwapReverted(Bytes),
+    #[error("Eth ABI error")]
+    EthABIError(#[from] ethers::abi::Error),
+    #[error("Unexpected quoter output")]
+    UnexpectedOutput,
+}
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    SerdeJson(#[from] serde_json::Error),
+    #[error("Unsupported snapshot version {0}")]
+    UnsupportedVersion(u32),
+}
+
 #[derive(Error, Debug)]
 pub enum ArithmeticError {
     ShadowOverflow(U256),
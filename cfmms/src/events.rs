@@ -0,0 +1,365 @@
+//! Pool state from logs
+//!
+//! Every state change of a Uniswap V2 pair ends with a `Sync` carrying the new reserves, and a V3
+//! `Swap` carries the price, tick and active liquidity after the swap. Applying the logs of a new
+//! block is much cheaper than reading the pools' storage again, one `eth_getLogs` covers every
+//! pool. V3 `Mint`s and `Burn`s move the active liquidity and the `liquidityNet` of their ticks.
+//!
+//! Logs have to be applied in order. A log removed by a reorg is reverted where it carries a
+//! delta, the `liquidityNet` of a `Mint` or `Burn`'s ticks, the rest carry the state after the
+//! event so the pools they touched are reported stale, to be read from storage again.
+
+use dashmap::DashMap;
+use ethers::{
+    abi::RawLog,
+    contract::{EthEvent, EthLogDecode},
+    types::{Address, Log, H256},
+};
+
+use crate::{
+    batch_requests::uniswap_v3::UniswapV3TickData,
+    pool::{Pool, PoolType},
+    registry::PoolMap,
+};
+
+pub mod v2 {
+    use ethers::contract::abigen;
+
+    abigen!(
+        UniswapV2Pair,
+        r#"[
+            event Sync(uint112 reserve0, uint112 reserve1)
+            event Swap(address indexed sender, uint256 amount0In, uint256 amount1In, uint256 amount0Out, uint256 amount1Out, address indexed to)
+            event Mint(address indexed sender, uint256 amount0, uint256 amount1)
+            event Burn(address indexed sender, uint256 amount0, uint256 amount1, address indexed to)
+        ]"#
+    );
+}
+
+pub mod v3 {
+    use ethers::contract::abigen;
+
+    abigen!(
+        UniswapV3Pool,
+        r#"[
+            event Swap(address indexed sender, address indexed recipient, int256 amount0, int256 amount1, uint160 sqrtPriceX96, uint128 liquidity, int24 tick)
+            event Mint(address sender, address indexed owner, int24 indexed tickLower, int24 indexed tickUpper, uint128 amount, uint256 amount0, uint256 amount1)
+            event Burn(address indexed owner, int24 indexed tickLower, int24 indexed tickUpper, uint128 amount, uint256 amount0, uint256 amount1)
+        ]"#
+    );
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PoolEvent {
+    V2Sync(v2::SyncFilter),
+    V2Swap(v2::SwapFilter),
+    V2Mint(v2::MintFilter),
+    V2Burn(v2::BurnFilter),
+    V3Swap(v3::SwapFilter),
+    V3Mint(v3::MintFilter),
+    V3Burn(v3::BurnFilter),
+}
+
+/// A decoded pool log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolLog {
+    pub pool: Address,
+    pub block_number: Option<u64>,
+    pub log_index: Option<u64>,
+    /// removed from the chain by a reorg
+    pub removed: bool,
+    pub event: PoolEvent,
+}
+
+/// `topic0` of every event decoded here, to filter `eth_getLogs` with
+pub fn pool_event_topics() -> Vec<H256> {
+    vec![
+        v2::SyncFilter::signature(),
+        v2::SwapFilter::signature(),
+        v2::MintFilter::signature(),
+        v2::BurnFilter::signature(),
+        v3::SwapFilter::signature(),
+        v3::MintFilter::signature(),
+        v3::BurnFilter::signature(),
+    ]
+}
+
+/// `topic0` of the `Swap` events, a log with one of them is a trade on the pool emitting it
+pub fn swap_event_topics() -> [H256; 2] {
+    [v2::SwapFilter::signature(), v3::SwapFilter::signature()]
+}
+
+impl PoolEvent {
+    pub fn decode(log: &Log) -> Option<Self> {
+        let raw = RawLog {
+            topics: log.topics.clone(),
+            data: log.data.to_vec(),
+        };
+        if let Ok(event) = v2::UniswapV2PairEvents::decode_log(&raw) {
+            return Some(match event {
+                v2::UniswapV2PairEvents::SyncFilter(e) => Self::V2Sync(e),
+                v2::UniswapV2PairEvents::SwapFilter(e) => Self::V2Swap(e),
+                v2::UniswapV2PairEvents::MintFilter(e) => Self::V2Mint(e),
+                v2::UniswapV2PairEvents::BurnFilter(e) => Self::V2Burn(e),
+            });
+        }
+        match v3::UniswapV3PoolEvents::decode_log(&raw).ok()? {
+            v3::UniswapV3PoolEvents::SwapFilter(e) => Some(Self::V3Swap(e)),
+            v3::UniswapV3PoolEvents::MintFilter(e) => Some(Self::V3Mint(e)),
+            v3::UniswapV3PoolEvents::BurnFilter(e) => Some(Self::V3Burn(e)),
+        }
+    }
+
+    /// Apply the event to `pool`, returns whether its state changed. V2 `Swap`s, `Mint`s and
+    /// `Burn`s are followed by a `Sync` with the resulting reserves and change nothing on their
+    /// own.
+    pub fn apply(&self, pool: &mut Pool) -> bool {
+        match (self, &mut pool.pool_type) {
+            (Self::V2Sync(sync), PoolType::UniswapV2(pair)) => {
+                pair.reserve_0 = sync.reserve_0;
+                pair.reserve_1 = sync.reserve_1;
+                true
+            }
+            (Self::V3Swap(swap), PoolType::UniswapV3(v3_pool)) => {
+                v3_pool.sqrt_price = swap.sqrt_price_x96;
+                v3_pool.liquidity = swap.liquidity;
+                v3_pool.tick = swap.tick;
+                true
+            }
+            (Self::V3Mint(mint), PoolType::UniswapV3(v3_pool)) => {
+                if mint.tick_lower <= v3_pool.tick && v3_pool.tick < mint.tick_upper {
+                    v3_pool.liquidity = v3_pool.liquidity.saturating_add(mint.amount);
+                }
+                true
+            }
+            (Self::V3Burn(burn), PoolType::UniswapV3(v3_pool)) => {
+                if burn.tick_lower <= v3_pool.tick && v3_pool.tick < burn.tick_upper {
+                    v3_pool.liquidity = v3_pool.liquidity.saturating_sub(burn.amount);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Apply a V3 `Mint` or `Burn` to the `liquidityNet` of its ticks, sorted by tick
+    pub fn apply_to_ticks(&self, ticks: &mut Vec<UniswapV3TickData>) -> bool {
+        self.add_to_ticks(ticks, false)
+    }
+
+    /// Undo [PoolEvent::apply_to_ticks], for a log removed by a reorg
+    pub fn revert_from_ticks(&self, ticks: &mut Vec<UniswapV3TickData>) -> bool {
+        self.add_to_ticks(ticks, true)
+    }
+
+    fn add_to_ticks(&self, ticks: &mut Vec<UniswapV3TickData>, revert: bool) -> bool {
+        let (tick_lower, tick_upper, mut delta) = match self {
+            Self::V3Mint(mint) => (mint.tick_lower, mint.tick_upper, mint.amount as i128),
+            Self::V3Burn(burn) => (burn.tick_lower, burn.tick_upper, -(burn.amount as i128)),
+            _ => return false,
+        };
+        if revert {
+            delta = -delta;
+        }
+        for (tick, delta) in [(tick_lower, delta), (tick_upper, -delta)] {
+            match ticks.binary_search_by_key(&tick, |data| data.tick) {
+                Ok(i) => ticks[i].liquidity_net = ticks[i].liquidity_net.saturating_add(delta),
+                Err(i) => ticks.insert(
+                    i,
+                    UniswapV3TickData {
+                        initialized: true,
+                        tick,
+                        liquidity_net: delta,
+                    },
+                ),
+            }
+        }
+        true
+    }
+}
+
+impl PoolLog {
+    /// `None` for logs of other events
+    pub fn decode(log: &Log) -> Option<Self> {
+        Some(Self {
+            pool: log.address,
+            block_number: log.block_number.map(|n| n.as_u64()),
+            log_index: log.log_index.map(|i| i.as_u64()),
+            removed: log.removed == Some(true),
+            event: PoolEvent::decode(log)?,
+        })
+    }
+}
+
+/// Pools touched by [apply_logs]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AppliedLogs {
+    /// state changed, in the order they were first touched
+    pub updated: Vec<Address>,
+    /// touched by a removed log that can't be reverted, to be read from storage again
+    pub stale: Vec<Address>,
+}
+
+/// Apply `logs`, in the order they were emitted, to the tracked `pools` and, given their `ticks`,
+/// to the initialized ticks of the V3 pools. Removed logs are reverted, see [crate::events].
+pub fn apply_logs(
+    pools: &mut PoolMap,
+    ticks: Option<&DashMap<Address, Vec<UniswapV3TickData>>>,
+    logs: &[Log],
+) -> AppliedLogs {
+    let mut applied = AppliedLogs::default();
+    for log in logs.iter().filter_map(PoolLog::decode) {
+        if !pools.contains_key(&log.pool) {
+            continue;
+        }
+        if log.removed {
+            // a `Mint` or `Burn` moved the active liquidity depending on the tick at the time
+            if !applied.stale.contains(&log.pool) {
+                applied.stale.push(log.pool);
+            }
+        } else if pools
+            .get_mut(&log.pool)
+            .map_or(false, |pool| log.event.apply(pool))
+            && !applied.updated.contains(&log.pool)
+        {
+            applied.updated.push(log.pool);
+        }
+        if let Some(mut ticks) = ticks.and_then(|ticks| ticks.get_mut(&log.pool)) {
+            if log.removed {
+                log.event.revert_from_ticks(&mut ticks);
+            } else {
+                log.event.apply_to_ticks(&mut ticks);
+            }
+        }
+    }
+    applied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::PoolVariant;
+    use ethers::{
+        abi::{encode, Token},
+        types::{Bytes, U256},
+    };
+
+    fn log(address: Address, topics: Vec<H256>, data: Vec<Token>) -> Log {
+        Log {
+            address,
+            topics,
+            data: Bytes::from(encode(&data)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_apply_logs() {
+        let pair = Address::from_low_u64_be(1);
+        let v3_pool = Address::from_low_u64_be(2);
+        let mut pools = PoolMap::new();
+        let token = |n| Address::from_low_u64_be(n);
+        pools.insert(
+            pair,
+            Pool::new_empty_pool(
+                pair,
+                token(10),
+                token(11),
+                U256::zero(),
+                PoolVariant::UniswapV2,
+            ),
+        );
+        pools.insert(
+            v3_pool,
+            Pool::new_empty_pool(
+                v3_pool,
+                token(10),
+                token(11),
+                U256::zero(),
+                PoolVariant::UniswapV3,
+            ),
+        );
+
+        let tick_topic = |tick: i32| {
+            let mut topic = [if tick < 0 { 0xff } else { 0 }; 32];
+            topic[28..].copy_from_slice(&tick.to_be_bytes());
+            H256(topic)
+        };
+        let logs = vec![
+            log(
+                pair,
+                vec![v2::SyncFilter::signature()],
+                vec![Token::Uint(U256::from(100)), Token::Uint(U256::from(200))],
+            ),
+            log(
+                v3_pool,
+                vec![v3::SwapFilter::signature(), H256::zero(), H256::zero()],
+                vec![
+                    Token::Int(U256::from(5)),
+                    Token::Int(U256::from(7)),
+                    Token::Uint(U256::from(1) << 96),
+                    Token::Uint(U256::from(1_000)),
+                    Token::Int(U256::from(10)),
+                ],
+            ),
+            // in range of the current tick
+            log(
+                v3_pool,
+                vec![
+                    v3::MintFilter::signature(),
+                    H256::zero(),
+                    tick_topic(-60),
+                    tick_topic(60),
+                ],
+                vec![
+                    Token::Address(Address::zero()),
+                    Token::Uint(U256::from(500)),
+                    Token::Uint(U256::zero()),
+                    Token::Uint(U256::zero()),
+                ],
+            ),
+            // untracked pool
+            log(
+                Address::from_low_u64_be(3),
+                vec![v2::SyncFilter::signature()],
+                vec![Token::Uint(U256::from(1)), Token::Uint(U256::from(1))],
+            ),
+        ];
+
+        let ticks = DashMap::from_iter([(v3_pool, vec![])]);
+        let applied = apply_logs(&mut pools, Some(&ticks), &logs);
+        assert_eq!(applied.updated, vec![pair, v3_pool]);
+        assert!(applied.stale.is_empty());
+        match pools.get(&pair).unwrap().pool_type {
+            PoolType::UniswapV2(p) => assert_eq!((p.reserve_0, p.reserve_1), (100, 200)),
+            _ => unreachable!(),
+        }
+        match pools.get(&v3_pool).unwrap().pool_type {
+            PoolType::UniswapV3(p) => {
+                assert_eq!((p.liquidity, p.tick), (1_500, 10));
+                assert_eq!(p.sqrt_price, U256::from(1) << 96);
+            }
+            _ => unreachable!(),
+        }
+
+        let liquidity_net = |ticks: &DashMap<Address, Vec<UniswapV3TickData>>| {
+            ticks
+                .get(&v3_pool)
+                .unwrap()
+                .iter()
+                .map(|t| (t.tick, t.liquidity_net))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(liquidity_net(&ticks), vec![(-60, 500), (60, -500)]);
+
+        // the mint is reorged out
+        let removed = Log {
+            removed: Some(true),
+            ..logs[2].clone()
+        };
+        let applied = apply_logs(&mut pools, Some(&ticks), &[removed]);
+        assert!(applied.updated.is_empty());
+        assert_eq!(applied.stale, vec![v3_pool]);
+        assert_eq!(liquidity_net(&ticks), vec![(-60, 0), (60, 0)]);
+    }
+}
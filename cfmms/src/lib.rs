@@ -1,5 +1,13 @@
+pub mod address_book;
 pub mod batch_requests;
 pub mod bindings;
+pub mod depth;
 pub mod dex;
 pub mod errors;
+pub mod events;
 pub mod pool;
+pub mod registry;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+pub mod v3_ticks;
+pub mod v4_pool;
@@ -172,39 +172,26 @@ impl Pool {
             }
         }
     }
+}
 
-    pub fn to_rp(&self) -> RustyPool {
-        match self.pool_variant {
-            PoolVariant::UniswapV2 => {
-                let mut rp: RustyPool = RustyPool::default();
-                if let PoolType::UniswapV2(pool_type) = &self.pool_type {
-                    rp = RustyPool::from(
-                        &self.address,
-                        &self.token_0,
-                        &self.token_1,
-                        &self.swap_fee,
-                        &PoolVariant::UniswapV2,
-                        &PoolType::UniswapV2(*pool_type),
-                    );
-                };
-
-                rp
-            }
-            PoolVariant::UniswapV3 => {
-                let mut rp: RustyPool = RustyPool::default();
-                if let PoolType::UniswapV3(pool_type) = &self.pool_type {
-                    rp = RustyPool::from(
-                        &self.address,
-                        &self.token_0,
-                        &self.token_1,
-                        &self.swap_fee,
-                        &PoolVariant::UniswapV3,
-                        &PoolType::UniswapV3(*pool_type),
-                    );
-                };
+/// Adapter for consumers still working with rusty-sando's pool type, e.g. its sandwich encoders.
+/// Internally everything is kept as [Pool], convert only at the boundary.
+impl From<&Pool> for RustyPool {
+    fn from(pool: &Pool) -> Self {
+        // inherent constructor of rusty-sando's pool
+        RustyPool::from(
+            &pool.address,
+            &pool.token_0,
+            &pool.token_1,
+            &pool.swap_fee,
+            &pool.pool_variant,
+            &pool.pool_type,
+        )
+    }
+}
 
-                rp
-            }
-        }
+impl From<Pool> for RustyPool {
+    fn from(pool: Pool) -> Self {
+        <RustyPool as From<&Pool>>::from(&pool)
     }
 }
@@ -0,0 +1,506 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+    sync::Arc,
+};
+
+use dashmap::DashMap;
+use ethers::{
+    providers::Middleware,
+    types::{Address, Log, U64},
+};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::warn;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    batch_requests::uniswap_v3::{get_uniswap_v3_tick_data_batch_request, UniswapV3TickData},
+    depth::DepthChart,
+    errors::{CFMMError, RegistryError},
+    events::{self, AppliedLogs},
+    pool::{Pool, PoolType},
+};
+
+/// Bumped on every breaking change to [PoolSnapshot]
+pub const SNAPSHOT_VERSION: u32 = 1;
+/// Changes buffered for a subscriber before it starts missing some
+const CHANGE_CHANNEL_CAPACITY: usize = 64;
+
+/// State of every tracked pool at a block, as written by [PoolRegistry::export_snapshot]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolSnapshot {
+    pub version: u32,
+    pub block: u64,
+    /// reserves, fees and V3 slot0 / liquidity live in the pools themselves
+    pub pools: Vec<Pool>,
+    /// initialized ticks of the V3 pools, sorted by tick
+    pub ticks: BTreeMap<Address, Vec<UniswapV3TickData>>,
+}
+
+/// Pools by address, persistent so a [PoolView] shares everything a commit didn't change with the
+/// live pools and the views before it
+pub type PoolMap = im::HashMap<Address, Pool>;
+
+/// The pools as of one [PoolRegistry::commit], unaffected by later updates so a strategy
+/// iterating it sees every pool at the same block
+#[derive(Debug, Clone, Default)]
+pub struct PoolView {
+    epoch: u64,
+    block: Option<u64>,
+    pools: PoolMap,
+}
+
+impl PoolView {
+    /// Number of commits before this view, bumps by one with every commit
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Block the pools were synced at, if the sync engine said so
+    pub fn block(&self) -> Option<u64> {
+        self.block
+    }
+
+    pub fn get(&self, address: &Address) -> Option<Pool> {
+        self.pools.get(address).copied()
+    }
+
+    pub fn pools(&self) -> &PoolMap {
+        &self.pools
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Pool> {
+        self.pools.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pools.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pools.is_empty()
+    }
+}
+
+impl FromIterator<Pool> for PoolView {
+    /// An uncommitted view of `pools`, e.g. for tests or one-off tools
+    fn from_iter<I: IntoIterator<Item = Pool>>(pools: I) -> Self {
+        Self {
+            pools: pools.into_iter().map(|pool| (pool.address, pool)).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+/// An update of the sync engine, see [PoolRegistry::apply_updates]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolUpdate {
+    /// new pool, or new state of a tracked one
+    Upsert(Pool),
+    Remove(Address),
+}
+
+/// Pools changed by a commit, sent to the subscribers of [PoolRegistry::subscribe]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolChange {
+    pub epoch: u64,
+    pub block: Option<u64>,
+    /// added or updated, sorted
+    pub updated: Vec<Address>,
+    pub removed: Vec<Address>,
+}
+
+/// Live pools along with what changed since the last commit, under one lock so a commit never
+/// sees an update without its change or the other way around
+#[derive(Debug, Default)]
+struct LivePools {
+    pools: PoolMap,
+    pending: PoolChange,
+}
+
+/// All pools tracked by the bot, along with the initialized ticks of its V3 pools
+///
+/// Updates land in the live pools right away, [PoolRegistry::view] only sees them once
+/// [PoolRegistry::commit]ted, e.g. after a block's logs were applied. A commit costs the pools
+/// changed since the last one, not the pools tracked.
+#[derive(Debug)]
+pub struct PoolRegistry {
+    live: Mutex<LivePools>,
+    ticks: DashMap<Address, Vec<UniswapV3TickData>>,
+    /// block of the snapshot the registry was imported from
+    snapshot_block: Option<u64>,
+    view: RwLock<Arc<PoolView>>,
+    changes: broadcast::Sender<PoolChange>,
+}
+
+impl Default for PoolRegistry {
+    fn default() -> Self {
+        Self::with_state(PoolMap::new(), DashMap::new(), None)
+    }
+}
+
+impl PoolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_pools(pools: impl IntoIterator<Item = Pool>) -> Self {
+        let pools = pools.into_iter().map(|pool| (pool.address, pool)).collect();
+        Self::with_state(pools, DashMap::new(), None)
+    }
+
+    fn with_state(
+        pools: PoolMap,
+        ticks: DashMap<Address, Vec<UniswapV3TickData>>,
+        snapshot_block: Option<u64>,
+    ) -> Self {
+        let view = PoolView {
+            epoch: 0,
+            block: snapshot_block,
+            pools: pools.clone(),
+        };
+        Self {
+            live: Mutex::new(LivePools {
+                pools,
+                pending: PoolChange::default(),
+            }),
+            ticks,
+            snapshot_block,
+            view: RwLock::new(Arc::new(view)),
+            changes: broadcast::channel(CHANGE_CHANNEL_CAPACITY).0,
+        }
+    }
+
+    /// Live pools, including updates not committed yet. A copy, later updates don't show up.
+    pub fn live_pools(&self) -> PoolMap {
+        self.live.lock().pools.clone()
+    }
+
+    pub fn get(&self, address: &Address) -> Option<Pool> {
+        self.live.lock().pools.get(address).copied()
+    }
+
+    pub fn insert(&self, pool: Pool) {
+        let mut live = self.live.lock();
+        live.pools.insert(pool.address, pool);
+        live.pending.updated.push(pool.address);
+    }
+
+    /// Insert `pool` unless it's tracked already, returns whether it was inserted
+    pub fn insert_new(&self, pool: Pool) -> bool {
+        let mut live = self.live.lock();
+        if live.pools.contains_key(&pool.address) {
+            return false;
+        }
+        live.pools.insert(pool.address, pool);
+        live.pending.updated.push(pool.address);
+        true
+    }
+
+    pub fn remove(&self, address: &Address) -> Option<Pool> {
+        let mut live = self.live.lock();
+        let pool = live.pools.remove(address)?;
+        live.pending.removed.push(*address);
+        drop(live);
+        self.ticks.remove(address);
+        Some(pool)
+    }
+
+    /// The pools as of the last commit
+    pub fn view(&self) -> Arc<PoolView> {
+        self.view.read().clone()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.view.read().epoch
+    }
+
+    /// Notified of every commit changing pools, a subscriber lagging too far behind misses the
+    /// oldest changes and should re-read the view
+    pub fn subscribe(&self) -> broadcast::Receiver<PoolChange> {
+        self.changes.subscribe()
+    }
+
+    /// Apply a batch of the sync engine's updates for `block` and commit them
+    pub fn apply_updates(
+        &self,
+        block: Option<u64>,
+        updates: impl IntoIterator<Item = PoolUpdate>,
+    ) -> PoolChange {
+        for update in updates {
+            match update {
+                PoolUpdate::Upsert(pool) => self.insert(pool),
+                PoolUpdate::Remove(address) => {
+                    self.remove(&address);
+                }
+            }
+        }
+        self.commit(block)
+    }
+
+    /// Publish the updates since the last commit as a new [PoolView] of `block`
+    pub fn commit(&self, block: Option<u64>) -> PoolChange {
+        let mut view = self.view.write();
+        let mut live = self.live.lock();
+        let mut change = std::mem::take(&mut live.pending);
+        let pools = live.pools.clone();
+        drop(live);
+
+        change.updated.sort();
+        change.updated.dedup();
+        change.removed.sort();
+        change.removed.dedup();
+        // removed again before the commit, or re-added
+        change.updated.retain(|address| pools.contains_key(address));
+        change
+            .removed
+            .retain(|address| !pools.contains_key(address) && view.pools.contains_key(address));
+        change.epoch = view.epoch + 1;
+        change.block = block.or(view.block);
+
+        *view = Arc::new(PoolView {
+            epoch: change.epoch,
+            block: change.block,
+            pools,
+        });
+        drop(view);
+
+        if !change.updated.is_empty() || !change.removed.is_empty() {
+            // no subscribers isn't an error
+            let _ = self.changes.send(change.clone());
+        }
+        change
+    }
+
+    pub fn len(&self) -> usize {
+        self.live.lock().pools.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live.lock().pools.is_empty()
+    }
+
+    /// Initialized ticks of a V3 pool, sorted by tick
+    pub fn ticks(&self, address: &Address) -> Option<Vec<UniswapV3TickData>> {
+        self.ticks.get(address).map(|ticks| ticks.clone())
+    }
+
+    pub fn set_ticks(&self, address: Address, mut ticks: Vec<UniswapV3TickData>) {
+        ticks.sort_by_key(|tick| tick.tick);
+        ticks.dedup_by_key(|tick| tick.tick);
+        self.ticks.insert(address, ticks);
+    }
+
+    /// Apply the logs of new blocks, in the order they were emitted, to the tracked pools and the
+    /// ticks of V3 pools, see [events::apply_logs]. The stale pools are left for the caller to
+    /// read again and [PoolRegistry::insert].
+    pub fn apply_logs(&self, logs: &[Log]) -> AppliedLogs {
+        let mut live = self.live.lock();
+        let applied = events::apply_logs(&mut live.pools, Some(&self.ticks), logs);
+        live.pending.updated.extend(&applied.updated);
+        applied
+    }
+
+    /// [Pool::depth_chart] of a tracked pool, walking its ticks for V3 pools
+    pub fn depth_chart(
+        &self,
+        address: &Address,
+        zero_for_one: bool,
+        levels: usize,
+    ) -> Option<DepthChart> {
+        let pool = self.get(address)?;
+        let ticks = self.ticks(address).unwrap_or_default();
+        Some(pool.depth_chart_with_ticks(zero_for_one, levels, &ticks))
+    }
+
+    /// Block of the snapshot this registry was imported from, if any
+    pub fn snapshot_block(&self) -> Option<u64> {
+        self.snapshot_block
+    }
+
+    /// Fetches `num_ticks` words of initialized ticks on both sides of every V3 pool's current
+    /// tick at `block`, failures are logged and leave the pool without ticks
+    pub async fn fetch_tick_maps<M: Middleware>(
+        &self,
+        middleware: Arc<M>,
+        block: Option<U64>,
+        num_ticks: u16,
+    ) -> Result<(), CFMMError<M>> {
+        let v3_pools: Vec<_> = self
+            .live_pools()
+            .values()
+            .filter_map(|pool| match pool.pool_type {
+                PoolType::UniswapV3(pool) => Some(pool),
+                _ => None,
+            })
+            .collect();
+
+        for pool in v3_pools {
+            let mut ticks = vec![];
+            for zero_for_one in [true, false] {
+                match get_uniswap_v3_tick_data_batch_request(
+                    &pool,
+                    pool.tick,
+                    zero_for_one,
+                    num_ticks,
+                    block,
+                    middleware.clone(),
+                )
+                .await
+                {
+                    Ok((data, _)) => ticks.extend(data),
+                    Err(e) => warn!("Failed fetching ticks of {:?}: {:?}", pool.address, e),
+                }
+            }
+            self.set_ticks(pool.address, ticks);
+        }
+        Ok(())
+    }
+
+    /// Captures the state of every tracked pool, it's up to the caller to make sure the pools
+    /// were synced at `block`
+    pub fn snapshot(&self, block: u64) -> PoolSnapshot {
+        let mut pools: Vec<Pool> = self.live_pools().values().copied().collect();
+        pools.sort_by_key(|pool| pool.address);
+        PoolSnapshot {
+            version: SNAPSHOT_VERSION,
+            block,
+            pools,
+            ticks: self
+                .ticks
+                .iter()
+                .map(|entry| (*entry.key(), entry.value().clone()))
+                .collect(),
+        }
+    }
+
+    /// Writes a gzipped snapshot of all tracked pools at `block` to `path`
+    pub fn export_snapshot(&self, block: u64, path: impl AsRef<Path>) -> Result<(), RegistryError> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        serde_json::to_writer(&mut encoder, &self.snapshot(block))?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// Loads a registry from a snapshot written by [PoolRegistry::export_snapshot], no RPC needed
+    pub fn import_snapshot(path: impl AsRef<Path>) -> Result<Self, RegistryError> {
+        let decoder = GzDecoder::new(BufReader::new(File::open(path)?));
+        let snapshot: PoolSnapshot = serde_json::from_reader(decoder)?;
+        Self::from_snapshot(snapshot)
+    }
+
+    pub fn from_snapshot(snapshot: PoolSnapshot) -> Result<Self, RegistryError> {
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(RegistryError::UnsupportedVersion(snapshot.version));
+        }
+        Ok(Self::with_state(
+            snapshot
+                .pools
+                .into_iter()
+                .map(|pool| (pool.address, pool))
+                .collect(),
+            snapshot.ticks.into_iter().collect(),
+            Some(snapshot.block),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::PoolVariant;
+    use crate::test_utils::empty_pool;
+    use ethers::types::U256;
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let registry = PoolRegistry::new();
+        let v2 = Pool::new_empty_pool(
+            Address::from_low_u64_be(1),
+            Address::from_low_u64_be(10),
+            Address::from_low_u64_be(11),
+            U256::from(3000),
+            PoolVariant::UniswapV2,
+        );
+        let v3 = Pool::new_empty_pool(
+            Address::from_low_u64_be(2),
+            Address::from_low_u64_be(10),
+            Address::from_low_u64_be(12),
+            U256::from(500),
+            PoolVariant::UniswapV3,
+        );
+        registry.insert(v2);
+        registry.insert(v3);
+        let tick = |tick: i32, liquidity_net: i128| UniswapV3TickData {
+            initialized: true,
+            tick,
+            liquidity_net,
+        };
+        registry.set_ticks(v3.address, vec![tick(60, -5), tick(-60, 5), tick(60, -5)]);
+
+        let path = std::env::temp_dir().join(format!("qilin-pools-{}.json.gz", std::process::id()));
+        registry.export_snapshot(17444939, &path).unwrap();
+        let imported = PoolRegistry::import_snapshot(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(imported.snapshot_block(), Some(17444939));
+        assert_eq!(imported.len(), 2);
+        assert_eq!(imported.get(&v2.address), Some(v2));
+        assert_eq!(imported.get(&v3.address), Some(v3));
+        assert_eq!(
+            imported.ticks(&v3.address).unwrap(),
+            vec![tick(-60, 5), tick(60, -5)]
+        );
+    }
+
+    #[test]
+    fn test_versioned_views() {
+        let pool = |n: u64, fee: u64| Pool {
+            swap_fee: U256::from(fee),
+            ..empty_pool(n)
+        };
+        let registry = PoolRegistry::from_pools([pool(1, 3000)]);
+        let mut changes = registry.subscribe();
+        let before = registry.view();
+        assert_eq!(before.epoch(), 0);
+
+        // live updates aren't visible to views until committed
+        registry.insert(pool(1, 1000));
+        assert_eq!(registry.get(&pool(1, 0).address), Some(pool(1, 1000)));
+        assert_eq!(
+            registry.view().get(&pool(1, 0).address),
+            Some(pool(1, 3000))
+        );
+
+        let change = registry.apply_updates(
+            Some(100),
+            [
+                PoolUpdate::Upsert(pool(2, 500)),
+                PoolUpdate::Remove(pool(3, 0).address),
+            ],
+        );
+        assert_eq!(change.epoch, 1);
+        assert_eq!(change.updated, vec![pool(1, 0).address, pool(2, 0).address]);
+        assert!(change.removed.is_empty());
+        assert_eq!(changes.try_recv().unwrap(), change);
+
+        let after = registry.view();
+        assert_eq!(
+            (after.epoch(), after.block(), after.len()),
+            (1, Some(100), 2)
+        );
+        assert_eq!(after.get(&pool(1, 0).address), Some(pool(1, 1000)));
+        // an older view stays consistent
+        assert_eq!(before.len(), 1);
+        assert_eq!(before.get(&pool(1, 0).address), Some(pool(1, 3000)));
+
+        let change = registry.apply_updates(Some(101), [PoolUpdate::Remove(pool(2, 0).address)]);
+        assert_eq!(change.removed, vec![pool(2, 0).address]);
+        assert_eq!(registry.view().len(), 1);
+    }
+}
@@ -0,0 +1,72 @@
+//! Pools for tests
+//!
+//! Built into this crate's tests, and into the tests of the crates depending on it with the
+//! `test-utils` feature, so every test sets pools up the same way.
+
+use ethers::types::{Address, U256};
+
+use crate::pool::{Pool, PoolType, PoolVariant};
+
+/// Address `n`, for pools and tokens alike
+pub fn address(n: u64) -> Address {
+    Address::from_low_u64_be(n)
+}
+
+/// Empty V2 pool at `address(n)` between tokens 1 and 2, for tests that only look pools up
+pub fn empty_pool(n: u64) -> Pool {
+    Pool::new_empty_pool(
+        address(n),
+        address(1),
+        address(2),
+        U256::zero(),
+        PoolVariant::UniswapV2,
+    )
+}
+
+/// V2 pool of `token_0` / `token_1` with the given reserves and a 0.3% fee
+pub fn v2_pool(
+    address: Address,
+    token_0: Address,
+    token_1: Address,
+    reserves: (U256, U256),
+) -> Pool {
+    let mut pool = Pool::new_empty_pool(
+        address,
+        token_0,
+        token_1,
+        U256::from(300),
+        PoolVariant::UniswapV2,
+    );
+    if let PoolType::UniswapV2(pair) = &mut pool.pool_type {
+        pair.token_a_decimals = 18;
+        pair.token_b_decimals = 18;
+        pair.reserve_0 = reserves.0.as_u128();
+        pair.reserve_1 = reserves.1.as_u128();
+        pair.fee = 300;
+    }
+    pool
+}
+
+/// V3 pool of `token_0` / `token_1` at `fee`, in hundredths of a bp, with `liquidity` in range at
+/// a price of 1
+pub fn v3_pool(
+    address: Address,
+    token_0: Address,
+    token_1: Address,
+    fee: u32,
+    liquidity: u128,
+) -> Pool {
+    let mut pool = Pool::new_empty_pool(
+        address,
+        token_0,
+        token_1,
+        U256::from(fee),
+        PoolVariant::UniswapV3,
+    );
+    if let PoolType::UniswapV3(v3) = &mut pool.pool_type {
+        v3.fee = fee;
+        v3.liquidity = liquidity;
+        v3.sqrt_price = U256::from(2u128.pow(96));
+    }
+    pool
+}
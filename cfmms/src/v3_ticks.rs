@@ -0,0 +1,184 @@
+use ethers::types::{Address, U256};
+use fork_database::{
+    backend_handler::Priority,
+    errors::DatabaseResult,
+    shared_backend::SharedBackend,
+    storage_layout::{mapping_slot, SlotValue, V3_TICK_INFO},
+    utils::{h160_to_b160, ru256_to_u256, u256_to_ru256},
+};
+use revm::db::DatabaseRef;
+
+use crate::batch_requests::uniswap_v3::UniswapV3TickData;
+
+/// Storage slot of `mapping(int24 => Tick.Info) ticks` in `UniswapV3Pool`
+pub const TICKS_SLOT: u64 = 5;
+/// Storage slot of `mapping(int16 => uint256) tickBitmap` in `UniswapV3Pool`
+pub const TICK_BITMAP_SLOT: u64 = 6;
+/// Words taken by a `Tick.Info`, all of them are touched when a swap crosses the tick
+pub const TICK_INFO_WORDS: u64 = 4;
+/// `TickMath.MIN_TICK` / `TickMath.MAX_TICK`
+pub const MIN_TICK: i32 = -887272;
+pub const MAX_TICK: i32 = 887272;
+/// Ticks are powers of 1.0001
+const TICK_BASE: f64 = 1.0001;
+
+/// Storage slot of the tick bitmap word at `word_pos`
+pub fn tick_bitmap_slot(word_pos: i16) -> U256 {
+    mapping_slot(word_pos, TICK_BITMAP_SLOT)
+}
+
+/// Storage slot of the `Tick.Info` for `tick`, the first word holds `liquidityGross | liquidityNet`
+pub fn tick_info_slot(tick: i32) -> U256 {
+    mapping_slot(tick, TICKS_SLOT)
+}
+
+/// `TickBitmap.position`: bitmap word and bit of `tick`, rounding towards negative infinity like
+/// the pool does
+pub fn tick_position(tick: i32, tick_spacing: i32) -> (i16, u8) {
+    let compressed = tick.div_euclid(tick_spacing);
+    ((compressed >> 8) as i16, (compressed & 0xff) as u8)
+}
+
+/// Tick range a swap moving the price by at most `max_move_bps` in either direction can cross
+pub fn tick_range(current_tick: i32, max_move_bps: u32) -> (i32, i32) {
+    let ratio = max_move_bps as f64 / 10_000.0;
+    let up = ((1.0 + ratio).ln() / TICK_BASE.ln()).ceil() as i32;
+    // a move of 100% or more down takes the price to 0
+    let down = if ratio >= 1.0 {
+        i32::MAX
+    } else {
+        (-(1.0 - ratio).ln() / TICK_BASE.ln()).ceil() as i32
+    };
+    (
+        current_tick.saturating_sub(down).max(MIN_TICK),
+        current_tick.saturating_add(up).min(MAX_TICK),
+    )
+}
+
+/// Loads the tick bitmap words and initialized ticks of a V3 pool in `[tick_lower, tick_upper]`
+/// into the fork db, in two batched rounds of requests.
+///
+/// Without it each crossed tick costs the simulation a blocking bitmap read followed by the
+/// tick's own reads, one round trip at a time. The batches go through the [Priority::Prefetch]
+/// lane, strategies' reads go first.
+///
+/// Returns the initialized ticks found in the range, sorted by tick
+pub fn prefetch_tick_range(
+    backend: &SharedBackend,
+    pool: Address,
+    tick_spacing: i32,
+    tick_lower: i32,
+    tick_upper: i32,
+) -> DatabaseResult<Vec<UniswapV3TickData>> {
+    let pool = h160_to_b160(pool);
+    let prefetch = backend.with_priority(Priority::Prefetch);
+    let (word_lower, _) = tick_position(tick_lower, tick_spacing);
+    let (word_upper, _) = tick_position(tick_upper, tick_spacing);
+
+    let bitmap_slots: Vec<_> = (word_lower..=word_upper)
+        .map(|word_pos| (pool, u256_to_ru256(tick_bitmap_slot(word_pos))))
+        .collect();
+    prefetch.prefetch_storage(&bitmap_slots)?;
+
+    let mut ticks = vec![];
+    for (word_pos, (_, slot)) in (word_lower..=word_upper).zip(&bitmap_slots) {
+        let word = ru256_to_u256(backend.storage(pool, *slot)?);
+        if word.is_zero() {
+            continue;
+        }
+        for bit in 0..256usize {
+            if !word.bit(bit) {
+                continue;
+            }
+            let tick = ((word_pos as i32) * 256 + bit as i32) * tick_spacing;
+            if (tick_lower..=tick_upper).contains(&tick) {
+                ticks.push(tick);
+            }
+        }
+    }
+
+    let info_slots: Vec<_> = ticks
+        .iter()
+        .flat_map(|tick| {
+            let base = tick_info_slot(*tick);
+            (0..TICK_INFO_WORDS).map(move |offset| (pool, u256_to_ru256(base + offset)))
+        })
+        .collect();
+    prefetch.prefetch_storage(&info_slots)?;
+
+    ticks
+        .into_iter()
+        .map(|tick| {
+            let word = ru256_to_u256(backend.storage(pool, u256_to_ru256(tick_info_slot(tick)))?);
+            let SlotValue::Int(liquidity_net) = V3_TICK_INFO[1].decode(word) else {
+                unreachable!("liquidityNet is an int128");
+            };
+            let liquidity_net = liquidity_net.as_i128();
+            Ok(UniswapV3TickData {
+                initialized: true,
+                tick,
+                liquidity_net,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tick_position() {
+        assert_eq!(tick_position(0, 60), (0, 0));
+        assert_eq!(tick_position(60 * 255, 60), (0, 255));
+        assert_eq!(tick_position(60 * 256, 60), (1, 0));
+        // negative ticks round down, not towards zero
+        assert_eq!(tick_position(-1, 60), (-1, 255));
+        assert_eq!(tick_position(-60 * 256, 60), (-1, 0));
+        assert_eq!(tick_position(-60 * 256 - 1, 60), (-2, 255));
+    }
+
+    #[test]
+    fn test_tick_range() {
+        // 1% up is ~99.5 ticks, 1% down ~100.5
+        assert_eq!(tick_range(1000, 100), (1000 - 101, 1000 + 100));
+        assert_eq!(tick_range(0, 10_000).0, MIN_TICK);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_prefetch_tick_range_resident() {
+        use ethers::{providers::Provider, types::H256};
+        use fork_database::blockchain_db::{BlockchainDb, BlockchainDbMeta};
+        use std::collections::BTreeSet;
+
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        let db = BlockchainDb::new(meta, None);
+        let pool = Address::from_low_u64_be(0x3333);
+
+        // one word serves every read: bit 0 marks tick 0 initialized in the bitmap, the upper
+        // half is the tick's liquidityNet of 1
+        let word = U256::one() | (U256::one() << 128);
+        let (provider, mock) = Provider::mocked();
+        for _ in 0..=TICK_INFO_WORDS {
+            mock.push::<H256, _>(H256::from_uint(&word)).unwrap();
+        }
+        let backend = SharedBackend::spawn_backend(provider, db.clone(), None).await;
+
+        let ticks = prefetch_tick_range(&backend, pool, 1, 0, 10).unwrap();
+        assert_eq!(ticks.len(), 1);
+        assert_eq!((ticks[0].tick, ticks[0].liquidity_net), (0, 1));
+
+        // the bitmap word and the whole tick are in the db, no request left for the swap
+        let pool = h160_to_b160(pool);
+        let resident = |slot: U256| db.db().storage_slot(&pool, &u256_to_ru256(slot));
+        assert_eq!(resident(tick_bitmap_slot(0)), Some(u256_to_ru256(word)));
+        for offset in 0..TICK_INFO_WORDS {
+            assert!(resident(tick_info_slot(0) + offset).is_some());
+        }
+        assert!(resident(tick_info_slot(1)).is_none());
+    }
+}
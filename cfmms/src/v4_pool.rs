@@ -0,0 +1,300 @@
+use ethers::abi::{self, ParamType, Token};
+use ethers::types::{Address, Bytes, H256, I256, U256};
+use ethers::utils::{id, keccak256};
+use fork_database::utils::{h160_to_b160, ru256_to_u256, u256_to_ru256};
+use revm::{
+    db::DatabaseRef,
+    primitives::{Env, ExecutionResult, Output, TransactTo},
+    EVM,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::V4PoolError;
+
+/// Storage slot of `mapping(PoolId => Pool.State) _pools` in the V4 PoolManager
+pub const POOLS_SLOT: u64 = 6;
+/// Offset of `liquidity` inside `Pool.State`
+pub const LIQUIDITY_OFFSET: u64 = 3;
+/// Offset of `mapping(int24 => TickInfo) ticks` inside `Pool.State`
+pub const TICKS_OFFSET: u64 = 4;
+/// Offset of `mapping(int16 => uint256) tickBitmap` inside `Pool.State`
+pub const TICK_BITMAP_OFFSET: u64 = 5;
+
+/// Hook permissions, encoded in the lowest 14 bits of the hook address
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct HookFlags(pub u16);
+
+impl HookFlags {
+    pub const BEFORE_INITIALIZE: u16 = 1 << 13;
+    pub const AFTER_INITIALIZE: u16 = 1 << 12;
+    pub const BEFORE_ADD_LIQUIDITY: u16 = 1 << 11;
+    pub const AFTER_ADD_LIQUIDITY: u16 = 1 << 10;
+    pub const BEFORE_REMOVE_LIQUIDITY: u16 = 1 << 9;
+    pub const AFTER_REMOVE_LIQUIDITY: u16 = 1 << 8;
+    pub const BEFORE_SWAP: u16 = 1 << 7;
+    pub const AFTER_SWAP: u16 = 1 << 6;
+    pub const BEFORE_DONATE: u16 = 1 << 5;
+    pub const AFTER_DONATE: u16 = 1 << 4;
+    pub const BEFORE_SWAP_RETURNS_DELTA: u16 = 1 << 3;
+    pub const AFTER_SWAP_RETURNS_DELTA: u16 = 1 << 2;
+    pub const AFTER_ADD_LIQUIDITY_RETURNS_DELTA: u16 = 1 << 1;
+    pub const AFTER_REMOVE_LIQUIDITY_RETURNS_DELTA: u16 = 1;
+
+    pub fn from_hooks(hooks: Address) -> Self {
+        let bytes = hooks.as_bytes();
+        HookFlags(u16::from_be_bytes([bytes[18], bytes[19]]) & 0x3fff)
+    }
+
+    pub fn contains(&self, flag: u16) -> bool {
+        self.0 & flag != 0
+    }
+
+    /// Whether the hook can change the outcome of a swap, in which case local V3 math is not enough
+    pub fn affects_swap(&self) -> bool {
+        self.contains(Self::BEFORE_SWAP)
+            || self.contains(Self::AFTER_SWAP)
+            || self.contains(Self::BEFORE_SWAP_RETURNS_DELTA)
+            || self.contains(Self::AFTER_SWAP_RETURNS_DELTA)
+    }
+}
+
+/// The V4 `PoolKey`, identifying a pool inside the singleton PoolManager
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct V4PoolKey {
+    pub currency_0: Address,
+    pub currency_1: Address,
+    pub fee: u32,
+    pub tick_spacing: i32,
+    pub hooks: Address,
+}
+
+impl V4PoolKey {
+    fn to_token(&self) -> Token {
+        Token::Tuple(vec![
+            Token::Address(self.currency_0),
+            Token::Address(self.currency_1),
+            Token::Uint(U256::from(self.fee)),
+            Token::Int(I256::from(self.tick_spacing).into_raw()),
+            Token::Address(self.hooks),
+        ])
+    }
+
+    /// `PoolId` is `keccak256(abi.encode(key))`
+    pub fn pool_id(&self) -> H256 {
+        let encoded = match self.to_token() {
+            Token::Tuple(tokens) => abi::encode(&tokens),
+            _ => unreachable!(),
+        };
+        H256::from(keccak256(encoded))
+    }
+}
+
+/// A Uniswap V4 pool, its state lives in the PoolManager singleton rather than in its own contract
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct V4Pool {
+    pub pool_manager: Address,
+    pub key: V4PoolKey,
+    pub hook_flags: HookFlags,
+    pub sqrt_price_x96: U256,
+    pub tick: i32,
+    pub protocol_fee: u32,
+    pub lp_fee: u32,
+    pub liquidity: u128,
+}
+
+impl V4Pool {
+    pub fn new(pool_manager: Address, key: V4PoolKey) -> Self {
+        Self {
+            pool_manager,
+            key,
+            hook_flags: HookFlags::from_hooks(key.hooks),
+            sqrt_price_x96: U256::zero(),
+            tick: 0,
+            protocol_fee: 0,
+            lp_fee: 0,
+            liquidity: 0,
+        }
+    }
+
+    pub fn pool_id(&self) -> H256 {
+        self.key.pool_id()
+    }
+
+    /// Base storage slot of this pool's `Pool.State` in the PoolManager
+    pub fn state_slot(&self) -> U256 {
+        let encoded = abi::encode(&[
+            Token::FixedBytes(self.pool_id().as_bytes().to_vec()),
+            Token::Uint(U256::from(POOLS_SLOT)),
+        ]);
+        U256::from_big_endian(&keccak256(encoded))
+    }
+
+    /// Storage slot of the tick bitmap word at `word_pos`
+    pub fn tick_bitmap_slot(&self, word_pos: i16) -> U256 {
+        let encoded = abi::encode(&[
+            Token::Int(I256::from(word_pos).into_raw()),
+            Token::Uint(self.state_slot() + TICK_BITMAP_OFFSET),
+        ]);
+        U256::from_big_endian(&keccak256(encoded))
+    }
+
+    /// Storage slot of the `TickInfo` for `tick`, the first word holds `liquidityGross | liquidityNet`
+    pub fn tick_info_slot(&self, tick: i32) -> U256 {
+        let encoded = abi::encode(&[
+            Token::Int(I256::from(tick).into_raw()),
+            Token::Uint(self.state_slot() + TICKS_OFFSET),
+        ]);
+        U256::from_big_endian(&keccak256(encoded))
+    }
+
+    /// Refresh slot0 and liquidity straight from the PoolManager storage in the fork db
+    pub fn sync_from_db<DB>(&mut self, db: &DB) -> Result<(), V4PoolError>
+    where
+        DB: DatabaseRef,
+        DB::Error: std::fmt::Debug,
+    {
+        let manager = h160_to_b160(self.pool_manager);
+        let base = self.state_slot();
+
+        let slot0 = db
+            .storage(manager, u256_to_ru256(base))
+            .map_err(|e| V4PoolError::DatabaseError(format!("{:?}", e)))?;
+        let liquidity = db
+            .storage(manager, u256_to_ru256(base + LIQUIDITY_OFFSET))
+            .map_err(|e| V4PoolError::DatabaseError(format!("{:?}", e)))?;
+
+        let slot0 = ru256_to_u256(slot0);
+        if slot0.is_zero() {
+            return Err(V4PoolError::PoolNotInitialized(self.pool_id()));
+        }
+
+        let (sqrt_price_x96, tick, protocol_fee, lp_fee) = decode_slot0(slot0);
+        self.sqrt_price_x96 = sqrt_price_x96;
+        self.tick = tick;
+        self.protocol_fee = protocol_fee;
+        self.lp_fee = lp_fee;
+        self.liquidity = ru256_to_u256(liquidity).low_u128();
+
+        Ok(())
+    }
+
+    /// Hookless pools (or pools whose hooks don't touch swaps) can be quoted with plain V3 math
+    pub fn requires_evm_simulation(&self) -> bool {
+        self.hook_flags.affects_swap()
+    }
+
+    /// Simulate an exact-in swap through the PoolManager, hooks included, by calling a
+    /// deployed V4 Quoter on top of the fork db. Nothing is committed to `db`.
+    pub fn simulate_swap<DB>(
+        &self,
+        db: DB,
+        env: &Env,
+        quoter: Address,
+        token_in: Address,
+        amount_in: u128,
+        hook_data: Bytes,
+    ) -> Result<U256, V4PoolError>
+    where
+        DB: DatabaseRef,
+        DB::Error: std::fmt::Debug,
+    {
+        let zero_for_one = if token_in == self.key.currency_0 {
+            true
+        } else if token_in == self.key.currency_1 {
+            false
+        } else {
+            return Err(V4PoolError::TokenNotInPool(token_in));
+        };
+
+        let params = Token::Tuple(vec![
+            self.key.to_token(),
+            Token::Bool(zero_for_one),
+            Token::Uint(U256::from(amount_in)),
+            Token::Bytes(hook_data.to_vec()),
+        ]);
+        let mut calldata = id(
+            "quoteExactInputSingle(((address,address,uint24,int24,address),bool,uint128,bytes))",
+        )
+        .to_vec();
+        calldata.extend(abi::encode(&[params]));
+
+        let mut evm = EVM::new();
+        evm.database(db);
+        evm.env = env.clone();
+        evm.env.tx.transact_to = TransactTo::Call(h160_to_b160(quoter));
+        evm.env.tx.data = calldata.into();
+        evm.env.tx.value = revm::primitives::U256::ZERO;
+        evm.env.tx.gas_price = revm::primitives::U256::ZERO;
+        evm.env.tx.gas_priority_fee = None;
+        evm.env.tx.gas_limit = 5_000_000;
+        evm.env.tx.nonce = None;
+
+        let result = evm
+            .transact_ref()
+            .map_err(|e| V4PoolError::EvmError(format!("{:?}", e)))?;
+
+        let output = match result.result {
+            ExecutionResult::Success {
+                output: Output::Call(output),
+                ..
+            } => output,
+            ExecutionResult::Revert { output, .. } => {
+                return Err(V4PoolError::SwapReverted(output.into()))
+            }
+            other => return Err(V4PoolError::EvmError(format!("{:?}", other))),
+        };
+
+        let tokens = abi::decode(&[ParamType::Uint(256), ParamType::Uint(256)], &output)?;
+        tokens[0]
+            .clone()
+            .into_uint()
+            .ok_or(V4PoolError::UnexpectedOutput)
+    }
+}
+
+/// Unpack `Slot0`: sqrtPriceX96 (160) | tick (int24) | protocolFee (uint24) | lpFee (uint24)
+pub fn decode_slot0(slot0: U256) -> (U256, i32, u32, u32) {
+    let mask_160 = (U256::one() << 160) - 1;
+    let mask_24 = U256::from(0xffffffu32);
+
+    let sqrt_price_x96 = slot0 & mask_160;
+    let raw_tick = ((slot0 >> 160) & mask_24).as_u32();
+    // sign extend the int24
+    let tick = ((raw_tick << 8) as i32) >> 8;
+    let protocol_fee = ((slot0 >> 184) & mask_24).as_u32();
+    let lp_fee = ((slot0 >> 208) & mask_24).as_u32();
+
+    (sqrt_price_x96, tick, protocol_fee, lp_fee)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_decode_slot0_negative_tick() {
+        let sqrt_price = U256::from(79228162514264337593543950336u128);
+        let tick: i32 = -200;
+        let slot0 = sqrt_price
+            | (U256::from((tick as u32) & 0xffffff) << 160)
+            | (U256::from(100u32) << 184)
+            | (U256::from(3000u32) << 208);
+
+        assert_eq!(decode_slot0(slot0), (sqrt_price, -200, 100, 3000));
+    }
+
+    #[test]
+    fn test_hook_flags() {
+        let hookless = HookFlags::from_hooks(Address::zero());
+        assert!(!hookless.affects_swap());
+
+        // lowest bits 0x00c0 = beforeSwap | afterSwap
+        let hooks = Address::from_str("0x00000000000000000000000000000000000000c0").unwrap();
+        let flags = HookFlags::from_hooks(hooks);
+        assert!(flags.contains(HookFlags::BEFORE_SWAP));
+        assert!(flags.contains(HookFlags::AFTER_SWAP));
+        assert!(!flags.contains(HookFlags::BEFORE_INITIALIZE));
+        assert!(flags.affects_swap());
+    }
+}
@@ -1,17 +1,27 @@
 use super::state_diff::{get_from_txs, StateDiffError};
-use crate::types::{BlockPayload, RwLockMap};
+use super::trace_client::TraceClient;
+use crate::types::BlockPayload;
 use anyhow::Result;
 use artemis::types::{Collector, CollectorStream};
 use async_trait::async_trait;
 use ethers::{
     providers::{Middleware, PubsubClient},
-    types::{Block, BlockId, Transaction, H256, U64},
+    types::{AccountDiff, Block, BlockId, Filter, Transaction, H160, H256, U64},
 };
-use log::{error, info};
+use fork_database::{
+    blockchain_db::{BlockchainDb, DEFAULT_IDLE_CODE_BLOCKS, DEFAULT_IDLE_CODE_MIN_LEN},
+    hot_slots::HotSlots,
+    shared_backend::SharedBackend,
+    stale::{BaseBlock, ForkHead},
+};
+use log::{debug, error, info, warn};
 use parking_lot::RwLock;
 use qilin_cfmms::batch_requests;
+use qilin_cfmms::events::pool_event_topics;
 use qilin_cfmms::pool::Pool;
+use qilin_cfmms::registry::PoolRegistry;
 use rusty::prelude::fork_factory::ForkFactory;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::runtime::Runtime;
@@ -19,12 +29,110 @@ use tokio_stream::StreamExt;
 
 type PoolVariant = cfmms::dex::DexVariant;
 
+/// How the collector keeps the tracked pools current
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PoolSyncMode {
+    /// trace the block's txs and read the touched pools' state again
+    #[default]
+    Trace,
+    /// apply the pools' `Sync` / `Swap` / `Mint` / `Burn` logs of the block, a single
+    /// `eth_getLogs`, see [qilin_cfmms::events]
+    Logs,
+}
+
+/// Work done once per new block, after the tracked pools were synced
+pub trait BlockHook: Send + Sync {
+    /// `diff` is the block's state diff when the pools were synced by tracing it
+    fn on_block(&self, block: &Block<Transaction>, diff: Option<&BTreeMap<H160, AccountDiff>>);
+}
+
+/// Keeps a [HotSlots] profile learning from the blocks and loads its hot slots into the fork db at
+/// the start of each block
+pub struct PrefetchHotSlots {
+    pub profile: Arc<HotSlots>,
+    pub backend: SharedBackend,
+}
+
+impl BlockHook for PrefetchHotSlots {
+    fn on_block(&self, block: &Block<Transaction>, diff: Option<&BTreeMap<H160, AccountDiff>>) {
+        let number = block.number.unwrap_or_default().as_u64();
+        if let Some(diff) = diff {
+            self.profile.record_block_diff(diff, number);
+        }
+        self.profile.prune(number);
+        match self.profile.prefetch(&self.backend, number) {
+            Ok(slots) => debug!("Prefetched {} hot slots for block {}", slots, number),
+            Err(e) => warn!("Failed to prefetch hot slots: {:?}", e),
+        }
+    }
+}
+
+/// Compresses the fork db's code of at least `min_len` bytes that wasn't used over the last
+/// `every` blocks, see [MemDb::compress_idle_code]
+///
+/// [MemDb::compress_idle_code]: fork_database::blockchain_db::MemDb::compress_idle_code
+pub struct CompressIdleCode {
+    pub db: BlockchainDb,
+    pub min_len: usize,
+    pub every: u64,
+}
+
+impl CompressIdleCode {
+    pub fn new(db: BlockchainDb) -> Self {
+        Self {
+            db,
+            min_len: DEFAULT_IDLE_CODE_MIN_LEN,
+            every: DEFAULT_IDLE_CODE_BLOCKS,
+        }
+    }
+
+    pub fn with_min_len(mut self, min_len: usize) -> Self {
+        self.min_len = min_len;
+        self
+    }
+
+    pub fn with_every(mut self, every: u64) -> Self {
+        self.every = every;
+        self
+    }
+}
+
+impl BlockHook for CompressIdleCode {
+    fn on_block(&self, block: &Block<Transaction>, _diff: Option<&BTreeMap<H160, AccountDiff>>) {
+        let number = block.number.unwrap_or_default().as_u64();
+        if number % self.every.max(1) != 0 {
+            return;
+        }
+        let compressed = self.db.db().compress_idle_code(self.min_len);
+        debug!(
+            "Compressed {} idle code blobs at block {}",
+            compressed, number
+        );
+    }
+}
+
+/// Moves the [ForkHead] to each block once the pools are synced to it, so bundles built on the
+/// block before are refused
+impl BlockHook for ForkHead {
+    fn on_block(&self, block: &Block<Transaction>, _diff: Option<&BTreeMap<H160, AccountDiff>>) {
+        if let (Some(number), Some(hash)) = (block.number, block.hash) {
+            self.advance(BaseBlock::new(number.as_u64(), hash));
+        }
+    }
+}
+
 pub struct QilinBlockCollector<M> {
     provider: Arc<M>,
+    tracer: Arc<dyn TraceClient>,
     block_hash: Block<H256>,
     block: RwLock<Block<Transaction>>,
     fork_factory: Arc<ForkFactory>,
-    all_pools: Arc<RwLockMap>,
+    /// synced and committed once per block, the ticks of its V3 pools too in [PoolSyncMode::Logs]
+    registry: Arc<PoolRegistry>,
+    sync_mode: PoolSyncMode,
+    /// last block whose logs were applied, and the pools they changed
+    last_applied: RwLock<Option<(H256, Vec<H160>)>>,
+    hooks: Vec<Arc<dyn BlockHook>>,
 }
 
 #[derive(Error, Debug)]
@@ -45,6 +153,36 @@ where
     M: Middleware + 'static,
     M::Provider: PubsubClient,
 {
+    pub fn new(
+        provider: Arc<M>,
+        tracer: Arc<dyn TraceClient>,
+        fork_factory: Arc<ForkFactory>,
+        registry: Arc<PoolRegistry>,
+    ) -> Self {
+        Self {
+            provider,
+            tracer,
+            block_hash: Block::default(),
+            block: RwLock::new(Block::default()),
+            fork_factory,
+            registry,
+            sync_mode: PoolSyncMode::default(),
+            last_applied: RwLock::new(None),
+            hooks: vec![],
+        }
+    }
+
+    pub fn with_sync_mode(mut self, sync_mode: PoolSyncMode) -> Self {
+        self.sync_mode = sync_mode;
+        self
+    }
+
+    /// Run `hook` on every new block, hooks run in the order they were added
+    pub fn with_hook(mut self, hook: Arc<dyn BlockHook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
     /// Update the block hash and block transactions
     async fn process_block_update(
         &self,
@@ -70,8 +208,11 @@ where
 
     // TODO: switch the trace_call_many to trace_replay_block_transactions
     // See https://docs.rs/ethers/latest/ethers/providers/trait.Middleware.html#method.trace_replay_block_transactions
-    /// Update the the local pool state
-    async fn update_pools(&self, meat: &Vec<Transaction>) -> Result<(), BlockCollectorError<M>> {
+    /// Update the the local pool state, returns the state diff of the block
+    async fn update_pools(
+        &self,
+        meat: &Vec<Transaction>,
+    ) -> Result<BTreeMap<H160, AccountDiff>, BlockCollectorError<M>> {
         // get last block number to do the tracing
         let last_block_num = self.block.read().number.unwrap() - U64::from(1);
 
@@ -79,8 +220,8 @@ where
         let state_diffs =
             // take the state of last block and trace diffs
             if let Some(state_diffs) = get_from_txs(
-                &self.provider.clone(),
-                &meat,
+                self.tracer.as_ref(),
+                meat,
                 ethers::types::BlockNumber::Number(last_block_num)
             ).await {
                 state_diffs
@@ -90,14 +231,21 @@ where
                 ));
             };
 
-        let read_pool = self.all_pools.read();
+        let touched: Vec<Pool> = state_diffs
+            .keys()
+            .filter_map(|address| self.registry.get(address))
+            .collect();
+        self.refresh_pools(touched).await;
+
+        Ok(state_diffs)
+    }
 
+    /// Read the state of `pools` from storage again
+    async fn refresh_pools(&self, pools: Vec<Pool>) {
         // get v2 and v3 pools that were touched
-        let (mut touched_v3_pools, mut touched_v2_pools): (Vec<Pool>, Vec<Pool>) = state_diffs
-            .keys()
-            .filter_map(|e| read_pool.get(e).map(|p| (*p.value())))
+        let (mut touched_v3_pools, mut touched_v2_pools): (Vec<Pool>, Vec<Pool>) = pools
+            .into_iter()
             .partition(|pool| matches!(pool.pool_variant, PoolVariant::UniswapV3));
-        drop(read_pool);
 
         // batch update v3 pools
         let v3_pool_slice = touched_v3_pools.as_mut_slice();
@@ -109,12 +257,9 @@ where
         .unwrap_or_else(|e| {
             error!("Error: {}", e);
         });
-        let write_pool = self.all_pools.write();
-        v3_pool_slice.to_vec().into_iter().for_each(|pool| {
-            write_pool.insert(pool.address, pool);
-        });
-        drop(write_pool);
-        info!("write_pool: {:?}", self.all_pools);
+        v3_pool_slice
+            .iter()
+            .for_each(|pool| self.registry.insert(*pool));
 
         // batch update v2 pools
         let v2_pool_slice = touched_v2_pools.as_mut_slice();
@@ -126,12 +271,50 @@ where
         .unwrap_or_else(|e| {
             error!("Error: {}", e);
         });
-        let write_pool = self.all_pools.write();
-        v2_pool_slice.to_vec().into_iter().for_each(|pool| {
-            write_pool.insert(pool.address, pool);
-        });
-        drop(write_pool);
+        v2_pool_slice
+            .iter()
+            .for_each(|pool| self.registry.insert(*pool));
+    }
 
+    /// Update the local pool state from the pool logs of the block. If the block doesn't build on
+    /// the last one applied, that one was reorged out and the pools it changed are read again.
+    async fn update_pools_from_logs(
+        &self,
+        block_hash: &H256,
+    ) -> Result<(), BlockCollectorError<M>> {
+        let filter = Filter::new()
+            .at_block_hash(*block_hash)
+            .topic0(pool_event_topics());
+        let logs = self
+            .provider
+            .get_logs(&filter)
+            .await
+            .map_err(BlockCollectorError::MiddlewareError)?;
+
+        let parent_hash = self.block.read().parent_hash;
+        let orphaned = match &*self.last_applied.read() {
+            Some((hash, updated)) if *hash != parent_hash => updated.clone(),
+            _ => vec![],
+        };
+
+        let applied = self.registry.apply_logs(&logs);
+        info!(
+            "Applied {} pool logs to {} pools",
+            logs.len(),
+            applied.updated.len()
+        );
+
+        let mut stale = applied.stale;
+        stale.extend(orphaned);
+        if !stale.is_empty() {
+            warn!("Reading {} pools again after a reorg", stale.len());
+            let stale: Vec<Pool> = stale
+                .iter()
+                .filter_map(|address| self.registry.get(address))
+                .collect();
+            self.refresh_pools(stale).await;
+        }
+        *self.last_applied.write() = Some((*block_hash, applied.updated));
         Ok(())
     }
 
@@ -140,7 +323,25 @@ where
         block_hash: &H256,
     ) -> Result<(), BlockCollectorError<M>> {
         let block_transactions = self.process_block_update(block_hash).await?;
-        self.update_pools(&block_transactions).await?;
+        let diff = match self.sync_mode {
+            PoolSyncMode::Trace => Some(self.update_pools(&block_transactions).await?),
+            PoolSyncMode::Logs => {
+                self.update_pools_from_logs(block_hash).await?;
+                None
+            }
+        };
+        let block = self.block.read().clone();
+        let change = self
+            .registry
+            .commit(block.number.map(|number| number.as_u64()));
+        debug!(
+            "Committed pool view {} with {} pools updated",
+            change.epoch,
+            change.updated.len()
+        );
+        for hook in &self.hooks {
+            hook.on_block(&block, diff.as_ref());
+        }
         Ok(())
     }
 }
@@ -166,7 +367,7 @@ where
 
                 return Some(BlockPayload {
                     block_hash: self.block_hash.clone(),
-                    all_pools: self.all_pools.clone(),
+                    pools: self.registry.view(),
                 });
             } else {
                 return None;
@@ -176,3 +377,45 @@ where
         Ok(Box::pin(block_stream))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fork_database::blockchain_db::BlockchainDbMeta;
+    use revm::primitives::{AccountInfo, Bytecode, B160};
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_compress_idle_code() {
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        let db = BlockchainDb::new(meta, None);
+        let code = Bytecode::new_raw(vec![0x60, 0x01, 0x60, 0x00, 0x55].repeat(40).into());
+        db.db().do_insert_account(
+            B160::from_low_u64_be(1),
+            AccountInfo {
+                code_hash: code.hash(),
+                code: Some(code.to_checked()),
+                ..Default::default()
+            },
+        );
+        let size = db.db().code_size();
+        let hook = CompressIdleCode::new(db.clone())
+            .with_min_len(100)
+            .with_every(2);
+        let block = |n: u64| Block {
+            number: Some(U64::from(n)),
+            ..Default::default()
+        };
+
+        // used since stored, then idle for a round
+        hook.on_block(&block(2), None);
+        hook.on_block(&block(3), None);
+        assert_eq!(db.db().code_size(), size);
+        hook.on_block(&block(4), None);
+        assert!(db.db().code_size() < size);
+    }
+}
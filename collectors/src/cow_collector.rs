@@ -0,0 +1,184 @@
+use anyhow::Result;
+use artemis::types::{Collector, CollectorStream};
+use async_trait::async_trait;
+use ethers::types::{Address, U256};
+use futures::StreamExt;
+use log::{error, info};
+use parking_lot::Mutex;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Default CoW Protocol order book endpoint for Ethereum mainnet
+pub const COW_MAINNET_API: &str = "https://api.cow.fi/mainnet";
+
+/// Side of a CoW order, `Sell` orders fix the sell amount, `Buy` orders fix the buy amount
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderKind {
+    Sell,
+    Buy,
+}
+
+/// A solvable order as returned by the CoW Protocol `auction` endpoint
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CowOrder {
+    pub uid: String,
+    pub owner: Address,
+    pub sell_token: Address,
+    pub buy_token: Address,
+    #[serde(deserialize_with = "deserialize_dec_u256")]
+    pub sell_amount: U256,
+    #[serde(deserialize_with = "deserialize_dec_u256")]
+    pub buy_amount: U256,
+    #[serde(default, deserialize_with = "deserialize_dec_u256")]
+    pub fee_amount: U256,
+    pub kind: OrderKind,
+    pub partially_fillable: bool,
+    pub valid_to: u32,
+}
+
+/// The current batch auction, only the fields we consume are deserialized
+#[derive(Debug, Clone, Deserialize)]
+struct Auction {
+    #[serde(default)]
+    id: Option<u64>,
+    orders: Vec<CowOrder>,
+}
+
+#[derive(Error, Debug)]
+pub enum CowCollectorError {
+    #[error("Order book request failed")]
+    RequestError(#[from] reqwest::Error),
+    #[error("Order book returned status {0}")]
+    StatusError(u16),
+}
+
+/// Polls the CoW Protocol order book and emits every solvable order once
+pub struct CowOrderCollector {
+    client: reqwest::Client,
+    api_url: String,
+    poll_interval: Duration,
+    // uids already emitted that are still part of the current auction
+    seen: Mutex<HashSet<String>>,
+}
+
+impl CowOrderCollector {
+    pub fn new(api_url: impl Into<String>, poll_interval: Duration) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            api_url: api_url.into(),
+            poll_interval,
+            seen: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Collector pointed at the public mainnet order book, polling once per slot
+    pub fn mainnet() -> Self {
+        Self::new(COW_MAINNET_API, Duration::from_secs(12))
+    }
+
+    /// Fetch the current auction and return the orders that haven't been emitted yet
+    pub async fn fetch_new_orders(&self) -> Result<Vec<CowOrder>, CowCollectorError> {
+        let url = format!("{}/api/v1/auction", self.api_url.trim_end_matches('/'));
+        let response = self.client.get(url).send().await?;
+
+        if !response.status().is_success() {
+            return Err(CowCollectorError::StatusError(response.status().as_u16()));
+        }
+
+        let auction: Auction = response.json().await?;
+        info!(
+            "CoW auction {:?} with {} solvable orders",
+            auction.id,
+            auction.orders.len()
+        );
+
+        Ok(self.filter_unseen(auction.orders))
+    }
+
+    /// Drop already emitted orders, and forget uids that left the auction (filled, expired or cancelled)
+    fn filter_unseen(&self, orders: Vec<CowOrder>) -> Vec<CowOrder> {
+        let mut seen = self.seen.lock();
+        let live: HashSet<String> = orders.iter().map(|o| o.uid.clone()).collect();
+        seen.retain(|uid| live.contains(uid));
+
+        orders
+            .into_iter()
+            .filter(|order| seen.insert(order.uid.clone()))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Collector<CowOrder> for CowOrderCollector {
+    async fn get_event_stream(&self) -> Result<CollectorStream<'_, CowOrder>> {
+        let stream = futures::stream::unfold(true, move |first| async move {
+            if !first {
+                tokio::time::sleep(self.poll_interval).await;
+            }
+            let orders = match self.fetch_new_orders().await {
+                Ok(orders) => orders,
+                Err(e) => {
+                    error!("Error polling CoW order book: {:?}", e);
+                    Vec::new()
+                }
+            };
+            Some((futures::stream::iter(orders), false))
+        })
+        .flatten();
+
+        Ok(Box::pin(stream))
+    }
+}
+
+/// The order book encodes amounts as decimal strings
+fn deserialize_dec_u256<'de, D>(deserializer: D) -> Result<U256, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    U256::from_dec_str(&s).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUCTION: &str = r#"{
+        "id": 1,
+        "block": 17444939,
+        "orders": [{
+            "uid": "0x01",
+            "owner": "0x0000000000000000000000000000000000000001",
+            "sellToken": "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+            "buyToken": "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+            "sellAmount": "1000000000000000000",
+            "buyAmount": "1700000000",
+            "feeAmount": "0",
+            "kind": "sell",
+            "partiallyFillable": false,
+            "validTo": 4294967295
+        }]
+    }"#;
+
+    #[test]
+    fn test_parse_auction_and_dedup() {
+        let auction: Auction = serde_json::from_str(AUCTION).unwrap();
+        assert_eq!(auction.orders[0].kind, OrderKind::Sell);
+        assert_eq!(
+            auction.orders[0].sell_amount,
+            U256::exp10(18),
+            "decimal amounts should be parsed"
+        );
+
+        let collector = CowOrderCollector::mainnet();
+        assert_eq!(collector.filter_unseen(auction.orders.clone()).len(), 1);
+        assert_eq!(collector.filter_unseen(auction.orders.clone()).len(), 0);
+        // order leaves the auction, then comes back
+        assert_eq!(collector.filter_unseen(vec![]).len(), 0);
+        assert_eq!(collector.filter_unseen(auction.orders).len(), 1);
+    }
+}
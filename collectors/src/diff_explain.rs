@@ -0,0 +1,529 @@
+//! Human readable rendering of state diffs
+//!
+//! Raw state diffs are a wall of slots and words. [explain] turns them into per account changes
+//! a person can read: pool reserves and prices, token balances and allowances of the accounts
+//! touched by the diff, ETH balances and nonces, with token symbols and pool labels in place of
+//! addresses. Slots it can't attribute are shown raw.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+use ethers::abi::{self, ParamType, Token};
+use ethers::prelude::*;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::utils::format_units;
+use fork_database::storage_layout::{
+    mapping_slot, nested_mapping_slot, unpack, unpack_signed, Field, V2_RESERVES, V2_RESERVES_SLOT,
+    V3_LIQUIDITY, V3_LIQUIDITY_SLOT, V3_SLOT0, V3_SLOT0_SLOT,
+};
+use qilin_cfmms::pool::{Pool, PoolVariant};
+
+/// `balanceOf` mapping bases tried for tokens without a known layout, OpenZeppelin's is 0 and
+/// WETH9's is 3. Allowances are assumed to be declared right after the balances.
+const GUESSED_BALANCE_SLOTS: [u64; 10] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+const SYMBOL_SELECTOR: [u8; 4] = [0x95, 0xd8, 0x9b, 0x41];
+const DECIMALS_SELECTOR: [u8; 4] = [0x31, 0x3c, 0xe5, 0x67];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenInfo {
+    pub symbol: String,
+    pub decimals: u8,
+    /// `balanceOf` mapping slot, guessed from [GUESSED_BALANCE_SLOTS] when unknown
+    pub balance_slot: Option<U256>,
+    /// `allowance` mapping slot, the one after the balances when unknown
+    pub allowance_slot: Option<U256>,
+}
+
+impl TokenInfo {
+    pub fn new(symbol: impl Into<String>, decimals: u8) -> Self {
+        Self {
+            symbol: symbol.into(),
+            decimals,
+            balance_slot: None,
+            allowance_slot: None,
+        }
+    }
+
+    pub fn with_balance_slot(mut self, slot: impl Into<U256>) -> Self {
+        self.balance_slot = Some(slot.into());
+        self
+    }
+
+    pub fn with_allowance_slot(mut self, slot: impl Into<U256>) -> Self {
+        self.allowance_slot = Some(slot.into());
+        self
+    }
+
+    fn amount(&self, value: U256) -> String {
+        let amount =
+            format_units(value, self.decimals as u32).unwrap_or_else(|_| value.to_string());
+        format!("{} {}", amount, self.symbol)
+    }
+}
+
+/// What [explain] knows about the addresses in a diff
+#[derive(Debug, Clone, Default)]
+pub struct ExplainContext {
+    tokens: HashMap<Address, TokenInfo>,
+    pools: HashMap<Address, Pool>,
+    labels: HashMap<Address, String>,
+}
+
+impl ExplainContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_token(mut self, address: Address, token: TokenInfo) -> Self {
+        self.tokens.insert(address, token);
+        self
+    }
+
+    pub fn with_pools(mut self, pools: impl IntoIterator<Item = Pool>) -> Self {
+        self.pools
+            .extend(pools.into_iter().map(|pool| (pool.address, pool)));
+        self
+    }
+
+    /// Name shown instead of `address`, e.g. "searcher" or "sandwich contract"
+    pub fn with_label(mut self, address: Address, label: impl Into<String>) -> Self {
+        self.labels.insert(address, label.into());
+        self
+    }
+
+    pub fn token(&self, address: &Address) -> Option<&TokenInfo> {
+        self.tokens.get(address)
+    }
+
+    /// Fetch symbol and decimals of the tokens of the known pools, and of `addresses`, that
+    /// aren't known yet. Addresses that don't answer like an ERC20 are skipped.
+    pub async fn resolve_tokens<M: Middleware + 'static>(
+        &mut self,
+        provider: Arc<M>,
+        addresses: impl IntoIterator<Item = Address>,
+    ) {
+        let mut wanted: Vec<Address> = addresses.into_iter().collect();
+        for pool in self.pools.values() {
+            wanted.extend([pool.token_0, pool.token_1]);
+        }
+        for address in wanted {
+            if self.tokens.contains_key(&address) {
+                continue;
+            }
+            if let Some(token) = fetch_token_info(provider.clone(), address).await {
+                self.tokens.insert(address, token);
+            }
+        }
+    }
+
+    /// Label, pool description or token symbol of `address`, the address itself otherwise
+    pub fn label(&self, address: &Address) -> String {
+        if let Some(label) = self.labels.get(address) {
+            return label.clone();
+        }
+        if let Some(pool) = self.pools.get(address) {
+            return format!(
+                "{:?} {}/{} ({:?})",
+                pool.pool_variant,
+                self.symbol(&pool.token_0),
+                self.symbol(&pool.token_1),
+                address
+            );
+        }
+        if let Some(token) = self.tokens.get(address) {
+            return format!("{} ({:?})", token.symbol, address);
+        }
+        format!("{:?}", address)
+    }
+
+    fn symbol(&self, address: &Address) -> String {
+        self.tokens
+            .get(address)
+            .map(|token| token.symbol.clone())
+            .unwrap_or_else(|| format!("{:?}", address))
+    }
+
+    fn amount(&self, token: &Address, value: U256) -> String {
+        match self.tokens.get(token) {
+            Some(token) => token.amount(value),
+            None => format!("{} {}", value, self.symbol(token)),
+        }
+    }
+}
+
+/// Symbol and decimals of an ERC20, tolerating `bytes32` symbols like MKR's
+pub async fn fetch_token_info<M: Middleware + 'static>(
+    provider: Arc<M>,
+    token: Address,
+) -> Option<TokenInfo> {
+    let call = |selector: [u8; 4]| {
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(token)
+            .data(Bytes::from(selector.to_vec()))
+            .into();
+        tx
+    };
+    let symbol = provider.call(&call(SYMBOL_SELECTOR), None).await.ok()?;
+    let decimals = provider.call(&call(DECIMALS_SELECTOR), None).await.ok()?;
+
+    let symbol = match abi::decode(&[ParamType::String], &symbol) {
+        Ok(tokens) => match tokens.into_iter().next() {
+            Some(Token::String(symbol)) => symbol,
+            _ => return None,
+        },
+        Err(_) if symbol.len() == 32 => String::from_utf8_lossy(&symbol)
+            .trim_end_matches('\0')
+            .to_string(),
+        Err(_) => return None,
+    };
+    if decimals.len() < 32 {
+        return None;
+    }
+    let decimals = U256::from_big_endian(&decimals[..32]);
+    if decimals > U256::from(u8::MAX) {
+        return None;
+    }
+    Some(TokenInfo::new(symbol, decimals.as_u32() as u8))
+}
+
+/// One change of an account, already rendered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub what: String,
+    pub from: String,
+    pub to: String,
+}
+
+impl Change {
+    fn new(what: impl Into<String>, from: impl Into<String>, to: impl Into<String>) -> Self {
+        Self {
+            what: what.into(),
+            from: from.into(),
+            to: to.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountExplanation {
+    pub address: Address,
+    pub label: String,
+    pub changes: Vec<Change>,
+}
+
+/// Explanation of a whole diff, [Display](fmt::Display) renders it one account per block
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DiffExplanation {
+    pub accounts: Vec<AccountExplanation>,
+}
+
+impl fmt::Display for DiffExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for account in &self.accounts {
+            writeln!(f, "{}", account.label)?;
+            for change in &account.changes {
+                writeln!(f, "  {}: {} -> {}", change.what, change.from, change.to)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a decoded storage slot holds
+enum SlotMeaning {
+    Balance(Address),
+    Allowance(Address, Address),
+}
+
+/// Explain every account of `diffs` that changed
+pub fn explain(
+    diffs: &BTreeMap<Address, AccountDiff>,
+    context: &ExplainContext,
+) -> DiffExplanation {
+    // balances and allowances are only decoded for the accounts taking part in the diff
+    let mut holders: Vec<Address> = diffs.keys().copied().collect();
+    holders.extend(context.labels.keys().copied());
+    holders.sort();
+    holders.dedup();
+
+    let accounts = diffs
+        .iter()
+        .filter_map(|(address, diff)| {
+            let changes = explain_account(address, diff, context, &holders);
+            (!changes.is_empty()).then(|| AccountExplanation {
+                address: *address,
+                label: context.label(address),
+                changes,
+            })
+        })
+        .collect();
+    DiffExplanation { accounts }
+}
+
+fn explain_account(
+    address: &Address,
+    diff: &AccountDiff,
+    context: &ExplainContext,
+    holders: &[Address],
+) -> Vec<Change> {
+    let mut changes = vec![];
+    if let Some((from, to)) = changed(&diff.balance) {
+        changes.push(Change::new(
+            "balance",
+            format_eth(from),
+            format!("{} ({})", format_eth(to), signed_delta(from, to, 18)),
+        ));
+    }
+    if let Some((from, to)) = changed(&diff.nonce) {
+        changes.push(Change::new("nonce", from.to_string(), to.to_string()));
+    }
+    match &diff.code {
+        Diff::Born(_) => changes.push(Change::new("code", "none", "deployed")),
+        Diff::Died(_) => changes.push(Change::new("code", "deployed", "none")),
+        Diff::Changed(_) => changes.push(Change::new("code", "old", "new")),
+        Diff::Same => {}
+    }
+
+    let token_slots = context
+        .tokens
+        .get(address)
+        .map(|token| token_slot_meanings(token, holders))
+        .unwrap_or_default();
+    let pool = context.pools.get(address);
+
+    for (slot, value) in &diff.storage {
+        let Some((from, to)) = changed(value) else {
+            continue;
+        };
+        let (from, to) = (h256_to_u256(from), h256_to_u256(to));
+        let slot = h256_to_u256(*slot);
+
+        if let Some(change) = pool.and_then(|pool| explain_pool_slot(pool, slot, from, to, context))
+        {
+            changes.push(change);
+            continue;
+        }
+        match token_slots.get(&slot) {
+            Some(SlotMeaning::Balance(holder)) => {
+                let token = &context.tokens[address];
+                changes.push(Change::new(
+                    format!("balanceOf[{}]", context.label(holder)),
+                    token.amount(from),
+                    format!(
+                        "{} ({})",
+                        token.amount(to),
+                        signed_delta(from, to, token.decimals)
+                    ),
+                ));
+            }
+            Some(SlotMeaning::Allowance(owner, spender)) => {
+                let token = &context.tokens[address];
+                let allowance = |value: U256| {
+                    if value == U256::MAX {
+                        "unlimited".to_string()
+                    } else {
+                        token.amount(value)
+                    }
+                };
+                changes.push(Change::new(
+                    format!(
+                        "allowance[{}][{}]",
+                        context.label(owner),
+                        context.label(spender)
+                    ),
+                    allowance(from),
+                    allowance(to),
+                ));
+            }
+            None => changes.push(Change::new(
+                format!("slot {:#x}", slot),
+                format!("{:#x}", from),
+                format!("{:#x}", to),
+            )),
+        }
+    }
+    changes
+}
+
+fn explain_pool_slot(
+    pool: &Pool,
+    slot: U256,
+    from: U256,
+    to: U256,
+    context: &ExplainContext,
+) -> Option<Change> {
+    let member = |word: U256, field: &Field| unpack(word, field.offset * 8, field.ty.bits());
+    match pool.pool_variant {
+        PoolVariant::UniswapV2 if slot == V2_RESERVES_SLOT.into() => {
+            let reserves = |word: U256| {
+                format!(
+                    "{} / {}",
+                    context.amount(&pool.token_0, member(word, &V2_RESERVES[0])),
+                    context.amount(&pool.token_1, member(word, &V2_RESERVES[1]))
+                )
+            };
+            Some(Change::new("reserves", reserves(from), reserves(to)))
+        }
+        PoolVariant::UniswapV3 if slot == V3_SLOT0_SLOT.into() => {
+            let tick = &V3_SLOT0[1];
+            let slot0 = |word: U256| {
+                format!(
+                    "tick {} (sqrtPriceX96 {})",
+                    unpack_signed(word, tick.offset * 8, tick.ty.bits()),
+                    member(word, &V3_SLOT0[0])
+                )
+            };
+            Some(Change::new("slot0", slot0(from), slot0(to)))
+        }
+        PoolVariant::UniswapV3 if slot == V3_LIQUIDITY_SLOT.into() => Some(Change::new(
+            "liquidity",
+            member(from, &V3_LIQUIDITY).to_string(),
+            member(to, &V3_LIQUIDITY).to_string(),
+        )),
+        _ => None,
+    }
+}
+
+/// Balance and allowance slots of `holders` in `token`
+fn token_slot_meanings(token: &TokenInfo, holders: &[Address]) -> HashMap<U256, SlotMeaning> {
+    let bases: Vec<(U256, U256)> = match token.balance_slot {
+        Some(balance) => vec![(balance, token.allowance_slot.unwrap_or(balance + 1))],
+        None => GUESSED_BALANCE_SLOTS
+            .iter()
+            .map(|base| (U256::from(*base), U256::from(base + 1)))
+            .collect(),
+    };
+
+    let mut meanings = HashMap::new();
+    for (balance_base, allowance_base) in bases {
+        for holder in holders {
+            meanings.insert(
+                mapping_slot(*holder, balance_base),
+                SlotMeaning::Balance(*holder),
+            );
+            for spender in holders.iter().filter(|spender| *spender != holder) {
+                meanings.insert(
+                    nested_mapping_slot(*holder, *spender, allowance_base),
+                    SlotMeaning::Allowance(*holder, *spender),
+                );
+            }
+        }
+    }
+    meanings
+}
+
+fn changed<T: Clone + Default>(diff: &Diff<T>) -> Option<(T, T)> {
+    match diff {
+        Diff::Same => None,
+        Diff::Born(to) => Some((T::default(), to.clone())),
+        Diff::Died(from) => Some((from.clone(), T::default())),
+        Diff::Changed(ChangedType { from, to }) => Some((from.clone(), to.clone())),
+    }
+}
+
+fn h256_to_u256(word: H256) -> U256 {
+    U256::from_big_endian(word.as_bytes())
+}
+
+fn format_eth(wei: U256) -> String {
+    format!(
+        "{} ETH",
+        format_units(wei, "ether").unwrap_or_else(|_| wei.to_string())
+    )
+}
+
+fn signed_delta(from: U256, to: U256, decimals: u8) -> String {
+    let (sign, delta) = if to >= from {
+        ("+", to - from)
+    } else {
+        ("-", from - to)
+    };
+    let delta = format_units(delta, decimals as u32).unwrap_or_else(|_| delta.to_string());
+    format!("{}{}", sign, delta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(value: U256) -> H256 {
+        let mut bytes = [0u8; 32];
+        value.to_big_endian(&mut bytes);
+        H256(bytes)
+    }
+
+    #[test]
+    fn test_explain_swap() {
+        let weth = Address::from_low_u64_be(1);
+        let usdc = Address::from_low_u64_be(2);
+        let pair = Address::from_low_u64_be(3);
+        let trader = Address::from_low_u64_be(4);
+        let context = ExplainContext::new()
+            .with_token(weth, TokenInfo::new("WETH", 18).with_balance_slot(3))
+            .with_token(usdc, TokenInfo::new("USDC", 6))
+            .with_pools([Pool::new(
+                pair,
+                weth,
+                usdc,
+                U256::from(300),
+                PoolVariant::UniswapV2,
+            )])
+            .with_label(trader, "trader");
+
+        let reserves = |weth: u64, usdc: u64| word(U256::from(weth) | (U256::from(usdc) << 112));
+        let account_diff = || AccountDiff {
+            balance: Diff::Same,
+            nonce: Diff::Same,
+            code: Diff::Same,
+            storage: BTreeMap::new(),
+        };
+        let mut pair_diff = account_diff();
+        pair_diff.storage.insert(
+            word(V2_RESERVES_SLOT.into()),
+            Diff::Changed(ChangedType {
+                from: reserves(10_000_000_000_000_000_000, 20_000_000_000),
+                to: reserves(11_000_000_000_000_000_000, 18_000_000_000),
+            }),
+        );
+
+        let mut usdc_diff = account_diff();
+        usdc_diff.storage.insert(
+            word(mapping_slot(trader, 9u64)),
+            Diff::Born(word(U256::from(2_000_000_000u64))),
+        );
+        usdc_diff.storage.insert(
+            word(nested_mapping_slot(trader, pair, 10u64)),
+            Diff::Born(word(U256::MAX)),
+        );
+
+        let diffs = BTreeMap::from([(pair, pair_diff), (usdc, usdc_diff)]);
+        let explanation = explain(&diffs, &context);
+
+        // accounts are in address order, the token before the pair
+        let token_changes = &explanation.accounts[0].changes;
+        assert_eq!(token_changes.len(), 2);
+        assert!(token_changes.contains(&Change::new(
+            "balanceOf[trader]",
+            "0.000000 USDC",
+            "2000.000000 USDC (+2000.000000)"
+        )));
+        assert!(token_changes.contains(&Change::new(
+            format!("allowance[trader][{}]", context.label(&pair)),
+            "0.000000 USDC",
+            "unlimited"
+        )));
+        assert_eq!(
+            explanation.accounts[1].changes,
+            vec![Change::new(
+                "reserves",
+                "10.000000000000000000 WETH / 20000.000000 USDC",
+                "11.000000000000000000 WETH / 18000.000000 USDC"
+            )]
+        );
+        assert!(explanation.accounts[1]
+            .label
+            .starts_with("UniswapV2 WETH/USDC"));
+    }
+}
@@ -0,0 +1,285 @@
+//! State diff fixtures captured from historical blocks
+//!
+//! `qilin fixtures capture <BLOCK>...` traces every tx of the given blocks on an archive node and
+//! writes one [StateDiffFixture] per block to `collectors/fixtures/`, with the tracked pools the
+//! txs touch. Tests load them with [load_fixture] or [load_all] and run the `extract_*` functions
+//! and [merge_diffs](crate::state_diff::merge_diffs) against real diffs, without a node.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use ethers::{
+    providers::Middleware,
+    types::{AccountDiff, Address, BlockNumber, H256},
+};
+use qilin_cfmms::{pool::Pool, registry::PoolView};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{state_diff::TxDiff, trace_client::TraceClient};
+
+#[derive(Error, Debug)]
+pub enum FixtureError {
+    #[error("Io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed fixture: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Block {0} not found")]
+    BlockNotFound(u64),
+    #[error("Provider error: {0}")]
+    Provider(String),
+    #[error("Trace error: {0}")]
+    Trace(String),
+}
+
+/// State diff of one tx of the block
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FixtureTx {
+    pub hash: H256,
+    pub diff: BTreeMap<Address, AccountDiff>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateDiffFixture {
+    pub block_number: u64,
+    /// the block's txs, each traced after the ones before it
+    pub txs: Vec<FixtureTx>,
+    /// tracked pools touched by the txs, as synced when the fixture was captured
+    pub pools: Vec<Pool>,
+}
+
+impl StateDiffFixture {
+    /// The txs as the tracing pipeline hands them out
+    pub fn tx_diffs(&self) -> Vec<TxDiff> {
+        self.txs
+            .iter()
+            .enumerate()
+            .map(|(index, tx)| TxDiff {
+                index,
+                tx_hash: tx.hash,
+                diff: tx.diff.clone(),
+            })
+            .collect()
+    }
+
+    /// The pools as the strategies see them
+    pub fn pool_view(&self) -> PoolView {
+        self.pools.iter().copied().collect()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FixtureError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), FixtureError> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// `block_<number>.json`
+    pub fn file_name(&self) -> String {
+        format!("block_{}.json", self.block_number)
+    }
+}
+
+/// Where fixtures are committed
+pub fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures")
+}
+
+/// The fixture `name` in [fixtures_dir], e.g. `block_17000000.json`
+pub fn load_fixture(name: &str) -> Result<StateDiffFixture, FixtureError> {
+    StateDiffFixture::load(fixtures_dir().join(name))
+}
+
+/// Every fixture in `dir`, sorted by file name
+pub fn load_all(dir: impl AsRef<Path>) -> Result<Vec<(String, StateDiffFixture)>, FixtureError> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into();
+            Ok((name, StateDiffFixture::load(&path)?))
+        })
+        .collect()
+}
+
+/// Trace the txs of `block_number` on top of its parent and keep the tracked `pools` they touch
+pub async fn capture<M: Middleware>(
+    provider: &M,
+    tracer: &dyn TraceClient,
+    block_number: u64,
+    pools: &PoolView,
+) -> Result<StateDiffFixture, FixtureError> {
+    let block = provider
+        .get_block_with_txs(block_number)
+        .await
+        .map_err(|e| FixtureError::Provider(e.to_string()))?
+        .ok_or(FixtureError::BlockNotFound(block_number))?;
+    let parent = BlockNumber::Number(block_number.saturating_sub(1).into());
+    let diffs = tracer
+        .state_diffs(&block.transactions, parent)
+        .await
+        .map_err(|e| FixtureError::Trace(e.to_string()))?;
+
+    let txs: Vec<FixtureTx> = block
+        .transactions
+        .iter()
+        .zip(diffs)
+        .map(|(tx, diff)| FixtureTx {
+            hash: tx.hash,
+            diff,
+        })
+        .collect();
+    let mut touched: Vec<Pool> = txs
+        .iter()
+        .flat_map(|tx| tx.diff.keys())
+        .filter_map(|address| pools.get(address))
+        .collect();
+    touched.sort_by_key(|pool| pool.address);
+    touched.dedup_by_key(|pool| pool.address);
+
+    Ok(StateDiffFixture {
+        block_number,
+        txs,
+        pools: touched,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_diff::{extract_sandwich_opportunities, merge_diffs, DiffMergeStrategy};
+    use ethers::types::{ChangedType, Diff, Transaction, U256};
+    use fork_database::storage_layout::{slot_key, unpack};
+    use qilin_cfmms::test_utils::empty_pool;
+
+    #[test]
+    fn test_fixture_roundtrip() {
+        let pool = empty_pool(10);
+        let diff = AccountDiff {
+            balance: Diff::Same,
+            nonce: Diff::Same,
+            code: Diff::Same,
+            storage: BTreeMap::new(),
+        };
+        let fixture = StateDiffFixture {
+            block_number: 17_000_000,
+            txs: vec![FixtureTx {
+                hash: H256::from_low_u64_be(9),
+                diff: BTreeMap::from([(pool.address, diff)]),
+            }],
+            pools: vec![pool],
+        };
+
+        let dir = std::env::temp_dir().join(format!("qilin-fixtures-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fixture.save(dir.join(fixture.file_name())).unwrap();
+        let loaded = load_all(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].0, "block_17000000.json");
+        assert_eq!(loaded[0].1, fixture);
+        assert_eq!(
+            fixture.tx_diffs()[0].touched_pools(&fixture.pool_view()),
+            vec![pool]
+        );
+    }
+
+    /// Runs against whatever has been captured into [fixtures_dir]
+    #[test]
+    fn test_committed_fixtures() {
+        for (name, fixture) in load_all(fixtures_dir()).unwrap() {
+            let pools = fixture.pool_view();
+            let merged = merge_diffs(fixture.tx_diffs(), DiffMergeStrategy::FirstSeen);
+            for (address, diff) in &merged {
+                let first = fixture
+                    .txs
+                    .iter()
+                    .find_map(|tx| tx.diff.get(address))
+                    .unwrap();
+                assert_eq!(
+                    diff, first,
+                    "{}: {:?} not the first tx's diff",
+                    name, address
+                );
+            }
+            for tx in &fixture.txs {
+                let victim = Transaction {
+                    hash: tx.hash,
+                    ..Default::default()
+                };
+                for sandwichable in
+                    extract_sandwich_opportunities(&victim, &tx.diff, &pools).unwrap_or_default()
+                {
+                    assert!(pools.get(&sandwichable.pool.address).is_some(), "{}", name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_synthetic_v2_swaps() {
+        let fixture = load_fixture("synthetic_v2_swaps.json").unwrap();
+        let pools = fixture.pool_view();
+        let pair: Address = "0xb4e16d0168e52d35cacd2c6185b44281ec28c9dc"
+            .parse()
+            .unwrap();
+        let reserves_key = slot_key(U256::from(8));
+        let reserves =
+            |diff: &BTreeMap<Address, AccountDiff>| match &diff[&pair].storage[&reserves_key] {
+                Diff::Changed(ChangedType { from, to }) => {
+                    let decode = |word: &H256| {
+                        let word = U256::from_big_endian(word.as_bytes());
+                        (unpack(word, 0, 112), unpack(word, 112, 112))
+                    };
+                    (decode(from), decode(to))
+                }
+                other => panic!("reserves not changed: {:?}", other),
+            };
+        let e18 = U256::exp10(18);
+
+        let victim = Transaction::default();
+
+        // 10 WETH in, USDC out
+        let weth_in = &fixture.txs[0].diff;
+        let sandwichable = extract_sandwich_opportunities(&victim, weth_in, &pools).unwrap();
+        assert_eq!(sandwichable.len(), 1);
+        assert_eq!(sandwichable[0].pool.address, pair);
+        assert!(sandwichable[0].is_weth_input);
+        let (before, after) = reserves(weth_in);
+        assert_eq!(
+            before,
+            (U256::from(50_000_000u64) * U256::exp10(6), e18 * 27_000)
+        );
+        assert_eq!(after.1 - before.1, e18 * 10);
+        assert_eq!(before.0 - after.0, U256::from(18_456_147_859u64));
+
+        // 20,000 USDC in, WETH out
+        let weth_out = &fixture.txs[1].diff;
+        let sandwichable = extract_sandwich_opportunities(&victim, weth_out, &pools).unwrap();
+        assert_eq!(sandwichable.len(), 1);
+        assert!(!sandwichable[0].is_weth_input);
+        assert_eq!(fixture.tx_diffs()[1].touched_pools(&pools), fixture.pools);
+
+        // the first swap's diff is the state before the block, the last one's after it
+        let first = merge_diffs(fixture.tx_diffs(), DiffMergeStrategy::FirstSeen);
+        let last = merge_diffs(fixture.tx_diffs(), DiffMergeStrategy::LastSeen);
+        assert_eq!(first.len(), 5);
+        assert_eq!(reserves(&first), reserves(weth_in));
+        assert_eq!(reserves(&last), reserves(weth_out));
+        assert_eq!(reserves(&last).0, reserves(&first).1);
+    }
+}
@@ -0,0 +1,417 @@
+//! Pre-confirmation tx flow on L2s
+//!
+//! L2s have no public mempool, but their sequencers publish txs before they're sealed into an L1
+//! batch: Arbitrum through its sequencer feed, OP-stack chains through the unsafe head their
+//! nodes follow. [ArbitrumFeedCollector] and [OpUnsafeHeadCollector] turn both into the same
+//! [MempoolEvent]s the mempool collector emits, traced and stamped with a
+//! [timeline](OpportunityTimeline), so the strategies don't care where a tx came from. Latency
+//! budgets follow the chain's block time, see [L2Chain::latency_config]. Both reconnect on their
+//! own when their stream ends.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use artemis::types::{Collector, CollectorStream};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ethers::{
+    prelude::Middleware,
+    providers::PubsubClient,
+    types::{Block, BlockNumber, Transaction, U64},
+    utils::rlp,
+};
+use futures::{stream, Stream, StreamExt};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use crate::latency::{LatencyConfig, OpportunityTimeline, Stage};
+use crate::state_diff::get_from_txs;
+use crate::trace_client::{detect_trace_client, TraceClient};
+use crate::types::{MempoolEvent, NewTx};
+
+/// Public sequencer feed of Arbitrum One
+pub const ARBITRUM_ONE_FEED: &str = "wss://arb1.arbitrum.io/feed";
+
+/// `L1MessageType_L2Message`, the only feed message kind carrying user txs
+const L1_MESSAGE_KIND_L2: u8 = 3;
+/// `L2MessageKind_Batch`, length prefixed nested l2 messages
+const L2_MESSAGE_KIND_BATCH: u8 = 3;
+/// `L2MessageKind_SignedTx`, an RLP encoded signed tx
+const L2_MESSAGE_KIND_SIGNED_TX: u8 = 4;
+/// Nesting limit of batches, same as the nitro node
+const MAX_BATCH_DEPTH: usize = 16;
+/// Wait between attempts to reconnect a dropped feed or subscription
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// OP-stack deposit txs are forced in from L1, there's nothing to react to
+const OP_DEPOSIT_TX_TYPE: u64 = 0x7e;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum L2Chain {
+    Arbitrum,
+    Optimism,
+    Base,
+}
+
+impl L2Chain {
+    pub fn block_time(&self) -> Duration {
+        match self {
+            L2Chain::Arbitrum => Duration::from_millis(250),
+            L2Chain::Optimism | L2Chain::Base => Duration::from_secs(2),
+        }
+    }
+
+    /// A tx is only worth reacting to within one block of it being sequenced
+    pub fn latency_config(&self) -> LatencyConfig {
+        LatencyConfig {
+            budget: self.block_time(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BroadcastMessage {
+    #[serde(default)]
+    messages: Vec<FeedMessage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FeedMessage {
+    sequence_number: u64,
+    message: MessageWithMetadata,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageWithMetadata {
+    message: L1IncomingMessage,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct L1IncomingMessage {
+    header: MessageHeader,
+    #[serde(default)]
+    l2_msg: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageHeader {
+    kind: u8,
+}
+
+/// Signed txs of a sequencer feed broadcast, with the sequence number of the message they came
+/// in. Messages that aren't user txs (delayed inbox, heartbeats) are skipped.
+pub fn decode_feed_message(raw: &str) -> Result<Vec<(u64, Transaction)>> {
+    let broadcast: BroadcastMessage = serde_json::from_str(raw)?;
+    let mut txs = vec![];
+    for feed in broadcast.messages {
+        let message = feed.message.message;
+        if message.header.kind != L1_MESSAGE_KIND_L2 {
+            continue;
+        }
+        let l2_msg = BASE64.decode(message.l2_msg)?;
+        for tx in decode_l2_message(&l2_msg, 0)? {
+            txs.push((feed.sequence_number, tx));
+        }
+    }
+    Ok(txs)
+}
+
+fn decode_l2_message(data: &[u8], depth: usize) -> Result<Vec<Transaction>> {
+    let Some((kind, payload)) = data.split_first() else {
+        return Ok(vec![]);
+    };
+    match *kind {
+        L2_MESSAGE_KIND_SIGNED_TX => {
+            let mut tx: Transaction = rlp::decode(payload)?;
+            tx.from = tx.recover_from()?;
+            Ok(vec![tx])
+        }
+        L2_MESSAGE_KIND_BATCH if depth < MAX_BATCH_DEPTH => {
+            let mut txs = vec![];
+            let mut rest = payload;
+            while rest.len() >= 8 {
+                let (len, tail) = rest.split_at(8);
+                let len = u64::from_be_bytes(len.try_into().expect("8 bytes")) as usize;
+                anyhow::ensure!(len <= tail.len(), "Truncated batch message");
+                let (message, tail) = tail.split_at(len);
+                txs.extend(decode_l2_message(message, depth + 1)?);
+                rest = tail;
+            }
+            Ok(txs)
+        }
+        _ => Ok(vec![]),
+    }
+}
+
+/// Streams from `connect`, reconnecting after [RECONNECT_DELAY] each time the current one ends
+fn reconnecting<S, F, Fut, E>(first: S, connect: F) -> impl Stream<Item = S::Item>
+where
+    S: Stream,
+    F: Fn() -> Fut + Clone,
+    Fut: std::future::Future<Output = Result<S, E>>,
+    E: std::fmt::Debug,
+{
+    let reconnects = stream::unfold((), move |_| {
+        let connect = connect.clone();
+        async move {
+            loop {
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                match connect().await {
+                    Ok(next) => {
+                        info!("Reconnected the pre-confirmation stream");
+                        return Some((next, ()));
+                    }
+                    Err(e) => warn!("Failed to reconnect the pre-confirmation stream: {:?}", e),
+                }
+            }
+        }
+    });
+    stream::once(async { first }).chain(reconnects).flatten()
+}
+
+/// Trace `tx` on top of `block` and wrap it into the event the strategies consume
+async fn traced_event(
+    tracer: &dyn TraceClient,
+    tx: Transaction,
+    block: BlockNumber,
+    mut timeline: OpportunityTimeline,
+) -> Option<MempoolEvent> {
+    timeline.mark(Stage::Decoded);
+    let state_diff = get_from_txs(tracer, &[tx.clone()], block).await?;
+    timeline.mark(Stage::Traced);
+    Some(MempoolEvent::NewTx(
+        NewTx::new(tx, state_diff).with_timeline(timeline),
+    ))
+}
+
+/// Txs of the Arbitrum sequencer feed, as soon as the sequencer has ordered them
+pub struct ArbitrumFeedCollector {
+    feed_url: String,
+    tracer: Arc<dyn TraceClient>,
+}
+
+impl ArbitrumFeedCollector {
+    /// Collector for the public Arbitrum One feed, tracing through the best endpoint of
+    /// `provider`
+    pub async fn new<M: Middleware + 'static>(provider: Arc<M>) -> Self {
+        Self {
+            feed_url: ARBITRUM_ONE_FEED.to_string(),
+            tracer: detect_trace_client(provider).await,
+        }
+    }
+
+    /// Read the feed of a local relay or another chain instead
+    pub fn with_feed_url(mut self, url: impl Into<String>) -> Self {
+        self.feed_url = url.into();
+        self
+    }
+
+    pub fn with_trace_client(mut self, tracer: Arc<dyn TraceClient>) -> Self {
+        self.tracer = tracer;
+        self
+    }
+}
+
+#[async_trait]
+impl Collector<MempoolEvent> for ArbitrumFeedCollector {
+    async fn get_event_stream(&self) -> Result<CollectorStream<'_, MempoolEvent>> {
+        // pings are answered while reading, the feed needs nothing else from us
+        let (socket, _) = connect_async(self.feed_url.as_str()).await?;
+        let url = self.feed_url.clone();
+        let messages = reconnecting(socket, move || {
+            let url = url.clone();
+            async move { connect_async(url.as_str()).await.map(|(socket, _)| socket) }
+        });
+        let stream = messages
+            .filter_map(|message| async move {
+                match message {
+                    Ok(Message::Text(text)) => Some(text),
+                    Ok(_) => None,
+                    Err(e) => {
+                        warn!("Sequencer feed error: {:?}", e);
+                        None
+                    }
+                }
+            })
+            .flat_map(|text| {
+                let seen = Instant::now();
+                let txs = decode_feed_message(&text).unwrap_or_else(|e| {
+                    debug!("Undecodable feed message: {:?}", e);
+                    vec![]
+                });
+                futures::stream::iter(txs.into_iter().map(move |(sequence, tx)| {
+                    let timeline = OpportunityTimeline::seen_at(tx.hash, seen);
+                    (sequence, tx, timeline)
+                }))
+            })
+            .filter_map(move |(sequence, tx, timeline)| async move {
+                debug!("Sequenced tx {:?} at {}", tx.hash, sequence);
+                traced_event(self.tracer.as_ref(), tx, BlockNumber::Latest, timeline).await
+            });
+        Ok(Box::pin(stream))
+    }
+}
+
+/// Txs of OP-stack unsafe blocks, gossiped by the sequencer before they're batched to L1
+///
+/// The node behind `provider` (op-geth following op-node) advances `latest` on the unsafe head,
+/// so its new heads subscription is the pre-confirmation stream. The txs are already sealed in
+/// the unsafe block, each is traced at its position in it rather than alone on the parent.
+pub struct OpUnsafeHeadCollector<M> {
+    provider: Arc<M>,
+    tracer: Arc<dyn TraceClient>,
+}
+
+impl<M> OpUnsafeHeadCollector<M>
+where
+    M: Middleware + 'static,
+    M::Provider: PubsubClient,
+{
+    pub async fn new(provider: Arc<M>) -> Self {
+        Self {
+            tracer: detect_trace_client(provider.clone()).await,
+            provider,
+        }
+    }
+
+    pub fn with_trace_client(mut self, tracer: Arc<dyn TraceClient>) -> Self {
+        self.tracer = tracer;
+        self
+    }
+}
+
+impl<M: Middleware + 'static> OpUnsafeHeadCollector<M> {
+    /// Events of the user txs of `block`, traced in one call in block order on top of the parent,
+    /// so each diff includes the effects of the txs sequenced before it
+    async fn traced_block(&self, block: Block<Transaction>, seen: Instant) -> Vec<MempoolEvent> {
+        let parent = block.number.unwrap_or_default().saturating_sub(U64::one());
+        let txs: Vec<Transaction> = block
+            .transactions
+            .into_iter()
+            .filter(|tx| tx.transaction_type != Some(OP_DEPOSIT_TX_TYPE.into()))
+            .collect();
+        let mut timelines: Vec<OpportunityTimeline> = txs
+            .iter()
+            .map(|tx| OpportunityTimeline::seen_at(tx.hash, seen))
+            .collect();
+        timelines.iter_mut().for_each(|t| t.mark(Stage::Decoded));
+
+        let diffs = match self
+            .tracer
+            .state_diffs(&txs, BlockNumber::Number(parent))
+            .await
+        {
+            Ok(diffs) => diffs,
+            Err(e) => {
+                warn!("Failed to trace unsafe block {}: {:?}", parent + 1, e);
+                return vec![];
+            }
+        };
+        txs.into_iter()
+            .zip(diffs)
+            .zip(timelines)
+            .map(|((tx, state_diff), mut timeline)| {
+                timeline.mark(Stage::Traced);
+                MempoolEvent::NewTx(NewTx::new(tx, state_diff).with_timeline(timeline))
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl<M> Collector<MempoolEvent> for OpUnsafeHeadCollector<M>
+where
+    M: Middleware + 'static,
+    M::Provider: PubsubClient,
+    M::Error: 'static,
+{
+    async fn get_event_stream(&self) -> Result<CollectorStream<'_, MempoolEvent>> {
+        let heads = self.provider.subscribe_blocks().await?;
+        let provider = self.provider.as_ref();
+        let heads = reconnecting(heads, move || provider.subscribe_blocks());
+        let stream = heads
+            .filter_map(move |head| async move {
+                let seen = Instant::now();
+                let hash = head.hash?;
+                match self.provider.get_block_with_txs(hash).await {
+                    Ok(Some(block)) => Some((block, seen)),
+                    Ok(None) => None,
+                    Err(e) => {
+                        warn!("Failed to fetch unsafe block {:?}: {:?}", hash, e);
+                        None
+                    }
+                }
+            })
+            .then(move |(block, seen)| async move {
+                let events = self.traced_block(block, seen).await;
+                stream::iter(events)
+            })
+            .flatten();
+        Ok(Box::pin(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::{LocalWallet, Signer};
+    use ethers::types::{transaction::eip2718::TypedTransaction, TransactionRequest};
+
+    #[test]
+    fn test_decode_feed_message() {
+        let wallet: LocalWallet =
+            "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+                .parse()
+                .unwrap();
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(ethers::types::Address::from_low_u64_be(1))
+            .value(1)
+            .nonce(0)
+            .gas(21_000)
+            .gas_price(100_000_000)
+            .chain_id(42161u64)
+            .into();
+        let signature = wallet.sign_transaction_sync(&tx).unwrap();
+        let raw = tx.rlp_signed(&signature);
+        let hash = ethers::utils::keccak256(&raw);
+
+        let signed = [&[L2_MESSAGE_KIND_SIGNED_TX][..], &raw].concat();
+        // a batch holding the signed tx, length prefixed
+        let batch = [
+            &[L2_MESSAGE_KIND_BATCH][..],
+            &(signed.len() as u64).to_be_bytes(),
+            &signed,
+        ]
+        .concat();
+
+        let message = |kind: u8, l2_msg: &[u8]| {
+            serde_json::json!({
+                "sequenceNumber": 7,
+                "message": {
+                    "message": {
+                        "header": { "kind": kind },
+                        "l2Msg": BASE64.encode(l2_msg),
+                    },
+                    "delayedMessagesRead": 1,
+                },
+                "signature": null,
+            })
+        };
+        let raw = serde_json::json!({
+            "version": 1,
+            "messages": [message(L1_MESSAGE_KIND_L2, &batch), message(12, &signed)],
+        })
+        .to_string();
+
+        let txs = decode_feed_message(&raw).unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].0, 7);
+        assert_eq!(txs[0].1.hash.0, hash);
+        assert_eq!(txs[0].1.from, wallet.address());
+    }
+}
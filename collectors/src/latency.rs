@@ -0,0 +1,260 @@
+//! Per opportunity latency tracing
+//!
+//! An [OpportunityTimeline] travels with an opportunity from the moment its tx is seen and is
+//! stamped at every pipeline stage. Finished timelines go to a [LatencyTracker], which keeps a
+//! window of stage durations, publishes their p50/p95 as gauges and raises an alarm when the
+//! whole pipeline ran over the block time budget.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use ethers::types::H256;
+use log::warn;
+use parking_lot::Mutex;
+
+/// Pipeline stages, in the order an opportunity goes through them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Stage {
+    Seen,
+    Decoded,
+    Traced,
+    Simulated,
+    BundleBuilt,
+    Submitted,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 6] = [
+        Stage::Seen,
+        Stage::Decoded,
+        Stage::Traced,
+        Stage::Simulated,
+        Stage::BundleBuilt,
+        Stage::Submitted,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Seen => "seen",
+            Stage::Decoded => "decoded",
+            Stage::Traced => "traced",
+            Stage::Simulated => "simulated",
+            Stage::BundleBuilt => "bundle_built",
+            Stage::Submitted => "submitted",
+        }
+    }
+}
+
+/// When an opportunity reached each stage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpportunityTimeline {
+    /// hash of the tx the opportunity was found in
+    pub id: H256,
+    marks: [Option<Instant>; Stage::ALL.len()],
+}
+
+impl OpportunityTimeline {
+    /// Timeline of an opportunity whose tx is seen now
+    pub fn new(id: H256) -> Self {
+        Self::seen_at(id, Instant::now())
+    }
+
+    pub fn seen_at(id: H256, seen: Instant) -> Self {
+        let mut marks = [None; Stage::ALL.len()];
+        marks[Stage::Seen as usize] = Some(seen);
+        Self { id, marks }
+    }
+
+    /// Stamp `stage` now
+    pub fn mark(&mut self, stage: Stage) {
+        self.mark_at(stage, Instant::now());
+    }
+
+    pub fn mark_at(&mut self, stage: Stage, at: Instant) {
+        self.marks[stage as usize] = Some(at);
+    }
+
+    pub fn at(&self, stage: Stage) -> Option<Instant> {
+        self.marks[stage as usize]
+    }
+
+    /// Time spent getting to `stage` from the previous stamped stage
+    pub fn stage_duration(&self, stage: Stage) -> Option<Duration> {
+        let at = self.at(stage)?;
+        let previous = self.marks[..stage as usize].iter().rev().flatten().next()?;
+        Some(at.saturating_duration_since(*previous))
+    }
+
+    /// Time from the tx being seen to the last stamped stage
+    pub fn elapsed(&self) -> Duration {
+        let seen = self.at(Stage::Seen);
+        let last = self.marks.iter().rev().flatten().next();
+        match (seen, last) {
+            (Some(seen), Some(last)) => last.saturating_duration_since(seen),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LatencyConfig {
+    /// end to end latency above which an alarm is raised, a mainnet slot by default
+    pub budget: Duration,
+    /// durations kept per stage for the percentiles
+    pub window: usize,
+}
+
+impl Default for LatencyConfig {
+    fn default() -> Self {
+        Self {
+            budget: Duration::from_secs(12),
+            window: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Samples {
+    stages: HashMap<Stage, VecDeque<Duration>>,
+    end_to_end: VecDeque<Duration>,
+    over_budget: u64,
+}
+
+/// Collects finished [OpportunityTimeline]s, see the [module docs](self)
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    config: LatencyConfig,
+    samples: Mutex<Samples>,
+}
+
+impl LatencyTracker {
+    pub fn new(config: LatencyConfig) -> Self {
+        Self {
+            config,
+            samples: Mutex::new(Samples::default()),
+        }
+    }
+
+    pub fn config(&self) -> &LatencyConfig {
+        &self.config
+    }
+
+    /// Record a timeline, returns `false` if it ran over the budget
+    pub fn record(&self, timeline: &OpportunityTimeline) -> bool {
+        let mut samples = self.samples.lock();
+        for stage in &Stage::ALL[1..] {
+            if let Some(duration) = timeline.stage_duration(*stage) {
+                metrics::histogram!(
+                    "qilin_stage_latency_seconds",
+                    duration.as_secs_f64(),
+                    "stage" => stage.as_str()
+                );
+                push(
+                    samples.stages.entry(*stage).or_default(),
+                    duration,
+                    self.config.window,
+                );
+            }
+        }
+
+        let elapsed = timeline.elapsed();
+        metrics::histogram!("qilin_opportunity_latency_seconds", elapsed.as_secs_f64());
+        push(&mut samples.end_to_end, elapsed, self.config.window);
+
+        if elapsed > self.config.budget {
+            samples.over_budget += 1;
+            metrics::counter!("qilin_latency_budget_exceeded", 1);
+            warn!(
+                "Opportunity {:?} took {:?}, over the {:?} budget",
+                timeline.id, elapsed, self.config.budget
+            );
+            return false;
+        }
+        true
+    }
+
+    /// `quantile` (0 to 1) of the durations of `stage` in the window
+    pub fn percentile(&self, stage: Stage, quantile: f64) -> Option<Duration> {
+        percentile(self.samples.lock().stages.get(&stage)?, quantile)
+    }
+
+    pub fn end_to_end_percentile(&self, quantile: f64) -> Option<Duration> {
+        percentile(&self.samples.lock().end_to_end, quantile)
+    }
+
+    /// Timelines recorded over the budget so far
+    pub fn over_budget(&self) -> u64 {
+        self.samples.lock().over_budget
+    }
+
+    /// Publish the p50 and p95 of every stage as gauges, meant to be called periodically
+    pub fn publish(&self) {
+        for stage in &Stage::ALL[1..] {
+            for (name, quantile) in [("p50", 0.5), ("p95", 0.95)] {
+                if let Some(duration) = self.percentile(*stage, quantile) {
+                    metrics::gauge!(
+                        "qilin_stage_latency_quantile_seconds",
+                        duration.as_secs_f64(),
+                        "stage" => stage.as_str(),
+                        "quantile" => name
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn push(window: &mut VecDeque<Duration>, duration: Duration, size: usize) {
+    if window.len() == size {
+        window.pop_front();
+    }
+    window.push_back(duration);
+}
+
+/// Nearest rank percentile
+fn percentile(window: &VecDeque<Duration>, quantile: f64) -> Option<Duration> {
+    if window.is_empty() {
+        return None;
+    }
+    let mut sorted: Vec<Duration> = window.iter().copied().collect();
+    sorted.sort();
+    let rank = (quantile.clamp(0.0, 1.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.saturating_sub(1)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_tracker() {
+        let tracker = LatencyTracker::new(LatencyConfig {
+            budget: Duration::from_millis(150),
+            window: 100,
+        });
+
+        let start = Instant::now();
+        for i in 1..=20u64 {
+            let mut timeline = OpportunityTimeline::seen_at(H256::from_low_u64_be(i), start);
+            timeline.mark_at(Stage::Traced, start + Duration::from_millis(i));
+            timeline.mark_at(Stage::Submitted, start + Duration::from_millis(10 * i));
+            assert_eq!(tracker.record(&timeline), 10 * i <= 150);
+        }
+
+        // decoding wasn't stamped, tracing counts from the tx being seen
+        assert_eq!(tracker.percentile(Stage::Decoded, 0.5), None);
+        assert_eq!(
+            tracker.percentile(Stage::Traced, 0.5),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(
+            tracker.percentile(Stage::Traced, 0.95),
+            Some(Duration::from_millis(19))
+        );
+        assert_eq!(
+            tracker.end_to_end_percentile(0.95),
+            Some(Duration::from_millis(190))
+        );
+        assert_eq!(tracker.over_budget(), 5);
+    }
+}
@@ -0,0 +1,573 @@
+//! Storage layouts from verified source
+//!
+//! Pulls verified sources from Sourcify, falling back to Etherscan, compiles them with the
+//! `storageLayout` output of solc (or `-f layout` of vyper) and caches the slot map per contract.
+//! Gives [slot_finder](crate::slot_finder) an authoritative answer before it probes storage.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use ethers::etherscan::{errors::EtherscanError, Client as EtherscanClient};
+use ethers::types::{H160, U256};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+const SOURCIFY_URL: &str = "https://sourcify.dev/server";
+/// Names the `balanceOf` mapping is commonly declared under
+const BALANCE_OF_LABELS: &[&str] = &["balanceOf", "_balances", "balances", "_balanceOf"];
+/// Type labels of an `address => uint256` mapping, in solc and vyper spelling
+const BALANCE_OF_TYPES: &[&str] = &["mapping(address => uint256)", "HashMap[address, uint256]"];
+
+#[derive(Error, Debug)]
+pub enum LayoutError {
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Etherscan(#[from] EtherscanError),
+    #[error(transparent)]
+    SerdeJson(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("{0:?} has no verified source")]
+    Unverified(H160),
+    #[error("Compiler error: {0}")]
+    Compiler(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Language {
+    Solidity,
+    Vyper,
+}
+
+/// Verified source of a contract, as needed to compile it again
+#[derive(Debug, Clone)]
+pub struct VerifiedSource {
+    pub language: Language,
+    /// Bare version, e.g. `0.8.19` without the `v` prefix or commit
+    pub compiler_version: String,
+    pub contract_name: String,
+    /// Source path => content
+    pub sources: BTreeMap<String, String>,
+    /// Compiler settings from a standard json input or metadata, if any
+    pub settings: Option<Value>,
+}
+
+/// A state variable in a [StorageLayout]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageEntry {
+    pub label: String,
+    pub slot: U256,
+    /// Byte offset within the slot for packed variables
+    pub offset: u8,
+    /// Human readable type, e.g. `mapping(address => uint256)`
+    pub type_label: String,
+}
+
+/// Storage slot map of a contract
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageLayout {
+    pub entries: Vec<StorageEntry>,
+}
+
+impl StorageLayout {
+    pub fn get(&self, label: &str) -> Option<&StorageEntry> {
+        self.entries.iter().find(|e| e.label == label)
+    }
+
+    /// Slot of the ERC20 `balanceOf` mapping, matched by name and type
+    pub fn balance_of_slot(&self) -> Option<U256> {
+        self.entries
+            .iter()
+            .find(|e| {
+                BALANCE_OF_LABELS.contains(&e.label.as_str())
+                    && BALANCE_OF_TYPES.contains(&e.type_label.as_str())
+            })
+            .map(|e| e.slot)
+    }
+
+    /// Layout of `contract_name` from a solc standard json output
+    pub fn from_solc_output(output: &Value, contract_name: &str) -> Option<Self> {
+        let layout = output["contracts"]
+            .as_object()?
+            .values()
+            .find_map(|contracts| contracts.get(contract_name))?
+            .get("storageLayout")?;
+        let types = &layout["types"];
+
+        let entries = layout["storage"]
+            .as_array()?
+            .iter()
+            .filter_map(|entry| {
+                let type_id = entry["type"].as_str()?;
+                Some(StorageEntry {
+                    label: entry["label"].as_str()?.to_string(),
+                    slot: U256::from_dec_str(entry["slot"].as_str()?).ok()?,
+                    offset: entry["offset"].as_u64()? as u8,
+                    type_label: types[type_id]["label"]
+                        .as_str()
+                        .unwrap_or(type_id)
+                        .to_string(),
+                })
+            })
+            .collect();
+        Some(Self { entries })
+    }
+
+    /// Layout from vyper's `-f layout` output, both the flat (< 0.3.4) and the nested format
+    pub fn from_vyper_output(output: &Value) -> Option<Self> {
+        let storage = output.get("storage_layout").unwrap_or(output).as_object()?;
+
+        let mut entries: Vec<StorageEntry> = storage
+            .iter()
+            .filter_map(|(label, entry)| {
+                Some(StorageEntry {
+                    label: label.clone(),
+                    slot: U256::from(entry["slot"].as_u64()?),
+                    offset: 0,
+                    type_label: entry["type"].as_str()?.to_string(),
+                })
+            })
+            .collect();
+        entries.sort_by_key(|e| e.slot);
+        Some(Self { entries })
+    }
+}
+
+/// Split an Etherscan `SourceCode` field into source files and settings
+///
+/// It holds either a single flattened file, a json object of files or a standard json input
+/// wrapped in an extra pair of braces.
+pub fn parse_etherscan_source(
+    source_code: &str,
+    contract_name: &str,
+) -> Result<(BTreeMap<String, String>, Option<Value>), LayoutError> {
+    let trimmed = source_code.trim();
+    if !trimmed.starts_with('{') {
+        let sources = BTreeMap::from([(format!("{}.sol", contract_name), trimmed.to_string())]);
+        return Ok((sources, None));
+    }
+
+    let unwrapped = match trimmed.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+        Some(inner) if inner.starts_with('{') => inner,
+        _ => trimmed,
+    };
+    let value: Value = serde_json::from_str(unwrapped)?;
+    let (files, settings) = match value.get("sources") {
+        Some(files) => (files.clone(), value.get("settings").cloned()),
+        None => (value, None),
+    };
+
+    let sources = files
+        .as_object()
+        .map(|files| {
+            files
+                .iter()
+                .filter_map(|(path, file)| {
+                    Some((path.clone(), file["content"].as_str()?.to_string()))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok((sources, settings))
+}
+
+/// Strip the `v` prefix, `vyper:` prefix and commit suffix of a compiler version
+fn bare_version(version: &str) -> String {
+    let version = version.trim_start_matches("vyper:").trim_start_matches('v');
+    version.split('+').next().unwrap_or(version).to_string()
+}
+
+/// Fetches, compiles and caches storage layouts of verified contracts
+pub struct LayoutFetcher {
+    client: reqwest::Client,
+    etherscan: Option<EtherscanClient>,
+    sourcify_url: String,
+    chain_id: u64,
+    /// svm style directory of compiler binaries, `PATH` is used when missing
+    compilers_dir: Option<PathBuf>,
+    cache_dir: Option<PathBuf>,
+    /// `None` for contracts known to be unverified
+    layouts: DashMap<H160, Option<Arc<StorageLayout>>>,
+}
+
+impl LayoutFetcher {
+    pub fn new(chain_id: u64) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            etherscan: None,
+            sourcify_url: SOURCIFY_URL.to_string(),
+            chain_id,
+            compilers_dir: None,
+            cache_dir: None,
+            layouts: DashMap::new(),
+        }
+    }
+
+    /// Fall back to Etherscan for contracts not verified on Sourcify
+    pub fn with_etherscan(mut self, client: EtherscanClient) -> Self {
+        self.etherscan = Some(client);
+        self
+    }
+
+    pub fn with_sourcify_url(mut self, url: impl Into<String>) -> Self {
+        self.sourcify_url = url.into();
+        self
+    }
+
+    /// Look up `solc` as `<dir>/<version>/solc-<version>` and `vyper` as `<dir>/vyper-<version>`
+    pub fn with_compilers_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.compilers_dir = Some(dir.into());
+        self
+    }
+
+    /// Persist layouts under `dir` so they survive restarts
+    pub fn with_cache_dir(mut self, dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        self.cache_dir = Some(dir);
+        Ok(self)
+    }
+
+    /// Layout already in memory, without fetching
+    pub fn cached(&self, address: H160) -> Option<Arc<StorageLayout>> {
+        self.layouts.get(&address).and_then(|l| l.value().clone())
+    }
+
+    /// Storage layout of `address`, `None` if its source isn't verified
+    pub async fn layout(&self, address: H160) -> Result<Option<Arc<StorageLayout>>, LayoutError> {
+        if let Some(layout) = self.layouts.get(&address) {
+            return Ok(layout.value().clone());
+        }
+        if let Some(layout) = self.load(address) {
+            let layout = Arc::new(layout);
+            self.layouts.insert(address, Some(layout.clone()));
+            return Ok(Some(layout));
+        }
+
+        let source = match self.fetch_source(address).await {
+            Ok(source) => source,
+            Err(LayoutError::Unverified(_)) => {
+                self.layouts.insert(address, None);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        debug!(
+            target: "layout",
+            "Compiling {} ({:?} {}) for {:?}",
+            source.contract_name, source.language, source.compiler_version, address
+        );
+
+        let compiler = self.compiler_path(&source);
+        let layout = tokio::task::spawn_blocking(move || compile_layout(&compiler, &source))
+            .await
+            .map_err(|e| LayoutError::Compiler(e.to_string()))??;
+
+        self.store(address, &layout);
+        let layout = Arc::new(layout);
+        self.layouts.insert(address, Some(layout.clone()));
+        Ok(Some(layout))
+    }
+
+    /// `balanceOf` slot of `token` from its verified layout
+    pub async fn balance_of_slot(&self, token: H160) -> Option<U256> {
+        match self.layout(token).await {
+            Ok(layout) => layout?.balance_of_slot(),
+            Err(e) => {
+                warn!(target: "layout", "Failed to infer layout of {:?}: {}", token, e);
+                None
+            }
+        }
+    }
+
+    async fn fetch_source(&self, address: H160) -> Result<VerifiedSource, LayoutError> {
+        match self.sourcify_source(address).await {
+            Ok(source) => return Ok(source),
+            Err(e) => debug!(target: "layout", "Sourcify lookup of {:?}: {}", address, e),
+        }
+        self.etherscan_source(address).await
+    }
+
+    async fn sourcify_source(&self, address: H160) -> Result<VerifiedSource, LayoutError> {
+        let url = format!(
+            "{}/files/any/{}/{:?}",
+            self.sourcify_url, self.chain_id, address
+        );
+        let response = self.client.get(url).send().await?;
+        if !response.status().is_success() {
+            return Err(LayoutError::Unverified(address));
+        }
+        let body: Value = response.json().await?;
+        let files = body["files"]
+            .as_array()
+            .ok_or(LayoutError::Unverified(address))?;
+
+        let metadata = files
+            .iter()
+            .find(|f| f["name"] == "metadata.json")
+            .and_then(|f| f["content"].as_str())
+            .ok_or(LayoutError::Unverified(address))?;
+        let metadata: Value = serde_json::from_str(metadata)?;
+
+        let (target_path, contract_name) = metadata["settings"]["compilationTarget"]
+            .as_object()
+            .and_then(|target| target.iter().next())
+            .and_then(|(path, name)| Some((path.clone(), name.as_str()?.to_string())))
+            .ok_or(LayoutError::Unverified(address))?;
+
+        // files are stored under `.../sources/<path as in the metadata>`
+        let sources = metadata["sources"]
+            .as_object()
+            .map(|paths| {
+                paths
+                    .keys()
+                    .filter_map(|path| {
+                        let suffix = format!("sources/{}", path);
+                        let file = files
+                            .iter()
+                            .find(|f| f["path"].as_str().map_or(false, |p| p.ends_with(&suffix)))?;
+                        Some((path.clone(), file["content"].as_str()?.to_string()))
+                    })
+                    .collect::<BTreeMap<_, _>>()
+            })
+            .unwrap_or_default();
+        if !sources.contains_key(&target_path) {
+            return Err(LayoutError::Unverified(address));
+        }
+
+        let language = match metadata["language"].as_str() {
+            Some("Vyper") => Language::Vyper,
+            _ => Language::Solidity,
+        };
+        let mut settings = metadata["settings"].clone();
+        if let Some(settings) = settings.as_object_mut() {
+            // metadata only fields, not accepted in a standard json input
+            settings.remove("compilationTarget");
+            settings.remove("libraries");
+        }
+
+        Ok(VerifiedSource {
+            language,
+            compiler_version: bare_version(metadata["compiler"]["version"].as_str().unwrap_or("")),
+            contract_name,
+            sources,
+            settings: Some(settings),
+        })
+    }
+
+    async fn etherscan_source(&self, address: H160) -> Result<VerifiedSource, LayoutError> {
+        let client = self
+            .etherscan
+            .as_ref()
+            .ok_or(LayoutError::Unverified(address))?;
+        let metadata = client.contract_source_code(address).await?;
+        let item = metadata
+            .items
+            .first()
+            .ok_or(LayoutError::Unverified(address))?;
+
+        let source_code = item.source_code();
+        if source_code.trim().is_empty() {
+            return Err(LayoutError::Unverified(address));
+        }
+        let (sources, settings) = parse_etherscan_source(&source_code, &item.contract_name)?;
+        let language = if item.compiler_version.starts_with("vyper") {
+            Language::Vyper
+        } else {
+            Language::Solidity
+        };
+
+        Ok(VerifiedSource {
+            language,
+            compiler_version: bare_version(&item.compiler_version),
+            contract_name: item.contract_name.clone(),
+            sources,
+            settings,
+        })
+    }
+
+    fn compiler_path(&self, source: &VerifiedSource) -> PathBuf {
+        let version = &source.compiler_version;
+        let (name, versioned) = match source.language {
+            Language::Solidity => (
+                "solc",
+                PathBuf::from(version).join(format!("solc-{}", version)),
+            ),
+            Language::Vyper => ("vyper", PathBuf::from(format!("vyper-{}", version))),
+        };
+        self.compilers_dir
+            .as_ref()
+            .map(|dir| dir.join(versioned))
+            .filter(|path| path.exists())
+            .unwrap_or_else(|| PathBuf::from(name))
+    }
+
+    fn cache_path(&self, address: H160) -> Option<PathBuf> {
+        self.cache_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{:?}.json", address)))
+    }
+
+    fn load(&self, address: H160) -> Option<StorageLayout> {
+        let data = fs::read(self.cache_path(address)?).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    fn store(&self, address: H160, layout: &StorageLayout) {
+        let Some(path) = self.cache_path(address) else {
+            return;
+        };
+        let result = serde_json::to_vec(layout)
+            .map_err(LayoutError::from)
+            .and_then(|data| fs::write(path, data).map_err(LayoutError::from));
+        if let Err(e) = result {
+            warn!(target: "layout", "Failed to persist layout of {:?}: {}", address, e);
+        }
+    }
+}
+
+/// Compile `source` with `compiler` and extract its storage layout
+fn compile_layout(
+    compiler: &PathBuf,
+    source: &VerifiedSource,
+) -> Result<StorageLayout, LayoutError> {
+    match source.language {
+        Language::Solidity => {
+            let mut settings = source.settings.clone().unwrap_or_else(|| json!({}));
+            settings["outputSelection"] = json!({ "*": { "*": ["storageLayout"] } });
+            let sources: BTreeMap<_, _> = source
+                .sources
+                .iter()
+                .map(|(path, content)| (path.clone(), json!({ "content": content })))
+                .collect();
+            let input = json!({
+                "language": "Solidity",
+                "sources": sources,
+                "settings": settings,
+            });
+
+            let output = run_compiler(
+                Command::new(compiler).arg("--standard-json"),
+                input.to_string().as_bytes(),
+            )?;
+            let output: Value = serde_json::from_slice(&output)?;
+            let errors: Vec<&str> = output["errors"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|e| e["severity"] == "error")
+                .filter_map(|e| e["formattedMessage"].as_str())
+                .collect();
+            if !errors.is_empty() {
+                return Err(LayoutError::Compiler(errors.join("\n")));
+            }
+            StorageLayout::from_solc_output(&output, &source.contract_name).ok_or_else(|| {
+                LayoutError::Compiler(format!("{} missing from output", source.contract_name))
+            })
+        }
+        Language::Vyper => {
+            // vyper contracts are single files
+            let content = source
+                .sources
+                .values()
+                .next()
+                .ok_or_else(|| LayoutError::Compiler("No vyper source".to_string()))?;
+            let dir = std::env::temp_dir().join(format!("qilin-layout-{}", std::process::id()));
+            fs::create_dir_all(&dir)?;
+            let path = dir.join(format!("{}.vy", source.contract_name));
+            fs::write(&path, content)?;
+
+            let output = run_compiler(
+                Command::new(compiler).arg("-f").arg("layout").arg(&path),
+                &[],
+            );
+            let _ = fs::remove_file(&path);
+            let output: Value = serde_json::from_slice(&output?)?;
+            StorageLayout::from_vyper_output(&output)
+                .ok_or_else(|| LayoutError::Compiler("Unexpected vyper layout".to_string()))
+        }
+    }
+}
+
+fn run_compiler(command: &mut Command, stdin: &[u8]) -> Result<Vec<u8>, LayoutError> {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    if let Some(mut pipe) = child.stdin.take() {
+        pipe.write_all(stdin)?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(LayoutError::Compiler(
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_etherscan_source() {
+        let (sources, settings) = parse_etherscan_source("contract WETH9 {}", "WETH9").unwrap();
+        assert_eq!(sources["WETH9.sol"], "contract WETH9 {}");
+        assert!(settings.is_none());
+
+        let files = r#"{"a.sol": {"content": "import './b.sol';"}, "b.sol": {"content": "b"}}"#;
+        let (sources, _) = parse_etherscan_source(files, "A").unwrap();
+        assert_eq!(sources.len(), 2);
+
+        let standard_json = r#"{{"language": "Solidity", "sources": {"src/A.sol": {"content": "a"}}, "settings": {"remappings": ["x/=lib/x/"]}}}"#;
+        let (sources, settings) = parse_etherscan_source(standard_json, "A").unwrap();
+        assert_eq!(sources["src/A.sol"], "a");
+        assert_eq!(settings.unwrap()["remappings"][0], "x/=lib/x/");
+    }
+
+    #[test]
+    fn test_layout_from_compiler_output() {
+        let solc = json!({
+            "contracts": { "WETH9.sol": { "WETH9": { "storageLayout": {
+                "storage": [
+                    { "label": "name", "offset": 0, "slot": "0", "type": "t_string_storage" },
+                    { "label": "decimals", "offset": 0, "slot": "2", "type": "t_uint8" },
+                    { "label": "balanceOf", "offset": 0, "slot": "3",
+                      "type": "t_mapping(t_address,t_uint256)" },
+                ],
+                "types": {
+                    "t_mapping(t_address,t_uint256)": { "label": "mapping(address => uint256)" },
+                    "t_uint8": { "label": "uint8" },
+                },
+            }}}}
+        });
+        let layout = StorageLayout::from_solc_output(&solc, "WETH9").unwrap();
+        assert_eq!(layout.balance_of_slot(), Some(U256::from(3)));
+        assert_eq!(layout.get("decimals").unwrap().type_label, "uint8");
+        assert!(StorageLayout::from_solc_output(&solc, "Other").is_none());
+
+        let vyper = json!({ "storage_layout": {
+            "name": { "type": "String[64]", "slot": 0 },
+            "balanceOf": { "type": "HashMap[address, uint256]", "slot": 4 },
+        }});
+        let layout = StorageLayout::from_vyper_output(&vyper).unwrap();
+        assert_eq!(layout.balance_of_slot(), Some(U256::from(4)));
+    }
+
+    #[test]
+    fn test_bare_version() {
+        assert_eq!(bare_version("v0.8.19+commit.7dd6d404"), "0.8.19");
+        assert_eq!(bare_version("vyper:0.3.7"), "0.3.7");
+        assert_eq!(bare_version("0.4.24+commit.e67f0147"), "0.4.24");
+    }
+}
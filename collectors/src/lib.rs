@@ -1,5 +1,18 @@
 pub mod block_collector;
+pub mod cow_collector;
+pub mod diff_explain;
+pub mod fixtures;
+pub mod l2_feed;
+pub mod latency;
+pub mod liquidation;
+pub mod layout_fetcher;
 pub mod mempool_collector;
+pub mod opportunity;
+pub mod pair_discovery;
+pub mod pool_classifier;
 pub mod slot_finder;
 pub mod state_diff;
+pub mod trace_client;
+pub mod transfer_graph;
 pub mod types;
+pub mod wrapped_tokens;
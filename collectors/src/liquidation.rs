@@ -0,0 +1,172 @@
+//! Liquidatable positions in Aave V3 markets
+//!
+//! A position can be liquidated once its health factor, as `getUserAccountData` reports it, drops
+//! below 1. Up to half of the debt can be repaid in one `liquidationCall`, all of it below a health
+//! factor of 0.95. [aave_v3_liquidation] checks a position the caller tracks, e.g. each borrower
+//! of a reserve whose oracle price just moved, and hands a liquidatable one over as an
+//! [Opportunity].
+
+use ethers::{
+    abi::Token,
+    providers::Middleware,
+    types::{Address, BlockId, H256, U256},
+};
+use thiserror::Error;
+
+use crate::opportunity::{LiquidationOpportunity, Opportunity};
+use crate::wrapped_tokens::{call, call_uint};
+
+/// Health factors are WADs, positions below one are liquidatable
+pub const HEALTH_FACTOR_ONE: u128 = 1_000_000_000_000_000_000;
+/// Below this health factor the whole debt can be repaid at once
+pub const CLOSE_FACTOR_HF_THRESHOLD: u128 = 950_000_000_000_000_000;
+pub const DEFAULT_CLOSE_FACTOR_BPS: u64 = 5_000;
+pub const MAX_CLOSE_FACTOR_BPS: u64 = 10_000;
+
+#[derive(Error, Debug)]
+pub enum LiquidationError {
+    #[error("Failed to read the account data of {0:?}")]
+    AccountDataUnavailable(Address),
+    #[error("Failed to read the debt of {0:?}")]
+    DebtUnavailable(Address),
+}
+
+/// A borrower's position in an Aave V3 market
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AaveV3Position {
+    /// the market's `Pool`, `liquidationCall` goes to it
+    pub market: Address,
+    pub borrower: Address,
+    /// reserve seized
+    pub collateral: Address,
+    /// reserve repaid
+    pub debt: Address,
+    /// variable debt token of the `debt` reserve, holding what the borrower owes
+    pub variable_debt_token: Address,
+}
+
+/// Share of the debt a liquidator may repay at `health_factor`, in bps
+pub fn close_factor_bps(health_factor: U256) -> u64 {
+    if health_factor < U256::from(CLOSE_FACTOR_HF_THRESHOLD) {
+        MAX_CLOSE_FACTOR_BPS
+    } else {
+        DEFAULT_CLOSE_FACTOR_BPS
+    }
+}
+
+/// Health factor of `borrower` in `market`, the last word `getUserAccountData` returns
+pub async fn health_factor<M: Middleware>(
+    provider: &M,
+    market: Address,
+    borrower: Address,
+    block: Option<BlockId>,
+) -> Result<U256, LiquidationError> {
+    let out = call(
+        provider,
+        market,
+        "getUserAccountData(address)",
+        &[Token::Address(borrower)],
+        block,
+    )
+    .await
+    .filter(|out| out.len() >= 6 * 32)
+    .ok_or(LiquidationError::AccountDataUnavailable(borrower))?;
+    Ok(U256::from_big_endian(&out[5 * 32..6 * 32]))
+}
+
+/// The liquidation `position` allows at `block`, `None` while it's healthy. `trigger` is the tx
+/// that made it liquidatable, if known.
+pub async fn aave_v3_liquidation<M: Middleware>(
+    provider: &M,
+    position: &AaveV3Position,
+    trigger: Option<H256>,
+    block: Option<BlockId>,
+) -> Result<Option<Opportunity>, LiquidationError> {
+    let health_factor = health_factor(provider, position.market, position.borrower, block).await?;
+    if health_factor >= U256::from(HEALTH_FACTOR_ONE) {
+        return Ok(None);
+    }
+    let debt = call_uint(
+        provider,
+        position.variable_debt_token,
+        "balanceOf(address)",
+        &[Token::Address(position.borrower)],
+        block,
+    )
+    .await
+    .ok_or(LiquidationError::DebtUnavailable(position.borrower))?;
+    let debt_to_cover = debt * close_factor_bps(health_factor) / MAX_CLOSE_FACTOR_BPS;
+    if debt_to_cover.is_zero() {
+        return Ok(None);
+    }
+    Ok(Some(Opportunity::Liquidation(LiquidationOpportunity {
+        trigger,
+        protocol: "aave-v3".to_string(),
+        market: position.market,
+        borrower: position.borrower,
+        collateral: position.collateral,
+        debt: position.debt,
+        debt_to_cover,
+        base: None,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{abi, providers::Provider, types::Bytes};
+
+    fn account_data(health_factor: U256) -> Bytes {
+        let mut words = vec![Token::Uint(U256::from(1_000)); 5];
+        words.push(Token::Uint(health_factor));
+        abi::encode(&words).into()
+    }
+
+    #[tokio::test]
+    async fn test_aave_v3_liquidation() {
+        let position = AaveV3Position {
+            market: Address::from_low_u64_be(1),
+            borrower: Address::from_low_u64_be(2),
+            collateral: Address::from_low_u64_be(3),
+            debt: Address::from_low_u64_be(4),
+            variable_debt_token: Address::from_low_u64_be(5),
+        };
+        let (provider, mock) = Provider::mocked();
+        let wad = U256::from(HEALTH_FACTOR_ONE);
+        let debt: Bytes = abi::encode(&[Token::Uint(U256::from(1_000))]).into();
+
+        // healthy, the debt isn't read
+        mock.push::<Bytes, _>(account_data(wad)).unwrap();
+        assert!(aave_v3_liquidation(&provider, &position, None, None)
+            .await
+            .unwrap()
+            .is_none());
+
+        // half the debt at 0.98, responses are served last in first out
+        mock.push::<Bytes, _>(debt.clone()).unwrap();
+        mock.push::<Bytes, _>(account_data(wad * 98 / 100)).unwrap();
+        let trigger = Some(H256::from_low_u64_be(7));
+        let Some(Opportunity::Liquidation(liquidation)) =
+            aave_v3_liquidation(&provider, &position, trigger, None)
+                .await
+                .unwrap()
+        else {
+            panic!("position not liquidatable");
+        };
+        assert_eq!(liquidation.debt_to_cover, U256::from(500));
+        assert_eq!(liquidation.borrower, position.borrower);
+        assert_eq!(liquidation.trigger, trigger);
+
+        // all of it at 0.9
+        mock.push::<Bytes, _>(debt).unwrap();
+        mock.push::<Bytes, _>(account_data(wad * 9 / 10)).unwrap();
+        let opportunity = aave_v3_liquidation(&provider, &position, None, None)
+            .await
+            .unwrap()
+            .unwrap();
+        let Opportunity::Liquidation(liquidation) = opportunity else {
+            panic!("not a liquidation");
+        };
+        assert_eq!(liquidation.debt_to_cover, U256::from(1_000));
+    }
+}
@@ -1,29 +1,219 @@
 use super::state_diff::{get_from_txs, StateDiffError};
-use crate::types::NewTx;
+use super::trace_client::{ParityTraceClient, TraceClient};
+use crate::latency::{OpportunityTimeline, Stage};
+use crate::pair_discovery::{self, PairDiscovery};
+use crate::types::{CancelReason, CancelledTx, MempoolEvent, NewTx};
 use anyhow::Result;
 use artemis::types::{Collector, CollectorStream};
 use async_trait::async_trait;
+use dashmap::DashMap;
 use ethers::{
     prelude::Middleware,
     providers::PubsubClient,
     types::{AccountDiff, Block, BlockNumber, Transaction, H160, H256, U256, U64},
 };
-use log::error;
-use parking_lot::RwLock;
+use fork_database::shared_backend::SharedBackend;
+use hashbrown::HashMap;
+use log::{debug, error};
+use parking_lot::{Mutex, RwLock};
+use qilin_cfmms::{pool::Pool, registry::PoolRegistry};
 use std::collections::BTreeMap;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio::runtime::Runtime;
 use tokio_stream::StreamExt;
 
+/// Minimum fee bump for a tx to replace another with the same sender and nonce, same as geth
+const REPLACEMENT_BUMP_PERCENT: u64 = 10;
+/// Blocks a tx is tracked for before it's considered dropped from the mempool
+const MAX_PENDING_BLOCKS: u64 = 25;
+
 pub struct QilinMempoolCollector<M> {
     provider: Arc<M>,
+    tracer: Arc<dyn TraceClient>,
     block: RwLock<Block<H256>>,
+    tracker: Mutex<TxTracker>,
+    /// deployments spotted in pending txs, and the fork db backend their tokens are preloaded into
+    discovery: Option<(Mutex<PairDiscovery>, SharedBackend)>,
+    /// the bot's pools discovered pools are registered with, by address and by pair
+    pools: Option<(Arc<PoolRegistry>, Arc<DashMap<H160, Vec<Pool>>>)>,
+}
+
+/// Outcome of observing a pending tx with [TxTracker::observe]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackStatus {
+    /// First time seen
+    New,
+    /// Rebroadcast of a tracked or already invalidated tx
+    Duplicate,
+    /// Fee bump of a tracked tx, which is cancelled
+    Replacement(CancelledTx),
+    /// Same sender and nonce as a tracked tx without a sufficient fee bump, it won't replace it
+    Underpriced,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TrackedTx {
+    hash: H256,
+    max_fee: U256,
+    priority_fee: U256,
+    seen_at: U64,
+}
+
+/// Deduplicates pending txs and tracks their replacement and inclusion
+///
+/// Every tx emitted to the strategies is tracked by sender and nonce until it's replaced, mined
+/// or dropped, so strategies can be told to abandon in flight work on a stale victim.
+#[derive(Debug, Default)]
+pub struct TxTracker {
+    pending: HashMap<(H160, U256), TrackedTx>,
+    nonce_of: HashMap<H256, (H160, U256)>,
+    /// txs no longer pending, kept around to drop late rebroadcasts
+    invalidated: HashMap<H256, U64>,
+    block: U64,
+}
+
+impl TxTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Latest block given to [TxTracker::on_block]
+    pub fn block(&self) -> U64 {
+        self.block
+    }
+
+    pub fn is_pending(&self, hash: &H256) -> bool {
+        self.nonce_of.contains_key(hash)
+    }
+
+    /// Number of txs currently tracked as pending
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Track a pending tx, `tx.from` has to be recovered already
+    pub fn observe(&mut self, tx: &Transaction) -> TrackStatus {
+        if self.nonce_of.contains_key(&tx.hash) || self.invalidated.contains_key(&tx.hash) {
+            return TrackStatus::Duplicate;
+        }
+
+        let (max_fee, priority_fee) = fees_of(tx);
+        let incoming = TrackedTx {
+            hash: tx.hash,
+            max_fee,
+            priority_fee,
+            seen_at: self.block,
+        };
+
+        let key = (tx.from, tx.nonce);
+        let status = match self.pending.get(&key) {
+            None => TrackStatus::New,
+            Some(current) if !is_sufficient_bump(current, &incoming) => {
+                return TrackStatus::Underpriced
+            }
+            Some(current) => {
+                let cancelled = CancelledTx {
+                    hash: current.hash,
+                    reason: CancelReason::Replaced(tx.hash),
+                };
+                self.nonce_of.remove(&current.hash);
+                self.invalidated.insert(current.hash, self.block);
+                TrackStatus::Replacement(cancelled)
+            }
+        };
+
+        self.pending.insert(key, incoming);
+        self.nonce_of.insert(tx.hash, key);
+        status
+    }
+
+    /// Invalidate the tracked txs mined, or whose nonce got consumed, in `block`
+    ///
+    /// Also drops txs pending for more than [MAX_PENDING_BLOCKS], no cancel is emitted for those
+    /// as they may still land.
+    pub fn on_block(&mut self, block: &Block<Transaction>) -> Vec<CancelledTx> {
+        let number = block.number.unwrap_or_default();
+        self.block = self.block.max(number);
+
+        let mut cancelled = vec![];
+        for mined in &block.transactions {
+            let stale: Vec<(H160, U256)> = self
+                .pending
+                .keys()
+                .filter(|(sender, nonce)| *sender == mined.from && *nonce <= mined.nonce)
+                .copied()
+                .collect();
+
+            for key in stale {
+                let tracked = self.pending.remove(&key).unwrap();
+                let reason = if tracked.hash == mined.hash {
+                    CancelReason::Mined(number)
+                } else {
+                    CancelReason::NonceConsumed(number)
+                };
+                self.nonce_of.remove(&tracked.hash);
+                self.invalidated.insert(tracked.hash, number);
+                cancelled.push(CancelledTx {
+                    hash: tracked.hash,
+                    reason,
+                });
+            }
+        }
+
+        self.prune();
+        cancelled
+    }
+
+    fn prune(&mut self) {
+        let cutoff = self.block.saturating_sub(U64::from(MAX_PENDING_BLOCKS));
+
+        let nonce_of = &mut self.nonce_of;
+        self.pending.retain(|_, tracked| {
+            let keep = tracked.seen_at >= cutoff;
+            if !keep {
+                nonce_of.remove(&tracked.hash);
+            }
+            keep
+        });
+        self.invalidated.retain(|_, at| *at >= cutoff);
+    }
+}
+
+/// `(max fee, priority fee)` of a tx, legacy txs pay their gas price for both
+fn fees_of(tx: &Transaction) -> (U256, U256) {
+    match (tx.max_fee_per_gas, tx.max_priority_fee_per_gas) {
+        (Some(max_fee), Some(priority_fee)) => (max_fee, priority_fee),
+        _ => {
+            let gas_price = tx.gas_price.unwrap_or_default();
+            (gas_price, gas_price)
+        }
+    }
+}
+
+fn is_sufficient_bump(current: &TrackedTx, incoming: &TrackedTx) -> bool {
+    let bumped = |fee: U256| fee * (100 + REPLACEMENT_BUMP_PERCENT) / 100;
+    incoming.max_fee >= bumped(current.max_fee)
+        && incoming.priority_fee >= bumped(current.priority_fee)
 }
 
 impl NewTx {
     pub fn new(tx: Transaction, state_diff: BTreeMap<H160, AccountDiff>) -> Self {
-        Self { tx, state_diff }
+        let timeline = OpportunityTimeline::new(tx.hash);
+        Self {
+            tx,
+            state_diff,
+            timeline,
+        }
+    }
+
+    pub fn with_timeline(mut self, timeline: OpportunityTimeline) -> Self {
+        self.timeline = timeline;
+        self
     }
 }
 
@@ -69,11 +259,122 @@ where
 {
     pub fn new(provider: Arc<M>, block: Block<H256>) -> Self {
         Self {
+            tracer: Arc::new(ParityTraceClient::new(provider.clone())),
             provider,
             block: RwLock::new(block),
+            tracker: Mutex::new(TxTracker::new()),
+            discovery: None,
+            pools: None,
         }
     }
 
+    /// Emit the pools and tokens pending txs deploy, preloading the tokens of new pools into
+    /// `backend`
+    pub fn with_pair_discovery(mut self, discovery: PairDiscovery, backend: SharedBackend) -> Self {
+        self.discovery = Some((Mutex::new(discovery), backend));
+        self
+    }
+
+    /// Register the pools discovery finds with `registry` and `hash_pools`, each priced with the
+    /// model of its code, see [PairDiscovery::register]
+    pub fn with_pool_registry(
+        mut self,
+        registry: Arc<PoolRegistry>,
+        hash_pools: Arc<DashMap<H160, Vec<Pool>>>,
+    ) -> Self {
+        self.pools = Some((registry, hash_pools));
+        self
+    }
+
+    /// Trace through `tracer` instead of `trace_callMany`, e.g. the one
+    /// [detected](crate::trace_client::detect_trace_client) for the node
+    pub fn with_trace_client(mut self, tracer: Arc<dyn TraceClient>) -> Self {
+        self.tracer = tracer;
+        self
+    }
+
+    pub fn tracker(&self) -> &Mutex<TxTracker> {
+        &self.tracker
+    }
+
+    /// Feed the mined txs of the latest block to the tracker, if it hasn't seen it yet
+    async fn sync_mined_txs(&self) -> Result<Vec<CancelledTx>, MempoolCollectorError<M>> {
+        let block_num = self.block.read().number.unwrap_or_default();
+        if block_num <= self.tracker.lock().block() {
+            return Ok(vec![]);
+        }
+
+        let block = match self.provider.get_block_with_txs(block_num).await {
+            Ok(Some(block)) => block,
+            Ok(None) => return Err(MempoolCollectorError::BlockError),
+            Err(e) => return Err(MempoolCollectorError::MiddlewareError(e)),
+        };
+
+        self.route_deployed(block_num).await;
+        Ok(self.tracker.lock().on_block(&block))
+    }
+
+    /// Route the discovered pools deployed by `block` since they were registered
+    async fn route_deployed(&self, block: U64) {
+        let (Some((discovery, backend)), Some((registry, hash_pools))) =
+            (self.discovery.as_ref(), self.pools.as_ref())
+        else {
+            return;
+        };
+        let unresolved = discovery.lock().unresolved().to_vec();
+        let mut deployed = vec![];
+        for pool in unresolved {
+            if self.has_code(pool, block).await {
+                deployed.push(pool);
+            }
+        }
+        if deployed.is_empty() {
+            return;
+        }
+        let resolved = discovery
+            .lock()
+            .route_deployed(backend, &deployed, registry, hash_pools);
+        debug!("Routed {} discovered pools now deployed", resolved);
+    }
+
+    async fn has_code(&self, address: H160, block: U64) -> bool {
+        match self
+            .provider
+            .get_code(address, Some(BlockNumber::Number(block).into()))
+            .await
+        {
+            Ok(code) => !code.is_empty(),
+            Err(e) => {
+                debug!("Could not check {:?} is deployed: {}", address, e);
+                false
+            }
+        }
+    }
+
+    /// The deployment `tx` makes, with the tokens of a new pool preloaded
+    async fn discover(&self, tx: &Transaction) -> Option<pair_discovery::Deployment> {
+        let (discovery, backend) = self.discovery.as_ref()?;
+        let deployment = discovery.lock().on_pending(tx)?;
+        let block = BlockNumber::Number(self.block.read().number.unwrap_or_default()).into();
+        pair_discovery::preload(self.provider.as_ref(), backend, &deployment, block).await;
+        if let (pair_discovery::Deployment::Pool { pool, .. }, Some((registry, hash_pools))) =
+            (&deployment, self.pools.as_ref())
+        {
+            // the pending tx deploys it, unless it's a rebroadcast of a mined one
+            let number = self.block.read().number.unwrap_or_default();
+            if self.has_code(pool.address, number).await {
+                discovery
+                    .lock()
+                    .register(backend, *pool, registry, hash_pools);
+            } else {
+                discovery
+                    .lock()
+                    .register_pending(*pool, registry, hash_pools);
+            }
+        }
+        Some(deployment)
+    }
+
     async fn update_block(&self, new_block: Block<H256>) {
         let mut block_writer = self.block.write();
         *block_writer = new_block;
@@ -121,8 +422,8 @@ where
         tx: &mut Transaction,
     ) -> Result<BTreeMap<H160, AccountDiff>, MempoolCollectorError<M>> {
         let state_diffs = if let Some(state_diff) = get_from_txs(
-            &self.provider,
-            &vec![tx.clone()],
+            self.tracer.as_ref(),
+            &[tx.clone()],
             BlockNumber::Number(self.block.read().number.unwrap_or(U64::zero())).into(),
         )
         .await
@@ -137,13 +438,13 @@ where
 }
 
 #[async_trait]
-impl<M> Collector<NewTx> for QilinMempoolCollector<M>
+impl<M> Collector<MempoolEvent> for QilinMempoolCollector<M>
 where
     M: Middleware + 'static,
     M::Provider: PubsubClient,
     M::Error: 'static,
 {
-    async fn get_event_stream(&self) -> Result<CollectorStream<'_, NewTx>> {
+    async fn get_event_stream(&self) -> Result<CollectorStream<'_, MempoolEvent>> {
         let stream = self.provider.subscribe_pending_txs().await?;
         let stream = stream.transactions_unordered(256);
         let stream = stream.filter_map(|res| {
@@ -154,22 +455,131 @@ where
             } else {
                 return None;
             };
+            let mut timeline = OpportunityTimeline::new(incoming_tx.hash);
 
             let tx = if let Ok(tx) = rt.block_on(self.drop_max_fee_per_gas_tx(&mut incoming_tx)) {
                 tx
             } else {
                 return None;
             };
+            timeline.mark(Stage::Decoded);
 
-            let state_diff = rt.block_on(self.get_account_diffs(tx));
-
-            if let Some(state_diff) = state_diff.ok() {
-                let res = NewTx::new(tx.clone(), state_diff);
-                return Some(res);
-            } else {
-                return None;
+            let mut events: Vec<MempoolEvent> = match rt.block_on(self.sync_mined_txs()) {
+                Ok(cancelled) => cancelled.into_iter().map(MempoolEvent::Cancel).collect(),
+                Err(e) => {
+                    error!("Error syncing mined txs: {:?}", e);
+                    vec![]
+                }
             };
+
+            match self.tracker.lock().observe(tx) {
+                TrackStatus::New => {}
+                TrackStatus::Replacement(cancelled) => events.push(MempoolEvent::Cancel(cancelled)),
+                status => {
+                    debug!("Skipping tx {:?}: {:?}", tx.hash, status);
+                    return Some(events);
+                }
+            }
+
+            if let Some(deployment) = rt.block_on(self.discover(tx)) {
+                events.push(MempoolEvent::Deployment(deployment));
+            }
+
+            if let Some(state_diff) = rt.block_on(self.get_account_diffs(tx)).ok() {
+                timeline.mark(Stage::Traced);
+                let new_tx = NewTx::new(tx.clone(), state_diff).with_timeline(timeline);
+                events.push(MempoolEvent::NewTx(new_tx));
+            }
+            Some(events)
         });
+        let stream = futures::StreamExt::flat_map(stream, futures::stream::iter);
         Ok(Box::pin(stream))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx(hash: u64, from: u64, nonce: u64, max_fee: u64, priority_fee: u64) -> Transaction {
+        Transaction {
+            hash: H256::from_low_u64_be(hash),
+            from: H160::from_low_u64_be(from),
+            nonce: U256::from(nonce),
+            max_fee_per_gas: Some(U256::from(max_fee)),
+            max_priority_fee_per_gas: Some(U256::from(priority_fee)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_tx_tracker_replacement() {
+        let mut tracker = TxTracker::new();
+        let victim = tx(1, 1, 0, 100, 10);
+        assert_eq!(tracker.observe(&victim), TrackStatus::New);
+        assert_eq!(tracker.observe(&victim), TrackStatus::Duplicate);
+
+        // a 5% bump isn't enough to replace it
+        assert_eq!(
+            tracker.observe(&tx(2, 1, 0, 105, 11)),
+            TrackStatus::Underpriced
+        );
+        assert!(tracker.is_pending(&victim.hash));
+
+        let bumped = tx(3, 1, 0, 110, 11);
+        assert_eq!(
+            tracker.observe(&bumped),
+            TrackStatus::Replacement(CancelledTx {
+                hash: victim.hash,
+                reason: CancelReason::Replaced(bumped.hash),
+            })
+        );
+        assert!(!tracker.is_pending(&victim.hash));
+        assert!(tracker.is_pending(&bumped.hash));
+
+        // late rebroadcast of the replaced tx
+        assert_eq!(tracker.observe(&victim), TrackStatus::Duplicate);
+        assert_eq!(tracker.len(), 1);
+    }
+
+    #[test]
+    fn test_tx_tracker_on_block() {
+        let mut tracker = TxTracker::new();
+        let mined = tx(1, 1, 0, 100, 10);
+        let consumed = tx(2, 2, 5, 100, 10);
+        let next = tx(3, 1, 1, 100, 10);
+        for tx in [&mined, &consumed, &next] {
+            assert_eq!(tracker.observe(tx), TrackStatus::New);
+        }
+
+        let block = Block {
+            number: Some(U64::from(10)),
+            transactions: vec![mined.clone(), tx(4, 2, 5, 200, 20)],
+            ..Default::default()
+        };
+        let cancelled = tracker.on_block(&block);
+        assert_eq!(
+            cancelled,
+            vec![
+                CancelledTx {
+                    hash: mined.hash,
+                    reason: CancelReason::Mined(U64::from(10)),
+                },
+                CancelledTx {
+                    hash: consumed.hash,
+                    reason: CancelReason::NonceConsumed(U64::from(10)),
+                },
+            ]
+        );
+        assert!(tracker.is_pending(&next.hash));
+        assert_eq!(tracker.observe(&mined), TrackStatus::Duplicate);
+
+        // dropped once pending for too long
+        let block = Block {
+            number: Some(U64::from(10 + MAX_PENDING_BLOCKS + 1)),
+            ..Default::default()
+        };
+        assert!(tracker.on_block(&block).is_empty());
+        assert!(tracker.is_empty());
+    }
+}
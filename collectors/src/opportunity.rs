@@ -0,0 +1,264 @@
+//! Opportunities handed from detection to optimization and execution
+//!
+//! Detection hands over an [Opportunity] carrying everything the next stages need. Sandwiches and
+//! arbs come from the state diff extractors in [state_diff](crate::state_diff), liquidations from
+//! [aave_v3_liquidation](crate::liquidation::aave_v3_liquidation).
+//!
+//! Opportunities leaving the process, e.g. to the bundle store or a remote optimizer, are
+//! serialized as a [VersionedOpportunity]. Bump [OPPORTUNITY_SCHEMA_VERSION] with every change
+//! that breaks the format, readers reject versions they don't know.
+
+use ethers::types::{Address, Transaction, H256, U256};
+use fork_database::stale::BaseBlock;
+use qilin_cfmms::pool::Pool;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub const OPPORTUNITY_SCHEMA_VERSION: u32 = 2;
+
+#[derive(Error, Debug)]
+pub enum OpportunitySchemaError {
+    #[error("Unsupported opportunity schema version {0}, expected {OPPORTUNITY_SCHEMA_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("Malformed opportunity: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// A victim tx moving a pool's price far enough to be sandwiched
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SandwichOpportunity {
+    /// the victim as seen in the mempool, bundled between the frontrun and the backrun
+    pub victim: Transaction,
+    pub pool: Pool,
+    /// whether the victim sells WETH into the pool
+    pub is_weth_input: bool,
+    /// block the opportunity was found on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<BaseBlock>,
+}
+
+impl SandwichOpportunity {
+    pub fn new(victim: Transaction, pool: Pool, is_weth_input: bool) -> Self {
+        Self {
+            victim,
+            pool,
+            is_weth_input,
+            base: None,
+        }
+    }
+}
+
+/// A pool moved away from the price of another pool with the same tokens
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TwoPoolArbOpportunity {
+    /// tx moving the pool, `None` for dislocations found in a block's state
+    pub trigger: Option<H256>,
+    /// pool the asset is bought from
+    pub moved: Pool,
+    /// pool the asset is sold to
+    pub counter: Pool,
+    /// token the arb starts and ends with
+    pub token_in: Address,
+    /// block the opportunity was found on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<BaseBlock>,
+}
+
+/// A cycle through several pools, starting and ending with `token_in`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MultiHopArbOpportunity {
+    pub trigger: Option<H256>,
+    /// pools in the order they are traded
+    pub pools: Vec<Pool>,
+    pub token_in: Address,
+    /// block the opportunity was found on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<BaseBlock>,
+}
+
+/// An undercollateralized lending position
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LiquidationOpportunity {
+    /// e.g. an oracle update making the position liquidatable
+    pub trigger: Option<H256>,
+    /// lending market the position lives in, e.g. `"aave-v3"`
+    pub protocol: String,
+    /// contract `liquidationCall` goes to
+    pub market: Address,
+    pub borrower: Address,
+    /// collateral seized
+    pub collateral: Address,
+    /// debt repaid
+    pub debt: Address,
+    pub debt_to_cover: U256,
+    /// block the opportunity was found on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<BaseBlock>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+// adjacently tagged, internally tagged enums buffer their content and lose the u128 reserves
+#[serde(tag = "kind", content = "data", rename_all = "snake_case")]
+pub enum Opportunity {
+    Sandwich(SandwichOpportunity),
+    TwoPoolArb(TwoPoolArbOpportunity),
+    MultiHopArb(MultiHopArbOpportunity),
+    Liquidation(LiquidationOpportunity),
+}
+
+impl Opportunity {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Sandwich(_) => "sandwich",
+            Self::TwoPoolArb(_) => "two_pool_arb",
+            Self::MultiHopArb(_) => "multi_hop_arb",
+            Self::Liquidation(_) => "liquidation",
+        }
+    }
+
+    /// Tx the opportunity depends on
+    pub fn trigger(&self) -> Option<H256> {
+        match self {
+            Self::Sandwich(sandwich) => Some(sandwich.victim.hash),
+            Self::TwoPoolArb(arb) => arb.trigger,
+            Self::MultiHopArb(arb) => arb.trigger,
+            Self::Liquidation(liquidation) => liquidation.trigger,
+        }
+    }
+
+    /// Block the opportunity was found on, see [ForkHead](fork_database::stale::ForkHead)
+    pub fn base(&self) -> Option<BaseBlock> {
+        match self {
+            Self::Sandwich(sandwich) => sandwich.base,
+            Self::TwoPoolArb(arb) => arb.base,
+            Self::MultiHopArb(arb) => arb.base,
+            Self::Liquidation(liquidation) => liquidation.base,
+        }
+    }
+
+    pub fn with_base(mut self, base: BaseBlock) -> Self {
+        match &mut self {
+            Self::Sandwich(sandwich) => sandwich.base = Some(base),
+            Self::TwoPoolArb(arb) => arb.base = Some(base),
+            Self::MultiHopArb(arb) => arb.base = Some(base),
+            Self::Liquidation(liquidation) => liquidation.base = Some(base),
+        }
+        self
+    }
+
+    /// Pools traded through, e.g. to check for conflicts with other opportunities
+    pub fn pools(&self) -> Vec<&Pool> {
+        match self {
+            Self::Sandwich(sandwich) => vec![&sandwich.pool],
+            Self::TwoPoolArb(arb) => vec![&arb.moved, &arb.counter],
+            Self::MultiHopArb(arb) => arb.pools.iter().collect(),
+            Self::Liquidation(_) => vec![],
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, OpportunitySchemaError> {
+        Ok(serde_json::to_string(&VersionedOpportunity::new(
+            self.clone(),
+        ))?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, OpportunitySchemaError> {
+        serde_json::from_str::<VersionedOpportunity>(json)?.into_current()
+    }
+}
+
+/// Serialized form of an [Opportunity]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionedOpportunity {
+    pub schema_version: u32,
+    pub opportunity: Opportunity,
+}
+
+impl VersionedOpportunity {
+    pub fn new(opportunity: Opportunity) -> Self {
+        Self {
+            schema_version: OPPORTUNITY_SCHEMA_VERSION,
+            opportunity,
+        }
+    }
+
+    pub fn into_current(self) -> Result<Opportunity, OpportunitySchemaError> {
+        if self.schema_version != OPPORTUNITY_SCHEMA_VERSION {
+            return Err(OpportunitySchemaError::UnsupportedVersion(
+                self.schema_version,
+            ));
+        }
+        Ok(self.opportunity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qilin_cfmms::test_utils::{address, empty_pool as pool};
+
+    #[test]
+    fn test_versioned_roundtrip() {
+        let victim = Transaction {
+            hash: H256::from_low_u64_be(7),
+            from: address(3),
+            input: vec![0x7f, 0xf3, 0x6a, 0xb5].into(),
+            ..Default::default()
+        };
+        let trigger = Some(victim.hash);
+        let base = BaseBlock::new(100, H256::from_low_u64_be(100));
+        let opportunities = vec![
+            Opportunity::TwoPoolArb(TwoPoolArbOpportunity {
+                trigger,
+                moved: pool(10),
+                counter: pool(11),
+                token_in: address(2),
+                base: None,
+            }),
+            Opportunity::MultiHopArb(MultiHopArbOpportunity {
+                trigger,
+                pools: vec![pool(10), pool(12), pool(13)],
+                token_in: address(2),
+                base: None,
+            }),
+            Opportunity::Liquidation(LiquidationOpportunity {
+                trigger: None,
+                protocol: "aave-v3".to_string(),
+                market: address(20),
+                borrower: address(21),
+                collateral: address(1),
+                debt: address(2),
+                debt_to_cover: U256::from(500),
+                base: None,
+            }),
+            Opportunity::Sandwich(SandwichOpportunity::new(victim.clone(), pool(10), true))
+                .with_base(base),
+        ];
+        assert_eq!(opportunities[3].trigger(), Some(victim.hash));
+        assert_eq!(opportunities[3].base(), Some(base));
+        assert_eq!(opportunities[0].base(), None);
+        assert_eq!(opportunities[1].pools().len(), 3);
+        assert!(opportunities[2].pools().is_empty());
+        for opportunity in &opportunities {
+            let json = opportunity.to_json().unwrap();
+            assert_eq!(&Opportunity::from_json(&json).unwrap(), opportunity);
+        }
+
+        // the victim travels in full, later stages don't look it up again
+        let json = opportunities[3].to_json().unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["schema_version"], 2);
+        assert_eq!(value["opportunity"]["kind"], "sandwich");
+        assert_eq!(
+            value["opportunity"]["data"]["victim"]["input"],
+            "0x7ff36ab5"
+        );
+
+        // a format from the future is rejected instead of misread
+        let newer = json.replace("\"schema_version\":2", "\"schema_version\":3");
+        assert!(matches!(
+            Opportunity::from_json(&newer),
+            Err(OpportunitySchemaError::UnsupportedVersion(3))
+        ));
+    }
+}
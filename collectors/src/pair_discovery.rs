@@ -0,0 +1,564 @@
+//! New pairs and tokens found in pending txs, before they are mined
+//!
+//! A pending `createPair` or `createPool` to a known factory gives the pool's address up front,
+//! the factories deploy with CREATE2. [PairDiscovery] registers the pool with the bot's pool maps
+//! as soon as the tx is seen, so the first swaps into it can be arbed in the block it lands in.
+//! Contract creations whose init code carries the ERC20 selectors are reported as new tokens.
+//!
+//! Tokens of new pools are queued for the token safety checks, see
+//! [PairDiscovery::take_unvetted], nothing should trade them before those ran.
+//!
+//! A factory's pools aren't trusted to run the factory's math, [PairDiscovery::register] prices
+//! each with the model its code implements, see [PoolClassifier::route]. Pools not deployed yet
+//! are registered as decoded and routed once they are, by [PairDiscovery::route_deployed].
+
+use std::collections::{HashSet, VecDeque};
+
+use dashmap::DashMap;
+use ethers::{
+    abi::{self, ParamType, Token},
+    providers::Middleware,
+    types::{Address, BlockId, Transaction, H160, H256, U256},
+    utils::{get_contract_address, get_create2_address_from_hash, id, keccak256},
+};
+use fork_database::{shared_backend::SharedBackend, utils::h160_to_b160};
+use qilin_cfmms::{
+    pool::{Pool, PoolVariant},
+    registry::PoolRegistry,
+};
+use revm::db::DatabaseRef;
+use std::fmt::Debug;
+
+use crate::pool_classifier::{PoolClassifier, Routing};
+use crate::state_diff::pair_key;
+
+const CREATE_PAIR: &str = "createPair(address,address)";
+const CREATE_POOL: &str = "createPool(address,address,uint24)";
+/// `transfer(address,uint256)`, `balanceOf(address)` and `approve(address,uint256)`
+const ERC20_SELECTORS: [[u8; 4]; 3] = [
+    [0xa9, 0x05, 0x9c, 0xbb],
+    [0x70, 0xa0, 0x82, 0x31],
+    [0x09, 0x5e, 0xa7, 0xb3],
+];
+/// Deployments remembered to drop rebroadcasts of their txs
+const MAX_SEEN: usize = 10_000;
+
+/// A factory deploying pools with CREATE2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Factory {
+    pub address: Address,
+    pub variant: PoolVariant,
+    /// keccak of the pool's creation code
+    pub init_code_hash: H256,
+}
+
+impl Factory {
+    /// Uniswap V2 and V3 on mainnet
+    pub fn mainnet() -> Vec<Factory> {
+        vec![
+            Factory {
+                address: "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f"
+                    .parse()
+                    .unwrap(),
+                variant: PoolVariant::UniswapV2,
+                init_code_hash:
+                    "0x96e8ac4277198ff8b6f785478aa9a39f403cb768dd02cbee326c3e7da348845f"
+                        .parse()
+                        .unwrap(),
+            },
+            Factory {
+                address: "0x1F98431c8aD98523631AE4a59f267346ea31F984"
+                    .parse()
+                    .unwrap(),
+                variant: PoolVariant::UniswapV3,
+                init_code_hash:
+                    "0xe34f199b19b2b4f47f68442619d555527d244f78a3297ea89325f843f87b8b54"
+                        .parse()
+                        .unwrap(),
+            },
+        ]
+    }
+
+    /// Address of the pool the factory deploys for the tokens, `fee` only matters to V3
+    pub fn pool_address(&self, token_0: Address, token_1: Address, fee: U256) -> Address {
+        let salt = match self.variant {
+            PoolVariant::UniswapV2 => keccak256([token_0.as_bytes(), token_1.as_bytes()].concat()),
+            PoolVariant::UniswapV3 => keccak256(abi::encode(&[
+                Token::Address(token_0),
+                Token::Address(token_1),
+                Token::Uint(fee),
+            ])),
+        };
+        get_create2_address_from_hash(self.address, salt, self.init_code_hash)
+    }
+}
+
+/// Something a pending tx deploys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Deployment {
+    Pool {
+        tx: H256,
+        factory: Address,
+        pool: Pool,
+    },
+    Token {
+        tx: H256,
+        deployer: Address,
+        token: Address,
+    },
+}
+
+impl Deployment {
+    pub fn tx(&self) -> H256 {
+        match self {
+            Self::Pool { tx, .. } | Self::Token { tx, .. } => *tx,
+        }
+    }
+
+    /// Tokens deployed or traded by the new pool
+    pub fn tokens(&self) -> Vec<Address> {
+        match self {
+            Self::Pool { pool, .. } => vec![pool.token_0, pool.token_1],
+            Self::Token { token, .. } => vec![*token],
+        }
+    }
+}
+
+/// Watches pending txs for deployments
+#[derive(Debug, Default)]
+pub struct PairDiscovery {
+    factories: Vec<Factory>,
+    seen: HashSet<Address>,
+    seen_order: VecDeque<Address>,
+    unvetted: VecDeque<Address>,
+    classifier: PoolClassifier,
+    /// registered before their implementation could be told
+    unresolved: Vec<Address>,
+}
+
+impl PairDiscovery {
+    pub fn new(factories: Vec<Factory>) -> Self {
+        Self {
+            factories,
+            ..Default::default()
+        }
+    }
+
+    /// Route new pools with `classifier`, e.g. one with the code hashes of known forks pinned
+    pub fn with_classifier(mut self, classifier: PoolClassifier) -> Self {
+        self.classifier = classifier;
+        self
+    }
+
+    /// The pool or token `tx` deploys, `None` if it deploys neither or was seen before
+    pub fn on_pending(&mut self, tx: &Transaction) -> Option<Deployment> {
+        let deployment = self.decode(tx)?;
+        let address = match &deployment {
+            Deployment::Pool { pool, .. } => pool.address,
+            Deployment::Token { token, .. } => *token,
+        };
+        if !self.seen.insert(address) {
+            return None;
+        }
+        self.seen_order.push_back(address);
+        if self.seen_order.len() > MAX_SEEN {
+            let oldest = self.seen_order.pop_front().unwrap();
+            self.seen.remove(&oldest);
+        }
+        for token in deployment.tokens() {
+            if !self.unvetted.contains(&token) {
+                self.unvetted.push_back(token);
+            }
+        }
+        Some(deployment)
+    }
+
+    /// Tokens of new deployments not handed to the safety checks yet
+    pub fn take_unvetted(&mut self) -> Vec<Address> {
+        self.unvetted.drain(..).collect()
+    }
+
+    /// Register `pool` with the bot's pools, priced with the model of its code on `db`. Pools the
+    /// bot has no model for are left out. Returns whether the pool was registered.
+    pub fn register<DB>(
+        &mut self,
+        db: &DB,
+        pool: Pool,
+        registry: &PoolRegistry,
+        hash_pools: &DashMap<H160, Vec<Pool>>,
+    ) -> bool
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        match self.classifier.route(db, pool) {
+            Routing::Routed(pool) => register_pool(pool, registry, hash_pools),
+            Routing::Unresolved(pool) => {
+                let registered = register_pool(pool, registry, hash_pools);
+                if registered {
+                    self.unresolved.push(pool.address);
+                }
+                registered
+            }
+            Routing::Unsupported(implementation) => {
+                log::debug!(
+                    "Not registering pool {:?}, no model for {:?}",
+                    pool.address,
+                    implementation
+                );
+                false
+            }
+        }
+    }
+
+    /// Register `pool` as decoded, without looking it up: it isn't deployed yet, and reading
+    /// it would cache an empty account in a fork db. It's routed once deployed.
+    pub fn register_pending(
+        &mut self,
+        pool: Pool,
+        registry: &PoolRegistry,
+        hash_pools: &DashMap<H160, Vec<Pool>>,
+    ) -> bool {
+        let registered = register_pool(pool, registry, hash_pools);
+        if registered {
+            self.unresolved.push(pool.address);
+        }
+        registered
+    }
+
+    /// Pools registered before their implementation could be told
+    pub fn unresolved(&self) -> &[Address] {
+        &self.unresolved
+    }
+
+    /// Route the [unresolved](PairDiscovery::unresolved) pools among `deployed` again, now that
+    /// `db` has their code: re-priced ones replace the registered pool, those without a model
+    /// are removed. Returns the number of pools resolved.
+    pub fn route_deployed<DB>(
+        &mut self,
+        db: &DB,
+        deployed: &[Address],
+        registry: &PoolRegistry,
+        hash_pools: &DashMap<H160, Vec<Pool>>,
+    ) -> usize
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        let before = self.unresolved.len();
+        let unresolved = std::mem::take(&mut self.unresolved);
+        for address in unresolved {
+            let pool = match registry.get(&address) {
+                Some(pool) if deployed.contains(&address) => pool,
+                Some(_) => {
+                    self.unresolved.push(address);
+                    continue;
+                }
+                None => continue,
+            };
+            let key = pair_key(pool.token_0, pool.token_1);
+            match self.classifier.route(db, pool) {
+                Routing::Routed(routed) => {
+                    registry.insert(routed);
+                    if let Some(mut pools) = hash_pools.get_mut(&key) {
+                        for registered in pools.iter_mut().filter(|p| p.address == address) {
+                            *registered = routed;
+                        }
+                    }
+                }
+                Routing::Unresolved(_) => self.unresolved.push(address),
+                Routing::Unsupported(implementation) => {
+                    log::debug!(
+                        "Dropping pool {:?}, no model for {:?}",
+                        address,
+                        implementation
+                    );
+                    registry.remove(&address);
+                    if let Some(mut pools) = hash_pools.get_mut(&key) {
+                        pools.retain(|p| p.address != address);
+                    }
+                }
+            }
+        }
+        before - self.unresolved.len()
+    }
+
+    fn decode(&self, tx: &Transaction) -> Option<Deployment> {
+        let Some(to) = tx.to else {
+            return is_erc20_init_code(&tx.input).then(|| Deployment::Token {
+                tx: tx.hash,
+                deployer: tx.from,
+                token: get_contract_address(tx.from, tx.nonce),
+            });
+        };
+        let factory = self.factories.iter().find(|f| f.address == to)?;
+        if tx.input.len() < 4 {
+            return None;
+        }
+        let (signature, params) = match factory.variant {
+            PoolVariant::UniswapV2 => (CREATE_PAIR, vec![ParamType::Address; 2]),
+            PoolVariant::UniswapV3 => (
+                CREATE_POOL,
+                vec![ParamType::Address, ParamType::Address, ParamType::Uint(24)],
+            ),
+        };
+        if tx.input[..4] != id(signature) {
+            return None;
+        }
+        let mut args = abi::decode(&params, &tx.input[4..]).ok()?.into_iter();
+        let token_a = args.next()?.into_address()?;
+        let token_b = args.next()?.into_address()?;
+        // V2 pairs pay 0.3%, as everywhere else in the pool maps
+        let fee = args
+            .next()
+            .and_then(Token::into_uint)
+            .unwrap_or_else(|| U256::from(3000));
+        if token_a == token_b {
+            return None;
+        }
+        let (token_0, token_1) = if token_a < token_b {
+            (token_a, token_b)
+        } else {
+            (token_b, token_a)
+        };
+        let address = factory.pool_address(token_0, token_1, fee);
+        Some(Deployment::Pool {
+            tx: tx.hash,
+            factory: factory.address,
+            pool: Pool::new_empty_pool(address, token_0, token_1, fee, factory.variant),
+        })
+    }
+}
+
+/// Add a new pool to the bot's pools, empty until it's deployed and synced. Returns whether it
+/// wasn't registered already.
+pub fn register_pool(
+    pool: Pool,
+    registry: &PoolRegistry,
+    hash_pools: &DashMap<H160, Vec<Pool>>,
+) -> bool {
+    if !registry.insert_new(pool) {
+        return false;
+    }
+    hash_pools
+        .entry(pair_key(pool.token_0, pool.token_1))
+        .or_default()
+        .push(pool);
+    true
+}
+
+/// Fetch the tokens a new pool's sims touch into the fork db, blocks on the backend
+///
+/// Only tokens with code at `block`, the block the backend is pinned to, are fetched. Tokens
+/// deployed by pending txs don't exist there yet, loading one would cache an empty account that
+/// outlives its deployment. Returns the number of tokens preloaded.
+pub async fn preload<M: Middleware>(
+    provider: &M,
+    backend: &SharedBackend,
+    deployment: &Deployment,
+    block: BlockId,
+) -> usize {
+    let Deployment::Pool { pool, .. } = deployment else {
+        return 0;
+    };
+    let mut preloaded = 0;
+    for token in [pool.token_0, pool.token_1] {
+        match provider.get_code(token, Some(block)).await {
+            Ok(code) if !code.is_empty() => {}
+            Ok(_) => continue,
+            Err(e) => {
+                log::debug!("Could not check {:?} exists: {}", token, e);
+                continue;
+            }
+        }
+        match backend.basic(h160_to_b160(token)) {
+            Ok(_) => preloaded += 1,
+            Err(e) => log::debug!("Could not preload {:?}: {}", token, e),
+        }
+    }
+    preloaded
+}
+
+/// Whether init code contains the PUSH4s of the ERC20 functions
+fn is_erc20_init_code(code: &[u8]) -> bool {
+    ERC20_SELECTORS.iter().all(|selector| {
+        code.windows(5)
+            .any(|window| window[0] == 0x63 && window[1..] == selector[..])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discovers_pending_pools() {
+        let usdc: Address = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48"
+            .parse()
+            .unwrap();
+        let weth: Address = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+            .parse()
+            .unwrap();
+        let factories = Factory::mainnet();
+        let call = |to: Address, signature: &str, mut params: Vec<Token>| {
+            let mut input = id(signature).to_vec();
+            params.insert(0, Token::Address(usdc));
+            params.insert(0, Token::Address(weth));
+            input.extend(abi::encode(&params));
+            Transaction {
+                hash: H256::from_low_u64_be(input.len() as u64),
+                to: Some(to),
+                input: input.into(),
+                ..Default::default()
+            }
+        };
+
+        let mut discovery = PairDiscovery::new(factories.clone());
+        let v2 = call(factories[0].address, CREATE_PAIR, vec![]);
+        let Some(Deployment::Pool { pool, .. }) = discovery.on_pending(&v2) else {
+            panic!("createPair not decoded");
+        };
+        assert_eq!(
+            pool.address,
+            "0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc"
+                .parse::<Address>()
+                .unwrap()
+        );
+        assert_eq!((pool.token_0, pool.token_1), (usdc, weth));
+        // rebroadcasts aren't reported twice
+        assert_eq!(discovery.on_pending(&v2), None);
+
+        let v3 = call(
+            factories[1].address,
+            CREATE_POOL,
+            vec![Token::Uint(U256::from(500))],
+        );
+        let Some(Deployment::Pool { pool: v3_pool, .. }) = discovery.on_pending(&v3) else {
+            panic!("createPool not decoded");
+        };
+        assert_eq!(
+            v3_pool.address,
+            "0x88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640"
+                .parse::<Address>()
+                .unwrap()
+        );
+        assert_eq!(discovery.take_unvetted(), vec![usdc, weth]);
+
+        let (all, hashed) = (PoolRegistry::new(), DashMap::new());
+        register_pool(pool, &all, &hashed);
+        register_pool(v3_pool, &all, &hashed);
+        register_pool(pool, &all, &hashed);
+        assert_eq!(all.len(), 2);
+        assert_eq!(hashed.get(&pair_key(usdc, weth)).unwrap().len(), 2);
+
+        let mut init_code = vec![];
+        for selector in ERC20_SELECTORS {
+            init_code.push(0x63);
+            init_code.extend(selector);
+        }
+        let deploy = Transaction {
+            from: Address::from_low_u64_be(1),
+            input: init_code.into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            discovery.on_pending(&deploy),
+            Some(Deployment::Token { token, .. })
+                if token == get_contract_address(deploy.from, U256::zero())
+        ));
+    }
+
+    #[test]
+    fn test_routes_new_pools() {
+        use crate::pool_classifier::PoolImplementation;
+        use qilin_cfmms::{pool::PoolType, test_utils::address};
+        use revm::{
+            db::{CacheDB, EmptyDB},
+            primitives::{AccountInfo, Bytecode},
+        };
+
+        // dispatchers pushing the selectors of the pool's functions
+        let code = |signatures: &[&str]| {
+            let mut code = vec![];
+            for signature in signatures {
+                code.push(0x63);
+                code.extend(id(signature));
+            }
+            code
+        };
+        let v2 = [
+            "getReserves()",
+            "swap(uint256,uint256,address,bytes)",
+            "token0()",
+            "token1()",
+        ];
+        let fork = code(&v2);
+        let solidly = code(&[&v2[..], &["stable()", "getAmountOut(uint256,address)"]].concat());
+        let mut db = CacheDB::new(EmptyDB::default());
+        let deploy = |db: &mut CacheDB<EmptyDB>, pool: Address, code: &[u8]| {
+            db.insert_account_info(
+                h160_to_b160(pool),
+                AccountInfo {
+                    code: Some(Bytecode::new_raw(code.to_vec().into())),
+                    ..Default::default()
+                },
+            );
+        };
+        let pool = |n: u64| {
+            Pool::new_empty_pool(
+                address(n),
+                address(1),
+                address(2),
+                U256::from(3000),
+                PoolVariant::UniswapV2,
+            )
+        };
+
+        // a fork's pair charging 0.2%
+        let classifier = PoolClassifier::new().with_code_hash(
+            H256::from(keccak256(&fork)),
+            PoolImplementation::UniswapV2Clone { fee: Some(200) },
+        );
+        let mut discovery = PairDiscovery::new(vec![]).with_classifier(classifier);
+        let (registry, hashed) = (PoolRegistry::new(), DashMap::new());
+        deploy(&mut db, address(10), &fork);
+        assert!(discovery.register(&db, pool(10), &registry, &hashed));
+        assert!(matches!(
+            registry.get(&address(10)).unwrap().pool_type,
+            PoolType::UniswapV2(pair) if pair.fee == 200
+        ));
+        deploy(&mut db, address(11), &solidly);
+        assert!(!discovery.register(&db, pool(11), &registry, &hashed));
+
+        // pending deployments are registered as decoded, and routed once deployed
+        assert!(discovery.register_pending(pool(12), &registry, &hashed));
+        assert!(discovery.register_pending(pool(13), &registry, &hashed));
+        assert_eq!(discovery.unresolved(), [address(12), address(13)]);
+        deploy(&mut db, address(12), &fork);
+        deploy(&mut db, address(13), &solidly);
+        let deployed = [address(12), address(13)];
+        assert_eq!(
+            discovery.route_deployed(&db, &deployed[..1], &registry, &hashed),
+            1
+        );
+        assert_eq!(discovery.unresolved(), [address(13)]);
+        assert_eq!(
+            discovery.route_deployed(&db, &deployed, &registry, &hashed),
+            1
+        );
+        assert!(discovery.unresolved().is_empty());
+        assert!(matches!(
+            registry.get(&address(12)).unwrap().pool_type,
+            PoolType::UniswapV2(pair) if pair.fee == 200
+        ));
+        assert_eq!(registry.get(&address(13)), None);
+        let pair = hashed.get(&pair_key(address(1), address(2))).unwrap();
+        assert_eq!(
+            pair.iter().map(|p| p.address).collect::<Vec<_>>(),
+            vec![address(10), address(12)]
+        );
+        assert!(pair
+            .iter()
+            .all(|p| matches!(p.pool_type, PoolType::UniswapV2(pair) if pair.fee == 200)));
+    }
+}
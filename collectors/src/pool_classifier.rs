@@ -0,0 +1,582 @@
+//! Telling AMM implementations apart by their code
+//!
+//! Pools of unknown factories, or of forks deploying from copied factories, can't be trusted to
+//! follow the math of whoever they were discovered through. [PoolClassifier] looks at the runtime
+//! code instead: a code hash pinned with [PoolClassifier::with_code_hash] decides directly,
+//! otherwise the selectors the dispatcher compares against tell a V2 clone from a Solidly fork or
+//! a V3 clone. Verdicts are cached by code hash, clones of the same pool share one lookup, and an
+//! account's code hash is taken as the node reports it rather than hashed again.
+//!
+//! V2 clones don't all charge 0.3%. [detect_v2_fee] measures a clone's fee off its own `swap`: on
+//! a sandbox with both tokens replaced by stubs reporting whatever balance the probe sets, the
+//! largest output the pair's `x * y = k` check lets through gives the fee away.
+//! [PoolClassifier::route] puts it all together for the pools discovery registers.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+
+use ethers::{
+    abi::{self, ParamType, Token},
+    types::{Address, H256, U256},
+    utils::{id, keccak256},
+};
+use fork_database::utils::{b256_to_h256, h160_to_b160, u256_to_ru256, RefDb};
+use qilin_cfmms::pool::{Pool, PoolType, PoolVariant};
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{
+        AccountInfo, Bytecode, ExecutionResult, Output, TransactTo, TxEnv, KECCAK_EMPTY,
+        U256 as rU256,
+    },
+    EVM,
+};
+
+const PUSH1: u8 = 0x60;
+const PUSH4: u8 = 0x63;
+const PUSH32: u8 = 0x7f;
+/// EIP-1167 minimal proxy, the implementation's address sits between the two
+const MINIMAL_PROXY_PREFIX: [u8; 10] = [0x36, 0x3d, 0x3d, 0x37, 0x3d, 0x3d, 0x3d, 0x36, 0x3d, 0x73];
+const MINIMAL_PROXY_SUFFIX: [u8; 15] = [
+    0x5a, 0xf4, 0x3d, 0x82, 0x80, 0x3e, 0x90, 0x3d, 0x91, 0x60, 0x2b, 0x57, 0xfd, 0x5b, 0xf3,
+];
+/// Token stub of the fee probe: `balanceOf(a)` returns slot `a`, anything else returns `true`
+const STUB_TOKEN: [u8; 38] = [
+    0x60, 0x00, 0x35, 0x60, 0xe0, 0x1c, // calldataload(0) >> 224
+    0x63, 0x70, 0xa0, 0x82, 0x31, 0x14, // == balanceOf(address)
+    0x60, 0x19, 0x57, // jumpi 0x19
+    0x60, 0x01, 0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3, // return true
+    0x5b, 0x60, 0x04, 0x35, 0x54, // 0x19: sload(calldataload(4))
+    0x60, 0x00, 0x52, 0x60, 0x20, 0x60, 0x00, 0xf3, // return it
+];
+/// Fees are in hundredths of a bp, like [UniswapV2Pool::fee](cfmms::pool::UniswapV2Pool)
+const FEE_DENOMINATOR: u32 = 100_000;
+/// Share of the input reserve the fee probe swaps, large enough for the output to carry the fee
+/// to the last unit
+const PROBE_SHARE: u64 = 10;
+
+const V2_SELECTORS: [&str; 4] = [
+    "getReserves()",
+    "swap(uint256,uint256,address,bytes)",
+    "token0()",
+    "token1()",
+];
+const SOLIDLY_SELECTORS: [&str; 2] = ["stable()", "getAmountOut(uint256,address)"];
+const V3_SELECTORS: [&str; 4] = [
+    "slot0()",
+    "swap(address,bool,int256,uint160,bytes)",
+    "liquidity()",
+    "tickSpacing()",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PoolImplementation {
+    /// `fee` in hundredths of a bp, `None` until measured with [detect_v2_fee]
+    UniswapV2Clone {
+        fee: Option<u32>,
+    },
+    UniswapV3Clone,
+    /// `x * y = k` or the stable curve, depending on the pool's `stable()`
+    SolidlyFork,
+    /// none of the above, left alone until it has a model
+    Custom,
+}
+
+impl PoolImplementation {
+    /// Math model the pool is priced with, `None` if the bot has none for it. V2 clones get one
+    /// once their fee is known, the V2 math would misprice them otherwise.
+    pub fn model(&self) -> Option<PoolVariant> {
+        match self {
+            Self::UniswapV2Clone { fee: Some(_) } => Some(PoolVariant::UniswapV2),
+            Self::UniswapV3Clone => Some(PoolVariant::UniswapV3),
+            Self::UniswapV2Clone { fee: None } | Self::SolidlyFork | Self::Custom => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PoolClassifier {
+    /// pinned and already classified code hashes
+    by_hash: HashMap<H256, PoolImplementation>,
+}
+
+impl PoolClassifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Classify code with `hash` as `implementation` whatever its selectors. A V2 clone pinned
+    /// with its fee isn't probed.
+    pub fn with_code_hash(mut self, hash: H256, implementation: PoolImplementation) -> Self {
+        self.by_hash.insert(hash, implementation);
+        self
+    }
+
+    /// Implementation of runtime `code`, V2 clones with their fee unknown unless pinned
+    pub fn classify_code(&mut self, code: &[u8]) -> PoolImplementation {
+        self.classify_code_with_hash(H256::from(keccak256(code)), code)
+    }
+
+    /// [PoolClassifier::classify_code] of code already known to hash to `hash`
+    pub fn classify_code_with_hash(&mut self, hash: H256, code: &[u8]) -> PoolImplementation {
+        *self
+            .by_hash
+            .entry(hash)
+            .or_insert_with(|| classify_selectors(&selectors(code)))
+    }
+
+    /// Implementation of the contract at `address` on `db`, through a minimal proxy if it is one.
+    /// The fee of a V2 clone is measured on `address` the first time its code is seen, clones
+    /// keeping their fee in storage are assumed to share the first one's. `None` without code.
+    pub fn classify<DB>(&mut self, db: &DB, address: Address) -> Option<PoolImplementation>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        let (mut hash, mut code) = runtime_code(db, address)?;
+        if let Some(implementation) = minimal_proxy_target(&code) {
+            (hash, code) = runtime_code(db, implementation)?;
+        }
+        let implementation = match self.by_hash.get(&hash) {
+            Some(known) => *known,
+            None => self.classify_code_with_hash(hash, &code),
+        };
+        if implementation != (PoolImplementation::UniswapV2Clone { fee: None }) {
+            return Some(implementation);
+        }
+        let fee = detect_v2_fee(db, address);
+        if fee.is_none() {
+            // retried on the next pool with this code, this one may have been empty
+            log::debug!("Could not measure the fee of V2 clone {:?}", address);
+            return Some(implementation);
+        }
+        let measured = PoolImplementation::UniswapV2Clone { fee };
+        self.by_hash.insert(hash, measured);
+        Some(measured)
+    }
+
+    /// [PoolClassifier::classify] down to the model to price the pool with
+    pub fn model<DB>(&mut self, db: &DB, address: Address) -> Option<PoolVariant>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        self.classify(db, address)?.model()
+    }
+
+    /// `pool`, as discovery decoded it, priced with the model its code on `db` implements: a
+    /// V3 pool stays one, a V2 clone gets its measured fee
+    pub fn route<DB>(&mut self, db: &DB, pool: Pool) -> Routing
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        let implementation = match self.classify(db, pool.address) {
+            None | Some(PoolImplementation::UniswapV2Clone { fee: None }) => {
+                return Routing::Unresolved(pool)
+            }
+            Some(implementation) => implementation,
+        };
+        let Some(variant) = implementation.model() else {
+            return Routing::Unsupported(implementation);
+        };
+        let mut routed = if variant == pool.pool_variant {
+            pool
+        } else {
+            Pool::new_empty_pool(
+                pool.address,
+                pool.token_0,
+                pool.token_1,
+                pool.swap_fee,
+                variant,
+            )
+        };
+        if let (PoolType::UniswapV2(pair), PoolImplementation::UniswapV2Clone { fee: Some(fee) }) =
+            (&mut routed.pool_type, implementation)
+        {
+            pair.fee = fee;
+        }
+        Routing::Routed(routed)
+    }
+}
+
+/// What [PoolClassifier::route] made of a pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Routing {
+    /// priced with the model of its code
+    Routed(Pool),
+    /// not deployed yet, or a V2 clone too empty to measure its fee, kept as decoded until
+    /// routed again
+    Unresolved(Pool),
+    /// code the bot has no model for, the pool shouldn't be traded
+    Unsupported(PoolImplementation),
+}
+
+fn classify_selectors(selectors: &HashSet<[u8; 4]>) -> PoolImplementation {
+    let has_all = |signatures: &[&str]| signatures.iter().all(|s| selectors.contains(&id(s)));
+    if has_all(&V3_SELECTORS) {
+        PoolImplementation::UniswapV3Clone
+    } else if has_all(&V2_SELECTORS) && has_all(&SOLIDLY_SELECTORS) {
+        PoolImplementation::SolidlyFork
+    } else if has_all(&V2_SELECTORS) {
+        PoolImplementation::UniswapV2Clone { fee: None }
+    } else {
+        PoolImplementation::Custom
+    }
+}
+
+/// Operands of the `PUSH4`s in `code`, skipping the data of other pushes. Function dispatchers
+/// push each selector they compare the calldata against.
+pub fn selectors(code: &[u8]) -> HashSet<[u8; 4]> {
+    let mut selectors = HashSet::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        let opcode = code[pc];
+        if opcode == PUSH4 && pc + 5 <= code.len() {
+            selectors.insert([code[pc + 1], code[pc + 2], code[pc + 3], code[pc + 4]]);
+        }
+        pc += 1;
+        if (PUSH1..=PUSH32).contains(&opcode) {
+            pc += (opcode - PUSH1 + 1) as usize;
+        }
+    }
+    selectors
+}
+
+/// Implementation an EIP-1167 minimal proxy delegates to
+pub fn minimal_proxy_target(code: &[u8]) -> Option<Address> {
+    let prefix = MINIMAL_PROXY_PREFIX.len();
+    if code.len() != prefix + 20 + MINIMAL_PROXY_SUFFIX.len()
+        || code[..prefix] != MINIMAL_PROXY_PREFIX
+        || code[prefix + 20..] != MINIMAL_PROXY_SUFFIX
+    {
+        return None;
+    }
+    Some(Address::from_slice(&code[prefix..prefix + 20]))
+}
+
+/// Code hash of the account at `address`, as the db has it, and its runtime code
+fn runtime_code<DB>(db: &DB, address: Address) -> Option<(H256, Vec<u8>)>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let info = db.basic(h160_to_b160(address)).ok()??;
+    if info.code_hash == KECCAK_EMPTY {
+        return None;
+    }
+    let code = match info.code {
+        Some(code) => code,
+        None => db.code_by_hash(info.code_hash).ok()?,
+    };
+    let code = code.original_bytes().to_vec();
+    (!code.is_empty()).then_some((b256_to_h256(info.code_hash), code))
+}
+
+/// Fee of the V2 clone at `pool` on `db`, in hundredths of a bp
+///
+/// Swaps a tenth of its `token0` reserve for `token1` on a sandbox whose tokens are stubs, each
+/// probe setting the pair's balances to what the swap would leave, and binary searches the largest
+/// output the pair's own `k` check lets through. `None` if the pool is empty or doesn't behave
+/// like a V2 pair.
+pub fn detect_v2_fee<DB>(db: &DB, pool: Address) -> Option<u32>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let mut sandbox = CacheDB::new(RefDb(db));
+    let token_0 = address_call(&mut sandbox, pool, "token0()")?;
+    let token_1 = address_call(&mut sandbox, pool, "token1()")?;
+    let output = call(&mut sandbox, pool, id("getReserves()").to_vec())?;
+    let mut reserves = abi::decode(
+        &[
+            ParamType::Uint(112),
+            ParamType::Uint(112),
+            ParamType::Uint(32),
+        ],
+        &output,
+    )
+    .ok()?
+    .into_iter();
+    let reserve_0 = reserves.next()?.into_uint()?;
+    let reserve_1 = reserves.next()?.into_uint()?;
+    let amount_in = reserve_0 / PROBE_SHARE;
+    if amount_in.is_zero() || reserve_1.is_zero() {
+        return None;
+    }
+
+    for token in [token_0, token_1] {
+        sandbox.insert_account_info(
+            h160_to_b160(token),
+            AccountInfo {
+                code: Some(Bytecode::new_raw(STUB_TOKEN.to_vec().into())),
+                ..Default::default()
+            },
+        );
+    }
+    let balance_slot = u256_to_ru256(U256::from_big_endian(pool.as_bytes()));
+    sandbox
+        .insert_account_storage(
+            h160_to_b160(token_0),
+            balance_slot,
+            u256_to_ru256(reserve_0 + amount_in),
+        )
+        .ok()?;
+    let mut swaps = |amount_out: U256| {
+        sandbox
+            .insert_account_storage(
+                h160_to_b160(token_1),
+                balance_slot,
+                u256_to_ru256(reserve_1 - amount_out),
+            )
+            .ok()?;
+        let data = [
+            id("swap(uint256,uint256,address,bytes)").to_vec(),
+            abi::encode(&[
+                Token::Uint(U256::zero()),
+                Token::Uint(amount_out),
+                Token::Address(Address::from_low_u64_be(0xfee)),
+                Token::Bytes(vec![]),
+            ]),
+        ]
+        .concat();
+        call(&mut sandbox, pool, data).map(|_| ())
+    };
+
+    // a fee-less pair pays out at most this much
+    let (mut low, mut high) = (
+        U256::zero(),
+        amount_in * reserve_1 / (reserve_0 + amount_in),
+    );
+    while low < high {
+        let mid = (low + high + 1) / 2;
+        if swaps(mid).is_some() {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    fee_from_quote((reserve_0, reserve_1), amount_in, low)
+}
+
+/// Fee, in hundredths of a bp, of a `x * y = k` pool with `reserves` paying out at most
+/// `amount_out` for `amount_in` of its first token, rounded to the nearest unit
+pub fn fee_from_quote(reserves: (U256, U256), amount_in: U256, amount_out: U256) -> Option<u32> {
+    // amount_out = amount_in * g * reserve_1 / (reserve_0 + amount_in * g), g the share kept
+    if amount_out.is_zero() || amount_out >= reserves.1 {
+        return None;
+    }
+    let kept = U256::from(FEE_DENOMINATOR) * amount_out * reserves.0;
+    let denominator = amount_in * (reserves.1 - amount_out);
+    let kept = (kept + denominator / 2) / denominator;
+    U256::from(FEE_DENOMINATOR)
+        .checked_sub(kept)
+        .map(|fee| fee.as_u32())
+        .filter(|fee| *fee < FEE_DENOMINATOR)
+}
+
+/// Address returned by a call to the getter `signature` of `to`
+fn address_call<DB>(sandbox: &mut CacheDB<DB>, to: Address, signature: &str) -> Option<Address>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let output = call(sandbox, to, id(signature).to_vec())?;
+    abi::decode(&[ParamType::Address], &output)
+        .ok()?
+        .pop()?
+        .into_address()
+}
+
+/// Output of a successful call to `to` on `sandbox`, nothing is committed
+fn call<DB>(sandbox: &mut CacheDB<DB>, to: Address, data: Vec<u8>) -> Option<Vec<u8>>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let mut evm = EVM::new();
+    evm.env.cfg.disable_base_fee = true;
+    // pairs accumulate price over the time since their last update, which has to be in the past
+    evm.env.block.timestamp = rU256::from(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs()),
+    );
+    evm.env.tx = TxEnv {
+        caller: h160_to_b160(Address::from_low_u64_be(0xfee)),
+        transact_to: TransactTo::Call(h160_to_b160(to)),
+        data: data.into(),
+        gas_price: rU256::ZERO,
+        gas_limit: 1_000_000,
+        ..Default::default()
+    };
+    evm.database(sandbox);
+    match evm.transact().ok()?.result {
+        ExecutionResult::Success {
+            output: Output::Call(output),
+            ..
+        } => Some(output.to_vec()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qilin_cfmms::test_utils::{address, empty_pool};
+    use revm::db::EmptyDB;
+
+    fn deploy(db: &mut CacheDB<EmptyDB>, address: Address, code: Vec<u8>) {
+        db.insert_account_info(
+            h160_to_b160(address),
+            AccountInfo {
+                code: Some(Bytecode::new_raw(code.into())),
+                ..Default::default()
+            },
+        );
+    }
+
+    /// A dispatcher comparing against the selectors of `signatures`
+    fn dispatcher(signatures: &[&str]) -> Vec<u8> {
+        let mut code = vec![0x60, 0x80, 0x60, 0x40, 0x52];
+        for signature in signatures {
+            code.push(PUSH4);
+            code.extend_from_slice(&id(signature));
+            code.extend_from_slice(&[0x14, 0x61, 0x00, 0x00, 0x57]);
+        }
+        code
+    }
+
+    #[test]
+    fn test_classify_pools() {
+        let mut classifier = PoolClassifier::new();
+        let v2 = dispatcher(&[&V2_SELECTORS[..], &["skim(address)"]].concat());
+        let solidly =
+            dispatcher(&[&V2_SELECTORS[..], &SOLIDLY_SELECTORS, &["metadata()"]].concat());
+        let v3 = dispatcher(&V3_SELECTORS);
+        assert_eq!(
+            classifier.classify_code(&v2),
+            PoolImplementation::UniswapV2Clone { fee: None }
+        );
+        assert_eq!(
+            classifier.classify_code(&solidly),
+            PoolImplementation::SolidlyFork
+        );
+        assert_eq!(
+            classifier.classify_code(&v3),
+            PoolImplementation::UniswapV3Clone
+        );
+        assert_eq!(
+            classifier.classify_code(&dispatcher(&["getReserves()"])),
+            PoolImplementation::Custom
+        );
+
+        // a selector inside another push's data isn't one
+        let mut hidden = vec![PUSH32 - 4, PUSH4];
+        hidden.extend_from_slice(&id("slot0()"));
+        assert!(selectors(&hidden).is_empty());
+
+        // a pinned hash wins over the selectors
+        let hash = H256::from(keccak256(&v2));
+        let mut pinned = PoolClassifier::new().with_code_hash(hash, PoolImplementation::Custom);
+        assert_eq!(pinned.classify_code(&v2), PoolImplementation::Custom);
+
+        // clones behind a minimal proxy are classified by their implementation
+        let mut db = CacheDB::new(EmptyDB::default());
+        let (implementation, clone) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let proxy = [
+            MINIMAL_PROXY_PREFIX.as_slice(),
+            implementation.as_bytes(),
+            MINIMAL_PROXY_SUFFIX.as_slice(),
+        ]
+        .concat();
+        assert_eq!(minimal_proxy_target(&proxy), Some(implementation));
+        deploy(&mut db, implementation, solidly.clone());
+        deploy(&mut db, clone, proxy);
+        assert_eq!(
+            classifier.classify(&db, clone),
+            Some(PoolImplementation::SolidlyFork)
+        );
+        assert_eq!(classifier.model(&db, clone), None);
+        assert_eq!(classifier.classify(&db, Address::from_low_u64_be(3)), None);
+    }
+
+    #[test]
+    fn test_route_pools() {
+        let mut db = CacheDB::new(EmptyDB::default());
+        let v2 = dispatcher(&V2_SELECTORS);
+        let (pinned, unmeasured, solidly) = (address(10), address(11), address(12));
+        deploy(&mut db, pinned, v2.clone());
+        deploy(
+            &mut db,
+            solidly,
+            dispatcher(&[&V2_SELECTORS[..], &SOLIDLY_SELECTORS].concat()),
+        );
+        // a fork charging 0.25%, pinned by the code hash the db reports
+        let mut classifier = PoolClassifier::new().with_code_hash(
+            H256::from(keccak256(&v2)),
+            PoolImplementation::UniswapV2Clone { fee: Some(250) },
+        );
+
+        let Routing::Routed(routed) = classifier.route(&db, empty_pool(10)) else {
+            panic!("pinned clone not routed");
+        };
+        assert_eq!(routed.pool_variant, PoolVariant::UniswapV2);
+        assert!(matches!(routed.pool_type, PoolType::UniswapV2(pair) if pair.fee == 250));
+        assert_eq!(
+            classifier.route(&db, empty_pool(12)),
+            Routing::Unsupported(PoolImplementation::SolidlyFork)
+        );
+        // not deployed yet
+        assert_eq!(
+            classifier.route(&db, empty_pool(13)),
+            Routing::Unresolved(empty_pool(13))
+        );
+
+        // a clone whose fee can't be measured isn't priced yet
+        let other = dispatcher(&[&V2_SELECTORS[..], &["skim(address)"]].concat());
+        deploy(&mut db, unmeasured, other);
+        assert_eq!(
+            classifier.route(&db, empty_pool(11)),
+            Routing::Unresolved(empty_pool(11))
+        );
+        assert_eq!(classifier.model(&db, unmeasured), None);
+        assert_eq!(classifier.model(&db, pinned), Some(PoolVariant::UniswapV2));
+    }
+
+    #[test]
+    fn test_fee_from_quote() {
+        let e18 = U256::exp10(18);
+        let reserves = (e18 * 1_000_000, e18 * 2_000_000);
+        let amount_in = reserves.0 / PROBE_SHARE;
+        for fee in [300u32, 250, 100, 1_000] {
+            // the most `k` lets out, as in `getAmountOut`
+            let kept = amount_in * (FEE_DENOMINATOR - fee);
+            let amount_out = kept * reserves.1 / (reserves.0 * FEE_DENOMINATOR + kept);
+            assert_eq!(fee_from_quote(reserves, amount_in, amount_out), Some(fee));
+        }
+        assert_eq!(fee_from_quote(reserves, amount_in, U256::zero()), None);
+        assert_eq!(fee_from_quote(reserves, amount_in, reserves.1), None);
+
+        // the probe's token stub answers with the balance set for the pair
+        let mut db = CacheDB::new(EmptyDB::default());
+        let (token, pair) = (address(20), address(21));
+        deploy(&mut db, token, STUB_TOKEN.to_vec());
+        let slot = u256_to_ru256(U256::from_big_endian(pair.as_bytes()));
+        db.insert_account_storage(h160_to_b160(token), slot, rU256::from(42))
+            .unwrap();
+        let balance_of = [
+            id("balanceOf(address)").to_vec(),
+            abi::encode(&[Token::Address(pair)]),
+        ]
+        .concat();
+        let balance = call(&mut db, token, balance_of).unwrap();
+        assert_eq!(U256::from_big_endian(&balance), U256::from(42));
+        let transfer = [
+            id("transfer(address,uint256)").to_vec(),
+            abi::encode(&[Token::Address(pair), Token::Uint(U256::one())]),
+        ]
+        .concat();
+        let success = call(&mut db, token, transfer).unwrap();
+        assert_eq!(U256::from_big_endian(&success), U256::one());
+    }
+}
@@ -1,56 +1,57 @@
-use ethers::abi::Abi;
+use crate::layout_fetcher::LayoutFetcher;
+use crate::wrapped_tokens::{cached_model, stored_balance};
 use ethers::prelude::*;
 use ethers::types::U256;
 use ethers::{
-    contract::Contract,
     providers::{Middleware, Provider},
     types::H160,
 };
+use fork_database::proxy::resolve_implementation_remote;
+use fork_database::storage_layout::{mapping_slot, slot_key};
 use log;
-use std::fs::File;
-use std::io::Read;
 use std::sync::Arc;
 
 /// Given a ERC20 token address and a pool address, find storage slot in the `balanceOf` mapping
+///
+/// The verified storage layout from `layouts` is used when available, read from the
+/// implementation for proxied tokens, otherwise the first 100 slots are probed for the pool's
+/// balance. For share tokens whose slot doesn't hold `balanceOf`, e.g. stETH shares or Aave
+/// scaled balances, the probe looks for the stored word, see [crate::wrapped_tokens].
 pub async fn slot_finder(
     provider: Arc<Provider<Ws>>,
+    layouts: Option<&LayoutFetcher>,
     token_address: H160,
     pool_address: H160,
 ) -> Option<U256> {
-    let mut file = File::open("..abi/erc20.json").unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-    let abi: Abi = serde_json::from_str(&contents).unwrap();
-
-    let token = Contract::new(token_address, abi.clone(), provider.clone());
-
-    //     let token_decimals = token.method::<_, u128>("decimals", ()).unwrap().call().await.unwrap();
-    //     let token_symnol = token.method::<_, String>("symbol", ()).unwrap().call().await.unwrap();
-    //     println!("token_decimals: {:?}", token_decimals);
-    //     println!("token_symnol: {:?}", token_symnol);
-
-    let balance = match token
-        .method::<_, U256>("balanceOf", pool_address.clone())
-        .unwrap()
-        .call()
-        .await
-    {
-        Ok(b) => b,
-        Err(e) => {
-            log::error!("Error: {}", e);
-            return None;
+    if let Some(layouts) = layouts {
+        let implementation = resolve_implementation_remote(provider.as_ref(), token_address, None)
+            .await
+            .unwrap_or(token_address);
+        if let Some(slot) = layouts.balance_of_slot(implementation).await {
+            return Some(slot);
         }
-    };
+    }
+
+    let model = cached_model(provider.as_ref(), token_address).await;
+    let balance =
+        match stored_balance(provider.as_ref(), token_address, &model, pool_address, None).await {
+            Some(b) => b,
+            None => {
+                log::error!(
+                    "Error: no balance of {:?} in {:?}",
+                    pool_address,
+                    token_address
+                );
+                return None;
+            }
+        };
 
     let mut slot;
     // TODO: use threads
     // TODO: use while loop
     for i in 0..=100 {
         slot = U256::from(i);
-        let tx_hash = TxHash::from(ethers::utils::keccak256(abi::encode(&[
-            abi::Token::Address(pool_address),
-            abi::Token::Uint(slot.clone()),
-        ])));
+        let tx_hash = slot_key(mapping_slot(pool_address, slot));
 
         let storage_value: TxHash = provider
             .clone()
@@ -59,7 +60,7 @@ pub async fn slot_finder(
             .unwrap();
         let storage_value_u256 = U256::from_big_endian(&storage_value.as_bytes());
 
-        if storage_value_u256 == balance.clone() {
+        if model.stored_word(storage_value_u256) == balance {
             return Some(U256::from(i));
         }
     }
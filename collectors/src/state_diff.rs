@@ -1,17 +1,25 @@
 use anyhow::Result;
 use dashmap::DashMap;
 use ethers::types::H160;
-use hashbrown::HashMap;
+use fork_database::storage_layout::{mapping_slot, slot_key, WETH_BALANCE_OF_SLOT};
 use qilin_cfmms::pool::Pool;
+use qilin_cfmms::registry::PoolView;
 use std::{
     collections::{hash_map::DefaultHasher, HashSet},
     hash::{Hash, Hasher},
 };
 use thiserror::Error;
 
+use super::layout_fetcher::LayoutFetcher;
+use super::opportunity::{
+    MultiHopArbOpportunity, Opportunity, SandwichOpportunity, TwoPoolArbOpportunity,
+};
 use super::slot_finder;
+use super::trace_client::{TraceClient, TraceError};
 use ethers::prelude::*;
-use futures::stream::FuturesUnordered;
+use futures::stream::{self, BoxStream, FuturesUnordered, StreamExt};
+use log::warn;
+use rayon::prelude::*;
 use revm::{
     db::{CacheDB, EmptyDB},
     primitives::{AccountInfo, Bytecode},
@@ -19,12 +27,70 @@ use revm::{
 use serde::{Serialize, Serializer};
 use std::{
     collections::{btree_map::Entry, BTreeMap},
-    sync::Arc,
+    sync::{Arc, OnceLock},
 };
-use tokio::sync::RwLock;
 
-pub type ArbPools = Vec<HashMap<Pool, Vec<Pool>>>;
-type RustyPool = rusty::cfmm::Pool;
+/// WETH on mainnet, 0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2
+pub const WETH: H160 = H160([
+    0xc0, 0x2a, 0xaa, 0x39, 0xb2, 0x23, 0xfe, 0x8d, 0x0a, 0x0e, 0x5c, 0x4f, 0x27, 0xea, 0xd9, 0x08,
+    0x3c, 0x75, 0x6c, 0xc2,
+]);
+
+/// Storage keys of `balanceOf` entries, e.g. a pool's WETH balance. Each key takes an ABI encoding
+/// and a keccak, and the same (pool, slot) pairs come back with every tx touching the pool, so
+/// they are computed once.
+#[derive(Debug, Default)]
+pub struct StorageKeyCache {
+    keys: DashMap<(Address, U256), H256>,
+}
+
+impl StorageKeyCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Key of `holder` in the mapping at `slot`
+    pub fn balance_key(&self, holder: Address, slot: U256) -> H256 {
+        *self
+            .keys
+            .entry((holder, slot))
+            .or_insert_with(|| slot_key(mapping_slot(holder, slot)))
+    }
+
+    pub fn weth_balance_key(&self, pool: Address) -> H256 {
+        self.balance_key(pool, U256::from(WETH_BALANCE_OF_SLOT))
+    }
+
+    /// Precompute the WETH balance keys of `pools`, e.g. when the pools are loaded
+    pub fn warm<'a>(&self, pools: impl IntoIterator<Item = &'a Pool>) {
+        for pool in pools {
+            self.weth_balance_key(pool.address);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Cache shared by the `extract_*` functions
+pub fn storage_keys() -> &'static StorageKeyCache {
+    static KEYS: OnceLock<StorageKeyCache> = OnceLock::new();
+    KEYS.get_or_init(StorageKeyCache::new)
+}
+
+/// Whether a changed slot grew, `None` if it didn't change. Words are big endian, so they compare
+/// like the numbers they hold without converting them.
+pub fn slot_increased(diff: &Diff<H256>) -> Option<bool> {
+    match diff {
+        Diff::Changed(c) => Some(c.to > c.from),
+        _ => None,
+    }
+}
 struct SerializedBTreeMap<K, V>(BTreeMap<K, V>);
 
 impl<K, V> Serialize for SerializedBTreeMap<K, V>
@@ -40,21 +106,6 @@ where
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
-pub struct TradablePool {
-    pub pool: RustyPool,
-    pub is_weth_input: bool,
-}
-
-impl TradablePool {
-    pub fn new(pool: RustyPool, is_weth_input: bool) -> Self {
-        Self {
-            pool,
-            is_weth_input,
-        }
-    }
-}
-
 #[derive(Error, Debug)]
 pub enum StateDiffError<M>
 where
@@ -66,76 +117,272 @@ where
     GetTransactionTraceError,
 }
 
+/// Txs traced at once by [stream_from_txs]
+pub const DEFAULT_TRACE_CONCURRENCY: usize = 16;
+
+/// State diff of one of the traced txs
+#[derive(Debug, Clone)]
+pub struct TxDiff {
+    /// position of the tx in the traced slice
+    pub index: usize,
+    pub tx_hash: H256,
+    pub diff: BTreeMap<Address, AccountDiff>,
+}
+
+impl TxDiff {
+    /// Tracked pools whose state the tx changes
+    pub fn touched_pools(&self, pools: &PoolView) -> Vec<Pool> {
+        self.diff
+            .keys()
+            .filter_map(|address| pools.get(address))
+            .collect()
+    }
+}
+
+/// Traces each of `meats` on its own on top of `block_num` and yields the diffs as the traces
+/// complete, not in tx order, so the first victims can be worked on before the slowest trace
+/// returns. Failed traces come out as the index of the tx and the error.
+///
+/// Only for txs independent of each other, e.g. pending victims each simulated on the head. The
+/// txs of a block or a bundle run on the state the earlier ones left, trace them in one call with
+/// [get_from_txs].
+pub fn stream_from_txs<'a>(
+    tracer: &'a dyn TraceClient,
+    meats: &'a [Transaction],
+    block_num: BlockNumber,
+    concurrency: usize,
+) -> BoxStream<'a, Result<TxDiff, (usize, TraceError)>> {
+    stream::iter(meats.iter().enumerate())
+        .map(move |(index, tx)| async move {
+            let diffs = tracer
+                .state_diffs(std::slice::from_ref(tx), block_num)
+                .await
+                .map_err(|e| (index, e))?;
+            Ok(TxDiff {
+                index,
+                tx_hash: tx.hash,
+                diff: diffs.into_iter().next().unwrap_or_default(),
+            })
+        })
+        .buffer_unordered(concurrency.max(1))
+        .boxed()
+}
+
+/// Txs from which [merge_diffs] merges in parallel, below that the rayon overhead dominates
+pub const PARALLEL_MERGE_THRESHOLD: usize = 64;
+
+/// Which tx's diff an account keeps when several txs touch it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiffMergeStrategy {
+    /// the earliest tx's, i.e. the state before any of the txs ran
+    #[default]
+    FirstSeen,
+    /// the latest tx's
+    LastSeen,
+}
+
+impl DiffMergeStrategy {
+    fn wins(&self, index: usize, current: usize) -> bool {
+        match self {
+            DiffMergeStrategy::FirstSeen => index < current,
+            DiffMergeStrategy::LastSeen => index > current,
+        }
+    }
+}
+
+type IndexedDiffs = BTreeMap<Address, (usize, AccountDiff)>;
+
+fn merge_account(
+    merged: &mut IndexedDiffs,
+    address: Address,
+    index: usize,
+    diff: AccountDiff,
+    strategy: DiffMergeStrategy,
+) {
+    match merged.entry(address) {
+        Entry::Vacant(entry) => {
+            entry.insert((index, diff));
+        }
+        Entry::Occupied(mut entry) => {
+            if strategy.wins(index, entry.get().0) {
+                entry.insert((index, diff));
+            }
+        }
+    }
+}
+
+/// Merge the diffs of several txs into one, an account keeping the diff picked by `strategy`.
+/// The result doesn't depend on the order of `diffs`, only on the tx indexes.
+///
+/// With [PARALLEL_MERGE_THRESHOLD] txs or more, chunks of txs are merged on the rayon pool and
+/// the partial maps reduced pairwise, keeping the winning tx of every address.
+pub fn merge_diffs(
+    diffs: Vec<TxDiff>,
+    strategy: DiffMergeStrategy,
+) -> BTreeMap<Address, AccountDiff> {
+    let merge_tx = |mut merged: IndexedDiffs, tx: TxDiff| {
+        for (address, diff) in tx.diff {
+            merge_account(&mut merged, address, tx.index, diff, strategy);
+        }
+        merged
+    };
+    let merged = if diffs.len() < PARALLEL_MERGE_THRESHOLD {
+        diffs.into_iter().fold(IndexedDiffs::new(), merge_tx)
+    } else {
+        diffs
+            .into_par_iter()
+            .fold(IndexedDiffs::new, merge_tx)
+            .reduce(IndexedDiffs::new, |mut left, mut right| {
+                if left.len() < right.len() {
+                    std::mem::swap(&mut left, &mut right);
+                }
+                for (address, (index, diff)) in right {
+                    merge_account(&mut left, address, index, diff, strategy);
+                }
+                left
+            })
+    };
+    merged
+        .into_iter()
+        .map(|(address, (_, diff))| (address, diff))
+        .collect()
+}
+
 // credit to rusty-sando
 // https://github.com/mouseless-eth/rusty-sando/blob/master/bot/src/utils/state_diff.rs
 // Extract state diffs from a given tx
 //
 // Arguments:
-// * `client`: Websocket provider used for making rpc calls
+// * `tracer`: Trace endpoint of the node, see [detect_trace_client](crate::trace_client::detect_trace_client)
 // * `meats`: Vec of transactions to extract state diffs from
 // * `block_num`: Block number of the block the txs are in
 //
 // Returns:
 // Some(BTreeMap<Address, AccountDiff>): State diffs for each address)
 // None: If encountered error or state diffs are non existant
-pub async fn get_from_txs<M>(
-    client: &Arc<M>,
-    meats: &Vec<Transaction>,
+pub async fn get_from_txs(
+    tracer: &dyn TraceClient,
+    meats: &[Transaction],
     block_num: BlockNumber,
-) -> Option<BTreeMap<Address, AccountDiff>>
-where
-    M: Middleware + 'static,
-{
-    // add statediff trace to each transaction
-    let req = meats
-        .iter()
-        .map(|tx| (tx, vec![TraceType::StateDiff]))
-        .collect();
+) -> Option<BTreeMap<Address, AccountDiff>> {
+    get_from_txs_with(
+        tracer,
+        meats,
+        block_num,
+        DiffMergeStrategy::default(),
+        |_| {},
+    )
+    .await
+}
 
-    let block_traces = match client.trace_call_many(req, Some(block_num)).await {
+/// Same as [get_from_txs], handing every tx's diff to `on_diff` in tx order, e.g. to start
+/// looking for opportunities in the pools it touched, and merging with `strategy`. The txs are
+/// traced in one call, each on the state the txs before it left.
+pub async fn get_from_txs_with(
+    tracer: &dyn TraceClient,
+    meats: &[Transaction],
+    block_num: BlockNumber,
+    strategy: DiffMergeStrategy,
+    mut on_diff: impl FnMut(&TxDiff),
+) -> Option<BTreeMap<Address, AccountDiff>> {
+    let tx_diffs = match tracer.state_diffs(meats, block_num).await {
         Ok(x) => x,
         Err(e) => {
-            println!("Block Trace Error: {:?}", e);
+            warn!("Block Trace Error: {:?}", e);
             return None;
         }
     };
-    println!("block_traces: {:?}", block_traces);
 
-    let mut merged_state_diffs = BTreeMap::new();
+    let traced: Vec<TxDiff> = tx_diffs
+        .into_iter()
+        .zip(meats)
+        .enumerate()
+        .map(|(index, (diff, tx))| TxDiff {
+            index,
+            tx_hash: tx.hash,
+            diff,
+        })
+        .collect();
+    traced.iter().for_each(&mut on_diff);
+
+    Some(merge_diffs(traced, strategy))
+}
+
+/// Key of the pools trading `token0` against `token1` in the hashed pool map
+pub fn pair_key(token0: Address, token1: Address) -> H160 {
+    let mut hasher = DefaultHasher::new();
+    token0.hash(&mut hasher);
+    token1.hash(&mut hasher);
+    H160::from_low_u64_be(hasher.finish())
+}
 
-    block_traces
+/// Pools of the pair `a`/`b`, pools sort their tokens
+fn pair_pools(hash_pools: &DashMap<H160, Vec<Pool>>, a: Address, b: Address) -> Vec<Pool> {
+    let (token0, token1) = if a < b { (a, b) } else { (b, a) };
+    hash_pools
+        .get(&pair_key(token0, token1))
+        .map(|pools| pools.clone())
+        .unwrap_or_default()
+}
+
+/// Arbs on `moved`, a pool holding more of its `token_0` than before: `token_0` is bought there
+/// with `token_1` and sold back for `token_1`, either to a `counter` pool of the same pair or
+/// through WETH in two hops. Returns the counter pools along with the arbs.
+pub fn arb_routes(
+    moved: Pool,
+    hash_pools: &DashMap<H160, Vec<Pool>>,
+    trigger: Option<H256>,
+) -> (Vec<Opportunity>, Vec<Pool>) {
+    let (token0, token1) = (moved.token_0, moved.token_1);
+    let counters: Vec<Pool> = pair_pools(hash_pools, token0, token1)
         .into_iter()
-        .flat_map(|bt| bt.state_diff.map(|sd| sd.0.into_iter()))
-        .flatten()
-        .for_each(|(address, account_diff)| {
-            match merged_state_diffs.entry(address) {
-                Entry::Vacant(entry) => {
-                    entry.insert(account_diff);
-                }
-                Entry::Occupied(_) => {
-                    // Do nothing if the key already exists
-                    // we only care abt the starting state
-                }
+        .filter(|p| p.address != moved.address)
+        .collect();
+    let mut arbs: Vec<Opportunity> = counters
+        .iter()
+        .map(|counter| {
+            Opportunity::TwoPoolArb(TwoPoolArbOpportunity {
+                trigger,
+                moved,
+                counter: *counter,
+                token_in: token1,
+                base: None,
+            })
+        })
+        .collect();
+    if token0 != WETH && token1 != WETH {
+        let to_weth = pair_pools(hash_pools, token0, WETH);
+        let from_weth = pair_pools(hash_pools, WETH, token1);
+        for first in &to_weth {
+            for second in &from_weth {
+                arbs.push(Opportunity::MultiHopArb(MultiHopArbOpportunity {
+                    trigger,
+                    pools: vec![moved, *first, *second],
+                    token_in: token1,
+                    base: None,
+                }));
             }
-        });
-
-    Some(merged_state_diffs)
+        }
+    }
+    (arbs, counters)
 }
 
-pub async fn extract_arb_pools(
+/// Arbs opened by the pools `state_diffs` touched, see [arb_routes]. `trigger` is the tx the
+/// diffs are of.
+pub async fn extract_arb_opportunities(
     provider: Arc<Provider<Ws>>,
     state_diffs: &BTreeMap<Address, AccountDiff>,
-    all_pools: &Arc<RwLock<DashMap<Address, Pool>>>,
+    pools: &PoolView,
     hash_pools: &Arc<DashMap<H160, Vec<Pool>>>,
-) -> Option<ArbPools> {
-    let read_lock = all_pools.read().await;
+    layouts: Option<&LayoutFetcher>,
+    trigger: Option<H256>,
+) -> Option<Vec<Opportunity>> {
     let touched_pools: Vec<Pool> = state_diffs
         .keys()
-        .filter_map(|e| read_lock.get(e).map(|p| (*p.value())))
+        .filter_map(|address| pools.get(address))
         .collect();
-    drop(read_lock);
 
-    let mut arb_pools: ArbPools = vec![];
+    let mut opportunities: Vec<Opportunity> = vec![];
 
     let mut exclusion_map: HashSet<Pool> = HashSet::new();
 
@@ -145,13 +392,12 @@ pub async fn extract_arb_pools(
         };
 
         let token0 = pool.token_0;
-        let token1 = pool.token_1;
 
         let token0_state_diff = &state_diffs.get(&token0)?.storage;
 
         // read the balanceOf mapping from the ERC20 contract
         let slot = if let Some(slot) =
-            slot_finder::slot_finder(provider.clone(), token0.clone(), pool.address).await
+            slot_finder::slot_finder(provider.clone(), layouts, token0.clone(), pool.address).await
         {
             slot
         } else {
@@ -161,98 +407,74 @@ pub async fn extract_arb_pools(
         };
 
         // key in the balanceOf mapping with pool's address
-        let storage_key = TxHash::from(ethers::utils::keccak256(abi::encode(&[
-            abi::Token::Address(pool.address),
-            abi::Token::Uint(slot),
-        ])));
+        let storage_key = storage_keys().balance_key(pool.address, slot);
 
         // if storage_diff is true, then pool has more token0 than before
-        let storage_diff = match token0_state_diff.get(&storage_key)? {
-            Diff::Changed(c) => {
-                let from = U256::from(c.from.to_fixed_bytes());
-                let to = U256::from(c.to.to_fixed_bytes());
-                to > from
-            }
-            _ => break,
+        let Some(storage_diff) = slot_increased(token0_state_diff.get(&storage_key)?) else {
+            break;
         };
-        // hash token0 & token1 addresses to key in all the relevant pools from
-        // hash_pools
-        let mut hasher = DefaultHasher::new();
-        token0.hash(&mut hasher);
-        token1.hash(&mut hasher);
-        let hash = hasher.finish();
-
-        let mut pool_map: HashMap<Pool, Vec<Pool>> = HashMap::new();
-        let pools = hash_pools.get(&H160::from_low_u64_be(hash))?;
 
         if storage_diff {
-            let mut vec_pool: Vec<Pool> = vec![];
-            for pool in pools.iter().filter(|p| p.address != pool.address) {
-                exclusion_map.insert(pool.clone());
-                vec_pool.push(pool.clone());
-            }
-
-            pool_map.insert(pool, vec_pool);
-
             // if to > from, then pool has more token0 and less token1 than before*
             // to arb, buy token0 and sell token1 to other pools
             // *not always the case
-            arb_pools.push(pool_map);
+            let (arbs, counters) = arb_routes(pool, hash_pools, trigger);
+            exclusion_map.extend(counters);
+            opportunities.extend(arbs);
         } else {
             // need to add logic to handle when
             // to < from
             continue;
         }
     }
-    Some(arb_pools)
+    Some(opportunities)
 }
 
 // credit to rusty-sando
 // https://github.com/mouseless-eth/rusty-sando/blob/master/bot/src/utils/state_diff.rs
-pub fn extract_sandwich_pools(
+/// Sandwiches on the pools `victim`, whose diffs are `state_diffs`, trades through
+pub fn extract_sandwich_opportunities(
+    victim: &Transaction,
     state_diffs: &BTreeMap<Address, AccountDiff>,
-    all_pools: &DashMap<Address, Pool>,
-) -> Option<Vec<TradablePool>> {
-    // capture all addresses that have a state change and are also a pool
-    let touched_pools: Vec<Pool> = state_diffs
-        .keys()
-        .filter_map(|e| all_pools.get(e).map(|p| (*p.value())))
-        .collect();
+    pools: &PoolView,
+) -> Option<Vec<SandwichOpportunity>> {
+    extract_sandwich_opportunities_with(victim, state_diffs, pools, storage_keys())
+}
 
+/// [extract_sandwich_opportunities] with the storage keys taken from `keys`. The diff is only
+/// borrowed, pools are copied out once they turn out to be tradable.
+pub fn extract_sandwich_opportunities_with(
+    victim: &Transaction,
+    state_diffs: &BTreeMap<Address, AccountDiff>,
+    pools: &PoolView,
+    keys: &StorageKeyCache,
+) -> Option<Vec<SandwichOpportunity>> {
     // find direction of swap based on state diff (does weth have state changes?)
-    let weth_state_diff = &state_diffs
-        .get(
-            &"0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
-                .parse::<H160>()
-                .unwrap(),
-        )?
-        .storage;
+    let weth_state_diff = &state_diffs.get(&WETH)?.storage;
 
-    let mut tradable_pools: Vec<TradablePool> = vec![];
+    let mut sandwiches: Vec<SandwichOpportunity> = vec![];
 
-    // find storage mapping index for each pool
-    for pool in touched_pools {
+    // capture all addresses that have a state change and are also a pool
+    for address in state_diffs.keys() {
+        let Some(pool) = pools.get(address) else {
+            continue;
+        };
         // find mapping storage location
         // reading balanceOf mapping given the address of the pool's address
-        let storage_key = TxHash::from(ethers::utils::keccak256(abi::encode(&[
-            abi::Token::Address(pool.address),
-            abi::Token::Uint(U256::from(3)),
-        ])));
-
-        let is_weth_input = match weth_state_diff.get(&storage_key)? {
-            Diff::Changed(c) => {
-                let from = U256::from(c.from.to_fixed_bytes());
-                let to = U256::from(c.to.to_fixed_bytes());
-                to > from
-            }
-            // TODO: handle reverse direction
-            _ => continue,
+        let storage_key = keys.weth_balance_key(*address);
+
+        // TODO: handle reverse direction
+        let Some(is_weth_input) = slot_increased(weth_state_diff.get(&storage_key)?) else {
+            continue;
         };
-        let rp = pool.to_rp();
-        tradable_pools.push(TradablePool::new(rp, is_weth_input));
+        sandwiches.push(SandwichOpportunity::new(
+            victim.clone(),
+            pool,
+            is_weth_input,
+        ));
     }
 
-    Some(tradable_pools)
+    Some(sandwiches)
 }
 
 // credit to rusty-sando
@@ -292,12 +514,8 @@ pub async fn to_cache_db(
 
             let code = code_provider.get_code(addy, block_num).await?;
 
-            Ok::<(AccountDiff, Address, U256, U256, Bytes), ProviderError>((
-                acc_diff.clone(),
-                *address,
-                nonce,
-                balance,
-                code,
+            Ok::<(&AccountDiff, Address, U256, U256, Bytes), ProviderError>((
+                acc_diff, *address, nonce, balance, code,
             ))
         };
 
@@ -310,7 +528,7 @@ pub async fn to_cache_db(
         cache_db.insert_account_info(address.0.into(), info);
 
         acc_diff.storage.iter().for_each(|(slot, storage_diff)| {
-            let slot_value: U256 = match storage_diff.to_owned() {
+            let slot_value: U256 = match storage_diff {
                 Diff::Changed(v) => v.from.0.into(),
                 Diff::Died(v) => v.0.into(),
                 _ => {
@@ -327,3 +545,181 @@ pub async fn to_cache_db(
 
     Ok(cache_db)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace_client::{TraceBackend, TxStateDiffs};
+
+    /// Every tx writes `pool`, its balance set to the tx's nonce
+    struct MockTracer {
+        pool: Address,
+        calls: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl TraceClient for MockTracer {
+        fn backend(&self) -> TraceBackend {
+            TraceBackend::Parity
+        }
+
+        async fn state_diffs(
+            &self,
+            txs: &[Transaction],
+            _block: BlockNumber,
+        ) -> Result<TxStateDiffs, TraceError> {
+            self.calls
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(txs
+                .iter()
+                .map(|tx| {
+                    let account = AccountDiff {
+                        balance: Diff::Born(tx.nonce),
+                        nonce: Diff::Same,
+                        code: Diff::Same,
+                        storage: BTreeMap::new(),
+                    };
+                    BTreeMap::from([(self.pool, account)])
+                })
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_get_from_txs_traces_in_one_call() {
+        let tracer = MockTracer {
+            pool: Address::from_low_u64_be(1),
+            calls: Default::default(),
+        };
+        let meats: Vec<Transaction> = (0..3u64)
+            .map(|i| Transaction {
+                hash: H256::from_low_u64_be(i),
+                nonce: U256::from(i),
+                ..Default::default()
+            })
+            .collect();
+
+        let mut seen = vec![];
+        let merged = futures::executor::block_on(get_from_txs_with(
+            &tracer,
+            &meats,
+            BlockNumber::Latest,
+            DiffMergeStrategy::FirstSeen,
+            |diff| seen.push(diff.index),
+        ))
+        .unwrap();
+
+        // one trace of the whole block, diffs handed out in tx order
+        assert_eq!(tracer.calls.load(std::sync::atomic::Ordering::Relaxed), 1);
+        assert_eq!(seen, vec![0, 1, 2]);
+        // the earliest tx's diff is kept
+        assert_eq!(merged[&tracer.pool].balance, Diff::Born(U256::zero()));
+    }
+
+    #[test]
+    fn test_storage_key_cache() {
+        assert_eq!(
+            WETH,
+            "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+                .parse::<H160>()
+                .unwrap()
+        );
+
+        let pool = Address::from_low_u64_be(1);
+        let keys = StorageKeyCache::new();
+        let key = keys.weth_balance_key(pool);
+        assert_eq!(key, slot_key(mapping_slot(pool, WETH_BALANCE_OF_SLOT)));
+        keys.weth_balance_key(pool);
+        assert_eq!(keys.len(), 1);
+
+        let changed = |from: u64, to: u64| {
+            Diff::Changed(ChangedType {
+                from: H256::from_low_u64_be(from),
+                to: H256::from_low_u64_be(to),
+            })
+        };
+        assert_eq!(slot_increased(&changed(0xff, 0x100)), Some(true));
+        assert_eq!(slot_increased(&changed(2, 1)), Some(false));
+        assert_eq!(slot_increased(&Diff::Same), None);
+    }
+
+    #[test]
+    fn test_arb_routes() {
+        use qilin_cfmms::test_utils::{address, v2_pool};
+
+        let reserves = (U256::from(1_000), U256::from(1_000));
+        let (token0, token1) = (address(1), address(2));
+        let moved = v2_pool(address(10), token0, token1, reserves);
+        let counter = v2_pool(address(11), token0, token1, reserves);
+        // WETH sorts above both tokens
+        let to_weth = v2_pool(address(12), token0, WETH, reserves);
+        let from_weth = v2_pool(address(13), token1, WETH, reserves);
+        let hash_pools = DashMap::new();
+        for pool in [moved, counter, to_weth, from_weth] {
+            hash_pools
+                .entry(pair_key(pool.token_0, pool.token_1))
+                .or_insert_with(Vec::new)
+                .push(pool);
+        }
+
+        let trigger = Some(H256::from_low_u64_be(7));
+        let (arbs, counters) = arb_routes(moved, &hash_pools, trigger);
+        assert_eq!(counters, vec![counter]);
+        assert_eq!(
+            arbs,
+            vec![
+                Opportunity::TwoPoolArb(TwoPoolArbOpportunity {
+                    trigger,
+                    moved,
+                    counter,
+                    token_in: token1,
+                    base: None,
+                }),
+                Opportunity::MultiHopArb(MultiHopArbOpportunity {
+                    trigger,
+                    pools: vec![moved, to_weth, from_weth],
+                    token_in: token1,
+                    base: None,
+                }),
+            ]
+        );
+
+        // a WETH pair has no hop through WETH
+        let (arbs, counters) = arb_routes(to_weth, &hash_pools, None);
+        assert!(arbs.is_empty() && counters.is_empty());
+    }
+
+    #[test]
+    fn test_merge_diffs() {
+        let account = |nonce: usize| AccountDiff {
+            balance: Diff::Same,
+            nonce: Diff::Born(U256::from(nonce)),
+            code: Diff::Same,
+            storage: BTreeMap::new(),
+        };
+        // every tx touches a shared account and one of its own, in shuffled order
+        let shared = Address::from_low_u64_be(1);
+        let diffs = |count: usize| -> Vec<TxDiff> {
+            (0..count)
+                .map(|i| (i * 7) % count)
+                .map(|index| TxDiff {
+                    index,
+                    tx_hash: H256::from_low_u64_be(index as u64),
+                    diff: BTreeMap::from([
+                        (shared, account(index)),
+                        (Address::from_low_u64_be(100 + index as u64), account(index)),
+                    ]),
+                })
+                .collect()
+        };
+
+        for count in [3, PARALLEL_MERGE_THRESHOLD * 4 + 1] {
+            let first = merge_diffs(diffs(count), DiffMergeStrategy::FirstSeen);
+            assert_eq!(first.len(), count + 1);
+            assert_eq!(first[&shared].nonce, Diff::Born(U256::zero()));
+
+            let last = merge_diffs(diffs(count), DiffMergeStrategy::LastSeen);
+            assert_eq!(last[&shared].nonce, Diff::Born(U256::from(count - 1)));
+        }
+    }
+}
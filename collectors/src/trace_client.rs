@@ -0,0 +1,641 @@
+//! State diff tracing across node implementations
+//!
+//! Parity style `trace_callMany` is only served by Erigon, Nethermind and Reth. [TraceClient]
+//! hides which endpoint produces the diffs, [detect_trace_client] picks the best one the node
+//! supports.
+//!
+//! Some nodes also answer whole blocks in one call: Erigon and Reth serve `eth_getBlockReceipts`
+//! and Erigon `erigon_getBalanceChangesInBlock`. Clients without them return `None`, see
+//! [fetch_block_receipts] for the per tx fallback.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use ethers::prelude::*;
+use futures::future::try_join_all;
+use log::debug;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TraceError {
+    #[error("Provider error: {0}")]
+    Provider(String),
+    #[error("Unexpected trace response: {0}")]
+    Response(String),
+}
+
+/// State diffs of a bundle of txs, one map per tx, in order
+pub type TxStateDiffs = Vec<BTreeMap<Address, AccountDiff>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceBackend {
+    /// `trace_callMany`
+    Parity,
+    /// `debug_traceCall` with the prestate tracer, one call per tx
+    Geth,
+    /// `debug_traceCallMany` with the prestate tracer, the whole bundle in one call
+    Reth,
+    /// `trace_callMany`, plus Erigon's block level APIs
+    Erigon,
+}
+
+#[async_trait]
+pub trait TraceClient: Send + Sync {
+    fn backend(&self) -> TraceBackend;
+
+    /// Execute `txs` in order on top of `block` and return the state diff of each
+    async fn state_diffs(
+        &self,
+        txs: &[Transaction],
+        block: BlockNumber,
+    ) -> Result<TxStateDiffs, TraceError>;
+
+    /// State diff of the mined tx `hash`, replayed at its index in its block, i.e. on top of the
+    /// txs before it
+    async fn replayed_state_diff(
+        &self,
+        hash: H256,
+    ) -> Result<BTreeMap<Address, AccountDiff>, TraceError> {
+        Err(TraceError::Response(format!(
+            "{:?} can't be replayed by {:?}",
+            hash,
+            self.backend()
+        )))
+    }
+
+    /// All receipts of `block` in a single call, `None` if the node can't serve them
+    async fn block_receipts(
+        &self,
+        _block: BlockNumber,
+    ) -> Result<Option<Vec<TransactionReceipt>>, TraceError> {
+        Ok(None)
+    }
+
+    /// Post-block balance of every account whose balance `block` changed, `None` if the node
+    /// can't serve them
+    async fn balance_changes(
+        &self,
+        _block: BlockNumber,
+    ) -> Result<Option<BTreeMap<Address, U256>>, TraceError> {
+        Ok(None)
+    }
+}
+
+/// Probe the node behind `provider` and return a client for its best tracing endpoint
+///
+/// Reth and Erigon are recognised by their client version, otherwise Parity traces are
+/// preferred when `trace_callMany` answers, with Geth's debug namespace as the fallback.
+pub async fn detect_trace_client<M: Middleware + 'static>(
+    provider: Arc<M>,
+) -> Arc<dyn TraceClient> {
+    let version = provider.client_version().await.unwrap_or_default();
+    if version.to_lowercase().starts_with("reth") {
+        debug!("Tracing through reth's debug_traceCallMany ({})", version);
+        return Arc::new(RethTraceClient::new(provider));
+    }
+    if version.to_lowercase().starts_with("erigon") {
+        debug!("Tracing through erigon's trace_callMany ({})", version);
+        return Arc::new(ErigonTraceClient::new(provider));
+    }
+
+    let probe: Result<Value, _> = provider
+        .provider()
+        .request("trace_callMany", json!([[], "latest"]))
+        .await;
+    if probe.is_ok() {
+        debug!("Tracing through trace_callMany ({})", version);
+        Arc::new(ParityTraceClient::new(provider))
+    } else {
+        debug!("Tracing through debug_traceCall ({})", version);
+        Arc::new(GethTraceClient::new(provider))
+    }
+}
+
+#[derive(Debug)]
+pub struct ParityTraceClient<M> {
+    provider: Arc<M>,
+}
+
+impl<M> ParityTraceClient<M> {
+    pub fn new(provider: Arc<M>) -> Self {
+        Self { provider }
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> TraceClient for ParityTraceClient<M> {
+    fn backend(&self) -> TraceBackend {
+        TraceBackend::Parity
+    }
+
+    async fn state_diffs(
+        &self,
+        txs: &[Transaction],
+        block: BlockNumber,
+    ) -> Result<TxStateDiffs, TraceError> {
+        let req = txs
+            .iter()
+            .map(|tx| (tx, vec![TraceType::StateDiff]))
+            .collect();
+        let traces = self
+            .provider
+            .trace_call_many(req, Some(block))
+            .await
+            .map_err(|e| TraceError::Provider(e.to_string()))?;
+        Ok(traces
+            .into_iter()
+            .map(|trace| trace.state_diff.map(|diff| diff.0).unwrap_or_default())
+            .collect())
+    }
+
+    async fn replayed_state_diff(
+        &self,
+        hash: H256,
+    ) -> Result<BTreeMap<Address, AccountDiff>, TraceError> {
+        let trace = self
+            .provider
+            .trace_replay_transaction(hash, vec![TraceType::StateDiff])
+            .await
+            .map_err(|e| TraceError::Provider(e.to_string()))?;
+        Ok(trace.state_diff.map(|diff| diff.0).unwrap_or_default())
+    }
+}
+
+/// Receipts of `block`, in one call when `tracer` supports it and one call per tx otherwise
+pub async fn fetch_block_receipts<M: Middleware + 'static>(
+    tracer: &dyn TraceClient,
+    provider: &M,
+    block: BlockNumber,
+) -> Result<Vec<TransactionReceipt>, TraceError> {
+    if let Some(receipts) = tracer.block_receipts(block).await? {
+        return Ok(receipts);
+    }
+    let block = provider
+        .get_block(block)
+        .await
+        .map_err(|e| TraceError::Provider(e.to_string()))?
+        .ok_or_else(|| TraceError::Response(format!("block {:?} not found", block)))?;
+    try_join_all(block.transactions.iter().map(|hash| async move {
+        provider
+            .get_transaction_receipt(*hash)
+            .await
+            .map_err(|e| TraceError::Provider(e.to_string()))?
+            .ok_or_else(|| TraceError::Response(format!("receipt of {:?} not found", hash)))
+    }))
+    .await
+}
+
+/// Geth has no bundle tracing, each tx is traced on its own with the post-state of the txs before
+/// it passed as state overrides
+#[derive(Debug)]
+pub struct GethTraceClient<M> {
+    provider: Arc<M>,
+}
+
+impl<M> GethTraceClient<M> {
+    pub fn new(provider: Arc<M>) -> Self {
+        Self { provider }
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> TraceClient for GethTraceClient<M> {
+    fn backend(&self) -> TraceBackend {
+        TraceBackend::Geth
+    }
+
+    async fn state_diffs(
+        &self,
+        txs: &[Transaction],
+        block: BlockNumber,
+    ) -> Result<TxStateDiffs, TraceError> {
+        let mut overrides = serde_json::Map::new();
+        let mut diffs = Vec::with_capacity(txs.len());
+        for tx in txs {
+            let mut options = prestate_diff_options();
+            options["stateOverrides"] = Value::Object(overrides.clone());
+            let frame: PrestateDiff = self
+                .provider
+                .provider()
+                .request("debug_traceCall", json!([call_object(tx), block, options]))
+                .await
+                .map_err(|e| TraceError::Provider(e.to_string()))?;
+            frame.extend_overrides(&mut overrides);
+            diffs.push(frame.into_account_diffs());
+        }
+        Ok(diffs)
+    }
+
+    async fn replayed_state_diff(
+        &self,
+        hash: H256,
+    ) -> Result<BTreeMap<Address, AccountDiff>, TraceError> {
+        debug_trace_transaction(self.provider.as_ref(), hash).await
+    }
+}
+
+#[derive(Debug)]
+pub struct RethTraceClient<M> {
+    provider: Arc<M>,
+}
+
+impl<M> RethTraceClient<M> {
+    pub fn new(provider: Arc<M>) -> Self {
+        Self { provider }
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> TraceClient for RethTraceClient<M> {
+    fn backend(&self) -> TraceBackend {
+        TraceBackend::Reth
+    }
+
+    async fn state_diffs(
+        &self,
+        txs: &[Transaction],
+        block: BlockNumber,
+    ) -> Result<TxStateDiffs, TraceError> {
+        let bundle = json!({ "transactions": txs.iter().map(call_object).collect::<Vec<_>>() });
+        let frames: Vec<Vec<PrestateDiff>> = self
+            .provider
+            .provider()
+            .request(
+                "debug_traceCallMany",
+                json!([[bundle], { "blockNumber": block }, prestate_diff_options()]),
+            )
+            .await
+            .map_err(|e| TraceError::Provider(e.to_string()))?;
+        let frames = frames
+            .into_iter()
+            .next()
+            .ok_or_else(|| TraceError::Response("empty bundle trace".to_string()))?;
+        if frames.len() != txs.len() {
+            return Err(TraceError::Response(format!(
+                "{} traces for {} txs",
+                frames.len(),
+                txs.len()
+            )));
+        }
+        Ok(frames
+            .into_iter()
+            .map(PrestateDiff::into_account_diffs)
+            .collect())
+    }
+
+    async fn replayed_state_diff(
+        &self,
+        hash: H256,
+    ) -> Result<BTreeMap<Address, AccountDiff>, TraceError> {
+        debug_trace_transaction(self.provider.as_ref(), hash).await
+    }
+
+    async fn block_receipts(
+        &self,
+        block: BlockNumber,
+    ) -> Result<Option<Vec<TransactionReceipt>>, TraceError> {
+        get_block_receipts(self.provider.as_ref(), block).await
+    }
+}
+
+/// Erigon traces like Parity and adds block level APIs on top
+#[derive(Debug)]
+pub struct ErigonTraceClient<M> {
+    parity: ParityTraceClient<M>,
+}
+
+impl<M> ErigonTraceClient<M> {
+    pub fn new(provider: Arc<M>) -> Self {
+        Self {
+            parity: ParityTraceClient::new(provider),
+        }
+    }
+}
+
+#[async_trait]
+impl<M: Middleware + 'static> TraceClient for ErigonTraceClient<M> {
+    fn backend(&self) -> TraceBackend {
+        TraceBackend::Erigon
+    }
+
+    async fn state_diffs(
+        &self,
+        txs: &[Transaction],
+        block: BlockNumber,
+    ) -> Result<TxStateDiffs, TraceError> {
+        self.parity.state_diffs(txs, block).await
+    }
+
+    async fn replayed_state_diff(
+        &self,
+        hash: H256,
+    ) -> Result<BTreeMap<Address, AccountDiff>, TraceError> {
+        self.parity.replayed_state_diff(hash).await
+    }
+
+    async fn block_receipts(
+        &self,
+        block: BlockNumber,
+    ) -> Result<Option<Vec<TransactionReceipt>>, TraceError> {
+        get_block_receipts(self.parity.provider.as_ref(), block).await
+    }
+
+    async fn balance_changes(
+        &self,
+        block: BlockNumber,
+    ) -> Result<Option<BTreeMap<Address, U256>>, TraceError> {
+        self.parity
+            .provider
+            .provider()
+            .request("erigon_getBalanceChangesInBlock", json!([block]))
+            .await
+            .map(Some)
+            .map_err(|e| TraceError::Provider(e.to_string()))
+    }
+}
+
+async fn get_block_receipts<M: Middleware>(
+    provider: &M,
+    block: BlockNumber,
+) -> Result<Option<Vec<TransactionReceipt>>, TraceError> {
+    provider
+        .get_block_receipts(block)
+        .await
+        .map(Some)
+        .map_err(|e| TraceError::Provider(e.to_string()))
+}
+
+/// `debug_traceTransaction` with the prestate tracer, the tx is replayed at its index in its block
+async fn debug_trace_transaction<M: Middleware>(
+    provider: &M,
+    hash: H256,
+) -> Result<BTreeMap<Address, AccountDiff>, TraceError> {
+    let frame: PrestateDiff = provider
+        .provider()
+        .request(
+            "debug_traceTransaction",
+            json!([hash, prestate_diff_options()]),
+        )
+        .await
+        .map_err(|e| TraceError::Provider(e.to_string()))?;
+    Ok(frame.into_account_diffs())
+}
+
+fn prestate_diff_options() -> Value {
+    json!({ "tracer": "prestateTracer", "tracerConfig": { "diffMode": true } })
+}
+
+/// `eth_call` style call object of a tx
+fn call_object(tx: &Transaction) -> Value {
+    let mut call = json!({
+        "from": tx.from,
+        "gas": tx.gas,
+        "value": tx.value,
+        "data": tx.input,
+        "nonce": tx.nonce,
+    });
+    if let Some(to) = tx.to {
+        call["to"] = json!(to);
+    }
+    match tx.max_fee_per_gas {
+        Some(max_fee) => {
+            call["maxFeePerGas"] = json!(max_fee);
+            call["maxPriorityFeePerGas"] = json!(tx.max_priority_fee_per_gas.unwrap_or_default());
+        }
+        None => call["gasPrice"] = json!(tx.gas_price.unwrap_or_default()),
+    }
+    call
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct PrestateAccount {
+    balance: Option<U256>,
+    nonce: Option<u64>,
+    code: Option<Bytes>,
+    #[serde(default)]
+    storage: BTreeMap<H256, H256>,
+}
+
+/// Diff mode output of the prestate tracer
+///
+/// `pre` holds the touched accounts before the tx, `post` only the fields that changed. Accounts
+/// missing from `post` were destroyed, slots missing from it were zeroed.
+#[derive(Debug, Clone, Default, Deserialize)]
+struct PrestateDiff {
+    #[serde(default)]
+    pre: BTreeMap<Address, PrestateAccount>,
+    #[serde(default)]
+    post: BTreeMap<Address, PrestateAccount>,
+}
+
+impl PrestateDiff {
+    fn into_account_diffs(self) -> BTreeMap<Address, AccountDiff> {
+        let mut diffs = BTreeMap::new();
+        for (address, pre) in &self.pre {
+            let diff = match self.post.get(address) {
+                Some(post) => changed_account(pre, post),
+                None => AccountDiff {
+                    balance: Diff::Died(pre.balance.unwrap_or_default()),
+                    nonce: Diff::Died(pre.nonce.unwrap_or_default().into()),
+                    code: Diff::Died(pre.code.clone().unwrap_or_default()),
+                    storage: pre
+                        .storage
+                        .iter()
+                        .map(|(slot, value)| (*slot, Diff::Died(*value)))
+                        .collect(),
+                },
+            };
+            diffs.insert(*address, diff);
+        }
+        for (address, post) in &self.post {
+            if self.pre.contains_key(address) {
+                continue;
+            }
+            diffs.insert(
+                *address,
+                AccountDiff {
+                    balance: Diff::Born(post.balance.unwrap_or_default()),
+                    nonce: Diff::Born(post.nonce.unwrap_or_default().into()),
+                    code: Diff::Born(post.code.clone().unwrap_or_default()),
+                    storage: post
+                        .storage
+                        .iter()
+                        .map(|(slot, value)| (*slot, Diff::Born(*value)))
+                        .collect(),
+                },
+            );
+        }
+        diffs
+    }
+
+    /// Add the post-state to geth `stateOverrides`, so the next tx is traced on top of it
+    fn extend_overrides(&self, overrides: &mut serde_json::Map<String, Value>) {
+        for (address, pre) in &self.pre {
+            let entry = overrides
+                .entry(format!("{:?}", address))
+                .or_insert_with(|| json!({}));
+            let Some(post) = self.post.get(address) else {
+                *entry = json!({
+                    "balance": U256::zero(),
+                    "nonce": U256::zero(),
+                    "code": "0x",
+                    "state": {},
+                });
+                continue;
+            };
+            if let Some(balance) = post.balance {
+                entry["balance"] = json!(balance);
+            }
+            if let Some(nonce) = post.nonce {
+                entry["nonce"] = json!(U256::from(nonce));
+            }
+            if let Some(code) = &post.code {
+                entry["code"] = json!(code);
+            }
+            let state_key = if entry.get("state").is_some() {
+                "state"
+            } else {
+                "stateDiff"
+            };
+            if entry.get(state_key).is_none() {
+                entry[state_key] = json!({});
+            }
+            for slot in pre.storage.keys() {
+                let value = post.storage.get(slot).copied().unwrap_or_default();
+                entry[state_key][format!("{:?}", slot)] = json!(value);
+            }
+            for (slot, value) in &post.storage {
+                entry[state_key][format!("{:?}", slot)] = json!(value);
+            }
+        }
+        for (address, post) in &self.post {
+            if self.pre.contains_key(address) {
+                continue;
+            }
+            overrides.insert(
+                format!("{:?}", address),
+                json!({
+                    "balance": post.balance.unwrap_or_default(),
+                    "nonce": U256::from(post.nonce.unwrap_or_default()),
+                    "code": post.code.clone().unwrap_or_default(),
+                    "state": post.storage,
+                }),
+            );
+        }
+    }
+}
+
+fn changed_account(pre: &PrestateAccount, post: &PrestateAccount) -> AccountDiff {
+    fn diff<T: Clone + Default + PartialEq>(pre: &Option<T>, post: &Option<T>) -> Diff<T> {
+        match post {
+            Some(to) if post != pre => Diff::Changed(ChangedType {
+                from: pre.clone().unwrap_or_default(),
+                to: to.clone(),
+            }),
+            _ => Diff::Same,
+        }
+    }
+
+    let mut storage = BTreeMap::new();
+    for (slot, from) in &pre.storage {
+        let to = post.storage.get(slot).copied().unwrap_or_default();
+        if to != *from {
+            storage.insert(*slot, Diff::Changed(ChangedType { from: *from, to }));
+        }
+    }
+    for (slot, to) in &post.storage {
+        if !pre.storage.contains_key(slot) {
+            storage.insert(
+                *slot,
+                Diff::Changed(ChangedType {
+                    from: H256::zero(),
+                    to: *to,
+                }),
+            );
+        }
+    }
+
+    AccountDiff {
+        balance: diff(&pre.balance, &post.balance),
+        nonce: match diff(&pre.nonce, &post.nonce) {
+            Diff::Changed(ChangedType { from, to }) => Diff::Changed(ChangedType {
+                from: from.into(),
+                to: to.into(),
+            }),
+            _ => Diff::Same,
+        },
+        code: diff(&pre.code, &post.code),
+        storage,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prestate_diff_to_account_diffs() {
+        let frame: PrestateDiff = serde_json::from_value(json!({
+            "pre": {
+                "0x0000000000000000000000000000000000000001": {
+                    "balance": "0x10",
+                    "nonce": 1,
+                    "storage": {
+                        "0x0000000000000000000000000000000000000000000000000000000000000001":
+                            "0x0000000000000000000000000000000000000000000000000000000000000005",
+                        "0x0000000000000000000000000000000000000000000000000000000000000002":
+                            "0x0000000000000000000000000000000000000000000000000000000000000007"
+                    }
+                }
+            },
+            "post": {
+                "0x0000000000000000000000000000000000000001": {
+                    "balance": "0x20",
+                    "storage": {
+                        "0x0000000000000000000000000000000000000000000000000000000000000001":
+                            "0x0000000000000000000000000000000000000000000000000000000000000006"
+                    }
+                },
+                "0x0000000000000000000000000000000000000002": {
+                    "balance": "0x1"
+                }
+            }
+        }))
+        .unwrap();
+
+        let mut overrides = serde_json::Map::new();
+        frame.extend_overrides(&mut overrides);
+        let diffs = frame.into_account_diffs();
+
+        let touched = &diffs[&Address::from_low_u64_be(1)];
+        assert_eq!(
+            touched.balance,
+            Diff::Changed(ChangedType {
+                from: U256::from(0x10),
+                to: U256::from(0x20)
+            })
+        );
+        assert_eq!(touched.nonce, Diff::Same);
+        // slot 2 is missing from post, it was zeroed
+        assert_eq!(
+            touched.storage[&H256::from_low_u64_be(2)],
+            Diff::Changed(ChangedType {
+                from: H256::from_low_u64_be(7),
+                to: H256::zero()
+            })
+        );
+        assert_eq!(
+            diffs[&Address::from_low_u64_be(2)].balance,
+            Diff::Born(U256::one())
+        );
+
+        let state = &overrides["0x0000000000000000000000000000000000000001"]["stateDiff"];
+        assert_eq!(
+            state["0x0000000000000000000000000000000000000000000000000000000000000002"],
+            json!(H256::zero())
+        );
+    }
+}
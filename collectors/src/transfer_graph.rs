@@ -0,0 +1,524 @@
+//! Token flows of a tx reconstructed from its logs or state diff
+//!
+//! Swaps through known pools are what
+//! [extract_sandwich_opportunities](crate::state_diff::extract_sandwich_opportunities) and
+//! [extract_arb_opportunities](crate::state_diff::extract_arb_opportunities) look for. Flows
+//! that don't go through them can be worth a backrun too: a marketplace sweep buying up a
+//! collection, a large OTC transfer about to be sold.
+//!
+//! [TransferGraph::from_logs] takes the token [Flow]s from the tx's `Transfer` logs, whoever the
+//! holders are: EOAs receiving tokens usually don't show up in a state diff. ERC721 transfers
+//! move a `balanceOf` count of one. ETH has no logs, its flows are paired from the balances the
+//! diff shows.
+//!
+//! [TransferGraph::from_diff] works from the diff alone, for tokens whose `balanceOf` slot is
+//! known, see [slot_finder](crate::slot_finder::slot_finder). Holders have to be part of the
+//! diff or given as extra holders, and gainers are paired with losers into flows.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use ethers::types::{AccountDiff, Address, Diff, Log, H256, I256, U256};
+use fork_database::{
+    inspectors::{balance_delta::TRANSFER_TOPIC, Asset},
+    storage_layout::{mapping_slot, slot_key},
+    utils::{b160_to_h160, h160_to_b160},
+};
+
+/// `amount` of `asset` moving from `from` to `to`, `None` for mints and burns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flow {
+    pub asset: Asset,
+    pub from: Option<Address>,
+    pub to: Option<Address>,
+    pub amount: U256,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlowPattern {
+    /// a single flow above the asset's threshold
+    LargeTransfer(Flow),
+    /// `buyer` receiving `asset` from many holders at once, e.g. an NFT sweep
+    Sweep {
+        buyer: Address,
+        asset: Asset,
+        sellers: usize,
+        amount: U256,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferGraph {
+    /// net change of each holder, by asset
+    deltas: BTreeMap<(Address, Address), I256>,
+    eth: BTreeMap<Address, I256>,
+    flows: Vec<Flow>,
+}
+
+impl TransferGraph {
+    /// Token flows of the `Transfer`s among `logs`, in the order emitted, and ETH flows of the
+    /// balances changed in `diffs`
+    pub fn from_logs(logs: &[Log], diffs: &BTreeMap<Address, AccountDiff>) -> Self {
+        let mut graph = Self {
+            eth: eth_deltas(diffs),
+            ..Self::default()
+        };
+        for flow in logs.iter().filter_map(transfer_flow) {
+            let (Asset::Token(token), Ok(amount)) = (flow.asset, I256::try_from(flow.amount))
+            else {
+                continue;
+            };
+            let token = b160_to_h160(token);
+            if let Some(from) = flow.from {
+                let delta = graph.deltas.entry((token, from)).or_default();
+                *delta = delta.saturating_sub(amount);
+            }
+            if let Some(to) = flow.to {
+                let delta = graph.deltas.entry((token, to)).or_default();
+                *delta = delta.saturating_add(amount);
+            }
+            graph.flows.push(flow);
+        }
+        graph.deltas.retain(|_, delta| !delta.is_zero());
+        graph.flows.extend(pair_flows(graph.eth_by_holder()));
+        graph
+    }
+
+    /// Balances changed in `diffs`. `balance_slots` gives the `balanceOf` base of the tokens to
+    /// decode, others are left out: guessing the base would read any changed mapping entry of
+    /// a holder as a balance.
+    pub fn from_diff(
+        diffs: &BTreeMap<Address, AccountDiff>,
+        extra_holders: &[Address],
+        balance_slots: &HashMap<Address, U256>,
+    ) -> Self {
+        let holders: BTreeSet<Address> =
+            diffs.keys().chain(extra_holders.iter()).copied().collect();
+
+        let mut graph = Self {
+            eth: eth_deltas(diffs),
+            ..Self::default()
+        };
+        for (address, diff) in diffs {
+            let Some(base) = balance_slots.get(address) else {
+                continue;
+            };
+            for holder in &holders {
+                let key = slot_key(mapping_slot(*holder, *base));
+                if let Some(delta) = diff.storage.get(&key).and_then(delta_of_word) {
+                    graph.deltas.insert((*address, *holder), delta);
+                }
+            }
+        }
+        let mut by_asset = graph.eth_by_holder();
+        for ((token, holder), delta) in &graph.deltas {
+            by_asset
+                .entry(AssetKey::Token(*token))
+                .or_default()
+                .push((*holder, *delta));
+        }
+        graph.flows = pair_flows(by_asset);
+        graph
+    }
+
+    /// Net change of `holder` in `asset`
+    pub fn delta(&self, asset: Asset, holder: Address) -> I256 {
+        match asset {
+            Asset::Eth => self.eth.get(&holder),
+            Asset::Token(token) => self.deltas.get(&(b160_to_h160(token), holder)),
+        }
+        .copied()
+        .unwrap_or_default()
+    }
+
+    pub fn flows(&self) -> &[Flow] {
+        &self.flows
+    }
+
+    /// Flows of `asset`, largest first
+    pub fn flows_of(&self, asset: Asset) -> Vec<Flow> {
+        let mut flows: Vec<Flow> = self
+            .flows
+            .iter()
+            .filter(|flow| flow.asset == asset)
+            .copied()
+            .collect();
+        flows.sort_by(|a, b| b.amount.cmp(&a.amount));
+        flows
+    }
+
+    /// Flows not touching `known` addresses, e.g. tracked pools and routers, worth a look:
+    /// single flows of at least the asset's threshold, assets without one are ignored, and
+    /// holders receiving an asset from at least `min_sellers` others
+    pub fn patterns(
+        &self,
+        known: &HashSet<Address>,
+        thresholds: &HashMap<Asset, U256>,
+        min_sellers: usize,
+    ) -> Vec<FlowPattern> {
+        let unknown = |address: Option<Address>| address.map_or(true, |a| !known.contains(&a));
+        let flows: Vec<&Flow> = self
+            .flows
+            .iter()
+            .filter(|flow| unknown(flow.from) && unknown(flow.to))
+            .collect();
+
+        let mut patterns: Vec<FlowPattern> = flows
+            .iter()
+            .filter(|flow| {
+                thresholds
+                    .get(&flow.asset)
+                    .map_or(false, |min| flow.amount >= *min)
+            })
+            .map(|flow| FlowPattern::LargeTransfer(**flow))
+            .collect();
+
+        let mut received: BTreeMap<(Address, AssetKey), (BTreeSet<Address>, U256)> =
+            BTreeMap::new();
+        for flow in &flows {
+            let (Some(from), Some(to)) = (flow.from, flow.to) else {
+                continue;
+            };
+            let entry = received
+                .entry((to, AssetKey::from(flow.asset)))
+                .or_default();
+            entry.0.insert(from);
+            entry.1 = entry.1.saturating_add(flow.amount);
+        }
+        for ((buyer, asset), (sellers, amount)) in received {
+            if sellers.len() >= min_sellers.max(2) {
+                patterns.push(FlowPattern::Sweep {
+                    buyer,
+                    asset: asset.into(),
+                    sellers: sellers.len(),
+                    amount,
+                });
+            }
+        }
+        patterns
+    }
+
+    fn eth_by_holder(&self) -> BTreeMap<AssetKey, Vec<(Address, I256)>> {
+        let deltas: Vec<(Address, I256)> = self.eth.iter().map(|(h, d)| (*h, *d)).collect();
+        if deltas.is_empty() {
+            return BTreeMap::new();
+        }
+        BTreeMap::from([(AssetKey::Eth, deltas)])
+    }
+}
+
+/// Losers of each asset paired with its gainers, largest first. What is gained beyond what was
+/// lost is minted, and the other way around burned.
+fn pair_flows(by_asset: BTreeMap<AssetKey, Vec<(Address, I256)>>) -> Vec<Flow> {
+    let mut flows = vec![];
+    for (asset, deltas) in by_asset {
+        let asset = Asset::from(asset);
+        let mut losers: Vec<(Address, U256)> = deltas
+            .iter()
+            .filter(|(_, d)| d.is_negative())
+            .map(|(h, d)| (*h, d.unsigned_abs()))
+            .collect();
+        let mut gainers: Vec<(Address, U256)> = deltas
+            .iter()
+            .filter(|(_, d)| d.is_positive())
+            .map(|(h, d)| (*h, d.unsigned_abs()))
+            .collect();
+        losers.sort_by(|a, b| b.1.cmp(&a.1));
+        gainers.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let (mut l, mut g) = (0, 0);
+        while l < losers.len() && g < gainers.len() {
+            let amount = losers[l].1.min(gainers[g].1);
+            flows.push(Flow {
+                asset,
+                from: Some(losers[l].0),
+                to: Some(gainers[g].0),
+                amount,
+            });
+            losers[l].1 -= amount;
+            gainers[g].1 -= amount;
+            if losers[l].1.is_zero() {
+                l += 1;
+            }
+            if gainers[g].1.is_zero() {
+                g += 1;
+            }
+        }
+        for (holder, amount) in &losers[l.min(losers.len())..] {
+            flows.push(Flow {
+                asset,
+                from: Some(*holder),
+                to: None,
+                amount: *amount,
+            });
+        }
+        for (holder, amount) in &gainers[g.min(gainers.len())..] {
+            flows.push(Flow {
+                asset,
+                from: None,
+                to: Some(*holder),
+                amount: *amount,
+            });
+        }
+    }
+    flows.retain(|flow| !flow.amount.is_zero());
+    flows
+}
+
+/// The flow of an ERC20 or ERC721 `Transfer`, from or to the zero address for mints and burns
+fn transfer_flow(log: &Log) -> Option<Flow> {
+    if log.topics.first()?.0 != TRANSFER_TOPIC {
+        return None;
+    }
+    let amount = match log.topics.len() {
+        3 if log.data.len() >= 32 => U256::from_big_endian(&log.data[..32]),
+        // ERC721 indexes the token id
+        4 => U256::one(),
+        _ => return None,
+    };
+    let holder = |topic: &H256| Some(Address::from(*topic)).filter(|a| !a.is_zero());
+    let (from, to) = (holder(&log.topics[1]), holder(&log.topics[2]));
+    if amount.is_zero() || from == to {
+        return None;
+    }
+    Some(Flow {
+        asset: Asset::Token(h160_to_b160(log.address)),
+        from,
+        to,
+        amount,
+    })
+}
+
+fn eth_deltas(diffs: &BTreeMap<Address, AccountDiff>) -> BTreeMap<Address, I256> {
+    diffs
+        .iter()
+        .filter_map(|(address, diff)| Some((*address, delta_of(&diff.balance)?)))
+        .collect()
+}
+
+/// [Asset] with an order, to group flows deterministically
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum AssetKey {
+    Eth,
+    Token(Address),
+}
+
+impl From<Asset> for AssetKey {
+    fn from(asset: Asset) -> Self {
+        match asset {
+            Asset::Eth => Self::Eth,
+            Asset::Token(token) => Self::Token(b160_to_h160(token)),
+        }
+    }
+}
+
+impl From<AssetKey> for Asset {
+    fn from(asset: AssetKey) -> Self {
+        match asset {
+            AssetKey::Eth => Self::Eth,
+            AssetKey::Token(token) => Self::Token(h160_to_b160(token)),
+        }
+    }
+}
+
+fn delta_of(diff: &Diff<U256>) -> Option<I256> {
+    let (from, to) = match diff {
+        Diff::Changed(c) => (c.from, c.to),
+        Diff::Born(to) => (U256::zero(), *to),
+        Diff::Died(from) => (*from, U256::zero()),
+        Diff::Same => return None,
+    };
+    signed_delta(from, to)
+}
+
+fn delta_of_word(diff: &Diff<H256>) -> Option<I256> {
+    let word = |h: &H256| U256::from_big_endian(h.as_bytes());
+    let (from, to) = match diff {
+        Diff::Changed(c) => (word(&c.from), word(&c.to)),
+        Diff::Born(to) => (U256::zero(), word(to)),
+        Diff::Died(from) => (word(from), U256::zero()),
+        Diff::Same => return None,
+    };
+    signed_delta(from, to)
+}
+
+/// `None` for unchanged values and deltas too large to be balances
+fn signed_delta(from: U256, to: U256) -> Option<I256> {
+    if from == to {
+        return None;
+    }
+    let delta = if to > from {
+        I256::try_from(to - from).ok()?
+    } else {
+        -I256::try_from(from - to).ok()?
+    };
+    Some(delta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{abi::AbiEncode, types::ChangedType};
+
+    fn changed(from: u64, to: u64) -> Diff<H256> {
+        let word = |n: u64| H256::from_low_u64_be(n);
+        Diff::Changed(ChangedType {
+            from: word(from),
+            to: word(to),
+        })
+    }
+
+    #[test]
+    fn test_sweep_and_large_transfer() {
+        let address = |n| Address::from_low_u64_be(n);
+        let (nft, token, buyer, whale, desk) = (
+            address(100),
+            address(200),
+            address(1),
+            address(2),
+            address(3),
+        );
+        let sellers = [address(11), address(12), address(13)];
+
+        // OpenZeppelin ERC721 keeps balances at slot 3, the token at slot 0
+        let mut nft_storage = BTreeMap::new();
+        nft_storage.insert(slot_key(mapping_slot(buyer, 3u64)), changed(0, 3));
+        for seller in sellers {
+            nft_storage.insert(slot_key(mapping_slot(seller, 3u64)), changed(1, 0));
+        }
+        let mut token_storage = BTreeMap::new();
+        token_storage.insert(slot_key(mapping_slot(whale, 0u64)), changed(5_000, 0));
+        token_storage.insert(slot_key(mapping_slot(desk, 0u64)), changed(0, 5_000));
+
+        let account = |storage| AccountDiff {
+            balance: Diff::Same,
+            nonce: Diff::Same,
+            code: Diff::Same,
+            storage,
+        };
+        // a changed entry of some other mapping, no balance
+        let mut other_storage = BTreeMap::new();
+        other_storage.insert(slot_key(mapping_slot(whale, 1u64)), changed(1, 2));
+        let other = address(300);
+
+        let diffs = BTreeMap::from([
+            (nft, account(nft_storage)),
+            (token, account(token_storage)),
+            (other, account(other_storage)),
+        ]);
+        let holders: Vec<Address> = [buyer, whale, desk].into_iter().chain(sellers).collect();
+        let balance_slots = HashMap::from([(nft, U256::from(3)), (token, U256::zero())]);
+        let graph = TransferGraph::from_diff(&diffs, &holders, &balance_slots);
+        assert_eq!(
+            graph.delta(Asset::Token(h160_to_b160(other)), whale),
+            I256::zero()
+        );
+
+        let nft_asset = Asset::Token(h160_to_b160(nft));
+        let token_asset = Asset::Token(h160_to_b160(token));
+        assert_eq!(graph.delta(nft_asset, buyer), I256::from(3));
+        assert_eq!(graph.delta(token_asset, whale), I256::from(-5_000));
+        assert_eq!(graph.flows_of(nft_asset).len(), 3);
+
+        let patterns = graph.patterns(
+            &HashSet::new(),
+            &HashMap::from([(token_asset, U256::from(1_000))]),
+            3,
+        );
+        assert!(patterns.contains(&FlowPattern::LargeTransfer(Flow {
+            asset: token_asset,
+            from: Some(whale),
+            to: Some(desk),
+            amount: U256::from(5_000),
+        })));
+        assert!(patterns.contains(&FlowPattern::Sweep {
+            buyer,
+            asset: nft_asset,
+            sellers: 3,
+            amount: U256::from(3),
+        }));
+
+        // flows through known addresses aren't interesting
+        let known = HashSet::from([desk, buyer]);
+        assert!(graph
+            .patterns(&known, &HashMap::from([(token_asset, U256::one())]), 3)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_from_logs() {
+        let address = |n| Address::from_low_u64_be(n);
+        let (token, nft, sender, pool, recipient) = (
+            address(200),
+            address(100),
+            address(1),
+            address(2),
+            address(3),
+        );
+        let topic = |a: Address| H256::from(a);
+        let transfer = |asset, from, to, amount: u64| Log {
+            address: asset,
+            topics: vec![H256(TRANSFER_TOPIC), topic(from), topic(to)],
+            data: U256::from(amount).encode().into(),
+            ..Default::default()
+        };
+        let logs = vec![
+            transfer(token, sender, pool, 1_000),
+            // the recipient is an EOA no diff shows
+            transfer(token, pool, recipient, 990),
+            // minted fee
+            transfer(token, Address::zero(), sender, 5),
+            Log {
+                address: nft,
+                topics: vec![
+                    H256(TRANSFER_TOPIC),
+                    topic(pool),
+                    topic(recipient),
+                    H256::from_low_u64_be(42),
+                ],
+                ..Default::default()
+            },
+        ];
+        let mut diffs = BTreeMap::new();
+        diffs.insert(
+            sender,
+            AccountDiff {
+                balance: Diff::Changed(ChangedType {
+                    from: U256::from(100),
+                    to: U256::from(40),
+                }),
+                nonce: Diff::Same,
+                code: Diff::Same,
+                storage: BTreeMap::new(),
+            },
+        );
+
+        let graph = TransferGraph::from_logs(&logs, &diffs);
+        let token_asset = Asset::Token(h160_to_b160(token));
+        let nft_asset = Asset::Token(h160_to_b160(nft));
+        assert_eq!(graph.delta(token_asset, sender), I256::from(-995));
+        assert_eq!(graph.delta(token_asset, pool), I256::from(10));
+        assert_eq!(graph.delta(token_asset, recipient), I256::from(990));
+        assert_eq!(graph.delta(nft_asset, recipient), I256::one());
+        assert_eq!(graph.delta(Asset::Eth, sender), I256::from(-60));
+        assert_eq!(
+            graph.flows_of(token_asset)[1],
+            Flow {
+                asset: token_asset,
+                from: Some(pool),
+                to: Some(recipient),
+                amount: U256::from(990),
+            }
+        );
+        assert_eq!(graph.flows_of(token_asset)[2].from, None);
+        // the ETH the sender spent is burned, no one gained it
+        assert_eq!(
+            graph.flows_of(Asset::Eth),
+            vec![Flow {
+                asset: Asset::Eth,
+                from: Some(sender),
+                to: None,
+                amount: U256::from(60),
+            }]
+        );
+    }
+}
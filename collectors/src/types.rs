@@ -1,20 +1,20 @@
 /// Artemis Collectors types implementations
-use ethers::types::{AccountDiff, Block, Transaction, H160, H256};
-use qilin_cfmms::pool::Pool;
+use ethers::types::{AccountDiff, Block, Transaction, H160, H256, U64};
+use qilin_cfmms::registry::PoolView;
+
+use crate::latency::OpportunityTimeline;
+use crate::pair_discovery::Deployment;
 
-use dashmap::DashMap;
-use parking_lot::RwLock;
 use std::collections::BTreeMap;
 use std::sync::Arc;
 
-pub(crate) type RwLockMap = RwLock<DashMap<H160, Pool>>;
-
 /// A block payload, containing the all pool's states and block hash.
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct BlockPayload {
     pub block_hash: Block<H256>,
-    pub all_pools: Arc<RwLockMap>,
+    /// the pools as committed for the block
+    pub pools: Arc<PoolView>,
 }
 
 /// A new block event, containing the [Transaction] type and the `state_diff` BTreeMap.
@@ -22,6 +22,8 @@ pub struct BlockPayload {
 pub struct NewTx {
     pub tx: Transaction,
     pub state_diff: BTreeMap<H160, AccountDiff>,
+    /// pipeline stages the tx went through so far, strategies keep stamping it
+    pub timeline: OpportunityTimeline,
 }
 
 impl Default for NewTx {
@@ -29,6 +31,34 @@ impl Default for NewTx {
         Self {
             tx: Transaction::default(),
             state_diff: BTreeMap::new(),
+            timeline: OpportunityTimeline::new(H256::zero()),
         }
     }
 }
+
+/// Why a pending tx can no longer land as it was seen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelReason {
+    /// A fee-bumped tx with the same sender and nonce replaced it
+    Replaced(H256),
+    /// Mined in the given block
+    Mined(U64),
+    /// Its nonce was consumed by another tx mined in the given block
+    NonceConsumed(U64),
+}
+
+/// A previously emitted [NewTx] that strategies should stop working on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CancelledTx {
+    pub hash: H256,
+    pub reason: CancelReason,
+}
+
+/// Events emitted by the mempool collector
+#[derive(Debug, Clone)]
+pub enum MempoolEvent {
+    NewTx(NewTx),
+    Cancel(CancelledTx),
+    /// A pending tx deploys a pool or token, see [PairDiscovery](crate::pair_discovery)
+    Deployment(Deployment),
+}
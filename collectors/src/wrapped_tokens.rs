@@ -0,0 +1,450 @@
+//! Balances of vault shares and wrapped tokens
+//!
+//! For most tokens the `balanceOf` slot holds the balance and the balance is what the token is
+//! worth. Share tokens break both assumptions:
+//!
+//! - ERC-4626 vaults and wstETH store plain balances, but a share is worth `convertToAssets` of
+//!   the vault's asset, or `stEthPerToken` stETH
+//! - stETH stores shares, `balanceOf` is `getPooledEthByShares` of them
+//! - Aave aTokens store the scaled balance (in the low 128 bits of `_userState` since v3),
+//!   `balanceOf` multiplies it by the reserve's normalized income
+//!
+//! [detect_model] tells them apart from the functions the token answers, [cached_model] only
+//! asks once per token. [stored_balance] is the word [slot_finder](crate::slot_finder::slot_finder)
+//! looks for and a [TokenValuation] converts storage words and balance deltas into the
+//! underlying, so profits in share tokens add up, see [value_deltas].
+
+use dashmap::DashMap;
+use ethers::{
+    abi::{self, ParamType, Token},
+    providers::Middleware,
+    types::{
+        transaction::eip2718::TypedTransaction, Address, BlockId, Bytes, TransactionRequest, H160,
+        I256, U256, U512,
+    },
+    utils::id,
+};
+use fork_database::inspectors::Asset;
+use hashbrown::HashMap;
+use revm::primitives::B160;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use thiserror::Error;
+
+/// Share amount the conversion rates are read for, large enough to keep the precision of 6
+/// decimal vaults
+const RATE_SCALE: u128 = 1_000_000_000_000_000_000;
+/// Aave's normalized income is a ray
+const RAY: u128 = 1_000_000_000_000_000_000_000_000_000;
+
+#[derive(Error, Debug)]
+pub enum WrappedTokenError {
+    #[error("Failed to read the conversion rate of {0:?}")]
+    RateUnavailable(Address),
+}
+
+/// How the words in a token's balance slots relate to what the holder owns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BalanceModel {
+    /// the slot holds the balance, worth itself
+    Plain,
+    /// ERC-4626 vault shares, worth `convertToAssets` of `asset`
+    Erc4626Shares { asset: Address },
+    /// stETH, the slot holds shares of the pooled ETH
+    LidoShares,
+    /// wstETH, plain balances worth `stEthPerToken` of `steth`
+    WstEth { steth: Address },
+    /// Aave aToken, the slot holds the balance scaled down by the reserve's normalized income
+    AaveScaled { pool: Address, underlying: Address },
+}
+
+impl BalanceModel {
+    /// The part of a balance slot's word that holds the stored balance
+    pub fn stored_word(&self, word: U256) -> U256 {
+        match self {
+            // the upper half holds the user's last index
+            Self::AaveScaled { .. } => word & U256::from(u128::MAX),
+            _ => word,
+        }
+    }
+}
+
+/// `numerator / denominator`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rate {
+    pub numerator: U256,
+    pub denominator: U256,
+}
+
+impl Rate {
+    pub const ONE: Rate = Rate {
+        numerator: U256([1, 0, 0, 0]),
+        denominator: U256([1, 0, 0, 0]),
+    };
+
+    pub fn new(numerator: U256, denominator: U256) -> Self {
+        Self {
+            numerator,
+            denominator,
+        }
+    }
+
+    /// `amount` converted, rounded down and saturating at `U256::MAX`
+    pub fn apply(&self, amount: U256) -> U256 {
+        if self.denominator.is_zero() {
+            return U256::zero();
+        }
+        let converted = amount.full_mul(self.numerator) / U512::from(self.denominator);
+        U256::try_from(converted).unwrap_or(U256::MAX)
+    }
+
+    /// `delta` converted, rounded towards zero
+    pub fn apply_signed(&self, delta: I256) -> I256 {
+        let magnitude = I256::try_from(self.apply(delta.unsigned_abs())).unwrap_or(I256::MAX);
+        if delta.is_negative() {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+}
+
+/// Converts amounts of `token` into `underlying`, with rates read at one block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenValuation {
+    pub token: Address,
+    pub model: BalanceModel,
+    /// what the token's value is counted in, `token` itself for plain tokens
+    pub underlying: Address,
+    /// stored word to `balanceOf` units
+    pub stored: Rate,
+    /// `balanceOf` units to `underlying`
+    pub value: Rate,
+}
+
+impl TokenValuation {
+    pub fn plain(token: Address) -> Self {
+        Self {
+            token,
+            model: BalanceModel::Plain,
+            underlying: token,
+            stored: Rate::ONE,
+            value: Rate::ONE,
+        }
+    }
+
+    /// `balanceOf` of a holder whose balance slot holds `word`
+    pub fn balance_of_stored(&self, word: U256) -> U256 {
+        self.stored.apply(self.model.stored_word(word))
+    }
+
+    /// Worth of a holder whose balance slot holds `word`, in `underlying`
+    pub fn value_of_stored(&self, word: U256) -> U256 {
+        self.value.apply(self.balance_of_stored(word))
+    }
+
+    /// Worth of a `balanceOf` amount, e.g. a `Transfer` log's, in `underlying`
+    pub fn value_of(&self, amount: U256) -> U256 {
+        self.value.apply(amount)
+    }
+
+    /// Worth of a balance delta, e.g. from the
+    /// [BalanceDeltaInspector](fork_database::inspectors::BalanceDeltaInspector), in `underlying`
+    pub fn value_of_delta(&self, delta: I256) -> I256 {
+        self.value.apply_signed(delta)
+    }
+}
+
+/// Balance deltas in share tokens moved to, and summed up with, their underlying token. Deltas of
+/// tokens without a valuation are kept as they are.
+pub fn value_deltas(
+    deltas: &HashMap<Asset, I256>,
+    valuations: &HashMap<Address, TokenValuation>,
+) -> HashMap<Asset, I256> {
+    let mut valued: HashMap<Asset, I256> = HashMap::new();
+    for (asset, delta) in deltas {
+        let (asset, delta) = match asset {
+            Asset::Token(token) => match valuations.get(&H160(token.0)) {
+                Some(valuation) => (
+                    Asset::Token(B160(valuation.underlying.0)),
+                    valuation.value_of_delta(*delta),
+                ),
+                None => (*asset, *delta),
+            },
+            Asset::Eth => (*asset, *delta),
+        };
+        let entry = valued.entry(asset).or_default();
+        *entry = entry.saturating_add(delta);
+    }
+    valued
+}
+
+/// eth_call `signature` on `token` with `args`, `None` if it reverts or returns less than a word
+pub(crate) async fn call<M: Middleware>(
+    provider: &M,
+    token: Address,
+    signature: &str,
+    args: &[Token],
+    block: Option<BlockId>,
+) -> Option<Bytes> {
+    let mut data = id(signature).to_vec();
+    data.extend(abi::encode(args));
+    let tx: TypedTransaction = TransactionRequest::new().to(token).data(data).into();
+    provider
+        .call(&tx, block)
+        .await
+        .ok()
+        .filter(|out| out.len() >= 32)
+}
+
+pub(crate) async fn call_uint<M: Middleware>(
+    provider: &M,
+    token: Address,
+    signature: &str,
+    args: &[Token],
+    block: Option<BlockId>,
+) -> Option<U256> {
+    let out = call(provider, token, signature, args, block).await?;
+    Some(U256::from_big_endian(&out[..32]))
+}
+
+async fn call_address<M: Middleware>(
+    provider: &M,
+    token: Address,
+    signature: &str,
+    block: Option<BlockId>,
+) -> Option<Address> {
+    let out = call(provider, token, signature, &[], block).await?;
+    match abi::decode(&[ParamType::Address], &out[..32]).ok()?.pop()? {
+        Token::Address(address) if !address.is_zero() => Some(address),
+        _ => None,
+    }
+}
+
+/// Tell share tokens from plain ones by the functions they answer
+pub async fn detect_model<M: Middleware>(
+    provider: &M,
+    token: Address,
+    block: Option<BlockId>,
+) -> BalanceModel {
+    let probe = [Token::Uint(U256::from(RATE_SCALE))];
+    let holder = [Token::Address(Address::zero())];
+
+    // wstETH answers `getPooledEthByShares` too, through stETH, so it goes first
+    if let Some(steth) = call_address(provider, token, "stETH()", block).await {
+        if call_uint(provider, token, "stEthPerToken()", &[], block)
+            .await
+            .is_some()
+        {
+            return BalanceModel::WstEth { steth };
+        }
+    }
+    if call_uint(
+        provider,
+        token,
+        "getPooledEthByShares(uint256)",
+        &probe,
+        block,
+    )
+    .await
+    .is_some()
+        && call_uint(provider, token, "sharesOf(address)", &holder, block)
+            .await
+            .is_some()
+    {
+        return BalanceModel::LidoShares;
+    }
+    if let (Some(pool), Some(underlying)) = (
+        call_address(provider, token, "POOL()", block).await,
+        call_address(provider, token, "UNDERLYING_ASSET_ADDRESS()", block).await,
+    ) {
+        if call_uint(provider, token, "scaledBalanceOf(address)", &holder, block)
+            .await
+            .is_some()
+        {
+            return BalanceModel::AaveScaled { pool, underlying };
+        }
+    }
+    if let Some(asset) = call_address(provider, token, "asset()", block).await {
+        if call_uint(provider, token, "convertToAssets(uint256)", &probe, block)
+            .await
+            .is_some()
+        {
+            return BalanceModel::Erc4626Shares { asset };
+        }
+    }
+    BalanceModel::Plain
+}
+
+/// Models of the tokens detected so far, shared by every [cached_model] call
+pub fn model_cache() -> &'static DashMap<Address, BalanceModel> {
+    static MODELS: OnceLock<DashMap<Address, BalanceModel>> = OnceLock::new();
+    MODELS.get_or_init(DashMap::new)
+}
+
+/// [detect_model] of `token`, detected on its first lookup only. A token's model comes with its
+/// code, it doesn't change from a block to the next.
+pub async fn cached_model<M: Middleware>(provider: &M, token: Address) -> BalanceModel {
+    if let Some(model) = model_cache().get(&token) {
+        return *model;
+    }
+    let model = detect_model(provider, token, None).await;
+    model_cache().insert(token, model);
+    model
+}
+
+/// The word `holder`'s balance slot holds, i.e. what the slot finder compares storage against
+pub async fn stored_balance<M: Middleware>(
+    provider: &M,
+    token: Address,
+    model: &BalanceModel,
+    holder: Address,
+    block: Option<BlockId>,
+) -> Option<U256> {
+    let holder = [Token::Address(holder)];
+    let signature = match model {
+        BalanceModel::LidoShares => "sharesOf(address)",
+        BalanceModel::AaveScaled { .. } => "scaledBalanceOf(address)",
+        _ => "balanceOf(address)",
+    };
+    call_uint(provider, token, signature, &holder, block).await
+}
+
+/// Valuation of `token` at `block`, reading the conversion rates its model needs
+pub async fn valuation<M: Middleware>(
+    provider: &M,
+    token: Address,
+    model: BalanceModel,
+    block: Option<BlockId>,
+) -> Result<TokenValuation, WrappedTokenError> {
+    let scale = U256::from(RATE_SCALE);
+    let probe = [Token::Uint(scale)];
+    let unavailable = || WrappedTokenError::RateUnavailable(token);
+
+    let (underlying, stored, value) = match model {
+        BalanceModel::Plain => (token, Rate::ONE, Rate::ONE),
+        BalanceModel::Erc4626Shares { asset } => {
+            let assets = call_uint(provider, token, "convertToAssets(uint256)", &probe, block)
+                .await
+                .ok_or_else(unavailable)?;
+            (asset, Rate::ONE, Rate::new(assets, scale))
+        }
+        BalanceModel::LidoShares => {
+            let pooled = call_uint(
+                provider,
+                token,
+                "getPooledEthByShares(uint256)",
+                &probe,
+                block,
+            )
+            .await
+            .ok_or_else(unavailable)?;
+            (token, Rate::new(pooled, scale), Rate::ONE)
+        }
+        BalanceModel::WstEth { steth } => {
+            let per_token = call_uint(provider, token, "stEthPerToken()", &[], block)
+                .await
+                .ok_or_else(unavailable)?;
+            (steth, Rate::ONE, Rate::new(per_token, scale))
+        }
+        BalanceModel::AaveScaled { pool, underlying } => {
+            let income = call_uint(
+                provider,
+                pool,
+                "getReserveNormalizedIncome(address)",
+                &[Token::Address(underlying)],
+                block,
+            )
+            .await
+            .ok_or_else(unavailable)?;
+            (underlying, Rate::new(income, U256::from(RAY)), Rate::ONE)
+        }
+    };
+    Ok(TokenValuation {
+        token,
+        model,
+        underlying,
+        stored,
+        value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_token_values() {
+        let vault = Address::from_low_u64_be(1);
+        let usdc = Address::from_low_u64_be(2);
+        let ausdc = Address::from_low_u64_be(3);
+
+        // 1 share is worth 1.05 assets
+        let shares = TokenValuation {
+            token: vault,
+            model: BalanceModel::Erc4626Shares { asset: usdc },
+            underlying: usdc,
+            stored: Rate::ONE,
+            value: Rate::new(U256::from(105), U256::from(100)),
+        };
+        assert_eq!(shares.value_of(U256::from(1_000)), U256::from(1_050));
+        assert_eq!(
+            shares.value_of_delta(I256::from(-1_000)),
+            I256::from(-1_050)
+        );
+
+        // the scaled balance in the low half, the user's index above it
+        let index = U256::from(RAY) * 11 / 10;
+        let atoken = TokenValuation {
+            token: ausdc,
+            model: BalanceModel::AaveScaled {
+                pool: Address::from_low_u64_be(4),
+                underlying: usdc,
+            },
+            underlying: usdc,
+            stored: Rate::new(index, U256::from(RAY)),
+            value: Rate::ONE,
+        };
+        let word = (U256::from(7u64) << 128) | U256::from(2_000);
+        assert_eq!(atoken.balance_of_stored(word), U256::from(2_200));
+        assert_eq!(atoken.value_of_stored(word), U256::from(2_200));
+
+        // profits in shares and aTokens are counted in USDC
+        let deltas = HashMap::from([
+            (Asset::Token(B160(vault.0)), I256::from(1_000)),
+            (Asset::Token(B160(ausdc.0)), I256::from(-500)),
+            (Asset::Token(B160(usdc.0)), I256::from(10)),
+            (Asset::Eth, I256::from(-1)),
+        ]);
+        let valuations = HashMap::from([(vault, shares), (ausdc, atoken)]);
+        let valued = value_deltas(&deltas, &valuations);
+        assert_eq!(valued.len(), 2);
+        assert_eq!(valued[&Asset::Token(B160(usdc.0))], I256::from(560));
+        assert_eq!(valued[&Asset::Eth], I256::from(-1));
+        assert_eq!(
+            Rate::new(U256::one(), U256::zero()).apply(U256::one()),
+            U256::zero()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cached_model() {
+        let (provider, _mock) = ethers::providers::Provider::mocked();
+        let (wsteth, plain) = (
+            Address::from_low_u64_be(0x51),
+            Address::from_low_u64_be(0x52),
+        );
+        let model = BalanceModel::WstEth {
+            steth: Address::from_low_u64_be(0x53),
+        };
+        model_cache().insert(wsteth, model);
+        // no response is queued, a probe would have found a plain token
+        assert_eq!(cached_model(&provider, wsteth).await, model);
+
+        assert_eq!(cached_model(&provider, plain).await, BalanceModel::Plain);
+        assert_eq!(
+            model_cache().get(&plain).map(|m| *m),
+            Some(BalanceModel::Plain)
+        );
+    }
+}
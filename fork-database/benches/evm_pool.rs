@@ -0,0 +1,54 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use fork_database::{evm_pool::EvmPool, sim_env::simulate};
+use revm::{
+    db::{CacheDB, EmptyDB},
+    primitives::{AccountInfo, Env, TransactTo, TxEnv, B160, U256},
+};
+
+const SENDER: u64 = 1;
+
+fn funded_db() -> CacheDB<EmptyDB> {
+    let mut db = CacheDB::new(EmptyDB::default());
+    db.insert_account_info(
+        B160::from_low_u64_be(SENDER),
+        AccountInfo {
+            balance: U256::from(10u64.pow(18)),
+            ..Default::default()
+        },
+    );
+    db
+}
+
+fn transfer() -> TxEnv {
+    TxEnv {
+        caller: B160::from_low_u64_be(SENDER),
+        transact_to: TransactTo::Call(B160::from_low_u64_be(2)),
+        value: U256::from(1u64),
+        gas_limit: 21_000,
+        ..Default::default()
+    }
+}
+
+/// A fresh EVM and env per candidate, against one checked out of the pool
+fn bench_evm_pool(c: &mut Criterion) {
+    let db = funded_db();
+    let env = Env::default();
+
+    let mut group = c.benchmark_group("simulate_transfer");
+    group.bench_function("fresh_evm", |b| {
+        b.iter(|| {
+            let mut env = env.clone();
+            env.tx = transfer();
+            criterion::black_box(simulate(env, db.clone()).unwrap())
+        })
+    });
+
+    let pool = EvmPool::new(env.clone());
+    group.bench_function("pooled_evm", |b| {
+        b.iter(|| criterion::black_box(pool.simulate(db.clone(), transfer()).unwrap()))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_evm_pool);
+criterion_main!(benches);
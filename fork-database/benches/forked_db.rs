@@ -0,0 +1,131 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use ethers::providers::{MockProvider, Provider};
+use fork_database::{
+    backend_supervisor::{BackendRuntime, SupervisorConfig},
+    blockchain_db::{BlockchainDb, BlockchainDbMeta},
+    forked_db::ForkedDatabase,
+    shared_backend::SharedBackend,
+};
+use revm::{
+    db::DatabaseRef,
+    primitives::{AccountInfo, B160, U256},
+    Database,
+};
+use std::collections::BTreeSet;
+
+/// A 32 byte word, parses as a balance, nonce, code and storage value alike
+const WORD: &str = "0x0000000000000000000000000000000000000000000000000000000000000001";
+
+/// [ForkedDatabase] whose remote is a mocked provider, so cold reads don't need a node
+fn mocked_forked_db() -> (ForkedDatabase, MockProvider) {
+    let (provider, mock) = Provider::mocked();
+    let meta = BlockchainDbMeta {
+        cfg_env: Default::default(),
+        block_env: Default::default(),
+        hosts: BTreeSet::new(),
+    };
+    let db = BlockchainDb::new(meta, None);
+    let config = SupervisorConfig::default().with_runtime(BackendRuntime::Dedicated);
+    let (backend, _handle) = SharedBackend::spawn_supervised(provider, db.clone(), None, config);
+    (ForkedDatabase::new(backend, db), mock)
+}
+
+fn push_responses(mock: &MockProvider, count: usize) {
+    for _ in 0..count {
+        mock.push::<String, _>(WORD.to_string()).unwrap();
+    }
+}
+
+fn bench_basic(c: &mut Criterion) {
+    let (mut forked_db, mock) = mocked_forked_db();
+    let mut group = c.benchmark_group("forked_db_basic");
+
+    // missing everywhere, fetched from the remote (balance, nonce and code)
+    let mut next = 0u64;
+    group.bench_function("cold", |b| {
+        b.iter_batched(
+            || {
+                next += 1;
+                push_responses(&mock, 3);
+                B160::from_low_u64_be(next)
+            },
+            |address| forked_db.basic(address).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    // cached in the shared backend, but not in the local layer
+    let address = B160::from_low_u64_be(u64::MAX);
+    forked_db
+        .inner()
+        .db()
+        .do_insert_account(address, AccountInfo::default());
+    let backend = forked_db.backend().clone();
+    group.bench_function("backend", |b| b.iter(|| backend.basic(address).unwrap()));
+
+    // cached in the local layer
+    forked_db.basic(address).unwrap();
+    group.bench_function("warm", |b| b.iter(|| forked_db.basic(address).unwrap()));
+    group.finish();
+}
+
+fn bench_storage(c: &mut Criterion) {
+    let (mut forked_db, mock) = mocked_forked_db();
+    let address = B160::from_low_u64_be(1);
+    forked_db
+        .inner()
+        .db()
+        .do_insert_account(address, AccountInfo::default());
+    forked_db.basic(address).unwrap();
+
+    let mut group = c.benchmark_group("forked_db_storage");
+
+    let mut next = 0u64;
+    group.bench_function("cold", |b| {
+        b.iter_batched(
+            || {
+                next += 1;
+                push_responses(&mock, 1);
+                U256::from(next)
+            },
+            |index| forked_db.storage(address, index).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+
+    let index = U256::from(1u64);
+    group.bench_function("warm", |b| {
+        b.iter(|| forked_db.storage(address, index).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_snapshots(c: &mut Criterion) {
+    let mut group = c.benchmark_group("forked_db_snapshot");
+    for accounts in [100u64, 1_000, 10_000] {
+        let (mut forked_db, _mock) = mocked_forked_db();
+        let db = forked_db.inner().db().clone();
+        for i in 0..accounts {
+            let address = B160::from_low_u64_be(i);
+            db.do_insert_account(address, AccountInfo::default());
+            for slot in 0..8u64 {
+                db.insert_storage(address, U256::from(slot), U256::from(i));
+            }
+        }
+
+        group.bench_with_input(BenchmarkId::new("create", accounts), &accounts, |b, _| {
+            b.iter(|| forked_db.create_snapshot())
+        });
+        group.bench_with_input(BenchmarkId::new("revert", accounts), &accounts, |b, _| {
+            b.iter_batched(
+                || forked_db.insert_snapshot(),
+                |id| assert!(forked_db.revert_snapshot(id)),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_basic, bench_storage, bench_snapshots);
+criterion_main!(benches);
@@ -0,0 +1,79 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use fork_database::blockchain_db::MemDb;
+use revm::primitives::{AccountInfo, B160, U256};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+const ACCOUNTS: u64 = 1_000;
+const SLOTS: u64 = 64;
+const READS_PER_THREAD: u64 = 10_000;
+
+fn populated_db() -> Arc<MemDb> {
+    let db = MemDb::default();
+    for i in 0..ACCOUNTS {
+        let address = B160::from_low_u64_be(i);
+        db.do_insert_account(address, AccountInfo::default());
+        for slot in 0..SLOTS {
+            db.insert_storage(address, U256::from(slot), U256::from(i * slot));
+        }
+    }
+    Arc::new(db)
+}
+
+/// Hot read path of the simulation threads, with the backend concurrently writing new slots
+fn bench_concurrent_reads(c: &mut Criterion) {
+    let db = populated_db();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let db = db.clone();
+        let stop = stop.clone();
+        thread::spawn(move || {
+            let mut i = 0u64;
+            while !stop.load(Ordering::Relaxed) {
+                let address = B160::from_low_u64_be(i % ACCOUNTS);
+                db.insert_storage(address, U256::from(SLOTS + i), U256::from(i));
+                i += 1;
+            }
+        })
+    };
+
+    let mut group = c.benchmark_group("mem_db_storage_read");
+    for threads in [1u64, 4, 8, 16] {
+        group.throughput(Throughput::Elements(threads * READS_PER_THREAD));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    thread::scope(|s| {
+                        for t in 0..threads {
+                            let db = &db;
+                            s.spawn(move || {
+                                for i in 0..READS_PER_THREAD {
+                                    let address = B160::from_low_u64_be((i * 7 + t) % ACCOUNTS);
+                                    criterion::black_box(
+                                        db.storage_slot(&address, &U256::from(i % SLOTS)),
+                                    );
+                                    criterion::black_box(db.account(&address));
+                                }
+                            });
+                        }
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+
+    stop.store(true, Ordering::Relaxed);
+    writer.join().unwrap();
+}
+
+criterion_group!(benches, bench_concurrent_reads);
+criterion_main!(benches);
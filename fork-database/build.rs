@@ -0,0 +1,6 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // the gRPC service is optional, protoc is only needed with the `grpc` feature
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/sim.proto")?;
+    Ok(())
+}
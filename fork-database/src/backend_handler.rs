@@ -17,7 +17,7 @@ use futures::{
     task::{Context, Poll},
     Future, FutureExt,
 };
-use revm::primitives::{bytes, AccountInfo, Bytecode, KECCAK_EMPTY, U256 as rU256};
+use revm::primitives::{bytes, AccountInfo, Bytecode, B256, KECCAK_EMPTY, U256 as rU256};
 use std::{
     collections::{hash_map::Entry, HashMap, VecDeque},
     pin::Pin,
@@ -28,6 +28,7 @@ use tracing::{error, trace, warn};
 type AccountFuture<Err> =
     Pin<Box<dyn Future<Output = (Result<(U256, U256, Bytes), Err>, Address)> + Send>>;
 type StorageFuture<Err> = Pin<Box<dyn Future<Output = (Result<U256, Err>, Address, U256)> + Send>>;
+type CodeFuture<Err> = Pin<Box<dyn Future<Output = (Result<Bytes, Err>, B256, Address)> + Send>>;
 type BlockHashFuture<Err> = Pin<Box<dyn Future<Output = (Result<H256, Err>, u64)> + Send>>;
 type FullBlockFuture<Err> = Pin<
     Box<
@@ -46,14 +47,31 @@ type TransactionFuture<Err> = Pin<
 
 type AccountInfoSender = OneshotSender<DatabaseResult<AccountInfo>>;
 type StorageSender = OneshotSender<DatabaseResult<U256>>;
+type CodeSender = OneshotSender<DatabaseResult<Bytecode>>;
 type BlockHashSender = OneshotSender<DatabaseResult<H256>>;
 type FullBlockSender = OneshotSender<DatabaseResult<Block<Transaction>>>;
 type TransactionSender = OneshotSender<DatabaseResult<Transaction>>;
 
+/// While strategy reads keep coming, every this many of them a prefetch request goes out anyway
+pub const DEFAULT_PREFETCH_EVERY: usize = 8;
+
+/// Lane a request waits in when the provider is busy, i.e. when the handler caps the requests in
+/// flight, see [BackendHandler::with_max_in_flight]. Without a cap every request is sent right
+/// away, critical ones first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Priority {
+    /// reads a strategy is blocked on, e.g. during a simulation
+    #[default]
+    Critical,
+    /// background warm-up and prefetch traffic, sent when no critical request is waiting
+    Prefetch,
+}
+
 /// Request variants that are executed by the provider
 enum ProviderRequest<Err> {
     Account(AccountFuture<Err>),
     Storage(StorageFuture<Err>),
+    Code(CodeFuture<Err>),
     BlockHash(BlockHashFuture<Err>),
     FullBlock(FullBlockFuture<Err>),
     Transaction(TransactionFuture<Err>),
@@ -66,6 +84,8 @@ pub enum BackendRequest {
     Basic(Address, AccountInfoSender),
     /// Fetch a storage slot
     Storage(Address, U256, StorageSender),
+    /// Fetch code by its hash, from an account known to hold it
+    Code(B256, CodeSender),
     /// Fetch a block hash
     BlockHash(u64, BlockHashSender),
     /// Fetch an entire block with transactions
@@ -91,12 +111,23 @@ pub struct BackendHandler<M: Middleware> {
     account_requests: HashMap<Address, Vec<AccountInfoSender>>,
     /// Listeners that wait for a `get_storage_at` response
     storage_requests: HashMap<(Address, U256), Vec<StorageSender>>,
+    /// Listeners that wait for a `get_code` response
+    code_requests: HashMap<B256, Vec<CodeSender>>,
     /// Listeners that wait for a `get_block` response
     block_requests: HashMap<u64, Vec<BlockHashSender>>,
     /// Incoming commands.
     incoming: Receiver<BackendRequest>,
+    /// Incoming commands of the prefetch lane
+    incoming_prefetch: Receiver<BackendRequest>,
     /// unprocessed queued requests
     queued_requests: VecDeque<BackendRequest>,
+    /// unprocessed prefetch requests, dispatched after `queued_requests`
+    queued_prefetch: VecDeque<BackendRequest>,
+    /// critical requests dispatched since the last prefetch request
+    critical_streak: usize,
+    /// provider requests in flight at once, further requests wait in their lane, no cap if `None`
+    max_in_flight: Option<usize>,
+    prefetch_every: usize,
     /// The block to fetch data from.
     // This is an `Option` so that we can have less code churn in the functions below
     block_id: Option<BlockId>,
@@ -110,6 +141,7 @@ where
         provider: M,
         db: BlockchainDb,
         rx: Receiver<BackendRequest>,
+        prefetch_rx: Receiver<BackendRequest>,
         block_id: Option<BlockId>,
     ) -> Self {
         Self {
@@ -118,13 +150,66 @@ where
             pending_requests: Default::default(),
             account_requests: Default::default(),
             storage_requests: Default::default(),
+            code_requests: Default::default(),
             block_requests: Default::default(),
             queued_requests: Default::default(),
+            queued_prefetch: Default::default(),
+            critical_streak: 0,
+            max_in_flight: None,
+            prefetch_every: DEFAULT_PREFETCH_EVERY,
             incoming: rx,
+            incoming_prefetch: prefetch_rx,
             block_id,
         }
     }
 
+    pub fn with_max_in_flight(mut self, max_in_flight: Option<usize>) -> Self {
+        self.max_in_flight = max_in_flight.map(|max| max.max(1));
+        self
+    }
+
+    fn has_capacity(&self) -> bool {
+        self.max_in_flight
+            .map_or(true, |max| self.pending_requests.len() < max)
+    }
+
+    /// Starvation protection of the prefetch lane, see [DEFAULT_PREFETCH_EVERY]
+    pub fn with_prefetch_every(mut self, prefetch_every: usize) -> Self {
+        self.prefetch_every = prefetch_every.max(1);
+        self
+    }
+
+    /// Next queued request to dispatch, critical ones first unless prefetch requests have been
+    /// waiting for `prefetch_every` critical ones
+    fn next_request(&mut self) -> Option<BackendRequest> {
+        let prefetch_due =
+            self.critical_streak >= self.prefetch_every && !self.queued_prefetch.is_empty();
+        if !prefetch_due {
+            if let Some(req) = self.queued_requests.pop_front() {
+                self.critical_streak += 1;
+                return Some(req);
+            }
+        }
+        let req = self.queued_prefetch.pop_front()?;
+        self.critical_streak = 0;
+        Some(req)
+    }
+
+    fn has_queued(&self) -> bool {
+        !self.queued_requests.is_empty() || !self.queued_prefetch.is_empty()
+    }
+
+    /// Dispatch queued requests while the provider has capacity, cached values are answered
+    /// without using any
+    fn dispatch_queued(&mut self) {
+        while self.has_capacity() {
+            let Some(req) = self.next_request() else {
+                return;
+            };
+            self.on_request(req);
+        }
+    }
+
     /// handle the request in queue in the future.
     ///
     /// We always check:
@@ -135,20 +220,19 @@ where
         match req {
             BackendRequest::Basic(addr, sender) => {
                 trace!(target: "backendhandler", "received request basic address={:?}", addr);
-                let acc = self.db.accounts().read().get(&h160_to_b160(addr)).cloned();
+                let acc = self.db.db().account(&h160_to_b160(addr));
                 if let Some(basic) = acc {
                     let _ = sender.send(Ok(basic));
+                } else if self.db.is_known_absent(&h160_to_b160(addr)) {
+                    // don't resurrect destroyed accounts from the remote
+                    trace!(target: "backendhandler", "account known absent address={:?}", addr);
+                    let _ = sender.send(Ok(AccountInfo::default()));
                 } else {
                     self.request_account(addr, sender);
                 }
             }
             BackendRequest::BlockHash(number, sender) => {
-                let hash = self
-                    .db
-                    .block_hashes()
-                    .read()
-                    .get(&rU256::from(number))
-                    .cloned();
+                let hash = self.db.db().block_hash(&rU256::from(number));
                 if let Some(hash) = hash {
                     let _ = sender.send(Ok(hash.into()));
                 } else {
@@ -165,17 +249,26 @@ where
                 // account is already stored in the cache
                 let value = self
                     .db
-                    .storage()
-                    .read()
-                    .get(&h160_to_b160(addr))
-                    .and_then(|acc| acc.get(&u256_to_ru256(idx)).copied());
+                    .db()
+                    .storage_slot(&h160_to_b160(addr), &u256_to_ru256(idx));
                 if let Some(value) = value {
                     let _ = sender.send(Ok(ru256_to_u256(value)));
+                } else if self.db.is_known_absent(&h160_to_b160(addr)) {
+                    let _ = sender.send(Ok(U256::zero()));
                 } else {
                     // account present but not storage -> fetch storage
                     self.request_account_storage(addr, idx, sender);
                 }
             }
+            BackendRequest::Code(code_hash, sender) => {
+                if let Some(code) = self.db.db().code_by_hash(&code_hash) {
+                    let _ = sender.send(Ok(code));
+                } else if let Some(owner) = self.db.db().code_owner(&code_hash) {
+                    self.request_code(code_hash, owner.into(), sender);
+                } else {
+                    let _ = sender.send(Err(DatabaseError::MissingCode(b256_to_h256(code_hash))));
+                }
+            }
             BackendRequest::SetPinnedBlock(block_id) => {
                 self.block_id = Some(block_id);
             }
@@ -205,6 +298,26 @@ where
         }
     }
 
+    /// process a request for code, fetched from `owner`
+    fn request_code(&mut self, code_hash: B256, owner: Address, listener: CodeSender) {
+        match self.code_requests.entry(code_hash) {
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().push(listener);
+            }
+            Entry::Vacant(entry) => {
+                trace!(target: "backendhandler", "preparing code request, hash={:?}, owner={:?}", code_hash, owner);
+                entry.insert(vec![listener]);
+                let provider = self.provider.clone();
+                let block_id = self.block_id;
+                let fut = Box::pin(async move {
+                    let code = provider.get_code(owner, block_id).await;
+                    (code, code_hash, owner)
+                });
+                self.pending_requests.push(ProviderRequest::Code(fut));
+            }
+        }
+    }
+
     /// returns the future that fetches the account data
     fn get_account_req(&self, address: Address) -> ProviderRequest<M::Error> {
         trace!(target: "backendhandler", "preparing account request, address={:?}", address);
@@ -301,10 +414,8 @@ where
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let pin = self.get_mut();
         loop {
-            // Drain queued requests first.
-            while let Some(req) = pin.queued_requests.pop_front() {
-                pin.on_request(req)
-            }
+            // Drain queued requests first, as far as the provider has capacity.
+            pin.dispatch_queued();
 
             // receive new requests to delegate to the underlying provider
             loop {
@@ -319,6 +430,11 @@ where
                     Poll::Pending => break,
                 }
             }
+            // the prefetch lane closes along with the critical one, once the last
+            // `SharedBackend` is dropped
+            while let Poll::Ready(Some(req)) = Pin::new(&mut pin.incoming_prefetch).poll_next(cx) {
+                pin.queued_prefetch.push_back(req);
+            }
 
             // poll all requests in progress
             for n in (0..pin.pending_requests.len()).rev() {
@@ -357,7 +473,7 @@ where
                                 code: code.map(|bytes| Bytecode::new_raw(bytes).to_checked()),
                                 code_hash,
                             };
-                            pin.db.accounts().write().insert(addr.into(), acc.clone());
+                            pin.db.db().do_insert_account(addr.into(), acc.clone());
 
                             // notify all listeners
                             if let Some(listeners) = pin.account_requests.remove(&addr) {
@@ -392,11 +508,8 @@ where
 
                             // update the cache
                             pin.db
-                                .storage()
-                                .write()
-                                .entry(addr.into())
-                                .or_default()
-                                .insert(idx.into(), value.into());
+                                .db()
+                                .insert_storage(addr.into(), idx.into(), value.into());
 
                             // notify all listeners
                             if let Some(listeners) = pin.storage_requests.remove(&(addr, idx)) {
@@ -407,6 +520,49 @@ where
                             continue;
                         }
                     }
+                    ProviderRequest::Code(fut) => {
+                        if let Poll::Ready((resp, code_hash, owner)) = fut.poll_unpin(cx) {
+                            let code = match resp {
+                                Ok(code) => code,
+                                Err(err) => {
+                                    let err = Arc::new(eyre::Error::new(err));
+                                    if let Some(listeners) = pin.code_requests.remove(&code_hash) {
+                                        listeners.into_iter().for_each(|l| {
+                                            let _ = l.send(Err(DatabaseError::GetCode(
+                                                b256_to_h256(code_hash),
+                                                owner,
+                                                Arc::clone(&err),
+                                            )));
+                                        })
+                                    }
+                                    continue;
+                                }
+                            };
+                            let listeners =
+                                pin.code_requests.remove(&code_hash).unwrap_or_default();
+
+                            // the owner's code changed since it was indexed
+                            if B256::from(keccak256(&code)) != code_hash {
+                                listeners.into_iter().for_each(|l| {
+                                    let _ = l.send(Err(DatabaseError::CodeHashMismatch(
+                                        b256_to_h256(code_hash),
+                                        owner,
+                                    )));
+                                });
+                                continue;
+                            }
+
+                            // update the cache
+                            let code = Bytecode::new_raw(code.0).to_checked();
+                            pin.db.db().insert_code(code.clone());
+
+                            // notify all listeners
+                            listeners.into_iter().for_each(|l| {
+                                let _ = l.send(Ok(code.clone()));
+                            });
+                            continue;
+                        }
+                    }
                     ProviderRequest::BlockHash(fut) => {
                         if let Poll::Ready((block_hash, number)) = fut.poll_unpin(cx) {
                             let value = match block_hash {
@@ -429,7 +585,6 @@ where
                             // update the cache
                             pin.db
                                 .block_hashes()
-                                .write()
                                 .insert(rU256::from(number), value.into());
 
                             // notify all listeners
@@ -474,11 +629,53 @@ where
                 pin.pending_requests.push(request);
             }
 
-            // If no new requests have been queued, break to
-            // be polled again later.
-            if pin.queued_requests.is_empty() {
+            // If no queued requests can be dispatched, break to be polled again later, when new
+            // requests arrive or requests in progress complete.
+            if !pin.has_queued() || !pin.has_capacity() {
                 return Poll::Pending;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain_db::BlockchainDbMeta;
+    use ethers::providers::Provider;
+    use futures::channel::mpsc::channel;
+    use std::{collections::BTreeSet, sync::mpsc::channel as oneshot_channel};
+
+    #[test]
+    fn test_prefetch_lane_is_not_starved() {
+        let db = BlockchainDb::new(
+            BlockchainDbMeta {
+                cfg_env: Default::default(),
+                block_env: Default::default(),
+                hosts: BTreeSet::new(),
+            },
+            None,
+        );
+        let (_tx, rx) = channel(1);
+        let (_prefetch_tx, prefetch_rx) = channel(1);
+        let (provider, _mock) = Provider::mocked();
+        let mut handler =
+            BackendHandler::new(provider, db, rx, prefetch_rx, None).with_prefetch_every(2);
+
+        let request = |number: u64| BackendRequest::BlockHash(number, oneshot_channel().0);
+        for number in 0..5 {
+            handler.queued_requests.push_back(request(number));
+        }
+        handler.queued_prefetch.push_back(request(100));
+        handler.queued_prefetch.push_back(request(101));
+
+        let order: Vec<u64> = std::iter::from_fn(|| handler.next_request())
+            .map(|req| match req {
+                BackendRequest::BlockHash(number, _) => number,
+                _ => unreachable!(),
+            })
+            .collect();
+        // one prefetch request after every two critical ones
+        assert_eq!(order, vec![0, 1, 100, 2, 3, 101, 4]);
+    }
+}
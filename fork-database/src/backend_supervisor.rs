@@ -0,0 +1,356 @@
+//! Lifecycle of the backend's fetch loop
+//!
+//! [SharedBackend::spawn_backend](crate::shared_backend::SharedBackend::spawn_backend) spawns
+//! the [BackendHandler] and forgets about it, if it panics every read fails from then on without
+//! anything noticing. [SharedBackend::spawn_supervised] runs it under a supervisor instead: the
+//! supervisor owns the request channels and forwards them to the handler, a handler that panicked
+//! is replaced by a fresh one on the same db and pinned block, and the returned [BackendHandle]
+//! reports whether the loop is still up and shuts it down. Requests in flight when the handler
+//! panicked fail, later ones are served by its replacement.
+
+use std::{
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+use ethers::{providers::Middleware, types::BlockId};
+use futures::{
+    channel::{
+        mpsc::{channel, Receiver, Sender},
+        oneshot,
+    },
+    future, pin_mut, select, FutureExt, SinkExt, StreamExt,
+};
+use parking_lot::Mutex;
+use tracing::{error, trace, warn};
+
+use crate::{
+    backend_handler::{BackendHandler, BackendRequest},
+    blockchain_db::{BlockchainDb, FlushJsonBlockCacheDB},
+    shared_backend::SharedBackend,
+};
+
+/// Panics after which the fetch loop is given up on
+pub const DEFAULT_MAX_RESTARTS: u32 = 5;
+
+/// Where the fetch loop runs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackendRuntime {
+    /// a task on the runtime [SharedBackend::spawn_supervised] is called from
+    #[default]
+    Current,
+    /// a thread with its own runtime, so the loop isn't starved by a busy strategy runtime
+    Dedicated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SupervisorConfig {
+    pub runtime: BackendRuntime,
+    pub max_restarts: u32,
+    /// see [BackendHandler::with_max_in_flight], no cap by default
+    pub max_in_flight: Option<usize>,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            runtime: BackendRuntime::default(),
+            max_restarts: DEFAULT_MAX_RESTARTS,
+            max_in_flight: None,
+        }
+    }
+}
+
+impl SupervisorConfig {
+    pub fn with_runtime(mut self, runtime: BackendRuntime) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    pub fn with_max_restarts(mut self, max_restarts: u32) -> Self {
+        self.max_restarts = max_restarts;
+        self
+    }
+
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct SupervisorState {
+    running: AtomicBool,
+    restarts: AtomicU32,
+}
+
+/// Control over a supervised fetch loop. Dropping the handle leaves the loop running, it stops
+/// with the last [SharedBackend] as an unsupervised one does.
+#[derive(Debug)]
+pub struct BackendHandle {
+    state: Arc<SupervisorState>,
+    shutdown: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl BackendHandle {
+    /// Whether the loop is serving requests, `false` once shut down or given up on
+    pub fn is_healthy(&self) -> bool {
+        self.state.running.load(Ordering::Acquire)
+    }
+
+    /// Times the handler panicked and was replaced
+    pub fn restarts(&self) -> u32 {
+        self.state.restarts.load(Ordering::Acquire)
+    }
+
+    /// Stops the loop, requests in flight and later reads fail
+    pub fn shutdown(&self) {
+        if let Some(shutdown) = self.shutdown.lock().take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+impl SharedBackend {
+    /// [SharedBackend::spawn_backend] under a supervisor restarting the handler if it panics,
+    /// see [BackendHandle]. Must be called from within a tokio runtime unless the loop runs on a
+    /// [BackendRuntime::Dedicated] thread.
+    pub fn spawn_supervised<M>(
+        provider: M,
+        db: BlockchainDb,
+        pin_block: Option<BlockId>,
+        config: SupervisorConfig,
+    ) -> (Self, BackendHandle)
+    where
+        M: Middleware + Unpin + 'static + Clone,
+    {
+        let cache = Arc::new(FlushJsonBlockCacheDB(Arc::clone(db.cache())));
+        spawn(
+            move |incoming, incoming_prefetch, pin_block| {
+                BackendHandler::new(
+                    provider.clone(),
+                    db.clone(),
+                    incoming,
+                    incoming_prefetch,
+                    pin_block,
+                )
+                .with_max_in_flight(config.max_in_flight)
+            },
+            cache,
+            pin_block,
+            config,
+        )
+    }
+}
+
+/// Spawns [supervise] over the handlers `new_handler` builds
+fn spawn<F, H>(
+    new_handler: F,
+    cache: Arc<FlushJsonBlockCacheDB>,
+    pin_block: Option<BlockId>,
+    config: SupervisorConfig,
+) -> (SharedBackend, BackendHandle)
+where
+    F: FnMut(Receiver<BackendRequest>, Receiver<BackendRequest>, Option<BlockId>) -> H
+        + Send
+        + 'static,
+    H: Future<Output = ()> + Send + 'static,
+{
+    let (backend, incoming) = channel(1);
+    let (prefetch, incoming_prefetch) = channel(1);
+    let (shutdown, shutdown_rx) = oneshot::channel();
+    let state = Arc::new(SupervisorState {
+        running: AtomicBool::new(true),
+        restarts: AtomicU32::new(0),
+    });
+    let supervisor = supervise(
+        new_handler,
+        incoming,
+        incoming_prefetch,
+        pin_block,
+        config.max_restarts,
+        Arc::clone(&state),
+        shutdown_rx,
+    );
+    match config.runtime {
+        BackendRuntime::Current => {
+            tokio::spawn(supervisor);
+        }
+        BackendRuntime::Dedicated => {
+            std::thread::Builder::new()
+                .name("fork-backend-supervisor".to_string())
+                .spawn(move || {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .expect("failed to create fork-backend-supervisor tokio runtime")
+                        .block_on(supervisor)
+                })
+                .expect("failed to spawn backend supervisor thread");
+        }
+    }
+    (
+        SharedBackend::from_channels(backend, prefetch, cache),
+        BackendHandle {
+            state,
+            shutdown: Mutex::new(Some(shutdown)),
+        },
+    )
+}
+
+async fn supervise<F, H>(
+    mut new_handler: F,
+    mut incoming: Receiver<BackendRequest>,
+    mut incoming_prefetch: Receiver<BackendRequest>,
+    mut pin_block: Option<BlockId>,
+    max_restarts: u32,
+    state: Arc<SupervisorState>,
+    mut shutdown: oneshot::Receiver<()>,
+) where
+    F: FnMut(Receiver<BackendRequest>, Receiver<BackendRequest>, Option<BlockId>) -> H,
+    H: Future<Output = ()>,
+{
+    loop {
+        let (critical, critical_rx) = channel(1);
+        let (prefetch, prefetch_rx) = channel(1);
+        let handler = AssertUnwindSafe(new_handler(critical_rx, prefetch_rx, pin_block))
+            .catch_unwind()
+            .fuse();
+        let mut unpinned = None;
+        let forwarding = future::join(
+            forward(&mut incoming, critical, &mut pin_block),
+            forward(&mut incoming_prefetch, prefetch, &mut unpinned),
+        )
+        .fuse();
+        let stop = shutdown_requested(&mut shutdown).fuse();
+        pin_mut!(handler, forwarding, stop);
+
+        let ended = select! {
+            ended = handler => Some(ended),
+            _ = forwarding => None,
+            _ = stop => {
+                trace!(target: "backendsupervisor", "shutting down");
+                state.running.store(false, Ordering::Release);
+                return;
+            }
+        };
+        // without an outcome every `SharedBackend` was dropped or the handler is gone, either way
+        // it is done or about to be
+        let ended = match ended {
+            Some(ended) => ended,
+            None => handler.await,
+        };
+        if ended.is_ok() {
+            trace!(target: "backendsupervisor", "handler finished");
+            state.running.store(false, Ordering::Release);
+            return;
+        }
+
+        let restarts = state.restarts.fetch_add(1, Ordering::AcqRel) + 1;
+        if restarts > max_restarts {
+            error!(target: "backendsupervisor", restarts, "backend handler keeps panicking, giving up");
+            state.running.store(false, Ordering::Release);
+            return;
+        }
+        warn!(target: "backendsupervisor", restarts, "backend handler panicked, restarting it");
+    }
+}
+
+/// Forwards `incoming` to the handler, remembering the block it is pinned to for its successor.
+/// Returns once either side is closed.
+async fn forward(
+    incoming: &mut Receiver<BackendRequest>,
+    mut handler: Sender<BackendRequest>,
+    pin_block: &mut Option<BlockId>,
+) {
+    while let Some(req) = incoming.next().await {
+        if let BackendRequest::SetPinnedBlock(block) = &req {
+            *pin_block = Some(*block);
+        }
+        if handler.send(req).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Resolves once [BackendHandle::shutdown] is called, never if the handle was dropped instead
+async fn shutdown_requested(shutdown: &mut oneshot::Receiver<()>) {
+    if shutdown.await.is_err() {
+        future::pending::<()>().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain_db::BlockchainDbMeta;
+    use ethers::types::H256;
+    use revm::{
+        db::DatabaseRef,
+        primitives::{B256, U256 as rU256},
+    };
+    use std::{collections::BTreeSet, time::Duration};
+
+    /// Answers block hash requests with the number, the first incarnation panics on its first
+    /// request
+    fn handlers() -> impl FnMut(
+        Receiver<BackendRequest>,
+        Receiver<BackendRequest>,
+        Option<BlockId>,
+    ) -> future::BoxFuture<'static, ()> {
+        let mut incarnation = 0;
+        move |mut incoming, _, _| {
+            incarnation += 1;
+            let panics = incarnation == 1;
+            async move {
+                while let Some(req) = incoming.next().await {
+                    assert!(!panics, "first handler panics");
+                    if let BackendRequest::BlockHash(number, sender) = req {
+                        let _ = sender.send(Ok(H256::from_low_u64_be(number)));
+                    }
+                }
+            }
+            .boxed()
+        }
+    }
+
+    async fn until(condition: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition never met");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_restart_and_shutdown() {
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        let db = BlockchainDb::new(meta, None);
+        let cache = Arc::new(FlushJsonBlockCacheDB(Arc::clone(db.cache())));
+        let (backend, handle) = spawn(handlers(), cache, None, SupervisorConfig::default());
+        assert!(handle.is_healthy());
+
+        // lost with the panicking handler
+        assert!(backend.block_hash(rU256::from(1u64)).is_err());
+        until(|| handle.restarts() == 1).await;
+        assert!(handle.is_healthy());
+        assert_eq!(
+            backend.block_hash(rU256::from(2u64)).unwrap(),
+            B256::from_low_u64_be(2)
+        );
+
+        handle.shutdown();
+        until(|| !handle.is_healthy()).await;
+        assert!(backend.block_hash(rU256::from(3u64)).is_err());
+    }
+}
@@ -1,20 +1,45 @@
 // ported from foundry's executor with some modifications
 // https://github.com/foundry-rs/foundry/blob/master/evm/src/executor/fork/cache.rs
-use super::snapshot::StateSnapshot;
-use hashbrown::HashMap as Map;
+use super::{
+    snapshot::StateSnapshot,
+    utils::{h160_to_b160, h256_to_u256_be, u256_to_ru256},
+};
+use dashmap::{DashMap, DashSet};
+use ethers::{
+    types::{AccountDiff, Address, Diff},
+    utils::keccak256,
+};
+use hashbrown::{hash_map::DefaultHashBuilder, HashMap as Map};
 use parking_lot::RwLock;
 use revm::{
-    primitives::{Account, AccountInfo, B160, B256, KECCAK_EMPTY, U256},
+    primitives::{Account, AccountInfo, Bytecode, B160, B256, KECCAK_EMPTY, U256},
     DatabaseCommit,
 };
 use serde::{ser::SerializeMap, Deserialize, Deserializer, Serialize, Serializer};
-use std::{collections::BTreeSet, fs, io::BufWriter, path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 use tracing::{trace, warn};
 
 use url::Url;
 
 pub type StorageInfo = Map<U256, U256>;
 
+/// Frame magic number zstd compressed cache files start with
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const ZSTD_LEVEL: i32 = 3;
+/// Smallest code [MemDb::compress_idle_code] is worth running on by default, in bytes
+pub const DEFAULT_IDLE_CODE_MIN_LEN: usize = 1_024;
+/// Blocks code has to go unused for before it is compressed by default
+pub const DEFAULT_IDLE_CODE_BLOCKS: u64 = 50;
+
 /// A shareable Block database
 #[derive(Clone, Debug)]
 pub struct BlockchainDb {
@@ -86,20 +111,73 @@ impl BlockchainDb {
     }
 
     /// Returns the map that holds the account related info
-    pub fn accounts(&self) -> &RwLock<Map<B160, AccountInfo>> {
+    pub fn accounts(&self) -> &ConcurrentMap<B160, AccountInfo> {
         &self.db.accounts
     }
 
     /// Returns the map that holds the storage related info
-    pub fn storage(&self) -> &RwLock<Map<B160, StorageInfo>> {
+    pub fn storage(&self) -> &ConcurrentMap<B160, StorageInfo> {
         &self.db.storage
     }
 
     /// Returns the map that holds all the block hashes
-    pub fn block_hashes(&self) -> &RwLock<Map<U256, B256>> {
+    pub fn block_hashes(&self) -> &ConcurrentMap<U256, B256> {
         &self.db.block_hashes
     }
 
+    /// Returns all cached storage slots of `address`, ordered by slot
+    ///
+    /// The slots are copied out of the cache, so the iterator doesn't hold the lock
+    pub fn storage_of(&self, address: B160) -> impl Iterator<Item = (U256, U256)> {
+        let _restoring = self.db.restoring.read();
+        let slots: BTreeMap<U256, U256> = self
+            .db
+            .storage
+            .get(&address)
+            .map(|slots| slots.iter().map(|(k, v)| (*k, *v)).collect())
+            .unwrap_or_default();
+        slots.into_iter()
+    }
+
+    /// Dumps the cached account info and storage slots of `address` as JSON
+    pub fn dump_storage_json(&self, address: B160) -> serde_json::Value {
+        let storage: BTreeMap<U256, U256> = self.storage_of(address).collect();
+        serde_json::json!({
+            "address": address,
+            "account": self.db.account(&address),
+            "known_absent": self.is_known_absent(&address),
+            "storage": storage,
+        })
+    }
+
+    /// Returns the set of accounts known to not exist at the pinned block
+    ///
+    /// These are distinct from accounts that simply haven't been fetched yet, requests for them are
+    /// answered locally instead of hitting the remote
+    pub fn known_absent(&self) -> &ConcurrentSet<B160> {
+        &self.db.known_absent
+    }
+
+    /// Returns `true` if the account is known to not exist
+    pub fn is_known_absent(&self, address: &B160) -> bool {
+        self.db.is_known_absent(address)
+    }
+
+    /// Applies a block's trace state diff, moving the cached remote state forward
+    ///
+    /// See [MemDb::apply_block_diff]
+    pub fn apply_block_diff(&self, diff: &BTreeMap<Address, AccountDiff>) -> Vec<B160> {
+        self.db.apply_block_diff(diff)
+    }
+
+    /// See [MemDb::apply_balance_changes]
+    pub fn apply_balance_changes(
+        &self,
+        balances: &BTreeMap<Address, ethers::types::U256>,
+    ) -> Vec<B160> {
+        self.db.apply_balance_changes(balances)
+    }
+
     /// Returns the [revm::Env] related metadata
     pub fn meta(&self) -> &Arc<RwLock<BlockchainDbMeta>> {
         &self.meta
@@ -230,41 +308,396 @@ impl<'de> Deserialize<'de> for BlockchainDbMeta {
     }
 }
 
+/// Concurrent map used by [MemDb], sharded so readers of one account don't contend with writers of
+/// another
+pub type ConcurrentMap<K, V> = DashMap<K, V, DefaultHashBuilder>;
+
+/// Concurrent set used by [MemDb]
+pub type ConcurrentSet<K> = DashSet<K, DefaultHashBuilder>;
+
 /// In Memory cache containing all fetched accounts and storage slots
 /// and their values from RPC
-#[derive(Debug, Default)]
+///
+/// Every map is sharded, there is no lock spanning multiple maps or shards. Individual entries are
+/// always consistent, but a reader racing a [MemDb::do_commit] may observe some of its accounts
+/// before others. [MemDb::restore] is the exception, it replaces the whole state and readers
+/// going through the accessors wait for it rather than seeing the cache empty or half restored.
+#[derive(Debug, Default, Clone)]
 pub struct MemDb {
     /// Account related data
-    pub accounts: RwLock<Map<B160, AccountInfo>>,
+    pub accounts: ConcurrentMap<B160, AccountInfo>,
     /// Storage related data
-    pub storage: RwLock<Map<B160, StorageInfo>>,
+    pub storage: ConcurrentMap<B160, StorageInfo>,
     /// All retrieved block hashes
-    pub block_hashes: RwLock<Map<U256, B256>>,
+    pub block_hashes: ConcurrentMap<U256, B256>,
+    /// Accounts that are known to not exist, e.g. selfdestructed ones
+    pub known_absent: ConcurrentSet<B160>,
+    /// An account each cached code hash was seen on, to fetch code that is only referenced by
+    /// its hash. Not persisted, rebuilt from the accounts on load.
+    pub code_owners: ConcurrentMap<B256, B160>,
+    /// Cached code by hash, accounts only hold the hash of code stored here. Clones of a token
+    /// contract share a single copy. Not persisted, accounts are written with their code.
+    pub codes: ConcurrentMap<B256, CodeEntry>,
+    /// held for writing while [MemDb::restore] swaps the state in
+    restoring: RestoreLock,
     // TODO: add a block number hashmap
 }
 
+/// Lock of a [MemDb] only, a clone of the db gets its own
+#[derive(Debug, Default)]
+struct RestoreLock(RwLock<()>);
+
+impl RestoreLock {
+    /// Recursive, the accessors call each other and mustn't deadlock behind a waiting restore
+    fn read(&self) -> parking_lot::RwLockReadGuard<'_, ()> {
+        self.0.read_recursive()
+    }
+
+    fn write(&self) -> parking_lot::RwLockWriteGuard<'_, ()> {
+        self.0.write()
+    }
+}
+
+impl Clone for RestoreLock {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
 impl MemDb {
     /// Clears all data stored in this db
     pub fn clear(&self) {
-        self.accounts.write().clear();
-        self.storage.write().clear();
-        self.block_hashes.write().clear();
+        self.accounts.clear();
+        self.storage.clear();
+        self.block_hashes.clear();
+        self.known_absent.clear();
+        self.code_owners.clear();
+        self.codes.clear();
+    }
+
+    /// Returns the cached account info, along with its code if it is cached
+    pub fn account(&self, address: &B160) -> Option<AccountInfo> {
+        let _restoring = self.restoring.read();
+        let info = self.accounts.get(address).map(|acc| acc.value().clone())?;
+        Some(self.with_code(info))
+    }
+
+    /// `info` with its code from the code store, if it only holds the hash
+    fn with_code(&self, mut info: AccountInfo) -> AccountInfo {
+        if info.code.is_none() {
+            info.code = self.code(&info.code_hash);
+        }
+        info
+    }
+
+    /// Returns the cached value of a storage slot
+    pub fn storage_slot(&self, address: &B160, index: &U256) -> Option<U256> {
+        let _restoring = self.restoring.read();
+        self.storage
+            .get(address)
+            .and_then(|slots| slots.get(index).copied())
+    }
+
+    /// Returns the cached block hash
+    pub fn block_hash(&self, number: &U256) -> Option<B256> {
+        let _restoring = self.restoring.read();
+        self.block_hashes.get(number).map(|hash| *hash)
+    }
+
+    /// Returns the cached code with the hash `code_hash`, from the code store or an account
+    /// holding it along with its code
+    pub fn code_by_hash(&self, code_hash: &B256) -> Option<Bytecode> {
+        let _restoring = self.restoring.read();
+        if let Some(code) = self.code(code_hash) {
+            return Some(code);
+        }
+        let owner = self.code_owner(code_hash)?;
+        let account = self.accounts.get(&owner)?;
+        account
+            .code
+            .as_ref()
+            .filter(|_| account.code_hash == *code_hash)
+            .cloned()
+    }
+
+    /// Returns `true` if the account is known to not exist
+    pub fn is_known_absent(&self, address: &B160) -> bool {
+        let _restoring = self.restoring.read();
+        self.known_absent.contains(address)
+    }
+
+    /// Returns an account the code with the hash `code_hash` was seen on
+    pub fn code_owner(&self, code_hash: &B256) -> Option<B160> {
+        self.code_owners.get(code_hash).map(|owner| *owner)
+    }
+
+    /// Records `address` as holding the code of `info`, whether or not the code itself is cached
+    pub fn index_code(&self, address: B160, info: &AccountInfo) {
+        if info.code_hash != KECCAK_EMPTY && !info.code_hash.is_zero() {
+            self.code_owners.insert(info.code_hash, address);
+        }
+    }
+
+    /// Returns the code with the hash `code_hash` from the code store, decompressing it if it was
+    /// compressed by [MemDb::compress_idle_code]
+    pub fn code(&self, code_hash: &B256) -> Option<Bytecode> {
+        let _restoring = self.restoring.read();
+        let compressed = {
+            let entry = self.codes.get(code_hash)?;
+            entry.used.store(true, Ordering::Relaxed);
+            match &entry.blob {
+                CodeBlob::Plain(code) => return Some(code.clone()),
+                CodeBlob::Compressed(compressed) => zstd::decode_all(compressed.as_slice()),
+            }
+        };
+        let code = match compressed {
+            Ok(raw) => Bytecode::new_raw(raw.into()).to_checked(),
+            Err(err) => {
+                warn!(target: "cache", ?err, ?code_hash, "Failed to decompress code");
+                self.codes.remove(code_hash);
+                return None;
+            }
+        };
+        if let Some(mut entry) = self.codes.get_mut(code_hash) {
+            entry.blob = CodeBlob::Plain(code.clone());
+        }
+        Some(code)
+    }
+
+    /// Moves the code of `info` into the code store, leaving `info` with its hash only
+    pub fn dedupe_code(&self, info: &mut AccountInfo) {
+        if info.code_hash == KECCAK_EMPTY || info.code_hash.is_zero() {
+            return;
+        }
+        if let Some(code) = info.code.take() {
+            if code.is_empty() {
+                info.code = Some(code);
+                return;
+            }
+            self.codes
+                .entry(info.code_hash)
+                .or_insert_with(|| CodeEntry::new(code));
+        }
+    }
+
+    /// Stores `code` under its hash, e.g. code fetched for a hash an account refers to
+    pub fn insert_code(&self, code: Bytecode) {
+        if !code.is_empty() {
+            self.codes
+                .entry(code.hash())
+                .or_insert_with(|| CodeEntry::new(code));
+        }
+    }
+
+    /// Compresses the stored code of at least `min_len` bytes not used since the previous call,
+    /// returns how many blobs were compressed. Compressed code is restored on its next use.
+    pub fn compress_idle_code(&self, min_len: usize) -> usize {
+        let mut compressed = 0;
+        for mut entry in self.codes.iter_mut() {
+            let used = entry.used.swap(false, Ordering::Relaxed);
+            let CodeBlob::Plain(code) = &entry.blob else {
+                continue;
+            };
+            if used || code.len() < min_len {
+                continue;
+            }
+            match zstd::encode_all(code.original_bytes().as_ref(), ZSTD_LEVEL) {
+                Ok(blob) => {
+                    entry.blob = CodeBlob::Compressed(blob);
+                    compressed += 1;
+                }
+                Err(err) => warn!(target: "cache", ?err, "Failed to compress code"),
+            }
+        }
+        compressed
+    }
+
+    /// Bytes taken by the code store, compressed blobs at their compressed size
+    pub fn code_size(&self) -> usize {
+        self.codes
+            .iter()
+            .map(|entry| match &entry.blob {
+                CodeBlob::Plain(code) => code.len(),
+                CodeBlob::Compressed(blob) => blob.len(),
+            })
+            .sum()
+    }
+
+    /// Inserts a single storage slot
+    pub fn insert_storage(&self, address: B160, index: U256, value: U256) {
+        self.storage
+            .entry(address)
+            .or_default()
+            .insert(index, value);
     }
 
     // Inserts the account, replacing it if it exists already
-    pub fn do_insert_account(&self, address: B160, account: AccountInfo) {
-        self.accounts.write().insert(address, account);
+    pub fn do_insert_account(&self, address: B160, mut account: AccountInfo) {
+        self.known_absent.remove(&address);
+        self.index_code(address, &account);
+        self.dedupe_code(&mut account);
+        self.accounts.insert(address, account);
+    }
+
+    /// Marks the account as non-existent, dropping any cached info and storage
+    pub fn mark_absent(&self, address: B160) {
+        self.accounts.remove(&address);
+        self.storage.remove(&address);
+        self.known_absent.insert(address);
+    }
+
+    /// Copies the current state out of the concurrent maps
+    pub fn to_state_snapshot(&self) -> StateSnapshot {
+        let mut snapshot = StateSnapshot {
+            accounts: self
+                .accounts
+                .iter()
+                .map(|entry| (*entry.key(), self.with_code(entry.value().clone())))
+                .collect(),
+            storage: self
+                .storage
+                .iter()
+                .map(|entry| (*entry.key(), entry.value().clone()))
+                .collect(),
+            block_hashes: self
+                .block_hashes
+                .iter()
+                .map(|entry| (*entry.key(), *entry.value()))
+                .collect(),
+            known_absent: self.known_absent.iter().map(|entry| *entry.key()).collect(),
+            codes: Default::default(),
+        };
+        snapshot.index_codes();
+        snapshot
+    }
+
+    /// Replaces the current state with `snapshot`, atomically for readers going through the
+    /// accessors
+    pub fn restore(&self, snapshot: StateSnapshot) {
+        let StateSnapshot {
+            accounts,
+            storage,
+            block_hashes,
+            known_absent,
+            ..
+        } = snapshot;
+        let _restoring = self.restoring.write();
+        self.clear();
+        accounts.into_iter().for_each(|(k, mut v)| {
+            self.index_code(k, &v);
+            self.dedupe_code(&mut v);
+            self.accounts.insert(k, v);
+        });
+        storage.into_iter().for_each(|(k, v)| {
+            self.storage.insert(k, v);
+        });
+        block_hashes.into_iter().for_each(|(k, v)| {
+            self.block_hashes.insert(k, v);
+        });
+        known_absent.into_iter().for_each(|k| {
+            self.known_absent.insert(k);
+        });
+    }
+
+    /// Applies a trace state diff (e.g. from `trace_replayBlockTransactions`) to the cached state
+    ///
+    /// `Died` accounts become known absent, `Born` accounts replace whatever was cached (wiping
+    /// storage, since a re-created account starts empty) and `Changed` fields are patched in place
+    /// for accounts that are already cached. Returns all touched addresses.
+    pub fn apply_block_diff(&self, diff: &BTreeMap<Address, AccountDiff>) -> Vec<B160> {
+        let mut touched = Vec::with_capacity(diff.len());
+        for (address, account_diff) in diff {
+            let address = h160_to_b160(*address);
+            touched.push(address);
+
+            let died = matches!(account_diff.balance, Diff::Died(_))
+                || matches!(account_diff.nonce, Diff::Died(_))
+                || matches!(account_diff.code, Diff::Died(_));
+            if died {
+                trace!(target: "cache", ?address, "account died");
+                self.mark_absent(address);
+                continue;
+            }
+
+            let born = matches!(account_diff.balance, Diff::Born(_))
+                || matches!(account_diff.nonce, Diff::Born(_))
+                || matches!(account_diff.code, Diff::Born(_));
+
+            if born {
+                trace!(target: "cache", ?address, "account born");
+                self.known_absent.remove(&address);
+                self.storage.remove(&address);
+                self.accounts.insert(address, AccountInfo::default());
+            }
+
+            if let Some(mut info) = self.accounts.get_mut(&address) {
+                if let Some(balance) = diff_to(&account_diff.balance) {
+                    info.balance = u256_to_ru256(*balance);
+                }
+                if let Some(nonce) = diff_to(&account_diff.nonce) {
+                    info.nonce = nonce.as_u64();
+                }
+                if let Some(code) = diff_to(&account_diff.code) {
+                    if code.0.is_empty() {
+                        info.code = Some(Bytecode::new());
+                        info.code_hash = KECCAK_EMPTY;
+                    } else {
+                        info.code_hash = keccak256(code).into();
+                        info.code = Some(Bytecode::new_raw(code.0.clone()).to_checked());
+                        self.index_code(address, &*info);
+                        self.dedupe_code(&mut info);
+                    }
+                }
+            }
+
+            // storage values are exact, so they can be applied even if the account isn't cached
+            if !account_diff.storage.is_empty() {
+                let mut acc_storage = self.storage.entry(address).or_default();
+                for (slot, value) in &account_diff.storage {
+                    if let Some(value) = diff_to(value) {
+                        acc_storage.insert(
+                            u256_to_ru256(h256_to_u256_be(*slot)),
+                            u256_to_ru256(h256_to_u256_be(*value)),
+                        );
+                    }
+                }
+            }
+        }
+        touched
+    }
+
+    /// Sets the post-block balances of a block (e.g. from `erigon_getBalanceChangesInBlock`) on
+    /// the cached accounts. Accounts that aren't cached are left to be fetched. Returns the
+    /// updated addresses.
+    pub fn apply_balance_changes(
+        &self,
+        balances: &BTreeMap<Address, ethers::types::U256>,
+    ) -> Vec<B160> {
+        let mut updated = Vec::new();
+        for (address, balance) in balances {
+            let address = h160_to_b160(*address);
+            if let Some(mut info) = self.accounts.get_mut(&address) {
+                info.balance = u256_to_ru256(*balance);
+                updated.push(address);
+            }
+        }
+        updated
     }
 
     /// The implementation of [DatabaseCommit::commit()]
+    ///
+    /// Entries are updated one account at a time, no shard lock is held across maps
     pub fn do_commit(&self, changes: Map<B160, Account>) {
-        let mut storage = self.storage.write();
-        let mut accounts = self.accounts.write();
         for (add, mut acc) in changes {
             if acc.is_empty() || acc.is_destroyed {
-                accounts.remove(&add);
-                storage.remove(&add);
+                self.accounts.remove(&add);
+                self.storage.remove(&add);
+                self.known_absent.insert(add);
             } else {
+                // the account was (re-)created
+                if self.known_absent.remove(&add).is_some() {
+                    self.storage.remove(&add);
+                }
                 // insert account
                 if let Some(code_hash) = acc
                     .info
@@ -277,37 +710,75 @@ impl MemDb {
                 } else if acc.info.code_hash.is_zero() {
                     acc.info.code_hash = KECCAK_EMPTY;
                 }
-                accounts.insert(add, acc.info);
-
-                let acc_storage = storage.entry(add).or_default();
-                if acc.storage_cleared {
-                    acc_storage.clear();
-                }
-                for (index, value) in acc.storage {
-                    if value.present_value() == U256::from(0) {
-                        acc_storage.remove(&index);
-                    } else {
-                        acc_storage.insert(index, value.present_value());
+                self.index_code(add, &acc.info);
+                self.dedupe_code(&mut acc.info);
+                self.accounts.insert(add, acc.info);
+
+                let is_empty = {
+                    let mut acc_storage = self.storage.entry(add).or_default();
+                    if acc.storage_cleared {
+                        acc_storage.clear();
                     }
-                }
-                if acc_storage.is_empty() {
-                    storage.remove(&add);
+                    for (index, value) in acc.storage {
+                        if value.present_value() == U256::from(0) {
+                            acc_storage.remove(&index);
+                        } else {
+                            acc_storage.insert(index, value.present_value());
+                        }
+                    }
+                    acc_storage.is_empty()
+                };
+                if is_empty {
+                    self.storage.remove_if(&add, |_, slots| slots.is_empty());
                 }
             }
         }
     }
 }
 
-impl Clone for MemDb {
+/// Code in the [MemDb::codes] store
+#[derive(Debug)]
+pub struct CodeEntry {
+    pub blob: CodeBlob,
+    /// looked up since the last [MemDb::compress_idle_code]
+    used: AtomicBool,
+}
+
+impl CodeEntry {
+    fn new(code: Bytecode) -> Self {
+        Self {
+            blob: CodeBlob::Plain(code),
+            used: AtomicBool::new(true),
+        }
+    }
+}
+
+impl Clone for CodeEntry {
     fn clone(&self) -> Self {
         Self {
-            storage: RwLock::new(self.storage.read().clone()),
-            accounts: RwLock::new(self.accounts.read().clone()),
-            block_hashes: RwLock::new(self.block_hashes.read().clone()),
+            blob: self.blob.clone(),
+            used: AtomicBool::new(self.used.load(Ordering::Relaxed)),
         }
     }
 }
 
+#[derive(Debug, Clone)]
+pub enum CodeBlob {
+    Plain(Bytecode),
+    /// zstd compressed original bytes
+    Compressed(Vec<u8>),
+}
+
+/// The post-state of a [Diff], if the field was set
+fn diff_to<T>(diff: &Diff<T>) -> Option<&T> {
+    match diff {
+        Diff::Same => None,
+        Diff::Born(value) => Some(value),
+        Diff::Died(_) => None,
+        Diff::Changed(changed) => Some(&changed.to),
+    }
+}
+
 impl DatabaseCommit for MemDb {
     fn commit(&mut self, changes: Map<B160, Account>) {
         self.do_commit(changes)
@@ -337,7 +808,8 @@ impl JsonBlockCacheDB {
         }
     }
 
-    /// Loads the contents of the diskmap file and returns the read object
+    /// Loads the contents of the diskmap file and returns the read object, zstd compressed files
+    /// are detected and decompressed
     ///
     /// # Errors
     /// This will fail if
@@ -346,12 +818,19 @@ impl JsonBlockCacheDB {
     pub fn load(path: impl Into<PathBuf>) -> eyre::Result<Self> {
         let path = path.into();
         trace!(target : "cache", ?path, "reading json cache");
-        let file = fs::File::open(&path).map_err(|err| {
+        let raw = fs::read(&path).map_err(|err| {
             warn!(?err, ?path, "Failed to read cache file");
             err
         })?;
-        let file = std::io::BufReader::new(file);
-        let data = serde_json::from_reader(file).map_err(|err| {
+        let raw = if raw.starts_with(&ZSTD_MAGIC) {
+            zstd::decode_all(raw.as_slice()).map_err(|err| {
+                warn!(target : "cache", ?err, ?path, "Failed to decompress cache data");
+                err
+            })?
+        } else {
+            raw
+        };
+        let data = serde_json::from_slice(&raw).map_err(|err| {
             warn!(target : "cache", ?err, ?path, "Failed to deserialize cache data");
             err
         })?;
@@ -377,28 +856,57 @@ impl JsonBlockCacheDB {
     }
 
     /// Flushes the DB to disk if caching is enabled
+    ///
+    /// The file is replaced atomically, a crash mid flush leaves the previous cache intact. Paths
+    /// ending in `.zst` are written zstd compressed.
     pub fn flush(&self) {
-        // writes the data to a json file
         if let Some(ref path) = self.cache_path {
             trace!(target: "cache", "saving json cache path={:?}", path);
-            if let Some(parent) = path.parent() {
-                let _ = fs::create_dir_all(parent);
+            match self.write_to(path) {
+                Ok(()) => trace!(target: "cache", "saved json cache path={:?}", path),
+                Err(e) => warn!(target: "cache", "Failed to write json cache: {}", e),
             }
-            let _ = fs::File::create(path)
-                .map_err(|e| warn!(target: "cache", "Failed to open json cache for writing: {}", e))
-                .and_then(|f| {
-                    serde_json::to_writer(BufWriter::new(f), &self.data)
-                        .map_err(|e| warn!(target: "cache" ,"Failed to write to json cache: {}", e))
-                });
-            trace!(target: "cache", "saved json cache path={:?}", path);
         }
     }
+
+    fn write_to(&self, path: &Path) -> eyre::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut tmp_name = path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp = PathBuf::from(tmp_name);
+
+        let mut writer = BufWriter::new(fs::File::create(&tmp)?);
+        if path.extension().map_or(false, |ext| ext == "zst") {
+            let mut encoder = zstd::Encoder::new(writer, ZSTD_LEVEL)?;
+            serde_json::to_writer(&mut encoder, &self.data)?;
+            writer = encoder.finish()?;
+        } else {
+            serde_json::to_writer(&mut writer, &self.data)?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Number of cached accounts, slots and block hashes, used to decide when to flush again
+    pub fn entry_count(&self) -> usize {
+        let db = self.db();
+        db.accounts.len()
+            + db.storage.iter().map(|slots| slots.len()).sum::<usize>()
+            + db.block_hashes.len()
+    }
 }
 
 /// The Data the [JsonBlockCacheDB] can read and flush
 ///
 /// This will be deserialized in a JSON object with the keys:
-/// `["meta", "accounts", "storage", "block_hashes"]`
+/// `["meta", "accounts", "storage", "block_hashes", "known_absent"]`
 #[derive(Debug)]
 pub struct JsonBlockCacheData {
     pub meta: Arc<RwLock<BlockchainDbMeta>>,
@@ -410,28 +918,41 @@ impl Serialize for JsonBlockCacheData {
     where
         S: Serializer,
     {
-        let mut map = serializer.serialize_map(Some(4))?;
+        let mut map = serializer.serialize_map(Some(5))?;
 
         let meta = self.meta.read();
         map.serialize_entry("meta", &*meta)?;
         drop(meta);
 
-        let accounts = self.data.accounts.read();
-        map.serialize_entry("accounts", &*accounts)?;
-        drop(accounts);
+        map.serialize_entry("accounts", &AccountsWithCode(&self.data))?;
+
+        map.serialize_entry("storage", &self.data.storage)?;
 
-        let storage = self.data.storage.read();
-        map.serialize_entry("storage", &*storage)?;
-        drop(storage);
+        map.serialize_entry("block_hashes", &self.data.block_hashes)?;
 
-        let block_hashes = self.data.block_hashes.read();
-        map.serialize_entry("block_hashes", &*block_hashes)?;
-        drop(block_hashes);
+        map.serialize_entry("known_absent", &self.data.known_absent)?;
 
         map.end()
     }
 }
 
+/// The accounts of a [MemDb] written along with their code, the code store isn't persisted
+struct AccountsWithCode<'a>(&'a MemDb);
+
+impl Serialize for AccountsWithCode<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_map(
+            self.0
+                .accounts
+                .iter()
+                .map(|entry| (*entry.key(), self.0.with_code(entry.value().clone()))),
+        )
+    }
+}
+
 impl<'de> Deserialize<'de> for JsonBlockCacheData {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -444,23 +965,14 @@ impl<'de> Deserialize<'de> for JsonBlockCacheData {
             data: StateSnapshot,
         }
 
-        let Data {
-            meta,
-            data:
-                StateSnapshot {
-                    accounts,
-                    storage,
-                    block_hashes,
-                },
-        } = Data::deserialize(deserializer)?;
+        let Data { meta, data } = Data::deserialize(deserializer)?;
+
+        let db = MemDb::default();
+        db.restore(data);
 
         Ok(JsonBlockCacheData {
             meta: Arc::new(RwLock::new(meta)),
-            data: Arc::new(MemDb {
-                accounts: RwLock::new(accounts),
-                storage: RwLock::new(storage),
-                block_hashes: RwLock::new(block_hashes),
-            }),
+            data: Arc::new(db),
         })
     }
 }
@@ -0,0 +1,137 @@
+//! Background flushing of the [JsonBlockCacheDB]
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{trace, warn};
+
+use crate::blockchain_db::JsonBlockCacheDB;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CacheFlushConfig {
+    /// How often the cache is checked for new entries
+    pub interval: Duration,
+    /// New accounts, slots and block hashes needed since the last flush before flushing again
+    pub min_new_entries: usize,
+}
+
+impl Default for CacheFlushConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(60),
+            min_new_entries: 1_000,
+        }
+    }
+}
+
+/// Periodically flushes a cache from a background task, off the simulation path
+///
+/// Call [CacheFlusher::shutdown] before exiting, it stops the task and does a final flush. If the
+/// flusher is dropped instead the final flush happens synchronously in `drop`.
+#[derive(Debug)]
+pub struct CacheFlusher {
+    cache: Arc<JsonBlockCacheDB>,
+    stop: watch::Sender<bool>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl CacheFlusher {
+    /// Spawn the flush task on the current tokio runtime, nothing is spawned for transient caches
+    pub fn spawn(cache: Arc<JsonBlockCacheDB>, config: CacheFlushConfig) -> Self {
+        let (stop, mut stopped) = watch::channel(false);
+        let task = (!cache.is_transient()).then(|| {
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(config.interval);
+                let mut flushed_entries = cache.entry_count();
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = stopped.changed() => break,
+                    }
+                    let entries = cache.entry_count();
+                    let new_entries = entries.saturating_sub(flushed_entries);
+                    if new_entries < config.min_new_entries {
+                        continue;
+                    }
+                    trace!(target: "cache", "flushing {} new cache entries", new_entries);
+                    let flushing = cache.clone();
+                    if let Err(e) = tokio::task::spawn_blocking(move || flushing.flush()).await {
+                        warn!(target: "cache", "Cache flush task failed: {}", e);
+                    }
+                    flushed_entries = entries;
+                }
+            })
+        });
+
+        Self { cache, stop, task }
+    }
+
+    /// Stop the background task and flush whatever it hasn't written yet
+    pub async fn shutdown(mut self) {
+        let _ = self.stop.send(true);
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+            let cache = self.cache.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || cache.flush()).await {
+                warn!(target: "cache", "Final cache flush failed: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for CacheFlusher {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            let _ = self.stop.send(true);
+            task.abort();
+            self.cache.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain_db::{BlockchainDb, BlockchainDbMeta};
+    use revm::primitives::{AccountInfo, B160, U256};
+    use std::collections::BTreeSet;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shutdown_flushes_compressed_cache() {
+        let path =
+            std::env::temp_dir().join(format!("qilin-cache-{}.json.zst", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        let db = BlockchainDb::new(meta, Some(path.clone()));
+        let flusher = CacheFlusher::spawn(
+            db.cache().clone(),
+            CacheFlushConfig {
+                interval: Duration::from_secs(3600),
+                min_new_entries: usize::MAX,
+            },
+        );
+
+        let address = B160::from_low_u64_be(1);
+        db.db().do_insert_account(
+            address,
+            AccountInfo {
+                balance: U256::from(1u64),
+                ..Default::default()
+            },
+        );
+        flusher.shutdown().await;
+
+        let raw = std::fs::read(&path).unwrap();
+        assert!(raw.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]));
+        let loaded = JsonBlockCacheDB::load(&path).unwrap();
+        assert!(loaded.db().accounts.contains_key(&address));
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
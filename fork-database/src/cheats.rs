@@ -0,0 +1,287 @@
+//! Anvil style cheatcodes over a [ForkedDatabase]
+//!
+//! Mirrors `anvil_setBalance`, `anvil_setCode`, `anvil_setStorageAt`, `anvil_setNonce`,
+//! `anvil_impersonateAccount`, `evm_setNextBlockTimestamp` and `anvil_mine`, so Foundry tests
+//! written against a forked Anvil port over by swapping the RPC calls for these. Writes only touch
+//! the local layer of the database, the remote state is never modified.
+
+use ethers::types::U256;
+use hashbrown::HashSet;
+use revm::{
+    db::DatabaseRef,
+    primitives::{
+        AccountInfo, Bytecode, Bytes, Env, ExecutionResult, TransactTo, TxEnv, B160, U256 as rU256,
+    },
+    EVM,
+};
+use thiserror::Error;
+
+use crate::{
+    errors::DatabaseError, forked_db::ForkedDatabase, storage_layout::mapping_slot,
+    utils::u256_to_ru256,
+};
+
+#[derive(Error, Debug)]
+pub enum CheatError {
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+    #[error("{0:?} is not impersonated")]
+    NotImpersonated(B160),
+    #[error("Execution error: {0}")]
+    Execution(String),
+}
+
+/// Cheatcodes over a [ForkedDatabase], holding the block env the cheated txs run in
+#[derive(Debug)]
+pub struct Cheats<'a> {
+    db: &'a mut ForkedDatabase,
+    env: Env,
+    impersonated: HashSet<B160>,
+}
+
+impl<'a> Cheats<'a> {
+    /// Cheats running txs in the fork's block env
+    pub fn new(db: &'a mut ForkedDatabase) -> Self {
+        let mut env = Env::default();
+        {
+            let meta = db.inner().meta().read();
+            env.cfg = meta.cfg_env.clone();
+            env.block = meta.block_env.clone();
+        }
+        Self::with_env(db, env)
+    }
+
+    pub fn with_env(db: &'a mut ForkedDatabase, env: Env) -> Self {
+        Self {
+            db,
+            env,
+            impersonated: HashSet::new(),
+        }
+    }
+
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    pub fn db(&self) -> &ForkedDatabase {
+        self.db
+    }
+
+    fn account(&self, address: B160) -> Result<AccountInfo, CheatError> {
+        Ok(self.db.basic(address)?.unwrap_or_default())
+    }
+
+    /// `anvil_setBalance`
+    pub fn set_balance(&mut self, address: B160, balance: rU256) -> Result<(), CheatError> {
+        let mut info = self.account(address)?;
+        info.balance = balance;
+        self.db.database_mut().insert_account_info(address, info);
+        Ok(())
+    }
+
+    /// Foundry's `deal` for ETH, same as [Self::set_balance]
+    pub fn deal(&mut self, address: B160, balance: rU256) -> Result<(), CheatError> {
+        self.set_balance(address, balance)
+    }
+
+    /// Foundry's `deal` for an ERC20 whose balances live in the mapping at `balance_slot`, see
+    /// [storage_layout](crate::storage_layout). The total supply isn't adjusted.
+    pub fn deal_erc20(
+        &mut self,
+        token: B160,
+        owner: B160,
+        balance_slot: u64,
+        amount: rU256,
+    ) -> Result<(), CheatError> {
+        let slot = mapping_slot(ethers::types::H160(owner.0), balance_slot);
+        self.set_storage_at(token, slot, amount)
+    }
+
+    /// `anvil_setCode`
+    pub fn set_code(&mut self, address: B160, code: Bytes) -> Result<(), CheatError> {
+        let mut info = self.account(address)?;
+        let code = Bytecode::new_raw(code);
+        info.code_hash = code.hash_slow();
+        info.code = Some(code);
+        self.db.database_mut().insert_account_info(address, info);
+        Ok(())
+    }
+
+    /// `anvil_setNonce`
+    pub fn set_nonce(&mut self, address: B160, nonce: u64) -> Result<(), CheatError> {
+        let mut info = self.account(address)?;
+        info.nonce = nonce;
+        self.db.database_mut().insert_account_info(address, info);
+        Ok(())
+    }
+
+    /// `anvil_setStorageAt`
+    pub fn set_storage_at(
+        &mut self,
+        address: B160,
+        slot: U256,
+        value: rU256,
+    ) -> Result<(), CheatError> {
+        self.db
+            .database_mut()
+            .insert_account_storage(address, u256_to_ru256(slot), value)?;
+        Ok(())
+    }
+
+    /// `anvil_impersonateAccount`, txs from `address` can be sent with [Self::send_as]
+    pub fn impersonate(&mut self, address: B160) {
+        self.impersonated.insert(address);
+    }
+
+    /// `anvil_stopImpersonatingAccount`
+    pub fn stop_impersonating(&mut self, address: B160) {
+        self.impersonated.remove(&address);
+    }
+
+    pub fn is_impersonated(&self, address: B160) -> bool {
+        self.impersonated.contains(&address)
+    }
+
+    /// `evm_setNextBlockTimestamp`, Foundry's `warp`
+    pub fn warp(&mut self, timestamp: u64) {
+        self.env.block.timestamp = rU256::from(timestamp);
+    }
+
+    /// Foundry's `roll`, also sets the block number `anvil_mine` would move to
+    pub fn roll(&mut self, number: u64) {
+        self.env.block.number = rU256::from(number);
+    }
+
+    /// `anvil_mine`, advances the block number by `blocks` and the timestamp by `interval`
+    /// seconds per block
+    pub fn mine(&mut self, blocks: u64, interval: u64) {
+        self.env.block.number += rU256::from(blocks);
+        self.env.block.timestamp += rU256::from(blocks * interval);
+    }
+
+    /// `eth_sendTransaction` from an impersonated account, executed and committed right away
+    ///
+    /// Like Anvil the sender may be a contract, EIP-3607 isn't enforced. The nonce is taken from
+    /// the sender's account.
+    pub fn send_as(
+        &mut self,
+        from: B160,
+        to: B160,
+        data: Bytes,
+        value: rU256,
+    ) -> Result<ExecutionResult, CheatError> {
+        if !self.is_impersonated(from) {
+            return Err(CheatError::NotImpersonated(from));
+        }
+
+        let mut evm = EVM::new();
+        evm.env = self.env.clone();
+        evm.env.cfg.disable_eip3607 = true;
+        evm.env.tx = TxEnv {
+            caller: from,
+            transact_to: TransactTo::Call(to),
+            data,
+            value,
+            gas_limit: u64::try_from(self.env.block.gas_limit).unwrap_or(u64::MAX),
+            gas_price: self.env.block.basefee,
+            nonce: None,
+            ..Default::default()
+        };
+        evm.database(&mut *self.db);
+        evm.transact_commit()
+            .map_err(|e| CheatError::Execution(format!("{:?}", e)))
+    }
+
+    /// Read back a slot, e.g. to assert on a cheated storage write
+    pub fn storage_at(&self, address: B160, slot: U256) -> Result<rU256, CheatError> {
+        Ok(DatabaseRef::storage(
+            &*self.db,
+            address,
+            u256_to_ru256(slot),
+        )?)
+    }
+}
+
+impl ForkedDatabase {
+    /// [Cheats] over this database
+    pub fn cheats(&mut self) -> Cheats<'_> {
+        Cheats::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend_supervisor::{BackendRuntime, SupervisorConfig},
+        blockchain_db::{BlockchainDb, BlockchainDbMeta},
+        shared_backend::SharedBackend,
+    };
+    use ethers::providers::Provider;
+    use std::collections::BTreeSet;
+
+    fn forked_db() -> ForkedDatabase {
+        let (provider, _mock) = Provider::mocked();
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        let db = BlockchainDb::new(meta, None);
+        let config = SupervisorConfig::default().with_runtime(BackendRuntime::Dedicated);
+        let (backend, _handle) =
+            SharedBackend::spawn_supervised(provider, db.clone(), None, config);
+        ForkedDatabase::new(backend, db)
+    }
+
+    #[test]
+    fn test_cheats() {
+        let alice = B160::from_low_u64_be(0xa11ce);
+        let token = B160::from_low_u64_be(0x70c3);
+        let mut db = forked_db();
+        for address in [alice, token] {
+            db.inner()
+                .db()
+                .do_insert_account(address, AccountInfo::default());
+        }
+
+        let mut cheats = db.cheats();
+        cheats.deal(alice, rU256::from(1_000u64)).unwrap();
+        cheats
+            .deal_erc20(token, alice, 3, rU256::from(42u64))
+            .unwrap();
+        cheats.set_code(token, vec![0x00].into()).unwrap();
+        cheats.warp(1_700_000_000);
+        cheats.mine(2, 12);
+
+        assert_eq!(
+            cheats
+                .storage_at(token, mapping_slot(ethers::types::H160(alice.0), 3))
+                .unwrap(),
+            rU256::from(42u64)
+        );
+        assert_eq!(cheats.env().block.timestamp, rU256::from(1_700_000_024u64));
+        assert_eq!(cheats.env().block.number, rU256::from(2u64));
+
+        // sending needs impersonation, after which a plain ETH transfer goes through
+        let bob = B160::from_low_u64_be(0xb0b);
+        cheats
+            .db
+            .inner()
+            .db()
+            .do_insert_account(bob, AccountInfo::default());
+        assert!(matches!(
+            cheats.send_as(alice, bob, Bytes::new(), rU256::from(1u64)),
+            Err(CheatError::NotImpersonated(_))
+        ));
+        cheats.impersonate(alice);
+        let result = cheats
+            .send_as(alice, bob, Bytes::new(), rU256::from(100u64))
+            .unwrap();
+        assert!(result.is_success());
+        assert_eq!(
+            cheats.db().basic(bob).unwrap().unwrap().balance,
+            rU256::from(100u64)
+        );
+    }
+}
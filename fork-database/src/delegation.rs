@@ -0,0 +1,451 @@
+//! EIP-7702 delegated accounts
+//!
+//! A type-4 tx carries a list of authorizations, each signed by an EOA, which set the EOA's code
+//! to the delegation designator `0xef0100 || address` before the tx executes. Calls to the EOA
+//! then run the code of `address` in the EOA's context. revm 3 knows neither the tx type nor the
+//! designator, so [apply_authorizations] writes the designators into a [CacheDB] and
+//! [DelegationDb] resolves them to the delegate's code when the EVM loads an account.
+//!
+//! Unlike on chain, `EXTCODESIZE` and `EXTCODECOPY` of a delegated account see the delegate's code
+//! instead of the 23 byte designator. `EXTCODEHASH` still returns the designator's hash. The
+//! intrinsic gas of the authorizations, [PER_EMPTY_ACCOUNT_COST] each, isn't charged by revm and
+//! has to be added to gas estimates.
+
+use ethers::{
+    types::{Address, Signature, H256, U256},
+    utils::{
+        keccak256,
+        rlp::{DecoderError, Rlp, RlpStream},
+    },
+};
+use revm::{
+    db::{CacheDB, DatabaseCommit, DatabaseRef},
+    primitives::{
+        bytes, Account, AccountInfo, Bytecode, HashMap, B160, B256, KECCAK_EMPTY, U256 as rU256,
+    },
+    Database,
+};
+use thiserror::Error;
+
+use crate::utils::{b160_to_h160, h160_to_b160};
+
+/// Code of a delegated EOA is this prefix followed by the delegate's address
+pub const DELEGATION_PREFIX: [u8; 3] = [0xef, 0x01, 0x00];
+/// Prepended to the rlp of an authorization before hashing
+pub const AUTHORIZATION_MAGIC: u8 = 0x05;
+/// Intrinsic gas charged per authorization in the list
+pub const PER_EMPTY_ACCOUNT_COST: u64 = 25_000;
+
+const DESIGNATOR_LEN: usize = DELEGATION_PREFIX.len() + 20;
+/// secp256k1n / 2, higher `s` values are malleable and rejected
+const SECP256K1N_HALF: U256 = U256([
+    0xdfe92f46681b20a0,
+    0x5d576e7357a4501d,
+    0xffffffffffffffff,
+    0x7fffffffffffffff,
+]);
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum AuthorizationError {
+    #[error("Authorization for chain {0}")]
+    WrongChain(U256),
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("{0:?} has code and isn't delegated")]
+    HasCode(Address),
+    #[error("Nonce of {authority:?} is {expected}, authorization has {got}")]
+    NonceMismatch {
+        authority: Address,
+        expected: u64,
+        got: u64,
+    },
+    #[error("Database error: {0}")]
+    Database(String),
+}
+
+/// `[chain_id, address, nonce]` of an authorization, chain id 0 is valid on every chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Authorization {
+    pub chain_id: U256,
+    /// the delegate, the zero address clears the delegation
+    pub address: Address,
+    pub nonce: u64,
+}
+
+impl Authorization {
+    /// `keccak256(0x05 || rlp([chain_id, address, nonce]))`, what the authority signs
+    pub fn signing_hash(&self) -> H256 {
+        let mut stream = RlpStream::new_list(3);
+        stream.append(&self.chain_id);
+        stream.append(&self.address);
+        stream.append(&self.nonce);
+        let mut payload = vec![AUTHORIZATION_MAGIC];
+        payload.extend_from_slice(&stream.out());
+        H256(keccak256(payload))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignedAuthorization {
+    pub auth: Authorization,
+    pub y_parity: u8,
+    pub r: U256,
+    pub s: U256,
+}
+
+impl SignedAuthorization {
+    /// The EOA that signed the authorization
+    pub fn authority(&self) -> Result<Address, AuthorizationError> {
+        if self.y_parity > 1 || self.s > SECP256K1N_HALF {
+            return Err(AuthorizationError::InvalidSignature(
+                "non-canonical signature".into(),
+            ));
+        }
+        let signature = Signature {
+            r: self.r,
+            s: self.s,
+            v: 27 + self.y_parity as u64,
+        };
+        signature
+            .recover(self.auth.signing_hash())
+            .map_err(|e| AuthorizationError::InvalidSignature(e.to_string()))
+    }
+
+    /// Append `[chain_id, address, nonce, y_parity, r, s]` to an authorization list
+    pub fn rlp_append(&self, stream: &mut RlpStream) {
+        stream.begin_list(6);
+        stream.append(&self.auth.chain_id);
+        stream.append(&self.auth.address);
+        stream.append(&self.auth.nonce);
+        stream.append(&self.y_parity);
+        stream.append(&self.r);
+        stream.append(&self.s);
+    }
+
+    /// Decode an entry of an authorization list, see [SignedAuthorization::rlp_append]
+    pub fn rlp_decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        if rlp.item_count()? != 6 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        Ok(Self {
+            auth: Authorization {
+                chain_id: rlp.val_at(0)?,
+                address: rlp.val_at(1)?,
+                nonce: rlp.val_at(2)?,
+            },
+            y_parity: rlp.val_at(3)?,
+            r: rlp.val_at(4)?,
+            s: rlp.val_at(5)?,
+        })
+    }
+}
+
+/// Code of an EOA delegating to `delegate`
+pub fn delegation_code(delegate: Address) -> Bytecode {
+    let mut code = DELEGATION_PREFIX.to_vec();
+    code.extend_from_slice(delegate.as_bytes());
+    Bytecode::new_raw(bytes::Bytes::from(code))
+}
+
+/// The delegate of an account with `code`, `None` for accounts that aren't delegated
+pub fn delegated_to(code: &[u8]) -> Option<B160> {
+    // analysed code is padded, the designator itself is always 23 bytes
+    if code.len() < DESIGNATOR_LEN || code[..DELEGATION_PREFIX.len()] != DELEGATION_PREFIX {
+        return None;
+    }
+    Some(B160::from_slice(
+        &code[DELEGATION_PREFIX.len()..DESIGNATOR_LEN],
+    ))
+}
+
+/// Process the authorization list of a type-4 tx sent by `sender` on chain `chain_id`, in order.
+/// Each entry returns its authority or why it was skipped, invalid entries don't fail the tx.
+///
+/// The tx's own nonce increment comes first on chain, so a sender delegating its own account
+/// signs for its nonce + 1. The increment itself is left to the EVM, which leaves the sender's
+/// nonce one ahead of the tx: simulate it with `nonce: None`.
+pub fn apply_authorizations<ExtDB>(
+    db: &mut CacheDB<ExtDB>,
+    chain_id: u64,
+    sender: Address,
+    authorizations: &[SignedAuthorization],
+) -> Vec<Result<Address, AuthorizationError>>
+where
+    ExtDB: DatabaseRef,
+    ExtDB::Error: std::fmt::Debug,
+{
+    authorizations
+        .iter()
+        .map(|signed| {
+            let auth = signed.auth;
+            if !auth.chain_id.is_zero() && auth.chain_id != U256::from(chain_id) {
+                return Err(AuthorizationError::WrongChain(auth.chain_id));
+            }
+            let authority = signed.authority()?;
+            let mut info = Database::basic(db, h160_to_b160(authority))
+                .map_err(|e| AuthorizationError::Database(format!("{:?}", e)))?
+                .unwrap_or_default();
+
+            let code = match &info.code {
+                Some(code) => code.clone(),
+                None if info.code_hash == KECCAK_EMPTY => Bytecode::new(),
+                None => Database::code_by_hash(db, info.code_hash)
+                    .map_err(|e| AuthorizationError::Database(format!("{:?}", e)))?,
+            };
+            if !code.is_empty() && delegated_to(code.bytes()).is_none() {
+                return Err(AuthorizationError::HasCode(authority));
+            }
+
+            let expected = info.nonce + u64::from(authority == sender);
+            if auth.nonce != expected {
+                return Err(AuthorizationError::NonceMismatch {
+                    authority,
+                    expected,
+                    got: auth.nonce,
+                });
+            }
+
+            // the sender's own increment is left to the EVM
+            info.nonce = auth.nonce + u64::from(authority != sender);
+            if auth.address.is_zero() {
+                info.code = Some(Bytecode::new());
+                info.code_hash = KECCAK_EMPTY;
+            } else {
+                let code = delegation_code(auth.address);
+                info.code_hash = B256(keccak256(code.bytes()));
+                info.code = Some(code);
+            }
+            db.insert_account_info(h160_to_b160(authority), info);
+            Ok(authority)
+        })
+        .collect()
+}
+
+/// Wraps a database to run the delegate's code for delegated accounts
+#[derive(Debug, Clone)]
+pub struct DelegationDb<DB> {
+    db: DB,
+}
+
+impl<DB> DelegationDb<DB> {
+    pub fn new(db: DB) -> Self {
+        Self { db }
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.db
+    }
+
+    pub fn into_inner(self) -> DB {
+        self.db
+    }
+}
+
+/// Delegate of `info`, loading its code by hash if needed
+fn delegate_of<E>(
+    info: &AccountInfo,
+    code_by_hash: impl FnOnce(B256) -> Result<Bytecode, E>,
+) -> Result<Option<B160>, E> {
+    let code = match &info.code {
+        Some(code) => return Ok(delegated_to(code.bytes())),
+        None if info.code_hash == KECCAK_EMPTY => return Ok(None),
+        None => code_by_hash(info.code_hash)?,
+    };
+    Ok(delegated_to(code.bytes()))
+}
+
+impl<DB: Database> Database for DelegationDb<DB> {
+    type Error = DB::Error;
+
+    fn basic(&mut self, address: B160) -> Result<Option<AccountInfo>, Self::Error> {
+        let Some(mut info) = self.db.basic(address)? else {
+            return Ok(None);
+        };
+        if let Some(delegate) = delegate_of(&info, |hash| self.db.code_by_hash(hash))? {
+            // designators aren't followed further, a delegate's own designator doesn't execute
+            info.code = Some(match self.db.basic(delegate)? {
+                Some(AccountInfo {
+                    code: Some(code), ..
+                }) => code,
+                Some(delegate) if delegate.code_hash != KECCAK_EMPTY => {
+                    self.db.code_by_hash(delegate.code_hash)?
+                }
+                _ => Bytecode::new(),
+            });
+        }
+        Ok(Some(info))
+    }
+
+    fn code_by_hash(&mut self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        self.db.code_by_hash(code_hash)
+    }
+
+    fn storage(&mut self, address: B160, index: rU256) -> Result<rU256, Self::Error> {
+        self.db.storage(address, index)
+    }
+
+    fn block_hash(&mut self, number: rU256) -> Result<B256, Self::Error> {
+        self.db.block_hash(number)
+    }
+}
+
+impl<DB: DatabaseRef> DatabaseRef for DelegationDb<DB> {
+    type Error = DB::Error;
+
+    fn basic(&self, address: B160) -> Result<Option<AccountInfo>, Self::Error> {
+        let Some(mut info) = self.db.basic(address)? else {
+            return Ok(None);
+        };
+        if let Some(delegate) = delegate_of(&info, |hash| self.db.code_by_hash(hash))? {
+            info.code = Some(match self.db.basic(delegate)? {
+                Some(AccountInfo {
+                    code: Some(code), ..
+                }) => code,
+                Some(delegate) if delegate.code_hash != KECCAK_EMPTY => {
+                    self.db.code_by_hash(delegate.code_hash)?
+                }
+                _ => Bytecode::new(),
+            });
+        }
+        Ok(Some(info))
+    }
+
+    fn code_by_hash(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        self.db.code_by_hash(code_hash)
+    }
+
+    fn storage(&self, address: B160, index: rU256) -> Result<rU256, Self::Error> {
+        self.db.storage(address, index)
+    }
+
+    fn block_hash(&self, number: rU256) -> Result<B256, Self::Error> {
+        self.db.block_hash(number)
+    }
+}
+
+impl<DB: DatabaseCommit> DatabaseCommit for DelegationDb<DB> {
+    fn commit(&mut self, changes: HashMap<B160, Account>) {
+        // resolved code is never written back, revm only commits code of created contracts
+        self.db.commit(changes)
+    }
+}
+
+/// Whether `address` is an EOA delegating its code, e.g. a victim calling through a smart wallet
+pub fn is_delegated<DB>(db: &DB, address: Address) -> Result<Option<Address>, DB::Error>
+where
+    DB: DatabaseRef,
+{
+    let Some(info) = db.basic(h160_to_b160(address))? else {
+        return Ok(None);
+    };
+    Ok(delegate_of(&info, |hash| db.code_by_hash(hash))?.map(b160_to_h160))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::{LocalWallet, Signer};
+    use revm::{
+        db::EmptyDB,
+        primitives::{ExecutionResult, TransactTo, TxEnv},
+        EVM,
+    };
+
+    fn sign(wallet: &LocalWallet, auth: Authorization) -> SignedAuthorization {
+        let signature = wallet.sign_hash(auth.signing_hash()).unwrap();
+        SignedAuthorization {
+            auth,
+            y_parity: (signature.v - 27) as u8,
+            r: signature.r,
+            s: signature.s,
+        }
+    }
+
+    #[test]
+    fn test_delegated_eoa_runs_delegate_code() {
+        let wallet: LocalWallet =
+            "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+                .parse()
+                .unwrap();
+        let eoa = wallet.address();
+        let delegate = Address::from_low_u64_be(0xde);
+        let sender = Address::from_low_u64_be(1);
+
+        let mut db = CacheDB::new(EmptyDB::default());
+        // PUSH1 0x2a PUSH1 0 SSTORE STOP
+        db.insert_account_info(
+            h160_to_b160(delegate),
+            AccountInfo {
+                code: Some(Bytecode::new_raw(bytes::Bytes::from_static(&[
+                    0x60, 0x2a, 0x60, 0x00, 0x55, 0x00,
+                ]))),
+                ..Default::default()
+            },
+        );
+        db.insert_account_info(
+            h160_to_b160(sender),
+            AccountInfo {
+                balance: rU256::from(10u64.pow(18)),
+                ..Default::default()
+            },
+        );
+
+        let auths = [
+            sign(
+                &wallet,
+                Authorization {
+                    chain_id: U256::from(1),
+                    address: delegate,
+                    nonce: 0,
+                },
+            ),
+            // replayed on another chain
+            sign(
+                &wallet,
+                Authorization {
+                    chain_id: U256::from(10),
+                    address: delegate,
+                    nonce: 1,
+                },
+            ),
+        ];
+        let mut stream = RlpStream::new();
+        auths[0].rlp_append(&mut stream);
+        let encoded = stream.out();
+        assert_eq!(
+            SignedAuthorization::rlp_decode(&Rlp::new(&encoded)),
+            Ok(auths[0])
+        );
+
+        let applied = apply_authorizations(&mut db, 1, sender, &auths);
+        assert_eq!(applied[0], Ok(eoa));
+        assert_eq!(
+            applied[1],
+            Err(AuthorizationError::WrongChain(U256::from(10)))
+        );
+        assert_eq!(is_delegated(&db, eoa).unwrap(), Some(delegate));
+        assert_eq!(
+            DatabaseRef::basic(&db, h160_to_b160(eoa))
+                .unwrap()
+                .unwrap()
+                .nonce,
+            1
+        );
+
+        let mut evm = EVM::new();
+        evm.database(DelegationDb::new(db));
+        evm.env.tx = TxEnv {
+            caller: h160_to_b160(sender),
+            transact_to: TransactTo::Call(h160_to_b160(eoa)),
+            gas_limit: 100_000,
+            ..Default::default()
+        };
+        let result = evm.transact_commit().unwrap();
+        assert!(matches!(result, ExecutionResult::Success { .. }));
+
+        // the delegate's code ran in the EOA's storage
+        let db = evm.db.take().unwrap().into_inner();
+        let stored = DatabaseRef::storage(&db, h160_to_b160(eoa), rU256::ZERO).unwrap();
+        assert_eq!(stored, rU256::from(0x2a));
+    }
+}
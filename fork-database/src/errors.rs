@@ -9,8 +9,12 @@ use std::{
 
 #[derive(Debug, thiserror::Error)]
 pub enum DatabaseError {
-    #[error("Could should already be loaded: {0:?}")]
+    #[error("Code {0:?} is not cached and no account holding it is known")]
     MissingCode(H256),
+    #[error("Code of {1:?} no longer hashes to {0:?}")]
+    CodeHashMismatch(H256, Address),
+    #[error("Failed to get code {0:?} of {1:?}: {2:?}")]
+    GetCode(H256, Address, Arc<eyre::Error>),
     #[error("{0}")]
     Message(String),
     #[error("Failed to get account for {0:?}: {0:?}")]
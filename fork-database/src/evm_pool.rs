@@ -0,0 +1,184 @@
+//! Reusing EVM instances across simulations
+//!
+//! Evaluating a candidate used to build a fresh [EVM] and clone a full [Env] for every
+//! simulation. An [EvmPool] keeps idle instances preconfigured with the block's env: a checkout
+//! only swaps in the database and the tx, and the instance goes back to the pool when the
+//! [PooledEvm] is dropped, with its database taken out and the tx cleared. The access list
+//! buffer of the tx env keeps its capacity between runs.
+//!
+//! Interpreter memory itself is allocated by revm per call frame and can't be handed in from
+//! outside with revm 3, shared memory only comes with later versions.
+
+use std::{
+    mem,
+    ops::{Deref, DerefMut},
+};
+
+use parking_lot::{Mutex, RwLock};
+use revm::{
+    primitives::{Env, TxEnv},
+    Database, EVM,
+};
+
+use crate::{
+    errors::DatabaseResult,
+    sim_env::{run_simulation, SimOutcome},
+};
+
+/// Idle instances kept by default, about one per simulation thread
+pub const DEFAULT_MAX_IDLE: usize = 32;
+
+pub struct EvmPool<DB> {
+    idle: Mutex<Vec<EVM<DB>>>,
+    /// block and cfg env every checkout starts from
+    env: RwLock<Env>,
+    max_idle: usize,
+}
+
+impl<DB> EvmPool<DB> {
+    pub fn new(env: Env) -> Self {
+        Self {
+            idle: Mutex::new(Vec::new()),
+            env: RwLock::new(env),
+            max_idle: DEFAULT_MAX_IDLE,
+        }
+    }
+
+    pub fn with_max_idle(mut self, max_idle: usize) -> Self {
+        self.max_idle = max_idle;
+        self
+    }
+
+    /// Env of new checkouts, e.g. on every new block. Idle instances are updated on checkout.
+    pub fn set_env(&self, env: Env) {
+        *self.env.write() = env;
+    }
+
+    pub fn idle(&self) -> usize {
+        self.idle.lock().len()
+    }
+
+    /// An instance executing `tx` on `db` with the pool's block and cfg env
+    pub fn checkout(&self, db: DB, tx: TxEnv) -> PooledEvm<'_, DB> {
+        let mut evm = self.idle.lock().pop().unwrap_or_else(EVM::new);
+        {
+            let env = self.env.read();
+            evm.env.cfg.clone_from(&env.cfg);
+            evm.env.block.clone_from(&env.block);
+        }
+        evm.env.tx = tx;
+        evm.database(db);
+        PooledEvm {
+            evm: Some(evm),
+            pool: self,
+        }
+    }
+
+    /// [simulate](crate::sim_env::simulate) `tx` on `db` with a pooled instance
+    pub fn simulate(&self, db: DB, tx: TxEnv) -> DatabaseResult<SimOutcome>
+    where
+        DB: Database,
+        DB::Error: std::fmt::Debug,
+    {
+        run_simulation(&mut self.checkout(db, tx))
+    }
+
+    fn release(&self, mut evm: EVM<DB>) {
+        evm.db = None;
+        // keep the access list's buffer, drop the rest of the tx
+        let mut access_list = mem::take(&mut evm.env.tx.access_list);
+        access_list.clear();
+        evm.env.tx = TxEnv {
+            access_list,
+            ..Default::default()
+        };
+
+        let mut idle = self.idle.lock();
+        if idle.len() < self.max_idle {
+            idle.push(evm);
+        }
+    }
+}
+
+/// An [EVM] checked out of an [EvmPool], returned to it on drop
+pub struct PooledEvm<'a, DB> {
+    evm: Option<EVM<DB>>,
+    pool: &'a EvmPool<DB>,
+}
+
+impl<DB> PooledEvm<'_, DB> {
+    /// Take the database back out, e.g. a `CacheDB` holding the simulation's writes
+    pub fn take_db(&mut self) -> Option<DB> {
+        self.evm.as_mut().and_then(|evm| evm.db.take())
+    }
+}
+
+impl<DB> Deref for PooledEvm<'_, DB> {
+    type Target = EVM<DB>;
+
+    fn deref(&self) -> &Self::Target {
+        self.evm.as_ref().expect("evm is only taken on drop")
+    }
+}
+
+impl<DB> DerefMut for PooledEvm<'_, DB> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.evm.as_mut().expect("evm is only taken on drop")
+    }
+}
+
+impl<DB> Drop for PooledEvm<'_, DB> {
+    fn drop(&mut self) {
+        if let Some(evm) = self.evm.take() {
+            self.pool.release(evm);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::{
+        db::{CacheDB, EmptyDB},
+        primitives::{AccountInfo, ExecutionResult, TransactTo, B160, U256 as rU256},
+    };
+
+    #[test]
+    fn test_instances_are_reused() {
+        let sender = B160::from_low_u64_be(1);
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            sender,
+            AccountInfo {
+                balance: rU256::from(10u64.pow(18)),
+                ..Default::default()
+            },
+        );
+        let mut env = Env::default();
+        env.block.number = rU256::from(7u64);
+        let pool = EvmPool::new(env).with_max_idle(1);
+        let transfer = TxEnv {
+            caller: sender,
+            transact_to: TransactTo::Call(B160::from_low_u64_be(2)),
+            value: rU256::from(1u64),
+            gas_limit: 21_000,
+            ..Default::default()
+        };
+
+        let outcome = pool.simulate(db.clone(), transfer.clone()).unwrap();
+        assert!(matches!(outcome.result, ExecutionResult::Success { .. }));
+        assert_eq!(pool.idle(), 1);
+
+        // the idle instance is handed out again, with the new block env and no leftover db
+        let mut next = Env::default();
+        next.block.number = rU256::from(8u64);
+        pool.set_env(next);
+        {
+            let mut evm = pool.checkout(db, transfer);
+            assert_eq!(pool.idle(), 0);
+            assert_eq!(evm.env.block.number, rU256::from(8u64));
+            assert!(evm.take_db().is_some());
+        }
+        assert_eq!(pool.idle(), 1);
+    }
+}
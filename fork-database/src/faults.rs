@@ -0,0 +1,308 @@
+//! Fault injection for robustness tests
+//!
+//! Strategies are expected to back off, not to trade, when the node is slow, drops requests or
+//! serves garbage, and when relays rate limit us. [FaultyDb] wraps a db, e.g. the
+//! [SharedBackend](crate::shared_backend::SharedBackend) as the backend of a
+//! [ForkedDatabase](crate::forked_db::ForkedDatabase), delaying, dropping and corrupting its
+//! responses, and [FaultInjector::relay] decides how a relay answers, the mock relay asks it
+//! before every request. Both are driven by a [FaultConfig], the `faults` table in the `test`
+//! section of the bot's config. Faults are drawn from a seeded generator so a failing run can be
+//! replayed.
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use ethers::types::{Block, BlockId, Transaction};
+use parking_lot::Mutex;
+use revm::{
+    db::DatabaseRef,
+    primitives::{AccountInfo, Bytecode, B160, B256, U256 as rU256},
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::errors::{DatabaseError, DatabaseResult};
+use crate::forked_db::PinnedBackend;
+
+/// Rates are probabilities between 0 and 1, everything is off by default
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultConfig {
+    pub seed: u64,
+    /// share of responses delayed, by up to `max_latency_ms`
+    pub latency_rate: f64,
+    pub max_latency_ms: u64,
+    /// share of db requests failing as if the node never answered
+    pub drop_rate: f64,
+    /// share of storage reads returning a flipped value
+    pub corrupt_storage_rate: f64,
+    /// share of relay submissions answered with `429 Too Many Requests`
+    pub relay_rate_limit_rate: f64,
+}
+
+impl FaultConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.latency_rate > 0.0
+            || self.drop_rate > 0.0
+            || self.corrupt_storage_rate > 0.0
+            || self.relay_rate_limit_rate > 0.0
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RelayFault {
+    #[error("429 Too Many Requests (injected)")]
+    RateLimited,
+}
+
+/// Draws the faults of a [FaultConfig], shared by all the layers of a run
+#[derive(Debug, Clone)]
+pub struct FaultInjector {
+    config: FaultConfig,
+    /// xorshift64 state
+    state: Arc<Mutex<u64>>,
+}
+
+impl FaultInjector {
+    pub fn new(config: FaultConfig) -> Self {
+        // xorshift never leaves 0
+        let state = Arc::new(Mutex::new(config.seed.max(1)));
+        Self { config, state }
+    }
+
+    pub fn config(&self) -> &FaultConfig {
+        &self.config
+    }
+
+    fn next(&self) -> u64 {
+        let mut state = self.state.lock();
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    fn roll(&self, rate: f64) -> bool {
+        rate > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < rate
+    }
+
+    /// Delay to add to the next response, if any
+    pub fn latency(&self) -> Option<Duration> {
+        if self.config.max_latency_ms == 0 || !self.roll(self.config.latency_rate) {
+            return None;
+        }
+        Some(Duration::from_millis(
+            1 + self.next() % self.config.max_latency_ms,
+        ))
+    }
+
+    pub fn drops(&self) -> bool {
+        self.roll(self.config.drop_rate)
+    }
+
+    /// `value` or, now and then, a corrupted copy of it
+    pub fn corrupt(&self, value: rU256) -> rU256 {
+        if !self.roll(self.config.corrupt_storage_rate) {
+            return value;
+        }
+        value ^ (rU256::from(1u64) << (self.next() % 256) as usize)
+    }
+
+    /// What the relay answers the next submission, after the injected latency
+    pub async fn relay(&self) -> Result<(), RelayFault> {
+        if let Some(delay) = self.latency() {
+            tokio::time::sleep(delay).await;
+        }
+        if self.roll(self.config.relay_rate_limit_rate) {
+            return Err(RelayFault::RateLimited);
+        }
+        Ok(())
+    }
+}
+
+/// `db` with the db faults of an [FaultInjector] injected into its responses
+#[derive(Debug, Clone)]
+pub struct FaultyDb<DB> {
+    db: DB,
+    faults: FaultInjector,
+}
+
+impl<DB> FaultyDb<DB> {
+    pub fn new(db: DB, faults: FaultInjector) -> Self {
+        Self { db, faults }
+    }
+
+    pub fn inner(&self) -> &DB {
+        &self.db
+    }
+
+    fn before_response(&self, request: &str) -> Result<(), DatabaseError> {
+        if let Some(delay) = self.faults.latency() {
+            thread::sleep(delay);
+        }
+        if self.faults.drops() {
+            return Err(DatabaseError::msg(format!(
+                "injected fault: `{}` dropped",
+                request
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl<DB> DatabaseRef for FaultyDb<DB>
+where
+    DB: DatabaseRef,
+    DatabaseError: From<DB::Error>,
+{
+    type Error = DatabaseError;
+
+    fn basic(&self, address: B160) -> Result<Option<AccountInfo>, Self::Error> {
+        self.before_response("basic")?;
+        Ok(self.db.basic(address)?)
+    }
+
+    fn code_by_hash(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        self.before_response("code_by_hash")?;
+        Ok(self.db.code_by_hash(code_hash)?)
+    }
+
+    fn storage(&self, address: B160, index: rU256) -> Result<rU256, Self::Error> {
+        self.before_response("storage")?;
+        Ok(self.faults.corrupt(self.db.storage(address, index)?))
+    }
+
+    fn block_hash(&self, number: rU256) -> Result<B256, Self::Error> {
+        self.before_response("block_hash")?;
+        Ok(self.db.block_hash(number)?)
+    }
+}
+
+impl<DB> PinnedBackend for FaultyDb<DB>
+where
+    DB: PinnedBackend,
+    DatabaseError: From<DB::Error>,
+{
+    fn set_pinned_block(&self, block: BlockId) -> eyre::Result<()> {
+        self.db.set_pinned_block(block)
+    }
+
+    fn get_full_block(&self, block: BlockId) -> DatabaseResult<Block<Transaction>> {
+        self.before_response("full_block")?;
+        self.db.get_full_block(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blockchain_db::{BlockchainDb, BlockchainDbMeta},
+        forked_db::ForkedDatabase,
+    };
+    use revm::db::{CacheDB, EmptyDB};
+    use serde_json::json;
+    use std::collections::BTreeSet;
+
+    /// Storage of one slot holding 7
+    #[derive(Clone)]
+    struct Slot;
+
+    impl PinnedBackend for Slot {
+        fn set_pinned_block(&self, _: BlockId) -> eyre::Result<()> {
+            Ok(())
+        }
+
+        fn get_full_block(&self, _: BlockId) -> DatabaseResult<Block<Transaction>> {
+            Ok(Block::default())
+        }
+    }
+
+    impl DatabaseRef for Slot {
+        type Error = DatabaseError;
+
+        fn basic(&self, _: B160) -> Result<Option<AccountInfo>, Self::Error> {
+            Ok(None)
+        }
+
+        fn code_by_hash(&self, _: B256) -> Result<Bytecode, Self::Error> {
+            Ok(Bytecode::new())
+        }
+
+        fn storage(&self, _: B160, _: rU256) -> Result<rU256, Self::Error> {
+            Ok(rU256::from(7u64))
+        }
+
+        fn block_hash(&self, _: rU256) -> Result<B256, Self::Error> {
+            Ok(B256::zero())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_injected_faults() {
+        let config: FaultConfig =
+            serde_json::from_value(json!({ "seed": 3, "corrupt_storage_rate": 1.0 })).unwrap();
+        assert!(config.is_enabled());
+        assert!(!FaultConfig::default().is_enabled());
+        assert!(serde_json::from_value::<FaultConfig>(json!({ "drop_rte": 1.0 })).is_err());
+
+        let corrupting = FaultyDb::new(Slot, FaultInjector::new(config));
+        let value = corrupting.storage(B160::zero(), rU256::ZERO).unwrap();
+        assert_ne!(value, rU256::from(7u64));
+        assert_eq!((value ^ rU256::from(7u64)).count_ones(), 1);
+
+        let dropping = FaultyDb::new(
+            Slot,
+            FaultInjector::new(FaultConfig {
+                drop_rate: 1.0,
+                ..Default::default()
+            }),
+        );
+        assert!(dropping.basic(B160::zero()).is_err());
+        assert!(dropping.get_full_block(BlockId::from(1u64)).is_err());
+        assert!(dropping.set_pinned_block(BlockId::from(1u64)).is_ok());
+
+        // off by default
+        let faults = FaultInjector::new(FaultConfig::default());
+        let healthy = FaultyDb::new(Slot, faults.clone());
+        assert_eq!(
+            healthy.storage(B160::zero(), rU256::ZERO).unwrap(),
+            rU256::from(7u64)
+        );
+        assert_eq!(faults.relay().await, Ok(()));
+
+        let faults = FaultInjector::new(FaultConfig {
+            relay_rate_limit_rate: 1.0,
+            latency_rate: 1.0,
+            max_latency_ms: 2,
+            ..Default::default()
+        });
+        assert!(faults.latency().unwrap() <= Duration::from_millis(2));
+        assert_eq!(faults.relay().await, Err(RelayFault::RateLimited));
+    }
+
+    #[test]
+    fn test_faulty_fork_backend() {
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        let fork = |config: FaultConfig| {
+            let backend =
+                FaultyDb::new(CacheDB::new(EmptyDB::default()), FaultInjector::new(config));
+            ForkedDatabase::new(backend, BlockchainDb::new(meta.clone(), None))
+        };
+
+        let healthy = fork(FaultConfig::default());
+        assert!(DatabaseRef::basic(&healthy, B160::zero()).is_ok());
+        // the node never answers, nothing reads as empty state
+        let dropping = fork(FaultConfig {
+            drop_rate: 1.0,
+            ..Default::default()
+        });
+        assert!(DatabaseRef::basic(&dropping, B160::zero()).is_err());
+        assert!(DatabaseRef::storage(&dropping, B160::zero(), rU256::ZERO).is_err());
+    }
+}
@@ -1,20 +1,33 @@
 // ported from foundry's executor with some modifications
 // https://github.com/foundry-rs/foundry/blob/master/evm/src/executor/fork/database.rs
 use super::{
-    blockchain_db::BlockchainDb, errors::DatabaseError, shared_backend::SharedBackend,
+    blockchain_db::BlockchainDb,
+    cache_flush::{CacheFlushConfig, CacheFlusher},
+    errors::{DatabaseError, DatabaseResult},
+    shared_backend::SharedBackend,
     snapshot::StateSnapshot,
+    utils::{b256_to_h256, h160_to_b160, h256_to_b256, u256_to_ru256},
+};
+use ethers::{
+    prelude::U256,
+    types::{AccountDiff, Address, Block, BlockId, Transaction},
 };
-use ethers::{prelude::U256, types::BlockId};
 use hashbrown::HashMap as Map;
 use log::{trace, warn};
 use parking_lot::Mutex;
-use revm::db::CacheDB;
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
+use revm::db::{AccountState, CacheDB};
 use revm::{
     db::DatabaseRef,
     primitives::{Account, AccountInfo, Bytecode, B160, B256, U256 as rU256},
     Database, DatabaseCommit,
 };
-use std::sync::Arc;
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
 
 /// a [revm::Database] that's forked off another client
 ///
@@ -22,29 +35,55 @@ use std::sync::Arc;
 /// endpoint. The inner in-memory database holds this storage and will be used for write operations.
 /// This database uses the `backend` for read and the `db` for write operations. But note the
 /// `backend` will also write (missing) data to the `db` in the background
+///
+/// The backend is the [SharedBackend] fetching from the node, any other [DatabaseRef] over the
+/// same `db` can stand in for it, e.g. a [LocalBackend](crate::local_backend::LocalBackend) or a
+/// [FaultyDb](crate::faults::FaultyDb) wrapping the shared one.
 #[derive(Debug, Clone)]
-pub struct ForkedDatabase {
+pub struct ForkedDatabase<B = SharedBackend> {
     /// responsible for fetching missing data
     ///
     /// This is responsible for getting data
-    backend: SharedBackend,
+    backend: B,
     /// Cached Database layer, ensures that changes are not written to the database that
     /// exclusively stores the state of the remote client.
     ///
     /// This separates Read/Write operations
     ///   - reads from the `SharedBackend as DatabaseRef` writes to the internal cache storage
-    cache_db: CacheDB<SharedBackend>,
+    cache_db: CacheDB<B>,
     /// Contains all the data already fetched
     ///
     /// This exclusively stores the _unchanged_ remote client state
     db: BlockchainDb,
-    /// holds the snapshot state of a blockchain
-    snapshots: Arc<Mutex<Snapshots<ForkDbSnapshot>>>,
+    /// holds the snapshot state of a blockchain, shared so sweeps can read one without holding
+    /// the lock
+    snapshots: Arc<Mutex<Snapshots<Arc<ForkDbSnapshot<B>>>>>,
 }
 
-impl ForkedDatabase {
+/// Backend a [ForkedDatabase] can be reset through, see [ForkedDatabase::reset], and the
+/// [SimEnv](crate::sim_env::SimEnv) reads block headers from
+pub trait PinnedBackend: DatabaseRef + Clone {
+    /// Fetch state at `block` from now on
+    fn set_pinned_block(&self, block: BlockId) -> eyre::Result<()>;
+
+    fn get_full_block(&self, block: BlockId) -> DatabaseResult<Block<Transaction>>;
+}
+
+/// Threads [ForkedDatabase::sweep] runs simulations on, one per core, kept apart from the global
+/// rayon pool so sweeps don't queue behind state diff merging and the like
+pub fn sim_pool() -> &'static ThreadPool {
+    static SIM_POOL: OnceLock<ThreadPool> = OnceLock::new();
+    SIM_POOL.get_or_init(|| {
+        ThreadPoolBuilder::new()
+            .thread_name(|i| format!("sim-{}", i))
+            .build()
+            .expect("failed to build the simulation thread pool")
+    })
+}
+
+impl<B: DatabaseRef + Clone> ForkedDatabase<B> {
     /// Creates a new instance of this DB
-    pub fn new(backend: SharedBackend, db: BlockchainDb) -> Self {
+    pub fn new(backend: B, db: BlockchainDb) -> Self {
         Self {
             cache_db: CacheDB::new(backend.clone()),
             backend,
@@ -53,93 +92,125 @@ impl ForkedDatabase {
         }
     }
 
-    pub fn database(&self) -> &CacheDB<SharedBackend> {
+    /// Returns the backend used to fetch missing data from the remote
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn database(&self) -> &CacheDB<B> {
         &self.cache_db
     }
 
-    pub fn database_mut(&mut self) -> &mut CacheDB<SharedBackend> {
+    pub fn database_mut(&mut self) -> &mut CacheDB<B> {
         &mut self.cache_db
     }
 
-    pub fn snapshots(&self) -> &Arc<Mutex<Snapshots<ForkDbSnapshot>>> {
+    pub fn snapshots(&self) -> &Arc<Mutex<Snapshots<Arc<ForkDbSnapshot<B>>>>> {
         &self.snapshots
     }
 
-    /// Reset the fork to a fresh forked state, and optionally update the fork config
-    pub fn reset(&mut self, block_number: impl Into<BlockId>) -> Result<(), String> {
-        self.backend
-            .set_pinned_block(block_number)
-            .map_err(|err| err.to_string())?;
-
-        // TODO need to find a way to update generic provider via url
-
-        // wipe the storage retrieved from remote
-        self.inner().db().clear();
-        // create a fresh `CacheDB`, effectively wiping modified state
-        self.cache_db = CacheDB::new(self.backend.clone());
-        trace!(target: "backend::forkdb", "Cleared database");
-        Ok(())
-    }
-
-    /// Flushes the cache to disk if configured
+    /// Flushes the cache to disk if configured, blocking until it's written. Long running
+    /// processes should prefer [Self::spawn_cache_flusher].
     pub fn flush_cache(&self) {
         self.db.cache().flush()
     }
 
+    /// Flush the cache from a background task, see [CacheFlusher]
+    pub fn spawn_cache_flusher(&self, config: CacheFlushConfig) -> CacheFlusher {
+        CacheFlusher::spawn(self.db.cache().clone(), config)
+    }
+
     /// Returns the database that holds the remote state
     pub fn inner(&self) -> &BlockchainDb {
         &self.db
     }
 
-    pub fn create_snapshot(&self) -> ForkDbSnapshot {
-        let db = self.db.db();
-        let snapshot = StateSnapshot {
-            accounts: db.accounts.read().clone(),
-            storage: db.storage.read().clone(),
-            block_hashes: db.block_hashes.read().clone(),
-        };
-        ForkDbSnapshot {
-            local: self.cache_db.clone(),
-            snapshot,
+    /// Applies a block's trace state diff to the remote state and evicts the touched accounts from
+    /// the local cache, so they are re-read from the updated state.
+    ///
+    /// Destroyed accounts are remembered as known absent and won't be re-fetched from the remote.
+    pub fn apply_block_diff(&mut self, diff: &BTreeMap<Address, AccountDiff>) {
+        let touched = self.db.apply_block_diff(diff);
+        for address in touched {
+            self.cache_db.accounts.remove(&address);
+        }
+        trace!(target: "backend::forkdb", "Applied block diff for {} accounts", diff.len());
+    }
+
+    /// Applies a block's balance changes to the remote state, a cheaper alternative to
+    /// [ForkedDatabase::apply_block_diff] for blocks that only need balances refreshed
+    pub fn apply_balance_changes(&mut self, balances: &BTreeMap<Address, ethers::types::U256>) {
+        for address in self.db.apply_balance_changes(balances) {
+            self.cache_db.accounts.remove(&address);
         }
     }
 
+    /// Returns the slots of `address` modified locally on top of the remote state, ordered by slot
+    pub fn modified_storage_of(&self, address: B160) -> impl Iterator<Item = (rU256, rU256)> {
+        let slots: BTreeMap<rU256, rU256> = self
+            .cache_db
+            .accounts
+            .get(&address)
+            .map(|account| account.storage.iter().map(|(k, v)| (*k, *v)).collect())
+            .unwrap_or_default();
+        slots.into_iter()
+    }
+
+    /// Dumps both the remote (cached) and the locally modified storage of `address` as JSON,
+    /// handy to see why a simulation diverges from on-chain execution
+    pub fn dump_storage_json(&self, address: B160) -> serde_json::Value {
+        let modified: BTreeMap<rU256, rU256> = self.modified_storage_of(address).collect();
+        serde_json::json!({
+            "remote": self.db.dump_storage_json(address),
+            "modified": modified,
+        })
+    }
+
+    /// Writes [Self::dump_storage_json] to `path`
+    pub fn write_storage_dump(&self, address: B160, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(
+            std::io::BufWriter::new(file),
+            &self.dump_storage_json(address),
+        )
+        .map_err(std::io::Error::from)
+    }
+
+    pub fn create_snapshot(&self) -> ForkDbSnapshot<B> {
+        let snapshot = self.db.db().to_state_snapshot();
+        ForkDbSnapshot::new(self.cache_db.clone(), snapshot)
+    }
+
     pub fn insert_snapshot(&self) -> U256 {
         let snapshot = self.create_snapshot();
         let mut snapshots = self.snapshots().lock();
-        let id = snapshots.insert(snapshot);
+        let id = snapshots.insert(Arc::new(snapshot));
         trace!(target: "backend::forkdb", "Created new snapshot {}", id);
         id
     }
 
+    /// Same as [Self::insert_snapshot], but the snapshot is never evicted by the [SnapshotLimits]
+    pub fn insert_labeled_snapshot(&self, label: impl Into<String>) -> U256 {
+        let snapshot = self.create_snapshot();
+        let mut snapshots = self.snapshots().lock();
+        let id = snapshots.insert_labeled(Arc::new(snapshot), label);
+        trace!(target: "backend::forkdb", "Created new labeled snapshot {}", id);
+        id
+    }
+
+    /// Configures the limits enforced on the retained snapshots
+    pub fn set_snapshot_limits(&self, limits: SnapshotLimits) {
+        self.snapshots().lock().set_limits(limits);
+    }
+
     pub fn revert_snapshot(&mut self, id: U256) -> bool {
         let snapshot = { self.snapshots().lock().remove(id) };
         if let Some(snapshot) = snapshot {
+            // a sweep still reading it keeps its copy
             let ForkDbSnapshot {
-                local,
-                snapshot:
-                    StateSnapshot {
-                        accounts,
-                        storage,
-                        block_hashes,
-                    },
-            } = snapshot;
-            let db = self.inner().db();
-            {
-                let mut accounts_lock = db.accounts.write();
-                accounts_lock.clear();
-                accounts_lock.extend(accounts);
-            }
-            {
-                let mut storage_lock = db.storage.write();
-                storage_lock.clear();
-                storage_lock.extend(storage);
-            }
-            {
-                let mut block_hashes_lock = db.block_hashes.write();
-                block_hashes_lock.clear();
-                block_hashes_lock.extend(block_hashes);
-            }
+                local, snapshot, ..
+            } = Arc::try_unwrap(snapshot).unwrap_or_else(|shared| (*shared).clone());
+            self.inner().db().restore(snapshot);
 
             self.cache_db = local;
 
@@ -150,9 +221,81 @@ impl ForkedDatabase {
             false
         }
     }
+
+    /// Run `f` once per parameter, each on its own fork of the snapshot `base_snapshot`, in
+    /// parallel on the [sim_pool]. Results are returned in the order of `params`, `None` if there
+    /// is no such snapshot.
+    ///
+    /// Forks only hold their own writes on top of the shared snapshot, which is kept. The sweep
+    /// holds on to the snapshot rather than the snapshot lock, other snapshot operations on this
+    /// database, reverting `base_snapshot` included, don't wait for it.
+    pub fn sweep<P, T, F>(&self, base_snapshot: U256, params: Vec<P>, f: F) -> Option<Vec<(P, T)>>
+    where
+        P: Send,
+        T: Send,
+        F: for<'a> Fn(&P, &mut SweepDb<'a, B>) -> T + Sync,
+        B: Sync,
+    {
+        let snapshot = self.snapshots().lock().get(base_snapshot)?.clone();
+        let resolver = snapshot.resolver();
+        let results = sim_pool().install(|| {
+            params
+                .into_par_iter()
+                .map(|param| {
+                    let mut db = CacheDB::new(resolver);
+                    let result = f(&param, &mut db);
+                    (param, result)
+                })
+                .collect()
+        });
+        Some(results)
+    }
+}
+
+impl<B: PinnedBackend> ForkedDatabase<B> {
+    /// Reset the fork to a fresh forked state, and optionally update the fork config
+    pub fn reset(&mut self, block_number: impl Into<BlockId>) -> Result<(), String> {
+        self.backend
+            .set_pinned_block(block_number.into())
+            .map_err(|err| err.to_string())?;
+
+        // TODO need to find a way to update generic provider via url
+
+        // wipe the storage retrieved from remote
+        self.inner().db().clear();
+        // create a fresh `CacheDB`, effectively wiping modified state
+        self.cache_db = CacheDB::new(self.backend.clone());
+        trace!(target: "backend::forkdb", "Cleared database");
+        Ok(())
+    }
+
+    /// [ForkedDatabase::reset] to `block` and take the block env from its header, the
+    /// [SimEnv](crate::sim_env::SimEnv) then builds on `block` rather than the block the fork
+    /// was created at
+    pub fn reset_to<T>(&mut self, block: &Block<T>) -> Result<(), String> {
+        let number = block.number.ok_or("block is pending")?;
+        self.reset(number)?;
+
+        let mut meta = self.db.meta().write();
+        let env = &mut meta.block_env;
+        env.number = rU256::from(number.as_u64());
+        env.timestamp = u256_to_ru256(block.timestamp);
+        env.gas_limit = u256_to_ru256(block.gas_limit);
+        if let Some(basefee) = block.base_fee_per_gas {
+            env.basefee = u256_to_ru256(basefee);
+        }
+        if let Some(author) = block.author {
+            env.coinbase = h160_to_b160(author);
+        }
+        env.prevrandao = block.mix_hash.map(h256_to_b256);
+        Ok(())
+    }
 }
 
-impl Database for ForkedDatabase {
+/// Fork of a snapshot a [ForkedDatabase::sweep] closure runs on
+pub type SweepDb<'a, B = SharedBackend> = CacheDB<SnapshotResolver<'a, B>>;
+
+impl<B: DatabaseRef<Error = DatabaseError>> Database for ForkedDatabase<B> {
     type Error = DatabaseError;
 
     fn basic(&mut self, address: B160) -> Result<Option<AccountInfo>, Self::Error> {
@@ -175,7 +318,7 @@ impl Database for ForkedDatabase {
     }
 }
 
-impl DatabaseRef for ForkedDatabase {
+impl<B: DatabaseRef<Error = DatabaseError>> DatabaseRef for ForkedDatabase<B> {
     type Error = DatabaseError;
 
     fn basic(&self, address: B160) -> Result<Option<AccountInfo>, Self::Error> {
@@ -195,100 +338,353 @@ impl DatabaseRef for ForkedDatabase {
     }
 }
 
-impl DatabaseCommit for ForkedDatabase {
+impl<B: DatabaseRef> DatabaseCommit for ForkedDatabase<B> {
     fn commit(&mut self, changes: Map<B160, Account>) {
-        self.database_mut().commit(changes)
+        self.cache_db.commit(changes)
     }
 }
 
 /// Represents a snapshot of the database
 ///
-/// This mimics `revm::CacheDB`
-#[derive(Debug)]
-pub struct ForkDbSnapshot {
-    pub local: CacheDB<SharedBackend>,
+/// Reads are answered by a [SnapshotResolver], see there for the order of the layers.
+#[derive(Debug, Clone)]
+pub struct ForkDbSnapshot<B = SharedBackend> {
+    pub local: CacheDB<B>,
     pub snapshot: StateSnapshot,
+    /// whether reads missing from both `local` and `snapshot` are fetched from the backend
+    pub live_fallback: bool,
 }
 
 // === impl DbSnapshot ===
 
-impl ForkDbSnapshot {
+impl<B: DatabaseRef> ForkDbSnapshot<B> {
+    pub fn new(local: CacheDB<B>, snapshot: StateSnapshot) -> Self {
+        Self {
+            local,
+            snapshot,
+            live_fallback: true,
+        }
+    }
+
+    /// Disable (or re-enable) fetching reads the snapshot didn't capture from the live backend
+    pub fn with_live_fallback(mut self, live_fallback: bool) -> Self {
+        self.live_fallback = live_fallback;
+        self
+    }
+
+    pub fn resolver(&self) -> SnapshotResolver<'_, B> {
+        SnapshotResolver::new(&self.local, &self.snapshot, self.live_fallback)
+    }
+
+    /// Storage captured in the remote snapshot, ignoring local modifications
     pub(crate) fn get_storage(&self, address: B160, index: rU256) -> Option<rU256> {
+        self.resolver().remote_storage(address, index)
+    }
+}
+
+impl<B> DatabaseRef for ForkDbSnapshot<B>
+where
+    B: DatabaseRef,
+    DatabaseError: From<B::Error>,
+{
+    type Error = DatabaseError;
+
+    fn basic(&self, address: B160) -> Result<Option<AccountInfo>, Self::Error> {
+        self.resolver().basic(address)
+    }
+
+    fn code_by_hash(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        self.resolver().code_by_hash(code_hash)
+    }
+
+    fn storage(&self, address: B160, index: rU256) -> Result<rU256, Self::Error> {
+        self.resolver().storage(address, index)
+    }
+
+    fn block_hash(&self, number: rU256) -> Result<B256, Self::Error> {
+        self.resolver().block_hash(number)
+    }
+}
+
+/// Resolves reads of a snapshot through its layers, first match wins:
+///
+/// 1. local: accounts, storage and code modified (or cached) in the `CacheDB` at snapshot time.
+///    Accounts destroyed or with cleared storage locally end the lookup here.
+/// 2. remote: the state of the remote client captured with the snapshot. Accounts known to be
+///    absent end the lookup here.
+/// 3. live: the backend the `CacheDB` wraps, if enabled. Without it anything the snapshot didn't
+///    capture reads as empty.
+#[derive(Debug)]
+pub struct SnapshotResolver<'a, B> {
+    local: &'a CacheDB<B>,
+    remote: &'a StateSnapshot,
+    live: Option<&'a B>,
+}
+
+impl<B> Clone for SnapshotResolver<'_, B> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<B> Copy for SnapshotResolver<'_, B> {}
+
+impl<'a, B: DatabaseRef> SnapshotResolver<'a, B> {
+    pub fn new(local: &'a CacheDB<B>, remote: &'a StateSnapshot, live_fallback: bool) -> Self {
+        Self {
+            local,
+            remote,
+            live: live_fallback.then_some(&local.db),
+        }
+    }
+
+    /// Local layer, `Some(None)` for accounts destroyed locally
+    pub fn local_basic(&self, address: B160) -> Option<Option<AccountInfo>> {
         self.local
             .accounts
             .get(&address)
-            .and_then(|account| account.storage.get(&index))
-            .copied()
+            .map(|account| account.info())
+    }
+
+    /// Local layer, `Some(0)` for unset slots of accounts whose storage was cleared locally
+    pub fn local_storage(&self, address: B160, index: rU256) -> Option<rU256> {
+        let account = self.local.accounts.get(&address)?;
+        match account.storage.get(&index) {
+            Some(value) => Some(*value),
+            None if matches!(
+                account.account_state,
+                AccountState::StorageCleared | AccountState::NotExisting
+            ) =>
+            {
+                Some(rU256::ZERO)
+            }
+            None => None,
+        }
+    }
+
+    /// Remote layer, `Some(None)` for accounts known to be absent
+    pub fn remote_basic(&self, address: B160) -> Option<Option<AccountInfo>> {
+        if self.remote.known_absent.contains(&address) {
+            return Some(None);
+        }
+        self.remote.accounts.get(&address).cloned().map(Some)
+    }
+
+    /// Remote layer, `Some(0)` for slots of accounts known to be absent
+    pub fn remote_storage(&self, address: B160, index: rU256) -> Option<rU256> {
+        if self.remote.known_absent.contains(&address) {
+            return Some(rU256::ZERO);
+        }
+        self.remote.storage.get(&address)?.get(&index).copied()
     }
 }
 
-// This `DatabaseRef` implementation works similar to `CacheDB` which prioritizes modified elements,
-// and uses another db as fallback
-// We prioritize stored changed accounts/storage
-impl DatabaseRef for ForkDbSnapshot {
+impl<'a, B> DatabaseRef for SnapshotResolver<'a, B>
+where
+    B: DatabaseRef,
+    DatabaseError: From<B::Error>,
+{
     type Error = DatabaseError;
 
     fn basic(&self, address: B160) -> Result<Option<AccountInfo>, Self::Error> {
-        match self.local.accounts.get(&address) {
-            Some(account) => Ok(Some(account.info.clone())),
-            None => {
-                let mut acc = self.snapshot.accounts.get(&address).cloned();
-
-                if acc.is_none() {
-                    acc = self.local.basic(address)?;
-                }
-                Ok(acc)
-            }
+        if let Some(info) = self.local_basic(address) {
+            return Ok(info);
+        }
+        if let Some(info) = self.remote_basic(address) {
+            return Ok(info);
+        }
+        match self.live {
+            Some(live) => Ok(live.basic(address)?),
+            None => Ok(None),
         }
     }
 
     fn code_by_hash(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
-        self.local.code_by_hash(code_hash)
+        if let Some(code) = self.local.contracts.get(&code_hash) {
+            return Ok(code.clone());
+        }
+        if let Some(code) = self.remote.codes.get(&code_hash) {
+            return Ok(code.clone());
+        }
+        match self.live {
+            Some(live) => Ok(live.code_by_hash(code_hash)?),
+            None => Err(DatabaseError::MissingCode(b256_to_h256(code_hash))),
+        }
     }
 
     fn storage(&self, address: B160, index: rU256) -> Result<rU256, Self::Error> {
-        match self.local.accounts.get(&address) {
-            Some(account) => match account.storage.get(&index) {
-                Some(entry) => Ok(*entry),
-                None => match self.get_storage(address, index) {
-                    None => DatabaseRef::storage(&self.local, address, index),
-                    Some(storage) => Ok(storage),
-                },
-            },
-            None => match self.get_storage(address, index) {
-                None => DatabaseRef::storage(&self.local, address, index),
-                Some(storage) => Ok(storage),
-            },
+        if let Some(value) = self.local_storage(address, index) {
+            return Ok(value);
+        }
+        if let Some(value) = self.remote_storage(address, index) {
+            return Ok(value);
+        }
+        match self.live {
+            Some(live) => Ok(live.storage(address, index)?),
+            None => Ok(rU256::ZERO),
         }
     }
 
     fn block_hash(&self, number: rU256) -> Result<B256, Self::Error> {
-        match self.snapshot.block_hashes.get(&number).copied() {
-            None => self.local.block_hash(number),
-            Some(block_hash) => Ok(block_hash),
+        if let Some(hash) = self.remote.block_hashes.get(&number) {
+            return Ok(*hash);
+        }
+        match self.live {
+            Some(live) => Ok(live.block_hash(number)?),
+            None => Err(DatabaseError::msg(format!(
+                "block hash {number} not captured in snapshot"
+            ))),
         }
     }
 }
 
+/// Approximate heap size of a snapshot, used to enforce [SnapshotLimits::max_bytes]
+pub trait SnapshotSize {
+    fn approx_size(&self) -> usize;
+}
+
+impl<T: SnapshotSize> SnapshotSize for Arc<T> {
+    fn approx_size(&self) -> usize {
+        (**self).approx_size()
+    }
+}
+
+impl<B> SnapshotSize for ForkDbSnapshot<B> {
+    fn approx_size(&self) -> usize {
+        const ACCOUNT_SIZE: usize = std::mem::size_of::<(B160, AccountInfo)>();
+        const SLOT_SIZE: usize = std::mem::size_of::<(rU256, rU256)>();
+
+        let code_size = |info: &AccountInfo| info.code.as_ref().map_or(0, |code| code.len());
+
+        let remote = self
+            .snapshot
+            .accounts
+            .values()
+            .map(|info| ACCOUNT_SIZE + code_size(info))
+            .sum::<usize>()
+            + self
+                .snapshot
+                .storage
+                .values()
+                .map(|slots| slots.len() * SLOT_SIZE)
+                .sum::<usize>();
+
+        let local = self
+            .local
+            .accounts
+            .values()
+            .map(|account| {
+                ACCOUNT_SIZE + code_size(&account.info) + account.storage.len() * SLOT_SIZE
+            })
+            .sum::<usize>()
+            + self
+                .local
+                .contracts
+                .values()
+                .map(|code| code.len())
+                .sum::<usize>();
+
+        remote + local
+    }
+}
+
+/// Limits enforced on [Snapshots], `None` means unbounded
+///
+/// When a limit is exceeded the oldest unlabeled snapshots are evicted first, labeled snapshots
+/// are only ever removed explicitly
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SnapshotLimits {
+    /// max number of retained snapshots
+    pub max_count: Option<usize>,
+    /// max approximate memory of all retained snapshots, in bytes
+    pub max_bytes: Option<usize>,
+    /// unlabeled snapshots older than this are dropped
+    pub ttl: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+struct SnapshotEntry<T> {
+    snapshot: T,
+    label: Option<String>,
+    created: Instant,
+    size: usize,
+}
+
 /// Represents all snapshots
+///
+/// Invariants, checked after every change in debug builds:
+///
+/// - ids are handed out in increasing order and never reused, so an id held on to after its
+///   snapshot was removed or evicted can't resolve to a later snapshot
+/// - every retained id is below the next id, ids go up to `U256::MAX - 1` and inserting fails once
+///   they are exhausted
+/// - removing a snapshot also removes every retained snapshot taken after it, whether or not the
+///   removed id itself was still retained
+/// - [Self::total_size] is the sum of the sizes of the retained snapshots
+/// - an insert never evicts the snapshot it inserts, the id it returns resolves until the next
+///   change
 #[derive(Debug, Clone)]
 pub struct Snapshots<T> {
     id: U256,
-    snapshots: Map<U256, T>,
+    snapshots: Map<U256, SnapshotEntry<T>>,
+    limits: SnapshotLimits,
+    total_size: usize,
 }
 
 // === impl Snapshots ===
 
 impl<T> Snapshots<T> {
-    fn next_id(&mut self) -> U256 {
+    pub fn new(limits: SnapshotLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// `None` once the ids are exhausted, `U256::MAX` is never handed out
+    fn next_id(&mut self) -> Option<U256> {
         let id = self.id;
-        self.id = id.saturating_add(U256::one());
-        id
+        if id == U256::MAX {
+            return None;
+        }
+        self.id = id + 1;
+        Some(id)
     }
 
     /// Returns the snapshot with the given id `id`
     pub fn get(&self, id: U256) -> Option<&T> {
-        self.snapshots.get(&id)
+        self.snapshots.get(&id).map(|entry| &entry.snapshot)
+    }
+
+    /// Returns the label of the snapshot with the given id `id`
+    pub fn label(&self, id: U256) -> Option<&str> {
+        self.snapshots
+            .get(&id)
+            .and_then(|entry| entry.label.as_deref())
+    }
+
+    /// Number of retained snapshots
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Approximate memory used by all retained snapshots, in bytes
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    pub fn limits(&self) -> &SnapshotLimits {
+        &self.limits
+    }
+
+    /// Replaces the limits, evicting snapshots right away if they are exceeded
+    pub fn set_limits(&mut self, limits: SnapshotLimits) {
+        self.limits = limits;
+        self.gc();
     }
 
     /// Removes the snapshot with the given `id`.
@@ -296,23 +692,150 @@ impl<T> Snapshots<T> {
     /// This will also remove any snapshots taken after the snapshot with the `id`. e.g.: reverting
     /// to id 1 will delete snapshots with ids 1, 2, 3, etc.)
     pub fn remove(&mut self, id: U256) -> Option<T> {
-        let snapshot = self.snapshots.remove(&id);
+        let snapshot = self.remove_entry(id);
 
-        // revert all snapshots taken after the snapshot
-        let mut to_revert = id + 1;
-        while to_revert < self.id {
-            self.snapshots.remove(&to_revert);
-            to_revert = to_revert + 1;
+        // revert all snapshots taken after the snapshot, walking the retained ids rather than
+        // every id up to the next one
+        let later: Vec<U256> = self
+            .snapshots
+            .keys()
+            .filter(|later| **later > id)
+            .copied()
+            .collect();
+        for later in later {
+            self.remove_entry(later);
         }
 
+        self.emit_usage();
         snapshot
     }
 
+    fn remove_entry(&mut self, id: U256) -> Option<T> {
+        self.snapshots.remove(&id).map(|entry| {
+            self.total_size = self.total_size.saturating_sub(entry.size);
+            entry.snapshot
+        })
+    }
+
+    /// Evicts expired snapshots, then the oldest unlabeled ones until all limits are met.
+    /// Returns the evicted ids.
+    pub fn gc(&mut self) -> Vec<U256> {
+        self.gc_keeping(None)
+    }
+
+    /// [Self::gc] never evicting `keep`
+    fn gc_keeping(&mut self, keep: Option<U256>) -> Vec<U256> {
+        let mut evicted = Vec::new();
+
+        if let Some(ttl) = self.limits.ttl {
+            let expired: Vec<U256> = self
+                .snapshots
+                .iter()
+                .filter(|(id, entry)| {
+                    Some(**id) != keep && entry.label.is_none() && entry.created.elapsed() > ttl
+                })
+                .map(|(id, _)| *id)
+                .collect();
+            for id in expired {
+                self.remove_entry(id);
+                evicted.push(id);
+            }
+        }
+
+        while self.over_limits() {
+            let oldest = self
+                .snapshots
+                .iter()
+                .filter(|(id, entry)| Some(**id) != keep && entry.label.is_none())
+                .map(|(id, _)| *id)
+                .min();
+            match oldest {
+                Some(id) => {
+                    self.remove_entry(id);
+                    evicted.push(id);
+                }
+                // only labeled snapshots left, besides the one kept
+                None => {
+                    warn!(target: "backend::forkdb", "Snapshot limits exceeded by labeled snapshots");
+                    break;
+                }
+            }
+        }
+
+        if !evicted.is_empty() {
+            trace!(target: "backend::forkdb", "Evicted snapshots {:?}", evicted);
+        }
+        self.emit_usage();
+        evicted
+    }
+
+    fn over_limits(&self) -> bool {
+        self.limits
+            .max_count
+            .map_or(false, |max| self.snapshots.len() > max)
+            || self
+                .limits
+                .max_bytes
+                .map_or(false, |max| self.total_size > max)
+    }
+
+    fn emit_usage(&self) {
+        debug_assert!(self.invariants_hold(), "snapshot invariants violated");
+        metrics::gauge!("forkdb_snapshots_count", self.snapshots.len() as f64);
+        metrics::gauge!("forkdb_snapshots_bytes", self.total_size as f64);
+    }
+
+    fn invariants_hold(&self) -> bool {
+        let size: usize = self.snapshots.values().map(|entry| entry.size).sum();
+        size == self.total_size && self.snapshots.keys().all(|id| *id < self.id)
+    }
+}
+
+impl<T: SnapshotSize> Snapshots<T> {
     /// Inserts the new snapshot and returns the id
+    ///
+    /// # Panics
+    ///
+    /// Once the ids are exhausted, see [Self::try_insert]
     pub fn insert(&mut self, snapshot: T) -> U256 {
-        let id = self.next_id();
-        self.snapshots.insert(id, snapshot);
-        id
+        self.try_insert(snapshot).expect("snapshot ids exhausted")
+    }
+
+    /// Inserts a labeled snapshot, which is exempt from automatic eviction
+    ///
+    /// # Panics
+    ///
+    /// Once the ids are exhausted, see [Self::try_insert_labeled]
+    pub fn insert_labeled(&mut self, snapshot: T, label: impl Into<String>) -> U256 {
+        self.try_insert_labeled(snapshot, label)
+            .expect("snapshot ids exhausted")
+    }
+
+    /// Same as [Self::insert], `None` once the ids are exhausted
+    pub fn try_insert(&mut self, snapshot: T) -> Option<U256> {
+        self.insert_entry(snapshot, None)
+    }
+
+    /// Same as [Self::insert_labeled], `None` once the ids are exhausted
+    pub fn try_insert_labeled(&mut self, snapshot: T, label: impl Into<String>) -> Option<U256> {
+        self.insert_entry(snapshot, Some(label.into()))
+    }
+
+    fn insert_entry(&mut self, snapshot: T, label: Option<String>) -> Option<U256> {
+        let id = self.next_id()?;
+        let size = snapshot.approx_size();
+        self.total_size += size;
+        self.snapshots.insert(
+            id,
+            SnapshotEntry {
+                snapshot,
+                label,
+                created: Instant::now(),
+                size,
+            },
+        );
+        self.gc_keeping(Some(id));
+        Some(id)
     }
 }
 
@@ -321,6 +844,340 @@ impl<T> Default for Snapshots<T> {
         Self {
             id: U256::zero(),
             snapshots: Map::new(),
+            limits: SnapshotLimits::default(),
+            total_size: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use revm::db::EmptyDB;
+
+    const SLOT: u64 = 1;
+
+    fn info(balance: u64) -> AccountInfo {
+        AccountInfo {
+            balance: rU256::from(balance),
+            ..Default::default()
+        }
+    }
+
+    /// Live backend holding 1, the remote snapshot 2 and the local layer 3 for `address`
+    fn layers(address: B160) -> (CacheDB<CacheDB<EmptyDB>>, StateSnapshot) {
+        let mut live = CacheDB::new(EmptyDB::default());
+        live.insert_account_info(address, info(1));
+        live.insert_account_storage(address, rU256::from(SLOT), rU256::from(1))
+            .unwrap();
+
+        let mut remote = StateSnapshot::default();
+        remote.accounts.insert(address, info(2));
+        remote
+            .storage
+            .entry(address)
+            .or_default()
+            .insert(rU256::from(SLOT), rU256::from(2));
+
+        (CacheDB::new(live), remote)
+    }
+
+    #[test]
+    fn test_resolver_prefers_local() {
+        let address = B160::from_low_u64_be(1);
+        let (mut local, remote) = layers(address);
+        local.insert_account_info(address, info(3));
+        local
+            .insert_account_storage(address, rU256::from(SLOT), rU256::from(3))
+            .unwrap();
+
+        let resolver = SnapshotResolver::new(&local, &remote, true);
+        assert_eq!(resolver.basic(address).unwrap(), Some(info(3)));
+        assert_eq!(
+            resolver.storage(address, rU256::from(SLOT)).unwrap(),
+            rU256::from(3)
+        );
+    }
+
+    #[test]
+    fn test_resolver_falls_back_to_remote_snapshot() {
+        let address = B160::from_low_u64_be(1);
+        let (mut local, remote) = layers(address);
+
+        let resolver = SnapshotResolver::new(&local, &remote, true);
+        assert_eq!(resolver.basic(address).unwrap(), Some(info(2)));
+        assert_eq!(
+            resolver.storage(address, rU256::from(SLOT)).unwrap(),
+            rU256::from(2)
+        );
+
+        // touched locally without writing the slot, the read must still come from the snapshot
+        // and not the live backend
+        local.insert_account_info(address, info(3));
+        let resolver = SnapshotResolver::new(&local, &remote, true);
+        assert_eq!(
+            resolver.storage(address, rU256::from(SLOT)).unwrap(),
+            rU256::from(2)
+        );
+
+        let mut absent = StateSnapshot::default();
+        absent.known_absent.insert(address);
+        let (local, _) = layers(address);
+        let resolver = SnapshotResolver::new(&local, &absent, true);
+        assert_eq!(resolver.basic(address).unwrap(), None);
+        assert_eq!(
+            resolver.storage(address, rU256::from(SLOT)).unwrap(),
+            rU256::ZERO
+        );
+    }
+
+    #[test]
+    fn test_resolver_code_by_hash() {
+        let address = B160::from_low_u64_be(1);
+        let (local, _) = layers(address);
+        let code = Bytecode::new_raw(vec![0x60, 0x00].into());
+        let mut remote = StateSnapshot::default();
+        remote.accounts.insert(
+            address,
+            AccountInfo {
+                code_hash: code.hash_slow(),
+                code: Some(code.clone()),
+                ..Default::default()
+            },
+        );
+        remote.index_codes();
+
+        let resolver = SnapshotResolver::new(&local, &remote, false);
+        assert_eq!(resolver.code_by_hash(code.hash_slow()).unwrap(), code);
+        assert!(matches!(
+            resolver.code_by_hash(B256::from_low_u64_be(7)),
+            Err(DatabaseError::MissingCode(_))
+        ));
+    }
+
+    #[test]
+    fn test_resolver_live_fallback() {
+        let address = B160::from_low_u64_be(1);
+        let (local, _) = layers(address);
+        let remote = StateSnapshot::default();
+
+        let resolver = SnapshotResolver::new(&local, &remote, true);
+        assert_eq!(resolver.basic(address).unwrap(), Some(info(1)));
+        assert_eq!(
+            resolver.storage(address, rU256::from(SLOT)).unwrap(),
+            rU256::from(1)
+        );
+
+        // without the live backend uncaptured state reads as empty
+        let resolver = SnapshotResolver::new(&local, &remote, false);
+        assert_eq!(resolver.basic(address).unwrap(), None);
+        assert_eq!(
+            resolver.storage(address, rU256::from(SLOT)).unwrap(),
+            rU256::ZERO
+        );
+        assert!(resolver.block_hash(rU256::from(1)).is_err());
+    }
+
+    #[test]
+    fn test_sweep_forks_snapshot_per_param() {
+        use crate::blockchain_db::BlockchainDbMeta;
+        use ethers::providers::Provider;
+        use std::collections::BTreeSet;
+
+        let db = BlockchainDb::new(
+            BlockchainDbMeta {
+                cfg_env: Default::default(),
+                block_env: Default::default(),
+                hosts: BTreeSet::new(),
+            },
+            None,
+        );
+        // everything read below is cached locally, the backend is never asked
+        let (backend, _handler) = SharedBackend::new(Provider::mocked().0, db.clone(), None);
+        let mut forked_db = ForkedDatabase::new(backend, db);
+        let address = B160::from_low_u64_be(1);
+        forked_db
+            .database_mut()
+            .insert_account_info(address, info(1));
+        forked_db
+            .database_mut()
+            .insert_account_storage(address, rU256::from(SLOT), rU256::from(10))
+            .unwrap();
+        let id = forked_db.insert_snapshot();
+
+        let results = forked_db
+            .sweep(id, vec![1u64, 2, 3], |size, db| {
+                // on the sim pool, with the snapshots free for others
+                assert!(std::thread::current().name().unwrap().starts_with("sim-"));
+                assert_eq!(forked_db.snapshots().lock().len(), 1);
+                let before = Database::storage(db, address, rU256::from(SLOT)).unwrap();
+                db.insert_account_storage(address, rU256::from(SLOT), before + rU256::from(*size))
+                    .unwrap();
+                Database::storage(db, address, rU256::from(SLOT)).unwrap()
+            })
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                (1, rU256::from(11)),
+                (2, rU256::from(12)),
+                (3, rU256::from(13))
+            ]
+        );
+
+        // forks don't write through to the snapshot
+        let snapshots = forked_db.snapshots().lock();
+        let snapshot = snapshots.get(id).unwrap();
+        assert_eq!(
+            snapshot.storage(address, rU256::from(SLOT)).unwrap(),
+            rU256::from(10)
+        );
+        drop(snapshots);
+        assert!(forked_db
+            .sweep(U256::from(99), vec![1], |_, _| ())
+            .is_none());
+    }
+    /// Stand-in snapshot of a given size
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Sized(usize);
+
+    impl SnapshotSize for Sized {
+        fn approx_size(&self) -> usize {
+            self.0
         }
     }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Insert(usize),
+        InsertLabeled(usize),
+        /// index into the ids handed out so far, removed or not
+        Remove(usize),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => (0..100usize).prop_map(Op::Insert),
+            1 => (0..100usize).prop_map(Op::InsertLabeled),
+            2 => any::<usize>().prop_map(Op::Remove),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_snapshots_match_model(
+            ops in prop::collection::vec(op(), 0..64),
+            max_count in prop::option::of(1..8usize),
+        ) {
+            let mut snapshots = Snapshots::new(SnapshotLimits {
+                max_count,
+                ..Default::default()
+            });
+            // id -> (size, labeled) of the retained snapshots
+            let mut model: std::collections::BTreeMap<U256, (usize, bool)> = Default::default();
+            let mut handed_out: Vec<U256> = vec![];
+
+            for op in ops {
+                match op {
+                    Op::Insert(size) | Op::InsertLabeled(size) => {
+                        let labeled = matches!(op, Op::InsertLabeled(_));
+                        let id = if labeled {
+                            snapshots.insert_labeled(Sized(size), "keep")
+                        } else {
+                            snapshots.insert(Sized(size))
+                        };
+                        prop_assert!(handed_out.last().map_or(true, |last| id > *last));
+                        // the new snapshot survives its own insert
+                        prop_assert_eq!(snapshots.get(id).map(|s| s.0), Some(size));
+                        handed_out.push(id);
+                        model.insert(id, (size, labeled));
+                        while max_count.map_or(false, |max| model.len() > max) {
+                            let oldest = model
+                                .iter()
+                                .find(|(retained, (_, labeled))| **retained != id && !labeled)
+                                .map(|(id, _)| *id);
+                            match oldest {
+                                Some(oldest) => model.remove(&oldest),
+                                None => break,
+                            };
+                        }
+                    }
+                    Op::Remove(index) => {
+                        if handed_out.is_empty() {
+                            continue;
+                        }
+                        let id = handed_out[index % handed_out.len()];
+                        let removed = snapshots.remove(id);
+                        prop_assert_eq!(removed.map(|s| s.0), model.get(&id).map(|(size, _)| *size));
+                        model.retain(|retained, _| *retained < id);
+                    }
+                }
+
+                prop_assert_eq!(snapshots.len(), model.len());
+                prop_assert_eq!(
+                    snapshots.total_size(),
+                    model.values().map(|(size, _)| size).sum::<usize>()
+                );
+                for id in &handed_out {
+                    prop_assert_eq!(
+                        snapshots.get(*id).map(|s| s.0),
+                        model.get(id).map(|(size, _)| *size)
+                    );
+                    prop_assert_eq!(
+                        snapshots.label(*id).is_some(),
+                        model.get(id).map_or(false, |(_, labeled)| *labeled)
+                    );
+                }
+            }
+        }
+
+        #[test]
+        fn prop_snapshot_ids_exhaust_at_max(left in 1..5u64, inserts in 0..8usize) {
+            let mut snapshots = Snapshots::new(SnapshotLimits::default());
+            snapshots.id = U256::MAX - left;
+            let first = snapshots.insert(Sized(1));
+
+            let mut ids = vec![first];
+            for _ in 0..inserts {
+                match snapshots.try_insert(Sized(1)) {
+                    Some(id) => ids.push(id),
+                    None => break,
+                }
+            }
+            prop_assert_eq!(ids.len(), inserts.min(left as usize - 1) + 1);
+            prop_assert!(ids.iter().all(|id| *id < U256::MAX));
+            prop_assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+            prop_assert_eq!(snapshots.total_size(), ids.len());
+
+            // removing the first one drops everything after it without overflowing
+            prop_assert_eq!(snapshots.remove(first), Some(Sized(1)));
+            prop_assert!(snapshots.is_empty());
+            prop_assert_eq!(snapshots.total_size(), 0);
+            prop_assert_eq!(snapshots.remove(U256::MAX), None);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_ids_unique_across_threads() {
+        let snapshots = Arc::new(Mutex::new(Snapshots::new(SnapshotLimits::default())));
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let snapshots = snapshots.clone();
+                std::thread::spawn(move || {
+                    (0..100)
+                        .map(|_| snapshots.lock().insert(Sized(1)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut ids: Vec<U256> = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 400);
+        assert_eq!(snapshots.lock().total_size(), 400);
+    }
 }
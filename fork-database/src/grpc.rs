@@ -0,0 +1,243 @@
+//! gRPC simulation service over the shared [ForkedDatabase], see `proto/sim.proto`
+//!
+//! Every request runs in a throwaway [CacheDB] layered on top of the fork database, so callers
+//! reuse its warm state without ever mutating it.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use parking_lot::RwLock;
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{Env, ExecutionResult, Output, TransactTo, TxEnv, B160, U256 as rU256},
+    DatabaseCommit, EVM,
+};
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+    forked_db::ForkedDatabase,
+    sim_env::SimEnv,
+    storage_layout::{
+        unpack, unpack_signed, Field, V2_RESERVES, V2_RESERVES_SLOT, V3_LIQUIDITY,
+        V3_LIQUIDITY_SLOT, V3_SLOT0, V3_SLOT0_SLOT,
+    },
+    utils::{ru256_to_u256, u256_to_ru256},
+};
+
+pub mod proto {
+    tonic::include_proto!("qilin.sim.v1");
+}
+
+use proto::{
+    simulator_server::{Simulator, SimulatorServer},
+    Call, CallResult, GetPoolStateRequest, Log, PoolKind, PoolState, SimulateBundleRequest,
+    SimulateBundleResponse, SimulateCallRequest,
+};
+
+/// Default gas limit of calls that don't set one
+const DEFAULT_GAS_LIMIT: u64 = 30_000_000;
+
+#[derive(Debug, Clone)]
+pub struct SimService {
+    db: Arc<RwLock<ForkedDatabase>>,
+}
+
+impl SimService {
+    pub fn new(db: Arc<RwLock<ForkedDatabase>>) -> Self {
+        Self { db }
+    }
+
+    /// Serves the simulation service on `addr` until the server fails
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+        Server::builder()
+            .add_service(SimulatorServer::new(self))
+            .serve(addr)
+            .await
+    }
+
+    fn simulate(&self, calls: &[Call]) -> Result<(Vec<CallResult>, u64), Status> {
+        let db = self.db.read();
+        let env = SimEnv::next_block(&db).map_err(|e| Status::unavailable(e.to_string()))?;
+        let mut sandbox = CacheDB::new(&*db);
+
+        let results = calls
+            .iter()
+            .map(|call| execute(&mut sandbox, &env, call))
+            .collect::<Result<_, _>>()?;
+        Ok((results, env.block.number.to()))
+    }
+}
+
+#[tonic::async_trait]
+impl Simulator for SimService {
+    async fn simulate_bundle(
+        &self,
+        request: Request<SimulateBundleRequest>,
+    ) -> Result<Response<SimulateBundleResponse>, Status> {
+        let (results, block_number) = self.simulate(&request.into_inner().txs)?;
+        Ok(Response::new(SimulateBundleResponse {
+            results,
+            block_number,
+        }))
+    }
+
+    async fn simulate_call(
+        &self,
+        request: Request<SimulateCallRequest>,
+    ) -> Result<Response<CallResult>, Status> {
+        let call = request
+            .into_inner()
+            .call
+            .ok_or_else(|| Status::invalid_argument("missing call"))?;
+        let (mut results, _) = self.simulate(&[call])?;
+        Ok(Response::new(results.remove(0)))
+    }
+
+    async fn get_pool_state(
+        &self,
+        request: Request<GetPoolStateRequest>,
+    ) -> Result<Response<PoolState>, Status> {
+        let request = request.into_inner();
+        let pool = to_address(&request.pool)?;
+
+        let db = self.db.read();
+        let storage = |slot: u64| {
+            DatabaseRef::storage(&*db, pool, rU256::from(slot))
+                .map(ru256_to_u256)
+                .map_err(|e| Status::unavailable(e.to_string()))
+        };
+        let member = |word, field: &Field| {
+            let value = unpack(word, field.offset * 8, field.ty.bits());
+            to_bytes(u256_to_ru256(value))
+        };
+
+        let mut state = PoolState {
+            pool: request.pool.clone(),
+            kind: request.kind,
+            block_number: db.inner().meta().read().block_env.number.to(),
+            ..Default::default()
+        };
+        match PoolKind::from_i32(request.kind) {
+            Some(PoolKind::UniswapV2) => {
+                let reserves = storage(V2_RESERVES_SLOT)?;
+                state.reserve0 = member(reserves, &V2_RESERVES[0]);
+                state.reserve1 = member(reserves, &V2_RESERVES[1]);
+            }
+            Some(PoolKind::UniswapV3) => {
+                let slot0 = storage(V3_SLOT0_SLOT)?;
+                state.sqrt_price_x96 = member(slot0, &V3_SLOT0[0]);
+                let tick = &V3_SLOT0[1];
+                state.tick = unpack_signed(slot0, tick.offset * 8, tick.ty.bits()).as_i32();
+                state.liquidity = member(storage(V3_LIQUIDITY_SLOT)?, &V3_LIQUIDITY);
+            }
+            _ => return Err(Status::invalid_argument("unknown pool kind")),
+        }
+
+        Ok(Response::new(state))
+    }
+}
+
+/// Executes `call` and commits it to `db`, returns the call's outcome
+fn execute<DB>(db: &mut CacheDB<DB>, env: &Env, call: &Call) -> Result<CallResult, Status>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    let mut evm = EVM::new();
+    evm.env = env.clone();
+    // callers aren't expected to fund gas
+    evm.env.cfg.disable_base_fee = true;
+    evm.env.tx = TxEnv {
+        caller: to_address(&call.from)?,
+        transact_to: TransactTo::Call(to_address(&call.to)?),
+        data: call.data.clone().into(),
+        value: to_u256(&call.value)?,
+        gas_limit: if call.gas_limit == 0 {
+            DEFAULT_GAS_LIMIT
+        } else {
+            call.gas_limit
+        },
+        gas_price: rU256::ZERO,
+        ..Default::default()
+    };
+    evm.database(&mut *db);
+
+    let result = evm
+        .transact()
+        .map_err(|e| Status::internal(format!("{:?}", e)))?;
+    db.commit(result.state);
+
+    let mut outcome = CallResult::default();
+    match result.result {
+        ExecutionResult::Success {
+            gas_used,
+            logs,
+            output,
+            ..
+        } => {
+            outcome.success = true;
+            outcome.gas_used = gas_used;
+            outcome.output = match output {
+                Output::Call(data) => data.to_vec(),
+                Output::Create(data, _) => data.to_vec(),
+            };
+            outcome.logs = logs
+                .into_iter()
+                .map(|log| Log {
+                    address: log.address.0.to_vec(),
+                    topics: log.topics.iter().map(|t| t.0.to_vec()).collect(),
+                    data: log.data.to_vec(),
+                })
+                .collect();
+        }
+        ExecutionResult::Revert { gas_used, output } => {
+            outcome.gas_used = gas_used;
+            outcome.output = output.to_vec();
+        }
+        ExecutionResult::Halt { reason, gas_used } => {
+            outcome.gas_used = gas_used;
+            outcome.error = format!("{:?}", reason);
+        }
+    }
+    Ok(outcome)
+}
+
+fn to_address(bytes: &[u8]) -> Result<B160, Status> {
+    if bytes.len() != 20 {
+        return Err(Status::invalid_argument(format!(
+            "address must be 20 bytes, got {}",
+            bytes.len()
+        )));
+    }
+    Ok(B160::from_slice(bytes))
+}
+
+fn to_u256(bytes: &[u8]) -> Result<rU256, Status> {
+    if bytes.len() > 32 {
+        return Err(Status::invalid_argument(format!(
+            "integer must be at most 32 bytes, got {}",
+            bytes.len()
+        )));
+    }
+    let mut word = [0u8; 32];
+    word[32 - bytes.len()..].copy_from_slice(bytes);
+    Ok(rU256::from_be_bytes(word))
+}
+
+fn to_bytes(value: rU256) -> Vec<u8> {
+    value.to_be_bytes::<32>().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_u256_roundtrip() {
+        let value = rU256::from(0x1234u64);
+        assert_eq!(to_u256(&to_bytes(value)).unwrap(), value);
+        assert_eq!(to_u256(&[0x12, 0x34]).unwrap(), value);
+        assert_eq!(to_u256(&[]).unwrap(), rU256::ZERO);
+        assert!(to_u256(&[0u8; 33]).is_err());
+        assert!(to_address(&[0u8; 19]).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+//! Learning which storage slots contracts actually use
+//!
+//! A swap on a pool reads the same handful of slots every time, reserves, `slot0`, the balances
+//! of the pool in its tokens, yet each of them costs a blocking round trip the first time the
+//! EVM asks for it after a block. [HotSlots] records the slots each contract was seen touching,
+//! in simulations through [HotSlots::record_state] and in blocks through
+//! [HotSlots::record_block_diff], and [HotSlots::prefetch] loads the hot ones in one round of
+//! concurrent requests at the start of the next block. The profile is persisted, a restarted bot
+//! warms up from what the previous run learned.
+//!
+//! Hits halve every [DEFAULT_HALF_LIFE_BLOCKS] a slot goes unseen, so a pool that was busy last
+//! week doesn't outrank one busy now, and at most [DEFAULT_MAX_PREFETCH] of the hottest slots are
+//! loaded per block.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use dashmap::DashMap;
+use ethers::types::{AccountDiff, Address};
+use hashbrown::HashMap as Map;
+use revm::primitives::{Account, B160, U256 as rU256};
+use serde::{Deserialize, Serialize};
+use tracing::trace;
+
+use crate::{
+    backend_handler::Priority,
+    errors::DatabaseResult,
+    shared_backend::SharedBackend,
+    utils::{h160_to_b160, h256_to_u256_be, u256_to_ru256},
+};
+
+/// Slots need to be seen at least this many times to be prefetched
+pub const DEFAULT_MIN_HITS: u32 = 2;
+/// Slots unseen for this many blocks, about a day, are forgotten
+pub const DEFAULT_MAX_AGE_BLOCKS: u64 = 7_200;
+/// Hits halve for every this many blocks a slot isn't seen, about an hour
+pub const DEFAULT_HALF_LIFE_BLOCKS: u64 = 300;
+/// Slots loaded per block at most, the hottest first
+pub const DEFAULT_MAX_PREFETCH: usize = 512;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlotUse {
+    /// blocks and simulations the slot was touched in, decayed as of `last_block`
+    pub hits: u32,
+    pub last_block: u64,
+}
+
+impl SlotUse {
+    /// Hits left at `block` after halving every `half_life` blocks since the last one
+    pub fn hits_at(&self, block: u64, half_life: u64) -> u32 {
+        let halvings = block.saturating_sub(self.last_block) / half_life.max(1);
+        self.hits
+            .checked_shr(halvings.min(u32::BITS as u64) as u32)
+            .unwrap_or(0)
+    }
+}
+
+/// Per contract profile of the slots it was observed touching
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HotSlots {
+    contracts: DashMap<B160, Map<rU256, SlotUse>>,
+    #[serde(skip, default = "default_min_hits")]
+    min_hits: u32,
+    #[serde(skip, default = "default_max_age_blocks")]
+    max_age_blocks: u64,
+    #[serde(skip, default = "default_half_life_blocks")]
+    half_life_blocks: u64,
+    #[serde(skip, default = "default_max_prefetch")]
+    max_prefetch: usize,
+}
+
+fn default_min_hits() -> u32 {
+    DEFAULT_MIN_HITS
+}
+
+fn default_max_age_blocks() -> u64 {
+    DEFAULT_MAX_AGE_BLOCKS
+}
+
+fn default_half_life_blocks() -> u64 {
+    DEFAULT_HALF_LIFE_BLOCKS
+}
+
+fn default_max_prefetch() -> usize {
+    DEFAULT_MAX_PREFETCH
+}
+
+impl Default for HotSlots {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HotSlots {
+    pub fn new() -> Self {
+        Self {
+            contracts: DashMap::new(),
+            min_hits: DEFAULT_MIN_HITS,
+            max_age_blocks: DEFAULT_MAX_AGE_BLOCKS,
+            half_life_blocks: DEFAULT_HALF_LIFE_BLOCKS,
+            max_prefetch: DEFAULT_MAX_PREFETCH,
+        }
+    }
+
+    pub fn with_min_hits(mut self, min_hits: u32) -> Self {
+        self.min_hits = min_hits;
+        self
+    }
+
+    pub fn with_max_age_blocks(mut self, blocks: u64) -> Self {
+        self.max_age_blocks = blocks;
+        self
+    }
+
+    pub fn with_half_life_blocks(mut self, blocks: u64) -> Self {
+        self.half_life_blocks = blocks;
+        self
+    }
+
+    pub fn with_max_prefetch(mut self, slots: usize) -> Self {
+        self.max_prefetch = slots;
+        self
+    }
+
+    /// Loads a profile written by [HotSlots::save], an empty one if there is no file yet
+    pub fn load(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::new());
+        }
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Writes the profile to `path`, replacing the previous one atomically
+    pub fn save(&self, path: impl AsRef<Path>) -> eyre::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn touch(&self, address: B160, slot: rU256, block: u64) {
+        let mut slots = self.contracts.entry(address).or_default();
+        let entry = slots.entry(slot).or_default();
+        let block = entry.last_block.max(block);
+        entry.hits = entry
+            .hits_at(block, self.half_life_blocks)
+            .saturating_add(1);
+        entry.last_block = block;
+    }
+
+    /// Records the slots of a simulation's post-state, which holds every slot the EVM loaded,
+    /// read or written
+    pub fn record_state(&self, state: &Map<B160, Account>, block: u64) {
+        for (address, account) in state {
+            for slot in account.storage.keys() {
+                self.touch(*address, *slot, block);
+            }
+        }
+    }
+
+    /// Records the slots a block's txs wrote
+    pub fn record_block_diff(&self, diff: &BTreeMap<Address, AccountDiff>, block: u64) {
+        for (address, account_diff) in diff {
+            for slot in account_diff.storage.keys() {
+                self.touch(
+                    h160_to_b160(*address),
+                    u256_to_ru256(h256_to_u256_be(*slot)),
+                    block,
+                );
+            }
+        }
+    }
+
+    /// Slots of `address` by use, hot or not
+    pub fn slots_of(&self, address: B160) -> Vec<(rU256, SlotUse)> {
+        let mut slots: Vec<_> = self
+            .contracts
+            .get(&address)
+            .map(|slots| slots.iter().map(|(slot, used)| (*slot, *used)).collect())
+            .unwrap_or_default();
+        slots.sort_by(|a, b| b.1.hits.cmp(&a.1.hits).then(a.0.cmp(&b.0)));
+        slots
+    }
+
+    /// Slots seen often enough and recently enough before `block` to be worth prefetching, the
+    /// hottest first and at most the max prefetch of them
+    pub fn hot(&self, block: u64) -> Vec<(B160, rU256)> {
+        let mut hot: Vec<_> = self
+            .contracts
+            .iter()
+            .flat_map(|entry| {
+                let address = *entry.key();
+                entry
+                    .value()
+                    .iter()
+                    .filter(|(_, used)| self.is_hot(used, block))
+                    .map(|(slot, used)| {
+                        (used.hits_at(block, self.half_life_blocks), address, *slot)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        hot.sort_by(|a, b| b.0.cmp(&a.0).then((a.1, a.2).cmp(&(b.1, b.2))));
+        hot.truncate(self.max_prefetch);
+        hot.into_iter()
+            .map(|(_, address, slot)| (address, slot))
+            .collect()
+    }
+
+    fn is_hot(&self, used: &SlotUse, block: u64) -> bool {
+        used.hits_at(block, self.half_life_blocks) >= self.min_hits
+            && block.saturating_sub(used.last_block) <= self.max_age_blocks
+    }
+
+    /// Forgets the slots unseen for longer than the max age, returns how many
+    pub fn prune(&self, block: u64) -> usize {
+        let mut pruned = 0;
+        self.contracts.retain(|_, slots| {
+            let before = slots.len();
+            slots.retain(|_, used| block.saturating_sub(used.last_block) <= self.max_age_blocks);
+            pruned += before - slots.len();
+            !slots.is_empty()
+        });
+        pruned
+    }
+
+    /// Loads the hot slots of `block` through the [Priority::Prefetch] lane of `backend`, so
+    /// strategies' reads go first. Returns how many slots were requested.
+    pub fn prefetch(&self, backend: &SharedBackend, block: u64) -> DatabaseResult<usize> {
+        let hot = self.hot(block);
+        trace!(target: "hot_slots", "prefetching {} slots for block {}", hot.len(), block);
+        backend
+            .with_priority(Priority::Prefetch)
+            .prefetch_storage(&hot)?;
+        Ok(hot.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.contracts.iter().map(|slots| slots.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contracts.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{ChangedType, Diff, H256};
+    use revm::primitives::{AccountInfo, StorageSlot};
+
+    #[test]
+    fn test_hot_slots() {
+        let pool = B160::from_low_u64_be(1);
+        let profile = HotSlots::new().with_max_age_blocks(10);
+
+        // a simulation loaded slots 8 and 9
+        let mut account = Account::from(AccountInfo::default());
+        account
+            .storage
+            .insert(rU256::from(8u64), StorageSlot::new(rU256::from(1u64)));
+        account
+            .storage
+            .insert(rU256::from(9u64), StorageSlot::new(rU256::from(2u64)));
+        profile.record_state(&[(pool, account)].into_iter().collect(), 100);
+
+        // a block wrote slot 8 again
+        let diff = BTreeMap::from([(
+            Address::from_low_u64_be(1),
+            AccountDiff {
+                balance: Diff::Same,
+                nonce: Diff::Same,
+                code: Diff::Same,
+                storage: BTreeMap::from([(
+                    H256::from_low_u64_be(8),
+                    Diff::Changed(ChangedType {
+                        from: H256::zero(),
+                        to: H256::from_low_u64_be(3),
+                    }),
+                )]),
+            },
+        )]);
+        profile.record_block_diff(&diff, 101);
+
+        assert_eq!(profile.len(), 2);
+        assert_eq!(
+            profile.slots_of(pool)[0],
+            (
+                rU256::from(8u64),
+                SlotUse {
+                    hits: 2,
+                    last_block: 101
+                }
+            )
+        );
+        assert_eq!(profile.hot(102), vec![(pool, rU256::from(8u64))]);
+        assert!(profile.hot(112).is_empty());
+
+        // persisted, the thresholds are configuration and not part of the profile
+        let path = std::env::temp_dir().join(format!("hot_slots_{}.json", std::process::id()));
+        profile.save(&path).unwrap();
+        let loaded = HotSlots::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.slots_of(pool), profile.slots_of(pool));
+        assert_eq!(loaded.min_hits, DEFAULT_MIN_HITS);
+
+        assert_eq!(profile.prune(111), 1);
+        assert_eq!(profile.prune(112), 1);
+        assert!(profile.is_empty());
+    }
+
+    #[test]
+    fn test_hits_decay_and_prefetch_is_capped() {
+        let pool = B160::from_low_u64_be(1);
+        let profile = HotSlots::new()
+            .with_min_hits(2)
+            .with_half_life_blocks(10)
+            .with_max_prefetch(1);
+
+        // slot 1 was busy long ago, slot 2 only lately
+        for _ in 0..8 {
+            profile.touch(pool, rU256::from(1u64), 100);
+        }
+        for block in [128, 129, 130] {
+            profile.touch(pool, rU256::from(2u64), block);
+        }
+        // 8 hits 30 blocks ago are down to 1, below the threshold
+        assert_eq!(profile.slots_of(pool)[0].1.hits_at(130, 10), 1);
+        assert_eq!(profile.hot(130), vec![(pool, rU256::from(2u64))]);
+        // both hot again, only the hottest is prefetched
+        profile.touch(pool, rU256::from(1u64), 130);
+        profile.touch(pool, rU256::from(1u64), 130);
+        profile.touch(pool, rU256::from(1u64), 130);
+        assert_eq!(profile.hot(130), vec![(pool, rU256::from(1u64))]);
+    }
+}
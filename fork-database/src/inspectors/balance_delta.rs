@@ -0,0 +1,322 @@
+use ethers::types::I256;
+use hashbrown::{HashMap as Map, HashSet};
+use revm::{
+    interpreter::{CallInputs, CreateInputs, Gas, InstructionResult},
+    primitives::{Bytes, B160, B256, U256 as rU256},
+    Database, EVMData, Inspector,
+};
+
+use super::is_success;
+use crate::utils::ru256_to_u256;
+
+/// `keccak256("Transfer(address,address,uint256)")`
+pub const TRANSFER_TOPIC: [u8; 32] = [
+    0xdd, 0xf2, 0x52, 0xad, 0x1b, 0xe2, 0xc8, 0x9b, 0x69, 0xc2, 0xb0, 0x68, 0xfc, 0x37, 0x8d, 0xaa,
+    0x95, 0x2b, 0xa7, 0xf1, 0x63, 0xc4, 0xa1, 0x16, 0x28, 0xf5, 0x5a, 0x4d, 0xf5, 0x23, 0xb3, 0xef,
+];
+/// `keccak256("Deposit(address,uint256)")`, emitted by WETH instead of a mint `Transfer`
+pub const DEPOSIT_TOPIC: [u8; 32] = [
+    0xe1, 0xff, 0xfc, 0xc4, 0x92, 0x3d, 0x04, 0xb5, 0x59, 0xf4, 0xd2, 0x9a, 0x8b, 0xfc, 0x6c, 0xda,
+    0x04, 0xeb, 0x5b, 0x0d, 0x3c, 0x46, 0x07, 0x51, 0xc2, 0x40, 0x2c, 0x5c, 0x5c, 0xc9, 0x10, 0x9c,
+];
+/// `keccak256("Withdrawal(address,uint256)")`, emitted by WETH instead of a burn `Transfer`
+pub const WITHDRAWAL_TOPIC: [u8; 32] = [
+    0x7f, 0xcf, 0x53, 0x2c, 0x15, 0xf0, 0xa6, 0xdb, 0x0b, 0xd6, 0xd0, 0xe0, 0x38, 0xbe, 0xa7, 0x1d,
+    0x30, 0xd8, 0x08, 0xc7, 0xd9, 0x8c, 0xb3, 0xbf, 0x72, 0x68, 0xa9, 0x5b, 0xf5, 0x08, 0x1b, 0x65,
+];
+
+/// The asset a balance delta is denominated in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Asset {
+    Eth,
+    Token(B160),
+}
+
+/// A single balance movement, only applied once the frame it happened in succeeds
+#[derive(Debug, Clone, Copy)]
+struct Movement {
+    asset: Asset,
+    from: Option<B160>,
+    to: Option<B160>,
+    amount: rU256,
+}
+
+/// Records ETH and ERC20 balance deltas of a watched address set during simulation.
+///
+/// ETH deltas come from call / create value transfers, token deltas from `Transfer` logs (and
+/// WETH `Deposit` / `Withdrawal`). Movements are journaled per call frame so reverted frames
+/// don't leak into the result.
+#[derive(Debug, Clone, Default)]
+pub struct BalanceDeltaInspector {
+    watched: HashSet<B160>,
+    weth: Option<B160>,
+    frames: Vec<Vec<Movement>>,
+    deltas: Map<Asset, Map<B160, I256>>,
+}
+
+impl BalanceDeltaInspector {
+    pub fn new(watched: impl IntoIterator<Item = B160>) -> Self {
+        Self {
+            watched: watched.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Also track WETH wraps / unwraps, which don't emit `Transfer` events
+    pub fn with_weth(mut self, weth: B160) -> Self {
+        self.weth = Some(weth);
+        self
+    }
+
+    /// Net change of `owner`'s balance in `asset`
+    pub fn delta(&self, owner: B160, asset: Asset) -> I256 {
+        self.deltas
+            .get(&asset)
+            .and_then(|owners| owners.get(&owner))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// All non-zero deltas of `owner`
+    pub fn deltas_of(&self, owner: B160) -> Map<Asset, I256> {
+        self.deltas
+            .iter()
+            .filter_map(|(asset, owners)| {
+                owners
+                    .get(&owner)
+                    .filter(|delta| !delta.is_zero())
+                    .map(|delta| (*asset, *delta))
+            })
+            .collect()
+    }
+
+    pub fn deltas(&self) -> &Map<Asset, Map<B160, I256>> {
+        &self.deltas
+    }
+
+    fn is_watched(&self, address: &B160) -> bool {
+        self.watched.contains(address)
+    }
+
+    fn record(&mut self, movement: Movement) {
+        let watched = movement.from.map_or(false, |a| self.is_watched(&a))
+            || movement.to.map_or(false, |a| self.is_watched(&a));
+        if !watched || movement.amount == rU256::ZERO {
+            return;
+        }
+
+        match self.frames.last_mut() {
+            Some(frame) => frame.push(movement),
+            None => self.apply(vec![movement]),
+        }
+    }
+
+    fn apply(&mut self, movements: Vec<Movement>) {
+        for movement in movements {
+            let amount = I256::from_raw(ru256_to_u256(movement.amount));
+            let owners = self.deltas.entry(movement.asset).or_default();
+            if let Some(from) = movement.from.filter(|a| self.watched.contains(a)) {
+                let entry = owners.entry(from).or_default();
+                *entry = entry.saturating_sub(amount);
+            }
+            if let Some(to) = movement.to.filter(|a| self.watched.contains(a)) {
+                let entry = owners.entry(to).or_default();
+                *entry = entry.saturating_add(amount);
+            }
+        }
+    }
+
+    /// Close the innermost frame, folding its movements into the parent on success
+    fn close_frame(&mut self, success: bool) {
+        let movements = match self.frames.pop() {
+            Some(movements) => movements,
+            None => return,
+        };
+        if !success {
+            return;
+        }
+        match self.frames.last_mut() {
+            Some(parent) => parent.extend(movements),
+            None => self.apply(movements),
+        }
+    }
+}
+
+/// Topic words hold a left padded address
+fn topic_to_address(topic: &B256) -> B160 {
+    B160::from_slice(&topic.0[12..])
+}
+
+impl<DB: Database> Inspector<DB> for BalanceDeltaInspector {
+    fn log(
+        &mut self,
+        _evm_data: &mut EVMData<'_, DB>,
+        address: &B160,
+        topics: &[B256],
+        data: &Bytes,
+    ) {
+        if data.len() < 32 || topics.is_empty() {
+            return;
+        }
+        let amount = rU256::from_be_bytes::<32>(data[..32].try_into().unwrap());
+        let asset = Asset::Token(*address);
+
+        // ERC721 transfers index the token id as a fourth topic
+        if topics[0].0 == TRANSFER_TOPIC && topics.len() == 3 {
+            self.record(Movement {
+                asset,
+                from: Some(topic_to_address(&topics[1])),
+                to: Some(topic_to_address(&topics[2])),
+                amount,
+            });
+        } else if self.weth == Some(*address) && topics.len() == 2 {
+            let owner = topic_to_address(&topics[1]);
+            if topics[0].0 == DEPOSIT_TOPIC {
+                self.record(Movement {
+                    asset,
+                    from: None,
+                    to: Some(owner),
+                    amount,
+                });
+            } else if topics[0].0 == WITHDRAWAL_TOPIC {
+                self.record(Movement {
+                    asset,
+                    from: Some(owner),
+                    to: None,
+                    amount,
+                });
+            }
+        }
+    }
+
+    fn call(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        inputs: &mut CallInputs,
+        _is_static: bool,
+    ) -> (InstructionResult, Gas, Bytes) {
+        self.frames.push(Vec::new());
+        self.record(Movement {
+            asset: Asset::Eth,
+            from: Some(inputs.transfer.source),
+            to: Some(inputs.transfer.target),
+            amount: inputs.transfer.value,
+        });
+        (InstructionResult::Continue, Gas::new(0), Bytes::new())
+    }
+
+    fn call_end(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        _inputs: &CallInputs,
+        remaining_gas: Gas,
+        ret: InstructionResult,
+        out: Bytes,
+        _is_static: bool,
+    ) -> (InstructionResult, Gas, Bytes) {
+        self.close_frame(is_success(ret));
+        (ret, remaining_gas, out)
+    }
+
+    fn create(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        _inputs: &mut CreateInputs,
+    ) -> (InstructionResult, Option<B160>, Gas, Bytes) {
+        self.frames.push(Vec::new());
+        (InstructionResult::Continue, None, Gas::new(0), Bytes::new())
+    }
+
+    fn create_end(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        inputs: &CreateInputs,
+        ret: InstructionResult,
+        address: Option<B160>,
+        remaining_gas: Gas,
+        out: Bytes,
+    ) -> (InstructionResult, Option<B160>, Gas, Bytes) {
+        // the created address is only known once the frame is done
+        if let Some(created) = address {
+            self.record(Movement {
+                asset: Asset::Eth,
+                from: Some(inputs.caller),
+                to: Some(created),
+                amount: inputs.value,
+            });
+        }
+        self.close_frame(is_success(ret) && address.is_some());
+        (ret, address, remaining_gas, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::{
+        db::{CacheDB, EmptyDB},
+        primitives::{AccountInfo, Bytecode, TransactTo},
+        EVM,
+    };
+
+    /// Runtime code emitting `Transfer(from, to, amount)` and stopping
+    fn transfer_emitter(from: B160, to: B160, amount: u64) -> Vec<u8> {
+        let mut code = vec![0x7f];
+        code.extend(rU256::from(amount).to_be_bytes::<32>());
+        // PUSH1 0 MSTORE
+        code.extend([0x60, 0x00, 0x52]);
+        for word in [to, from] {
+            code.push(0x7f);
+            code.extend([0u8; 12]);
+            code.extend(word.0);
+        }
+        code.push(0x7f);
+        code.extend(TRANSFER_TOPIC);
+        // PUSH1 32 PUSH1 0 LOG3 STOP
+        code.extend([0x60, 0x20, 0x60, 0x00, 0xa3, 0x00]);
+        code
+    }
+
+    #[test]
+    fn test_tracks_eth_and_token_deltas() {
+        let searcher = B160::from_low_u64_be(1);
+        let victim = B160::from_low_u64_be(2);
+        let token = B160::from_low_u64_be(3);
+
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            searcher,
+            AccountInfo {
+                balance: rU256::from(1_000_000u64),
+                ..Default::default()
+            },
+        );
+        db.insert_account_info(
+            token,
+            AccountInfo::new(
+                rU256::ZERO,
+                0,
+                Bytecode::new_raw(transfer_emitter(victim, searcher, 500).into()),
+            ),
+        );
+
+        let mut evm = EVM::new();
+        evm.database(db);
+        evm.env.tx.caller = searcher;
+        evm.env.tx.transact_to = TransactTo::Call(token);
+        evm.env.tx.value = rU256::from(100u64);
+        evm.env.tx.gas_limit = 100_000;
+        evm.env.tx.gas_price = rU256::ZERO;
+
+        let mut inspector = BalanceDeltaInspector::new([searcher]);
+        evm.inspect(&mut inspector).unwrap();
+
+        assert_eq!(inspector.delta(searcher, Asset::Eth), I256::from(-100));
+        assert_eq!(
+            inspector.delta(searcher, Asset::Token(token)),
+            I256::from(500)
+        );
+        // the victim isn't watched
+        assert_eq!(inspector.delta(victim, Asset::Token(token)), I256::zero());
+    }
+}
@@ -0,0 +1,424 @@
+use ethers::types::{Address, U256};
+use hashbrown::HashSet;
+use revm::{
+    interpreter::{CallInputs, CreateInputs, Gas, InstructionResult, Interpreter},
+    primitives::{Bytes, B160, U256 as rU256},
+    Database, EVMData, Inspector,
+};
+use serde::{Serialize, Serializer};
+use thiserror::Error;
+
+use super::is_success;
+use crate::utils::{b160_to_h160, ru256_to_u256};
+
+pub const SELFDESTRUCT: u8 = 0xff;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum PaymentError {
+    #[error("Tx {tx} sent {amount} wei to {to:?} instead of the coinbase {coinbase:?}")]
+    Misrouted {
+        tx: usize,
+        to: Address,
+        coinbase: Address,
+        amount: U256,
+    },
+    #[error("Coinbase receives {paid} wei, below the minimum {min}")]
+    BelowMinimum { paid: U256, min: U256 },
+}
+
+/// A value transfer, only kept once the frame it happened in succeeds
+#[derive(Debug, Clone, Copy)]
+struct Transfer {
+    to: B160,
+    value: rU256,
+}
+
+/// What the inspector knows about a tx of the bundle
+#[derive(Debug, Clone, Default)]
+struct TxRecord {
+    from: B160,
+    coinbase: B160,
+    gas_price: rU256,
+    basefee: rU256,
+    gas_used: u64,
+    transfers: Vec<Transfer>,
+}
+
+/// Payment of a single tx, keyed like a tx result of `eth_callBundle`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TxPayment {
+    pub from_address: Address,
+    pub coinbase: Address,
+    pub gas_used: u64,
+    /// effective gas price
+    #[serde(serialize_with = "as_decimal")]
+    pub gas_price: U256,
+    /// priority fees, the part of the gas price the coinbase receives
+    #[serde(serialize_with = "as_decimal")]
+    pub gas_fees: U256,
+    /// base fees, burnt
+    #[serde(serialize_with = "as_decimal")]
+    pub burnt_fees: U256,
+    /// transfers and selfdestructs to the coinbase
+    #[serde(serialize_with = "as_decimal")]
+    pub eth_sent_to_coinbase: U256,
+    #[serde(serialize_with = "as_decimal")]
+    pub coinbase_diff: U256,
+    /// value sent to fee recipients other than this block's coinbase
+    pub misrouted: Vec<(Address, U256)>,
+}
+
+/// Payment of a whole bundle, keyed like the result of `eth_callBundle`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundlePayment {
+    pub results: Vec<TxPayment>,
+    #[serde(serialize_with = "as_decimal")]
+    pub coinbase_diff: U256,
+    #[serde(serialize_with = "as_decimal")]
+    pub eth_sent_to_coinbase: U256,
+    #[serde(serialize_with = "as_decimal")]
+    pub gas_fees: U256,
+    pub total_gas_used: u64,
+    /// coinbase diff per gas, what builders order bundles by
+    #[serde(serialize_with = "as_decimal")]
+    pub bundle_gas_price: U256,
+}
+
+impl BundlePayment {
+    /// Rejects bundles paying a fee recipient other than the coinbase, or paying the coinbase
+    /// less than `min_coinbase_diff`
+    pub fn check(&self, min_coinbase_diff: U256) -> Result<(), PaymentError> {
+        for (tx, payment) in self.results.iter().enumerate() {
+            if let Some((to, amount)) = payment.misrouted.first() {
+                return Err(PaymentError::Misrouted {
+                    tx,
+                    to: *to,
+                    coinbase: payment.coinbase,
+                    amount: *amount,
+                });
+            }
+        }
+        if self.coinbase_diff < min_coinbase_diff {
+            return Err(PaymentError::BelowMinimum {
+                paid: self.coinbase_diff,
+                min: min_coinbase_diff,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Attributes every wei the block's coinbase receives during a bundle simulation.
+///
+/// Direct payments come from call values and `SELFDESTRUCT` beneficiaries, journaled per call
+/// frame so reverted frames don't count. Fees are split into what the coinbase gets and what's
+/// burnt once [Self::end_tx] passes each tx's gas used. Value sent to the `fee_recipients` of
+/// other blocks, e.g. a builder's address hardcoded in the executor, is reported as misrouted.
+///
+/// Reuse the inspector for all txs of a bundle and call [Self::end_tx] after each of them.
+#[derive(Debug, Clone, Default)]
+pub struct CoinbasePaymentInspector {
+    fee_recipients: HashSet<B160>,
+    frames: Vec<Vec<Transfer>>,
+    txs: Vec<TxRecord>,
+}
+
+impl CoinbasePaymentInspector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Addresses bribes may end up at by mistake, e.g. the fee recipients of known builders
+    pub fn with_fee_recipients(mut self, fee_recipients: impl IntoIterator<Item = B160>) -> Self {
+        self.fee_recipients.extend(fee_recipients);
+        self
+    }
+
+    /// Set the gas used by the tx that just ran
+    pub fn end_tx(&mut self, gas_used: u64) {
+        if let Some(tx) = self.txs.last_mut() {
+            tx.gas_used = gas_used;
+        }
+    }
+
+    pub fn tx_count(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn payment(&self) -> BundlePayment {
+        let results: Vec<TxPayment> = self.txs.iter().map(tx_payment).collect();
+        let total_gas_used = results.iter().map(|tx| tx.gas_used).sum::<u64>();
+        let sum = |field: fn(&TxPayment) -> U256| {
+            results
+                .iter()
+                .fold(U256::zero(), |sum, tx| sum.saturating_add(field(tx)))
+        };
+        let coinbase_diff = sum(|tx| tx.coinbase_diff);
+        BundlePayment {
+            coinbase_diff,
+            eth_sent_to_coinbase: sum(|tx| tx.eth_sent_to_coinbase),
+            gas_fees: sum(|tx| tx.gas_fees),
+            total_gas_used,
+            bundle_gas_price: coinbase_diff
+                .checked_div(U256::from(total_gas_used))
+                .unwrap_or_default(),
+            results,
+        }
+    }
+
+    /// Transfers of interest, to the coinbase or to one of the fee recipients
+    fn is_tracked(&self, to: &B160) -> bool {
+        self.txs.last().map_or(false, |tx| tx.coinbase == *to) || self.fee_recipients.contains(to)
+    }
+
+    fn record(&mut self, to: B160, value: rU256) {
+        if value == rU256::ZERO {
+            return;
+        }
+        if let Some(frame) = self.frames.last_mut() {
+            frame.push(Transfer { to, value });
+        }
+    }
+
+    /// A call or create with no frame open is the start of the next tx
+    fn open_frame<DB: Database>(&mut self, data: &EVMData<'_, DB>) {
+        if self.frames.is_empty() {
+            self.txs.push(TxRecord {
+                from: data.env.tx.caller,
+                coinbase: data.env.block.coinbase,
+                gas_price: data.env.effective_gas_price(),
+                basefee: data.env.block.basefee,
+                ..Default::default()
+            });
+        }
+        self.frames.push(Vec::new());
+    }
+
+    fn close_frame(&mut self, success: bool) {
+        let Some(transfers) = self.frames.pop() else {
+            return;
+        };
+        if !success {
+            return;
+        }
+        match self.frames.last_mut() {
+            Some(parent) => parent.extend(transfers),
+            None => {
+                if let Some(tx) = self.txs.last_mut() {
+                    tx.transfers.extend(transfers);
+                }
+            }
+        }
+    }
+}
+
+fn tx_payment(tx: &TxRecord) -> TxPayment {
+    let gas_used = rU256::from(tx.gas_used);
+    let priority_fee = tx.gas_price.saturating_sub(tx.basefee);
+    let gas_fees = gas_used.saturating_mul(priority_fee);
+    // the gas price can be below the basefee when it isn't enforced, e.g. in eth_call style sims
+    let burnt_fees = gas_used.saturating_mul(tx.gas_price.min(tx.basefee));
+
+    let mut eth_sent_to_coinbase = rU256::ZERO;
+    let mut misrouted = Vec::new();
+    for transfer in &tx.transfers {
+        if transfer.to == tx.coinbase {
+            eth_sent_to_coinbase = eth_sent_to_coinbase.saturating_add(transfer.value);
+        } else {
+            misrouted.push((b160_to_h160(transfer.to), ru256_to_u256(transfer.value)));
+        }
+    }
+
+    TxPayment {
+        from_address: b160_to_h160(tx.from),
+        coinbase: b160_to_h160(tx.coinbase),
+        gas_used: tx.gas_used,
+        gas_price: ru256_to_u256(tx.gas_price),
+        gas_fees: ru256_to_u256(gas_fees),
+        burnt_fees: ru256_to_u256(burnt_fees),
+        eth_sent_to_coinbase: ru256_to_u256(eth_sent_to_coinbase),
+        coinbase_diff: ru256_to_u256(gas_fees.saturating_add(eth_sent_to_coinbase)),
+        misrouted,
+    }
+}
+
+fn as_decimal<S: Serializer>(value: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&value.to_string())
+}
+
+impl<DB: Database> Inspector<DB> for CoinbasePaymentInspector {
+    fn step(
+        &mut self,
+        interp: &mut Interpreter,
+        data: &mut EVMData<'_, DB>,
+        _is_static: bool,
+    ) -> InstructionResult {
+        if interp.current_opcode() != SELFDESTRUCT {
+            return InstructionResult::Continue;
+        }
+        let Ok(target) = interp.stack.peek(0) else {
+            return InstructionResult::Continue;
+        };
+        let contract = interp.contract.address;
+        let target = B160::from_slice(&target.to_be_bytes::<32>()[12..]);
+        // the whole balance goes to the beneficiary, the account is loaded as it's executing
+        let balance = data
+            .journaled_state
+            .state
+            .get(&contract)
+            .map_or(rU256::ZERO, |account| account.info.balance);
+        if target != contract && self.is_tracked(&target) {
+            self.record(target, balance);
+        }
+        InstructionResult::Continue
+    }
+
+    fn call(
+        &mut self,
+        data: &mut EVMData<'_, DB>,
+        inputs: &mut CallInputs,
+        _is_static: bool,
+    ) -> (InstructionResult, Gas, Bytes) {
+        self.open_frame(data);
+        let target = inputs.transfer.target;
+        if inputs.transfer.source != target && self.is_tracked(&target) {
+            self.record(target, inputs.transfer.value);
+        }
+        (InstructionResult::Continue, Gas::new(0), Bytes::new())
+    }
+
+    fn call_end(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        _inputs: &CallInputs,
+        remaining_gas: Gas,
+        ret: InstructionResult,
+        out: Bytes,
+        _is_static: bool,
+    ) -> (InstructionResult, Gas, Bytes) {
+        self.close_frame(is_success(ret));
+        (ret, remaining_gas, out)
+    }
+
+    fn create(
+        &mut self,
+        data: &mut EVMData<'_, DB>,
+        _inputs: &mut CreateInputs,
+    ) -> (InstructionResult, Option<B160>, Gas, Bytes) {
+        self.open_frame(data);
+        (InstructionResult::Continue, None, Gas::new(0), Bytes::new())
+    }
+
+    fn create_end(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        _inputs: &CreateInputs,
+        ret: InstructionResult,
+        address: Option<B160>,
+        remaining_gas: Gas,
+        out: Bytes,
+    ) -> (InstructionResult, Option<B160>, Gas, Bytes) {
+        self.close_frame(is_success(ret) && address.is_some());
+        (ret, address, remaining_gas, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::{
+        db::{CacheDB, EmptyDB},
+        primitives::{AccountInfo, Bytecode, TransactTo},
+        EVM,
+    };
+
+    /// Runtime code selfdestructing to `beneficiary`
+    fn selfdestruct_to(beneficiary: B160) -> Vec<u8> {
+        let mut code = vec![0x73];
+        code.extend(beneficiary.0);
+        code.push(SELFDESTRUCT);
+        code
+    }
+
+    #[test]
+    fn test_attributes_coinbase_payments() {
+        let searcher = B160::from_low_u64_be(1);
+        let coinbase = B160::from_low_u64_be(2);
+        let other_builder = B160::from_low_u64_be(3);
+        let executor = B160::from_low_u64_be(4);
+
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            searcher,
+            AccountInfo {
+                balance: rU256::from(10u64.pow(18)),
+                ..Default::default()
+            },
+        );
+
+        let mut evm = EVM::new();
+        evm.database(db);
+        evm.env.block.coinbase = coinbase;
+        evm.env.block.basefee = rU256::from(10u64);
+        evm.env.tx.caller = searcher;
+        evm.env.tx.gas_limit = 100_000;
+        evm.env.tx.gas_price = rU256::from(12u64);
+
+        let mut inspector = CoinbasePaymentInspector::new().with_fee_recipients([other_builder]);
+
+        // a direct bribe
+        evm.env.tx.transact_to = TransactTo::Call(coinbase);
+        evm.env.tx.value = rU256::from(1_000u64);
+        let gas_used = evm.inspect_commit(&mut inspector).unwrap().gas_used();
+        inspector.end_tx(gas_used);
+
+        // the executor selfdestructs its balance to the wrong builder
+        let mut db = evm.db.take().unwrap();
+        db.insert_account_info(
+            executor,
+            AccountInfo::new(
+                rU256::from(500u64),
+                0,
+                Bytecode::new_raw(selfdestruct_to(other_builder).into()),
+            ),
+        );
+        evm.database(db);
+        evm.env.tx.transact_to = TransactTo::Call(executor);
+        evm.env.tx.value = rU256::ZERO;
+        let second_gas = evm.inspect_commit(&mut inspector).unwrap().gas_used();
+        inspector.end_tx(second_gas);
+
+        let payment = inspector.payment();
+        assert_eq!(payment.results.len(), 2);
+        let bribe = &payment.results[0];
+        assert_eq!(bribe.gas_used, 21_000);
+        assert_eq!(bribe.gas_fees, U256::from(2 * 21_000));
+        assert_eq!(bribe.burnt_fees, U256::from(10 * 21_000));
+        assert_eq!(bribe.eth_sent_to_coinbase, U256::from(1_000));
+        assert_eq!(bribe.coinbase_diff, U256::from(2 * 21_000 + 1_000));
+        assert_eq!(
+            payment.results[1].misrouted,
+            vec![(b160_to_h160(other_builder), U256::from(500))]
+        );
+        assert_eq!(
+            payment.total_gas_used,
+            21_000 + second_gas,
+            "gas of both txs"
+        );
+        assert_eq!(
+            payment.check(U256::zero()),
+            Err(PaymentError::Misrouted {
+                tx: 1,
+                to: b160_to_h160(other_builder),
+                coinbase: b160_to_h160(coinbase),
+                amount: U256::from(500),
+            })
+        );
+
+        let json = serde_json::to_value(&payment).unwrap();
+        assert_eq!(json["results"][0]["ethSentToCoinbase"], "1000");
+        assert_eq!(json["coinbaseDiff"], payment.coinbase_diff.to_string());
+    }
+}
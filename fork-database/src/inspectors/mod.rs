@@ -0,0 +1,21 @@
+//! [revm::Inspector]s used while simulating bundles on top of the fork database
+pub mod balance_delta;
+pub mod coinbase;
+pub mod transient;
+
+pub use balance_delta::{Asset, BalanceDeltaInspector};
+pub use coinbase::{BundlePayment, CoinbasePaymentInspector, PaymentError, TxPayment};
+pub use transient::TransientStorageInspector;
+
+use revm::interpreter::InstructionResult;
+
+/// Whether a frame ending with `ret` keeps its effects
+pub(crate) fn is_success(ret: InstructionResult) -> bool {
+    matches!(
+        ret,
+        InstructionResult::Continue
+            | InstructionResult::Stop
+            | InstructionResult::Return
+            | InstructionResult::SelfDestruct
+    )
+}
@@ -0,0 +1,167 @@
+use hashbrown::HashMap as Map;
+use revm::{
+    interpreter::{CallInputs, CreateInputs, Gas, InstructionResult, Interpreter},
+    primitives::{Bytes, B160, U256 as rU256},
+    Database, EVMData, Inspector,
+};
+
+use super::is_success;
+
+pub const TLOAD: u8 = 0x5c;
+pub const TSTORE: u8 = 0x5d;
+pub const MCOPY: u8 = 0x5e;
+pub const BLOBHASH: u8 = 0x49;
+pub const BLOBBASEFEE: u8 = 0x4a;
+
+/// Opcodes introduced with Cancun
+pub const CANCUN_OPCODES: [u8; 5] = [TLOAD, TSTORE, MCOPY, BLOBHASH, BLOBBASEFEE];
+
+/// Records the EIP-1153 transient storage a simulation used.
+///
+/// `TSTORE`s are journaled per call frame, like transient storage itself a reverted frame's
+/// writes are dropped. Also remembers the first Cancun opcode the EVM couldn't execute, which
+/// happens when the simulation isn't configured for Cancun.
+#[derive(Debug, Clone, Default)]
+pub struct TransientStorageInspector {
+    frames: Vec<Vec<(B160, rU256, rU256)>>,
+    writes: Map<(B160, rU256), rU256>,
+    reads: Map<(B160, rU256), rU256>,
+    pending_load: Option<(B160, rU256)>,
+    /// opcode of the step being executed
+    opcode: u8,
+    unsupported: Option<u8>,
+}
+
+impl TransientStorageInspector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Last value written to transient `slot` of `address` by a frame that didn't revert
+    pub fn written(&self, address: B160, slot: rU256) -> Option<rU256> {
+        self.writes.get(&(address, slot)).copied()
+    }
+
+    pub fn writes(&self) -> &Map<(B160, rU256), rU256> {
+        &self.writes
+    }
+
+    /// Value of the last `TLOAD` of each slot
+    pub fn reads(&self) -> &Map<(B160, rU256), rU256> {
+        &self.reads
+    }
+
+    /// Cancun opcode that halted execution as unknown, if any
+    pub fn unsupported_opcode(&self) -> Option<u8> {
+        self.unsupported
+    }
+
+    fn close_frame(&mut self, success: bool) {
+        let Some(writes) = self.frames.pop() else {
+            return;
+        };
+        if !success {
+            return;
+        }
+        match self.frames.last_mut() {
+            Some(parent) => parent.extend(writes),
+            None => {
+                for (address, slot, value) in writes {
+                    self.writes.insert((address, slot), value);
+                }
+            }
+        }
+    }
+}
+
+impl<DB: Database> Inspector<DB> for TransientStorageInspector {
+    fn step(
+        &mut self,
+        interp: &mut Interpreter,
+        _data: &mut EVMData<'_, DB>,
+        _is_static: bool,
+    ) -> InstructionResult {
+        let address = interp.contract.address;
+        self.opcode = interp.current_opcode();
+        match self.opcode {
+            TSTORE => {
+                if let (Ok(slot), Ok(value), Some(frame)) = (
+                    interp.stack.peek(0),
+                    interp.stack.peek(1),
+                    self.frames.last_mut(),
+                ) {
+                    frame.push((address, slot, value));
+                }
+            }
+            TLOAD => self.pending_load = interp.stack.peek(0).ok().map(|slot| (address, slot)),
+            _ => {}
+        }
+        InstructionResult::Continue
+    }
+
+    fn step_end(
+        &mut self,
+        interp: &mut Interpreter,
+        _data: &mut EVMData<'_, DB>,
+        _is_static: bool,
+        eval: InstructionResult,
+    ) -> InstructionResult {
+        if let Some(key) = self.pending_load.take() {
+            if let Ok(value) = interp.stack.peek(0) {
+                self.reads.insert(key, value);
+            }
+        }
+        if eval == InstructionResult::OpcodeNotFound
+            && self.unsupported.is_none()
+            && CANCUN_OPCODES.contains(&self.opcode)
+        {
+            self.unsupported = Some(self.opcode);
+        }
+        eval
+    }
+
+    fn call(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        _inputs: &mut CallInputs,
+        _is_static: bool,
+    ) -> (InstructionResult, Gas, Bytes) {
+        self.frames.push(Vec::new());
+        (InstructionResult::Continue, Gas::new(0), Bytes::new())
+    }
+
+    fn call_end(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        _inputs: &CallInputs,
+        remaining_gas: Gas,
+        ret: InstructionResult,
+        out: Bytes,
+        _is_static: bool,
+    ) -> (InstructionResult, Gas, Bytes) {
+        self.close_frame(is_success(ret));
+        (ret, remaining_gas, out)
+    }
+
+    fn create(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        _inputs: &mut CreateInputs,
+    ) -> (InstructionResult, Option<B160>, Gas, Bytes) {
+        self.frames.push(Vec::new());
+        (InstructionResult::Continue, None, Gas::new(0), Bytes::new())
+    }
+
+    fn create_end(
+        &mut self,
+        _data: &mut EVMData<'_, DB>,
+        _inputs: &CreateInputs,
+        ret: InstructionResult,
+        address: Option<B160>,
+        remaining_gas: Gas,
+        out: Bytes,
+    ) -> (InstructionResult, Option<B160>, Gas, Bytes) {
+        self.close_frame(is_success(ret) && address.is_some());
+        (ret, address, remaining_gas, out)
+    }
+}
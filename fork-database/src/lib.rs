@@ -1,21 +1,57 @@
 pub mod backend_handler;
+pub mod backend_supervisor;
 pub mod blockchain_db;
+pub mod cache_flush;
+pub mod cheats;
+pub mod delegation;
 pub mod errors;
+pub mod evm_pool;
+#[cfg(any(test, feature = "faults"))]
+pub mod faults;
 pub mod forked_db;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod hot_slots;
+pub mod inspectors;
+pub mod local_backend;
+pub mod multi_block;
+pub mod pending_block;
+pub mod proxy;
+pub mod remote_sim;
+pub mod reorg;
 pub mod shared_backend;
+pub mod sim_cache;
+pub mod sim_env;
 pub mod snapshot;
+pub mod stale;
+pub mod storage_layout;
 pub mod utils;
 
+use crate::backend_supervisor::{BackendHandle, SupervisorConfig};
 use crate::blockchain_db::{BlockchainDb, BlockchainDbMeta};
 use crate::forked_db::ForkedDatabase;
 use crate::shared_backend::SharedBackend;
 use ethers::providers::{Middleware, Provider, Ws};
 use foundry_config::Config;
 use foundry_evm::executor::opts::EvmOpts;
-use std::{collections::BTreeSet, sync::Arc};
+use std::{collections::BTreeSet, path::PathBuf, sync::Arc};
+
+/// Setup forked database, its fetch loop runs supervised, see [BackendHandle]. Dropping the handle
+/// leaves the loop running, keep it to tell a dead fork from a cold one.
+pub async fn setup_fork_db(
+    provider: Arc<Provider<Ws>>,
+    http_url: String,
+) -> (ForkedDatabase, BackendHandle) {
+    setup_cached_fork_db(provider, http_url, None).await
+}
 
-/// Setup forked database
-pub async fn setup_fork_db(provider: Arc<Provider<Ws>>, http_url: String) -> ForkedDatabase {
+/// [setup_fork_db], with the fetched state cached at `cache_path` and loaded from it if it was
+/// cached at the same block, see [ForkedDatabase::spawn_cache_flusher]
+pub async fn setup_cached_fork_db(
+    provider: Arc<Provider<Ws>>,
+    http_url: String,
+    cache_path: Option<PathBuf>,
+) -> (ForkedDatabase, BackendHandle) {
     let block_num = provider.get_block_number().await.unwrap();
     let config = Config::figment();
     let mut evm_opts = config.extract::<EvmOpts>().unwrap();
@@ -29,12 +65,11 @@ pub async fn setup_fork_db(provider: Arc<Provider<Ws>>, http_url: String) -> For
         hosts: BTreeSet::from([http_url.clone()]),
     };
 
-    let db = BlockchainDb::new(meta, None);
-    let backend = SharedBackend::spawn_backend(provider.clone(), db.clone(), None).await;
-
-    let forked_db = ForkedDatabase::new(backend.clone(), db.clone());
+    let db = BlockchainDb::new(meta, cache_path);
+    let (backend, handle) =
+        SharedBackend::spawn_supervised(provider, db.clone(), None, SupervisorConfig::default());
 
-    forked_db
+    (ForkedDatabase::new(backend, db), handle)
 }
 
 #[cfg(test)]
@@ -86,19 +121,19 @@ mod tests {
         let account = backend.basic(address).unwrap().unwrap();
 
         // test accounts
-        let mem_acc = db.accounts().read().get(&address).unwrap().clone();
+        let mem_acc = db.accounts().get(&address).unwrap().clone();
         assert_eq!(account.balance, mem_acc.balance);
         assert_eq!(account.nonce, mem_acc.nonce);
 
         // test storage
-        let slots = db.storage().read().get(&address).unwrap().clone();
+        let slots = db.storage().get(&address).unwrap().clone();
         assert_eq!(slots.len(), 1);
         assert_eq!(slots.get(&idx).copied().unwrap(), value);
 
         // test hash
         let num = rU256::from(10u64);
         let hash = backend.block_hash(num).unwrap();
-        let mem_hash = *db.block_hashes().read().get(&num).unwrap();
+        let mem_hash = *db.block_hashes().get(&num).unwrap();
         assert_eq!(hash, mem_hash);
 
         let handle = std::thread::spawn(move || {
@@ -108,7 +143,7 @@ mod tests {
             }
         });
         handle.join().unwrap();
-        let slots = db.storage().read().get(&address).unwrap().clone();
+        let slots = db.storage().get(&address).unwrap().clone();
         assert_eq!(slots.len() as u64, 10);
     }
 
@@ -134,14 +169,14 @@ mod tests {
         let idx = rU256::from(0u64);
         let _ = backend.storage(address, idx).unwrap();
         let _ = backend.basic(address).unwrap().unwrap();
-        let _ = db.accounts().read().get(&address).unwrap().clone();
+        let _ = db.accounts().get(&address).unwrap().clone();
 
         // write to cache
         let _ = db.cache().flush();
 
         // read from cache
         let json = JsonBlockCacheDB::load(cache_path).unwrap();
-        assert!(!json.db().accounts.read().is_empty());
+        assert!(!json.db().accounts.is_empty());
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -174,7 +209,7 @@ mod tests {
 
         let mut forked_db = ForkedDatabase::new(backend.clone(), db.clone());
 
-        let db_data = forked_db.inner().accounts().read().is_empty();
+        let db_data = forked_db.inner().accounts().is_empty();
         assert!(db_data);
 
         let address: B160 = "0xd8da6bf26964af9d7eed9e03e53415d37aa96045"
@@ -184,7 +219,7 @@ mod tests {
         let snapshot = forked_db.create_snapshot();
         let idx = rU256::from(0u64);
         let account = forked_db.basic(address).unwrap().unwrap();
-        let mem_acc = db.accounts().read().get(&address).unwrap().clone();
+        let mem_acc = db.accounts().get(&address).unwrap().clone();
         let snap_shot_acc = snapshot.get_storage(address.clone(), idx.clone());
 
         // test snapshot
@@ -199,16 +234,330 @@ mod tests {
         account_delta.insert(rand_account.clone(), Account::from(account.clone()));
         DatabaseCommit::commit(&mut forked_db.clone(), account_delta);
 
-        let inner_account = forked_db.inner().accounts().read();
-        let account_detail = inner_account.get(&rand_account);
+        let account_detail = forked_db.inner().accounts().get(&rand_account);
         // test writing to cache
         // the db should have the randomly created account data
         assert!(!account_detail.is_some());
-        drop(inner_account);
+        drop(account_detail);
 
         forked_db.reset(block_num - U64::from(1)).unwrap();
         let cleared_account = forked_db.inner().accounts();
         // test reset
-        assert_eq!(cleared_account.read().is_empty(), true);
+        assert_eq!(cleared_account.is_empty(), true);
+    }
+
+    #[test]
+    fn test_known_absent_accounts() {
+        use ethers::types::{AccountDiff, Address, ChangedType, Diff, H256, U256};
+        use revm::primitives::AccountInfo;
+        use std::collections::BTreeMap;
+
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        let db = BlockchainDb::new(meta, None);
+        let address = Address::random();
+        let b160_address = B160(address.0);
+
+        db.db().do_insert_account(
+            b160_address,
+            AccountInfo {
+                balance: rU256::from(1u64),
+                ..Default::default()
+            },
+        );
+        db.db()
+            .insert_storage(b160_address, rU256::from(1u64), rU256::from(1u64));
+
+        // selfdestructed
+        let mut diff = BTreeMap::new();
+        diff.insert(
+            address,
+            AccountDiff {
+                balance: Diff::Died(U256::one()),
+                nonce: Diff::Died(U256::zero()),
+                code: Diff::Died(Default::default()),
+                storage: BTreeMap::new(),
+            },
+        );
+        db.apply_block_diff(&diff);
+        assert!(db.is_known_absent(&b160_address));
+        assert!(db.accounts().get(&b160_address).is_none());
+        assert!(db.storage().get(&b160_address).is_none());
+
+        // re-created in a later block, with fresh storage
+        let mut storage = BTreeMap::new();
+        storage.insert(
+            H256::from_low_u64_be(2),
+            Diff::Changed(ChangedType {
+                from: H256::zero(),
+                to: H256::from_low_u64_be(7),
+            }),
+        );
+        diff.insert(
+            address,
+            AccountDiff {
+                balance: Diff::Born(U256::from(5)),
+                nonce: Diff::Born(U256::one()),
+                code: Diff::Born(Default::default()),
+                storage,
+            },
+        );
+        db.apply_block_diff(&diff);
+        assert!(!db.is_known_absent(&b160_address));
+        let info = db.db().account(&b160_address).unwrap();
+        assert_eq!(info.balance, rU256::from(5u64));
+        assert_eq!(info.nonce, 1);
+        let slots = db.storage().get(&b160_address).unwrap().clone();
+        assert_eq!(slots.len(), 1);
+        assert_eq!(slots.get(&rU256::from(2u64)), Some(&rU256::from(7u64)));
+    }
+
+    #[test]
+    fn test_apply_balance_changes() {
+        use ethers::types::{Address, U256};
+        use revm::primitives::AccountInfo;
+        use std::collections::BTreeMap;
+
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        let db = BlockchainDb::new(meta, None);
+        let cached = Address::from_low_u64_be(1);
+        db.db().do_insert_account(
+            B160(cached.0),
+            AccountInfo {
+                nonce: 3,
+                ..Default::default()
+            },
+        );
+
+        let balances = BTreeMap::from([
+            (cached, U256::from(9)),
+            (Address::from_low_u64_be(2), U256::from(1)),
+        ]);
+        // only cached accounts are patched, the rest is fetched when needed
+        assert_eq!(db.apply_balance_changes(&balances), vec![B160(cached.0)]);
+        let info = db.db().account(&B160(cached.0)).unwrap();
+        assert_eq!(info.balance, rU256::from(9u64));
+        assert_eq!(info.nonce, 3);
+        assert!(db.accounts().get(&B160::from_low_u64_be(2)).is_none());
+    }
+
+    #[test]
+    fn test_storage_of() {
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        let db = BlockchainDb::new(meta, None);
+        let address = B160::random();
+
+        assert_eq!(db.storage_of(address).count(), 0);
+
+        db.db()
+            .insert_storage(address, rU256::from(3u64), rU256::from(30u64));
+        db.db()
+            .insert_storage(address, rU256::from(1u64), rU256::from(10u64));
+
+        let slots: Vec<_> = db.storage_of(address).collect();
+        assert_eq!(
+            slots,
+            vec![
+                (rU256::from(1u64), rU256::from(10u64)),
+                (rU256::from(3u64), rU256::from(30u64))
+            ]
+        );
+
+        let dump = db.dump_storage_json(address);
+        assert_eq!(dump["storage"].as_object().unwrap().len(), 2);
+        assert_eq!(dump["known_absent"], false);
+    }
+
+    #[test]
+    fn test_snapshot_limits() {
+        use crate::forked_db::{SnapshotLimits, SnapshotSize, Snapshots};
+        use ethers::types::U256;
+
+        struct Sized(usize);
+        impl SnapshotSize for Sized {
+            fn approx_size(&self) -> usize {
+                self.0
+            }
+        }
+
+        let mut snapshots = Snapshots::new(SnapshotLimits {
+            max_count: Some(2),
+            max_bytes: Some(100),
+            ttl: None,
+        });
+
+        let labeled = snapshots.insert_labeled(Sized(10), "pinned");
+        let first = snapshots.insert(Sized(10));
+        let second = snapshots.insert(Sized(10));
+
+        // the oldest unlabeled snapshot is evicted, the labeled one is kept
+        assert_eq!(snapshots.len(), 2);
+        assert!(snapshots.get(labeled).is_some());
+        assert!(snapshots.get(first).is_none());
+        assert!(snapshots.get(second).is_some());
+        assert_eq!(snapshots.label(labeled), Some("pinned"));
+        assert_eq!(snapshots.total_size(), 20);
+
+        // memory limit
+        let big = snapshots.insert(Sized(90));
+        assert!(snapshots.get(second).is_none());
+        assert!(snapshots.get(big).is_some());
+        assert_eq!(snapshots.total_size(), 100);
+
+        // expired snapshots are dropped on the next gc
+        snapshots.set_limits(SnapshotLimits {
+            ttl: Some(std::time::Duration::ZERO),
+            ..Default::default()
+        });
+        assert_eq!(snapshots.len(), 1);
+        assert!(snapshots.get(labeled).is_some());
+        assert_eq!(snapshots.get(U256::from(100)).map(|s| s.0), None);
+
+        // a new snapshot outlives its own insert, even when only labeled ones are left to evict
+        snapshots.set_limits(SnapshotLimits {
+            max_count: Some(1),
+            ..Default::default()
+        });
+        let kept = snapshots.insert(Sized(1));
+        assert!(snapshots.get(kept).is_some());
+        assert!(snapshots.get(labeled).is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_code_by_hash_fallback() {
+        use crate::errors::DatabaseError;
+        use ethers::{types::Bytes, utils::keccak256};
+        use revm::primitives::{AccountInfo, B256};
+
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        let db = BlockchainDb::new(meta, None);
+        let code = Bytes::from_static(&[0x60, 0x01, 0x60, 0x00, 0x55]);
+        let code_hash = B256::from(keccak256(&code));
+        let owner = B160::from_low_u64_be(1);
+
+        // the account's info was cached without its code
+        db.db().do_insert_account(
+            owner,
+            AccountInfo {
+                code_hash,
+                code: None,
+                ..Default::default()
+            },
+        );
+        assert!(db.db().code_by_hash(&code_hash).is_none());
+
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Bytes, _>(code).unwrap();
+        let backend = SharedBackend::spawn_backend(provider, db.clone(), None).await;
+
+        let fetched = backend.code_by_hash(code_hash).unwrap();
+        assert_eq!(fetched.hash(), code_hash);
+        assert_eq!(db.db().code_by_hash(&code_hash).unwrap().hash(), code_hash);
+
+        assert!(matches!(
+            backend.code_by_hash(B256::from_low_u64_be(2)),
+            Err(DatabaseError::MissingCode(_))
+        ));
+    }
+
+    #[test]
+    fn test_code_dedupe() {
+        use crate::blockchain_db::JsonBlockCacheData;
+        use ethers::utils::keccak256;
+        use revm::primitives::{AccountInfo, Bytecode, B256};
+
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        let db = BlockchainDb::new(meta, None);
+        let raw = vec![0x60, 0x01, 0x60, 0x00, 0x55].repeat(40);
+        let code_hash = B256::from(keccak256(&raw));
+        let clone = |nonce| AccountInfo {
+            nonce,
+            code_hash,
+            code: Some(Bytecode::new_raw(raw.clone().into()).to_checked()),
+            ..Default::default()
+        };
+        for i in 1..=3 {
+            db.db()
+                .do_insert_account(B160::from_low_u64_be(i), clone(i));
+        }
+
+        // one copy, the accounts refer to it by hash
+        assert_eq!(db.db().codes.len(), 1);
+        assert_eq!(db.db().code_size(), raw.len());
+        assert!(db
+            .accounts()
+            .get(&B160::from_low_u64_be(1))
+            .unwrap()
+            .code
+            .is_none());
+        let info = db.db().account(&B160::from_low_u64_be(2)).unwrap();
+        assert_eq!(info.code.unwrap().original_bytes().as_ref(), raw.as_slice());
+
+        // used since it was stored, compressed once idle
+        assert_eq!(db.db().compress_idle_code(0), 0);
+        assert_eq!(db.db().compress_idle_code(0), 1);
+        assert!(db.db().code_size() < raw.len());
+        let code = db.db().code_by_hash(&code_hash).unwrap();
+        assert_eq!(code.original_bytes().as_ref(), raw.as_slice());
+        assert_eq!(db.db().code_size(), raw.len());
+
+        // snapshots and the cache file still hold the code
+        let snapshot = db.db().to_state_snapshot();
+        assert!(snapshot.accounts[&B160::from_low_u64_be(3)].code.is_some());
+        let data = JsonBlockCacheData {
+            meta: db.meta().clone(),
+            data: db.db().clone(),
+        };
+        let json = serde_json::to_value(&data).unwrap();
+        let accounts = json["accounts"].as_object().unwrap();
+        assert_eq!(accounts.len(), 3);
+        assert!(accounts.values().all(|account| !account["code"].is_null()));
+    }
+
+    #[test]
+    fn test_restore_is_atomic_for_readers() {
+        use crate::blockchain_db::MemDb;
+        use revm::primitives::AccountInfo;
+
+        let db = MemDb::default();
+        let address = B160::from_low_u64_be(1);
+        db.do_insert_account(
+            address,
+            AccountInfo {
+                nonce: 1,
+                ..Default::default()
+            },
+        );
+
+        // the account is in the state before and after every restore, readers never miss it
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for _ in 0..1_000 {
+                    db.restore(db.to_state_snapshot());
+                }
+            });
+            for _ in 0..1_000 {
+                assert_eq!(db.account(&address).map(|info| info.nonce), Some(1));
+            }
+        });
     }
 }
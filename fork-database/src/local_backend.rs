@@ -0,0 +1,320 @@
+//! A [SharedBackend](crate::shared_backend::SharedBackend) alternative for colocated deployments
+//!
+//! Instead of going through JSON-RPC, [LocalBackend] reads state synchronously from a
+//! [StateSource], e.g. the MDBX database of a Reth node running on the same machine (see
+//! [RethStateSource], behind the `reth` feature) or state pushed by a Reth ExEx. Reads take
+//! microseconds instead of a round trip, so there is no handler task and no request batching.
+//! Values read are still written to the [BlockchainDb], so snapshots, cache flushing and block
+//! diffs work the same as with the RPC backend.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use revm::{
+    db::DatabaseRef,
+    primitives::{AccountInfo, Bytecode, B160, B256, KECCAK_EMPTY, U256 as rU256},
+};
+use tracing::trace;
+
+use crate::{
+    blockchain_db::BlockchainDb,
+    errors::{DatabaseError, DatabaseResult},
+    utils::{b160_to_h160, b256_to_h256},
+};
+
+/// Direct, synchronous access to the state of a node
+pub trait StateSource: Send + Sync + Debug {
+    /// Balance, nonce and code hash of `address`, `None` if it doesn't exist. The code may be
+    /// left out, it is then loaded with [StateSource::code_by_hash].
+    fn basic(&self, address: B160) -> eyre::Result<Option<AccountInfo>>;
+
+    fn code_by_hash(&self, hash: B256) -> eyre::Result<Option<Bytecode>>;
+
+    fn storage(&self, address: B160, index: rU256) -> eyre::Result<rU256>;
+
+    fn block_hash(&self, number: u64) -> eyre::Result<Option<B256>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalBackend<S> {
+    source: Arc<S>,
+    db: BlockchainDb,
+}
+
+impl<S: StateSource> LocalBackend<S> {
+    pub fn new(source: S, db: BlockchainDb) -> Self {
+        Self {
+            source: Arc::new(source),
+            db,
+        }
+    }
+
+    pub fn source(&self) -> &Arc<S> {
+        &self.source
+    }
+
+    pub fn db(&self) -> &BlockchainDb {
+        &self.db
+    }
+
+    fn load_account(&self, address: B160) -> DatabaseResult<AccountInfo> {
+        let err = |e| DatabaseError::GetAccount(b160_to_h160(address), Arc::new(e));
+        // accounts that don't exist are cached empty, like the RPC backend does
+        let mut info = self.source.basic(address).map_err(err)?.unwrap_or_default();
+        if info.code.is_none() && info.code_hash != KECCAK_EMPTY {
+            let code = self.source.code_by_hash(info.code_hash).map_err(err)?;
+            let missing = DatabaseError::MissingCode(b256_to_h256(info.code_hash));
+            info.code = Some(code.ok_or(missing)?);
+        }
+        self.db.db().do_insert_account(address, info.clone());
+        Ok(info)
+    }
+}
+
+impl<S: StateSource> DatabaseRef for LocalBackend<S> {
+    type Error = DatabaseError;
+
+    fn basic(&self, address: B160) -> Result<Option<AccountInfo>, Self::Error> {
+        trace!(target: "localbackend", "request basic {:?}", address);
+        if let Some(info) = self.db.db().account(&address) {
+            return Ok(Some(info));
+        }
+        if self.db.is_known_absent(&address) {
+            return Ok(Some(AccountInfo::default()));
+        }
+        self.load_account(address).map(Some)
+    }
+
+    fn code_by_hash(&self, hash: B256) -> Result<Bytecode, Self::Error> {
+        // code is loaded together with the account, same as the RPC backend
+        Err(DatabaseError::MissingCode(b256_to_h256(hash)))
+    }
+
+    fn storage(&self, address: B160, index: rU256) -> Result<rU256, Self::Error> {
+        trace!(target: "localbackend", "request storage {:?} at {:?}", address, index);
+        if let Some(value) = self.db.db().storage_slot(&address, &index) {
+            return Ok(value);
+        }
+        if self.db.is_known_absent(&address) {
+            return Ok(rU256::ZERO);
+        }
+        let value = self.source.storage(address, index).map_err(|e| {
+            DatabaseError::GetStorage(b160_to_h160(address), index.into(), Arc::new(e))
+        })?;
+        self.db.db().insert_storage(address, index, value);
+        Ok(value)
+    }
+
+    fn block_hash(&self, number: rU256) -> Result<B256, Self::Error> {
+        if number > rU256::from(u64::MAX) {
+            return Ok(KECCAK_EMPTY);
+        }
+        if let Some(hash) = self.db.db().block_hash(&number) {
+            return Ok(hash);
+        }
+        let n = number.as_limbs()[0];
+        let hash = self
+            .source
+            .block_hash(n)
+            .map_err(|e| DatabaseError::GetBlockHash(n, Arc::new(e)))?
+            .unwrap_or(KECCAK_EMPTY);
+        self.db.block_hashes().insert(number, hash);
+        Ok(hash)
+    }
+}
+
+#[cfg(feature = "reth")]
+pub use self::reth::RethStateSource;
+
+#[cfg(feature = "reth")]
+mod reth {
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use parking_lot::RwLock;
+    use reth_chainspec::ChainSpec;
+    use reth_db::{mdbx::DatabaseArguments, models::ClientVersion, open_db_read_only, DatabaseEnv};
+    use reth_primitives::{Address, B256 as RethB256, U256 as RethU256};
+    use reth_provider::{
+        providers::StaticFileProvider, AccountReader, BlockHashReader, ProviderFactory,
+        StateProvider, StateProviderBox, StateProviderFactory,
+    };
+    use revm::primitives::{AccountInfo, Bytecode, B160, B256, U256 as rU256};
+
+    use super::StateSource;
+
+    /// Reads the latest state straight from the database of a local Reth node, opened read only
+    /// next to the running node
+    pub struct RethStateSource {
+        factory: ProviderFactory<Arc<DatabaseEnv>>,
+        latest: RwLock<StateProviderBox>,
+    }
+
+    impl std::fmt::Debug for RethStateSource {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("RethStateSource").finish_non_exhaustive()
+        }
+    }
+
+    impl RethStateSource {
+        /// Open the database in `datadir`, e.g. `~/.local/share/reth/mainnet`
+        pub fn open(datadir: impl AsRef<Path>, chain: Arc<ChainSpec>) -> eyre::Result<Self> {
+            let datadir = datadir.as_ref();
+            let db = open_db_read_only(
+                &datadir.join("db"),
+                DatabaseArguments::new(ClientVersion::default()),
+            )?;
+            let static_files = StaticFileProvider::read_only(datadir.join("static_files"))?;
+            Self::new(ProviderFactory::new(Arc::new(db), chain, static_files))
+        }
+
+        pub fn new(factory: ProviderFactory<Arc<DatabaseEnv>>) -> eyre::Result<Self> {
+            let latest = factory.latest()?;
+            Ok(Self {
+                factory,
+                latest: RwLock::new(latest),
+            })
+        }
+
+        /// Move to the node's new tip, to be called on every new block. Reads keep seeing the
+        /// state they were opened at until then.
+        pub fn refresh(&self) -> eyre::Result<()> {
+            *self.latest.write() = self.factory.latest()?;
+            Ok(())
+        }
+    }
+
+    impl StateSource for RethStateSource {
+        fn basic(&self, address: B160) -> eyre::Result<Option<AccountInfo>> {
+            let Some(account) = self.latest.read().basic_account(Address::from(address.0))? else {
+                return Ok(None);
+            };
+            let mut info = AccountInfo {
+                balance: rU256::from_be_bytes(account.balance.to_be_bytes::<32>()),
+                nonce: account.nonce,
+                code: None,
+                ..Default::default()
+            };
+            if let Some(hash) = account.bytecode_hash {
+                info.code_hash = B256(hash.0);
+            }
+            Ok(Some(info))
+        }
+
+        fn code_by_hash(&self, hash: B256) -> eyre::Result<Option<Bytecode>> {
+            let code = self
+                .latest
+                .read()
+                .bytecode_by_hash(RethB256::from(hash.0))?;
+            Ok(code.map(|code| Bytecode::new_raw(code.original_bytes().to_vec().into())))
+        }
+
+        fn storage(&self, address: B160, index: rU256) -> eyre::Result<rU256> {
+            let key = RethB256::from(index.to_be_bytes::<32>());
+            let value = self
+                .latest
+                .read()
+                .storage(Address::from(address.0), key)?
+                .unwrap_or(RethU256::ZERO);
+            Ok(rU256::from_be_bytes(value.to_be_bytes::<32>()))
+        }
+
+        fn block_hash(&self, number: u64) -> eyre::Result<Option<B256>> {
+            let hash = self.factory.block_hash(number)?;
+            Ok(hash.map(|hash| B256(hash.0)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain_db::BlockchainDbMeta;
+    use hashbrown::HashMap;
+    use parking_lot::Mutex;
+    use std::collections::BTreeSet;
+
+    #[derive(Debug, Default)]
+    struct MemSource {
+        accounts: HashMap<B160, AccountInfo>,
+        code: HashMap<B256, Bytecode>,
+        storage: HashMap<(B160, rU256), rU256>,
+        reads: Mutex<usize>,
+    }
+
+    impl StateSource for MemSource {
+        fn basic(&self, address: B160) -> eyre::Result<Option<AccountInfo>> {
+            *self.reads.lock() += 1;
+            Ok(self.accounts.get(&address).cloned())
+        }
+
+        fn code_by_hash(&self, hash: B256) -> eyre::Result<Option<Bytecode>> {
+            Ok(self.code.get(&hash).cloned())
+        }
+
+        fn storage(&self, address: B160, index: rU256) -> eyre::Result<rU256> {
+            *self.reads.lock() += 1;
+            Ok(self
+                .storage
+                .get(&(address, index))
+                .copied()
+                .unwrap_or_default())
+        }
+
+        fn block_hash(&self, _number: u64) -> eyre::Result<Option<B256>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_local_backend() {
+        let db = BlockchainDb::new(
+            BlockchainDbMeta {
+                cfg_env: Default::default(),
+                block_env: Default::default(),
+                hosts: BTreeSet::new(),
+            },
+            None,
+        );
+        let contract = B160::from_low_u64_be(1);
+        let code = Bytecode::new_raw(vec![0x60, 0x00].into());
+        let mut source = MemSource::default();
+        source.accounts.insert(
+            contract,
+            AccountInfo {
+                balance: rU256::from(5u64),
+                code_hash: code.hash_slow(),
+                ..Default::default()
+            },
+        );
+        source.code.insert(code.hash_slow(), code.clone());
+        source
+            .storage
+            .insert((contract, rU256::from(1u64)), rU256::from(7u64));
+        let backend = LocalBackend::new(source, db.clone());
+
+        // code is filled in from its hash
+        let info = backend.basic(contract).unwrap().unwrap();
+        assert_eq!(info.balance, rU256::from(5u64));
+        assert_eq!(info.code.unwrap().bytes(), code.bytes());
+        assert_eq!(
+            backend.storage(contract, rU256::from(1u64)).unwrap(),
+            rU256::from(7u64)
+        );
+
+        // cached reads don't hit the source again
+        backend.basic(contract).unwrap();
+        backend.storage(contract, rU256::from(1u64)).unwrap();
+        assert_eq!(*backend.source().reads.lock(), 2);
+        assert!(db.accounts().get(&contract).is_some());
+
+        // missing accounts are cached empty
+        let missing = B160::from_low_u64_be(2);
+        assert_eq!(
+            backend.basic(missing).unwrap(),
+            Some(AccountInfo::default())
+        );
+        backend.basic(missing).unwrap();
+        assert_eq!(*backend.source().reads.lock(), 3);
+    }
+}
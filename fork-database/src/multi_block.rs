@@ -0,0 +1,304 @@
+//! Simulating plans spanning consecutive blocks
+//!
+//! When a builder wins most blocks in a row, a sandwich can open at the end of block N and close
+//! at the top of N+1, and inventory bought in one block can be carried into the next. A
+//! [MultiBlockSim] runs such a plan as one [BlockLeg] per block, each on top of the state the
+//! previous legs left, with the block env advanced in between. Txs of others expected in a block,
+//! the victim or pending txs predicted to land, go into the leg in their expected position.
+//! Between legs the basefee moves as EIP-1559 does, by how full the previous leg's block was.
+
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{BlockEnv, EVMError, Env, ExecutionResult, TxEnv, B160, U256 as rU256},
+    EVM,
+};
+
+use crate::{
+    errors::{DatabaseError, DatabaseResult},
+    sim_env::DEFAULT_BLOCK_TIME,
+    utils::RefDb,
+};
+
+#[derive(Debug, Clone)]
+pub struct LegTx {
+    pub tx: TxEnv,
+    /// whether the tx is part of our plan, only ours have to succeed
+    pub ours: bool,
+}
+
+/// EIP-1559: the basefee moves by at most 1/8 per block
+const BASEFEE_MAX_CHANGE_DENOMINATOR: u64 = 8;
+/// EIP-1559: blocks target half their gas limit
+const ELASTICITY_MULTIPLIER: u64 = 2;
+
+/// Basefee of the block after one with `basefee` which used `gas_used` of `gas_limit`
+pub fn next_basefee(basefee: rU256, gas_used: u64, gas_limit: rU256) -> rU256 {
+    let target = gas_limit / rU256::from(ELASTICITY_MULTIPLIER);
+    let gas_used = rU256::from(gas_used);
+    let denominator = rU256::from(BASEFEE_MAX_CHANGE_DENOMINATOR);
+    if target == rU256::ZERO || gas_used == target {
+        basefee
+    } else if gas_used > target {
+        let delta = basefee * (gas_used - target) / target / denominator;
+        basefee + delta.max(rU256::from(1u64))
+    } else {
+        basefee.saturating_sub(basefee * (target - gas_used) / target / denominator)
+    }
+}
+
+/// Txs of one block, in execution order
+#[derive(Debug, Clone, Default)]
+pub struct BlockLeg {
+    pub txs: Vec<LegTx>,
+    /// basefee of the block, predicted from the previous leg's by default
+    pub basefee: Option<rU256>,
+    /// fee recipient of the block, the previous block's by default
+    pub coinbase: Option<B160>,
+    /// gas the whole block is expected to use, for predicting the next leg's basefee. The gas of
+    /// the leg's own txs by default, as if they were all the block held.
+    pub block_gas_used: Option<u64>,
+}
+
+impl BlockLeg {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_ours(mut self, tx: TxEnv) -> Self {
+        self.txs.push(LegTx { tx, ours: true });
+        self
+    }
+
+    pub fn with_theirs(mut self, tx: TxEnv) -> Self {
+        self.txs.push(LegTx { tx, ours: false });
+        self
+    }
+
+    pub fn with_basefee(mut self, basefee: rU256) -> Self {
+        self.basefee = Some(basefee);
+        self
+    }
+
+    pub fn with_coinbase(mut self, coinbase: B160) -> Self {
+        self.coinbase = Some(coinbase);
+        self
+    }
+
+    pub fn with_block_gas_used(mut self, gas: u64) -> Self {
+        self.block_gas_used = Some(gas);
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LegOutcome {
+    pub block: BlockEnv,
+    /// one per tx of the leg, `None` for the txs that were invalid on the state they met, e.g.
+    /// their nonce was taken or they can't pay the basefee, and were left out
+    pub results: Vec<Option<ExecutionResult>>,
+    /// indices of the txs left out
+    pub skipped: Vec<usize>,
+    /// indices of our txs that didn't succeed, left out ones included
+    pub failed: Vec<usize>,
+    /// gas used by our txs
+    pub gas_used: u64,
+    /// gas used by all the txs of the leg
+    pub leg_gas_used: u64,
+}
+
+impl LegOutcome {
+    pub fn succeeded(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Executes legs block after block on a sandbox over `db`, nothing is committed to `db`
+pub struct MultiBlockSim<'a, DB: DatabaseRef> {
+    sandbox: CacheDB<RefDb<'a, DB>>,
+    env: Env,
+    block_time: u64,
+}
+
+impl<'a, DB> MultiBlockSim<'a, DB>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    /// `env` is the env of the first block of the plan, usually
+    /// [SimEnv::next_block_env](crate::sim_env::SimEnv::next_block_env) of the fork db
+    pub fn new(db: &'a DB, env: Env) -> Self {
+        Self {
+            sandbox: CacheDB::new(RefDb(db)),
+            env,
+            block_time: DEFAULT_BLOCK_TIME,
+        }
+    }
+
+    pub fn with_block_time(mut self, block_time: u64) -> Self {
+        self.block_time = block_time;
+        self
+    }
+
+    /// State after the legs run so far, e.g. to value the inventory carried
+    pub fn state(&self) -> &CacheDB<RefDb<'a, DB>> {
+        &self.sandbox
+    }
+
+    /// Env of the block the next leg executes in
+    pub fn next_env(&self) -> &Env {
+        &self.env
+    }
+
+    /// Execute `leg` in the next block. Txs of others failing or being invalid is fine, they're
+    /// only there for their effect on the state, invalid ones are recorded as skipped.
+    pub fn run_leg(&mut self, leg: BlockLeg) -> DatabaseResult<LegOutcome> {
+        if let Some(basefee) = leg.basefee {
+            self.env.block.basefee = basefee;
+        }
+        if let Some(coinbase) = leg.coinbase {
+            self.env.block.coinbase = coinbase;
+        }
+
+        let mut outcome = LegOutcome {
+            block: self.env.block.clone(),
+            results: Vec::with_capacity(leg.txs.len()),
+            skipped: vec![],
+            failed: vec![],
+            gas_used: 0,
+            leg_gas_used: 0,
+        };
+        for (index, planned) in leg.txs.into_iter().enumerate() {
+            let mut evm = EVM::new();
+            evm.env = self.env.clone();
+            evm.env.tx = planned.tx;
+            evm.database(&mut self.sandbox);
+            let result = match evm.transact_commit() {
+                Ok(result) => result,
+                Err(EVMError::Transaction(_)) => {
+                    outcome.skipped.push(index);
+                    if planned.ours {
+                        outcome.failed.push(index);
+                    }
+                    outcome.results.push(None);
+                    continue;
+                }
+                Err(e) => return Err(DatabaseError::msg(format!("{:?}", e))),
+            };
+            outcome.leg_gas_used += result.gas_used();
+            if planned.ours {
+                outcome.gas_used += result.gas_used();
+                if !result.is_success() {
+                    outcome.failed.push(index);
+                }
+            }
+            outcome.results.push(Some(result));
+        }
+
+        let block_gas_used = leg.block_gas_used.unwrap_or(outcome.leg_gas_used);
+        self.env.block.basefee = next_basefee(
+            self.env.block.basefee,
+            block_gas_used,
+            self.env.block.gas_limit,
+        );
+        self.env.block.number += rU256::from(1u64);
+        self.env.block.timestamp += rU256::from(self.block_time);
+        Ok(outcome)
+    }
+
+    /// Run all `legs`, stopping at the first leg where one of our txs fails, which is then the
+    /// last outcome returned
+    pub fn run(
+        &mut self,
+        legs: impl IntoIterator<Item = BlockLeg>,
+    ) -> DatabaseResult<Vec<LegOutcome>> {
+        let mut outcomes = vec![];
+        for leg in legs {
+            let outcome = self.run_leg(leg)?;
+            let succeeded = outcome.succeeded();
+            outcomes.push(outcome);
+            if !succeeded {
+                break;
+            }
+        }
+        Ok(outcomes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::{
+        db::EmptyDB,
+        primitives::{AccountInfo, Bytecode, TransactTo},
+    };
+
+    #[test]
+    fn test_legs_chain_state_and_blocks() {
+        let contract = B160::from_low_u64_be(1);
+        let caller = B160::from_low_u64_be(2);
+        // SSTORE(NUMBER, NUMBER)
+        let code = Bytecode::new_raw(vec![0x43, 0x43, 0x55, 0x00].into());
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            contract,
+            AccountInfo {
+                code_hash: code.hash_slow(),
+                code: Some(code),
+                ..Default::default()
+            },
+        );
+        db.insert_account_info(
+            caller,
+            AccountInfo {
+                balance: rU256::from(10u64.pow(18)),
+                ..Default::default()
+            },
+        );
+
+        let mut env = Env::default();
+        env.block.number = rU256::from(10u64);
+        env.block.basefee = rU256::from(8u64);
+        env.block.gas_limit = rU256::from(30_000_000u64);
+        let tx = |gas_price: u64| TxEnv {
+            caller,
+            transact_to: TransactTo::Call(contract),
+            gas_limit: 100_000,
+            gas_price: rU256::from(gas_price),
+            ..Default::default()
+        };
+
+        let mut sim = MultiBlockSim::new(&db, env);
+        let outcomes = sim
+            .run([
+                BlockLeg::new()
+                    .with_ours(tx(10))
+                    .with_block_gas_used(30_000_000),
+                // can't pay the basefee, left out without failing the plan
+                BlockLeg::new().with_theirs(tx(1)).with_theirs(tx(10)),
+            ])
+            .unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.iter().all(LegOutcome::succeeded));
+        assert_eq!(outcomes[1].block.number, rU256::from(11u64));
+        // a full block raises the basefee by an eighth
+        assert_eq!(outcomes[1].block.basefee, rU256::from(9u64));
+        assert_eq!(outcomes[1].skipped, vec![0]);
+        assert!(outcomes[1].results[0].is_none());
+        assert_eq!(outcomes[1].gas_used, 0);
+        assert!(outcomes[1].leg_gas_used > 0);
+
+        // both blocks' writes are visible, the base db is untouched
+        let state = sim.state();
+        for block in [10u64, 11] {
+            let slot = rU256::from(block);
+            assert_eq!(DatabaseRef::storage(state, contract, slot).unwrap(), slot);
+            assert_eq!(
+                DatabaseRef::storage(&db, contract, slot).unwrap(),
+                rU256::ZERO
+            );
+        }
+        // the second block was nearly empty
+        assert_eq!(sim.next_env().block.number, rU256::from(12u64));
+        assert_eq!(sim.next_env().block.basefee, rU256::from(8u64));
+    }
+}
@@ -0,0 +1,352 @@
+//! Simulating on top of a builder's pending block
+//!
+//! A backrun simulated right on top of the head block assumes it lands first in the next block.
+//! It lands behind whatever the builder already put there, which may have moved the same pools.
+//! Builders and relays exposing their current template through `eth_getBlockByNumber("pending")`
+//! let [BlockTemplate::fetch] pull it, and [PendingBase] replays it on a sandbox over the fork db
+//! so bundles are simulated in the context they'll actually be included in.
+//!
+//! Builders order by effective tip, a template tx paying less than we do likely ends up behind our
+//! bundle. An [InclusionPolicy] replays only the txs expected to precede ours, strategies set the
+//! one matching where their bundles land.
+
+use ethers::{
+    providers::Middleware,
+    types::{Address, Block, BlockId, BlockNumber, Transaction, H256, U256},
+};
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{Env, TxEnv},
+    EVM,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::{DatabaseError, DatabaseResult},
+    utils::{h160_to_b160, tx_to_tx_env, u256_to_ru256, RefDb},
+};
+
+/// A block being built, txs in the order the builder placed them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockTemplate {
+    pub number: u64,
+    pub parent_hash: H256,
+    pub coinbase: Address,
+    pub timestamp: U256,
+    pub basefee: U256,
+    pub gas_limit: U256,
+    pub txs: Vec<Transaction>,
+}
+
+impl BlockTemplate {
+    /// `None` if the block lacks what a template needs, e.g. a node returning a pending block
+    /// without a number
+    pub fn from_block(block: Block<Transaction>) -> Option<Self> {
+        Some(Self {
+            number: block.number?.as_u64(),
+            parent_hash: block.parent_hash,
+            coinbase: block.author.unwrap_or_default(),
+            timestamp: block.timestamp,
+            basefee: block.base_fee_per_gas?,
+            gas_limit: block.gas_limit,
+            txs: block.transactions,
+        })
+    }
+
+    /// The current template of the builder behind `builder`
+    pub async fn fetch<M: Middleware>(builder: &M) -> DatabaseResult<Self> {
+        let pending = BlockId::Number(BlockNumber::Pending);
+        let block = builder
+            .get_block_with_txs(pending)
+            .await
+            .map_err(|e| DatabaseError::msg(format!("Failed to get pending block: {}", e)))?
+            .ok_or(DatabaseError::BlockNotFound(pending))?;
+        Self::from_block(block)
+            .ok_or_else(|| DatabaseError::msg("pending block has no number or basefee"))
+    }
+
+    /// The template's block env on top of `env`, the env of the block it builds on
+    pub fn env(&self, env: &Env) -> Env {
+        let mut env = env.clone();
+        env.block.number = revm::primitives::U256::from(self.number);
+        env.block.coinbase = h160_to_b160(self.coinbase);
+        env.block.timestamp = u256_to_ru256(self.timestamp);
+        env.block.basefee = u256_to_ru256(self.basefee);
+        env.block.gas_limit = u256_to_ru256(self.gas_limit);
+        env.tx = TxEnv::default();
+        env
+    }
+}
+
+/// Which template txs are replayed before ours
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InclusionPolicy {
+    /// the whole template, e.g. for bundles requesting the tail of the block
+    #[default]
+    All,
+    /// txs tipping at least this much per gas at the template's basefee, i.e. those ordered
+    /// ahead of a bundle paying this tip. Ties are included, the builder may order them either way.
+    MinTip(U256),
+}
+
+impl InclusionPolicy {
+    /// The txs ahead of a bundle tipping `tip` per gas
+    pub fn ahead_of(tip: U256) -> Self {
+        Self::MinTip(tip)
+    }
+
+    pub fn includes(&self, tx: &Transaction, basefee: U256) -> bool {
+        match self {
+            Self::All => true,
+            Self::MinTip(min_tip) => {
+                matches!(effective_tip(tx, basefee), Some(tip) if tip >= *min_tip)
+            }
+        }
+    }
+}
+
+/// Tip per gas `tx` pays the builder at `basefee`, `None` if it can't pay the basefee
+pub fn effective_tip(tx: &Transaction, basefee: U256) -> Option<U256> {
+    match tx.max_fee_per_gas {
+        Some(max_fee) => {
+            let headroom = max_fee.checked_sub(basefee)?;
+            Some(headroom.min(tx.max_priority_fee_per_gas.unwrap_or_default()))
+        }
+        None => tx.gas_price.unwrap_or_default().checked_sub(basefee),
+    }
+}
+
+/// The fork db's state with a [BlockTemplate]'s txs applied, nothing is committed to the fork db
+pub struct PendingBase<'a, DB: DatabaseRef> {
+    sandbox: CacheDB<RefDb<'a, DB>>,
+    env: Env,
+    /// template txs executed, reverted ones included
+    included: Vec<H256>,
+    /// template txs that couldn't be executed on our state, e.g. a nonce we don't know of yet
+    skipped: Vec<H256>,
+    /// template txs left out by the [InclusionPolicy], expected to land after ours
+    excluded: Vec<H256>,
+    gas_used: u64,
+}
+
+impl<'a, DB> PendingBase<'a, DB>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    /// Replay `template` on `db`, whose state is the one of block `head` executed in `env`
+    ///
+    /// Fails if the template doesn't build on `head`, simulating on it would mix two chains.
+    pub fn build(
+        db: &'a DB,
+        env: &Env,
+        head: H256,
+        template: &BlockTemplate,
+    ) -> DatabaseResult<Self> {
+        Self::build_with_policy(db, env, head, template, InclusionPolicy::All)
+    }
+
+    /// [PendingBase::build] replaying only the template txs `policy` includes
+    pub fn build_with_policy(
+        db: &'a DB,
+        env: &Env,
+        head: H256,
+        template: &BlockTemplate,
+        policy: InclusionPolicy,
+    ) -> DatabaseResult<Self> {
+        let head_number: u64 = env.block.number.saturating_to();
+        if template.parent_hash != head || template.number != head_number + 1 {
+            return Err(DatabaseError::msg(format!(
+                "template {} builds on {:?}, not on block {} {:?}",
+                template.number, template.parent_hash, head_number, head
+            )));
+        }
+
+        let mut base = Self {
+            sandbox: CacheDB::new(RefDb(db)),
+            env: template.env(env),
+            included: Vec::with_capacity(template.txs.len()),
+            skipped: vec![],
+            excluded: vec![],
+            gas_used: 0,
+        };
+        for tx in &template.txs {
+            if !policy.includes(tx, template.basefee) {
+                base.excluded.push(tx.hash);
+                continue;
+            }
+            let mut evm = EVM::new();
+            evm.env = base.env.clone();
+            evm.env.tx = tx_to_tx_env(tx);
+            evm.database(&mut base.sandbox);
+            match evm.transact_commit() {
+                Ok(result) => {
+                    base.gas_used += result.gas_used();
+                    base.included.push(tx.hash);
+                }
+                Err(e) => {
+                    log::debug!("Skipping template tx {:?}: {:?}", tx.hash, e);
+                    base.skipped.push(tx.hash);
+                }
+            }
+        }
+        Ok(base)
+    }
+
+    /// State after the template's txs, to simulate our bundle on
+    pub fn state(&self) -> &CacheDB<RefDb<'a, DB>> {
+        &self.sandbox
+    }
+
+    pub fn into_state(self) -> CacheDB<RefDb<'a, DB>> {
+        self.sandbox
+    }
+
+    /// Env of the template's block, our txs execute at its end
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+
+    pub fn included(&self) -> &[H256] {
+        &self.included
+    }
+
+    pub fn skipped(&self) -> &[H256] {
+        &self.skipped
+    }
+
+    pub fn excluded(&self) -> &[H256] {
+        &self.excluded
+    }
+
+    /// Gas used by the template, what is left of the block limit is for us
+    pub fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    pub fn gas_left(&self) -> u64 {
+        self.env
+            .block
+            .gas_limit
+            .saturating_to::<u64>()
+            .saturating_sub(self.gas_used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::U64;
+    use revm::{
+        db::EmptyDB,
+        primitives::{AccountInfo, B160, U256 as rU256},
+    };
+
+    #[test]
+    fn test_template_replayed_before_our_txs() {
+        let sender = Address::from_low_u64_be(1);
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            h160_to_b160(sender),
+            AccountInfo {
+                balance: rU256::from(10u64.pow(18)),
+                ..Default::default()
+            },
+        );
+        let transfer = |nonce: u64| Transaction {
+            hash: H256::from_low_u64_be(nonce + 1),
+            from: sender,
+            to: Some(Address::from_low_u64_be(2)),
+            value: U256::from(1_000),
+            gas: U256::from(21_000),
+            gas_price: Some(U256::zero()),
+            nonce: U256::from(nonce),
+            ..Default::default()
+        };
+        let head = H256::from_low_u64_be(99);
+        let mut env = Env::default();
+        env.block.number = rU256::from(10u64);
+        let block = Block {
+            number: Some(U64::from(11)),
+            parent_hash: head,
+            base_fee_per_gas: Some(U256::zero()),
+            gas_limit: U256::from(30_000_000),
+            // the second tx reuses a nonce and can't execute
+            transactions: vec![transfer(0), transfer(0)],
+            ..Default::default()
+        };
+        let template = BlockTemplate::from_block(block).unwrap();
+
+        let base = PendingBase::build(&db, &env, head, &template).unwrap();
+        assert_eq!(base.included(), &[H256::from_low_u64_be(1)]);
+        assert_eq!(base.skipped().len(), 1);
+        assert_eq!(base.env().block.number, rU256::from(11u64));
+        assert_eq!(base.gas_left(), 30_000_000 - 21_000);
+        let receiver = DatabaseRef::basic(base.state(), B160::from_low_u64_be(2))
+            .unwrap()
+            .unwrap();
+        assert_eq!(receiver.balance, rU256::from(1_000u64));
+
+        assert!(PendingBase::build(&db, &env, H256::zero(), &template).is_err());
+    }
+
+    #[test]
+    fn test_only_txs_ahead_of_ours() {
+        let basefee = U256::from(10);
+        let legacy = |gas_price: u64| Transaction {
+            gas_price: Some(U256::from(gas_price)),
+            ..Default::default()
+        };
+        let dynamic = |max_fee: u64, max_priority_fee: u64| Transaction {
+            max_fee_per_gas: Some(U256::from(max_fee)),
+            max_priority_fee_per_gas: Some(U256::from(max_priority_fee)),
+            ..Default::default()
+        };
+        assert_eq!(effective_tip(&legacy(13), basefee), Some(U256::from(3)));
+        // capped by the max fee
+        assert_eq!(effective_tip(&dynamic(12, 5), basefee), Some(U256::from(2)));
+        assert_eq!(effective_tip(&dynamic(9, 5), basefee), None);
+
+        let policy = InclusionPolicy::ahead_of(U256::from(3));
+        assert!(policy.includes(&legacy(13), basefee));
+        assert!(policy.includes(&dynamic(20, 4), basefee));
+        assert!(!policy.includes(&dynamic(12, 5), basefee));
+        assert!(InclusionPolicy::All.includes(&dynamic(9, 5), basefee));
+
+        let sender = Address::from_low_u64_be(1);
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            h160_to_b160(sender),
+            AccountInfo {
+                balance: rU256::from(10u64.pow(18)),
+                ..Default::default()
+            },
+        );
+        let head = H256::from_low_u64_be(99);
+        let mut env = Env::default();
+        env.block.number = rU256::from(10u64);
+        let transfer = |nonce: u64, gas_price: u64| Transaction {
+            hash: H256::from_low_u64_be(nonce + 1),
+            from: sender,
+            to: Some(Address::from_low_u64_be(2)),
+            gas: U256::from(21_000),
+            gas_price: Some(U256::from(gas_price)),
+            nonce: U256::from(nonce),
+            ..Default::default()
+        };
+        let template = BlockTemplate::from_block(Block {
+            number: Some(U64::from(11)),
+            parent_hash: head,
+            base_fee_per_gas: Some(basefee),
+            gas_limit: U256::from(30_000_000),
+            transactions: vec![transfer(0, 20), transfer(1, 11)],
+            ..Default::default()
+        })
+        .unwrap();
+        let base = PendingBase::build_with_policy(&db, &env, head, &template, policy).unwrap();
+        assert_eq!(base.included(), &[H256::from_low_u64_be(1)]);
+        assert_eq!(base.excluded(), &[H256::from_low_u64_be(2)]);
+        assert!(base.skipped().is_empty());
+    }
+}
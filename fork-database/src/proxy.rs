@@ -0,0 +1,338 @@
+//! Proxy detection and implementation resolution
+//!
+//! Code and layout of a proxy say nothing about what it does, callers interested in behaviour
+//! should look at the implementation returned by [resolve_implementation]. Storage still lives at
+//! the proxy address.
+
+use ethers::{
+    providers::Middleware,
+    types::{BlockId, Bytes, TransactionRequest, H160, H256},
+    utils::keccak256,
+};
+use revm::{
+    db::DatabaseRef,
+    primitives::{
+        EVMError, ExecutionResult, Output, TransactTo, B160, KECCAK_EMPTY, U256 as rU256,
+    },
+    EVM,
+};
+
+use crate::utils::RefDb;
+
+/// Proxies pointing to proxies are followed this many times at most
+pub const MAX_PROXY_DEPTH: usize = 4;
+/// `implementation()` selector of an EIP-1967 beacon
+const BEACON_IMPLEMENTATION_SELECTOR: [u8; 4] = [0x5c, 0x60, 0xda, 0x1b];
+const BEACON_CALL_GAS: u64 = 100_000;
+/// EIP-1167 runtime code around the implementation address
+const MINIMAL_PROXY_PREFIX: [u8; 10] = [0x36, 0x3d, 0x3d, 0x37, 0x3d, 0x3d, 0x3d, 0x36, 0x3d, 0x73];
+const MINIMAL_PROXY_SUFFIX: [u8; 15] = [
+    0x5a, 0xf4, 0x3d, 0x82, 0x80, 0x3e, 0x90, 0x3d, 0x91, 0x60, 0x2b, 0x57, 0xfd, 0x5b, 0xf3,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// EIP-1967 transparent or UUPS proxy
+    Eip1967,
+    /// EIP-1967 beacon proxy, holding the beacon address
+    Beacon(B160),
+    /// EIP-1822 `PROXIABLE` slot
+    Eip1822,
+    /// Pre EIP-1967 OpenZeppelin proxy
+    ZeppelinOs,
+    /// EIP-1167 minimal proxy (clone)
+    MinimalProxy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub implementation: B160,
+}
+
+/// Implementation slot derived as `keccak256(label) - 1` as in EIP-1967
+fn eip1967_slot(label: &str) -> rU256 {
+    rU256::from_be_bytes(keccak256(label.as_bytes())) - rU256::from(1)
+}
+
+pub fn eip1967_implementation_slot() -> rU256 {
+    eip1967_slot("eip1967.proxy.implementation")
+}
+
+pub fn eip1967_beacon_slot() -> rU256 {
+    eip1967_slot("eip1967.proxy.beacon")
+}
+
+pub fn eip1822_slot() -> rU256 {
+    rU256::from_be_bytes(keccak256(b"PROXIABLE"))
+}
+
+pub fn zeppelinos_slot() -> rU256 {
+    rU256::from_be_bytes(keccak256(b"org.zeppelinos.proxy.implementation"))
+}
+
+/// Slots holding an implementation address directly
+fn implementation_slots() -> [(ProxyKind, rU256); 3] {
+    [
+        (ProxyKind::Eip1967, eip1967_implementation_slot()),
+        (ProxyKind::Eip1822, eip1822_slot()),
+        (ProxyKind::ZeppelinOs, zeppelinos_slot()),
+    ]
+}
+
+/// Implementation address baked into EIP-1167 runtime code
+pub fn minimal_proxy_target(code: &[u8]) -> Option<B160> {
+    let len = MINIMAL_PROXY_PREFIX.len() + 20 + MINIMAL_PROXY_SUFFIX.len();
+    if code.len() != len
+        || !code.starts_with(&MINIMAL_PROXY_PREFIX)
+        || !code.ends_with(&MINIMAL_PROXY_SUFFIX)
+    {
+        return None;
+    }
+    let start = MINIMAL_PROXY_PREFIX.len();
+    Some(B160::from_slice(&code[start..start + 20]))
+}
+
+/// Address stored in the low 20 bytes of a slot, `None` if unset
+fn word_to_address(word: [u8; 32]) -> Option<B160> {
+    let address = B160::from_slice(&word[12..]);
+    (address != B160::zero()).then_some(address)
+}
+
+/// Runtime code of `address`, empty for EOAs and missing accounts
+pub fn account_code<DB: DatabaseRef>(db: &DB, address: B160) -> Result<Bytes, DB::Error> {
+    let Some(info) = db.basic(address)? else {
+        return Ok(Bytes::default());
+    };
+    let code = match info.code {
+        Some(code) => code,
+        None if info.code_hash == KECCAK_EMPTY => return Ok(Bytes::default()),
+        None => db.code_by_hash(info.code_hash)?,
+    };
+    Ok(code.original_bytes().into())
+}
+
+/// Ask an EIP-1967 beacon for its current implementation
+fn beacon_implementation<DB: DatabaseRef>(
+    db: &DB,
+    beacon: B160,
+) -> Result<Option<B160>, DB::Error> {
+    let mut evm = EVM::new();
+    evm.database(RefDb(db));
+    evm.env.tx.transact_to = TransactTo::Call(beacon);
+    evm.env.tx.data = BEACON_IMPLEMENTATION_SELECTOR.to_vec().into();
+    evm.env.tx.gas_limit = BEACON_CALL_GAS;
+
+    let result = match evm.transact_ref() {
+        Ok(result) => result.result,
+        Err(EVMError::Database(e)) => return Err(e),
+        Err(_) => return Ok(None),
+    };
+    match result {
+        ExecutionResult::Success {
+            output: Output::Call(output),
+            ..
+        } if output.len() >= 32 => {
+            let mut word = [0u8; 32];
+            word.copy_from_slice(&output[..32]);
+            Ok(word_to_address(word))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Detect whether `address` is a proxy and read its current implementation
+pub fn detect_proxy<DB: DatabaseRef>(db: &DB, address: B160) -> Result<Option<Proxy>, DB::Error> {
+    let code = account_code(db, address)?;
+    if code.is_empty() {
+        return Ok(None);
+    }
+    if let Some(implementation) = minimal_proxy_target(&code) {
+        return Ok(Some(Proxy {
+            kind: ProxyKind::MinimalProxy,
+            implementation,
+        }));
+    }
+
+    for (kind, slot) in implementation_slots() {
+        let word = db.storage(address, slot)?.to_be_bytes::<32>();
+        if let Some(implementation) = word_to_address(word) {
+            return Ok(Some(Proxy {
+                kind,
+                implementation,
+            }));
+        }
+    }
+
+    let word = db
+        .storage(address, eip1967_beacon_slot())?
+        .to_be_bytes::<32>();
+    if let Some(beacon) = word_to_address(word) {
+        if let Some(implementation) = beacon_implementation(db, beacon)? {
+            return Ok(Some(Proxy {
+                kind: ProxyKind::Beacon(beacon),
+                implementation,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Contract the code of `address` is ultimately executed from, `address` itself if it isn't a
+/// proxy. Chains of proxies are followed up to [MAX_PROXY_DEPTH].
+pub fn resolve_implementation<DB: DatabaseRef>(db: &DB, address: B160) -> Result<B160, DB::Error> {
+    let mut current = address;
+    for _ in 0..MAX_PROXY_DEPTH {
+        match detect_proxy(db, current)? {
+            Some(proxy) if proxy.implementation != current => current = proxy.implementation,
+            _ => break,
+        }
+    }
+    Ok(current)
+}
+
+/// Runtime code of the implementation behind `address`
+pub fn implementation_code<DB: DatabaseRef>(db: &DB, address: B160) -> Result<Bytes, DB::Error> {
+    account_code(db, resolve_implementation(db, address)?)
+}
+
+/// [resolve_implementation] against a remote node, for callers without a fork database
+pub async fn resolve_implementation_remote<M: Middleware>(
+    provider: &M,
+    address: H160,
+    block: Option<BlockId>,
+) -> Result<H160, M::Error> {
+    let mut current = address;
+    for _ in 0..MAX_PROXY_DEPTH {
+        match detect_proxy_remote(provider, current, block).await? {
+            Some(implementation) if implementation != current => current = implementation,
+            _ => break,
+        }
+    }
+    Ok(current)
+}
+
+async fn detect_proxy_remote<M: Middleware>(
+    provider: &M,
+    address: H160,
+    block: Option<BlockId>,
+) -> Result<Option<H160>, M::Error> {
+    let code = provider.get_code(address, block).await?;
+    if code.is_empty() {
+        return Ok(None);
+    }
+    if let Some(implementation) = minimal_proxy_target(&code) {
+        return Ok(Some(H160(implementation.0)));
+    }
+
+    for (_, slot) in implementation_slots() {
+        let word = provider
+            .get_storage_at(address, H256(slot.to_be_bytes::<32>()), block)
+            .await?;
+        if let Some(implementation) = word_to_address(word.0) {
+            return Ok(Some(H160(implementation.0)));
+        }
+    }
+
+    let word = provider
+        .get_storage_at(
+            address,
+            H256(eip1967_beacon_slot().to_be_bytes::<32>()),
+            block,
+        )
+        .await?;
+    let Some(beacon) = word_to_address(word.0) else {
+        return Ok(None);
+    };
+    let call = TransactionRequest::new()
+        .to(H160(beacon.0))
+        .data(BEACON_IMPLEMENTATION_SELECTOR.to_vec());
+    let output = provider.call(&call.into(), block).await?;
+    if output.len() < 32 {
+        return Ok(None);
+    }
+    let mut word = [0u8; 32];
+    word.copy_from_slice(&output[..32]);
+    Ok(word_to_address(word).map(|a| H160(a.0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::db::{CacheDB, EmptyDB};
+    use revm::primitives::{AccountInfo, Bytecode};
+
+    fn minimal_proxy_code(implementation: B160) -> Vec<u8> {
+        [
+            &MINIMAL_PROXY_PREFIX[..],
+            implementation.as_bytes(),
+            &MINIMAL_PROXY_SUFFIX[..],
+        ]
+        .concat()
+    }
+
+    fn insert_code(db: &mut CacheDB<EmptyDB>, address: B160, code: Vec<u8>) {
+        let code = Bytecode::new_raw(code.into());
+        db.insert_account_info(
+            address,
+            AccountInfo {
+                code_hash: code.hash_slow(),
+                code: Some(code),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_slots() {
+        assert_eq!(
+            format!("{:x}", eip1967_implementation_slot()),
+            "360894a13ba1a3210667c828492db98dca3e2076cc3735a920a3ca505d382bbc"
+        );
+        assert_eq!(
+            format!("{:x}", eip1967_beacon_slot()),
+            "a3f0ad74e5423aebfd80d3ef4346578335a9a72aeaee59ff6cb3582b35133d50"
+        );
+    }
+
+    #[test]
+    fn test_resolve_implementation() {
+        let implementation = B160::from_low_u64_be(0x1000);
+        let proxy = B160::from_low_u64_be(0x2000);
+        let clone = B160::from_low_u64_be(0x3000);
+
+        let mut db = CacheDB::new(EmptyDB::default());
+        insert_code(&mut db, implementation, vec![0x60, 0x00, 0x60, 0x00, 0xf3]);
+        // any non empty code, the slot is what marks the proxy
+        insert_code(&mut db, proxy, vec![0x00]);
+        let mut word = [0u8; 32];
+        word[12..].copy_from_slice(implementation.as_bytes());
+        db.insert_account_storage(
+            proxy,
+            eip1967_implementation_slot(),
+            rU256::from_be_bytes(word),
+        )
+        .unwrap();
+        // clone of the proxy, resolved through both hops
+        insert_code(&mut db, clone, minimal_proxy_code(proxy));
+
+        assert_eq!(
+            detect_proxy(&db, clone).unwrap(),
+            Some(Proxy {
+                kind: ProxyKind::MinimalProxy,
+                implementation: proxy
+            })
+        );
+        assert_eq!(
+            detect_proxy(&db, proxy).unwrap().map(|p| p.kind),
+            Some(ProxyKind::Eip1967)
+        );
+        assert_eq!(detect_proxy(&db, implementation).unwrap(), None);
+        assert_eq!(resolve_implementation(&db, clone).unwrap(), implementation);
+        assert_eq!(
+            implementation_code(&db, clone).unwrap().to_vec(),
+            vec![0x60, 0x00, 0x60, 0x00, 0xf3]
+        );
+    }
+}
@@ -0,0 +1,407 @@
+//! Cross-checking local simulations with `eth_simulateV1`
+//!
+//! Nodes serving `eth_simulateV1` execute a whole bundle, with state and block overrides, in a
+//! single call on their own state. [RemoteSimulator] runs the bundle there as well and
+//! [compare] reports where the two disagree: a tx that succeeds locally but reverts remotely, or a
+//! gas usage off by more than a tolerance, usually means the fork db holds state the chain has
+//! moved past.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use ethers::{
+    providers::Middleware,
+    types::{Address, BlockNumber, Bytes, H256, U256, U64},
+};
+use log::{debug, warn};
+use revm::primitives::{Env, ExecutionResult, Output, TransactTo, TxEnv};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::{
+    sim_env::SimOutcome,
+    utils::{b160_to_h160, ru256_to_u256},
+};
+
+/// Relative gas difference tolerated by default, in bps
+pub const DEFAULT_GAS_TOLERANCE_BPS: u64 = 100;
+
+#[derive(Error, Debug)]
+pub enum RemoteSimError {
+    #[error("Provider error: {0}")]
+    Provider(String),
+    #[error("Unexpected eth_simulateV1 response: {0}")]
+    Response(String),
+}
+
+/// Fields of an account replaced for the simulation, e.g. the balance cheats gave it locally
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountOverride {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<U256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<Bytes>,
+    /// slots changed, the others keep their value
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub state_diff: BTreeMap<H256, H256>,
+}
+
+pub type StateOverrides = BTreeMap<Address, AccountOverride>;
+
+/// What the node reported for one call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteCallResult {
+    pub success: bool,
+    pub gas_used: u64,
+    pub return_data: Bytes,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DivergenceKind {
+    Status {
+        local: bool,
+        remote: bool,
+    },
+    GasUsed {
+        local: u64,
+        remote: u64,
+    },
+    Output {
+        local: Bytes,
+        remote: Bytes,
+    },
+    /// the node returned results for another number of txs
+    Length {
+        local: usize,
+        remote: usize,
+    },
+}
+
+/// `index`th tx of the bundle ran differently on the node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub index: usize,
+    pub kind: DivergenceKind,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SimulatedBlock {
+    calls: Vec<SimulatedCall>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SimulatedCall {
+    status: U64,
+    gas_used: U64,
+    #[serde(default)]
+    return_data: Bytes,
+    #[serde(default)]
+    error: Option<Value>,
+}
+
+#[derive(Debug)]
+pub struct RemoteSimulator<M> {
+    provider: Arc<M>,
+    gas_tolerance_bps: u64,
+}
+
+impl<M: Middleware> RemoteSimulator<M> {
+    pub fn new(provider: Arc<M>) -> Self {
+        Self {
+            provider,
+            gas_tolerance_bps: DEFAULT_GAS_TOLERANCE_BPS,
+        }
+    }
+
+    pub fn with_gas_tolerance_bps(mut self, gas_tolerance_bps: u64) -> Self {
+        self.gas_tolerance_bps = gas_tolerance_bps;
+        self
+    }
+
+    /// Simulator over `provider`, `None` if its node doesn't serve `eth_simulateV1`
+    pub async fn connect(provider: Arc<M>) -> Option<Self> {
+        Self::supported(provider.as_ref())
+            .await
+            .then(|| Self::new(provider))
+    }
+
+    /// Whether the node behind `provider` serves `eth_simulateV1`
+    pub async fn supported(provider: &M) -> bool {
+        let probe: Result<Value, _> = provider
+            .provider()
+            .request(
+                "eth_simulateV1",
+                json!([{ "blockStateCalls": [{ "calls": [] }] }, "latest"]),
+            )
+            .await;
+        debug!("eth_simulateV1 probe: {:?}", probe.as_ref().map(|_| ()));
+        probe.is_ok()
+    }
+
+    /// Execute `txs` in one block on top of `base`, with the block fields of `env`
+    pub async fn simulate_bundle(
+        &self,
+        env: &Env,
+        txs: &[TxEnv],
+        base: BlockNumber,
+        overrides: &StateOverrides,
+    ) -> Result<Vec<RemoteCallResult>, RemoteSimError> {
+        let blocks: Vec<SimulatedBlock> = self
+            .provider
+            .provider()
+            .request(
+                "eth_simulateV1",
+                json!([simulate_payload(env, txs, overrides), base]),
+            )
+            .await
+            .map_err(|e| RemoteSimError::Provider(e.to_string()))?;
+        let block = blocks
+            .into_iter()
+            .next()
+            .ok_or_else(|| RemoteSimError::Response("no block simulated".to_string()))?;
+        Ok(block
+            .calls
+            .into_iter()
+            .map(|call| RemoteCallResult {
+                success: call.status == U64::one(),
+                gas_used: call.gas_used.as_u64(),
+                return_data: call.return_data,
+                error: call.error.map(|e| e.to_string()),
+            })
+            .collect())
+    }
+
+    /// Simulate `txs` remotely and [compare] with the `local` outcomes
+    pub async fn cross_check(
+        &self,
+        env: &Env,
+        txs: &[TxEnv],
+        local: &[SimOutcome],
+        base: BlockNumber,
+        overrides: &StateOverrides,
+    ) -> Result<Vec<Divergence>, RemoteSimError> {
+        let remote = self.simulate_bundle(env, txs, base, overrides).await?;
+        let divergences = compare(local, &remote, self.gas_tolerance_bps);
+        if !divergences.is_empty() {
+            warn!(
+                "Local simulation diverges from eth_simulateV1, fork state may be stale: {:?}",
+                divergences
+            );
+        }
+        Ok(divergences)
+    }
+}
+
+/// First param of `eth_simulateV1` for `txs` executed in one block, without validation so
+/// unsigned txs and zero fees pass like they do locally
+pub fn simulate_payload(env: &Env, txs: &[TxEnv], overrides: &StateOverrides) -> Value {
+    let block = &env.block;
+    json!({
+        "blockStateCalls": [{
+            "blockOverrides": {
+                "number": ru256_to_u256(block.number),
+                "time": ru256_to_u256(block.timestamp),
+                "feeRecipient": b160_to_h160(block.coinbase),
+                "baseFeePerGas": ru256_to_u256(block.basefee),
+                "gasLimit": ru256_to_u256(block.gas_limit),
+            },
+            "stateOverrides": overrides,
+            "calls": txs.iter().map(call_object).collect::<Vec<_>>(),
+        }],
+        "validation": false,
+    })
+}
+
+fn call_object(tx: &TxEnv) -> Value {
+    let mut call = json!({
+        "from": b160_to_h160(tx.caller),
+        "gas": U64::from(tx.gas_limit),
+        "value": ru256_to_u256(tx.value),
+        "input": Bytes::from(tx.data.to_vec()),
+    });
+    if let TransactTo::Call(to) = tx.transact_to {
+        call["to"] = json!(b160_to_h160(to));
+    }
+    if let Some(nonce) = tx.nonce {
+        call["nonce"] = json!(U64::from(nonce));
+    }
+    match tx.gas_priority_fee {
+        Some(priority_fee) => {
+            call["maxFeePerGas"] = json!(ru256_to_u256(tx.gas_price));
+            call["maxPriorityFeePerGas"] = json!(ru256_to_u256(priority_fee));
+        }
+        None => call["gasPrice"] = json!(ru256_to_u256(tx.gas_price)),
+    }
+    call
+}
+
+/// Where `remote` disagrees with `local`, tx by tx. Outputs are only compared when both succeed,
+/// gas when both agree on the status.
+pub fn compare(
+    local: &[SimOutcome],
+    remote: &[RemoteCallResult],
+    gas_tolerance_bps: u64,
+) -> Vec<Divergence> {
+    let mut divergences = vec![];
+    if local.len() != remote.len() {
+        divergences.push(Divergence {
+            index: local.len().min(remote.len()),
+            kind: DivergenceKind::Length {
+                local: local.len(),
+                remote: remote.len(),
+            },
+        });
+    }
+    for (index, (local, remote)) in local.iter().zip(remote).enumerate() {
+        let (success, gas_used, output) = local_result(&local.result);
+        if success != remote.success {
+            divergences.push(Divergence {
+                index,
+                kind: DivergenceKind::Status {
+                    local: success,
+                    remote: remote.success,
+                },
+            });
+            continue;
+        }
+        let tolerance = gas_used.max(remote.gas_used) * gas_tolerance_bps / 10_000;
+        if gas_used.abs_diff(remote.gas_used) > tolerance {
+            divergences.push(Divergence {
+                index,
+                kind: DivergenceKind::GasUsed {
+                    local: gas_used,
+                    remote: remote.gas_used,
+                },
+            });
+        }
+        if success && output != remote.return_data {
+            divergences.push(Divergence {
+                index,
+                kind: DivergenceKind::Output {
+                    local: output,
+                    remote: remote.return_data.clone(),
+                },
+            });
+        }
+    }
+    divergences
+}
+
+fn local_result(result: &ExecutionResult) -> (bool, u64, Bytes) {
+    match result {
+        ExecutionResult::Success {
+            gas_used, output, ..
+        } => {
+            let output = match output {
+                Output::Call(output) => output.to_vec(),
+                // the node returns the deployed code too, compare it the same
+                Output::Create(output, _) => output.to_vec(),
+            };
+            (true, *gas_used, output.into())
+        }
+        ExecutionResult::Revert { gas_used, output } => (false, *gas_used, output.to_vec().into()),
+        ExecutionResult::Halt { gas_used, .. } => (false, *gas_used, Bytes::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::primitives::{State, B160};
+
+    fn outcome(result: ExecutionResult) -> SimOutcome {
+        SimOutcome {
+            result,
+            state: State::default(),
+            transient: Default::default(),
+            base: None,
+        }
+    }
+
+    #[test]
+    fn test_divergences() {
+        let local = vec![
+            outcome(ExecutionResult::Success {
+                reason: revm::primitives::Eval::Return,
+                gas_used: 100_000,
+                gas_refunded: 0,
+                logs: vec![],
+                output: Output::Call(vec![1u8].into()),
+            }),
+            outcome(ExecutionResult::Success {
+                reason: revm::primitives::Eval::Return,
+                gas_used: 50_000,
+                gas_refunded: 0,
+                logs: vec![],
+                output: Output::Call(Default::default()),
+            }),
+        ];
+        let remote = |success: bool, gas_used: u64, data: Vec<u8>| RemoteCallResult {
+            success,
+            gas_used,
+            return_data: data.into(),
+            error: None,
+        };
+
+        // within the gas tolerance
+        let same = vec![remote(true, 100_500, vec![1]), remote(true, 50_000, vec![])];
+        assert!(compare(&local, &same, DEFAULT_GAS_TOLERANCE_BPS).is_empty());
+
+        // the second tx reverts on the node, its pool moved on chain
+        let stale = vec![
+            remote(true, 100_000, vec![1]),
+            remote(false, 30_000, vec![]),
+        ];
+        assert_eq!(
+            compare(&local, &stale, DEFAULT_GAS_TOLERANCE_BPS),
+            vec![Divergence {
+                index: 1,
+                kind: DivergenceKind::Status {
+                    local: true,
+                    remote: false
+                },
+            }]
+        );
+        let short = vec![remote(true, 120_000, vec![2])];
+        let divergences = compare(&local, &short, DEFAULT_GAS_TOLERANCE_BPS);
+        assert_eq!(divergences.len(), 3);
+
+        let mut env = Env::default();
+        env.block.number = revm::primitives::U256::from(10u64);
+        let tx = TxEnv {
+            caller: B160::from_low_u64_be(1),
+            transact_to: TransactTo::Call(B160::from_low_u64_be(2)),
+            gas_priority_fee: Some(revm::primitives::U256::from(1u64)),
+            ..Default::default()
+        };
+        let mut overrides = StateOverrides::new();
+        overrides.insert(
+            Address::from_low_u64_be(1),
+            AccountOverride {
+                balance: Some(U256::exp10(18)),
+                ..Default::default()
+            },
+        );
+        let payload = simulate_payload(&env, &[tx], &overrides);
+        let block = &payload["blockStateCalls"][0];
+        assert_eq!(block["blockOverrides"]["number"], "0xa");
+        assert_eq!(block["calls"][0]["to"], json!(Address::from_low_u64_be(2)));
+        assert!(block["calls"][0]["maxPriorityFeePerGas"].is_string());
+        assert_eq!(
+            block["stateOverrides"][format!("{:?}", Address::from_low_u64_be(1))]["balance"],
+            "0xde0b6b3a7640000"
+        );
+        assert!(
+            block["stateOverrides"][format!("{:?}", Address::from_low_u64_be(1))]
+                .get("stateDiff")
+                .is_none()
+        );
+    }
+}
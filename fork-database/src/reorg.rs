@@ -0,0 +1,167 @@
+//! Detecting reorgs from the stream of new heads
+//!
+//! A [ReorgWatcher] remembers the hashes of the last blocks seen. A head that doesn't build on
+//! the block remembered at its parent's height replaces part of the chain, the replaced blocks
+//! are reported in a [ReorgEvent] so state derived from them, the fork db's block diffs or landed
+//! bundles, can be rolled back.
+
+use std::collections::BTreeMap;
+
+use ethers::{
+    providers::Middleware,
+    types::{Block, H256},
+};
+
+/// Blocks remembered by default, deeper reorgs are reported from the oldest remembered block
+pub const DEFAULT_REORG_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReorgEvent {
+    /// highest block both chains share, as far as remembered
+    pub common_ancestor: u64,
+    /// blocks no longer part of the chain, oldest first
+    pub removed: Vec<(u64, H256)>,
+    pub new_head: (u64, H256),
+}
+
+impl ReorgEvent {
+    pub fn depth(&self) -> usize {
+        self.removed.len()
+    }
+
+    pub fn removes(&self, number: u64, hash: H256) -> bool {
+        self.removed.contains(&(number, hash))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReorgWatcher {
+    hashes: BTreeMap<u64, H256>,
+    depth: usize,
+}
+
+impl Default for ReorgWatcher {
+    fn default() -> Self {
+        Self::new(DEFAULT_REORG_DEPTH)
+    }
+}
+
+impl ReorgWatcher {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            hashes: BTreeMap::new(),
+            depth: depth.max(1),
+        }
+    }
+
+    pub fn hash_at(&self, number: u64) -> Option<H256> {
+        self.hashes.get(&number).copied()
+    }
+
+    pub fn head(&self) -> Option<(u64, H256)> {
+        self.hashes.last_key_value().map(|(n, h)| (*n, *h))
+    }
+
+    /// Add the block `number` with `hash`, which builds on `parent_hash`
+    ///
+    /// Blocks have to come in order for a reorg deeper than one block to be resolved, see
+    /// [ReorgWatcher::on_head] for heads skipping blocks.
+    pub fn on_block(&mut self, number: u64, hash: H256, parent_hash: H256) -> Option<ReorgEvent> {
+        if self.hash_at(number) == Some(hash) {
+            return None;
+        }
+        let mut first_removed = number;
+        // the parent isn't ours either, walking further back needs the new chain's headers
+        if number > 0 && self.hash_at(number - 1).map_or(false, |h| h != parent_hash) {
+            first_removed = number - 1;
+        }
+        let removed: Vec<(u64, H256)> = self.hashes.split_off(&first_removed).into_iter().collect();
+        self.hashes.insert(number, hash);
+        while self.hashes.len() > self.depth {
+            self.hashes.pop_first();
+        }
+        (!removed.is_empty()).then(|| ReorgEvent {
+            common_ancestor: first_removed.saturating_sub(1),
+            removed,
+            new_head: (number, hash),
+        })
+    }
+
+    /// [ReorgWatcher::on_block] for a new head, fetching the blocks of the new chain down to the
+    /// common ancestor so a reorg of any remembered depth is reported in one event
+    pub async fn on_head<M: Middleware>(
+        &mut self,
+        provider: &M,
+        head: &Block<H256>,
+    ) -> Result<Option<ReorgEvent>, M::Error> {
+        let (Some(number), Some(hash)) = (head.number, head.hash) else {
+            return Ok(None);
+        };
+        let mut chain = vec![(number.as_u64(), hash, head.parent_hash)];
+        while chain.len() < self.depth {
+            let (number, _, parent_hash) = *chain.last().unwrap();
+            let Some(known) = number.checked_sub(1).and_then(|n| self.hash_at(n)) else {
+                break;
+            };
+            if known == parent_hash {
+                break;
+            }
+            let Some(parent) = provider.get_block(parent_hash).await? else {
+                break;
+            };
+            chain.push((number - 1, parent_hash, parent.parent_hash));
+        }
+
+        let mut event: Option<ReorgEvent> = None;
+        for (number, hash, parent_hash) in chain.into_iter().rev() {
+            let Some(next) = self.on_block(number, hash, parent_hash) else {
+                continue;
+            };
+            event = Some(match event {
+                None => next,
+                Some(mut merged) => {
+                    merged.common_ancestor = merged.common_ancestor.min(next.common_ancestor);
+                    merged.removed.extend(next.removed);
+                    merged.removed.sort();
+                    merged.removed.dedup();
+                    merged.new_head = next.new_head;
+                    merged
+                }
+            });
+        }
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reorg_watcher() {
+        let hash = |n: u64, fork: u64| H256::from_low_u64_be(n * 100 + fork);
+        let mut watcher = ReorgWatcher::new(8);
+        for n in 1..=5 {
+            assert_eq!(watcher.on_block(n, hash(n, 0), hash(n - 1, 0)), None);
+        }
+        // rebroadcast of the head
+        assert_eq!(watcher.on_block(5, hash(5, 0), hash(4, 0)), None);
+
+        // 4' and 5' replace 4 and 5, fed in order
+        let event = watcher.on_block(4, hash(4, 1), hash(3, 0)).unwrap();
+        assert_eq!(event.removed, vec![(4, hash(4, 0)), (5, hash(5, 0))]);
+        assert_eq!(event.common_ancestor, 3);
+        assert_eq!(watcher.on_block(5, hash(5, 1), hash(4, 1)), None);
+        assert_eq!(watcher.head(), Some((5, hash(5, 1))));
+
+        // a sibling of the head whose parent isn't ours either
+        let event = watcher.on_block(6, hash(6, 2), hash(5, 2)).unwrap();
+        assert!(event.removes(5, hash(5, 1)));
+        assert_eq!(event.depth(), 1);
+
+        for n in 7..20 {
+            watcher.on_block(n, hash(n, 0), hash(n - 1, 0));
+        }
+        assert_eq!(watcher.hashes.len(), 8);
+    }
+}
@@ -1,10 +1,11 @@
 // ported from foundry's executor with some modifications
 // https://github.com/foundry-rs/foundry/blob/master/evm/src/executor/fork/backend.rs
 use super::{
-    backend_handler::{BackendHandler, BackendRequest},
+    backend_handler::{BackendHandler, BackendRequest, Priority},
     blockchain_db::{BlockchainDb, FlushJsonBlockCacheDB},
     errors::{DatabaseError, DatabaseResult},
-    utils::{b160_to_h160, b256_to_h256, h256_to_b256},
+    forked_db::PinnedBackend,
+    utils::{b160_to_h160, h256_to_b256},
 };
 use ethers::{
     providers::Middleware,
@@ -22,6 +23,10 @@ use tracing::{error, trace};
 pub struct SharedBackend {
     /// channel used for sending commands related to database operations
     backend: Sender<BackendRequest>,
+    /// channel of the prefetch lane, served after `backend` by the same handler
+    prefetch: Sender<BackendRequest>,
+    /// lane the reads of this handle go through
+    priority: Priority,
     /// Ensures that the underlying cache gets flushed once the last `SharedBackend` is dropped.
     ///
     /// There is only one instance of the type, so as soon as the last `SharedBackend` is deleted,
@@ -89,9 +94,46 @@ impl SharedBackend {
         M: Middleware + Unpin + 'static + Clone,
     {
         let (backend, backend_rx) = channel(1);
+        let (prefetch, prefetch_rx) = channel(1);
         let cache = Arc::new(FlushJsonBlockCacheDB(Arc::clone(db.cache())));
-        let handler = BackendHandler::new(provider, db, backend_rx, pin_block);
-        (Self { backend, cache }, handler)
+        let handler = BackendHandler::new(provider, db, backend_rx, prefetch_rx, pin_block);
+        (Self::from_channels(backend, prefetch, cache), handler)
+    }
+
+    /// A backend sending its requests to the handler listening on the other ends of `backend`
+    /// and `prefetch`
+    pub(crate) fn from_channels(
+        backend: Sender<BackendRequest>,
+        prefetch: Sender<BackendRequest>,
+        cache: Arc<FlushJsonBlockCacheDB>,
+    ) -> Self {
+        Self {
+            backend,
+            prefetch,
+            priority: Priority::Critical,
+            cache,
+        }
+    }
+
+    /// A handle on the same backend whose reads go through the `priority` lane. Background
+    /// warm-up should read through a [Priority::Prefetch] handle so it never delays the reads
+    /// strategies are blocked on.
+    pub fn with_priority(&self, priority: Priority) -> Self {
+        Self {
+            priority,
+            ..self.clone()
+        }
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    fn sender(&self) -> Sender<BackendRequest> {
+        match self.priority {
+            Priority::Critical => self.backend.clone(),
+            Priority::Prefetch => self.prefetch.clone(),
+        }
     }
 
     /// Updates the pinned block to fetch data from
@@ -108,7 +150,7 @@ impl SharedBackend {
         tokio::task::block_in_place(|| {
             let (sender, rx) = oneshot_channel();
             let req = BackendRequest::FullBlock(block.into(), sender);
-            self.backend.clone().try_send(req)?;
+            self.sender().try_send(req)?;
             rx.recv()?
         })
     }
@@ -118,7 +160,7 @@ impl SharedBackend {
         tokio::task::block_in_place(|| {
             let (sender, rx) = oneshot_channel();
             let req = BackendRequest::Transaction(tx, sender);
-            self.backend.clone().try_send(req)?;
+            self.sender().try_send(req)?;
             rx.recv()?
         })
     }
@@ -127,7 +169,7 @@ impl SharedBackend {
         tokio::task::block_in_place(|| {
             let (sender, rx) = oneshot_channel();
             let req = BackendRequest::Basic(address, sender);
-            self.backend.clone().try_send(req)?;
+            self.sender().try_send(req)?;
             rx.recv()?.map(Some)
         })
     }
@@ -136,16 +178,44 @@ impl SharedBackend {
         tokio::task::block_in_place(|| {
             let (sender, rx) = oneshot_channel();
             let req = BackendRequest::Storage(address, index, sender);
-            self.backend.clone().try_send(req)?;
+            self.sender().try_send(req)?;
             rx.recv()?
         })
     }
 
+    fn do_get_code(&self, hash: B256) -> DatabaseResult<Bytecode> {
+        tokio::task::block_in_place(|| {
+            let (sender, rx) = oneshot_channel();
+            let req = BackendRequest::Code(hash, sender);
+            self.sender().try_send(req)?;
+            rx.recv()?
+        })
+    }
+
+    /// Loads all `slots` into the db in a single round of concurrent requests, instead of one
+    /// blocking request per slot as the EVM would. Slots already cached are answered right away.
+    /// The requests go through this handle's lane.
+    pub fn prefetch_storage(&self, slots: &[(B160, rU256)]) -> DatabaseResult<()> {
+        tokio::task::block_in_place(|| {
+            let mut receivers = Vec::with_capacity(slots.len());
+            for (address, index) in slots {
+                let (sender, rx) = oneshot_channel();
+                let req = BackendRequest::Storage(b160_to_h160(*address), (*index).into(), sender);
+                self.sender().try_send(req)?;
+                receivers.push(rx);
+            }
+            for rx in receivers {
+                rx.recv()??;
+            }
+            Ok(())
+        })
+    }
+
     fn do_get_block_hash(&self, number: u64) -> DatabaseResult<H256> {
         tokio::task::block_in_place(|| {
             let (sender, rx) = oneshot_channel();
             let req = BackendRequest::BlockHash(number, sender);
-            self.backend.clone().try_send(req)?;
+            self.sender().try_send(req)?;
             rx.recv()?
         })
     }
@@ -157,6 +227,16 @@ impl SharedBackend {
     }
 }
 
+impl PinnedBackend for SharedBackend {
+    fn set_pinned_block(&self, block: BlockId) -> eyre::Result<()> {
+        SharedBackend::set_pinned_block(self, block)
+    }
+
+    fn get_full_block(&self, block: BlockId) -> DatabaseResult<Block<Transaction>> {
+        SharedBackend::get_full_block(self, block)
+    }
+}
+
 impl DatabaseRef for SharedBackend {
     type Error = DatabaseError;
 
@@ -168,8 +248,23 @@ impl DatabaseRef for SharedBackend {
         })
     }
 
+    /// Code the EVM only knows the hash of, e.g. of accounts whose info was cached without their
+    /// code, is fetched from an account the backend has seen holding it. Fails with
+    /// [DatabaseError::MissingCode] if there is none.
     fn code_by_hash(&self, hash: B256) -> Result<Bytecode, Self::Error> {
-        Err(DatabaseError::MissingCode(b256_to_h256(hash)))
+        if hash == KECCAK_EMPTY {
+            return Ok(Bytecode::new());
+        }
+        trace!( target: "sharedbackend", "request code {:?}", hash);
+        self.do_get_code(hash).map_err(|err| {
+            // code only known by its hash is expected to be missing now and then
+            if matches!(err, DatabaseError::MissingCode(_)) {
+                trace!(target: "sharedbackend", ?hash, "no code for hash");
+            } else {
+                error!(target: "sharedbackend", ?err, ?hash, "Failed to send/recv `code_by_hash`");
+            }
+            err
+        })
     }
 
     fn storage(&self, address: B160, index: rU256) -> Result<rU256, Self::Error> {
@@ -0,0 +1,357 @@
+//! Caching bundle simulations by the state they run against
+//!
+//! A bundle rejected by a relay is usually retried right away, unchanged and against the same
+//! state, and simulated all over again. [SimCache] keys the outcomes by a [BundleKey]: the hash of
+//! the env and the [state](StateId) the bundle runs on, and the hash of its txs.
+//!
+//! The state is named by what it is rather than by where it's kept: snapshot ids restart with
+//! every fork db, and the same id taken on two forks is two different states. A block's state
+//! root names the same state everywhere, a fork with local writes is named by the block it forked
+//! off and an id its owner changes with every write.
+//!
+//! Besides raw outcomes, a cache can hold whatever is derived from running a bundle, e.g. the
+//! validations of the bundles a gate checks before sending them.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use parking_lot::Mutex;
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{keccak256, Env, TransactTo, TxEnv, B256},
+    DatabaseCommit,
+};
+
+use crate::{
+    errors::DatabaseResult,
+    sim_env::{simulate, SimOutcome},
+};
+
+/// Bundles kept by default
+pub const DEFAULT_CAPACITY: usize = 1_024;
+
+/// The state a bundle is simulated on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StateId {
+    /// the post state root of a block
+    Root(B256),
+    /// the state of the block with `block_hash` plus the local writes of a fork, `fork` has to
+    /// change whenever those do
+    Fork { fork: u64, block_hash: B256 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BundleKey {
+    pub state: B256,
+    pub bundle: B256,
+}
+
+impl BundleKey {
+    pub fn new(env: &Env, state: StateId, txs: &[TxEnv]) -> Self {
+        Self {
+            state: state_hash(env, state),
+            bundle: bundle_hash(txs),
+        }
+    }
+}
+
+/// Hash of what a simulation on `state` depends on besides the txs
+pub fn state_hash(env: &Env, state: StateId) -> B256 {
+    let block = &env.block;
+    let cfg = &env.cfg;
+    let mut buf = Vec::with_capacity(256);
+    match state {
+        StateId::Root(root) => {
+            buf.push(0);
+            buf.extend_from_slice(root.as_bytes());
+        }
+        StateId::Fork { fork, block_hash } => {
+            buf.push(1);
+            buf.extend_from_slice(&fork.to_be_bytes());
+            buf.extend_from_slice(block_hash.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&block.number.to_be_bytes::<32>());
+    buf.extend_from_slice(block.coinbase.as_bytes());
+    buf.extend_from_slice(&block.timestamp.to_be_bytes::<32>());
+    buf.extend_from_slice(&block.basefee.to_be_bytes::<32>());
+    buf.extend_from_slice(&block.gas_limit.to_be_bytes::<32>());
+    buf.extend_from_slice(block.prevrandao.unwrap_or_default().as_bytes());
+    buf.push(cfg.spec_id as u8);
+    buf.extend_from_slice(&cfg.chain_id.to_be_bytes::<32>());
+    // sims skipping checks succeed where the real txs wouldn't
+    buf.extend_from_slice(&[
+        cfg.disable_base_fee as u8,
+        cfg.disable_balance_check as u8,
+        cfg.disable_eip3607 as u8,
+        cfg.disable_block_gas_limit as u8,
+    ]);
+    keccak256(&buf)
+}
+
+/// Hash of the txs of a bundle, in order
+pub fn bundle_hash(txs: &[TxEnv]) -> B256 {
+    let mut buf = Vec::new();
+    for tx in txs {
+        buf.extend_from_slice(tx.caller.as_bytes());
+        buf.extend_from_slice(&tx.gas_limit.to_be_bytes());
+        buf.extend_from_slice(&tx.gas_price.to_be_bytes::<32>());
+        match tx.gas_priority_fee {
+            Some(fee) => {
+                buf.push(1);
+                buf.extend_from_slice(&fee.to_be_bytes::<32>());
+            }
+            None => buf.push(0),
+        }
+        match tx.transact_to {
+            TransactTo::Call(to) => {
+                buf.push(0);
+                buf.extend_from_slice(to.as_bytes());
+            }
+            TransactTo::Create(_) => buf.push(1),
+        }
+        buf.extend_from_slice(&tx.value.to_be_bytes::<32>());
+        // data is variable length, hash it so consecutive txs can't run into each other
+        buf.extend_from_slice(keccak256(&tx.data).as_bytes());
+        buf.extend_from_slice(&tx.nonce.unwrap_or(u64::MAX).to_be_bytes());
+        // lengths first, so slots of an entry can't pass for the next entry's address
+        buf.extend_from_slice(&(tx.access_list.len() as u64).to_be_bytes());
+        for (address, slots) in &tx.access_list {
+            buf.extend_from_slice(address.as_bytes());
+            buf.extend_from_slice(&(slots.len() as u64).to_be_bytes());
+            for slot in slots {
+                buf.extend_from_slice(&slot.to_be_bytes::<32>());
+            }
+        }
+    }
+    keccak256(&buf)
+}
+
+/// Execute `txs` in order on a sandbox over `db`, each on the state the previous ones left
+pub fn simulate_bundle<DB>(env: &Env, db: DB, txs: &[TxEnv]) -> DatabaseResult<Vec<SimOutcome>>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    let mut sandbox = CacheDB::new(db);
+    let mut outcomes = Vec::with_capacity(txs.len());
+    for tx in txs {
+        let mut env = env.clone();
+        env.tx = tx.clone();
+        let outcome = simulate(env, &mut sandbox)?;
+        sandbox.commit(outcome.state.clone());
+        outcomes.push(outcome);
+    }
+    Ok(outcomes)
+}
+
+#[derive(Debug)]
+struct Entries<T> {
+    outcomes: HashMap<BundleKey, Arc<T>>,
+    /// oldest first
+    order: VecDeque<BundleKey>,
+}
+
+impl<T> Default for Entries<T> {
+    fn default() -> Self {
+        Self {
+            outcomes: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+}
+
+/// Outcomes of simulated bundles, the oldest are evicted past the capacity
+#[derive(Debug)]
+pub struct SimCache<T = Vec<SimOutcome>> {
+    entries: Mutex<Entries<T>>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<T> Default for SimCache<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl<T> SimCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(Entries::default()),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &BundleKey) -> Option<Arc<T>> {
+        let outcomes = self.entries.lock().outcomes.get(key).cloned();
+        let counter = if outcomes.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        outcomes
+    }
+
+    pub fn insert(&self, key: BundleKey, outcomes: T) -> Arc<T> {
+        let outcomes = Arc::new(outcomes);
+        let mut entries = self.entries.lock();
+        if entries.outcomes.insert(key, outcomes.clone()).is_none() {
+            entries.order.push_back(key);
+        }
+        while entries.order.len() > self.capacity {
+            let oldest = entries.order.pop_front().unwrap();
+            entries.outcomes.remove(&oldest);
+        }
+        outcomes
+    }
+
+    /// The cached outcomes of `key`, or those of `simulate` which are cached if it succeeds
+    pub fn get_or_simulate<F, E>(&self, key: BundleKey, simulate: F) -> Result<Arc<T>, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        if let Some(outcomes) = self.get(&key) {
+            return Ok(outcomes);
+        }
+        Ok(self.insert(key, simulate()?))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().outcomes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock();
+        entries.outcomes.clear();
+        entries.order.clear();
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl SimCache {
+    /// [simulate_bundle] on `db`, holding `state`, unless it ran before
+    pub fn simulate_bundle<DB>(
+        &self,
+        env: &Env,
+        state: StateId,
+        db: DB,
+        txs: &[TxEnv],
+    ) -> DatabaseResult<Arc<Vec<SimOutcome>>>
+    where
+        DB: DatabaseRef,
+        DB::Error: std::fmt::Debug,
+    {
+        let key = BundleKey::new(env, state, txs);
+        self.get_or_simulate(key, || simulate_bundle(env, db, txs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::{
+        db::EmptyDB,
+        primitives::{AccountInfo, ExecutionResult, B160, U256 as rU256},
+    };
+
+    #[test]
+    fn test_resimulation_is_served_from_cache() {
+        let sender = B160::from_low_u64_be(1);
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            sender,
+            AccountInfo {
+                balance: rU256::from(10u64.pow(18)),
+                ..Default::default()
+            },
+        );
+        let transfer = |nonce| TxEnv {
+            caller: sender,
+            transact_to: TransactTo::Call(B160::from_low_u64_be(2)),
+            value: rU256::from(1u64),
+            gas_limit: 21_000,
+            nonce: Some(nonce),
+            ..Default::default()
+        };
+        let bundle = vec![transfer(0), transfer(1)];
+        let env = Env::default();
+        let state = StateId::Fork {
+            fork: 1,
+            block_hash: B256::from_low_u64_be(7),
+        };
+        let cache: SimCache = SimCache::new(1);
+
+        let outcomes = cache
+            .simulate_bundle(&env, state, db.clone(), &bundle)
+            .unwrap();
+        // the second tx runs on top of the first one, with the next nonce
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome.result, ExecutionResult::Success { .. })));
+        let cached = cache
+            .get_or_simulate(
+                BundleKey::new(&env, state, &bundle),
+                || -> DatabaseResult<_> { panic!("resimulated") },
+            )
+            .unwrap();
+        assert!(Arc::ptr_eq(&outcomes, &cached));
+        assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+        // another fork, block or bundle is another key, the oldest is evicted
+        let other_fork = StateId::Fork {
+            fork: 2,
+            block_hash: B256::from_low_u64_be(7),
+        };
+        assert_ne!(
+            BundleKey::new(&env, other_fork, &bundle),
+            BundleKey::new(&env, state, &bundle)
+        );
+        assert_ne!(
+            BundleKey::new(&env, StateId::Root(B256::from_low_u64_be(7)), &bundle),
+            BundleKey::new(&env, state, &bundle)
+        );
+        assert_ne!(bundle_hash(&bundle), bundle_hash(&bundle[..1]));
+        cache
+            .simulate_bundle(&env, state, db, &bundle[..1])
+            .unwrap();
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&BundleKey::new(&env, state, &bundle)).is_none());
+
+        // so does an env skipping checks
+        let mut unchecked = env.clone();
+        unchecked.cfg.disable_balance_check = true;
+        assert_ne!(state_hash(&unchecked, state), state_hash(&env, state));
+
+        // access lists are length delimited
+        let (a, b) = (B160::from_low_u64_be(3), B160::from_low_u64_be(4));
+        let with_list = |access_list| TxEnv {
+            access_list,
+            ..transfer(0)
+        };
+        assert_ne!(
+            bundle_hash(&[with_list(vec![(a, vec![]), (b, vec![])])]),
+            bundle_hash(&[with_list(vec![(a, vec![rU256::ZERO]), (b, vec![])])])
+        );
+    }
+}
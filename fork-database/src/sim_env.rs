@@ -0,0 +1,331 @@
+use super::{
+    errors::{DatabaseError, DatabaseResult},
+    forked_db::{ForkedDatabase, PinnedBackend},
+    inspectors::TransientStorageInspector,
+    stale::BaseBlock,
+    utils::{h160_to_b160, h256_to_b256, u256_to_ru256},
+};
+use ethers::types::{BlockId, BlockNumber, U64};
+use hashbrown::HashMap as Map;
+use revm::{
+    primitives::{BlockEnv, Env, ExecutionResult, SpecId, State, TxEnv, B160, U256 as rU256},
+    Database, EVM,
+};
+
+/// Default slot time used to predict the next block's timestamp
+pub const DEFAULT_BLOCK_TIME: u64 = 12;
+/// Mainnet activation timestamps of the forks since the merge
+pub const SHANGHAI_TIMESTAMP: u64 = 1_681_338_455;
+pub const CANCUN_TIMESTAMP: u64 = 1_710_338_135;
+
+/// Spec of a mainnet block at `timestamp`, for blocks after the merge
+pub fn spec_at(timestamp: u64) -> SpecId {
+    if timestamp >= CANCUN_TIMESTAMP {
+        SpecId::CANCUN
+    } else if timestamp >= SHANGHAI_TIMESTAMP {
+        SpecId::SHANGHAI
+    } else {
+        SpecId::MERGE
+    }
+}
+
+/// Builds [Env]s for simulating where a bundle will actually execute, rather than inside the sealed
+/// pinned block the fork database was created at
+#[derive(Debug, Clone, Copy)]
+pub struct SimEnv {
+    /// fee recipient of the block we're targeting, defaults to the pinned block's coinbase
+    pub coinbase: Option<B160>,
+    /// seconds between blocks
+    pub block_time: u64,
+    /// spec to execute with, derived from the simulated block's timestamp by default
+    pub spec: Option<SpecId>,
+}
+
+impl Default for SimEnv {
+    fn default() -> Self {
+        Self {
+            coinbase: None,
+            block_time: DEFAULT_BLOCK_TIME,
+            spec: None,
+        }
+    }
+}
+
+impl SimEnv {
+    pub fn with_coinbase(mut self, coinbase: B160) -> Self {
+        self.coinbase = Some(coinbase);
+        self
+    }
+
+    /// Execute with `spec` whatever the block, e.g. on chains with other fork timestamps
+    pub fn with_spec(mut self, spec: SpecId) -> Self {
+        self.spec = Some(spec);
+        self
+    }
+
+    /// [Env] of the block following the pinned block, with default settings
+    ///
+    /// See [SimEnv::next_block_env]
+    pub fn next_block<B: PinnedBackend>(db: &ForkedDatabase<B>) -> DatabaseResult<Env> {
+        Self::default().next_block_env(db)
+    }
+
+    /// [Env] of the pinned block itself, with default settings
+    ///
+    /// See [SimEnv::top_of_block_env]
+    pub fn top_of_block<B: PinnedBackend>(db: &ForkedDatabase<B>) -> DatabaseResult<Env> {
+        Self::default().top_of_block_env(db)
+    }
+
+    /// [Env] for executing on top of the pinned block's post-state, i.e. in the next block:
+    /// number + 1, timestamp + `block_time`, the EIP-1559 predicted basefee, the configured
+    /// coinbase and the spec active at that timestamp
+    pub fn next_block_env<B: PinnedBackend>(&self, db: &ForkedDatabase<B>) -> DatabaseResult<Env> {
+        let mut env = self.pinned_env(db);
+        let number: u64 = env.block.number.to();
+
+        // the pinned block header is needed for the gas used, which isn't part of the block env
+        let block = db
+            .backend()
+            .get_full_block(BlockId::Number(BlockNumber::Number(U64::from(number))))?;
+        let basefee = block.next_block_base_fee().ok_or_else(|| {
+            DatabaseError::msg(format!("block {} has no basefee, pre London?", number))
+        })?;
+
+        env.block = BlockEnv {
+            number: env.block.number + rU256::from(1u64),
+            coinbase: self.coinbase.unwrap_or(env.block.coinbase),
+            timestamp: env.block.timestamp + rU256::from(self.block_time),
+            basefee: u256_to_ru256(basefee),
+            // the next block's randao isn't known yet, reusing the parent's keeps PREVRANDAO
+            // deterministic
+            prevrandao: env
+                .block
+                .prevrandao
+                .or_else(|| block.mix_hash.map(h256_to_b256)),
+            ..env.block
+        };
+        env.cfg.spec_id = self.spec_for(&env.block);
+
+        Ok(env)
+    }
+
+    /// [Env] of the pinned block itself, for replaying at the top of the pinned block when the fork
+    /// database holds its parent's state (e.g. backtesting)
+    pub fn top_of_block_env<B: PinnedBackend>(
+        &self,
+        db: &ForkedDatabase<B>,
+    ) -> DatabaseResult<Env> {
+        let mut env = self.pinned_env(db);
+        let number: u64 = env.block.number.to();
+
+        let block = db
+            .backend()
+            .get_full_block(BlockId::Number(BlockNumber::Number(U64::from(number))))?;
+
+        env.block.coinbase = self
+            .coinbase
+            .or_else(|| block.author.map(h160_to_b160))
+            .unwrap_or(env.block.coinbase);
+        if let Some(basefee) = block.base_fee_per_gas {
+            env.block.basefee = u256_to_ru256(basefee);
+        }
+        env.block.timestamp = u256_to_ru256(block.timestamp);
+        env.cfg.spec_id = self.spec_for(&env.block);
+
+        Ok(env)
+    }
+
+    fn spec_for(&self, block: &BlockEnv) -> SpecId {
+        self.spec
+            .unwrap_or_else(|| spec_at(block.timestamp.saturating_to()))
+    }
+
+    /// Block `db` is pinned at, what's computed on it is checked against the
+    /// [ForkHead](crate::stale::ForkHead) with it
+    pub fn base_block<B: PinnedBackend>(
+        &self,
+        db: &ForkedDatabase<B>,
+    ) -> DatabaseResult<BaseBlock> {
+        let number: u64 = db.inner().meta().read().block_env.number.to();
+        let block = db
+            .backend()
+            .get_full_block(BlockId::Number(BlockNumber::Number(U64::from(number))))?;
+        let hash = block
+            .hash
+            .ok_or_else(|| DatabaseError::msg(format!("block {} has no hash", number)))?;
+        Ok(BaseBlock::new(number, hash))
+    }
+
+    /// [simulate] the tx of `env` on `db`, the outcome carries the block `db` is pinned at
+    pub fn simulate_on(&self, env: Env, db: &mut ForkedDatabase) -> DatabaseResult<SimOutcome> {
+        let base = self.base_block(db)?;
+        Ok(simulate(env, db)?.with_base(base))
+    }
+
+    fn pinned_env<B: PinnedBackend>(&self, db: &ForkedDatabase<B>) -> Env {
+        let meta = db.inner().meta().read();
+        Env {
+            cfg: meta.cfg_env.clone(),
+            block: meta.block_env.clone(),
+            tx: TxEnv::default(),
+        }
+    }
+}
+
+/// Result of a tx simulated with [simulate]
+#[derive(Debug, Clone)]
+pub struct SimOutcome {
+    pub result: ExecutionResult,
+    pub state: State,
+    /// EIP-1153 transient storage left by frames that didn't revert, by `(address, slot)`.
+    /// Transient storage is cleared after the tx, this is the only place it can be inspected.
+    pub transient: Map<(B160, rU256), rU256>,
+    /// block the fork was at, see [ForkHead::check](crate::stale::ForkHead::check)
+    pub base: Option<BaseBlock>,
+}
+
+impl SimOutcome {
+    pub fn with_base(mut self, base: BaseBlock) -> Self {
+        self.base = Some(base);
+        self
+    }
+}
+
+/// Execute the tx of `env` on `db` without committing it
+///
+/// Fails with a clear error when the tx halts on a Cancun opcode the configured spec doesn't
+/// have, instead of reporting a plain halt.
+pub fn simulate<DB>(env: Env, db: DB) -> DatabaseResult<SimOutcome>
+where
+    DB: Database,
+    DB::Error: std::fmt::Debug,
+{
+    let mut evm = EVM::new();
+    evm.env = env;
+    evm.database(db);
+    run_simulation(&mut evm)
+}
+
+/// [simulate] the tx of `env` as if `sender` sent it, no signature needed
+///
+/// For txs whose sender we don't want to recover or verify again, e.g. victims evaluated in the
+/// hot path, or to impersonate any address. The caller may be a contract, EIP-3607 isn't
+/// enforced, and the tx's nonce and chain id aren't checked. Neither are the caller's balance and
+/// the basefee, so unfunded addresses can be impersonated: whether the sender can actually pay
+/// for the tx is up to the caller to check.
+pub fn simulate_unsigned<DB>(env: Env, sender: B160, db: DB) -> DatabaseResult<SimOutcome>
+where
+    DB: Database,
+    DB::Error: std::fmt::Debug,
+{
+    simulate(unsigned(env, sender), db)
+}
+
+/// `env` relaxed to execute its tx from `sender` unsigned, see [simulate_unsigned]
+pub fn unsigned(mut env: Env, sender: B160) -> Env {
+    env.tx.caller = sender;
+    env.tx.nonce = None;
+    env.tx.chain_id = None;
+    env.cfg.disable_eip3607 = true;
+    env.cfg.disable_balance_check = true;
+    env.cfg.disable_base_fee = true;
+    env
+}
+
+/// [simulate] with an already configured instance, see [EvmPool](crate::evm_pool::EvmPool)
+pub(crate) fn run_simulation<DB>(evm: &mut EVM<DB>) -> DatabaseResult<SimOutcome>
+where
+    DB: Database,
+    DB::Error: std::fmt::Debug,
+{
+    let spec = evm.env.cfg.spec_id;
+    let mut inspector = TransientStorageInspector::new();
+    let result = evm
+        .inspect(&mut inspector)
+        .map_err(|e| DatabaseError::msg(format!("{:?}", e)))?;
+    if let Some(opcode) = inspector.unsupported_opcode() {
+        return Err(DatabaseError::msg(format!(
+            "opcode {:#04x} isn't available under {:?}",
+            opcode, spec
+        )));
+    }
+    Ok(SimOutcome {
+        result: result.result,
+        state: result.state,
+        transient: inspector.writes().clone(),
+        base: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unsigned_simulation() {
+        use crate::utils::typed_tx_to_tx_env;
+        use ethers::types::{transaction::eip2718::TypedTransaction, Address, TransactionRequest};
+        use revm::{
+            db::{CacheDB, EmptyDB},
+            primitives::{AccountInfo, Bytecode},
+        };
+
+        // a contract sending a tx, with a nonce the tx doesn't have
+        let sender = Address::from_low_u64_be(1);
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            h160_to_b160(sender),
+            AccountInfo {
+                nonce: 5,
+                code: Some(Bytecode::new_raw(vec![0x00].into())),
+                ..Default::default()
+            },
+        );
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(Address::from_low_u64_be(2))
+            .gas(21_000)
+            .gas_price(0)
+            .nonce(0)
+            .chain_id(10)
+            .into();
+        let mut env = Env::default();
+        env.block.gas_limit = rU256::from(30_000_000u64);
+        env.tx = typed_tx_to_tx_env(&tx, h160_to_b160(sender), &env.block);
+        assert_eq!(env.tx.nonce, Some(0));
+
+        assert!(simulate(env.clone(), &mut db).is_err());
+        let outcome = simulate_unsigned(env.clone(), h160_to_b160(sender), &mut db).unwrap();
+        assert!(outcome.result.is_success());
+        assert_eq!(outcome.result.gas_used(), 21_000);
+
+        // an unfunded address paying less than the basefee, with gas left to the block
+        let unfunded = Address::from_low_u64_be(3);
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(Address::from_low_u64_be(2))
+            .value(1)
+            .gas_price(1)
+            .into();
+        env.block.basefee = rU256::from(10u64);
+        env.tx = typed_tx_to_tx_env(&tx, h160_to_b160(unfunded), &env.block);
+        assert_eq!(env.tx.gas_limit, 30_000_000);
+        assert!(simulate(env.clone(), &mut db).is_err());
+        let outcome = simulate_unsigned(env.clone(), h160_to_b160(unfunded), &mut db).unwrap();
+        assert!(outcome.result.is_success());
+
+        // gas out of the u64 range is capped rather than panicking
+        let mut tx = tx;
+        tx.set_gas(ethers::types::U256::MAX);
+        assert_eq!(
+            typed_tx_to_tx_env(&tx, h160_to_b160(unfunded), &env.block).gas_limit,
+            30_000_000
+        );
+    }
+
+    #[test]
+    fn test_spec_at() {
+        assert_eq!(spec_at(SHANGHAI_TIMESTAMP - 1), SpecId::MERGE);
+        assert_eq!(spec_at(SHANGHAI_TIMESTAMP), SpecId::SHANGHAI);
+        assert_eq!(spec_at(CANCUN_TIMESTAMP + 12), SpecId::CANCUN);
+    }
+}
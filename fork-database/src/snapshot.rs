@@ -1,8 +1,8 @@
 // ported from foundry's executor
 // https://github.com/foundry-rs/foundry/blob/master/evm/src/executor/backend/snapshot.rs
-use hashbrown::HashMap as Map;
+use hashbrown::{HashMap as Map, HashSet};
 use revm::{
-    primitives::{AccountInfo, Env, B160, B256, U256},
+    primitives::{AccountInfo, Bytecode, Env, B160, B256, U256},
     JournaledState,
 };
 use serde::{Deserialize, Serialize};
@@ -13,6 +13,26 @@ pub struct StateSnapshot {
     pub accounts: Map<B160, AccountInfo>,
     pub storage: Map<B160, Map<U256, U256>>,
     pub block_hashes: Map<U256, B256>,
+    /// accounts known to not exist, missing from older cache files
+    #[serde(default)]
+    pub known_absent: HashSet<B160>,
+    /// code of the `accounts` by hash, see [StateSnapshot::index_codes]
+    #[serde(skip)]
+    pub codes: Map<B256, Bytecode>,
+}
+
+impl StateSnapshot {
+    /// Index the code of the `accounts` by hash, so code only referenced by its hash is found
+    /// without scanning every account
+    pub fn index_codes(&mut self) {
+        for info in self.accounts.values() {
+            if let Some(code) = &info.code {
+                self.codes
+                    .entry(info.code_hash)
+                    .or_insert_with(|| code.clone());
+            }
+        }
+    }
 }
 
 /// Represents a snapshot taken during evm execution
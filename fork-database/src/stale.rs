@@ -0,0 +1,112 @@
+//! Guarding against state the fork has moved past
+//!
+//! Opportunities and simulations are computed on the fork at some block. Once the fork advanced,
+//! or that block got reorged out, a bundle built from them trades on state that no longer exists
+//! and at best reverts. What's derived from the fork carries its [BaseBlock], the bundle builder
+//! and relay client check it against the [ForkHead] before anything goes out.
+
+use std::sync::Arc;
+
+use ethers::types::H256;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Block the fork was at when something was computed on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BaseBlock {
+    pub number: u64,
+    pub hash: H256,
+}
+
+impl BaseBlock {
+    pub fn new(number: u64, hash: H256) -> Self {
+        Self { number, hash }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum StaleStateError {
+    #[error("computed on block {}, the fork is at {} since", base.number, head.number)]
+    Advanced { base: BaseBlock, head: BaseBlock },
+    #[error("block {} was replaced, {:?} instead of {:?}", base.number, head.hash, base.hash)]
+    Reorged { base: BaseBlock, head: BaseBlock },
+    #[error("computed on block {}, the fork's head isn't known yet", base.number)]
+    Unknown { base: BaseBlock },
+}
+
+/// Block the fork is currently at, shared between simulation and submission
+#[derive(Debug, Clone, Default)]
+pub struct ForkHead {
+    head: Arc<RwLock<Option<BaseBlock>>>,
+}
+
+impl ForkHead {
+    pub fn new(head: BaseBlock) -> Self {
+        Self {
+            head: Arc::new(RwLock::new(Some(head))),
+        }
+    }
+
+    /// The fork moved to `head`, a block at the same height replaces the previous one
+    pub fn advance(&self, head: BaseBlock) {
+        *self.head.write() = Some(head);
+    }
+
+    pub fn get(&self) -> Option<BaseBlock> {
+        *self.head.read()
+    }
+
+    /// Whether `base` is still the fork's block. Nothing is known before the first
+    /// [ForkHead::advance], `base` is then refused rather than sent blind.
+    pub fn check(&self, base: BaseBlock) -> Result<(), StaleStateError> {
+        let Some(head) = self.get() else {
+            return Err(StaleStateError::Unknown { base });
+        };
+        if head.number > base.number {
+            Err(StaleStateError::Advanced { base, head })
+        } else if head.number == base.number && head.hash != base.hash {
+            Err(StaleStateError::Reorged { base, head })
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn is_current(&self, base: BaseBlock) -> bool {
+        self.check(base).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_base_is_refused() {
+        let base = BaseBlock::new(10, H256::from_low_u64_be(10));
+        let head = ForkHead::default();
+        assert_eq!(head.check(base), Err(StaleStateError::Unknown { base }));
+
+        head.advance(base);
+        assert_eq!(head.check(base), Ok(()));
+
+        // same height, other block
+        let replaced = BaseBlock::new(10, H256::from_low_u64_be(11));
+        head.advance(replaced);
+        assert_eq!(
+            head.check(base),
+            Err(StaleStateError::Reorged {
+                base,
+                head: replaced
+            })
+        );
+
+        let next = BaseBlock::new(11, H256::from_low_u64_be(12));
+        head.clone().advance(next);
+        assert!(matches!(
+            head.check(base),
+            Err(StaleStateError::Advanced { .. })
+        ));
+        assert!(head.is_current(next));
+    }
+}
@@ -0,0 +1,436 @@
+//! Solidity storage layout helpers
+//!
+//! Slots follow the solidity rules: a mapping value lives at `keccak256(key . base)`, with value
+//! type keys left padded (signed ones sign extended) to a word, and dynamic array elements start
+//! at `keccak256(base)`.
+//!
+//! Value types smaller than a word are packed low to high in declaration order, structs start a
+//! new slot and may span several. [Field] describes one member the way solc's `storageLayout`
+//! output does (slot, byte offset, type), so packed signed ints like V3 ticks are sign extended
+//! instead of read as huge unsigned values.
+
+use ethers::types::{Address, H256, I256, U256};
+use ethers::utils::keccak256;
+
+/// `balanceOf` mapping slot of the mainnet WETH9 contract
+pub const WETH_BALANCE_OF_SLOT: u64 = 3;
+
+/// A value type usable as a mapping key, encoded the way solidity pads it to a word
+pub trait SlotKey {
+    fn to_word(&self) -> [u8; 32];
+}
+
+impl SlotKey for Address {
+    fn to_word(&self) -> [u8; 32] {
+        H256::from(*self).0
+    }
+}
+
+impl SlotKey for H256 {
+    fn to_word(&self) -> [u8; 32] {
+        self.0
+    }
+}
+
+impl SlotKey for U256 {
+    fn to_word(&self) -> [u8; 32] {
+        let mut word = [0u8; 32];
+        self.to_big_endian(&mut word);
+        word
+    }
+}
+
+impl SlotKey for I256 {
+    fn to_word(&self) -> [u8; 32] {
+        self.into_raw().to_word()
+    }
+}
+
+impl SlotKey for bool {
+    fn to_word(&self) -> [u8; 32] {
+        U256::from(*self as u8).to_word()
+    }
+}
+
+macro_rules! impl_slot_key {
+    ($($unsigned:ty),* ; $($signed:ty),*) => {
+        $(impl SlotKey for $unsigned {
+            fn to_word(&self) -> [u8; 32] {
+                U256::from(*self).to_word()
+            }
+        })*
+        $(impl SlotKey for $signed {
+            fn to_word(&self) -> [u8; 32] {
+                I256::from(*self).to_word()
+            }
+        })*
+    };
+}
+
+impl_slot_key!(u8, u16, u32, u64, u128; i8, i16, i32, i64, i128);
+
+/// Slot of `mapping[key]` for a mapping declared at `base`
+pub fn mapping_slot(key: impl SlotKey, base: impl Into<U256>) -> U256 {
+    let mut preimage = [0u8; 64];
+    preimage[..32].copy_from_slice(&key.to_word());
+    preimage[32..].copy_from_slice(&base.into().to_word());
+    U256::from_big_endian(&keccak256(preimage))
+}
+
+/// Slot of `mapping[outer][inner]`, e.g. ERC20 `allowance[owner][spender]`
+pub fn nested_mapping_slot(
+    outer: impl SlotKey,
+    inner: impl SlotKey,
+    base: impl Into<U256>,
+) -> U256 {
+    mapping_slot(inner, mapping_slot(outer, base))
+}
+
+/// Slot of the first word of `array[index]` for a dynamic array declared at `base`, whose
+/// elements take `element_words` words each
+pub fn array_slot(base: impl Into<U256>, index: impl Into<U256>, element_words: u64) -> U256 {
+    let start = U256::from_big_endian(&keccak256(base.into().to_word()));
+    start.overflowing_add(index.into() * element_words).0
+}
+
+/// Slot as the `H256` key used by state diffs and `eth_getStorageAt`
+pub fn slot_key(slot: U256) -> H256 {
+    H256(slot.to_word())
+}
+
+/// Unsigned value packed at `offset` bits from the low end of `word`, `width` bits wide
+pub fn unpack(word: U256, offset: usize, width: usize) -> U256 {
+    let shifted = word >> offset;
+    if width >= 256 {
+        return shifted;
+    }
+    shifted & ((U256::one() << width) - 1)
+}
+
+/// Signed value packed at `offset` bits from the low end of `word`, `width` bits wide, e.g. an
+/// `int24` tick
+pub fn unpack_signed(word: U256, offset: usize, width: usize) -> I256 {
+    let value = unpack(word, offset, width);
+    if width > 0 && width < 256 && value.bit(width - 1) {
+        // sign extend
+        I256::from_raw(value | (U256::MAX << width))
+    } else {
+        I256::from_raw(value)
+    }
+}
+
+/// Bool packed at `offset` bits from the low end of `word`
+pub fn unpack_bool(word: U256, offset: usize) -> bool {
+    word.bit(offset)
+}
+
+/// Address packed at `offset` bits from the low end of `word`
+pub fn unpack_address(word: U256, offset: usize) -> Address {
+    let value = unpack(word, offset, 160);
+    Address::from(H256(value.to_word()))
+}
+
+/// `word` with the `width` bits at `offset` replaced by the low bits of `value`, the other
+/// members packed in the word are kept
+pub fn pack(word: U256, offset: usize, width: usize, value: U256) -> U256 {
+    if width >= 256 {
+        return value << offset;
+    }
+    let mask = ((U256::one() << width) - 1) << offset;
+    (word & !mask) | ((value << offset) & mask)
+}
+
+/// Type of a value type member, widths in bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    Uint(usize),
+    Int(usize),
+    Bool,
+    Address,
+}
+
+impl FieldType {
+    /// Parse a solc `storageLayout` type label, e.g. `t_int24`. `None` for non value types.
+    pub fn from_solc(label: &str) -> Option<Self> {
+        let label = label.strip_prefix("t_")?;
+        let bits = |digits: &str| -> Option<usize> {
+            match digits {
+                "" => Some(256),
+                digits => digits.parse().ok().filter(|bits| *bits > 0 && *bits <= 256),
+            }
+        };
+        match label {
+            "bool" => Some(FieldType::Bool),
+            "address" | "address_payable" => Some(FieldType::Address),
+            _ => match label.strip_prefix("uint") {
+                Some(digits) => bits(digits).map(FieldType::Uint),
+                None => bits(label.strip_prefix("int")?).map(FieldType::Int),
+            },
+        }
+    }
+
+    pub fn bits(&self) -> usize {
+        match self {
+            FieldType::Uint(bits) | FieldType::Int(bits) => *bits,
+            FieldType::Bool => 8,
+            FieldType::Address => 160,
+        }
+    }
+}
+
+/// A decoded member
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotValue {
+    Uint(U256),
+    Int(I256),
+    Bool(bool),
+    Address(Address),
+}
+
+/// A member of a struct or contract, placed like solc's `storageLayout` does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    /// slot relative to the start of the struct
+    pub slot: u64,
+    /// offset in bytes from the low end of the slot
+    pub offset: usize,
+    pub ty: FieldType,
+}
+
+impl Field {
+    pub const fn new(name: &'static str, slot: u64, offset: usize, ty: FieldType) -> Self {
+        Self {
+            name,
+            slot,
+            offset,
+            ty,
+        }
+    }
+
+    /// Read the member from the word at its slot
+    pub fn decode(&self, word: U256) -> SlotValue {
+        let offset = self.offset * 8;
+        match self.ty {
+            FieldType::Uint(bits) => SlotValue::Uint(unpack(word, offset, bits)),
+            FieldType::Int(bits) => SlotValue::Int(unpack_signed(word, offset, bits)),
+            FieldType::Bool => SlotValue::Bool(unpack_bool(word, offset)),
+            FieldType::Address => SlotValue::Address(unpack_address(word, offset)),
+        }
+    }
+
+    /// Write the member into the word at its slot, signed values are stored in two's complement
+    /// truncated to the member's width like solc does
+    pub fn encode(&self, word: U256, value: SlotValue) -> U256 {
+        let raw = match value {
+            SlotValue::Uint(value) => value,
+            SlotValue::Int(value) => value.into_raw(),
+            SlotValue::Bool(value) => U256::from(value as u8),
+            SlotValue::Address(value) => U256::from_big_endian(H256::from(value).as_bytes()),
+        };
+        pack(word, self.offset * 8, self.ty.bits(), raw)
+    }
+}
+
+/// Decode every member of a struct from its `words`, starting at the struct's first slot. Members
+/// in slots past `words` are skipped.
+pub fn decode_struct(fields: &[Field], words: &[U256]) -> Vec<(&'static str, SlotValue)> {
+    fields
+        .iter()
+        .filter_map(|field| {
+            let word = words.get(field.slot as usize)?;
+            Some((field.name, field.decode(*word)))
+        })
+        .collect()
+}
+
+/// Words a struct made of `fields` takes
+pub fn struct_words(fields: &[Field]) -> u64 {
+    fields.iter().map(|field| field.slot + 1).max().unwrap_or(0)
+}
+
+/// Slot of `UniswapV2Pair`'s packed reserves, see [V2_RESERVES]
+pub const V2_RESERVES_SLOT: u64 = 8;
+
+/// `UniswapV2Pair`'s `reserve0`, `reserve1` and `blockTimestampLast`, packed in
+/// [V2_RESERVES_SLOT] of the pair
+pub const V2_RESERVES: &[Field] = &[
+    Field::new("reserve0", 0, 0, FieldType::Uint(112)),
+    Field::new("reserve1", 0, 14, FieldType::Uint(112)),
+    Field::new("blockTimestampLast", 0, 28, FieldType::Uint(32)),
+];
+
+/// Slot of `UniswapV3Pool.slot0`, see [V3_SLOT0]
+pub const V3_SLOT0_SLOT: u64 = 0;
+
+/// `UniswapV3Pool.slot0`, slot 0 of the pool
+pub const V3_SLOT0: &[Field] = &[
+    Field::new("sqrtPriceX96", 0, 0, FieldType::Uint(160)),
+    Field::new("tick", 0, 20, FieldType::Int(24)),
+    Field::new("observationIndex", 0, 23, FieldType::Uint(16)),
+    Field::new("observationCardinality", 0, 25, FieldType::Uint(16)),
+    Field::new("observationCardinalityNext", 0, 27, FieldType::Uint(16)),
+    Field::new("feeProtocol", 0, 29, FieldType::Uint(8)),
+    Field::new("unlocked", 0, 30, FieldType::Bool),
+];
+
+/// Slot of `UniswapV3Pool.liquidity`, see [V3_LIQUIDITY]
+pub const V3_LIQUIDITY_SLOT: u64 = 4;
+
+/// `UniswapV3Pool.liquidity`, in range liquidity alone in [V3_LIQUIDITY_SLOT]
+pub const V3_LIQUIDITY: Field = Field::new("liquidity", 0, 0, FieldType::Uint(128));
+
+/// V3 `Tick.Info`, the values of the pool's `ticks` mapping
+pub const V3_TICK_INFO: &[Field] = &[
+    Field::new("liquidityGross", 0, 0, FieldType::Uint(128)),
+    Field::new("liquidityNet", 0, 16, FieldType::Int(128)),
+    Field::new("feeGrowthOutside0X128", 1, 0, FieldType::Uint(256)),
+    Field::new("feeGrowthOutside1X128", 2, 0, FieldType::Uint(256)),
+    Field::new("tickCumulativeOutside", 3, 0, FieldType::Int(56)),
+    Field::new("secondsPerLiquidityOutsideX128", 3, 7, FieldType::Uint(160)),
+    Field::new("secondsOutside", 3, 27, FieldType::Uint(32)),
+    Field::new("initialized", 3, 31, FieldType::Bool),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::{self, Token};
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_mapping_slots_match_abi_encoding() {
+        let holder = Address::from_low_u64_be(0xbeef);
+        let expected = U256::from_big_endian(&keccak256(abi::encode(&[
+            Token::Address(holder),
+            Token::Uint(U256::from(3)),
+        ])));
+        assert_eq!(mapping_slot(holder, 3u64), expected);
+
+        let expected = U256::from_big_endian(&keccak256(abi::encode(&[
+            Token::Int(I256::from(-60).into_raw()),
+            Token::Uint(U256::from(5)),
+        ])));
+        assert_eq!(mapping_slot(-60i32, 5u64), expected);
+
+        let spender = Address::from_low_u64_be(0xcafe);
+        assert_eq!(
+            nested_mapping_slot(holder, spender, 4u64),
+            mapping_slot(spender, mapping_slot(holder, 4u64))
+        );
+    }
+
+    #[test]
+    fn test_array_slot() {
+        let start = U256::from_big_endian(&keccak256(U256::from(2).to_word()));
+        assert_eq!(array_slot(2u64, 0u64, 1), start);
+        assert_eq!(array_slot(2u64, 3u64, 2), start + 6);
+    }
+
+    #[test]
+    fn test_unpack() {
+        // V2 reserves: reserve0 (112) | reserve1 (112) | blockTimestampLast (32)
+        let word = U256::from(7) | (U256::from(9) << 112) | (U256::from(1234) << 224);
+        assert_eq!(unpack(word, 0, 112), U256::from(7));
+        assert_eq!(unpack(word, 112, 112), U256::from(9));
+        assert_eq!(unpack(word, 224, 32), U256::from(1234));
+
+        // V3 slot0 tick, an int24 right after the 160 bit sqrt price
+        let word = U256::from(0xfffffe_u64) << 160;
+        assert_eq!(unpack_signed(word, 160, 24), I256::from(-2));
+        assert_eq!(unpack_signed(U256::from(5) << 160, 160, 24), I256::from(5));
+
+        let address = Address::from_low_u64_be(0xdead);
+        let word = U256::from_big_endian(H256::from(address).as_bytes()) << 8 | U256::one();
+        assert!(unpack_bool(word, 0));
+        assert_eq!(unpack_address(word, 8), address);
+    }
+
+    /// `storageLayout` of `UniswapV3Pool.Slot0` and `Tick.Info` as emitted by solc 0.7.6
+    fn solc_layouts() -> serde_json::Value {
+        json!({
+            "Slot0": [
+                { "label": "sqrtPriceX96", "offset": 0, "slot": "0", "type": "t_uint160" },
+                { "label": "tick", "offset": 20, "slot": "0", "type": "t_int24" },
+                { "label": "observationIndex", "offset": 23, "slot": "0", "type": "t_uint16" },
+                { "label": "observationCardinality", "offset": 25, "slot": "0", "type": "t_uint16" },
+                { "label": "observationCardinalityNext", "offset": 27, "slot": "0", "type": "t_uint16" },
+                { "label": "feeProtocol", "offset": 29, "slot": "0", "type": "t_uint8" },
+                { "label": "unlocked", "offset": 30, "slot": "0", "type": "t_bool" }
+            ],
+            "Info": [
+                { "label": "liquidityGross", "offset": 0, "slot": "0", "type": "t_uint128" },
+                { "label": "liquidityNet", "offset": 16, "slot": "0", "type": "t_int128" },
+                { "label": "feeGrowthOutside0X128", "offset": 0, "slot": "1", "type": "t_uint256" },
+                { "label": "feeGrowthOutside1X128", "offset": 0, "slot": "2", "type": "t_uint256" },
+                { "label": "tickCumulativeOutside", "offset": 0, "slot": "3", "type": "t_int56" },
+                { "label": "secondsPerLiquidityOutsideX128", "offset": 7, "slot": "3", "type": "t_uint160" },
+                { "label": "secondsOutside", "offset": 27, "slot": "3", "type": "t_uint32" },
+                { "label": "initialized", "offset": 31, "slot": "3", "type": "t_bool" }
+            ]
+        })
+    }
+
+    #[test]
+    fn test_layouts_match_solc() {
+        let layouts = solc_layouts();
+        for (name, fields) in [("Slot0", V3_SLOT0), ("Info", V3_TICK_INFO)] {
+            let members = layouts[name].as_array().unwrap();
+            assert_eq!(members.len(), fields.len());
+            for (member, field) in members.iter().zip(fields) {
+                assert_eq!(member["label"], field.name);
+                assert_eq!(member["slot"], field.slot.to_string());
+                assert_eq!(member["offset"], field.offset);
+                assert_eq!(
+                    FieldType::from_solc(member["type"].as_str().unwrap()),
+                    Some(field.ty)
+                );
+            }
+        }
+        assert_eq!(struct_words(V3_TICK_INFO), 4);
+        assert_eq!(FieldType::from_solc("t_mapping(t_int24,t_struct)"), None);
+    }
+
+    fn arbitrary_value(ty: FieldType, raw: [u8; 32]) -> SlotValue {
+        let raw = U256::from_big_endian(&raw);
+        match ty {
+            FieldType::Uint(bits) => SlotValue::Uint(unpack(raw, 0, bits)),
+            FieldType::Int(bits) => SlotValue::Int(unpack_signed(raw, 0, bits)),
+            FieldType::Bool => SlotValue::Bool(raw.bit(0)),
+            FieldType::Address => SlotValue::Address(unpack_address(raw, 0)),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_struct_roundtrip(raw in prop::collection::vec(any::<[u8; 32]>(), 8)) {
+            for fields in [V2_RESERVES, V3_SLOT0, V3_TICK_INFO] {
+                let values: Vec<SlotValue> = fields
+                    .iter()
+                    .zip(&raw)
+                    .map(|(field, raw)| arbitrary_value(field.ty, *raw))
+                    .collect();
+                let mut words = vec![U256::zero(); struct_words(fields) as usize];
+                for (field, value) in fields.iter().zip(&values) {
+                    let word = &mut words[field.slot as usize];
+                    *word = field.encode(*word, *value);
+                }
+                // packing a member never clobbers its neighbours
+                let decoded = decode_struct(fields, &words);
+                for ((name, value), (field, expected)) in decoded.iter().zip(fields.iter().zip(&values)) {
+                    prop_assert_eq!(*name, field.name);
+                    prop_assert_eq!(value, expected);
+                }
+            }
+        }
+
+        #[test]
+        fn test_tick_sign_extension(tick in -887272i32..=887272, sqrt_price in any::<u128>()) {
+            let word = V3_SLOT0[0].encode(U256::zero(), SlotValue::Uint(U256::from(sqrt_price)));
+            let word = V3_SLOT0[1].encode(word, SlotValue::Int(I256::from(tick)));
+            prop_assert_eq!(V3_SLOT0[1].decode(word), SlotValue::Int(I256::from(tick)));
+            prop_assert_eq!(V3_SLOT0[0].decode(word), SlotValue::Uint(U256::from(sqrt_price)));
+        }
+    }
+}
@@ -61,3 +61,144 @@ pub fn u256_to_ru256(u: ethers::types::U256) -> revm::primitives::U256 {
 pub fn ru256_to_u256(u: revm::primitives::U256) -> ethers::types::U256 {
     ethers::types::U256::from_little_endian(&u.as_le_bytes())
 }
+
+/// Small helper function to build revm's [TxEnv](revm::primitives::TxEnv) from an ethers's
+/// [Transaction](ethers::types::Transaction), legacy txs pay their gas price as priority fee.
+pub fn tx_to_tx_env(tx: &ethers::types::Transaction) -> revm::primitives::TxEnv {
+    use revm::primitives::{CreateScheme, TransactTo, TxEnv};
+
+    let (gas_price, gas_priority_fee) = match tx.max_fee_per_gas {
+        Some(max_fee) => (
+            u256_to_ru256(max_fee),
+            tx.max_priority_fee_per_gas.map(u256_to_ru256),
+        ),
+        None => (u256_to_ru256(tx.gas_price.unwrap_or_default()), None),
+    };
+
+    TxEnv {
+        caller: h160_to_b160(tx.from),
+        gas_limit: tx.gas.as_u64(),
+        gas_price,
+        gas_priority_fee,
+        transact_to: match tx.to {
+            Some(to) => TransactTo::Call(h160_to_b160(to)),
+            None => TransactTo::Create(CreateScheme::Create),
+        },
+        value: u256_to_ru256(tx.value),
+        data: tx.input.0.clone(),
+        chain_id: tx.chain_id.map(|id| id.as_u64()),
+        nonce: Some(tx.nonce.as_u64()),
+        access_list: tx
+            .access_list
+            .as_ref()
+            .map(|list| {
+                list.0
+                    .iter()
+                    .map(|item| {
+                        (
+                            h160_to_b160(item.address),
+                            item.storage_keys
+                                .iter()
+                                .map(|key| revm::primitives::U256::from_be_bytes(key.0))
+                                .collect(),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+/// [tx_to_tx_env] for a tx that doesn't carry its sender, e.g. one decoded without recovering its
+/// signature. `caller` is taken as the sender, ENS names as `to` aren't resolved and create a
+/// contract instead. A tx without gas, or with more than `block` allows, gets the block's gas
+/// limit.
+pub fn typed_tx_to_tx_env(
+    tx: &ethers::types::transaction::eip2718::TypedTransaction,
+    caller: revm::primitives::B160,
+    block: &revm::primitives::BlockEnv,
+) -> revm::primitives::TxEnv {
+    use ethers::types::transaction::eip2718::TypedTransaction;
+    use revm::primitives::{CreateScheme, TransactTo, TxEnv};
+
+    let block_gas_limit = u64::try_from(block.gas_limit).unwrap_or(u64::MAX);
+
+    let (gas_price, gas_priority_fee) = match tx {
+        TypedTransaction::Eip1559(tx) => (
+            tx.max_fee_per_gas.map(u256_to_ru256).unwrap_or_default(),
+            tx.max_priority_fee_per_gas.map(u256_to_ru256),
+        ),
+        _ => (tx.gas_price().map(u256_to_ru256).unwrap_or_default(), None),
+    };
+
+    TxEnv {
+        caller,
+        gas_limit: tx.gas().map_or(block_gas_limit, |gas| {
+            gas.min(ethers::types::U256::from(block_gas_limit)).as_u64()
+        }),
+        gas_price,
+        gas_priority_fee,
+        transact_to: match tx.to_addr() {
+            Some(to) => TransactTo::Call(h160_to_b160(*to)),
+            None => TransactTo::Create(CreateScheme::Create),
+        },
+        value: tx.value().copied().map(u256_to_ru256).unwrap_or_default(),
+        data: tx.data().map(|data| data.0.clone()).unwrap_or_default(),
+        chain_id: tx.chain_id().map(|id| id.as_u64()),
+        nonce: tx.nonce().map(|nonce| nonce.as_u64()),
+        access_list: tx
+            .access_list()
+            .map(|list| {
+                list.0
+                    .iter()
+                    .map(|item| {
+                        (
+                            h160_to_b160(item.address),
+                            item.storage_keys
+                                .iter()
+                                .map(|key| revm::primitives::U256::from_be_bytes(key.0))
+                                .collect(),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+/// Borrowed [DatabaseRef](revm::db::DatabaseRef), lets an `EVM` or a sandbox `CacheDB` run on top
+/// of a db without taking ownership of it.
+pub struct RefDb<'a, DB>(pub &'a DB);
+
+impl<'a, DB: revm::db::DatabaseRef> revm::db::DatabaseRef for RefDb<'a, DB> {
+    type Error = DB::Error;
+
+    fn basic(
+        &self,
+        address: revm::primitives::B160,
+    ) -> Result<Option<revm::primitives::AccountInfo>, Self::Error> {
+        self.0.basic(address)
+    }
+
+    fn code_by_hash(
+        &self,
+        code_hash: revm::primitives::B256,
+    ) -> Result<revm::primitives::Bytecode, Self::Error> {
+        self.0.code_by_hash(code_hash)
+    }
+
+    fn storage(
+        &self,
+        address: revm::primitives::B160,
+        index: revm::primitives::U256,
+    ) -> Result<revm::primitives::U256, Self::Error> {
+        self.0.storage(address, index)
+    }
+
+    fn block_hash(
+        &self,
+        number: revm::primitives::U256,
+    ) -> Result<revm::primitives::B256, Self::Error> {
+        self.0.block_hash(number)
+    }
+}
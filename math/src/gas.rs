@@ -0,0 +1,157 @@
+//! Gas costs beyond execution gas
+//!
+//! On OP-stack chains (Optimism, Base) a tx also pays for posting its data to L1, usually the
+//! larger part of its cost. Since Fjord the fee is derived from the FastLZ compressed size of the
+//! signed tx, see the `GasPriceOracle` predeploy and op-geth's `rollup_cost.go`.
+
+use ethers_core::types::U256;
+
+/// Linear regression from the FastLZ size to the brotli size batches are actually posted with
+const FJORD_INTERCEPT: i64 = -42_585_600;
+const FJORD_FASTLZ_COEF: i64 = 836_500;
+/// Smallest size a tx is charged for, scaled by 1e6 like the regression
+const FJORD_MIN_TX_SIZE_SCALED: i64 = 100 * 1_000_000;
+/// Signature bytes added to unsigned txs when estimating, same as the oracle
+const SIGNATURE_OVERHEAD: usize = 68;
+
+const HASH_TABLE_SIZE: usize = 8192;
+
+/// L1 fee parameters, as read from the `L1Block` predeploy of the L2
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct L1FeeParams {
+    pub l1_base_fee: U256,
+    pub l1_blob_base_fee: U256,
+    pub base_fee_scalar: u32,
+    pub blob_base_fee_scalar: u32,
+}
+
+impl L1FeeParams {
+    /// L1 data fee of a signed, RLP encoded tx
+    pub fn l1_fee(&self, signed_tx: &[u8]) -> U256 {
+        self.fee_for_fastlz_size(flz_compress_len(signed_tx) as u64)
+    }
+
+    /// Upper bound of the L1 fee of a tx whose unsigned encoding is `unsigned_size` bytes, for
+    /// txs that aren't signed yet. Assumes the tx doesn't compress at all.
+    pub fn l1_fee_upper_bound(&self, unsigned_size: usize) -> U256 {
+        let size = (unsigned_size + SIGNATURE_OVERHEAD) as u64;
+        // FastLZ never grows the input by more than this
+        self.fee_for_fastlz_size(size + size / 255 + 16)
+    }
+
+    fn fee_for_fastlz_size(&self, fastlz_size: u64) -> U256 {
+        let estimated = (FJORD_INTERCEPT + FJORD_FASTLZ_COEF * fastlz_size as i64)
+            .max(FJORD_MIN_TX_SIZE_SCALED) as u64;
+        let fee_per_byte_scaled = U256::from(self.base_fee_scalar) * self.l1_base_fee * 16
+            + U256::from(self.blob_base_fee_scalar) * self.l1_blob_base_fee;
+        U256::from(estimated) * fee_per_byte_scaled / U256::exp10(12)
+    }
+}
+
+/// Length of `data` compressed with FastLZ level 1, without compressing it. A port of op-geth's
+/// `FlzCompressLen`, which the fee formula is defined against.
+pub fn flz_compress_len(data: &[u8]) -> u32 {
+    let mut n = 0u32;
+    let mut table = [0u32; HASH_TABLE_SIZE];
+    let u24 = |i: u32| {
+        let i = i as usize;
+        data[i] as u32 | (data[i + 1] as u32) << 8 | (data[i + 2] as u32) << 16
+    };
+    let hash = |v: u32| ((2_654_435_769u32.wrapping_mul(v)) >> 19) & 0x1fff;
+    let literals = |n: &mut u32, r: u32| {
+        *n += 0x21 * (r / 0x20);
+        if r % 0x20 != 0 {
+            *n += r % 0x20 + 1;
+        }
+    };
+    let matched = |n: &mut u32, l: u32| {
+        let l = l - 1;
+        *n += 3 * (l / 262);
+        *n += if l % 262 >= 6 { 3 } else { 2 };
+    };
+    // length of the common run at `p` and `q`, counting the first mismatching byte like op-geth
+    let common = |p: u32, q: u32, end: u32| {
+        let mut l = 0u32;
+        let mut e = end - q;
+        while l < e {
+            if data[(p + l) as usize] != data[(q + l) as usize] {
+                e = 0;
+            }
+            l += 1;
+        }
+        l
+    };
+
+    let mut anchor = 0u32;
+    let ip_limit = if data.len() < 13 {
+        0
+    } else {
+        data.len() as u32 - 13
+    };
+    let mut ip = anchor + 2;
+    while ip < ip_limit {
+        let mut reference;
+        loop {
+            let seq = u24(ip);
+            let h = hash(seq) as usize;
+            reference = table[h];
+            table[h] = ip;
+            let distance = ip - reference;
+            if ip >= ip_limit {
+                break;
+            }
+            ip += 1;
+            if distance <= 0x1fff && seq == u24(reference) {
+                break;
+            }
+        }
+        if ip >= ip_limit {
+            break;
+        }
+        ip -= 1;
+        if ip > anchor {
+            literals(&mut n, ip - anchor);
+        }
+        let l = common(reference + 3, ip + 3, ip_limit + 9);
+        matched(&mut n, l);
+        ip += l;
+        for _ in 0..2 {
+            table[hash(u24(ip)) as usize] = ip;
+            ip += 1;
+        }
+        anchor = ip;
+    }
+    literals(&mut n, data.len() as u32 - anchor);
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flz_compress_len() {
+        // too short to match anything, one literal run plus its marker
+        assert_eq!(flz_compress_len(&[]), 0);
+        assert_eq!(flz_compress_len(&[1, 2, 3]), 4);
+        // a long run of zeros compresses to a handful of bytes
+        let zeros = flz_compress_len(&[0u8; 1_000]);
+        assert!(zeros < 30, "{}", zeros);
+    }
+
+    #[test]
+    fn test_l1_fee() {
+        let params = L1FeeParams {
+            l1_base_fee: U256::exp10(10),
+            l1_blob_base_fee: U256::one(),
+            base_fee_scalar: 1_368,
+            blob_base_fee_scalar: 810_949,
+        };
+        // small txs are charged the 100 byte minimum
+        let expected = U256::from(100u64)
+            * (U256::from(1_368u64 * 16) * U256::exp10(10) + U256::from(810_949u64))
+            / U256::exp10(6);
+        assert_eq!(params.l1_fee(&[1, 2, 3]), expected);
+        assert!(params.l1_fee_upper_bound(1_000) > params.l1_fee(&[0u8; 1_000]));
+    }
+}
@@ -0,0 +1,9 @@
+//! Swap and opportunity math shared by the bot and its monitoring tools
+//!
+//! Nothing in here touches the network or a runtime, so it builds for wasm32 as well:
+//! `cargo build -p qilin_math --target wasm32-unknown-unknown`
+
+pub mod gas;
+pub mod sandwich;
+pub mod tax;
+pub mod v2;
@@ -0,0 +1,433 @@
+use ethers_core::types::{I256, U256};
+
+use crate::tax::TaxRates;
+use crate::v2::get_amount_out;
+
+/// Number of ternary search rounds, enough to narrow any u128 range down to a handful of wei
+const SEARCH_ITERATIONS: usize = 256;
+
+/// Outcome of sandwiching a victim swap on a Uniswap V2 pair, all amounts in raw token units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SandwichPlan {
+    /// weth sent into the pair by the frontrun
+    pub frontrun_in: U256,
+    /// other token received by the frontrun, sent back in by the backrun
+    pub frontrun_out: U256,
+    /// other token the victim receives after the frontrun
+    pub victim_out: U256,
+    /// weth received by the backrun
+    pub backrun_out: U256,
+    /// `backrun_out - frontrun_in`, before gas
+    pub profit: I256,
+}
+
+/// Plays frontrun -> victim -> backrun against the pair's reserves
+///
+/// Arguments:
+/// * `reserve_weth`, `reserve_token`: reserves of the pair before the victim
+/// * `frontrun_in`: weth the frontrun swaps in
+/// * `victim_in`: weth the victim swaps in
+/// * `victim_min_out`: victim's slippage bound
+///
+/// Returns:
+/// Some(SandwichPlan): amounts of each leg
+/// None: the frontrun moves the price past the victim's slippage bound, the victim would revert
+pub fn simulate_v2_sandwich(
+    reserve_weth: U256,
+    reserve_token: U256,
+    frontrun_in: U256,
+    victim_in: U256,
+    victim_min_out: U256,
+) -> Option<SandwichPlan> {
+    simulate_v2_sandwich_taxed(
+        reserve_weth,
+        reserve_token,
+        frontrun_in,
+        victim_in,
+        victim_min_out,
+        TaxRates::default(),
+    )
+}
+
+/// [simulate_v2_sandwich] for a fee-on-transfer token
+///
+/// The buy tax is taken from what the frontrun and the victim receive, the sell tax from what the
+/// backrun sends back into the pair. `frontrun_out` and `victim_out` are amounts received after
+/// tax.
+pub fn simulate_v2_sandwich_taxed(
+    reserve_weth: U256,
+    reserve_token: U256,
+    frontrun_in: U256,
+    victim_in: U256,
+    victim_min_out: U256,
+    tax: TaxRates,
+) -> Option<SandwichPlan> {
+    let frontrun_gross = get_amount_out(frontrun_in, reserve_weth, reserve_token);
+    let frontrun_out = tax.apply_buy(frontrun_gross);
+    let reserve_weth = reserve_weth + frontrun_in;
+    let reserve_token = reserve_token.checked_sub(frontrun_gross)?;
+
+    let victim_gross = get_amount_out(victim_in, reserve_weth, reserve_token);
+    let victim_out = tax.apply_buy(victim_gross);
+    if victim_out < victim_min_out {
+        return None;
+    }
+    let reserve_weth = reserve_weth + victim_in;
+    let reserve_token = reserve_token.checked_sub(victim_gross)?;
+
+    let backrun_out = get_amount_out(tax.apply_sell(frontrun_out), reserve_token, reserve_weth);
+    let profit = I256::from_raw(backrun_out) - I256::from_raw(frontrun_in);
+
+    Some(SandwichPlan {
+        frontrun_in,
+        frontrun_out,
+        victim_out,
+        backrun_out,
+        profit,
+    })
+}
+
+/// Finds the frontrun size maximizing profit, capped by `max_frontrun_in`
+///
+/// Profit is unimodal in the frontrun size until the victim's slippage bound is hit, past which
+/// the sandwich is invalid, so invalid sizes are treated as the lowest possible profit.
+///
+/// Returns `None` if no size yields a positive profit.
+pub fn optimize_v2_sandwich(
+    reserve_weth: U256,
+    reserve_token: U256,
+    victim_in: U256,
+    victim_min_out: U256,
+    max_frontrun_in: U256,
+) -> Option<SandwichPlan> {
+    optimize_v2_sandwich_taxed(
+        reserve_weth,
+        reserve_token,
+        victim_in,
+        victim_min_out,
+        max_frontrun_in,
+        TaxRates::default(),
+    )
+}
+
+/// [optimize_v2_sandwich] for a fee-on-transfer token, see [simulate_v2_sandwich_taxed]
+pub fn optimize_v2_sandwich_taxed(
+    reserve_weth: U256,
+    reserve_token: U256,
+    victim_in: U256,
+    victim_min_out: U256,
+    max_frontrun_in: U256,
+    tax: TaxRates,
+) -> Option<SandwichPlan> {
+    optimize_v2_sandwich_traced(
+        reserve_weth,
+        reserve_token,
+        victim_in,
+        victim_min_out,
+        max_frontrun_in,
+        tax,
+        |_, _| {},
+    )
+}
+
+/// [optimize_v2_sandwich_taxed] calling `on_probe` with every frontrun size it evaluates and the
+/// profit there, `None` where the victim would revert
+pub fn optimize_v2_sandwich_traced(
+    reserve_weth: U256,
+    reserve_token: U256,
+    victim_in: U256,
+    victim_min_out: U256,
+    max_frontrun_in: U256,
+    tax: TaxRates,
+    mut on_probe: impl FnMut(U256, Option<I256>),
+) -> Option<SandwichPlan> {
+    let best = search(U256::zero(), max_frontrun_in, |amount| {
+        let plan = simulate_v2_sandwich_taxed(
+            reserve_weth,
+            reserve_token,
+            amount,
+            victim_in,
+            victim_min_out,
+            tax,
+        );
+        on_probe(amount, plan.map(|plan| plan.profit));
+        plan
+    });
+    best.filter(|plan| plan.profit > I256::zero())
+}
+
+/// Where to start a sandwich search, e.g. the optimal frontrun size found for the same victim on
+/// the previous block's reserves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchHint {
+    pub frontrun_in: U256,
+    /// sizes searched around `frontrun_in`, in bps of it
+    pub window_bps: u32,
+}
+
+/// [optimize_v2_sandwich_taxed] searching only the window of `hint`
+///
+/// Falls back to the full search if the best size found sits on an edge of the window that isn't
+/// a bound of the full range, the optimum may then lie outside of it.
+pub fn optimize_v2_sandwich_from(
+    reserve_weth: U256,
+    reserve_token: U256,
+    victim_in: U256,
+    victim_min_out: U256,
+    max_frontrun_in: U256,
+    tax: TaxRates,
+    hint: SearchHint,
+) -> Option<SandwichPlan> {
+    optimize_v2_sandwich_within(
+        reserve_weth,
+        reserve_token,
+        victim_in,
+        victim_min_out,
+        max_frontrun_in,
+        tax,
+        hint,
+    )
+    .unwrap_or_else(|| {
+        optimize_v2_sandwich_taxed(
+            reserve_weth,
+            reserve_token,
+            victim_in,
+            victim_min_out,
+            max_frontrun_in,
+            tax,
+        )
+    })
+}
+
+/// The window search of [optimize_v2_sandwich_from] without its fallback, `None` if the optimum
+/// may lie outside the window and the full search is needed
+pub fn optimize_v2_sandwich_within(
+    reserve_weth: U256,
+    reserve_token: U256,
+    victim_in: U256,
+    victim_min_out: U256,
+    max_frontrun_in: U256,
+    tax: TaxRates,
+    hint: SearchHint,
+) -> Option<Option<SandwichPlan>> {
+    let hint_in = hint.frontrun_in.min(max_frontrun_in);
+    let window = (hint_in * hint.window_bps / 10_000).max(U256::from(3));
+    let low = hint_in.saturating_sub(window);
+    let high = hint_in.saturating_add(window).min(max_frontrun_in);
+
+    let best = search(low, high, |amount| {
+        simulate_v2_sandwich_taxed(
+            reserve_weth,
+            reserve_token,
+            amount,
+            victim_in,
+            victim_min_out,
+            tax,
+        )
+    });
+    match best {
+        Some(plan)
+            if (plan.frontrun_in > low || low.is_zero())
+                && (plan.frontrun_in < high || high == max_frontrun_in) =>
+        {
+            Some(Some(plan).filter(|plan| plan.profit > I256::zero()))
+        }
+        _ => None,
+    }
+}
+
+/// Ternary search of the most profitable size in `low..=high`, sizes where `simulate` fails count
+/// as the lowest profit
+fn search(
+    mut low: U256,
+    mut high: U256,
+    mut simulate: impl FnMut(U256) -> Option<SandwichPlan>,
+) -> Option<SandwichPlan> {
+    let mut profit_at = |amount: U256| simulate(amount).map_or(I256::MIN, |plan| plan.profit);
+    for _ in 0..SEARCH_ITERATIONS {
+        if high - low < U256::from(3) {
+            break;
+        }
+        let third = (high - low) / 3;
+        let m1 = low + third;
+        let m2 = high - third;
+        if profit_at(m1) < profit_at(m2) {
+            low = m1;
+        } else {
+            high = m2;
+        }
+    }
+
+    let mut best: Option<SandwichPlan> = None;
+    let mut amount = low;
+    while amount <= high {
+        if let Some(plan) = simulate(amount) {
+            if best.map_or(true, |b| plan.profit > b.profit) {
+                best = Some(plan);
+            }
+        }
+        amount += U256::one();
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ether(n: u64) -> U256 {
+        U256::exp10(18) * n
+    }
+
+    #[test]
+    fn test_optimize_v2_sandwich() {
+        let (reserve_weth, reserve_token) = (ether(1_000), ether(2_000_000));
+        let victim_in = ether(50);
+
+        // no slippage bound, the optimizer is only limited by our balance
+        let unbounded = optimize_v2_sandwich(
+            reserve_weth,
+            reserve_token,
+            victim_in,
+            U256::zero(),
+            ether(10),
+        )
+        .unwrap();
+        assert_eq!(unbounded.frontrun_in, ether(10));
+
+        // with a 2% slippage bound the frontrun has to stay small enough for the victim to land
+        let quote = get_amount_out(victim_in, reserve_weth, reserve_token);
+        let min_out = quote * 98 / 100;
+        let bounded = optimize_v2_sandwich(
+            reserve_weth,
+            reserve_token,
+            victim_in,
+            min_out,
+            ether(1_000),
+        )
+        .unwrap();
+        assert!(bounded.victim_out >= min_out);
+        assert!(bounded.profit > I256::zero());
+        assert!(simulate_v2_sandwich(
+            reserve_weth,
+            reserve_token,
+            bounded.frontrun_in + ether(1),
+            victim_in,
+            min_out
+        )
+        .is_none());
+
+        // a victim this small doesn't cover the fees of both legs
+        assert!(optimize_v2_sandwich(
+            reserve_weth,
+            reserve_token,
+            U256::from(1_000),
+            U256::zero(),
+            ether(10)
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_taxed_sandwich() {
+        let (reserve_weth, reserve_token) = (ether(1_000), ether(2_000_000));
+        let victim_in = ether(50);
+        let untaxed = optimize_v2_sandwich(
+            reserve_weth,
+            reserve_token,
+            victim_in,
+            U256::zero(),
+            ether(10),
+        )
+        .unwrap();
+
+        // a round trip through a 1% / 1% token eats into the profit
+        let tax = TaxRates::new(100, 100);
+        let taxed = simulate_v2_sandwich_taxed(
+            reserve_weth,
+            reserve_token,
+            untaxed.frontrun_in,
+            victim_in,
+            U256::zero(),
+            tax,
+        )
+        .unwrap();
+        assert_eq!(taxed.frontrun_out, tax.apply_buy(untaxed.frontrun_out));
+        assert!(taxed.profit < untaxed.profit);
+
+        // and a 10% / 10% one makes it unprofitable
+        assert!(optimize_v2_sandwich_taxed(
+            reserve_weth,
+            reserve_token,
+            victim_in,
+            U256::zero(),
+            ether(10),
+            TaxRates::new(1_000, 1_000),
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_warm_started_sandwich() {
+        let (reserve_weth, reserve_token) = (ether(1_000), ether(2_000_000));
+        let victim_in = ether(50);
+        let quote = get_amount_out(victim_in, reserve_weth, reserve_token);
+        let min_out = quote * 98 / 100;
+        let cold = optimize_v2_sandwich(
+            reserve_weth,
+            reserve_token,
+            victim_in,
+            min_out,
+            ether(1_000),
+        )
+        .unwrap();
+
+        // reserves barely moved, the previous optimum is a good enough start
+        let (moved_weth, moved_token) = (reserve_weth + ether(1), reserve_token - ether(1_990));
+        let full = optimize_v2_sandwich(moved_weth, moved_token, victim_in, min_out, ether(1_000))
+            .unwrap();
+        let warm = optimize_v2_sandwich_from(
+            moved_weth,
+            moved_token,
+            victim_in,
+            min_out,
+            ether(1_000),
+            TaxRates::default(),
+            SearchHint {
+                frontrun_in: cold.frontrun_in,
+                window_bps: 500,
+            },
+        )
+        .unwrap();
+        assert_eq!(warm, full);
+
+        // a hint far off the optimum falls back to the full search
+        let far = optimize_v2_sandwich_from(
+            moved_weth,
+            moved_token,
+            victim_in,
+            min_out,
+            ether(1_000),
+            TaxRates::default(),
+            SearchHint {
+                frontrun_in: cold.frontrun_in / 10,
+                window_bps: 100,
+            },
+        )
+        .unwrap();
+        assert_eq!(far, full);
+        assert!(optimize_v2_sandwich_within(
+            moved_weth,
+            moved_token,
+            victim_in,
+            min_out,
+            ether(1_000),
+            TaxRates::default(),
+            SearchHint {
+                frontrun_in: cold.frontrun_in / 10,
+                window_bps: 100,
+            },
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,52 @@
+use ethers_core::types::U256;
+
+const BPS: u64 = 10_000;
+
+/// Transfer fees of a fee-on-transfer token, in bps of the amount moved
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TaxRates {
+    /// Taken from tokens sent out of the pool, i.e. on buys
+    pub buy_bps: u64,
+    /// Taken from tokens sent into the pool, i.e. on sells
+    pub sell_bps: u64,
+}
+
+impl TaxRates {
+    pub fn new(buy_bps: u64, sell_bps: u64) -> Self {
+        Self {
+            buy_bps: buy_bps.min(BPS),
+            sell_bps: sell_bps.min(BPS),
+        }
+    }
+
+    pub fn is_taxed(&self) -> bool {
+        self.buy_bps > 0 || self.sell_bps > 0
+    }
+
+    /// What the buyer receives when the pool sends out `amount`
+    pub fn apply_buy(&self, amount: U256) -> U256 {
+        amount - amount * self.buy_bps / BPS
+    }
+
+    /// What the pool receives when the seller sends in `amount`
+    pub fn apply_sell(&self, amount: U256) -> U256 {
+        amount - amount * self.sell_bps / BPS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tax_rates() {
+        let tax = TaxRates::new(300, 500);
+        assert_eq!(tax.apply_buy(U256::from(10_000)), U256::from(9_700));
+        assert_eq!(tax.apply_sell(U256::from(10_000)), U256::from(9_500));
+        assert!(!TaxRates::default().is_taxed());
+        assert_eq!(
+            TaxRates::new(20_000, 0).apply_buy(U256::from(7)),
+            U256::zero()
+        );
+    }
+}
@@ -0,0 +1,52 @@
+use ethers_core::types::U256;
+
+/// Uniswap V2 `getAmountOut`, 0.3% fee
+pub fn get_amount_out(amount_in: U256, reserve_in: U256, reserve_out: U256) -> U256 {
+    if amount_in.is_zero() || reserve_in.is_zero() || reserve_out.is_zero() {
+        return U256::zero();
+    }
+    let amount_in_with_fee = amount_in * 997;
+    let numerator = amount_in_with_fee * reserve_out;
+    let denominator = reserve_in * 1000 + amount_in_with_fee;
+    numerator / denominator
+}
+
+/// Uniswap V2 `getAmountIn`, 0.3% fee
+///
+/// Returns `None` if `amount_out` can't be taken out of the pool
+pub fn get_amount_in(amount_out: U256, reserve_in: U256, reserve_out: U256) -> Option<U256> {
+    if amount_out.is_zero() || reserve_in.is_zero() || amount_out >= reserve_out {
+        return None;
+    }
+    let numerator = reserve_in * amount_out * 1000;
+    let denominator = (reserve_out - amount_out) * 997;
+    Some(numerator / denominator + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ether(n: u64) -> U256 {
+        U256::exp10(18) * n
+    }
+
+    #[test]
+    fn test_get_amount_out() {
+        // 1 in against 1000 / 1000 reserves
+        let out = get_amount_out(ether(1), ether(1000), ether(1000));
+        assert_eq!(out, U256::from(996006981039903216u64));
+    }
+
+    #[test]
+    fn test_get_amount_in() {
+        let amount_in =
+            get_amount_in(U256::from(996006981039903216u64), ether(1000), ether(1000)).unwrap();
+        assert!(amount_in <= ether(1));
+        assert!(
+            get_amount_out(amount_in, ether(1000), ether(1000))
+                >= U256::from(996006981039903216u64)
+        );
+        assert!(get_amount_in(ether(1000), ether(1000), ether(1000)).is_none());
+    }
+}
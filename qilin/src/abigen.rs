@@ -1,4 +1,3 @@
-use crate::utils::constants::{UNISWAP_V3_FACTORY, UNISWAP_V3_WETH_USDT_LP_0_05};
 use anyhow::Result;
 use dotenv::dotenv;
 use ethers::core::types::Chain;
@@ -7,6 +6,7 @@ use ethers::prelude::Abigen;
 use ethers::prelude::*;
 use ethers::types::H160;
 use log;
+use qilin_cfmms::address_book::AddressBook;
 use std::collections::HashMap;
 use std::env;
 use std::error::Error;
@@ -44,28 +44,19 @@ pub async fn generate_abigen_for_addresses() -> Result<(), Box<dyn Error>> {
     let _etherscan_key = env::var("ETHERSCAN_API_KEY").unwrap();
     let etherscan_client = Client::new(Chain::Mainnet, _etherscan_key).unwrap();
 
+    let book = AddressBook::mainnet();
     let mut address_book = HashMap::new();
 
-    // address_book.insert("DAI", DAI_ADDRESS);
-    // address_book.insert("USDC", USDC_ADDRESS);
-    // address_book.insert("USDT", USDT_ADDRESS);
-    // address_book.insert("WETH", WETH_ADDRESS);
-    // address_book.insert("UNISWAP_V2_ROUTER_1", UNISWAP_V2_ROUTER_1);
-    // address_book.insert("UNISWAP_V2_ROUTER_2", UNISWAP_V2_ROUTER_2);
-    // address_book.insert("UNISWAP_V3_ROUTER_1", UNISWAP_V3_ROUTER_1);
-    // address_book.insert("UNISWAP_V3_ROUTER_2", UNISWAP_V3_ROUTER_2);
-    // address_book.insert("UNISWAP_UNIVERSAL_ROUTER", UNISWAP_UNIVERSAL_ROUTER);
-    // address_book.insert("UNISWAP_V3_WETH_DAI_LP", UNISWAP_V3_WETH_DAI_LP);
-    // address_book.insert("UNISWAP_V3_QUOTER", UNISWAP_V3_QUOTER);
-    // address_book.insert("UNISWAP_V3_QUOTER_V2", UNISWAP_V3_QUOTER_V2);
-    // address_book.insert("UNISWAP_V2_FACTORY", UNISWAP_V2_FACTORY);
-    // address_book.insert("UNISWAP_V3_FACTORY", UNISWAP_V3_FACTORY);
-    address_book.insert("UNISWAP_V3_WETH_USDT_LP_0_05", UNISWAP_V3_WETH_USDT_LP_0_05);
-
-    let mut parsed_addr;
+    // address_book.insert("WETH", book.weth.address);
+    // address_book.insert("UNISWAP_V2_FACTORY", book.factories.uniswap_v2.address);
+    // address_book.insert("UNISWAP_V3_FACTORY", book.factories.uniswap_v3.address);
+    address_book.insert(
+        "UNISWAP_V3_WETH_USDT_LP_0_05",
+        book.pools["uniswap_v3_weth_usdt_lp_0_05"].address,
+    );
+
     for (name, addr) in address_book {
-        parsed_addr = addr.parse::<H160>()?;
-        let _ = generate_abigen(&etherscan_client, name, parsed_addr).await;
+        let _ = generate_abigen(&etherscan_client, name, addr).await;
     }
 
     Ok(())
@@ -77,12 +68,13 @@ pub async fn generate_abigen_for_given_address(_address: Address) -> Result<(),
 
     let mut address_book = HashMap::new();
 
-    address_book.insert("UNISWAP_V3_FACTORY", UNISWAP_V3_FACTORY);
+    address_book.insert(
+        "UNISWAP_V3_FACTORY",
+        AddressBook::mainnet().factories.uniswap_v3.address,
+    );
 
-    let mut parsed_addr;
     for (name, addr) in address_book {
-        parsed_addr = addr.parse::<H160>()?;
-        generate_abigen(&etherscan_client, name, parsed_addr).await?;
+        generate_abigen(&etherscan_client, name, addr).await?;
     }
 
     Ok(())
@@ -0,0 +1,205 @@
+//! Operator commands to a running bot
+//!
+//! `qilin admin enable <STRATEGY>` leaves the command in a file of its own next to the admin
+//! file, see [RunConfig::admin_file](crate::config::RunConfig::admin_file) and [commands_dir],
+//! the running bot picks the commands up within [ADMIN_POLL] and removes their files. Commands
+//! are never rewritten once left, so one written while the bot takes the others isn't lost. This
+//! is how a strategy whose [RiskManager] breaker tripped is re-enabled, the breaker never closes
+//! on its own.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use strategies::risk::RiskManager;
+use tokio_util::sync::CancellationToken;
+
+/// How often the running bot looks for new commands
+pub const ADMIN_POLL: Duration = Duration::from_secs(2);
+
+/// Commands not picked up by the bot yet
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminCommands {
+    /// strategies to re-enable
+    pub enable: Vec<String>,
+}
+
+impl AdminCommands {
+    /// Apply the commands to `risk`
+    pub fn apply(&self, risk: &RiskManager) {
+        for strategy in &self.enable {
+            if risk.is_enabled(strategy) {
+                info!("Strategy {} is already enabled", strategy);
+                continue;
+            }
+            risk.enable_strategy(strategy);
+        }
+    }
+}
+
+/// Directory the commands for the bot watching `path` are left in, one file each
+pub fn commands_dir(path: impl AsRef<Path>) -> PathBuf {
+    path.as_ref().with_extension("d")
+}
+
+/// Ask the bot watching `path` to re-enable `strategy`
+pub fn request_enable(path: impl AsRef<Path>, strategy: &str) -> io::Result<()> {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+    let dir = commands_dir(path);
+    fs::create_dir_all(&dir)?;
+    let commands = AdminCommands {
+        enable: vec![strategy.to_string()],
+    };
+    // named in the order commands are left, unique across the processes leaving them
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let name = format!(
+        "{:024}-{}-{:020}",
+        nanos,
+        std::process::id(),
+        SEQUENCE.fetch_add(1, Ordering::Relaxed)
+    );
+    // the bot never reads a half written command, it only takes `.json` files
+    let tmp = dir.join(format!("{}.tmp", name));
+    fs::write(&tmp, serde_json::to_vec(&commands)?)?;
+    fs::rename(&tmp, dir.join(format!("{}.json", name)))
+}
+
+/// Commands left for the bot watching `path`, their files removed, `None` if there are none
+pub fn take_commands(path: impl AsRef<Path>) -> io::Result<Option<AdminCommands>> {
+    let entries = match fs::read_dir(commands_dir(path)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut files = vec![];
+    for entry in entries {
+        let file = entry?.path();
+        if file.extension().map_or(false, |ext| ext == "json") {
+            files.push(file);
+        }
+    }
+    if files.is_empty() {
+        return Ok(None);
+    }
+    files.sort();
+
+    let mut taken = AdminCommands::default();
+    for file in files {
+        let parsed = serde_json::from_slice::<AdminCommands>(&fs::read(&file)?);
+        // written whole, a command that doesn't parse never will
+        fs::remove_file(&file)?;
+        let commands = match parsed {
+            Ok(commands) => commands,
+            Err(e) => {
+                warn!("Dropping admin command {:?}: {}", file, e);
+                continue;
+            }
+        };
+        for strategy in commands.enable {
+            if !taken.enable.contains(&strategy) {
+                taken.enable.push(strategy);
+            }
+        }
+    }
+    Ok(Some(taken))
+}
+
+/// Apply the commands left at `path` to `risk` every [ADMIN_POLL] until `token` is cancelled
+pub async fn watch(path: impl AsRef<Path>, risk: Arc<RiskManager>, token: CancellationToken) {
+    let path = path.as_ref();
+    let mut interval = tokio::time::interval(ADMIN_POLL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = token.cancelled() => return,
+        }
+        match take_commands(path) {
+            Ok(Some(commands)) => commands.apply(&risk),
+            Ok(None) => {}
+            Err(e) => warn!("Failed to read admin commands from {:?}: {}", path, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Address, U256};
+    use strategies::risk::RiskConfig;
+
+    #[test]
+    fn test_enable_through_admin_file() {
+        let path = std::env::temp_dir().join(format!("qilin-admin-{}.json", std::process::id()));
+        let _ = fs::remove_dir_all(commands_dir(&path));
+        let risk = RiskManager::new(RiskConfig::default());
+        risk.disable_strategy("sandwich", "3 consecutive failed bundles");
+        assert!(take_commands(&path).unwrap().is_none());
+
+        request_enable(&path, "sandwich").unwrap();
+        request_enable(&path, "arb").unwrap();
+        request_enable(&path, "sandwich").unwrap();
+        let commands = take_commands(&path).unwrap().unwrap();
+        assert_eq!(commands.enable, vec!["sandwich", "arb"]);
+        assert!(take_commands(&path).unwrap().is_none());
+
+        commands.apply(&risk);
+        assert!(risk.is_enabled("sandwich"));
+        assert!(risk
+            .check("sandwich", 1, 0, Address::zero(), U256::one())
+            .is_ok());
+
+        let _ = fs::remove_dir_all(commands_dir(&path));
+    }
+
+    #[test]
+    fn test_commands_left_while_taking() {
+        let path =
+            std::env::temp_dir().join(format!("qilin-admin-race-{}.json", std::process::id()));
+        let _ = fs::remove_dir_all(commands_dir(&path));
+        let strategies: Vec<String> = (0..8).map(|i| format!("strategy-{}", i)).collect();
+
+        // operators leave commands while the bot keeps taking them, none goes missing
+        let writers: Vec<_> = strategies
+            .iter()
+            .cloned()
+            .map(|strategy| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..10 {
+                        request_enable(&path, &strategy).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let mut taken = vec![];
+        let mut take = || {
+            if let Some(commands) = take_commands(&path).unwrap() {
+                taken.extend(commands.enable);
+            }
+        };
+        while !writers.iter().all(|writer| writer.is_finished()) {
+            take();
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        take();
+
+        taken.sort();
+        taken.dedup();
+        assert_eq!(taken, strategies);
+        assert!(take_commands(&path).unwrap().is_none());
+
+        let _ = fs::remove_dir_all(commands_dir(&path));
+    }
+}
@@ -0,0 +1,315 @@
+//! The `qilin` command line
+//!
+//! `qilin run [NETWORK_NAME] [--config PATH]` starts the bot, see [RunConfig], running it is also
+//! the default without a subcommand, `qilin admin` sends commands to it, see [crate::admin]. The
+//! other subcommands are offline tools around the same pipeline.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use clap::{arg, value_parser, ArgMatches, Command};
+use collectors::{fixtures, trace_client::detect_trace_client};
+use ethers::{providers::Middleware, types::H256};
+use fork_database::blockchain_db::JsonBlockCacheDB;
+use qilin_cfmms::address_book;
+
+use crate::config::RunConfig;
+use crate::utils::{helpers::connect_from_env, serialization::read_pool_data};
+use crate::{abigen, admin, explain, init, simulate};
+
+pub fn command() -> Command {
+    Command::new("qilin")
+        .version("1.0")
+        .about("Qi(氣) Bot, a general purpose MEV bot")
+        .args_conflicts_with_subcommands(true)
+        .arg(arg!([NETWORK_NAME] "Same as `qilin run NETWORK_NAME`"))
+        .subcommand(
+            Command::new("run")
+                .about("Start the bot")
+                .arg(arg!([NETWORK_NAME] "mainnet or goerli, the config's or mainnet by default"))
+                .arg(
+                    arg!(--config <PATH> "Json config file, see RunConfig")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("simulate-tx")
+                .about("Trace a tx and look for sandwich and arb opportunities in it")
+                .arg(arg!(<HASH> "Transaction hash")),
+        )
+        .subcommand(
+            Command::new("explain-tx")
+                .about("Print a tx's state diff in human readable form")
+                .arg(arg!(<HASH> "Transaction hash")),
+        )
+        .subcommand(
+            Command::new("scan")
+                .about("Look for opportunities in the txs of past blocks")
+                .arg(arg!(--from <BLOCK> "First block").value_parser(value_parser!(u64)))
+                .arg(
+                    arg!(--to <BLOCK> "Last block, the first one by default")
+                        .required(false)
+                        .value_parser(value_parser!(u64)),
+                ),
+        )
+        .subcommand(
+            Command::new("pools")
+                .about("Manage the pool data in qilin/src/assets/")
+                .subcommand_required(true)
+                .subcommand(Command::new("sync").about("Sync all pools from the factories")),
+        )
+        .subcommand(
+            Command::new("cache")
+                .about("Inspect the fork database cache")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("stats")
+                        .about("Print what a cache file holds")
+                        .arg(arg!(<PATH> "Cache file, json or .zst")),
+                ),
+        )
+        .subcommand(
+            Command::new("fixtures")
+                .about("Capture state diff fixtures for the collectors' tests")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("capture")
+                        .about("Trace past blocks into collectors/fixtures/")
+                        .arg(arg!(<BLOCK> ... "Blocks to capture").value_parser(value_parser!(u64)))
+                        .arg(
+                            arg!(--out <DIR> "Output directory")
+                                .required(false)
+                                .value_parser(value_parser!(PathBuf)),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("address-book")
+                .about("Manage the built in address book")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("pin")
+                        .about("Pin the code hashes of the node's chain to its deployed code")
+                        .arg(
+                            arg!(--out <PATH> "Registry to write, the built in one by default")
+                                .required(false)
+                                .value_parser(value_parser!(PathBuf)),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("admin")
+                .about("Send a command to the running bot")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("enable")
+                        .about("Re-enable a strategy whose circuit breaker tripped")
+                        .arg(arg!(<STRATEGY> "Strategy name"))
+                        .arg(
+                            arg!(--config <PATH> "Json config file of the running bot")
+                                .required(false)
+                                .value_parser(value_parser!(PathBuf)),
+                        ),
+                ),
+        )
+        .subcommand(Command::new("abigen").about("Generate bindings for the tracked contracts"))
+}
+
+/// Run the subcommand of `matches`
+pub async fn dispatch(matches: ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("run", args)) => {
+            let mut config = match args.get_one::<PathBuf>("config") {
+                Some(path) => RunConfig::load(path)?,
+                None => RunConfig::default(),
+            };
+            if let Some(network) = args.get_one::<String>("NETWORK_NAME") {
+                config = config.with_network(network);
+            }
+            crate::run(config).await
+        }
+        Some(("simulate-tx", args)) => simulate::simulate_tx(hash(args)?).await,
+        Some(("explain-tx", args)) => explain::explain_tx(hash(args)?).await,
+        Some(("scan", args)) => {
+            let from = *args.get_one::<u64>("from").expect("required");
+            let to = args.get_one::<u64>("to").copied().unwrap_or(from);
+            simulate::scan(from, to).await
+        }
+        Some(("pools", args)) => match args.subcommand() {
+            Some(("sync", _)) => {
+                let provider = connect_from_env().await?;
+                let (pools, _) = init::sync_pools(provider).await?;
+                println!("Synced {} pools", pools.len());
+                Ok(())
+            }
+            _ => unreachable!("subcommand required"),
+        },
+        Some(("cache", args)) => match args.subcommand() {
+            Some(("stats", args)) => {
+                cache_stats(Path::new(args.get_one::<String>("PATH").expect("required")))
+            }
+            _ => unreachable!("subcommand required"),
+        },
+        Some(("fixtures", args)) => match args.subcommand() {
+            Some(("capture", args)) => {
+                let blocks: Vec<u64> = args
+                    .get_many::<u64>("BLOCK")
+                    .expect("required")
+                    .copied()
+                    .collect();
+                let out = args
+                    .get_one::<PathBuf>("out")
+                    .cloned()
+                    .unwrap_or_else(fixtures::fixtures_dir);
+                capture_fixtures(&blocks, &out).await
+            }
+            _ => unreachable!("subcommand required"),
+        },
+        Some(("address-book", args)) => match args.subcommand() {
+            Some(("pin", args)) => {
+                let out = args
+                    .get_one::<PathBuf>("out")
+                    .cloned()
+                    .unwrap_or_else(address_book::builtin_registry_path);
+                pin_address_book(&out).await
+            }
+            _ => unreachable!("subcommand required"),
+        },
+        Some(("admin", args)) => match args.subcommand() {
+            Some(("enable", args)) => {
+                let config = match args.get_one::<PathBuf>("config") {
+                    Some(path) => RunConfig::load(path)?,
+                    None => RunConfig::default(),
+                };
+                let strategy = args.get_one::<String>("STRATEGY").expect("required");
+                let path = config.admin_file();
+                admin::request_enable(&path, strategy)?;
+                println!(
+                    "Asked the bot watching {} to enable {}",
+                    path.display(),
+                    strategy
+                );
+                Ok(())
+            }
+            _ => unreachable!("subcommand required"),
+        },
+        Some(("abigen", _)) => abigen::generate_abigen_for_addresses()
+            .await
+            .map_err(|e| anyhow!("Failed to generate abigen: {}", e)),
+        Some((name, _)) => unreachable!("unknown subcommand {}", name),
+        None => {
+            let mut config = RunConfig::default();
+            if let Some(network) = matches.get_one::<String>("NETWORK_NAME") {
+                config = config.with_network(network);
+            }
+            crate::run(config).await
+        }
+    }
+}
+
+fn hash(args: &ArgMatches) -> Result<H256> {
+    let hash = args.get_one::<String>("HASH").expect("required");
+    hash.parse()
+        .map_err(|_| anyhow!("Invalid transaction hash {}", hash))
+}
+
+async fn capture_fixtures(blocks: &[u64], out: &Path) -> Result<()> {
+    let provider = connect_from_env().await?;
+    let (pools, _) = read_pool_data(provider.clone())
+        .await
+        .map_err(|e| anyhow!("{}, run `qilin pools sync` first", e))?;
+    let tracer = detect_trace_client(provider.clone()).await;
+    fs::create_dir_all(out)?;
+    for &block in blocks {
+        let fixture = fixtures::capture(&*provider, tracer.as_ref(), block, &pools.view())
+            .await
+            .map_err(|e| anyhow!("Failed to capture block {}: {}", block, e))?;
+        let path = out.join(fixture.file_name());
+        fixture.save(&path).map_err(|e| anyhow!("{}", e))?;
+        println!(
+            "{}: {} txs, {} pools",
+            path.display(),
+            fixture.txs.len(),
+            fixture.pools.len()
+        );
+    }
+    Ok(())
+}
+
+async fn pin_address_book(out: &Path) -> Result<()> {
+    let provider = connect_from_env().await?;
+    let chain_id = provider.get_chainid().await?.as_u64();
+    let mut registry = address_book::builtin_registry().clone();
+    let (network, book) = registry
+        .iter_mut()
+        .find(|(_, book)| book.chain_id == chain_id)
+        .ok_or_else(|| anyhow!("No address book for chain {}", chain_id))?;
+    book.pin_code_hashes(&*provider)
+        .await
+        .map_err(|e| anyhow!("{}", e))?;
+    println!("Pinned {} contracts of {}", book.contracts().len(), network);
+    address_book::save_registry(&registry, out).map_err(|e| anyhow!("{}", e))?;
+    println!("{}", out.display());
+    Ok(())
+}
+
+fn cache_stats(path: &Path) -> Result<()> {
+    let cache = JsonBlockCacheDB::load(path).map_err(|e| anyhow!("{}", e))?;
+    let db = cache.db();
+    let slots: usize = db.storage.iter().map(|slots| slots.len()).sum();
+    let meta = cache.meta().read();
+
+    println!("{}", path.display());
+    println!("  size:          {} bytes", fs::metadata(path)?.len());
+    println!("  block:         {}", meta.block_env.number);
+    println!("  accounts:      {}", db.accounts.len());
+    println!("  storage slots: {}", slots);
+    println!("  block hashes:  {}", db.block_hashes.len());
+    println!("  known absent:  {}", db.known_absent.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        command().debug_assert();
+
+        let matches = command().get_matches_from(["qilin", "scan", "--from", "17000000"]);
+        let (name, args) = matches.subcommand().unwrap();
+        assert_eq!(name, "scan");
+        assert_eq!(args.get_one::<u64>("from"), Some(&17_000_000));
+        assert_eq!(args.get_one::<u64>("to"), None);
+
+        let matches = command().get_matches_from(["qilin", "run", "--config", "qilin.json"]);
+        let (name, args) = matches.subcommand().unwrap();
+        assert_eq!(name, "run");
+        assert_eq!(
+            args.get_one::<PathBuf>("config"),
+            Some(&PathBuf::from("qilin.json"))
+        );
+
+        let matches = command().get_matches_from(["qilin", "admin", "enable", "sandwich"]);
+        let (_, args) = matches.subcommand().unwrap();
+        let (name, args) = args.subcommand().unwrap();
+        assert_eq!(name, "enable");
+        assert_eq!(
+            args.get_one::<String>("STRATEGY").map(String::as_str),
+            Some("sandwich")
+        );
+
+        // the bare network name of older invocations still runs the bot
+        let matches = command().get_matches_from(["qilin", "goerli"]);
+        assert!(matches.subcommand().is_none());
+        assert_eq!(
+            matches
+                .get_one::<String>("NETWORK_NAME")
+                .map(String::as_str),
+            Some("goerli")
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! `qilin run --config <PATH>`, what the bot runs with
+//!
+//! A json file, every field is optional and unknown fields are refused so a typo doesn't go
+//! unnoticed. Arguments given on the command line take precedence. The `test` section is only
+//! meant for robustness runs, see [TestConfig].
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use fork_database::cache_flush::CacheFlushConfig;
+#[cfg(feature = "faults")]
+use fork_database::faults::{FaultConfig, FaultInjector};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Where signed bundles and reserved nonces are kept across restarts, unless configured
+pub const DEFAULT_BUNDLE_STORE: &str = "bundles.json";
+/// Where the PnL of landed bundles is kept across restarts, unless configured
+pub const DEFAULT_PNL_LEDGER: &str = "pnl.json";
+/// Where `qilin admin` leaves commands for the running bot, unless configured
+pub const DEFAULT_ADMIN_FILE: &str = "admin.json";
+/// MEV-Share matchmaker bundles go to, unless configured
+pub const DEFAULT_MEV_SHARE_URL: &str = "https://relay.flashbots.net";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Invalid config {0}: {1}")]
+    Json(PathBuf, serde_json::Error),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunConfig {
+    /// mainnet or goerli, mainnet by default
+    pub network: Option<String>,
+    /// bundle store file, `BUNDLE_STORE` or [DEFAULT_BUNDLE_STORE] by default
+    pub bundle_store: Option<PathBuf>,
+    /// PnL ledger file, `PNL_LEDGER` or [DEFAULT_PNL_LEDGER] by default
+    pub pnl_ledger: Option<PathBuf>,
+    /// admin file the commands are left next to, `ADMIN_FILE` or [DEFAULT_ADMIN_FILE] by
+    /// default, see [crate::admin]
+    pub admin_file: Option<PathBuf>,
+    /// http endpoint the fork bundles are validated on fetches from, `HTTP_RPC` by default
+    pub http_rpc: Option<String>,
+    pub fork_cache: ForkCacheConfig,
+    /// send bundles through MEV-Share rather than `eth_sendBundle` if set
+    pub mev_share: Option<MevShareConfig>,
+    pub test: TestConfig,
+}
+
+/// MEV-Share submission, see [MevShareClient](crate::utils::relayer::MevShareClient)
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MevShareConfig {
+    /// matchmaker endpoint, [DEFAULT_MEV_SHARE_URL] by default
+    pub url: String,
+    /// gwei builders charge for the top of the block over its tail
+    pub top_premium_gwei: u64,
+    /// what the matchmaker may share about the bundles, e.g. `logs`
+    pub hints: Vec<String>,
+}
+
+impl Default for MevShareConfig {
+    fn default() -> Self {
+        Self {
+            url: DEFAULT_MEV_SHARE_URL.to_string(),
+            top_premium_gwei: 0,
+            hints: vec![],
+        }
+    }
+}
+
+/// On disk cache of the state the fork fetched, flushed in the background
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForkCacheConfig {
+    /// cache file, `.zst` for a compressed one, nothing is cached if unset
+    pub path: Option<PathBuf>,
+    /// seconds between checks for new entries
+    pub flush_interval: u64,
+    /// see [CacheFlushConfig::min_new_entries]
+    pub min_new_entries: usize,
+}
+
+impl Default for ForkCacheConfig {
+    fn default() -> Self {
+        let flush = CacheFlushConfig::default();
+        Self {
+            path: None,
+            flush_interval: flush.interval.as_secs(),
+            min_new_entries: flush.min_new_entries,
+        }
+    }
+}
+
+impl ForkCacheConfig {
+    pub fn flush_config(&self) -> CacheFlushConfig {
+        CacheFlushConfig {
+            interval: Duration::from_secs(self.flush_interval.max(1)),
+            min_new_entries: self.min_new_entries,
+        }
+    }
+}
+
+/// Settings of robustness runs, empty unless built with the `faults` feature
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TestConfig {
+    /// faults injected into the fork db backend and the relay
+    #[cfg(feature = "faults")]
+    pub faults: FaultConfig,
+}
+
+impl TestConfig {
+    /// Injector of the configured faults, `None` if there are none
+    #[cfg(feature = "faults")]
+    pub fn fault_injector(&self) -> Option<FaultInjector> {
+        self.faults
+            .is_enabled()
+            .then(|| FaultInjector::new(self.faults.clone()))
+    }
+}
+
+impl RunConfig {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.into(), e))?;
+        serde_json::from_str(&json).map_err(|e| ConfigError::Json(path.into(), e))
+    }
+
+    pub fn with_network(mut self, network: impl Into<String>) -> Self {
+        self.network = Some(network.into());
+        self
+    }
+
+    pub fn bundle_store(&self) -> PathBuf {
+        self.bundle_store.clone().unwrap_or_else(|| {
+            std::env::var("BUNDLE_STORE")
+                .unwrap_or_else(|_| DEFAULT_BUNDLE_STORE.to_string())
+                .into()
+        })
+    }
+
+    pub fn pnl_ledger(&self) -> PathBuf {
+        self.pnl_ledger.clone().unwrap_or_else(|| {
+            std::env::var("PNL_LEDGER")
+                .unwrap_or_else(|_| DEFAULT_PNL_LEDGER.to_string())
+                .into()
+        })
+    }
+
+    pub fn admin_file(&self) -> PathBuf {
+        self.admin_file.clone().unwrap_or_else(|| {
+            std::env::var("ADMIN_FILE")
+                .unwrap_or_else(|_| DEFAULT_ADMIN_FILE.to_string())
+                .into()
+        })
+    }
+
+    pub fn http_rpc(&self) -> Option<String> {
+        self.http_rpc
+            .clone()
+            .or_else(|| std::env::var("HTTP_RPC").ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_config() {
+        let config: RunConfig = serde_json::from_str(
+            r#"{"network": "goerli", "bundle_store": "/tmp/bundles.json", "pnl_ledger": "/tmp/pnl.json", "http_rpc": "http://localhost:8545"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.network.as_deref(), Some("goerli"));
+        assert_eq!(config.bundle_store(), PathBuf::from("/tmp/bundles.json"));
+        assert_eq!(config.pnl_ledger(), PathBuf::from("/tmp/pnl.json"));
+        assert_eq!(config.http_rpc().as_deref(), Some("http://localhost:8545"));
+        assert_eq!(
+            config.with_network("mainnet").network.as_deref(),
+            Some("mainnet")
+        );
+
+        assert_eq!(
+            serde_json::from_str::<RunConfig>("{}").unwrap(),
+            RunConfig::default()
+        );
+        assert!(serde_json::from_str::<RunConfig>(r#"{"netwrok": "goerli"}"#).is_err());
+
+        let config: RunConfig = serde_json::from_str(
+            r#"{"fork_cache": {"path": "fork.json.zst", "flush_interval": 5}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.fork_cache.path, Some(PathBuf::from("fork.json.zst")));
+        let flush = config.fork_cache.flush_config();
+        assert_eq!(flush.interval, Duration::from_secs(5));
+        assert_eq!(
+            flush.min_new_entries,
+            CacheFlushConfig::default().min_new_entries
+        );
+
+        let config: RunConfig =
+            serde_json::from_str(r#"{"mev_share": {"top_premium_gwei": 5, "hints": ["logs"]}}"#)
+                .unwrap();
+        let mev_share = config.mev_share.unwrap();
+        assert_eq!(mev_share.url, DEFAULT_MEV_SHARE_URL);
+        assert_eq!(mev_share.top_premium_gwei, 5);
+        assert_eq!(mev_share.hints, vec!["logs".to_string()]);
+    }
+
+    #[cfg(feature = "faults")]
+    #[test]
+    fn test_faults_config() {
+        let config: RunConfig =
+            serde_json::from_str(r#"{"test": {"faults": {"seed": 7, "drop_rate": 0.5}}}"#).unwrap();
+        assert_eq!(config.test.faults.drop_rate, 0.5);
+        assert!(config.test.fault_injector().is_some());
+        assert!(RunConfig::default().test.fault_injector().is_none());
+        assert!(serde_json::from_str::<RunConfig>(r#"{"test": {"fault": {}}}"#).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! Feeding the collectors' events to the supervised strategies
+//!
+//! Every collector runs in its own task, its events are converted into the engine's event type and
+//! broadcast to the strategies, each running under [supervise]. A collector whose stream fails or
+//! ends is restarted after the [RestartPolicy] backoff, the strategies keep their subscription
+//! meanwhile. The actions the strategies emit are broadcast to the receivers of
+//! [Engine::actions].
+
+use std::time::Instant;
+
+use artemis::types::Collector;
+use futures::StreamExt;
+use log::{error, warn};
+use tokio::{sync::broadcast, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+use crate::supervisor::{supervise, RestartPolicy, StrategyFactory};
+
+/// Events and actions buffered for lagging receivers by default
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 512;
+
+pub struct Engine<E, A> {
+    events: broadcast::Sender<E>,
+    actions: broadcast::Sender<A>,
+    policy: RestartPolicy,
+    token: CancellationToken,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl<E, A> Engine<E, A>
+where
+    E: Clone + Send + Sync + 'static,
+    A: Clone + Send + Sync + 'static,
+{
+    /// Engine running until `token` is cancelled
+    pub fn new(token: CancellationToken) -> Self {
+        Self::with_capacity(DEFAULT_CHANNEL_CAPACITY, token)
+    }
+
+    pub fn with_capacity(capacity: usize, token: CancellationToken) -> Self {
+        Self {
+            events: broadcast::channel(capacity).0,
+            actions: broadcast::channel(capacity).0,
+            policy: RestartPolicy::default(),
+            token,
+            tasks: vec![],
+        }
+    }
+
+    /// Backoff of the collectors and strategies added from now on
+    pub fn with_restart_policy(mut self, policy: RestartPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Actions emitted from now on
+    pub fn actions(&self) -> broadcast::Receiver<A> {
+        self.actions.subscribe()
+    }
+
+    pub fn add_collector<T>(&mut self, name: impl Into<String>, collector: Box<dyn Collector<T>>)
+    where
+        T: Send + 'static,
+        E: From<T>,
+    {
+        let (name, events, policy, token) = (
+            name.into(),
+            self.events.clone(),
+            self.policy,
+            self.token.clone(),
+        );
+        self.tasks.push(tokio::spawn(async move {
+            let mut restarts = 0;
+            loop {
+                let started = Instant::now();
+                match collector.get_event_stream().await {
+                    Ok(mut stream) => loop {
+                        let event = tokio::select! {
+                            event = stream.next() => event,
+                            _ = token.cancelled() => return,
+                        };
+                        let Some(event) = event else {
+                            warn!("Collector {} stream ended", name);
+                            break;
+                        };
+                        // no strategy subscribed yet isn't an error
+                        let _ = events.send(E::from(event));
+                    },
+                    Err(e) => error!("Collector {} failed: {:?}", name, e),
+                }
+
+                if started.elapsed() >= policy.reset_after {
+                    restarts = 0;
+                }
+                let backoff = policy.backoff(restarts);
+                restarts = restarts.saturating_add(1);
+                warn!("Restarting collector {} in {:?}", name, backoff);
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = token.cancelled() => return,
+                }
+                metrics::counter!("qilin_collector_restarts", 1, "collector" => name.clone());
+            }
+        }));
+    }
+
+    pub fn add_strategy(&mut self, name: impl Into<String>, factory: StrategyFactory<E, A>) {
+        self.tasks.push(supervise(
+            name,
+            factory,
+            self.events.clone(),
+            self.actions.clone(),
+            self.policy,
+            self.token.clone(),
+        ));
+    }
+
+    /// Wait for the collectors and strategies to stop, once the token is cancelled
+    pub async fn join(self) {
+        for task in self.tasks {
+            if let Err(e) = task.await {
+                error!("Engine task failed: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use artemis::types::{CollectorStream, Strategy};
+    use async_trait::async_trait;
+    use std::{sync::Arc, time::Duration};
+
+    /// Emits 1, 2, 3 then ends
+    struct Counter;
+
+    #[async_trait]
+    impl Collector<u64> for Counter {
+        async fn get_event_stream(&self) -> anyhow::Result<CollectorStream<'_, u64>> {
+            Ok(Box::pin(futures::stream::iter(1..=3)))
+        }
+    }
+
+    /// Doubles every event
+    struct Double;
+
+    #[async_trait]
+    impl Strategy<u64, u64> for Double {
+        async fn sync_state(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn process_event(&mut self, event: u64) -> Vec<u64> {
+            vec![event * 2]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collector_events_reach_strategies() {
+        let token = CancellationToken::new();
+        let mut engine =
+            Engine::<u64, u64>::new(token.clone()).with_restart_policy(RestartPolicy {
+                initial_backoff: Duration::from_millis(10),
+                ..Default::default()
+            });
+        let mut actions = engine.actions();
+        engine.add_strategy("double", Arc::new(|| Box::new(Double)));
+        // the strategy subscribes in its own task
+        while engine.events.receiver_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        engine.add_collector("counter", Box::new(Counter));
+
+        // the ended stream is started again
+        let mut received = vec![];
+        while received.len() < 6 {
+            let action = tokio::time::timeout(Duration::from_secs(5), actions.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            received.push(action);
+        }
+        assert_eq!(received, vec![2, 4, 6, 2, 4, 6]);
+
+        token.cancel();
+        engine.join().await;
+    }
+}
@@ -0,0 +1,82 @@
+//! `qilin explain-tx <hash>`, prints what a transaction did to the chain state
+//!
+//! A mined tx is replayed at its index in its block, on top of the txs ahead of it. A pending tx
+//! is traced on top of the latest block.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use collectors::{
+    diff_explain::{explain, ExplainContext},
+    state_diff::get_from_txs,
+    trace_client::{detect_trace_client, TraceClient},
+};
+use ethers::{
+    prelude::*,
+    providers::{Middleware, Provider, Ws},
+};
+use qilin_cfmms::{pool::Pool, registry::PoolView};
+
+use crate::utils::{helpers::connect_from_env, serialization::read_pool_data};
+
+/// Fetch the tx with `hash` and trace its state diff
+pub async fn trace_tx(
+    provider: &Arc<Provider<Ws>>,
+    tracer: &dyn TraceClient,
+    hash: H256,
+) -> Result<(Transaction, BTreeMap<Address, AccountDiff>)> {
+    let tx = provider
+        .get_transaction(hash)
+        .await?
+        .ok_or_else(|| anyhow!("Transaction {:?} not found", hash))?;
+    let diffs = match tx.block_number {
+        Some(_) => tracer
+            .replayed_state_diff(hash)
+            .await
+            .map_err(|e| anyhow!("Failed to replay {:?}: {}", hash, e))?,
+        None => get_from_txs(tracer, &[tx.clone()], BlockNumber::Latest)
+            .await
+            .ok_or_else(|| anyhow!("Failed to trace {:?}", hash))?,
+    };
+    Ok((tx, diffs))
+}
+
+/// Print the explained state diff of `tx`, labeling the pools found in `pools`
+pub async fn print_explanation(
+    provider: &Arc<Provider<Ws>>,
+    tx: &Transaction,
+    diffs: &BTreeMap<Address, AccountDiff>,
+    pools: &PoolView,
+) {
+    let touched: Vec<Pool> = diffs
+        .keys()
+        .filter_map(|address| pools.get(address))
+        .collect();
+    let mut context = ExplainContext::new()
+        .with_label(tx.from, "sender")
+        .with_pools(touched);
+    context
+        .resolve_tokens(provider.clone(), diffs.keys().copied())
+        .await;
+
+    println!("{:?}", tx.hash);
+    print!("{}", explain(diffs, &context));
+}
+
+/// Trace the tx with `hash` and print its explained state diff
+pub async fn explain_tx(hash: H256) -> Result<()> {
+    let provider = connect_from_env().await?;
+    let tracer = detect_trace_client(provider.clone()).await;
+    let (tx, diffs) = trace_tx(&provider, tracer.as_ref(), hash).await?;
+
+    let pools = match read_pool_data(provider.clone()).await {
+        Ok((registry, _)) => registry.view(),
+        Err(e) => {
+            log::warn!("Pools unavailable, pool addresses won't be labeled: {}", e);
+            Default::default()
+        }
+    };
+    print_explanation(&provider, &tx, &diffs, &pools).await;
+    Ok(())
+}
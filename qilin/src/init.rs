@@ -1,10 +1,8 @@
-use crate::utils::constants::{UNISWAP_V2_FACTORY, UNISWAP_V3_FACTORY};
 use crate::utils::{
-    helpers::{connect_to_network, generate_abigen},
+    helpers::connect_to_network,
     serialization::{read_pool_data, write_pool_data},
 };
 use anyhow::Result;
-use clap::{arg, Command};
 use dashmap::DashMap;
 use dotenv;
 use ethers::{
@@ -15,11 +13,12 @@ use ethers::{
 };
 use ethers_flashbots::FlashbotsMiddleware;
 use log;
-use parking_lot::RwLock;
 use qilin_cfmms::{
+    address_book::{AddressBook, AddressBookError},
     dex,
     dex::PairSyncError,
     pool::{Pool, PoolVariant},
+    registry::PoolRegistry,
 };
 use std::collections::hash_map::DefaultHasher;
 use std::env;
@@ -38,37 +37,32 @@ pub enum SetupError {
     ParsingError(#[from] std::num::ParseIntError),
     #[error("Failed to sync pairs")]
     PairSyncError(#[from] PairSyncError),
+    #[error("Invalid address book: {0}")]
+    AddressBookError(#[from] AddressBookError),
+    #[error("Provider error: {0}")]
+    ProviderError(String),
 }
 
 /// Load the envitonment variables, sync pool states, and initate the backend database
-pub async fn setup() -> Result<
+///
+/// `network` is `mainnet` or `goerli`, mainnet when not given
+pub async fn setup(
+    network: Option<&str>,
+) -> Result<
     (
         SignerMiddleware<FlashbotsMiddleware<Arc<Provider<Ws>>, LocalWallet>, LocalWallet>,
-        Arc<RwLock<DashMap<Address, Pool>>>,
+        Arc<PoolRegistry>,
         Arc<DashMap<H160, Vec<Pool>>>,
     ),
     SetupError,
 > {
     dotenv::dotenv().ok();
 
-    let arg: Vec<String> = env::args().collect();
-
-    generate_abigen(arg).await.unwrap_or_else(|e| {
-        log::error!("Error: {}", e);
-        std::process::exit(1);
-    });
-
-    let matches = Command::new("Qi(氣) Bot")
-        .version("1.0")
-        .about("A general purpose MEV bot")
-        .arg(arg!([NETWORK_NAME]).required(false))
-        .get_matches();
-
     let mut _ws_provider: Option<Arc<Provider<Ws>>> = None;
     let mut _middleware_url: Option<Url> = None;
     let mut _chain_id: Option<i32> = None;
 
-    match matches.get_one::<String>("NETWORK_NAME") {
+    match network {
         Some(network) if network == "mainnet" => {
             let mainnet_url = env::var("WSS_RPC").unwrap_or_else(|e| {
                 log::error!("Error: {}", e);
@@ -165,6 +159,11 @@ pub async fn setup() -> Result<
     let ws_provider = _ws_provider.unwrap();
     let middleware_url = _middleware_url.unwrap();
     let _chain_id = _chain_id.unwrap();
+
+    // a wrong or outdated address would only show up as reverted bundles
+    let address_book = AddressBook::for_chain_id_or_mainnet(_chain_id as u64);
+    address_book.validate(ws_provider.as_ref()).await?;
+
     let mut flashbot_middleware = FlashbotsMiddleware::new(
         ws_provider.clone(),
         middleware_url.clone(),
@@ -184,96 +183,100 @@ pub async fn setup() -> Result<
 
 async fn load_pools(
     provider: Arc<Provider<Ws>>,
-) -> Result<
-    (
-        Arc<RwLock<DashMap<Address, Pool>>>,
-        Arc<DashMap<H160, Vec<Pool>>>,
-    ),
-    SetupError,
-> {
-    // load pool data from json file
-    let all_pools = Arc::new(RwLock::new(DashMap::new()));
-    // same as above but key is hash of token0 and token1 addresses for faster lookup
+) -> Result<(Arc<PoolRegistry>, Arc<DashMap<H160, Vec<Pool>>>), SetupError> {
+    // same as the registry but key is hash of token0 and token1 addresses for faster lookup
     let hash_addr_pools: Arc<DashMap<H160, Vec<Pool>>> = Arc::new(DashMap::new());
 
-    match read_pool_data(provider.clone()).await {
-        Ok((dmap, pdmap)) => {
-            let write_lock = all_pools.write();
-            for item in dmap.iter() {
-                let (key, value) = item.pair();
-                write_lock.insert(*key, *value);
-            }
-
+    // load pool data from json file
+    let registry = match read_pool_data(provider.clone()).await {
+        Ok((registry, pdmap)) => {
             // when read from json, hash_addr_pools' values are never updated
             for item in pdmap.iter() {
                 let (key, value) = item.pair();
                 let pool_vec = value.clone();
                 hash_addr_pools.insert(*key, (*pool_vec).to_vec());
             }
+            registry
         }
         Err(e) => {
             log::info!("Error reading pool data: {}", e);
             log::info!("Pulling pool data......");
 
-            let dexes = vec![
-                // UniswapV2
-                dex::Dex::new(
-                    UNISWAP_V2_FACTORY
-                        .parse::<H160>()
-                        .expect("Failed to parse UNISWAP_V2_FACTORY"),
-                    PoolVariant::UniswapV2,
-                    10000835,
-                ),
-                // UniswapV3
-                dex::Dex::new(
-                    UNISWAP_V3_FACTORY
-                        .parse::<H160>()
-                        .expect("Failed to parse UNISWAP_V3_FACTORY"),
-                    PoolVariant::UniswapV3,
-                    12369621,
-                ),
-            ];
-
-            let current_block = provider
-                .as_ref()
-                .get_block_number()
-                .await
-                .expect("Failed to get block number");
-
-            let synced_pools = dex::sync_dex(
-                dexes.clone(),
-                &Arc::clone(&provider),
-                current_block,
-                None,
-                2, //throttled for 2 secs
-            )
-            .await
-            .expect("Failed to sync dexes");
-
-            let mut hasher = DefaultHasher::new();
-            let mut token0;
-            let mut token1;
-
-            let write_lock = all_pools.write();
-            for pool in synced_pools {
-                write_lock.insert(pool.address, pool);
-
-                token0 = pool.token_0;
-                token1 = pool.token_1;
-                token0.hash(&mut hasher);
-                token1.hash(&mut hasher);
-                let hash = hasher.finish();
-
-                hash_addr_pools
-                    .entry(H160::from_low_u64_be(hash))
-                    .and_modify(|pools| pools.push(pool))
-                    .or_insert_with(|| vec![pool]);
-            }
-            let read_lock = all_pools.read();
-
-            let _ = write_pool_data(&read_lock, false);
-            let _ = write_pool_data(&hash_addr_pools, true);
+            return sync_pools(provider).await;
         }
+    };
+    Ok((Arc::new(registry), hash_addr_pools))
+}
+
+/// Sync every UniswapV2 and V3 pool from the factories and write them to the pool json files
+pub async fn sync_pools(
+    provider: Arc<Provider<Ws>>,
+) -> Result<(Arc<PoolRegistry>, Arc<DashMap<H160, Vec<Pool>>>), SetupError> {
+    let registry = PoolRegistry::new();
+    let hash_addr_pools: Arc<DashMap<H160, Vec<Pool>>> = Arc::new(DashMap::new());
+
+    let chain_id = provider
+        .get_chainid()
+        .await
+        .map_err(|e| SetupError::ProviderError(e.to_string()))?;
+    let factories = AddressBook::for_chain_id_or_mainnet(chain_id.as_u64()).factories;
+    let dexes = vec![
+        // UniswapV2
+        dex::Dex::new(
+            factories.uniswap_v2.address,
+            PoolVariant::UniswapV2,
+            factories.uniswap_v2.deployed_at.unwrap_or_default(),
+        ),
+        // UniswapV3
+        dex::Dex::new(
+            factories.uniswap_v3.address,
+            PoolVariant::UniswapV3,
+            factories.uniswap_v3.deployed_at.unwrap_or_default(),
+        ),
+    ];
+
+    let current_block = provider
+        .as_ref()
+        .get_block_number()
+        .await
+        .expect("Failed to get block number");
+
+    let synced_pools = dex::sync_dex(
+        dexes.clone(),
+        &Arc::clone(&provider),
+        current_block,
+        None,
+        2, //throttled for 2 secs
+    )
+    .await
+    .expect("Failed to sync dexes");
+
+    let mut hasher = DefaultHasher::new();
+    let mut token0;
+    let mut token1;
+
+    for pool in synced_pools {
+        registry.insert(pool);
+
+        token0 = pool.token_0;
+        token1 = pool.token_1;
+        token0.hash(&mut hasher);
+        token1.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        hash_addr_pools
+            .entry(H160::from_low_u64_be(hash))
+            .and_modify(|pools| pools.push(pool))
+            .or_insert_with(|| vec![pool]);
     }
-    Ok((all_pools, hash_addr_pools))
+    registry.commit(Some(current_block.as_u64()));
+
+    let _ = write_pool_data(registry.live_pools(), false);
+    let _ = write_pool_data(
+        hash_addr_pools
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone())),
+        true,
+    );
+    Ok((Arc::new(registry), hash_addr_pools))
 }
@@ -1,18 +1,72 @@
 pub mod abigen;
+pub mod admin;
+pub mod cli;
+pub mod config;
+pub mod engine;
+pub mod explain;
 pub mod init;
+pub mod shutdown;
+pub mod simulate;
+pub mod supervisor;
 pub mod utils;
 
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::Result;
 
 use env_logger::Env;
 use ethers::{core::types::Block, prelude::*, providers::Middleware};
+use futures::StreamExt;
+use parking_lot::{Mutex, RwLock};
 
+use collectors::cow_collector::CowOrderCollector;
 use collectors::mempool_collector::QilinMempoolCollector;
+#[cfg(feature = "faults")]
+use fork_database::faults::{FaultConfig, FaultInjector, FaultyDb};
+#[cfg(feature = "faults")]
+use fork_database::forked_db::ForkedDatabase;
+use fork_database::setup_cached_fork_db;
+use fork_database::stale::{BaseBlock, ForkHead};
+use fork_database::utils::h160_to_b160;
+use strategies::bundle_check::{BundleCheck, BundleValidator};
+use strategies::capital::{CapitalAllocator, CapitalConfig};
+use strategies::cow::{CowMatcher, CowStrategy};
+use strategies::pnl::{PnlLedger, PnlReport};
+use strategies::pricing::{PriceOracle, CHAINLINK_ETH_USD};
+use strategies::risk::RiskManager;
+use strategies::sandwich::state::get_sandwich_contract_address;
+use strategies::sandwich::utils::constants::get_weth_address;
+use strategies::sandwich::utils::contracts::get_erc20_contract;
+use strategies::scorer::OpportunityScorer;
+use strategies::types::{Action, Event};
+
+use config::RunConfig;
+use engine::Engine;
+use shutdown::ShutdownController;
+use utils::serialization::write_pool_data;
+use utils::{
+    bundle_builder::BundleBuilder,
+    bundle_gate::BundleGate,
+    bundle_store::{BundleStore, Treasury},
+    fan_out,
+    relayer::{MevShareClient, MevSharePrivacy},
+};
+
+/// Time in-flight bundle submissions get to finish on shutdown
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+/// CoW orders expiring sooner than this, in seconds, aren't worth settling
+const COW_MIN_VALIDITY: u32 = 60;
 
 pub async fn runner() -> Result<()> {
     env_logger::Builder::from_env(Env::default()).init();
+    cli::dispatch(cli::command().get_matches()).await
+}
 
-    let (flashbot_client, _all_pools, _hash_addr_pools) = init::setup().await?;
+/// Start the bot with `config`, see [init::setup]
+pub async fn run(config: RunConfig) -> Result<()> {
+    let (flashbot_client, all_pools, hash_addr_pools) =
+        init::setup(config.network.as_deref()).await?;
     let ws_provider = flashbot_client.inner().inner().clone();
     let initial_block_num = ws_provider
         .get_block_number()
@@ -24,17 +78,203 @@ pub async fn runner() -> Result<()> {
         .unwrap()
         .unwrap_or(Block::default());
 
-    // let engine = Engine::<Event, Action>::default();
+    // bundles a previous run signed may still land, those whose targets are ahead are sent
+    // again block by block with the ones signed from now on
+    let ledger = Arc::new(Mutex::new(PnlLedger::load(config.pnl_ledger())?));
+    let store = Arc::new(
+        BundleStore::open(config.bundle_store())?
+            .with_ledger(ledger.clone())
+            .with_treasury(Treasury {
+                executor: get_sandwich_contract_address(),
+                weth: get_weth_address(),
+            }),
+    );
+    let oracle = Arc::new(PriceOracle::new(
+        ws_provider.clone(),
+        get_weth_address(),
+        CHAINLINK_ETH_USD.parse()?,
+    ));
+    store.reconcile(ws_provider.as_ref()).await?;
+    // every bundle is validated on the fork, reset to each new block, before it's sent
+    let http_rpc = config
+        .http_rpc()
+        .ok_or_else(|| anyhow::anyhow!("HTTP_RPC not set, bundles can't be validated"))?;
+    let (fork_db, _fork_backend) = setup_cached_fork_db(
+        ws_provider.clone(),
+        http_rpc,
+        config.fork_cache.path.clone(),
+    )
+    .await;
+    let cache_flusher = fork_db.spawn_cache_flusher(config.fork_cache.flush_config());
+    let validator = BundleValidator::new(
+        BundleCheck::new(h160_to_b160(get_weth_address())),
+        h160_to_b160(get_sandwich_contract_address()),
+        h160_to_b160(flashbot_client.signer().address()),
+    )
+    .with_weth(h160_to_b160(get_weth_address()));
+    // robustness runs inject the configured faults into the fork's backend and the relay
+    #[cfg(feature = "faults")]
+    let faults = config.test.fault_injector();
+    #[cfg(feature = "faults")]
+    let fork_db = {
+        let injector = faults
+            .clone()
+            .unwrap_or_else(|| FaultInjector::new(FaultConfig::default()));
+        ForkedDatabase::new(
+            FaultyDb::new(fork_db.backend().clone(), injector),
+            fork_db.inner().clone(),
+        )
+    };
+    let fork_db = Arc::new(RwLock::new(fork_db));
+    let gate = BundleGate::new(fork_db.clone(), validator);
+    #[cfg(feature = "faults")]
+    let gate = match faults {
+        Some(faults) => {
+            log::warn!("Injecting faults {:?}", faults.config());
+            gate.with_faults(faults)
+        }
+        None => gate,
+    };
+    let gate = Arc::new(gate);
+    // strategies share the executor's WETH, every bundle is signed within its strategy's share
+    let capital = Arc::new(CapitalAllocator::new(CapitalConfig::default()));
+    let inventory = get_erc20_contract(&get_weth_address(), &ws_provider)
+        .balance_of(get_sandwich_contract_address())
+        .call()
+        .await?;
+    capital.set_inventory(get_weth_address(), inventory);
+    let risk = Arc::new(RiskManager::default());
+    let builder = Arc::new(BundleBuilder::new(
+        store.clone(),
+        capital,
+        risk.clone(),
+        flashbot_client.signer().address(),
+    ));
+    let flashbot_client = Arc::new(flashbot_client);
+    let shutdown = ShutdownController::new();
+    // written once the in-flight submissions are done, the next run picks them up
+    {
+        let (ledger, path) = (ledger.clone(), config.pnl_ledger());
+        shutdown.on_shutdown("pnl ledger", move || async move {
+            if let Err(e) = ledger.lock().save(&path) {
+                log::warn!("Failed to save the PnL ledger to {:?}: {}", path, e);
+            }
+        });
+        shutdown.on_shutdown("fork cache", move || cache_flusher.shutdown());
+        let (pools, hash_pools) = (all_pools.clone(), hash_addr_pools.clone());
+        shutdown.on_shutdown("pool cache", move || async move {
+            write_pool_data(pools.live_pools(), false);
+            write_pool_data(
+                hash_pools
+                    .iter()
+                    .map(|entry| (*entry.key(), entry.value().clone())),
+                true,
+            );
+        });
+    }
+    // `qilin admin enable` re-enables the strategies whose breaker tripped
+    tokio::spawn(admin::watch(config.admin_file(), risk, shutdown.token()));
+    // advanced on every block, nothing built on an older block is sent
+    let fork_head = ForkHead::default();
+    // bundles go to MEV-Share in their preferred placement if configured, signed with the relay
+    // identity
+    let mev_share = match &config.mev_share {
+        Some(mev_share) => {
+            let identity = std::env::var("FLASHBOTS_IDENTIFIER")?.parse::<LocalWallet>()?;
+            let mut client = MevShareClient::new(mev_share.url.parse::<url::Url>()?, identity)
+                .with_top_premium(U256::from(mev_share.top_premium_gwei) * U256::exp10(9));
+            if !mev_share.hints.is_empty() {
+                client = client.with_privacy(MevSharePrivacy {
+                    hints: mev_share.hints.clone(),
+                    builders: vec![],
+                });
+            }
+            Some(Arc::new(client))
+        }
+        None => None,
+    };
+    {
+        let (flashbots, store, shutdown, ws, fork_head, gate, mev_share, builder) = (
+            flashbot_client.clone(),
+            store.clone(),
+            shutdown.clone(),
+            ws_provider.clone(),
+            fork_head.clone(),
+            gate.clone(),
+            mev_share.clone(),
+            builder.clone(),
+        );
+        tokio::spawn(async move {
+            let blocks = match ws.subscribe_blocks().await {
+                Ok(blocks) => blocks,
+                Err(e) => {
+                    log::error!("Failed to subscribe to blocks for bundle fan-out: {}", e);
+                    return;
+                }
+            };
+            let (advanced, reset) = (fork_head.clone(), gate.clone());
+            let heads = blocks.filter_map(move |block| {
+                let head = block
+                    .number
+                    .zip(block.hash)
+                    .map(|(number, hash)| BaseBlock::new(number.as_u64(), hash));
+                if let Some(head) = head {
+                    advanced.advance(head);
+                    if let Err(e) = reset.on_head(&block) {
+                        log::warn!("Failed to reset the fork to block {}: {}", head.number, e);
+                    }
+                }
+                futures::future::ready(head.map(|_| block))
+            });
+            fan_out::run_fan_outs(
+                flashbots.inner(),
+                &store,
+                heads,
+                &fork_head,
+                Some(gate.as_ref()),
+                mev_share.as_deref(),
+                Some(builder.as_ref()),
+                &shutdown,
+            )
+            .await;
+        });
+    }
 
-    let _mempool_collector = QilinMempoolCollector::new(ws_provider.clone(), initial_block.clone());
-    // let block_collector: Box<dyn Collector<BlockPayload>> = QilinBlockCollector::new(
-    //     ws_provider.clone(),
-    //     all_pools.clone(),
-    //     hash_addr_pools.clone(),
-    // );
+    let mut engine = Engine::<Event, Action>::new(shutdown.token());
+    engine.add_collector(
+        "mempool",
+        Box::new(QilinMempoolCollector::new(
+            ws_provider.clone(),
+            initial_block.clone(),
+        )),
+    );
+    // the CoW order book is only polled on mainnet
+    if config.network.as_deref().map_or(true, |n| n == "mainnet") {
+        engine.add_collector("cow", Box::new(CowOrderCollector::mainnet()));
+        let (ws, registry) = (ws_provider.clone(), all_pools.clone());
+        let scorer = Arc::new(OpportunityScorer::new(oracle.clone()));
+        engine.add_strategy(
+            "cow",
+            Arc::new(move || {
+                let matcher = CowMatcher::new(ws.clone(), COW_MIN_VALIDITY);
+                Box::new(CowStrategy::new(matcher, registry.clone()).with_scorer(scorer.clone()))
+            }),
+        );
+    }
+    // no executor settles the actions yet, they are only logged
+    let mut actions = engine.actions();
+    tokio::spawn(async move {
+        while let Ok(action) = actions.recv().await {
+            log::info!("Strategy action: {:?}", action);
+        }
+    });
 
-    // engine.add_collector(Box::new(mempool_collector));
-    // engine.add_collector();
+    shutdown.run_until_signal(SHUTDOWN_GRACE).await?;
+    engine.join().await;
+    match PnlReport::of(&ledger, None, &oracle).await {
+        Ok(report) => log::info!("PnL on exit: {:?}", report),
+        Err(e) => log::warn!("Failed to value the PnL on exit: {}", e),
+    }
 
     Ok(())
 }
@@ -0,0 +1,214 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use parking_lot::Mutex;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+type ShutdownHook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+#[derive(Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    done: Notify,
+}
+
+impl std::fmt::Debug for InFlight {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InFlight")
+            .field("count", &*self.count.lock())
+            .finish()
+    }
+}
+
+/// Coordinates a clean exit of the bot
+///
+/// Subsystems (engine, mempool collector, relay client, fork backend) hold a clone of
+/// [ShutdownController::token] and stop their loops once it's cancelled. Bundle submissions are
+/// wrapped in [ShutdownController::begin_submission] so shutdown waits for them, and whatever
+/// has to be persisted last (caches, PnL ledger, bundle store) registers a hook with
+/// [ShutdownController::on_shutdown].
+#[derive(Clone)]
+pub struct ShutdownController {
+    token: CancellationToken,
+    in_flight: Arc<InFlight>,
+    hooks: Arc<Mutex<Vec<(String, ShutdownHook)>>>,
+}
+
+impl Default for ShutdownController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for ShutdownController {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShutdownController")
+            .field("cancelled", &self.token.is_cancelled())
+            .field("in_flight", &*self.in_flight.count.lock())
+            .field("hooks", &self.hooks.lock().len())
+            .finish()
+    }
+}
+
+impl ShutdownController {
+    pub fn new() -> Self {
+        Self {
+            token: CancellationToken::new(),
+            in_flight: Arc::new(InFlight::default()),
+            hooks: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Token cancelled when shutdown starts
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Track a bundle submission until the returned guard is dropped, `None` once shutdown has
+    /// started and new opportunities are no longer accepted
+    pub fn begin_submission(&self) -> Option<SubmissionGuard> {
+        let mut count = self.in_flight.count.lock();
+        if self.token.is_cancelled() {
+            return None;
+        }
+        *count += 1;
+        Some(SubmissionGuard {
+            in_flight: self.in_flight.clone(),
+        })
+    }
+
+    /// Run `hook` once in-flight submissions are done, hooks run in registration order
+    pub fn on_shutdown<F, Fut>(&self, name: impl Into<String>, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks
+            .lock()
+            .push((name.into(), Box::new(move || Box::pin(hook()))));
+    }
+
+    /// Cancel the token, wait up to `grace` for in-flight submissions and run the shutdown hooks
+    pub async fn shutdown(&self, grace: Duration) {
+        {
+            // taken so no submission slips in between the check and the cancellation
+            let _count = self.in_flight.count.lock();
+            self.token.cancel();
+        }
+        info!("Shutting down, no new opportunities accepted");
+
+        if tokio::time::timeout(grace, self.wait_in_flight())
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} bundle submissions still in flight after {:?}, shutting down anyway",
+                *self.in_flight.count.lock(),
+                grace
+            );
+        }
+
+        let hooks: Vec<_> = self.hooks.lock().drain(..).collect();
+        for (name, hook) in hooks {
+            info!("Running shutdown hook {}", name);
+            hook().await;
+        }
+        info!("Shutdown complete");
+    }
+
+    /// Wait for SIGINT or SIGTERM, or the token being cancelled elsewhere, then
+    /// [shut down](ShutdownController::shutdown)
+    pub async fn run_until_signal(&self, grace: Duration) -> std::io::Result<()> {
+        tokio::select! {
+            signal = wait_for_signal() => {
+                signal?;
+                info!("Received shutdown signal");
+            }
+            _ = self.token.cancelled() => {}
+        }
+        self.shutdown(grace).await;
+        Ok(())
+    }
+
+    async fn wait_in_flight(&self) {
+        loop {
+            let done = self.in_flight.done.notified();
+            if *self.in_flight.count.lock() == 0 {
+                return;
+            }
+            done.await;
+        }
+    }
+}
+
+/// Held for the duration of a bundle submission, see [ShutdownController::begin_submission]
+#[derive(Debug)]
+pub struct SubmissionGuard {
+    in_flight: Arc<InFlight>,
+}
+
+impl Drop for SubmissionGuard {
+    fn drop(&mut self) {
+        let mut count = self.in_flight.count.lock();
+        *count -= 1;
+        if *count == 0 {
+            self.in_flight.done.notify_waiters();
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_submissions() {
+        let controller = ShutdownController::new();
+        let flushed = Arc::new(AtomicBool::new(false));
+        let hook_flushed = flushed.clone();
+        controller.on_shutdown("cache", move || async move {
+            hook_flushed.store(true, Ordering::SeqCst);
+        });
+
+        let guard = controller.begin_submission().unwrap();
+        let shutdown = {
+            let controller = controller.clone();
+            tokio::spawn(async move { controller.shutdown(Duration::from_secs(10)).await })
+        };
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(controller.is_shutting_down());
+        assert!(controller.begin_submission().is_none());
+        // the hook waits for the submission
+        assert!(!flushed.load(Ordering::SeqCst));
+
+        drop(guard);
+        shutdown.await.unwrap();
+        assert!(flushed.load(Ordering::SeqCst));
+    }
+}
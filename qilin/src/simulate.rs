@@ -0,0 +1,185 @@
+//! `qilin simulate-tx` and `qilin scan`, the sandwich and arb analysis of the bot run offline
+//!
+//! Both trace txs the same way `explain-tx` does, see [explain](crate::explain), and report the
+//! pools a sandwich or an arb could be built on.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use collectors::{
+    opportunity::Opportunity,
+    state_diff::{extract_arb_opportunities, extract_sandwich_opportunities},
+    trace_client::detect_trace_client,
+};
+use dashmap::DashMap;
+use ethers::{
+    prelude::*,
+    providers::{Middleware, Provider, Ws},
+};
+use fork_database::stale::BaseBlock;
+use qilin_cfmms::{pool::Pool, registry::PoolRegistry};
+
+use crate::explain::{print_explanation, trace_tx};
+use crate::utils::{helpers::connect_from_env, serialization::read_pool_data};
+
+/// Opportunities found in the state diff of a tx
+#[derive(Debug, Default)]
+pub struct TxAnalysis {
+    pub opportunities: Vec<Opportunity>,
+}
+
+impl TxAnalysis {
+    pub fn is_empty(&self) -> bool {
+        self.opportunities.is_empty()
+    }
+
+    pub fn sandwiches(&self) -> usize {
+        self.count(|o| matches!(o, Opportunity::Sandwich(_)))
+    }
+
+    /// arb routes through the pools the tx touched
+    pub fn arbs(&self) -> usize {
+        self.count(|o| matches!(o, Opportunity::TwoPoolArb(_) | Opportunity::MultiHopArb(_)))
+    }
+
+    fn count(&self, f: impl Fn(&Opportunity) -> bool) -> usize {
+        self.opportunities.iter().filter(|o| f(o)).count()
+    }
+}
+
+struct Pools {
+    registry: PoolRegistry,
+    hashed: Arc<DashMap<H160, Vec<Pool>>>,
+}
+
+impl Pools {
+    async fn load(provider: &Arc<Provider<Ws>>) -> Result<Self> {
+        let (registry, hashed) = read_pool_data(provider.clone())
+            .await
+            .map_err(|e| anyhow!("{}, run `qilin pools sync` first", e))?;
+        Ok(Self {
+            registry,
+            hashed: Arc::new(hashed),
+        })
+    }
+
+    /// `base` is the block the tx was traced on, the opportunities carry it
+    async fn analyze(
+        &self,
+        provider: &Arc<Provider<Ws>>,
+        tx: &Transaction,
+        diffs: &BTreeMap<Address, AccountDiff>,
+        base: Option<BaseBlock>,
+    ) -> TxAnalysis {
+        let pools = self.registry.view();
+        let mut opportunities: Vec<Opportunity> = extract_sandwich_opportunities(tx, diffs, &pools)
+            .unwrap_or_default()
+            .into_iter()
+            .map(Opportunity::Sandwich)
+            .collect();
+        if let Some(arbs) = extract_arb_opportunities(
+            provider.clone(),
+            diffs,
+            &pools,
+            &self.hashed,
+            None,
+            Some(tx.hash),
+        )
+        .await
+        {
+            opportunities.extend(arbs);
+        }
+        if let Some(base) = base {
+            opportunities = opportunities
+                .into_iter()
+                .map(|opportunity| opportunity.with_base(base))
+                .collect();
+        }
+        TxAnalysis { opportunities }
+    }
+}
+
+/// Block the txs of `block` are traced on
+fn parent_of<T>(block: &Block<T>) -> Option<BaseBlock> {
+    let number = block.number?.as_u64().checked_sub(1)?;
+    Some(BaseBlock::new(number, block.parent_hash))
+}
+
+/// Trace the tx with `hash`, explain it and print the opportunities it opens
+pub async fn simulate_tx(hash: H256) -> Result<()> {
+    let provider = connect_from_env().await?;
+    let pools = Pools::load(&provider).await?;
+    let tracer = detect_trace_client(provider.clone()).await;
+    let (tx, diffs) = trace_tx(&provider, tracer.as_ref(), hash).await?;
+
+    print_explanation(&provider, &tx, &diffs, &pools.registry.view()).await;
+    let base = match tx.block_hash {
+        Some(block_hash) => provider
+            .get_block(block_hash)
+            .await?
+            .and_then(|b| parent_of(&b)),
+        None => None,
+    };
+    let analysis = pools.analyze(&provider, &tx, &diffs, base).await;
+    println!();
+    if analysis.is_empty() {
+        println!("No sandwich or arb opportunity");
+    }
+    for opportunity in &analysis.opportunities {
+        if let Opportunity::Sandwich(sandwich) = opportunity {
+            println!(
+                "Sandwichable on {:?} ({:?}), weth input: {}",
+                sandwich.pool.address, sandwich.pool.pool_variant, sandwich.is_weth_input
+            );
+        }
+    }
+    if analysis.arbs() > 0 {
+        println!("{} arb routes through the touched pools", analysis.arbs());
+    }
+    Ok(())
+}
+
+/// Analyze every tx of the blocks `from..=to` and print the ones opening an opportunity
+pub async fn scan(from: u64, to: u64) -> Result<()> {
+    let provider = connect_from_env().await?;
+    let pools = Pools::load(&provider).await?;
+    let tracer = detect_trace_client(provider.clone()).await;
+
+    let (mut txs, mut sandwichable, mut arbs) = (0, 0, 0);
+    for number in from..=to {
+        let Some(block) = provider.get_block(number).await? else {
+            log::warn!("Block {} not found, stopping the scan", number);
+            break;
+        };
+        let base = parent_of(&block);
+        for hash in block.transactions {
+            txs += 1;
+            let (tx, diffs) = match trace_tx(&provider, tracer.as_ref(), hash).await {
+                Ok(traced) => traced,
+                Err(e) => {
+                    log::debug!("Skipping {:?}: {}", hash, e);
+                    continue;
+                }
+            };
+            let analysis = pools.analyze(&provider, &tx, &diffs, base).await;
+            if analysis.is_empty() {
+                continue;
+            }
+            sandwichable += (analysis.sandwiches() > 0) as usize;
+            arbs += (analysis.arbs() > 0) as usize;
+            println!(
+                "{} {:?}: {} sandwichable pools, {} arb routes",
+                number,
+                hash,
+                analysis.sandwiches(),
+                analysis.arbs()
+            );
+        }
+    }
+    println!(
+        "Scanned {} txs in blocks {}..={}: {} sandwichable, {} arbable",
+        txs, from, to, sandwichable, arbs
+    );
+    Ok(())
+}
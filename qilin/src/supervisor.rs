@@ -0,0 +1,295 @@
+//! Running strategies in isolation
+//!
+//! Each strategy gets its own task, fed from the engine's event channel. When a strategy panics,
+//! e.g. unwrapping a malformed token, only its task dies: the panic is logged and counted in
+//! `qilin_strategy_panics`, and a fresh instance is built from the strategy's factory and synced
+//! again after an exponential backoff. The mempool pipeline and the other strategies keep running.
+//! The strategy's subscription to the events outlives its instances, events sent during the
+//! backoff are handled by the next instance, as long as the channel doesn't lag.
+
+use std::{
+    any::Any,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use artemis::types::Strategy;
+use log::{error, info, warn};
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        Mutex,
+    },
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+/// Builds a fresh instance of a strategy, called again after every crash
+pub type StrategyFactory<E, A> = Arc<dyn Fn() -> Box<dyn Strategy<E, A>> + Send + Sync>;
+
+/// How long to wait before restarting a crashed strategy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// a strategy running this long without crashing starts over from `initial_backoff`
+    pub reset_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            reset_after: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Backoff before the `restart`th restart in a row, starting at 0
+    pub fn backoff(&self, restart: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(restart))
+            .min(self.max_backoff)
+    }
+}
+
+/// Why a strategy's task ended
+enum Exit {
+    /// the event channel closed or shutdown started
+    Done,
+    Failed(anyhow::Error),
+    Panicked(String),
+}
+
+/// Spawn the strategy named `name` in its own supervised task. It receives every event sent on
+/// `events` and its actions are sent on `actions`, until `token` is cancelled or `events` closes.
+pub fn supervise<E, A>(
+    name: impl Into<String>,
+    factory: StrategyFactory<E, A>,
+    events: broadcast::Sender<E>,
+    actions: broadcast::Sender<A>,
+    policy: RestartPolicy,
+    token: CancellationToken,
+) -> JoinHandle<()>
+where
+    E: Clone + Send + Sync + 'static,
+    A: Clone + Send + Sync + 'static,
+{
+    let name = name.into();
+    tokio::spawn(async move {
+        // shared with every instance, a panicking one doesn't take the subscription down with it
+        let events = Arc::new(Mutex::new(events.subscribe()));
+        let mut restarts = 0;
+        loop {
+            let started = Instant::now();
+            let task = tokio::spawn(run_strategy(
+                factory(),
+                events.clone(),
+                actions.clone(),
+                token.clone(),
+            ));
+            let exit = match task.await {
+                Ok(Ok(())) => Exit::Done,
+                Ok(Err(e)) => Exit::Failed(e),
+                Err(e) if e.is_panic() => Exit::Panicked(panic_message(e.into_panic())),
+                Err(_) => Exit::Done,
+            };
+            match exit {
+                Exit::Done => {
+                    info!("Strategy {} stopped", name);
+                    return;
+                }
+                Exit::Failed(e) => {
+                    error!("Strategy {} failed: {:?}", name, e);
+                    metrics::counter!("qilin_strategy_failures", 1, "strategy" => name.clone());
+                }
+                Exit::Panicked(message) => {
+                    error!("Strategy {} panicked: {}", name, message);
+                    metrics::counter!("qilin_strategy_panics", 1, "strategy" => name.clone());
+                }
+            }
+            if token.is_cancelled() {
+                return;
+            }
+
+            if started.elapsed() >= policy.reset_after {
+                restarts = 0;
+            }
+            let backoff = policy.backoff(restarts);
+            restarts = restarts.saturating_add(1);
+            warn!("Restarting strategy {} in {:?}", name, backoff);
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = token.cancelled() => return,
+            }
+            metrics::counter!("qilin_strategy_restarts", 1, "strategy" => name.clone());
+        }
+    })
+}
+
+async fn run_strategy<E, A>(
+    mut strategy: Box<dyn Strategy<E, A>>,
+    events: Arc<Mutex<broadcast::Receiver<E>>>,
+    actions: broadcast::Sender<A>,
+    token: CancellationToken,
+) -> anyhow::Result<()>
+where
+    E: Clone + Send + 'static,
+    A: Send + 'static,
+{
+    strategy.sync_state().await?;
+    loop {
+        let event = tokio::select! {
+            event = async { events.lock().await.recv().await } => event,
+            _ = token.cancelled() => return Ok(()),
+        };
+        match event {
+            Ok(event) => {
+                for action in strategy.process_event(event).await {
+                    // no executor listening is not the strategy's problem
+                    let _ = actions.send(action);
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Strategy lagging behind, skipped {} events", skipped);
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic payload".into(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Echoes events, panics on "boom"
+    struct Echo;
+
+    #[async_trait]
+    impl Strategy<String, String> for Echo {
+        async fn sync_state(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn process_event(&mut self, event: String) -> Vec<String> {
+            if event == "boom" {
+                panic!("malformed token");
+            }
+            vec![event]
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RestartPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            reset_after: Duration::from_secs(10),
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(400));
+        assert_eq!(policy.backoff(40), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_panicking_strategy_is_restarted() {
+        let (events, _) = broadcast::channel(16);
+        let (actions, mut received) = broadcast::channel(16);
+        let built = Arc::new(AtomicUsize::new(0));
+        let factory: StrategyFactory<String, String> = {
+            let built = built.clone();
+            Arc::new(move || {
+                built.fetch_add(1, Ordering::SeqCst);
+                Box::new(Echo)
+            })
+        };
+        let policy = RestartPolicy {
+            initial_backoff: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let token = CancellationToken::new();
+        let handle = supervise(
+            "echo",
+            factory,
+            events.clone(),
+            actions,
+            policy,
+            token.clone(),
+        );
+
+        // wait for the first instance to subscribe
+        while events.receiver_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        events.send("boom".to_string()).unwrap();
+
+        // the restarted instance handles events again
+        let action = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if built.load(Ordering::SeqCst) == 2 && events.receiver_count() > 0 {
+                    events.send("ok".to_string()).unwrap();
+                }
+                match tokio::time::timeout(Duration::from_millis(20), received.recv()).await {
+                    Ok(action) => return action.unwrap(),
+                    Err(_) => continue,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(action, "ok");
+        assert_eq!(built.load(Ordering::SeqCst), 2);
+
+        token.cancel();
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_events_during_backoff_are_kept() {
+        let (events, _) = broadcast::channel(16);
+        let (actions, mut received) = broadcast::channel(16);
+        let factory: StrategyFactory<String, String> = Arc::new(|| Box::new(Echo));
+        let policy = RestartPolicy {
+            initial_backoff: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let token = CancellationToken::new();
+        let handle = supervise(
+            "echo",
+            factory,
+            events.clone(),
+            actions,
+            policy,
+            token.clone(),
+        );
+        while events.receiver_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // sent right after the crash, before the restart
+        events.send("boom".to_string()).unwrap();
+        events.send("late".to_string()).unwrap();
+        let action = tokio::time::timeout(Duration::from_secs(5), received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(action, "late");
+
+        token.cancel();
+        handle.await.unwrap();
+    }
+}
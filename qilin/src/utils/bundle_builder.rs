@@ -0,0 +1,289 @@
+//! Signing bundles within the capital each strategy is allotted and the bot's risk limits
+//!
+//! A strategy asks for the amount it would like to trade, the [BundleBuilder] reserves it with the
+//! [CapitalAllocator] before anything is signed and hands the strategy what was granted, which may
+//! be less. The granted amount is then reserved as exposure with the [RiskManager], a strategy
+//! whose breaker tripped or a bot past its loss limits signs nothing. The txs are signed for that
+//! amount and the bundle is recorded in the [BundleStore] for the fan-out to send. Capital and
+//! exposure stay reserved until the fan-out knows what became of the bundle, see
+//! [BundleBuilder::settle], which also feeds the outcome to both, a landed bundle with the profit
+//! it actually made rather than the one it was simulated for.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use ethers::types::{Address, Bytes, H256, I256, U256, U64};
+use log::debug;
+use parking_lot::Mutex;
+use strategies::capital::{CapitalAllocator, CapitalError};
+use strategies::risk::{BundleOutcome, RiskError, RiskManager};
+use thiserror::Error;
+
+use super::bundle_store::{BundleStore, BundleStoreError, PendingBundle};
+
+#[derive(Error, Debug)]
+pub enum BuildError {
+    #[error(transparent)]
+    Capital(#[from] CapitalError),
+    #[error(transparent)]
+    Risk(#[from] RiskError),
+    #[error(transparent)]
+    Store(#[from] BundleStoreError),
+    #[error("Failed to sign the bundle: {0}")]
+    Signing(String),
+}
+
+/// A bundle a strategy wants to build
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleIntent {
+    pub strategy: String,
+    /// token the bundle trades out of the inventory
+    pub token: Address,
+    /// amount of `token` the strategy would like to trade
+    pub wanted: U256,
+    pub target_block: U64,
+    /// timestamp of the block the bundle is built on, for the daily loss limit
+    pub timestamp: u64,
+    /// last block targeted, see [PendingBundle::with_max_block]
+    pub max_block: Option<U64>,
+    /// pools the bundle trades, see [PendingBundle::with_pools]
+    pub pools: Vec<Address>,
+    /// txs expected to land before the bundle at the tail of the block, see
+    /// [PendingBundle::with_ahead]
+    pub ahead: Vec<Bytes>,
+}
+
+/// Txs of a bundle, signed for the amount the [BundleBuilder] granted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedBundle {
+    pub txs: Vec<Bytes>,
+    /// nonces of the searcher's txs, reserved with [BundleStore::reserve_nonce]
+    pub nonces: Vec<U256>,
+    pub expected_profit: I256,
+}
+
+#[derive(Debug, Clone)]
+struct Reservation {
+    strategy: String,
+    token: Address,
+    amount: U256,
+}
+
+/// Builds the bundles of every strategy, see the [module docs](self)
+#[derive(Debug)]
+pub struct BundleBuilder {
+    store: Arc<BundleStore>,
+    capital: Arc<CapitalAllocator>,
+    risk: Arc<RiskManager>,
+    searcher: Address,
+    /// capital and exposure held by the bundles not settled yet
+    reservations: Mutex<HashMap<H256, Reservation>>,
+}
+
+impl BundleBuilder {
+    pub fn new(
+        store: Arc<BundleStore>,
+        capital: Arc<CapitalAllocator>,
+        risk: Arc<RiskManager>,
+        searcher: Address,
+    ) -> Self {
+        Self {
+            store,
+            capital,
+            risk,
+            searcher,
+            reservations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn store(&self) -> &Arc<BundleStore> {
+        &self.store
+    }
+
+    pub fn capital(&self) -> &Arc<CapitalAllocator> {
+        &self.capital
+    }
+
+    pub fn risk(&self) -> &Arc<RiskManager> {
+        &self.risk
+    }
+
+    /// Reserve the capital `intent` asks for and `sign` the bundle's txs for the amount granted.
+    /// Nothing is signed once the strategy's allocation is used up or the [RiskManager] refuses
+    /// the exposure, and the reservations are released if signing or recording the bundle fails.
+    pub async fn build<F, Fut>(
+        &self,
+        intent: &BundleIntent,
+        sign: F,
+    ) -> Result<PendingBundle, BuildError>
+    where
+        F: FnOnce(U256) -> Fut,
+        Fut: Future<Output = eyre::Result<SignedBundle>>,
+    {
+        let granted = self
+            .capital
+            .reserve(&intent.strategy, intent.token, intent.wanted)?;
+        let block = intent.target_block.as_u64().saturating_sub(1);
+        if let Err(e) = self.risk.reserve(
+            &intent.strategy,
+            block,
+            intent.timestamp,
+            intent.token,
+            granted,
+        ) {
+            self.capital
+                .release(&intent.strategy, intent.token, granted);
+            return Err(e.into());
+        }
+        let release = || {
+            self.capital
+                .release(&intent.strategy, intent.token, granted);
+            self.risk.release(intent.token, granted);
+        };
+        let signed = match sign(granted).await {
+            Ok(signed) => signed,
+            Err(e) => {
+                release();
+                return Err(BuildError::Signing(e.to_string()));
+            }
+        };
+        let mut bundle = PendingBundle::new(
+            signed.txs,
+            intent.target_block,
+            self.searcher,
+            signed.nonces,
+        )
+        .with_profit(&intent.strategy, signed.expected_profit)
+        .with_pools(intent.pools.iter().copied())
+        .with_ahead(intent.ahead.iter().cloned());
+        if let Some(max_block) = intent.max_block {
+            bundle = bundle.with_max_block(max_block);
+        }
+        if let Err(e) = self.store.record(bundle.clone()) {
+            release();
+            return Err(e.into());
+        }
+        debug!(
+            "Bundle {:?} of {} signed for {} of {} token {:?}",
+            bundle.id, intent.strategy, granted, intent.wanted, intent.token
+        );
+        self.reservations.lock().insert(
+            bundle.id,
+            Reservation {
+                strategy: intent.strategy.clone(),
+                token: intent.token,
+                amount: granted,
+            },
+        );
+        Ok(bundle)
+    }
+
+    /// Release the capital and exposure of `bundle` once it landed or was dropped, as of `block`
+    /// at `timestamp`, and feed its `outcome` to the allocator's hit rates and the risk limits.
+    /// A landed outcome carries the realized profit, see [FanOutStep](super::fan_out::FanOutStep).
+    /// `false` if the bundle wasn't built here or was already settled.
+    pub fn settle(&self, bundle: H256, outcome: BundleOutcome, block: u64, timestamp: u64) -> bool {
+        let Some(reservation) = self.reservations.lock().remove(&bundle) else {
+            return false;
+        };
+        self.capital
+            .release(&reservation.strategy, reservation.token, reservation.amount);
+        self.capital.record_outcome(&reservation.strategy, outcome);
+        self.risk.release(reservation.token, reservation.amount);
+        self.risk
+            .record_outcome(&reservation.strategy, block, timestamp, outcome);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strategies::capital::CapitalConfig;
+    use strategies::risk::RiskConfig;
+
+    #[tokio::test]
+    async fn test_build_within_allocation() {
+        let path = std::env::temp_dir().join(format!("qilin-builder-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = Arc::new(BundleStore::open(&path).unwrap());
+        let capital = Arc::new(CapitalAllocator::new(CapitalConfig::default()));
+        let risk = Arc::new(RiskManager::new(RiskConfig {
+            max_consecutive_failures: 1,
+            ..Default::default()
+        }));
+        let builder = BundleBuilder::new(
+            store.clone(),
+            capital.clone(),
+            risk.clone(),
+            Address::zero(),
+        );
+        let weth = Address::from_low_u64_be(1);
+        let intent = BundleIntent {
+            strategy: "sandwich".to_string(),
+            token: weth,
+            wanted: U256::exp10(18),
+            target_block: U64::from(100),
+            timestamp: 1_700_000_000,
+            max_block: Some(U64::from(102)),
+            pools: vec![Address::from_low_u64_be(2)],
+            ahead: vec![],
+        };
+        let signed = |amount: U256| async move {
+            assert!(!amount.is_zero());
+            Ok::<_, eyre::Report>(SignedBundle {
+                txs: vec![Bytes::from(vec![0x02, 0x01])],
+                nonces: vec![],
+                expected_profit: I256::from(10),
+            })
+        };
+
+        // no inventory, nothing is signed
+        assert!(matches!(
+            builder.build(&intent, signed).await,
+            Err(BuildError::Capital(CapitalError::NoInventory(_)))
+        ));
+
+        // 25% of the inventory, a fifth of it until the strategy has a track record
+        capital.set_inventory(weth, U256::exp10(19));
+        let allocation = U256::exp10(17) * 5;
+        let bundle = builder.build(&intent, signed).await.unwrap();
+        assert_eq!(store.pending(), vec![bundle.clone()]);
+        assert_eq!(bundle.last_target(), U64::from(102));
+        assert_eq!(bundle.expected_profit, Some(I256::from(10)));
+        assert!(capital.available("sandwich", weth).unwrap().is_zero());
+        assert!(matches!(
+            builder.build(&intent, signed).await,
+            Err(BuildError::Capital(CapitalError::Exhausted(..)))
+        ));
+
+        // settling gives the capital back, and so does a failed signature
+        assert!(builder.settle(bundle.id, BundleOutcome::NotIncluded, 100, 1_700_000_012));
+        assert!(!builder.settle(bundle.id, BundleOutcome::NotIncluded, 100, 1_700_000_012));
+        let failing = |_: U256| async { Err::<SignedBundle, _>(eyre::eyre!("no nonce")) };
+        assert!(matches!(
+            builder.build(&intent, failing).await,
+            Err(BuildError::Signing(_))
+        ));
+        assert_eq!(capital.available("sandwich", weth).unwrap(), allocation);
+
+        // expected to make 10, the bundle lost 1: the loss counts against the hit rate and trips
+        // the breaker, nothing is signed until the strategy is re-enabled
+        let bundle = builder.build(&intent, signed).await.unwrap();
+        let loss = BundleOutcome::Landed(I256::from(-1));
+        assert!(builder.settle(bundle.id, loss, 100, 1_700_000_012));
+        let record = capital.record("sandwich");
+        assert_eq!((record.wins, record.losses), (0, 1));
+        assert_eq!(record.total_lost, U256::one());
+        assert!(matches!(
+            builder.build(&intent, signed).await,
+            Err(BuildError::Risk(RiskError::StrategyDisabled(..)))
+        ));
+        assert_eq!(capital.available("sandwich", weth).unwrap(), allocation);
+        risk.enable_strategy("sandwich");
+        assert!(builder.build(&intent, signed).await.is_ok());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,349 @@
+//! Last check of a bundle before it goes to the relays
+//!
+//! The [BundleGate] runs the [BundleValidator] on the fork, pinned at the block the bundle was
+//! built on, right before [send_bundle](super::relayer::send_bundle). A bundle it rejects would
+//! fail at the builder too, it isn't sent and [advance_fan_out](super::fan_out::advance_fan_out)
+//! drops it from the store. Set-code txs are decoded with their authorization lists, which the
+//! validator applies right before them, see [decode_bundle].
+//! Validations are cached per head in a [ValidationCache], a bundle re-checked on the same head,
+//! e.g. when a relay turned it away or it was considered for a merge first, isn't run again.
+//! Bundles sent through MEV-Share are also simulated at the top and the tail of the block, see
+//! [BundleGate::placement].
+//!
+//! Built with the `faults` feature, the fork's backend is a [FaultyDb] and the gate holds the
+//! run's [FaultInjector], which [send_bundle](super::relayer::send_bundle) asks before every
+//! submission, see [RunConfig::test](crate::config::RunConfig::test).
+
+use ethers::types::{Block, Bytes, Transaction, H256, I256, U256};
+use ethers::utils::rlp;
+use ethers_flashbots::{BundleRequest, BundleTransaction};
+#[cfg(feature = "faults")]
+use fork_database::faults::{FaultInjector, FaultyDb};
+use fork_database::forked_db::ForkedDatabase;
+use fork_database::shared_backend::SharedBackend;
+use fork_database::sim_cache::StateId;
+use fork_database::sim_env::SimEnv;
+use fork_database::stale::BaseBlock;
+use fork_database::utils::{h256_to_b256, tx_to_tx_env};
+use log::debug;
+use parking_lot::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use strategies::bundle_check::{
+    Authorizations, BundleValidation, BundleValidationError, BundleValidator, ValidationCache,
+};
+use strategies::placement::{simulate_executor_placements, Placement};
+use thiserror::Error;
+
+use super::eip7702::{SignedEip7702Transaction, EIP7702_TX_TYPE};
+
+/// Backend of the fork the gate validates on
+#[cfg(feature = "faults")]
+pub type ForkBackend = FaultyDb<SharedBackend>;
+#[cfg(not(feature = "faults"))]
+pub type ForkBackend = SharedBackend;
+
+#[derive(Error, Debug)]
+pub enum GateError {
+    #[error("Fork is at {head:?}, not at the bundle's base {base:?}")]
+    NotAtBase {
+        base: BaseBlock,
+        head: Option<BaseBlock>,
+    },
+    #[error("Fork error: {0}")]
+    Fork(String),
+    #[error("Tx {0} can't be decoded: {1}")]
+    Undecodable(usize, String),
+    #[error("Bundle fails or loses at both the top and the tail of the block")]
+    NoPlacement,
+    #[error(transparent)]
+    Invalid(#[from] BundleValidationError),
+}
+
+impl GateError {
+    /// Whether the bundle itself is at fault and won't pass on any fork, rather than the fork
+    /// not being where the bundle was built
+    pub fn is_rejection(&self) -> bool {
+        matches!(self, Self::Undecodable(..) | Self::Invalid(_))
+    }
+}
+
+pub struct BundleGate {
+    fork_db: Arc<RwLock<ForkedDatabase<ForkBackend>>>,
+    validator: BundleValidator,
+    /// block the fork was last reset to
+    head: RwLock<Option<BaseBlock>>,
+    /// bumped on every reset, the cached validations of an earlier one don't apply
+    resets: AtomicU64,
+    cache: ValidationCache,
+    /// faults injected into the relay submissions of the bundles that pass
+    #[cfg(feature = "faults")]
+    faults: Option<FaultInjector>,
+}
+
+impl BundleGate {
+    pub fn new(
+        fork_db: Arc<RwLock<ForkedDatabase<ForkBackend>>>,
+        validator: BundleValidator,
+    ) -> Self {
+        Self {
+            fork_db,
+            validator,
+            head: RwLock::new(None),
+            resets: AtomicU64::new(0),
+            cache: ValidationCache::default(),
+            #[cfg(feature = "faults")]
+            faults: None,
+        }
+    }
+
+    #[cfg(feature = "faults")]
+    pub fn with_faults(mut self, faults: FaultInjector) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    #[cfg(feature = "faults")]
+    pub fn faults(&self) -> Option<&FaultInjector> {
+        self.faults.as_ref()
+    }
+
+    pub fn validator(&self) -> &BundleValidator {
+        &self.validator
+    }
+
+    pub fn cache(&self) -> &ValidationCache {
+        &self.cache
+    }
+
+    /// Reset the fork to `block`, the bundles built on it are checked against its state
+    pub fn on_head(&self, block: &Block<H256>) -> Result<(), GateError> {
+        let (Some(number), Some(hash)) = (block.number, block.hash) else {
+            return Err(GateError::Fork("block is pending".to_string()));
+        };
+        let mut head = self.head.write();
+        *head = None;
+        self.resets.fetch_add(1, Ordering::Relaxed);
+        self.fork_db
+            .write()
+            .reset_to(block)
+            .map_err(GateError::Fork)?;
+        *head = Some(BaseBlock::new(number.as_u64(), hash));
+        Ok(())
+    }
+
+    /// Validate `bundle`, built on `base`, on the fork in the block after `base`. Without an
+    /// `expected_profit` the bundle only has to pass the validator's
+    /// [BundleCheck](strategies::bundle_check::BundleCheck).
+    pub fn check(
+        &self,
+        bundle: &BundleRequest,
+        base: BaseBlock,
+        expected_profit: Option<I256>,
+    ) -> Result<(), GateError> {
+        match self.validate(bundle, base, expected_profit.unwrap_or_default()) {
+            Ok(validation) => {
+                debug!(
+                    "Bundle for block {:?} validated, profit {} for {} gas",
+                    bundle.block(),
+                    validation.profit,
+                    validation.gas_used
+                );
+                Ok(())
+            }
+            Err(GateError::Invalid(BundleValidationError::ProfitMismatch { .. }))
+                if expected_profit.is_none() =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Profit `bundle`, built on `base`, makes on the fork in the block after `base`, if it
+    /// passes the validator's [BundleCheck](strategies::bundle_check::BundleCheck)
+    pub fn profit(&self, bundle: &BundleRequest, base: BaseBlock) -> Result<I256, GateError> {
+        match self.validate(bundle, base, I256::zero()) {
+            Ok(validation) => Ok(validation.profit),
+            Err(GateError::Invalid(BundleValidationError::ProfitMismatch { local, .. })) => {
+                Ok(local)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Where in the block after `base` to request `bundle`, built on `base`, and the profit it
+    /// makes there once `top_premium` is paid for the top: at the top, or at the tail behind
+    /// `ahead`, the txs expected to land first, see [PlacementReport::preferred]. Profit is what
+    /// the validator's executor gains in WETH and ETH. Txs of `ahead` that can't be decoded are
+    /// left out.
+    ///
+    /// [PlacementReport::preferred]: strategies::placement::PlacementReport::preferred
+    pub fn placement(
+        &self,
+        bundle: &BundleRequest,
+        base: BaseBlock,
+        ahead: &[Bytes],
+        top_premium: U256,
+    ) -> Result<(Placement, I256), GateError> {
+        let head = self.head.read();
+        if *head != Some(base) {
+            return Err(GateError::NotAtBase { base, head: *head });
+        }
+        let (txs, _) = decode_bundle(bundle)?;
+        let txs: Vec<_> = txs.iter().map(tx_to_tx_env).collect();
+        let ahead: Vec<_> = ahead
+            .iter()
+            .enumerate()
+            .filter_map(|(index, raw)| {
+                let tx = BundleTransaction::Raw(raw.clone());
+                match decode_tx(index, &tx, &mut Authorizations::new()) {
+                    Ok(tx) => Some(tx_to_tx_env(&tx)),
+                    Err(e) => {
+                        debug!("Leaving out tx ahead of the bundle: {}", e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        let fork_db = self.fork_db.read();
+        let env = SimEnv::next_block(&fork_db).map_err(|e| GateError::Fork(e.to_string()))?;
+        let report = simulate_executor_placements(
+            &*fork_db,
+            &env,
+            &txs,
+            &ahead,
+            self.validator.executor,
+            self.validator.weth,
+        )
+        .map_err(|e| GateError::Fork(e.to_string()))?;
+        report.preferred(top_premium).ok_or(GateError::NoPlacement)
+    }
+
+    fn validate(
+        &self,
+        bundle: &BundleRequest,
+        base: BaseBlock,
+        expected_profit: I256,
+    ) -> Result<BundleValidation, GateError> {
+        let head = self.head.read();
+        if *head != Some(base) {
+            return Err(GateError::NotAtBase { base, head: *head });
+        }
+        let (txs, authorizations) = decode_bundle(bundle)?;
+        let fork_db = self.fork_db.read();
+        let env = SimEnv::next_block(&fork_db).map_err(|e| GateError::Fork(e.to_string()))?;
+        let state = StateId::Fork {
+            fork: self.resets.load(Ordering::Relaxed),
+            block_hash: h256_to_b256(base.hash),
+        };
+        Ok(self.validator.validate_cached(
+            &self.cache,
+            state,
+            &*fork_db,
+            &env,
+            &txs,
+            &authorizations,
+            &[],
+            expected_profit,
+        )?)
+    }
+}
+
+/// The txs of `bundle`, in order, with their senders recovered, and the authorization lists of
+/// its set-code txs
+pub fn decode_bundle(
+    bundle: &BundleRequest,
+) -> Result<(Vec<Transaction>, Authorizations), GateError> {
+    let mut authorizations = Authorizations::new();
+    let txs = bundle
+        .transactions()
+        .iter()
+        .enumerate()
+        .map(|(index, tx)| decode_tx(index, tx, &mut authorizations))
+        .collect::<Result<_, _>>()?;
+    Ok((txs, authorizations))
+}
+
+/// Tx `index` of a bundle, the authorization list of a set-code tx goes to `authorizations`
+fn decode_tx(
+    index: usize,
+    tx: &BundleTransaction,
+    authorizations: &mut Authorizations,
+) -> Result<Transaction, GateError> {
+    match tx {
+        BundleTransaction::Signed(tx) => Ok(*tx.clone()),
+        // ethers doesn't know type-4 envelopes
+        BundleTransaction::Raw(raw) if raw.first() == Some(&EIP7702_TX_TYPE) => {
+            let signed = SignedEip7702Transaction::decode(raw.clone())
+                .map_err(|e| GateError::Undecodable(index, e.to_string()))?;
+            let tx = signed
+                .to_transaction()
+                .map_err(|e| GateError::Undecodable(index, e.to_string()))?;
+            authorizations.insert(tx.hash, signed.tx.authorization_list);
+            Ok(tx)
+        }
+        BundleTransaction::Raw(raw) => {
+            rlp::decode(raw).map_err(|e| GateError::Undecodable(index, e.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Bytes, U64};
+
+    #[test]
+    fn test_decode_bundle() {
+        let bundle = BundleRequest::new()
+            .push_transaction(Bytes::from(vec![0x02, 0x01]))
+            .set_block(U64::from(101));
+        let err = decode_bundle(&bundle).unwrap_err();
+        assert!(matches!(err, GateError::Undecodable(0, _)));
+        assert!(err.is_rejection());
+
+        let base = BaseBlock::new(100, H256::from_low_u64_be(100));
+        assert!(!GateError::NotAtBase { base, head: None }.is_rejection());
+    }
+
+    #[test]
+    fn test_decode_set_code_bundle() {
+        use crate::utils::eip7702::{delegate_executor, Eip7702Transaction};
+        use ethers::signers::{LocalWallet, Signer};
+        use ethers::types::{Address, U256};
+
+        let wallet: LocalWallet =
+            "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+                .parse::<LocalWallet>()
+                .unwrap()
+                .with_chain_id(1u64);
+        let authorization = delegate_executor(&wallet, Address::from_low_u64_be(0xe), 7).unwrap();
+        let signed = Eip7702Transaction::new(1, U256::from(7), wallet.address())
+            .with_fees(U256::from(30_000_000_000u64), U256::from(1_000_000_000u64))
+            .with_gas(U256::from(200_000))
+            .with_authorization(authorization)
+            .signed(&wallet)
+            .unwrap();
+        let bundle = BundleRequest::new()
+            .push_transaction(signed.clone())
+            .set_block(U64::from(101));
+
+        let (txs, authorizations) = decode_bundle(&bundle).unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].from, wallet.address());
+        assert_eq!(txs[0].to, Some(wallet.address()));
+        assert_eq!(txs[0].transaction_type, Some(U64::from(4)));
+        assert_eq!(authorizations[&txs[0].hash], vec![authorization]);
+
+        // a corrupted envelope is still the bundle's fault
+        let mut raw = signed.raw.to_vec();
+        raw.truncate(raw.len() - 8);
+        let bundle = BundleRequest::new()
+            .push_transaction(Bytes::from(raw))
+            .set_block(U64::from(101));
+        let err = decode_bundle(&bundle).unwrap_err();
+        assert!(matches!(err, GateError::Undecodable(0, _)));
+        assert!(err.is_rejection());
+    }
+}
@@ -0,0 +1,885 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ethers::abi::{encode, Token};
+use ethers::providers::Middleware;
+use ethers::types::transaction::eip2718::TypedTransaction;
+use ethers::types::{
+    Address, BlockId, BlockNumber, Bytes, Signature, TransactionRequest, H256, I256, U256, U64,
+};
+use ethers::utils::id;
+use ethers::utils::keccak256;
+use ethers::utils::rlp::{Rlp, RlpStream};
+use ethers_flashbots::BundleRequest;
+use fork_database::reorg::ReorgEvent;
+use fork_database::stale::{BaseBlock, ForkHead};
+use log::info;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use strategies::pnl::{PnlLedger, DEFAULT_CONFIRMATIONS};
+use thiserror::Error;
+
+use super::relayer::construct_bundle;
+
+#[derive(Error, Debug)]
+pub enum BundleStoreError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    SerdeJson(#[from] serde_json::Error),
+    #[error("Provider error: {0}")]
+    Provider(String),
+    #[error("No treasury to read the realized profit of landed bundles from")]
+    NoTreasury,
+}
+
+/// A bundle that was signed and sent out but isn't known to have landed or expired yet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingBundle {
+    /// `keccak256` of the concatenated tx hashes
+    pub id: H256,
+    pub target_block: U64,
+    /// Raw signed txs, in bundle order
+    pub signed_txs: Vec<Bytes>,
+    pub tx_hashes: Vec<H256>,
+    pub searcher: Address,
+    /// Searcher nonces the bundle consumes
+    pub nonces: Vec<U256>,
+    /// Unix timestamp of the submission
+    pub submitted_at: u64,
+    /// Last block targeted when the bundle fans out over several, see
+    /// [fan_out](super::fan_out). Only `target_block` if `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_block: Option<U64>,
+    /// Strategy the bundle comes from, as booked in the [PnlLedger]
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub strategy: String,
+    /// Profit the bundle was simulated to make, booked next to what it made once it lands
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "strategies::report::option_decimal"
+    )]
+    pub expected_profit: Option<I256>,
+    /// Pools the bundle trades, bundles for the same block sharing none can be merged in any
+    /// order, see [fan_out](super::fan_out)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pools: Vec<Address>,
+    /// Raw txs expected to land before the bundle when it's placed at the tail of the block, see
+    /// [BundleGate::placement](super::bundle_gate::BundleGate::placement)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ahead: Vec<Bytes>,
+}
+
+impl PendingBundle {
+    pub fn new(
+        signed_txs: Vec<Bytes>,
+        target_block: U64,
+        searcher: Address,
+        nonces: Vec<U256>,
+    ) -> Self {
+        let tx_hashes: Vec<H256> = signed_txs
+            .iter()
+            .map(|tx| H256::from(keccak256(tx)))
+            .collect();
+        let id = H256::from(keccak256(
+            tx_hashes
+                .iter()
+                .flat_map(|h| h.as_bytes().to_vec())
+                .collect::<Vec<u8>>(),
+        ));
+        let submitted_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        Self {
+            id,
+            target_block,
+            signed_txs,
+            tx_hashes,
+            searcher,
+            nonces,
+            submitted_at,
+            max_block: None,
+            strategy: String::new(),
+            expected_profit: None,
+            pools: vec![],
+            ahead: vec![],
+        }
+    }
+
+    /// Book the bundle under `strategy`, making `expected_profit` once it lands
+    pub fn with_profit(mut self, strategy: impl Into<String>, expected_profit: I256) -> Self {
+        self.strategy = strategy.into();
+        self.expected_profit = Some(expected_profit);
+        self
+    }
+
+    pub fn with_pools(mut self, pools: impl IntoIterator<Item = Address>) -> Self {
+        self.pools = pools.into_iter().collect();
+        self
+    }
+
+    pub fn with_ahead(mut self, ahead: impl IntoIterator<Item = Bytes>) -> Self {
+        self.ahead = ahead.into_iter().collect();
+        self
+    }
+
+    /// Target every block from `target_block` to `max_block`
+    pub fn with_max_block(mut self, max_block: U64) -> Self {
+        self.max_block = Some(max_block);
+        self
+    }
+
+    pub fn last_target(&self) -> U64 {
+        self.max_block
+            .unwrap_or(self.target_block)
+            .max(self.target_block)
+    }
+
+    /// Bundle request for the block after `base`, one of the blocks the bundle targets, simulated
+    /// on `base`. Refused once the fork moved past `base`.
+    pub fn to_bundle_request_on(
+        &self,
+        base: BaseBlock,
+        head: &ForkHead,
+    ) -> eyre::Result<BundleRequest> {
+        construct_bundle(self.signed_txs.clone(), base, head)
+    }
+
+    /// Hashes of the txs the searcher signed, i.e. the bundle's txs but the victims'
+    pub fn own_tx_hashes(&self) -> Vec<H256> {
+        self.signed_txs
+            .iter()
+            .zip(&self.tx_hashes)
+            .filter(|(raw, _)| signer(raw) == Some(self.searcher))
+            .map(|(_, hash)| *hash)
+            .collect()
+    }
+
+    /// Block the bundle landed in, `Some` once all of our own txs are mined in the same block.
+    /// Victims mine on their own whether the bundle lands or not, their receipts say nothing.
+    pub async fn landed_block<M: Middleware>(
+        &self,
+        provider: &M,
+    ) -> Result<Option<BaseBlock>, BundleStoreError> {
+        let mut landed = None;
+        for hash in self.own_tx_hashes() {
+            let block = provider
+                .get_transaction_receipt(hash)
+                .await
+                .map_err(|e| BundleStoreError::Provider(e.to_string()))?
+                .and_then(|receipt| receipt.block_number.zip(receipt.block_hash))
+                .map(|(number, hash)| BaseBlock::new(number.as_u64(), hash));
+            match (block, landed) {
+                (None, _) => return Ok(None),
+                (Some(block), Some(first)) if block != first => return Ok(None),
+                (Some(block), _) => landed = Some(block),
+            }
+        }
+        Ok(landed)
+    }
+}
+
+/// Sender of a raw signed tx. Typed txs end in `[y_parity, r, s]` and sign their type followed
+/// by the other fields, which also covers the set-code txs ethers can't decode.
+fn signer(raw: &[u8]) -> Option<Address> {
+    let tx_type = *raw.first()?;
+    if tx_type >= 0xc0 {
+        let (tx, signature) = TypedTransaction::decode_signed(&Rlp::new(raw)).ok()?;
+        return signature.recover(tx.sighash()).ok();
+    }
+    let fields = Rlp::new(&raw[1..]);
+    let count = fields.item_count().ok()?;
+    if count < 3 {
+        return None;
+    }
+    let mut unsigned = RlpStream::new_list(count - 3);
+    for index in 0..count - 3 {
+        unsigned.append_raw(fields.at(index).ok()?.as_raw(), 1);
+    }
+    let mut payload = vec![tx_type];
+    payload.extend_from_slice(&unsigned.out());
+    let signature = Signature {
+        v: fields.val_at(count - 3).ok()?,
+        r: fields.val_at(count - 2).ok()?,
+        s: fields.val_at(count - 1).ok()?,
+    };
+    signature.recover(H256(keccak256(payload))).ok()
+}
+
+/// What [BundleStore::reconcile] found out about the bundles pending before a restart
+#[derive(Debug, Default)]
+pub struct Reconciliation {
+    /// All of our own txs were mined, in one block
+    pub landed: Vec<PendingBundle>,
+    /// Last target block is still ahead, the relays may have lost the bundle with us
+    pub resubmit: Vec<PendingBundle>,
+    /// Target block passed without inclusion
+    pub expired: Vec<PendingBundle>,
+}
+
+/// A bundle seen in `block`, which may still be reorged out
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LandedBundle {
+    pub bundle: PendingBundle,
+    pub block: BaseBlock,
+    /// What the bundle made in `block`, see [Treasury::realized_profit]
+    #[serde(default)]
+    pub profit: I256,
+}
+
+/// Where the profit of our bundles ends up: the WETH and ETH of the `executor` contract, less the
+/// ETH the searcher spends on gas
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Treasury {
+    pub executor: Address,
+    pub weth: Address,
+}
+
+impl Treasury {
+    /// What `bundle` made landing in `block`: the change of the executor's WETH and ETH and of the
+    /// searcher's ETH over the block. Only our own txs move them, the difference is the bundle's
+    /// profit after gas and bribes.
+    pub async fn realized_profit<M: Middleware>(
+        &self,
+        provider: &M,
+        bundle: &PendingBundle,
+        block: BaseBlock,
+    ) -> Result<I256, BundleStoreError> {
+        let parent = BlockNumber::Number(block.number.saturating_sub(1).into()).into();
+        let before = self.holdings(provider, bundle.searcher, parent).await?;
+        let after = self
+            .holdings(provider, bundle.searcher, BlockId::Hash(block.hash))
+            .await?;
+        Ok(after.profit_since(&before))
+    }
+
+    async fn holdings<M: Middleware>(
+        &self,
+        provider: &M,
+        searcher: Address,
+        at: BlockId,
+    ) -> Result<Holdings, BundleStoreError> {
+        let provider_error = |e: M::Error| BundleStoreError::Provider(e.to_string());
+        let data = [
+            &id("balanceOf(address)")[..],
+            &encode(&[Token::Address(self.executor)]),
+        ]
+        .concat();
+        let balance_of: TypedTransaction =
+            TransactionRequest::new().to(self.weth).data(data).into();
+        let weth = provider
+            .call(&balance_of, Some(at))
+            .await
+            .map_err(provider_error)?;
+        if weth.len() < 32 {
+            return Err(BundleStoreError::Provider(format!(
+                "WETH balance of {:?} is {} bytes",
+                self.executor,
+                weth.len()
+            )));
+        }
+        Ok(Holdings {
+            executor_weth: U256::from_big_endian(&weth[..32]),
+            executor_eth: provider
+                .get_balance(self.executor, Some(at))
+                .await
+                .map_err(provider_error)?,
+            searcher_eth: provider
+                .get_balance(searcher, Some(at))
+                .await
+                .map_err(provider_error)?,
+        })
+    }
+}
+
+/// Balances of a [Treasury] and a searcher at some block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Holdings {
+    pub executor_weth: U256,
+    pub executor_eth: U256,
+    pub searcher_eth: U256,
+}
+
+impl Holdings {
+    /// Wei gained since `before`, negative if lost
+    pub fn profit_since(&self, before: &Holdings) -> I256 {
+        let delta = |after: U256, before: U256| I256::from_raw(after) - I256::from_raw(before);
+        delta(self.executor_weth, before.executor_weth)
+            + delta(self.executor_eth, before.executor_eth)
+            + delta(self.searcher_eth, before.searcher_eth)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoreState {
+    bundles: BTreeMap<H256, PendingBundle>,
+    /// Nonces handed out and not yet known to be consumed on chain or released
+    reserved: BTreeMap<Address, BTreeSet<U256>>,
+    /// Landed bundles without enough confirmations yet
+    #[serde(default)]
+    landed: BTreeMap<H256, LandedBundle>,
+}
+
+/// Disk backed record of signed bundles and reserved nonces
+///
+/// Every change is written through to `path`, replacing the file atomically, so after a crash the
+/// bot knows which bundles might still land and which nonces are taken instead of starting from
+/// scratch.
+///
+/// Landed bundles are kept until their block has the confirmations of the [PnlLedger], a reorg
+/// removing their block puts them back to pending, see [BundleStore::on_reorg]. With a ledger
+/// attached, see [BundleStore::with_ledger], every bundle recorded, landed or reorged is booked
+/// there as well.
+#[derive(Debug)]
+pub struct BundleStore {
+    path: PathBuf,
+    state: Mutex<StoreState>,
+    ledger: Option<Arc<Mutex<PnlLedger>>>,
+    treasury: Option<Treasury>,
+}
+
+impl BundleStore {
+    /// Open the store at `path`, loading what a previous run left there
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, BundleStoreError> {
+        let path = path.into();
+        let state = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => StoreState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path,
+            state: Mutex::new(state),
+            ledger: None,
+            treasury: None,
+        })
+    }
+
+    /// Book the bundles in `ledger`, those left by a previous run included
+    pub fn with_ledger(mut self, ledger: Arc<Mutex<PnlLedger>>) -> Self {
+        {
+            let state = self.state.lock();
+            let mut ledger = ledger.lock();
+            for bundle in state.bundles.values() {
+                ledger.submitted(
+                    bundle.id,
+                    &bundle.strategy,
+                    bundle.last_target().as_u64(),
+                    bundle.expected_profit,
+                );
+            }
+            for LandedBundle {
+                bundle,
+                block,
+                profit,
+            } in state.landed.values()
+            {
+                ledger.submitted(
+                    bundle.id,
+                    &bundle.strategy,
+                    bundle.last_target().as_u64(),
+                    bundle.expected_profit,
+                );
+                ledger.landed(bundle.id, block.number, block.hash, *profit);
+            }
+        }
+        self.ledger = Some(ledger);
+        self
+    }
+
+    /// Value landed bundles by what `treasury` gained, see [BundleStore::realized_profit]
+    pub fn with_treasury(mut self, treasury: Treasury) -> Self {
+        self.treasury = Some(treasury);
+        self
+    }
+
+    /// What `bundle` made landing in `block`, to pass to [BundleStore::landed]
+    pub async fn realized_profit<M: Middleware>(
+        &self,
+        provider: &M,
+        bundle: &PendingBundle,
+        block: BaseBlock,
+    ) -> Result<I256, BundleStoreError> {
+        let treasury = self.treasury.ok_or(BundleStoreError::NoTreasury)?;
+        treasury.realized_profit(provider, bundle, block).await
+    }
+
+    pub fn ledger(&self) -> Option<&Arc<Mutex<PnlLedger>>> {
+        self.ledger.as_ref()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn pending(&self) -> Vec<PendingBundle> {
+        self.state.lock().bundles.values().cloned().collect()
+    }
+
+    pub fn get(&self, id: H256) -> Option<PendingBundle> {
+        self.state.lock().bundles.get(&id).cloned()
+    }
+
+    /// Reserve the lowest nonce of `searcher` from the chain nonce on that isn't reserved yet, so
+    /// released nonces are handed out again before the gap blocks the later ones
+    pub fn reserve_nonce(
+        &self,
+        searcher: Address,
+        chain_nonce: U256,
+    ) -> Result<U256, BundleStoreError> {
+        let mut state = self.state.lock();
+        let reserved = state.reserved.entry(searcher).or_default();
+        let mut nonce = chain_nonce;
+        for taken in reserved.range(chain_nonce..) {
+            if *taken != nonce {
+                break;
+            }
+            nonce += U256::one();
+        }
+        reserved.insert(nonce);
+        self.persist(&state)?;
+        Ok(nonce)
+    }
+
+    /// Give back a nonce that won't be used, e.g. when a bundle is dropped before submission
+    pub fn release_nonce(&self, searcher: Address, nonce: U256) -> Result<(), BundleStoreError> {
+        let mut state = self.state.lock();
+        if let Some(reserved) = state.reserved.get_mut(&searcher) {
+            reserved.remove(&nonce);
+        }
+        self.persist(&state)
+    }
+
+    /// Record a signed bundle, call before handing it to the relays
+    pub fn record(&self, bundle: PendingBundle) -> Result<(), BundleStoreError> {
+        let mut state = self.state.lock();
+        let reserved = state.reserved.entry(bundle.searcher).or_default();
+        reserved.extend(bundle.nonces.iter().copied());
+        if let Some(ledger) = &self.ledger {
+            ledger.lock().submitted(
+                bundle.id,
+                &bundle.strategy,
+                bundle.last_target().as_u64(),
+                bundle.expected_profit,
+            );
+        }
+        state.bundles.insert(bundle.id, bundle);
+        self.persist(&state)
+    }
+
+    /// The bundle's txs were seen in `block`, making the realized `profit`, it's kept until the
+    /// block is confirmed
+    pub fn landed(&self, id: H256, block: BaseBlock, profit: I256) -> Result<(), BundleStoreError> {
+        let mut state = self.state.lock();
+        let Some(bundle) = state.bundles.remove(&id) else {
+            return Ok(());
+        };
+        if let Some(ledger) = &self.ledger {
+            ledger.lock().landed(id, block.number, block.hash, profit);
+        }
+        state.landed.insert(
+            id,
+            LandedBundle {
+                bundle,
+                block,
+                profit,
+            },
+        );
+        self.persist(&state)
+    }
+
+    pub fn landed_bundles(&self) -> Vec<LandedBundle> {
+        self.state.lock().landed.values().cloned().collect()
+    }
+
+    /// Landed bundles wait this many confirmations, the ledger's if one is attached
+    pub fn confirmations(&self) -> u64 {
+        self.ledger
+            .as_ref()
+            .map_or(DEFAULT_CONFIRMATIONS, |ledger| {
+                ledger.lock().confirmations()
+            })
+    }
+
+    /// Put the landed bundles whose block `event` removed back to pending. Their txs may land
+    /// again in the new chain, the bundles are targeted for the next [BundleStore::confirmations]
+    /// blocks, as long as the ledger waits for them.
+    pub fn on_reorg(&self, event: &ReorgEvent) -> Result<Vec<H256>, BundleStoreError> {
+        let mut state = self.state.lock();
+        let removed: Vec<H256> = state
+            .landed
+            .iter()
+            .filter(|(_, landed)| event.removes(landed.block.number, landed.block.hash))
+            .map(|(id, _)| *id)
+            .collect();
+        let retarget = U64::from(event.new_head.0 + self.confirmations());
+        for id in &removed {
+            let Some(LandedBundle { mut bundle, .. }) = state.landed.remove(id) else {
+                continue;
+            };
+            bundle.max_block = Some(bundle.last_target().max(retarget));
+            state.bundles.insert(*id, bundle);
+        }
+        if let Some(ledger) = &self.ledger {
+            ledger.lock().on_reorg(event);
+        }
+        self.persist(&state)?;
+        Ok(removed)
+    }
+
+    /// New head `number`, forgets the landed bundles with enough confirmations and expires the
+    /// ledger's. Returns the bundles that became final.
+    pub fn on_block(&self, number: u64) -> Result<Vec<H256>, BundleStoreError> {
+        let confirmations = self.confirmations();
+        let mut state = self.state.lock();
+        let finalized: Vec<H256> = state
+            .landed
+            .iter()
+            .filter(|(_, landed)| number + 1 >= landed.block.number + confirmations)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &finalized {
+            state.landed.remove(id);
+        }
+        if let Some(ledger) = &self.ledger {
+            let mut ledger = ledger.lock();
+            ledger.on_block(number);
+            ledger.expire(number);
+        }
+        if !finalized.is_empty() {
+            self.persist(&state)?;
+        }
+        Ok(finalized)
+    }
+
+    /// Forget a bundle once its fate is known, its nonces are released unless `landed`
+    pub fn resolve(&self, id: H256, landed: bool) -> Result<(), BundleStoreError> {
+        let mut state = self.state.lock();
+        let Some(bundle) = state.bundles.remove(&id) else {
+            return Ok(());
+        };
+        if !landed {
+            if let Some(reserved) = state.reserved.get_mut(&bundle.searcher) {
+                for nonce in &bundle.nonces {
+                    reserved.remove(nonce);
+                }
+            }
+        }
+        self.persist(&state)
+    }
+
+    /// Drop reservations the chain has moved past
+    pub fn sync_nonce(&self, searcher: Address, chain_nonce: U256) -> Result<(), BundleStoreError> {
+        let mut state = self.state.lock();
+        if let Some(reserved) = state.reserved.get_mut(&searcher) {
+            reserved.retain(|n| *n >= chain_nonce);
+        }
+        self.persist(&state)
+    }
+
+    /// [BundleStore::sync_nonce] with the nonce of `searcher` at the latest block, called once a
+    /// bundle of the searcher landed, expired or was rejected so the nonces it consumed don't stay
+    /// reserved
+    pub async fn resync_nonce<M: Middleware>(
+        &self,
+        provider: &M,
+        searcher: Address,
+    ) -> Result<(), BundleStoreError> {
+        let chain_nonce = provider
+            .get_transaction_count(searcher, Some(BlockNumber::Latest.into()))
+            .await
+            .map_err(|e| BundleStoreError::Provider(e.to_string()))?;
+        self.sync_nonce(searcher, chain_nonce)
+    }
+
+    /// Sort the bundles left by a previous run into landed, still resubmittable and expired ones,
+    /// resolving the landed and expired ones and syncing reserved nonces with the chain
+    pub async fn reconcile<M: Middleware>(
+        &self,
+        provider: &M,
+    ) -> Result<Reconciliation, BundleStoreError> {
+        let current_block = provider
+            .get_block_number()
+            .await
+            .map_err(|e| BundleStoreError::Provider(e.to_string()))?;
+
+        let mut reconciliation = Reconciliation::default();
+        let mut searchers = BTreeSet::new();
+        for bundle in self.pending() {
+            searchers.insert(bundle.searcher);
+
+            if let Some(block) = bundle.landed_block(provider).await? {
+                let profit = self.realized_profit(provider, &bundle, block).await?;
+                self.landed(bundle.id, block, profit)?;
+                reconciliation.landed.push(bundle);
+            } else if bundle.last_target() > current_block {
+                reconciliation.resubmit.push(bundle);
+            } else {
+                self.resolve(bundle.id, false)?;
+                reconciliation.expired.push(bundle);
+            }
+        }
+
+        for searcher in searchers {
+            self.resync_nonce(provider, searcher).await?;
+        }
+
+        info!(
+            "Reconciled bundles after restart: {} landed, {} to resubmit, {} expired",
+            reconciliation.landed.len(),
+            reconciliation.resubmit.len(),
+            reconciliation.expired.len()
+        );
+        Ok(reconciliation)
+    }
+
+    fn persist(&self, state: &StoreState) -> Result<(), BundleStoreError> {
+        let data = serde_json::to_vec(state)?;
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&data)?;
+        // on disk before it replaces the previous state, a crash can't leave an empty file behind
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::eip7702::Eip7702Transaction;
+    use ethers::signers::{LocalWallet, Signer};
+    use ethers::types::{Eip1559TransactionRequest, TransactionRequest};
+
+    #[test]
+    fn test_own_txs() {
+        let searcher: LocalWallet =
+            "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+                .parse()
+                .unwrap();
+        let victim: LocalWallet =
+            "0x0123456789012345678901234567890123456789012345678901234567890123"
+                .parse()
+                .unwrap();
+        let sign = |wallet: &LocalWallet, tx: TypedTransaction| {
+            let signature = wallet.sign_transaction_sync(&tx).unwrap();
+            tx.rlp_signed(&signature)
+        };
+        let frontrun = sign(
+            &searcher,
+            Eip1559TransactionRequest::new()
+                .to(Address::from_low_u64_be(1))
+                .nonce(0)
+                .chain_id(1u64)
+                .into(),
+        );
+        let victim_tx = sign(
+            &victim,
+            TransactionRequest::new()
+                .to(Address::from_low_u64_be(1))
+                .nonce(0)
+                .gas_price(1)
+                .chain_id(1u64)
+                .into(),
+        );
+        let backrun = Eip7702Transaction::new(1, U256::one(), searcher.address())
+            .sign(&searcher)
+            .unwrap();
+        assert_eq!(signer(&victim_tx), Some(victim.address()));
+        assert_eq!(signer(&backrun), Some(searcher.address()));
+
+        let bundle = PendingBundle::new(
+            vec![frontrun, victim_tx, backrun],
+            U64::from(100),
+            searcher.address(),
+            vec![U256::zero(), U256::one()],
+        );
+        assert_eq!(
+            bundle.own_tx_hashes(),
+            vec![bundle.tx_hashes[0], bundle.tx_hashes[2]]
+        );
+    }
+
+    #[test]
+    fn test_bundle_store_survives_restart() {
+        let path = std::env::temp_dir().join(format!("qilin-bundles-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let searcher = Address::from_low_u64_be(1);
+
+        let store = BundleStore::open(&path).unwrap();
+        let first = store.reserve_nonce(searcher, U256::from(7)).unwrap();
+        let second = store.reserve_nonce(searcher, U256::from(7)).unwrap();
+        assert_eq!((first, second), (U256::from(7), U256::from(8)));
+
+        let bundle = PendingBundle::new(
+            vec![Bytes::from(vec![0x02, 0x01]), Bytes::from(vec![0x02, 0x02])],
+            U64::from(100),
+            searcher,
+            vec![first, second],
+        );
+        store.record(bundle.clone()).unwrap();
+        drop(store);
+
+        // reopened state still holds the bundle and its nonces
+        let store = BundleStore::open(&path).unwrap();
+        assert_eq!(store.pending(), vec![bundle.clone()]);
+        assert_eq!(
+            store.reserve_nonce(searcher, U256::from(7)).unwrap(),
+            U256::from(9)
+        );
+
+        // a released nonce in the middle is handed out before the next one up
+        store.release_nonce(searcher, U256::from(8)).unwrap();
+        assert_eq!(
+            store.reserve_nonce(searcher, U256::from(7)).unwrap(),
+            U256::from(8)
+        );
+        assert_eq!(
+            store.reserve_nonce(searcher, U256::from(7)).unwrap(),
+            U256::from(10)
+        );
+
+        store.resolve(bundle.id, false).unwrap();
+        store.release_nonce(searcher, U256::from(9)).unwrap();
+        assert!(store.pending().is_empty());
+        assert_eq!(
+            store.reserve_nonce(searcher, U256::from(7)).unwrap(),
+            U256::from(7)
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_nonces_reserved_again_after_expiry() {
+        use ethers::providers::Provider;
+
+        let path = std::env::temp_dir().join(format!("qilin-nonces-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let searcher = Address::from_low_u64_be(1);
+        let store = BundleStore::open(&path).unwrap();
+        let bundle = |tx: u8, nonces: Vec<U256>| {
+            PendingBundle::new(
+                vec![Bytes::from(vec![0x02, tx])],
+                U64::from(10),
+                searcher,
+                nonces,
+            )
+        };
+
+        let nonce = store.reserve_nonce(searcher, U256::from(7)).unwrap();
+        let expiring = bundle(1, vec![nonce]);
+        store.record(expiring.clone()).unwrap();
+        assert_eq!(
+            store.reserve_nonce(searcher, U256::from(7)).unwrap(),
+            U256::from(8)
+        );
+        store.release_nonce(searcher, U256::from(8)).unwrap();
+
+        // expired without our tx mined, the chain is still at 7
+        let (provider, mock) = Provider::mocked();
+        mock.push::<U256, _>(U256::from(7)).unwrap();
+        store.resolve(expiring.id, false).unwrap();
+        store.resync_nonce(&provider, searcher).await.unwrap();
+        let nonce = store.reserve_nonce(searcher, U256::from(7)).unwrap();
+        assert_eq!(nonce, U256::from(7));
+
+        // the next bundle lands on it, its nonce doesn't stay reserved past the chain's
+        let landing = bundle(2, vec![nonce]);
+        store.record(landing.clone()).unwrap();
+        let block = BaseBlock::new(10, H256::from_low_u64_be(10));
+        store.landed(landing.id, block, I256::zero()).unwrap();
+        mock.push::<U256, _>(U256::from(8)).unwrap();
+        store.resync_nonce(&provider, searcher).await.unwrap();
+        assert!(store.state.lock().reserved[&searcher].is_empty());
+        assert_eq!(
+            store.reserve_nonce(searcher, U256::from(8)).unwrap(),
+            U256::from(8)
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_landed_bundle_reorged_out() {
+        let path = std::env::temp_dir().join(format!("qilin-landed-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let ledger = Arc::new(Mutex::new(PnlLedger::new(3)));
+        let store = BundleStore::open(&path)
+            .unwrap()
+            .with_ledger(ledger.clone());
+        let bundle = PendingBundle::new(
+            vec![Bytes::from(vec![0x02, 0x01])],
+            U64::from(10),
+            Address::from_low_u64_be(1),
+            vec![U256::zero()],
+        )
+        .with_profit("sandwich", I256::from(100));
+        store.record(bundle.clone()).unwrap();
+
+        // expected to make 100, the gas and the bribe cost more than simulated
+        let block = BaseBlock::new(10, H256::from_low_u64_be(10));
+        store.landed(bundle.id, block, I256::from(90)).unwrap();
+        assert!(store.pending().is_empty());
+        assert_eq!(ledger.lock().unconfirmed(None), I256::from(90));
+        assert_eq!(
+            ledger.lock().entry(&bundle.id).unwrap().expected_profit,
+            Some(I256::from(100))
+        );
+        drop(store);
+
+        // block 10 is replaced, the bundle may land again in the next confirmations blocks
+        let store = BundleStore::open(&path)
+            .unwrap()
+            .with_ledger(ledger.clone());
+        assert_eq!(store.landed_bundles()[0].profit, I256::from(90));
+        let event = ReorgEvent {
+            common_ancestor: 9,
+            removed: vec![(10, block.hash)],
+            new_head: (11, H256::from_low_u64_be(111)),
+        };
+        assert_eq!(store.on_reorg(&event).unwrap(), vec![bundle.id]);
+        let pending = store.pending();
+        assert_eq!(pending[0].last_target(), U64::from(14));
+        assert!(store.landed_bundles().is_empty());
+        assert_eq!(ledger.lock().unconfirmed(None), I256::zero());
+
+        // landed again in block 12, final once it has three confirmations
+        let again = BaseBlock::new(12, H256::from_low_u64_be(112));
+        store.on_block(11).unwrap();
+        store.landed(bundle.id, again, I256::from(95)).unwrap();
+        assert!(store.on_block(13).unwrap().is_empty());
+        assert_eq!(store.on_block(14).unwrap(), vec![bundle.id]);
+        assert!(store.landed_bundles().is_empty());
+        assert_eq!(ledger.lock().realized(Some("sandwich")), I256::from(95));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_realized_profit() {
+        let ether = |n: u64| U256::exp10(18) * n;
+        let before = Holdings {
+            executor_weth: ether(10),
+            executor_eth: U256::zero(),
+            searcher_eth: ether(1),
+        };
+        // the backrun brought 0.5 WETH, 0.1 of which went to the builder, and the searcher paid
+        // 0.1 ETH of gas
+        let after = Holdings {
+            executor_weth: ether(10) + ether(4) / 10,
+            executor_eth: U256::zero(),
+            searcher_eth: ether(9) / 10,
+        };
+        assert_eq!(after.profit_since(&before), I256::from_raw(ether(3) / 10));
+        assert_eq!(before.profit_since(&after), -I256::from_raw(ether(3) / 10));
+    }
+}
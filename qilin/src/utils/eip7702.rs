@@ -0,0 +1,331 @@
+//! Type-4 (EIP-7702) transactions
+//!
+//! ethers 2 can't build or sign set-code txs, [Eip7702Transaction] encodes them by hand as
+//! `0x04 || rlp([chain_id, nonce, max_priority_fee_per_gas, max_fee_per_gas, gas_limit, to,
+//! value, data, access_list, authorization_list, y_parity, r, s])`. A
+//! [SignedEip7702Transaction] goes into a bundle as its raw bytes next to the other txs, see
+//! [construct_bundle](super::relayer::construct_bundle).
+//!
+//! Delegating the executor to the searcher EOA saves deploying it and calling into it: the
+//! bundle's txs are sent to the EOA itself, which runs the executor's code with its own balances.
+//! [delegate_executor] signs that authorization. Simulate with
+//! [fork_database::delegation::apply_authorizations] before running the tx, the
+//! [BundleGate](super::bundle_gate::BundleGate) decodes the set-code txs of a bundle with
+//! [SignedEip7702Transaction::decode] and does so.
+
+use ethers::{
+    signers::{LocalWallet, Signer, WalletError},
+    types::{transaction::eip2930::AccessList, Address, Bytes, Signature, Transaction, H256, U256},
+    utils::{
+        keccak256,
+        rlp::{DecoderError, Rlp, RlpStream},
+    },
+};
+use ethers_flashbots::BundleTransaction;
+use fork_database::delegation::{Authorization, SignedAuthorization, PER_EMPTY_ACCOUNT_COST};
+
+pub const EIP7702_TX_TYPE: u8 = 0x04;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Eip7702Transaction {
+    pub chain_id: u64,
+    pub nonce: U256,
+    pub max_priority_fee_per_gas: U256,
+    pub max_fee_per_gas: U256,
+    pub gas: U256,
+    /// set-code txs can't create contracts
+    pub to: Address,
+    pub value: U256,
+    pub data: Bytes,
+    pub access_list: AccessList,
+    pub authorization_list: Vec<SignedAuthorization>,
+}
+
+impl Eip7702Transaction {
+    pub fn new(chain_id: u64, nonce: U256, to: Address) -> Self {
+        Self {
+            chain_id,
+            nonce,
+            max_priority_fee_per_gas: U256::zero(),
+            max_fee_per_gas: U256::zero(),
+            gas: U256::zero(),
+            to,
+            value: U256::zero(),
+            data: Bytes::default(),
+            access_list: AccessList::default(),
+            authorization_list: vec![],
+        }
+    }
+
+    pub fn with_fees(mut self, max_fee_per_gas: U256, max_priority_fee_per_gas: U256) -> Self {
+        self.max_fee_per_gas = max_fee_per_gas;
+        self.max_priority_fee_per_gas = max_priority_fee_per_gas;
+        self
+    }
+
+    pub fn with_gas(mut self, gas: U256) -> Self {
+        self.gas = gas;
+        self
+    }
+
+    pub fn with_value(mut self, value: U256) -> Self {
+        self.value = value;
+        self
+    }
+
+    pub fn with_data(mut self, data: Bytes) -> Self {
+        self.data = data;
+        self
+    }
+
+    pub fn with_access_list(mut self, access_list: AccessList) -> Self {
+        self.access_list = access_list;
+        self
+    }
+
+    pub fn with_authorization(mut self, authorization: SignedAuthorization) -> Self {
+        self.authorization_list.push(authorization);
+        self
+    }
+
+    /// Intrinsic gas of the authorization list, on top of the usual 21k and calldata
+    pub fn authorization_gas(&self) -> u64 {
+        self.authorization_list.len() as u64 * PER_EMPTY_ACCOUNT_COST
+    }
+
+    fn rlp_fields(&self, stream: &mut RlpStream) {
+        stream.append(&self.chain_id);
+        stream.append(&self.nonce);
+        stream.append(&self.max_priority_fee_per_gas);
+        stream.append(&self.max_fee_per_gas);
+        stream.append(&self.gas);
+        stream.append(&self.to);
+        stream.append(&self.value);
+        stream.append(&self.data);
+        stream.append(&self.access_list);
+        stream.begin_list(self.authorization_list.len());
+        for authorization in &self.authorization_list {
+            authorization.rlp_append(stream);
+        }
+    }
+
+    /// Hash signed by the sender
+    pub fn sighash(&self) -> H256 {
+        let mut stream = RlpStream::new_list(10);
+        self.rlp_fields(&mut stream);
+        let mut payload = vec![EIP7702_TX_TYPE];
+        payload.extend_from_slice(&stream.out());
+        H256(keccak256(payload))
+    }
+
+    /// Raw tx as sent to `eth_sendRawTransaction` or put into a bundle
+    pub fn rlp_signed(&self, signature: &Signature) -> Bytes {
+        let mut stream = RlpStream::new_list(13);
+        self.rlp_fields(&mut stream);
+        // typed txs carry the y parity, not a legacy `v`
+        let y_parity = if signature.v >= 27 {
+            signature.v - 27
+        } else {
+            signature.v
+        };
+        stream.append(&y_parity);
+        stream.append(&signature.r);
+        stream.append(&signature.s);
+        let mut raw = vec![EIP7702_TX_TYPE];
+        raw.extend_from_slice(&stream.out());
+        raw.into()
+    }
+
+    pub fn sign(&self, wallet: &LocalWallet) -> Result<Bytes, WalletError> {
+        let signature = wallet.sign_hash(self.sighash())?;
+        Ok(self.rlp_signed(&signature))
+    }
+
+    pub fn signed(&self, wallet: &LocalWallet) -> Result<SignedEip7702Transaction, WalletError> {
+        Ok(SignedEip7702Transaction {
+            raw: self.sign(wallet)?,
+            tx: self.clone(),
+        })
+    }
+}
+
+/// A signed [Eip7702Transaction] with its raw encoding
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedEip7702Transaction {
+    pub tx: Eip7702Transaction,
+    pub raw: Bytes,
+}
+
+impl SignedEip7702Transaction {
+    /// Decode a raw set-code tx, as [Eip7702Transaction::sign] encodes it
+    pub fn decode(raw: Bytes) -> Result<Self, DecoderError> {
+        if raw.first() != Some(&EIP7702_TX_TYPE) {
+            return Err(DecoderError::Custom("not a set-code tx"));
+        }
+        let rlp = Rlp::new(&raw[1..]);
+        if rlp.item_count()? != 13 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        let authorization_list = rlp
+            .at(9)?
+            .iter()
+            .map(|item| SignedAuthorization::rlp_decode(&item))
+            .collect::<Result<_, _>>()?;
+        let tx = Eip7702Transaction {
+            chain_id: rlp.val_at(0)?,
+            nonce: rlp.val_at(1)?,
+            max_priority_fee_per_gas: rlp.val_at(2)?,
+            max_fee_per_gas: rlp.val_at(3)?,
+            gas: rlp.val_at(4)?,
+            to: rlp.val_at(5)?,
+            value: rlp.val_at(6)?,
+            data: rlp.val_at(7)?,
+            access_list: rlp.val_at(8)?,
+            authorization_list,
+        };
+        Ok(Self { tx, raw })
+    }
+
+    pub fn signature(&self) -> Result<Signature, DecoderError> {
+        let rlp = Rlp::new(&self.raw[1..]);
+        Ok(Signature {
+            r: rlp.val_at(11)?,
+            s: rlp.val_at(12)?,
+            v: 27 + rlp.val_at::<u64>(10)?,
+        })
+    }
+
+    /// The tx as ethers sees txs, with its sender recovered. Its authorization list isn't part
+    /// of it and has to be applied separately.
+    pub fn to_transaction(&self) -> eyre::Result<Transaction> {
+        let signature = self.signature()?;
+        let tx = &self.tx;
+        Ok(Transaction {
+            hash: H256(keccak256(&self.raw)),
+            nonce: tx.nonce,
+            from: signature.recover(tx.sighash())?,
+            to: Some(tx.to),
+            value: tx.value,
+            gas: tx.gas,
+            input: tx.data.clone(),
+            max_fee_per_gas: Some(tx.max_fee_per_gas),
+            max_priority_fee_per_gas: Some(tx.max_priority_fee_per_gas),
+            access_list: Some(tx.access_list.clone()),
+            transaction_type: Some(EIP7702_TX_TYPE.into()),
+            chain_id: Some(tx.chain_id.into()),
+            v: (signature.v - 27).into(),
+            r: signature.r,
+            s: signature.s,
+            ..Default::default()
+        })
+    }
+}
+
+impl From<SignedEip7702Transaction> for BundleTransaction {
+    // ethers can't decode a type-4 tx, it's bundled as it was signed
+    fn from(signed: SignedEip7702Transaction) -> Self {
+        signed.raw.into()
+    }
+}
+
+pub fn sign_authorization(
+    wallet: &LocalWallet,
+    auth: Authorization,
+) -> Result<SignedAuthorization, WalletError> {
+    let signature = wallet.sign_hash(auth.signing_hash())?;
+    Ok(SignedAuthorization {
+        auth,
+        y_parity: (signature.v - 27) as u8,
+        r: signature.r,
+        s: signature.s,
+    })
+}
+
+/// Authorize `executor`'s code on the wallet's own EOA, to be put in a tx the wallet sends with
+/// `tx_nonce`. The tx's nonce is used up before the authorization is processed, so the
+/// authorization is signed for the next one.
+pub fn delegate_executor(
+    wallet: &LocalWallet,
+    executor: Address,
+    tx_nonce: u64,
+) -> Result<SignedAuthorization, WalletError> {
+    sign_authorization(
+        wallet,
+        Authorization {
+            chain_id: U256::from(wallet.chain_id()),
+            address: executor,
+            nonce: tx_nonce + 1,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::relayer::construct_bundle;
+    use ethers::utils::rlp::Rlp;
+    use fork_database::stale::{BaseBlock, ForkHead};
+
+    #[test]
+    fn test_self_delegating_tx() {
+        let wallet: LocalWallet =
+            "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+                .parse::<LocalWallet>()
+                .unwrap()
+                .with_chain_id(1u64);
+        let executor = Address::from_low_u64_be(0xe);
+        let authorization = delegate_executor(&wallet, executor, 7).unwrap();
+        assert_eq!(authorization.auth.nonce, 8);
+        assert_eq!(authorization.authority().unwrap(), wallet.address());
+
+        let tx = Eip7702Transaction::new(1, U256::from(7), wallet.address())
+            .with_fees(U256::from(30_000_000_000u64), U256::from(1_000_000_000u64))
+            .with_gas(U256::from(200_000))
+            .with_data(Bytes::from_static(&[0xde, 0xad]))
+            .with_authorization(authorization);
+        assert_eq!(tx.authorization_gas(), PER_EMPTY_ACCOUNT_COST);
+
+        let raw = tx.sign(&wallet).unwrap();
+        assert_eq!(raw[0], EIP7702_TX_TYPE);
+        let rlp = Rlp::new(&raw[1..]);
+        assert_eq!(rlp.item_count().unwrap(), 13);
+        assert_eq!(
+            rlp.at(5).unwrap().as_val::<Address>().unwrap(),
+            wallet.address()
+        );
+        assert_eq!(rlp.at(9).unwrap().item_count().unwrap(), 1);
+
+        // the sender recovers from the sighash
+        let signature = Signature {
+            r: rlp.val_at(11).unwrap(),
+            s: rlp.val_at(12).unwrap(),
+            v: 27 + rlp.val_at::<u64>(10).unwrap(),
+        };
+        assert_eq!(signature.recover(tx.sighash()).unwrap(), wallet.address());
+
+        // bundled as signed, after a victim tx
+        let signed = tx.signed(&wallet).unwrap();
+        assert_eq!(signed.raw, raw);
+        assert_eq!(
+            SignedEip7702Transaction::decode(raw.clone()),
+            Ok(signed.clone())
+        );
+        let decoded = signed.to_transaction().unwrap();
+        assert_eq!(decoded.from, wallet.address());
+        assert_eq!(decoded.nonce, U256::from(7));
+        assert_eq!(decoded.hash, H256(keccak256(&raw)));
+        let victim = Bytes::from(vec![0x02, 0x01]);
+        let base = BaseBlock::new(100, H256::from_low_u64_be(100));
+        let bundle = construct_bundle(
+            vec![BundleTransaction::from(victim.clone()), signed.into()],
+            base,
+            &ForkHead::new(base),
+        )
+        .unwrap();
+        let request = serde_json::to_value(&bundle).unwrap();
+        assert_eq!(
+            request["txs"],
+            serde_json::to_value(vec![victim, raw]).unwrap()
+        );
+    }
+}
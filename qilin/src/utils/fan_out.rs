@@ -0,0 +1,461 @@
+//! Submitting the same bundle for a range of target blocks
+//!
+//! A bundle recorded in the [BundleStore] with [PendingBundle::with_max_block] targets every block
+//! from its `target_block` to its `max_block`. Rather than sending all targets upfront,
+//! [advance_fan_out] sends the next one each block, after re-simulating the bundle on the block
+//! before it, as long as that block is still the [ForkHead], and stops as soon as our own txs
+//! landed or the last target passed, so the later targets are never sent. [run_fan_outs] does that
+//! for every bundle of the store as blocks come in, bundles left by a previous run included, and
+//! keeps the store's landed bundles in step with the chain: a reorg removing their block puts them
+//! back to pending, see [BundleStore::on_reorg], and they are forgotten once confirmed. Every
+//! submission, resubmits included, goes through the [BundleGate] first: a bundle it rejects is
+//! resolved as failed and never sent again. Once a bundle landed, expired or was rejected the
+//! [BundleBuilder] it came from releases its capital and exposure, see [BundleBuilder::settle].
+//! Bundles due for the same block are first considered for merging, see [merge_fan_outs]: when
+//! the gate simulates them together for more than they'd make apart, they're sent as one bundle.
+//! Given a [MevShareClient], bundles go to MEV-Share instead, requesting the placement the gate
+//! finds them most profitable in, see [send_placed_bundle](relayer::send_placed_bundle).
+
+use ethers::providers::Middleware;
+use ethers::signers::Signer;
+use ethers::types::{Block, Bytes, H256, I256, U64};
+use ethers_flashbots::{BundleRequest, FlashbotsMiddleware};
+use fork_database::reorg::{ReorgWatcher, DEFAULT_REORG_DEPTH};
+use fork_database::stale::{BaseBlock, ForkHead};
+use futures::{pin_mut, Stream, StreamExt};
+use log::{debug, info, warn};
+use strategies::bundle_merge::{plan_merge, MergeCandidate, MergePlan};
+use strategies::risk::BundleOutcome;
+
+use super::bundle_builder::BundleBuilder;
+use super::bundle_gate::{BundleGate, GateError};
+use super::bundle_store::{BundleStore, PendingBundle};
+use super::relayer::{self, validate_simulation_response, MevShareClient};
+use crate::shutdown::ShutdownController;
+
+/// What [advance_fan_out] did with a bundle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FanOutStep {
+    /// the first target is more than a block ahead
+    Waiting,
+    Submitted(U64),
+    /// re-simulation on the block before the target failed, the target is skipped, later ones
+    /// are still tried
+    SimulationFailed(U64, String),
+    /// the [BundleGate] rejected the bundle for the target, it's dropped from the store
+    Rejected(U64, String),
+    /// our txs were mined in the block, making the realized profit, the bundle waits for its
+    /// confirmations and its later targets are canceled
+    Landed(U64, I256),
+    /// the last target passed without the bundle landing
+    Expired,
+}
+
+impl FanOutStep {
+    /// What became of the bundle, `None` while it may still land
+    pub fn outcome(&self) -> Option<BundleOutcome> {
+        match self {
+            Self::Landed(_, profit) => Some(BundleOutcome::Landed(*profit)),
+            Self::Rejected(..) => Some(BundleOutcome::Failed),
+            Self::Expired => Some(BundleOutcome::NotIncluded),
+            Self::Waiting | Self::Submitted(_) | Self::SimulationFailed(..) => None,
+        }
+    }
+}
+
+/// Target to submit `bundle` for with the chain at `head`, the block after it if targeted
+pub fn next_target(bundle: &PendingBundle, head: U64) -> Option<U64> {
+    let target = head + 1;
+    (target >= bundle.target_block && target <= bundle.last_target()).then_some(target)
+}
+
+/// Advances `bundle`, recorded in `store`, with the chain at `base`: marks it landed if our txs
+/// were mined, resolves it if its last target passed, otherwise re-simulates it for the next block
+/// and submits it if it still passes, on `gate` too, and `base` is still the fork's head, through
+/// `mev_share` in its preferred placement if given along with a `gate`. The searcher's nonces are
+/// re-synced whenever the bundle's fate is known. Meant to be called once per new block.
+pub async fn advance_fan_out<M, S>(
+    flashbots: &FlashbotsMiddleware<M, S>,
+    store: &BundleStore,
+    bundle: &PendingBundle,
+    base: BaseBlock,
+    fork_head: &ForkHead,
+    gate: Option<&BundleGate>,
+    mev_share: Option<&MevShareClient<S>>,
+) -> eyre::Result<FanOutStep>
+where
+    M: Middleware,
+    S: Signer,
+{
+    if let Some(block) = bundle.landed_block(flashbots).await? {
+        let profit = store.realized_profit(flashbots, bundle, block).await?;
+        info!(
+            "Bundle {:?} landed in block {} making {}, canceling later targets",
+            bundle.id, block.number, profit
+        );
+        store.landed(bundle.id, block, profit)?;
+        store.resync_nonce(flashbots, bundle.searcher).await?;
+        return Ok(FanOutStep::Landed(U64::from(block.number), profit));
+    }
+    let head = U64::from(base.number);
+    if head >= bundle.last_target() {
+        store.resolve(bundle.id, false)?;
+        store.resync_nonce(flashbots, bundle.searcher).await?;
+        return Ok(FanOutStep::Expired);
+    }
+    let Some(target) = next_target(bundle, head) else {
+        return Ok(FanOutStep::Waiting);
+    };
+
+    let request = bundle.to_bundle_request_on(base, fork_head)?;
+    let simulated = flashbots
+        .simulate_bundle(&request)
+        .await
+        .map_err(|e| eyre::eyre!("Bundle simulation error: {}", e));
+    if let Err(e) = simulated.and_then(|sim| validate_simulation_response(&sim)) {
+        warn!(
+            "Not submitting bundle {:?} for block {}: {}",
+            bundle.id, target, e
+        );
+        return Ok(FanOutStep::SimulationFailed(target, e.to_string()));
+    }
+    let sent = match (mev_share, gate) {
+        (Some(client), Some(gate)) => relayer::send_placed_bundle(
+            client,
+            &request,
+            &bundle.ahead,
+            base,
+            fork_head,
+            gate,
+            bundle.expected_profit,
+        )
+        .await
+        .map(|placement| debug!("Bundle {:?} placed at the {:?}", bundle.id, placement)),
+        _ => {
+            relayer::send_bundle(
+                flashbots,
+                &request,
+                base,
+                fork_head,
+                gate,
+                bundle.expected_profit,
+            )
+            .await
+        }
+    };
+    match sent {
+        Ok(()) => Ok(FanOutStep::Submitted(target)),
+        Err(e) => match e.downcast_ref::<GateError>() {
+            Some(rejected) if rejected.is_rejection() => {
+                warn!(
+                    "Dropping bundle {:?}, rejected for block {}: {}",
+                    bundle.id, target, rejected
+                );
+                store.resolve(bundle.id, false)?;
+                store.resync_nonce(flashbots, bundle.searcher).await?;
+                Ok(FanOutStep::Rejected(target, rejected.to_string()))
+            }
+            Some(skipped) => Ok(FanOutStep::SimulationFailed(target, skipped.to_string())),
+            None => Err(e),
+        },
+    }
+}
+
+/// One request holding the txs of `bundles`, in order, built on `base`
+pub fn merge_request(
+    bundles: &[&PendingBundle],
+    base: BaseBlock,
+    fork_head: &ForkHead,
+) -> eyre::Result<BundleRequest> {
+    let txs: Vec<Bytes> = bundles
+        .iter()
+        .flat_map(|bundle| bundle.signed_txs.iter().cloned())
+        .collect();
+    relayer::construct_bundle(txs, base, fork_head)
+}
+
+/// Sends the bundles of `pending` due for the block after `base` as one bundle, when `gate`
+/// simulates them together for more than the sum of their expected profits, see [plan_merge].
+/// Bundles without an expected profit are left out. The ids of the bundles sent merged once the
+/// merged bundle was submitted, `None` if they're better off apart or the merged bundle couldn't
+/// be sent, see [unmerged].
+pub async fn merge_fan_outs<M, S>(
+    flashbots: &FlashbotsMiddleware<M, S>,
+    pending: &[PendingBundle],
+    base: BaseBlock,
+    fork_head: &ForkHead,
+    gate: &BundleGate,
+) -> Option<Vec<H256>>
+where
+    M: Middleware,
+    S: Signer,
+{
+    let head = U64::from(base.number);
+    let candidates: Vec<MergeCandidate<&PendingBundle>> = pending
+        .iter()
+        .filter(|bundle| next_target(bundle, head).is_some())
+        .filter_map(|bundle| {
+            let profit = bundle.expected_profit?;
+            Some(MergeCandidate::new(
+                bundle,
+                bundle.pools.iter().copied(),
+                profit,
+            ))
+        })
+        .collect();
+    if candidates.len() < 2 {
+        return None;
+    }
+    let plan = plan_merge(&candidates, |merged| {
+        let bundles: Vec<&PendingBundle> = merged.iter().map(|c| c.opportunity).collect();
+        let request = merge_request(&bundles, base, fork_head).ok()?;
+        gate.profit(&request, base).ok()
+    });
+    let MergePlan::Merged { order, profit } = plan else {
+        return None;
+    };
+    let bundles: Vec<&PendingBundle> = order.iter().map(|i| candidates[*i].opportunity).collect();
+    let ids: Vec<H256> = bundles.iter().map(|bundle| bundle.id).collect();
+    let sent = match merge_request(&bundles, base, fork_head) {
+        Ok(request) => {
+            relayer::send_bundle(
+                flashbots,
+                &request,
+                base,
+                fork_head,
+                Some(gate),
+                Some(profit),
+            )
+            .await
+        }
+        Err(e) => Err(e),
+    };
+    match sent {
+        Ok(()) => {
+            info!(
+                "Bundles {:?} merged for block {}, profit {}",
+                ids,
+                head + 1,
+                profit
+            );
+            Some(ids)
+        }
+        Err(e) => {
+            warn!(
+                "Failed to send bundles {:?} merged, sending apart: {}",
+                ids, e
+            );
+            None
+        }
+    }
+}
+
+/// Bundles of `pending` to advance on their own: those not in `merged`, the ids
+/// [merge_fan_outs] returns once their merged bundle was submitted, all of them without one
+pub fn unmerged<'a>(
+    pending: &'a [PendingBundle],
+    merged: Option<&'a [H256]>,
+) -> impl Iterator<Item = &'a PendingBundle> {
+    pending
+        .iter()
+        .filter(move |bundle| !matches!(merged, Some(ids) if ids.contains(&bundle.id)))
+}
+
+/// Advances every bundle of `store` on each block of `heads`, until shutdown. The bundles are only
+/// sent while the block is still `fork_head`, and if `gate` passes them. Reorgs are checked for
+/// first, so bundles whose block was removed are advanced again on the new chain. The bundles
+/// `builder` built are settled with it as they land or are dropped. With a `gate`, bundles due
+/// for the same block are merged first when that pays more, see [merge_fan_outs], and the others
+/// go through `mev_share` if given, see [advance_fan_out].
+#[allow(clippy::too_many_arguments)]
+pub async fn run_fan_outs<M, S>(
+    flashbots: &FlashbotsMiddleware<M, S>,
+    store: &BundleStore,
+    heads: impl Stream<Item = Block<H256>>,
+    fork_head: &ForkHead,
+    gate: Option<&BundleGate>,
+    mev_share: Option<&MevShareClient<S>>,
+    builder: Option<&BundleBuilder>,
+    shutdown: &ShutdownController,
+) where
+    M: Middleware,
+    S: Signer,
+{
+    let token = shutdown.token();
+    let mut reorgs = ReorgWatcher::new(DEFAULT_REORG_DEPTH);
+    pin_mut!(heads);
+    loop {
+        let head = tokio::select! {
+            head = heads.next() => head,
+            _ = token.cancelled() => None,
+        };
+        let Some(block) = head else {
+            return;
+        };
+        let Some(head) = block
+            .number
+            .zip(block.hash)
+            .map(|(number, hash)| BaseBlock::new(number.as_u64(), hash))
+        else {
+            continue;
+        };
+        match reorgs.on_head(flashbots, &block).await {
+            Ok(Some(event)) => match store.on_reorg(&event) {
+                Ok(reorged) if !reorged.is_empty() => info!(
+                    "Reorg of depth {} removed the block of bundles {:?}, pending again",
+                    event.depth(),
+                    reorged
+                ),
+                Ok(_) => {}
+                Err(e) => warn!("Failed to roll back bundles after reorg: {}", e),
+            },
+            Ok(None) => {}
+            Err(e) => warn!("Failed to check block {} for reorgs: {}", head.number, e),
+        }
+        if let Err(e) = store.on_block(head.number) {
+            warn!("Failed to confirm landed bundles: {}", e);
+        }
+        let Some(_submission) = shutdown.begin_submission() else {
+            return;
+        };
+        let pending = store.pending();
+        let merged = match gate {
+            Some(gate) => merge_fan_outs(flashbots, &pending, head, fork_head, gate).await,
+            None => None,
+        };
+        for bundle in unmerged(&pending, merged.as_deref()) {
+            let advanced =
+                advance_fan_out(flashbots, store, bundle, head, fork_head, gate, mev_share).await;
+            match advanced {
+                Ok(step) => {
+                    debug!(
+                        "Bundle {:?} at block {}: {:?}",
+                        bundle.id, head.number, step
+                    );
+                    if let (Some(builder), Some(outcome)) = (builder, step.outcome()) {
+                        builder.settle(bundle.id, outcome, head.number, block.timestamp.as_u64());
+                    }
+                }
+                Err(e) => warn!("Failed to advance bundle {:?}: {}", bundle.id, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Address, H256};
+    use ethers_flashbots::BundleTransaction;
+
+    #[test]
+    fn test_next_target() {
+        let bundle = PendingBundle::new(
+            vec![Bytes::from(vec![0x02, 0x01])],
+            U64::from(101),
+            Address::from_low_u64_be(1),
+            vec![],
+        )
+        .with_max_block(U64::from(104));
+        assert_eq!(bundle.last_target(), U64::from(104));
+
+        // blocks 101..=104, each simulated on the block before
+        assert_eq!(next_target(&bundle, U64::from(99)), None);
+        assert_eq!(next_target(&bundle, U64::from(100)), Some(U64::from(101)));
+        let base = BaseBlock::new(102, H256::from_low_u64_be(102));
+        let request = bundle
+            .to_bundle_request_on(base, &ForkHead::new(base))
+            .unwrap();
+        assert_eq!(request.block(), Some(U64::from(103)));
+        assert_eq!(request.simulation_block(), Some(U64::from(102)));
+        // the fork moved on
+        let next = BaseBlock::new(103, H256::from_low_u64_be(103));
+        assert!(bundle
+            .to_bundle_request_on(base, &ForkHead::new(next))
+            .is_err());
+        assert_eq!(next_target(&bundle, U64::from(103)), Some(U64::from(104)));
+        assert_eq!(next_target(&bundle, U64::from(104)), None);
+
+        // a single target bundle
+        let single = PendingBundle {
+            max_block: None,
+            ..bundle
+        };
+        assert_eq!(single.last_target(), U64::from(101));
+        assert_eq!(next_target(&single, U64::from(101)), None);
+    }
+
+    #[test]
+    fn test_merge_request() {
+        let bundle = |tx: u8| {
+            PendingBundle::new(
+                vec![Bytes::from(vec![0x02, tx])],
+                U64::from(101),
+                Address::from_low_u64_be(1),
+                vec![],
+            )
+        };
+        let (a, b) = (bundle(1), bundle(2));
+        let base = BaseBlock::new(100, H256::from_low_u64_be(100));
+        let request = merge_request(&[&b, &a], base, &ForkHead::new(base)).unwrap();
+        assert_eq!(request.block(), Some(U64::from(101)));
+        let txs: Vec<_> = request
+            .transactions()
+            .iter()
+            .map(|tx| match tx {
+                BundleTransaction::Raw(raw) => raw.clone(),
+                BundleTransaction::Signed(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(txs, vec![b.signed_txs[0].clone(), a.signed_txs[0].clone()]);
+    }
+
+    #[test]
+    fn test_unmerged() {
+        let bundle = |tx: u8| {
+            PendingBundle::new(
+                vec![Bytes::from(vec![0x02, tx])],
+                U64::from(101),
+                Address::from_low_u64_be(1),
+                vec![],
+            )
+        };
+        let pending = vec![bundle(1), bundle(2), bundle(3)];
+        let ids = |bundles: Vec<&PendingBundle>| -> Vec<H256> {
+            bundles.into_iter().map(|bundle| bundle.id).collect()
+        };
+
+        // the first two went out merged, the third on its own
+        let merged = vec![pending[0].id, pending[1].id];
+        assert_eq!(
+            ids(unmerged(&pending, Some(&merged)).collect()),
+            vec![pending[2].id]
+        );
+        // not merged or the merged bundle failed to send, all of them on their own
+        assert_eq!(
+            ids(unmerged(&pending, None).collect()),
+            ids(pending.iter().collect())
+        );
+    }
+
+    #[test]
+    fn test_step_outcome() {
+        // a bundle landing at a loss is reported as the loss, whatever it was expected to make
+        assert_eq!(
+            FanOutStep::Landed(U64::from(101), I256::from(-2)).outcome(),
+            Some(BundleOutcome::Landed(I256::from(-2)))
+        );
+        assert_eq!(
+            FanOutStep::Rejected(U64::from(101), "reverted".to_string()).outcome(),
+            Some(BundleOutcome::Failed)
+        );
+        assert_eq!(
+            FanOutStep::Expired.outcome(),
+            Some(BundleOutcome::NotIncluded)
+        );
+        assert_eq!(
+            FanOutStep::SimulationFailed(U64::from(101), "stale".to_string()).outcome(),
+            None
+        );
+    }
+}
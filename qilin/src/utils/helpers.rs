@@ -37,6 +37,13 @@ pub async fn connect_to_network(
     Ok((ws_provider, middleware_url, chain_id))
 }
 
+/// Websocket provider for the `WSS_RPC` endpoint
+pub async fn connect_from_env() -> Result<Arc<Provider<Ws>>> {
+    dotenv::dotenv().ok();
+    let url = std::env::var("WSS_RPC")?;
+    Ok(Arc::new(Provider::<Ws>::connect(url).await?))
+}
+
 pub async fn generate_abigen(arg: Vec<String>) -> Result<()> {
     let first_arg = if arg.len() > 1 {
         arg[1].clone()
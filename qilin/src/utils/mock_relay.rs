@@ -0,0 +1,703 @@
+//! Relay and builder stand-ins for end to end tests
+//!
+//! [MockRelay] answers `eth_sendBundle` and `eth_callBundle` like a Flashbots relay, checking the
+//! `X-Flashbots-Signature` header, but against a local anvil or fork instance instead of a builder
+//! network. Bundles sent are kept, [MockRelay::build_block] lands them the way a builder would,
+//! and [MockRelay::fail_next] makes the relay unavailable for a few requests to exercise retries.
+//! [MockRelay::set_faults] has it answer with the relay faults of a [FaultInjector] for as long as
+//! a robustness test runs. Only built with the `mock-relay` feature, which brings `faults` in.
+//!
+//! Bundles are sent to anvil's pool tx by tx, in order. Anvil has to run with `--order fifo` to
+//! mine them in that order rather than by fee.
+
+use std::net::{SocketAddr, TcpListener};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Json, Router,
+};
+use ethers::providers::{Http, Middleware, Provider};
+use ethers::types::{Address, BlockNumber, Bytes, Signature, TransactionReceipt, H256, U256, U64};
+use ethers::utils::keccak256;
+use fork_database::faults::FaultInjector;
+use log::{debug, warn};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+pub const SIGNATURE_HEADER: &str = "X-Flashbots-Signature";
+
+#[derive(Error, Debug)]
+pub enum MockRelayError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Server error: {0}")]
+    Server(String),
+    #[error("Provider error: {0}")]
+    Provider(String),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+}
+
+/// A bundle the relay accepted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedBundle {
+    pub hash: H256,
+    /// signer of the `X-Flashbots-Signature` header
+    pub searcher: Address,
+    pub txs: Vec<Bytes>,
+    pub block: U64,
+    /// txs allowed to revert without the bundle being dropped
+    pub reverting_tx_hashes: Vec<H256>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BundleParams {
+    txs: Vec<Bytes>,
+    block_number: U64,
+    #[serde(default)]
+    reverting_tx_hashes: Vec<H256>,
+    /// `eth_callBundle` only, the block whose state the bundle is executed on
+    #[serde(default)]
+    state_block_number: Option<BlockNumber>,
+}
+
+#[derive(Debug)]
+struct RelayState {
+    anvil: Provider<Http>,
+    bundles: Mutex<Vec<ReceivedBundle>>,
+    /// requests left to answer with a 503
+    failures: AtomicUsize,
+    /// relay faults drawn before answering each request
+    faults: Mutex<Option<FaultInjector>>,
+}
+
+#[derive(Debug)]
+pub struct MockRelay {
+    state: Arc<RelayState>,
+    addr: SocketAddr,
+    server: JoinHandle<()>,
+}
+
+impl MockRelay {
+    /// Serve on a free local port, executing on `anvil`
+    pub async fn spawn(anvil: Provider<Http>) -> Result<Self, MockRelayError> {
+        let state = Arc::new(RelayState {
+            anvil,
+            bundles: Mutex::new(vec![]),
+            failures: AtomicUsize::new(0),
+            faults: Mutex::new(None),
+        });
+        let app = Router::new()
+            .route("/", post(handle))
+            .with_state(state.clone());
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = axum::Server::from_tcp(listener)
+            .map_err(|e| MockRelayError::Server(e.to_string()))?
+            .serve(app.into_make_service());
+        let server = tokio::spawn(async move {
+            if let Err(e) = server.await {
+                warn!("Mock relay stopped: {}", e);
+            }
+        });
+        Ok(Self {
+            state,
+            addr,
+            server,
+        })
+    }
+
+    /// Url to configure as the relay, e.g. of a `FlashbotsMiddleware`
+    pub fn url(&self) -> url::Url {
+        url::Url::parse(&format!("http://{}", self.addr)).expect("valid socket address")
+    }
+
+    /// Answer the next `requests` with `503 Service Unavailable`
+    pub fn fail_next(&self, requests: usize) {
+        self.state.failures.store(requests, Ordering::SeqCst);
+    }
+
+    /// Delay and rate limit requests as `faults` draws, until set to `None`
+    pub fn set_faults(&self, faults: Option<FaultInjector>) {
+        *self.state.faults.lock() = faults;
+    }
+
+    /// Bundles received and not landed yet, in the order they came in
+    pub fn bundles(&self) -> Vec<ReceivedBundle> {
+        self.state.bundles.lock().clone()
+    }
+
+    /// Mine the next block on anvil with the bundles targeting it, in the order they came in.
+    /// Like a builder, a bundle is dropped whole when anvil rejects or doesn't mine one of its
+    /// txs, or one of them reverts without being listed in its `revertingTxHashes`. Returns the
+    /// hashes of the bundles that landed, bundles for past blocks are forgotten.
+    pub async fn build_block(&self) -> Result<Vec<H256>, MockRelayError> {
+        let anvil = &self.state.anvil;
+        let next = anvil.get_block_number().await.map_err(provider_error)? + 1;
+        let bundles: Vec<ReceivedBundle> = {
+            let mut bundles = self.state.bundles.lock();
+            bundles.retain(|bundle| bundle.block >= next);
+            let (targeting, later): (Vec<_>, Vec<_>) =
+                bundles.drain(..).partition(|bundle| bundle.block == next);
+            *bundles = later;
+            targeting
+        };
+
+        set_automine(anvil, false).await?;
+        let built = build_on(anvil, bundles).await;
+        set_automine(anvil, true).await?;
+        built
+    }
+
+    pub fn shutdown(self) {
+        self.server.abort();
+    }
+}
+
+/// Mine the bundles that land on top of each other, each one tried in a block after the ones
+/// before it and rolled back
+async fn build_on(
+    anvil: &Provider<Http>,
+    bundles: Vec<ReceivedBundle>,
+) -> Result<Vec<H256>, MockRelayError> {
+    let (mut included, mut landed): (Vec<Bytes>, Vec<H256>) = (vec![], vec![]);
+    for bundle in bundles {
+        let snapshot: U256 = request(anvil, "evm_snapshot", ()).await?;
+        let txs: Vec<Bytes> = included.iter().chain(&bundle.txs).cloned().collect();
+        let lands = match mine(anvil, &txs).await {
+            Ok(receipts) => lands(&bundle, &receipts[included.len()..]),
+            Err(e) => {
+                debug!("Bundle {:?} rejected: {}", bundle.hash, e);
+                false
+            }
+        };
+        let _: Value = request(anvil, "anvil_dropAllTransactions", ()).await?;
+        let _: bool = request(anvil, "evm_revert", [snapshot]).await?;
+        if lands {
+            included.extend(bundle.txs);
+            landed.push(bundle.hash);
+        } else {
+            debug!("Bundle {:?} dropped", bundle.hash);
+        }
+    }
+    mine(anvil, &included).await?;
+    Ok(landed)
+}
+
+/// Send `txs` and mine a block, the receipt of each tx if it was mined
+async fn mine(
+    anvil: &Provider<Http>,
+    txs: &[Bytes],
+) -> Result<Vec<Option<TransactionReceipt>>, MockRelayError> {
+    let hashes = send_raw(anvil, txs).await?;
+    let _: Value = request(anvil, "evm_mine", ()).await?;
+    let mut receipts = vec![];
+    for hash in hashes {
+        let receipt = anvil
+            .get_transaction_receipt(hash)
+            .await
+            .map_err(provider_error)?;
+        receipts.push(receipt);
+    }
+    Ok(receipts)
+}
+
+/// Whether all txs of `bundle` were mined, and didn't revert unless allowed to
+fn lands(bundle: &ReceivedBundle, receipts: &[Option<TransactionReceipt>]) -> bool {
+    receipts.iter().all(|receipt| match receipt {
+        Some(receipt) => {
+            receipt.status != Some(U64::zero())
+                || bundle
+                    .reverting_tx_hashes
+                    .contains(&receipt.transaction_hash)
+        }
+        None => false,
+    })
+}
+
+async fn handle(
+    State(state): State<Arc<RelayState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> (StatusCode, Json<Value>) {
+    let failing = state
+        .failures
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok();
+    if failing {
+        return rpc_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            &Value::Null,
+            -32000,
+            "relay unavailable",
+        );
+    }
+    let faults = state.faults.lock().clone();
+    if let Some(faults) = faults {
+        if let Err(e) = faults.relay().await {
+            return rpc_error(
+                StatusCode::TOO_MANY_REQUESTS,
+                &Value::Null,
+                -32000,
+                &e.to_string(),
+            );
+        }
+    }
+
+    let request: RpcRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            return rpc_error(
+                StatusCode::BAD_REQUEST,
+                &Value::Null,
+                -32700,
+                &e.to_string(),
+            )
+        }
+    };
+    let Some(searcher) = signer_of(&headers, &body) else {
+        return rpc_error(
+            StatusCode::FORBIDDEN,
+            &request.id,
+            -32600,
+            "missing or invalid X-Flashbots-Signature",
+        );
+    };
+
+    let result = match request.method.as_str() {
+        "eth_sendBundle" => send_bundle(&state, searcher, &request.params),
+        "eth_callBundle" => call_bundle(&state, &request.params).await,
+        method => {
+            let message = format!("method {} not supported", method);
+            return rpc_error(StatusCode::OK, &request.id, -32601, &message);
+        }
+    };
+    match result {
+        Ok(result) => (
+            StatusCode::OK,
+            Json(json!({"jsonrpc": "2.0", "id": request.id, "result": result})),
+        ),
+        Err(e) => rpc_error(StatusCode::OK, &request.id, -32000, &e.to_string()),
+    }
+}
+
+/// Address a request was signed by, the header holds `address:signature` of the hex encoded
+/// `keccak256` of the body
+fn signer_of(headers: &HeaderMap, body: &[u8]) -> Option<Address> {
+    let header = headers.get(SIGNATURE_HEADER)?.to_str().ok()?;
+    let (address, signature) = header.split_once(':')?;
+    let address = Address::from_str(address).ok()?;
+    let signature = Signature::from_str(signature).ok()?;
+    let message = format!("0x{:x}", H256::from(keccak256(body)));
+    signature.verify(message, address).ok()?;
+    Some(address)
+}
+
+fn send_bundle(
+    state: &RelayState,
+    searcher: Address,
+    params: &[Value],
+) -> Result<Value, MockRelayError> {
+    let params = bundle_params(params)?;
+    let hash = bundle_hash(&params.txs);
+    state.bundles.lock().push(ReceivedBundle {
+        hash,
+        searcher,
+        txs: params.txs,
+        block: params.block_number,
+        reverting_tx_hashes: params.reverting_tx_hashes,
+    });
+    Ok(json!({ "bundleHash": hash }))
+}
+
+/// Execute the bundle in a block of its own on anvil and roll it back. Only anvil's head can be
+/// the state block, the bundle is refused on any other rather than executed on the wrong state.
+async fn call_bundle(state: &RelayState, params: &[Value]) -> Result<Value, MockRelayError> {
+    let params = bundle_params(params)?;
+    let anvil = &state.anvil;
+    let head = anvil.get_block_number().await.map_err(provider_error)?;
+    let state_block = match params.state_block_number {
+        None | Some(BlockNumber::Latest) => head,
+        Some(BlockNumber::Number(number)) => number,
+        Some(other) => {
+            let message = format!("state block {} not supported", other);
+            return Err(MockRelayError::InvalidRequest(message));
+        }
+    };
+    if state_block != head {
+        let message = format!("state block {} isn't the head {}", state_block, head);
+        return Err(MockRelayError::InvalidRequest(message));
+    }
+    let snapshot: U256 = request(anvil, "evm_snapshot", ()).await?;
+    set_automine(anvil, false).await?;
+    let result = execute_bundle(anvil, &params.txs).await;
+    set_automine(anvil, true).await?;
+    let _: bool = request(anvil, "evm_revert", [snapshot]).await?;
+    let mut result = result?;
+    result["bundleHash"] = json!(bundle_hash(&params.txs));
+    Ok(result)
+}
+
+async fn execute_bundle(anvil: &Provider<Http>, txs: &[Bytes]) -> Result<Value, MockRelayError> {
+    let hashes = send_raw(anvil, txs).await?;
+    let _: Value = request(anvil, "evm_mine", ()).await?;
+    let block = anvil
+        .get_block(anvil.get_block_number().await.map_err(provider_error)?)
+        .await
+        .map_err(provider_error)?
+        .ok_or_else(|| MockRelayError::Provider("mined block not found".to_string()))?;
+    let base_fee = block.base_fee_per_gas.unwrap_or_default();
+
+    let mut results = vec![];
+    let (mut total_gas, mut gas_fees) = (U256::zero(), U256::zero());
+    for hash in hashes {
+        let receipt = anvil
+            .get_transaction_receipt(hash)
+            .await
+            .map_err(provider_error)?
+            .ok_or_else(|| MockRelayError::Provider(format!("no receipt for {:?}", hash)))?;
+        let gas_used = receipt.gas_used.unwrap_or_default();
+        let gas_price = receipt.effective_gas_price.unwrap_or_default();
+        let fees = gas_used * gas_price.saturating_sub(base_fee);
+        total_gas += gas_used;
+        gas_fees += fees;
+
+        let mut result = json!({
+            "txHash": hash,
+            "gasUsed": gas_used.as_u64(),
+            "gasPrice": gas_price.to_string(),
+            "gasFees": fees.to_string(),
+            "fromAddress": receipt.from,
+            "toAddress": receipt.to,
+            "coinbaseDiff": fees.to_string(),
+            "ethSentToCoinbase": "0",
+        });
+        if receipt.status == Some(U64::zero()) {
+            result["error"] = json!("execution reverted");
+            result["revert"] = json!("execution reverted");
+        } else {
+            result["value"] = json!("0x");
+        }
+        results.push(result);
+    }
+
+    let coinbase = block.author.unwrap_or_default();
+    let number = block.number.unwrap_or_default();
+    let after = anvil
+        .get_balance(coinbase, Some(number.into()))
+        .await
+        .map_err(provider_error)?;
+    let before = anvil
+        .get_balance(coinbase, Some((number - 1).into()))
+        .await
+        .map_err(provider_error)?;
+    let coinbase_diff = after.saturating_sub(before);
+    let bundle_gas_price = if total_gas.is_zero() {
+        U256::zero()
+    } else {
+        coinbase_diff / total_gas
+    };
+    Ok(json!({
+        "bundleGasPrice": bundle_gas_price.to_string(),
+        "coinbaseDiff": coinbase_diff.to_string(),
+        "ethSentToCoinbase": coinbase_diff.saturating_sub(gas_fees).to_string(),
+        "gasFees": gas_fees.to_string(),
+        "results": results,
+        "stateBlockNumber": (number - 1).as_u64(),
+        "totalGasUsed": total_gas.as_u64(),
+    }))
+}
+
+async fn send_raw(anvil: &Provider<Http>, txs: &[Bytes]) -> Result<Vec<H256>, MockRelayError> {
+    let mut hashes = vec![];
+    for tx in txs {
+        let hash: H256 = request(anvil, "eth_sendRawTransaction", [tx]).await?;
+        hashes.push(hash);
+    }
+    Ok(hashes)
+}
+
+async fn set_automine(anvil: &Provider<Http>, enabled: bool) -> Result<(), MockRelayError> {
+    let _: Value = request(anvil, "evm_setAutomine", [enabled]).await?;
+    Ok(())
+}
+
+async fn request<T, R>(anvil: &Provider<Http>, method: &str, params: T) -> Result<R, MockRelayError>
+where
+    T: std::fmt::Debug + serde::Serialize + Send + Sync,
+    R: serde::de::DeserializeOwned + Send,
+{
+    anvil.request(method, params).await.map_err(provider_error)
+}
+
+fn bundle_params(params: &[Value]) -> Result<BundleParams, MockRelayError> {
+    let params = params
+        .first()
+        .ok_or_else(|| MockRelayError::InvalidRequest("missing bundle".to_string()))?;
+    serde_json::from_value(params.clone())
+        .map_err(|e| MockRelayError::InvalidRequest(e.to_string()))
+}
+
+/// `keccak256` of the concatenated tx hashes, like the relays
+fn bundle_hash(txs: &[Bytes]) -> H256 {
+    let hashes: Vec<u8> = txs.iter().flat_map(|tx| keccak256(tx).to_vec()).collect();
+    H256::from(keccak256(hashes))
+}
+
+fn provider_error(e: impl std::fmt::Display) -> MockRelayError {
+    MockRelayError::Provider(e.to_string())
+}
+
+fn rpc_error(
+    status: StatusCode,
+    id: &Value,
+    code: i64,
+    message: &str,
+) -> (StatusCode, Json<Value>) {
+    (
+        status,
+        Json(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {"code": code, "message": message},
+        })),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::{LocalWallet, Signer};
+    use ethers::types::{transaction::eip2718::TypedTransaction, TransactionRequest};
+    use ethers::utils::{Anvil, AnvilInstance};
+    use ethers_flashbots::{BundleRequest, FlashbotsMiddleware};
+
+    /// Anvil mining in fifo order, with a contract reverting on any call at `reverter`
+    async fn fifo_anvil(reverter: Address) -> (AnvilInstance, Provider<Http>, LocalWallet) {
+        // note: spawn() will panic if spawn is called without anvil being available in the user’s $PATH
+        let anvil = Anvil::new().args(["--order", "fifo"]).spawn();
+        let provider = Provider::<Http>::try_from(anvil.endpoint()).unwrap();
+        let _: Value = provider
+            .request("anvil_setCode", (reverter, "0x60006000fd"))
+            .await
+            .unwrap();
+        let wallet = LocalWallet::from(anvil.keys()[0].clone()).with_chain_id(anvil.chain_id());
+        (anvil, provider, wallet)
+    }
+
+    async fn signed(wallet: &LocalWallet, to: Address, value: u64, nonce: u64) -> Bytes {
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(to)
+            .value(value)
+            .gas(100_000)
+            .gas_price(10_000_000_000u64)
+            .nonce(nonce)
+            .chain_id(wallet.chain_id())
+            .into();
+        let signature = wallet.sign_transaction(&tx).await.unwrap();
+        tx.rlp_signed(&signature)
+    }
+
+    #[tokio::test]
+    async fn test_bundles_land_in_order() {
+        let reverter = Address::from_low_u64_be(0xdead);
+        let (_anvil, provider, wallet) = fifo_anvil(reverter).await;
+        let relay = MockRelay::spawn(provider.clone()).await.unwrap();
+
+        let target = provider.get_block_number().await.unwrap() + 1;
+        let transfer = signed(&wallet, Address::from_low_u64_be(0xbeef), 1, 0).await;
+        let reverting = signed(&wallet, reverter, 1, 1).await;
+        let allowed = signed(&wallet, reverter, 2, 1).await;
+        let bundles = [
+            (transfer.clone(), vec![]),
+            (reverting, vec![]),
+            (allowed.clone(), vec![H256::from(keccak256(&allowed))]),
+        ];
+        for (tx, reverting_tx_hashes) in bundles {
+            let params = json!({
+                "txs": [tx],
+                "blockNumber": target,
+                "revertingTxHashes": reverting_tx_hashes,
+            });
+            send_bundle(&relay.state, wallet.address(), &[params]).unwrap();
+        }
+
+        // the reverting bundle is dropped, the one allowed to revert lands after the transfer
+        let landed = relay.build_block().await.unwrap();
+        assert_eq!(
+            landed,
+            vec![
+                bundle_hash(&[transfer.clone()]),
+                bundle_hash(&[allowed.clone()])
+            ]
+        );
+        let block = provider.get_block(target).await.unwrap().unwrap();
+        assert_eq!(
+            block.transactions,
+            vec![
+                H256::from(keccak256(&transfer)),
+                H256::from(keccak256(&allowed))
+            ]
+        );
+        assert!(relay.bundles().is_empty());
+        relay.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_call_bundle_on_state_block() {
+        let (_anvil, provider, wallet) = fifo_anvil(Address::from_low_u64_be(0xdead)).await;
+        let relay = MockRelay::spawn(provider.clone()).await.unwrap();
+
+        let head = provider.get_block_number().await.unwrap();
+        let transfer = signed(&wallet, Address::from_low_u64_be(0xbeef), 1, 0).await;
+        let params = |state_block: Value| {
+            json!({
+                "txs": [transfer],
+                "blockNumber": head + 1,
+                "stateBlockNumber": state_block,
+            })
+        };
+
+        let result = call_bundle(&relay.state, &[params(json!("latest"))])
+            .await
+            .unwrap();
+        assert_eq!(result["stateBlockNumber"], json!(head.as_u64()));
+        assert_eq!(result["results"].as_array().unwrap().len(), 1);
+        assert!(result["results"][0].get("error").is_none());
+        // rolled back
+        assert_eq!(provider.get_block_number().await.unwrap(), head);
+        let nonce = provider
+            .get_transaction_count(wallet.address(), None)
+            .await
+            .unwrap();
+        assert_eq!(nonce, U256::zero());
+
+        // the state block is no longer the head
+        let _: Value = provider.request("evm_mine", ()).await.unwrap();
+        assert!(call_bundle(&relay.state, &[params(json!(head))])
+            .await
+            .is_err());
+        relay.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_submission_retried_until_relay_recovers() {
+        // nothing listens there, eth_sendBundle doesn't touch anvil
+        let anvil = Provider::<Http>::try_from("http://127.0.0.1:1").unwrap();
+        let relay = MockRelay::spawn(anvil.clone()).await.unwrap();
+        let wallet: LocalWallet =
+            "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+                .parse()
+                .unwrap();
+        let flashbots = FlashbotsMiddleware::new(anvil, relay.url(), wallet);
+        let bundle = BundleRequest::new()
+            .push_transaction(Bytes::from(vec![0x02, 0x01]))
+            .set_block(U64::from(10));
+
+        relay.fail_next(2);
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            if flashbots.send_bundle(&bundle).await.is_ok() {
+                break;
+            }
+            assert!(attempts < 5, "relay never recovered");
+        }
+        assert_eq!(attempts, 3);
+        assert_eq!(relay.bundles().len(), 1);
+        relay.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_submissions_are_not_sent() {
+        use crate::config::RunConfig;
+        use crate::utils::relayer;
+        use fork_database::stale::{BaseBlock, ForkHead};
+
+        // nothing listens there, eth_sendBundle doesn't touch anvil
+        let anvil = Provider::<Http>::try_from("http://127.0.0.1:1").unwrap();
+        let relay = MockRelay::spawn(anvil.clone()).await.unwrap();
+        let wallet: LocalWallet =
+            "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+                .parse()
+                .unwrap();
+        let flashbots = FlashbotsMiddleware::new(anvil, relay.url(), wallet);
+        let bundle = BundleRequest::new()
+            .push_transaction(Bytes::from(vec![0x02, 0x01]))
+            .set_block(U64::from(10));
+        let base = BaseBlock::new(9, H256::from_low_u64_be(9));
+        let head = ForkHead::new(base);
+
+        let config: RunConfig = serde_json::from_value(json!({
+            "test": { "faults": { "seed": 1, "relay_rate_limit_rate": 1.0 } }
+        }))
+        .unwrap();
+        relay.set_faults(config.test.fault_injector());
+        for _ in 0..3 {
+            // the submission fails instead of being taken as sent
+            assert!(
+                relayer::send_bundle(&flashbots, &bundle, base, &head, None, None)
+                    .await
+                    .is_err()
+            );
+        }
+        assert!(relay.bundles().is_empty());
+
+        relay.set_faults(None);
+        relayer::send_bundle(&flashbots, &bundle, base, &head, None, None)
+            .await
+            .unwrap();
+        assert_eq!(relay.bundles().len(), 1);
+        relay.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_signed_bundles_are_received() {
+        // nothing listens there, eth_sendBundle doesn't touch anvil
+        let anvil = Provider::<Http>::try_from("http://127.0.0.1:1").unwrap();
+        let relay = MockRelay::spawn(anvil.clone()).await.unwrap();
+        let wallet: LocalWallet =
+            "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+                .parse()
+                .unwrap();
+        let flashbots = FlashbotsMiddleware::new(anvil, relay.url(), wallet.clone());
+
+        let tx = Bytes::from(vec![0x02, 0x01]);
+        let bundle = BundleRequest::new()
+            .push_transaction(tx.clone())
+            .set_block(U64::from(10));
+
+        // the first attempt hits an unavailable relay
+        relay.fail_next(1);
+        assert!(flashbots.send_bundle(&bundle).await.is_err());
+        assert!(relay.bundles().is_empty());
+
+        flashbots.send_bundle(&bundle).await.unwrap();
+        let received = relay.bundles();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].searcher, wallet.address());
+        assert_eq!(received[0].txs, vec![tx.clone()]);
+        assert_eq!(received[0].block, U64::from(10));
+        assert_eq!(received[0].hash, bundle_hash(&[tx]));
+        relay.shutdown();
+    }
+}
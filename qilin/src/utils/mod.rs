@@ -1,5 +1,14 @@
 pub mod base_fee_helper;
+pub mod bundle_builder;
+pub mod bundle_gate;
+pub mod bundle_store;
 pub mod constants;
+pub mod eip7702;
+pub mod fan_out;
 pub mod helpers;
+#[cfg(feature = "mock-relay")]
+pub mod mock_relay;
 pub mod relayer;
+#[cfg(feature = "rpc-cache")]
+pub mod rpc_cache;
 pub mod serialization;
@@ -1,12 +1,20 @@
+use crate::utils::bundle_gate::BundleGate;
 use crate::utils::relayer;
-use ethers::core::types::{Bytes, Eip1559TransactionRequest, NameOrAddress, U256, U64};
+use ethers::core::types::{Bytes, Eip1559TransactionRequest, NameOrAddress, H256, I256, U256, U64};
 use ethers::prelude::SignerMiddleware;
 use ethers::providers::{Middleware, Provider, Ws};
 use ethers::signers::{LocalWallet, Signer};
 use ethers::types::transaction::{eip2718::TypedTransaction, eip2930::AccessList};
-use ethers_flashbots::{BundleRequest, BundleTransaction, FlashbotsMiddleware, SimulatedBundle};
+use ethers_flashbots::{
+    BundleRequest, BundleTransaction, FlashbotsMiddleware, Relay, SimulatedBundle,
+};
+use fork_database::stale::{BaseBlock, ForkHead};
+use log::debug;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::sync::Arc;
+use strategies::placement::Placement;
+use url::Url;
 
 pub async fn simulate_bundle(
     _to: NameOrAddress,
@@ -56,10 +64,17 @@ pub async fn simulate_bundle(
     let signed_transactions = vec![signed_frontrun_tx];
     println!("signed_transactions: {:?}", signed_transactions);
 
-    let bundle = relayer::construct_bundle(signed_transactions, current_block).map_err(|e| {
-        println!("Bundle Construction Error{:?}", e);
-        e
-    })?;
+    let base = ws_provider
+        .get_block(current_block)
+        .await?
+        .and_then(|block| block.hash)
+        .map(|hash| BaseBlock::new(current_block.as_u64(), hash))
+        .ok_or("current block not found")?;
+    let bundle = relayer::construct_bundle(signed_transactions, base, &ForkHead::new(base))
+        .map_err(|e| {
+            println!("Bundle Construction Error{:?}", e);
+            e
+        })?;
 
     let simulated_bundle = flashbot_client.inner().simulate_bundle(&bundle).await?;
     println!("simulated_bundle: {:?}", simulated_bundle);
@@ -100,11 +115,18 @@ pub fn validate_simulation_response(sim: &SimulatedBundle) -> eyre::Result<()> {
     Ok(())
 }
 
-/// Construct a Bundle Request for FlashBots
+/// Construct a Bundle Request for FlashBots, for txs simulated on `base`. Refused once the fork
+/// moved past `base`, the bundle would trade on state that no longer exists. Bundles mixing
+/// set-code txs with others take a `Vec<BundleTransaction>`, a
+/// [SignedEip7702Transaction](super::eip7702::SignedEip7702Transaction) converts into one.
 pub fn construct_bundle<T: Into<BundleTransaction>>(
     signed_transactions: Vec<T>,
-    block_number: U64,
+    base: BaseBlock,
+    head: &ForkHead,
 ) -> eyre::Result<BundleRequest> {
+    head.check(base)?;
+    let block_number = U64::from(base.number);
+
     // Create the ethers-flashbots bundle request
     let mut bundle_request = BundleRequest::new();
 
@@ -123,3 +145,252 @@ pub fn construct_bundle<T: Into<BundleTransaction>>(
     // Return the constructed bundle request
     Ok(bundle_request)
 }
+
+/// Send `bundle`, built on `base`, unless the fork advanced while it was signed and built or
+/// `gate` rejects it, see [BundleGate::check]. The [GateError](super::bundle_gate::GateError) is
+/// returned as is, a caller can tell a bad bundle from a fork that moved. A relay fault the gate
+/// injects fails the submission like a relay error.
+pub async fn send_bundle<M, S>(
+    flashbots: &FlashbotsMiddleware<M, S>,
+    bundle: &BundleRequest,
+    base: BaseBlock,
+    head: &ForkHead,
+    gate: Option<&BundleGate>,
+    expected_profit: Option<I256>,
+) -> eyre::Result<()>
+where
+    M: Middleware,
+    S: Signer,
+{
+    head.check(base)?;
+    if let Some(gate) = gate {
+        gate.check(bundle, base, expected_profit)?;
+        #[cfg(feature = "faults")]
+        if let Some(faults) = gate.faults() {
+            faults.relay().await?;
+        }
+    }
+    flashbots
+        .send_bundle(bundle)
+        .await
+        .map_err(|e| eyre::eyre!("Bundle submission error: {}", e))?;
+    Ok(())
+}
+
+/// One element of a MEV-Share bundle body
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum MevShareItem {
+    #[serde(rename_all = "camelCase")]
+    Tx { tx: Bytes, can_revert: bool },
+    /// A tx shared through MEV-Share, e.g. the victim of a backrun, referenced by hash
+    Hash { hash: H256 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MevShareInclusion {
+    pub block: U64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_block: Option<U64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MevSharePrivacy {
+    /// what the matchmaker may share about the bundle, e.g. `"logs"` or `"calldata"`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hints: Vec<String>,
+    /// builders the bundle may be sent to, all of them if empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub builders: Vec<String>,
+}
+
+/// Params of `mev_sendBundle`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MevShareBundle {
+    pub version: String,
+    pub inclusion: MevShareInclusion,
+    pub body: Vec<MevShareItem>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub privacy: Option<MevSharePrivacy>,
+    /// Requested position in the block, for builders honouring placement preferences. Others
+    /// ignore the field, the bundle's profitability was simulated for this placement.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub placement: Option<Placement>,
+}
+
+impl MevShareBundle {
+    /// Put `victim`, a tx only known through MEV-Share, ahead of the bundle's own txs
+    pub fn with_victim(mut self, victim: H256) -> Self {
+        self.body.insert(0, MevShareItem::Hash { hash: victim });
+        self
+    }
+
+    pub fn with_privacy(mut self, privacy: MevSharePrivacy) -> Self {
+        self.privacy = Some(privacy);
+        self
+    }
+
+    pub fn with_max_block(mut self, max_block: U64) -> Self {
+        self.inclusion.max_block = Some(max_block);
+        self
+    }
+}
+
+/// Construct a MEV-Share bundle for the block after `block_number`, requesting `placement`
+pub fn construct_mev_share_bundle(
+    signed_transactions: Vec<Bytes>,
+    block_number: U64,
+    placement: Option<Placement>,
+) -> MevShareBundle {
+    MevShareBundle {
+        version: "v0.1".to_string(),
+        inclusion: MevShareInclusion {
+            block: block_number + 1,
+            max_block: None,
+        },
+        body: signed_transactions
+            .into_iter()
+            .map(|tx| MevShareItem::Tx {
+                tx,
+                can_revert: false,
+            })
+            .collect(),
+        privacy: None,
+        placement,
+    }
+}
+
+/// Sends [MevShareBundle]s to a MEV-Share matchmaker with `mev_sendBundle`, signed with the
+/// searcher's relay identity like Flashbots bundles
+pub struct MevShareClient<S> {
+    relay: Relay<S>,
+    top_premium: U256,
+    privacy: Option<MevSharePrivacy>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MevShareResponse {
+    bundle_hash: H256,
+}
+
+impl<S: Signer> MevShareClient<S> {
+    pub fn new(url: impl Into<Url>, signer: S) -> Self {
+        Self {
+            relay: Relay::new(url, Some(signer)),
+            top_premium: U256::zero(),
+            privacy: None,
+        }
+    }
+
+    /// Wei builders charge for the top of the block over its tail, see
+    /// [PlacementReport::preferred](strategies::placement::PlacementReport::preferred)
+    pub fn with_top_premium(mut self, top_premium: U256) -> Self {
+        self.top_premium = top_premium;
+        self
+    }
+
+    /// Privacy settings of every bundle sent
+    pub fn with_privacy(mut self, privacy: MevSharePrivacy) -> Self {
+        self.privacy = Some(privacy);
+        self
+    }
+
+    pub fn top_premium(&self) -> U256 {
+        self.top_premium
+    }
+
+    /// Send `bundle`, with the client's privacy settings unless it has its own, and return the
+    /// bundle hash the matchmaker assigned
+    pub async fn send_bundle(&self, bundle: &MevShareBundle) -> eyre::Result<H256> {
+        let bundle = match (&bundle.privacy, &self.privacy) {
+            (None, Some(privacy)) => bundle.clone().with_privacy(privacy.clone()),
+            _ => bundle.clone(),
+        };
+        let response: MevShareResponse = self
+            .relay
+            .request("mev_sendBundle", [bundle])
+            .await
+            .map_err(|e| eyre::eyre!("MEV-Share submission error: {}", e))?;
+        Ok(response.bundle_hash)
+    }
+}
+
+/// Send `bundle`, built on `base`, through MEV-Share in the placement `gate` simulates it most
+/// profitable in: at the top of the block or at its tail behind `ahead`, the txs expected to land
+/// first, see [BundleGate::placement]. Checked by the gate like [send_bundle] first.
+pub async fn send_placed_bundle<S: Signer>(
+    client: &MevShareClient<S>,
+    bundle: &BundleRequest,
+    ahead: &[Bytes],
+    base: BaseBlock,
+    head: &ForkHead,
+    gate: &BundleGate,
+    expected_profit: Option<I256>,
+) -> eyre::Result<Placement> {
+    head.check(base)?;
+    gate.check(bundle, base, expected_profit)?;
+    let (placement, profit) = gate.placement(bundle, base, ahead, client.top_premium())?;
+    #[cfg(feature = "faults")]
+    if let Some(faults) = gate.faults() {
+        faults.relay().await?;
+    }
+    let txs = bundle
+        .transactions()
+        .iter()
+        .map(|tx| match tx {
+            BundleTransaction::Signed(tx) => tx.rlp(),
+            BundleTransaction::Raw(raw) => raw.clone(),
+        })
+        .collect();
+    let mev_share = construct_mev_share_bundle(txs, U64::from(base.number), Some(placement));
+    let hash = client.send_bundle(&mev_share).await?;
+    debug!(
+        "MEV-Share bundle {:?} sent for block {} at the {:?}, profit {}",
+        hash, mev_share.inclusion.block, placement, profit
+    );
+    Ok(placement)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_mev_share_params() {
+        let victim = H256::from_low_u64_be(7);
+        let bundle = construct_mev_share_bundle(
+            vec![Bytes::from(vec![0x02, 0x01])],
+            U64::from(100),
+            Some(Placement::TailOfBlock),
+        )
+        .with_victim(victim)
+        .with_max_block(U64::from(103))
+        .with_privacy(MevSharePrivacy {
+            hints: vec!["logs".to_string()],
+            builders: vec![],
+        });
+        assert_eq!(
+            serde_json::to_value([&bundle]).unwrap(),
+            json!([{
+                "version": "v0.1",
+                "inclusion": {"block": "0x65", "maxBlock": "0x67"},
+                "body": [
+                    {"hash": format!("{:?}", victim)},
+                    {"tx": "0x0201", "canRevert": false},
+                ],
+                "privacy": {"hints": ["logs"]},
+                "placement": "tail",
+            }])
+        );
+
+        // nothing optional goes out unset
+        let bare = construct_mev_share_bundle(vec![], U64::from(100), None);
+        assert_eq!(
+            serde_json::to_value(&bare).unwrap(),
+            json!({"version": "v0.1", "inclusion": {"block": "0x65"}, "body": []})
+        );
+    }
+}
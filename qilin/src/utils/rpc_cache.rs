@@ -0,0 +1,292 @@
+use std::fmt::Debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+use ethers::providers::{Http, JsonRpcClient, JsonRpcError, Provider, ProviderError, RpcError};
+use ethers::utils::{hex, keccak256};
+use log::{debug, warn};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+/// Where a cacheable method takes the block its result is read at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockParam {
+    /// the result never changes
+    Constant,
+    /// a block number or hash at the index, the method reads `latest` without one
+    At(usize),
+    /// a block hash at the index
+    Hash(usize),
+    /// the `eth_getLogs` filter, pinned by its `blockHash` or both of its bounds
+    Filter,
+}
+
+/// Methods whose result only depends on their params once their block is pinned. Lookups by tx
+/// hash aren't in there, a tx may still be pending or get reorged into another block.
+const CACHEABLE_METHODS: &[(&str, BlockParam)] = &[
+    ("eth_chainId", BlockParam::Constant),
+    ("net_version", BlockParam::Constant),
+    ("eth_getBalance", BlockParam::At(1)),
+    ("eth_getCode", BlockParam::At(1)),
+    ("eth_getStorageAt", BlockParam::At(2)),
+    ("eth_getTransactionCount", BlockParam::At(1)),
+    ("eth_getProof", BlockParam::At(2)),
+    ("eth_call", BlockParam::At(1)),
+    ("eth_getBlockByNumber", BlockParam::At(0)),
+    ("eth_getBlockByHash", BlockParam::Hash(0)),
+    ("eth_getBlockReceipts", BlockParam::At(0)),
+    ("eth_getLogs", BlockParam::Filter),
+    ("trace_call", BlockParam::At(2)),
+    ("trace_callMany", BlockParam::At(1)),
+    ("trace_block", BlockParam::At(0)),
+    ("trace_replayBlockTransactions", BlockParam::At(0)),
+    ("debug_traceCall", BlockParam::At(1)),
+];
+
+#[derive(Error, Debug)]
+pub enum CachingClientError<C>
+where
+    C: JsonRpcClient,
+{
+    #[error(transparent)]
+    ClientError(C::Error),
+    #[error(transparent)]
+    SerdeJson(#[from] serde_json::Error),
+}
+
+impl<C> RpcError for CachingClientError<C>
+where
+    C: JsonRpcClient,
+{
+    fn as_error_response(&self) -> Option<&JsonRpcError> {
+        match self {
+            CachingClientError::ClientError(e) => e.as_error_response(),
+            CachingClientError::SerdeJson(_) => None,
+        }
+    }
+
+    fn as_serde_error(&self) -> Option<&serde_json::Error> {
+        match self {
+            CachingClientError::ClientError(e) => e.as_serde_error(),
+            CachingClientError::SerdeJson(e) => Some(e),
+        }
+    }
+}
+
+impl<C> From<CachingClientError<C>> for ProviderError
+where
+    C: JsonRpcClient + 'static,
+{
+    fn from(src: CachingClientError<C>) -> Self {
+        match src {
+            CachingClientError::ClientError(e) => e.into(),
+            CachingClientError::SerdeJson(e) => ProviderError::SerdeJson(e),
+        }
+    }
+}
+
+/// Development proxy between the bot and the remote RPC
+///
+/// Responses to requests pinned to a block number or hash are persisted under `cache_dir`, keyed
+/// by method and params, so repeated runs against the same blocks are served locally. Requests
+/// against a tag like `latest`, or without a block and so reading `latest`, always go to the
+/// remote, as do null and pending results.
+#[derive(Debug)]
+pub struct CachingClient<C> {
+    inner: C,
+    cache_dir: PathBuf,
+    memory: DashMap<String, Value>,
+}
+
+impl<C> CachingClient<C>
+where
+    C: JsonRpcClient,
+{
+    pub fn new(inner: C, cache_dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let cache_dir = cache_dir.into();
+        fs::create_dir_all(&cache_dir)?;
+        Ok(Self {
+            inner,
+            cache_dir,
+            memory: DashMap::new(),
+        })
+    }
+
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// Drop all cached responses, in memory and on disk
+    pub fn clear(&self) -> std::io::Result<()> {
+        self.memory.clear();
+        fs::remove_dir_all(&self.cache_dir)?;
+        fs::create_dir_all(&self.cache_dir)
+    }
+
+    fn path_for(&self, key: &str) -> PathBuf {
+        self.cache_dir
+            .join(format!("{}.json", hex::encode(keccak256(key.as_bytes()))))
+    }
+
+    fn load(&self, key: &str) -> Option<Value> {
+        if let Some(value) = self.memory.get(key) {
+            return Some(value.clone());
+        }
+        let data = fs::read(self.path_for(key)).ok()?;
+        let value: Value = serde_json::from_slice(&data).ok()?;
+        self.memory.insert(key.to_string(), value.clone());
+        Some(value)
+    }
+
+    fn store(&self, key: &str, value: &Value) {
+        self.memory.insert(key.to_string(), value.clone());
+        if let Err(e) = fs::write(self.path_for(key), value.to_string()) {
+            warn!(target: "cache", "Failed to persist rpc response: {:?}", e);
+        }
+    }
+}
+
+impl CachingClient<Http> {
+    /// [Provider] for `url` whose responses are cached under `cache_dir`
+    pub fn provider(
+        url: &str,
+        cache_dir: impl Into<PathBuf>,
+    ) -> eyre::Result<Provider<CachingClient<Http>>> {
+        let http: Http = url.parse()?;
+        Ok(Provider::new(CachingClient::new(http, cache_dir)?))
+    }
+}
+
+#[async_trait]
+impl<C> JsonRpcClient for CachingClient<C>
+where
+    C: JsonRpcClient + 'static,
+{
+    type Error = CachingClientError<C>;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, Self::Error>
+    where
+        T: Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let params = serde_json::to_value(params)?;
+        let key = cache_key(method, &params);
+
+        if let Some(value) = key.as_deref().and_then(|key| self.load(key)) {
+            debug!(target: "cache", "Serving {} from the rpc cache", method);
+            return Ok(serde_json::from_value(value)?);
+        }
+
+        let value: Value = self
+            .inner
+            .request(method, params)
+            .await
+            .map_err(CachingClientError::ClientError)?;
+
+        // a null result usually means "not yet", e.g. a block not mined yet
+        if let Some(key) = key.filter(|_| !value.is_null() && !is_pending(&value)) {
+            self.store(&key, &value);
+        }
+
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+/// Cache key of a request, `None` if its response may change over time
+pub fn cache_key(method: &str, params: &Value) -> Option<String> {
+    let (_, block) = CACHEABLE_METHODS.iter().find(|(m, _)| *m == method)?;
+    let pinned = match *block {
+        BlockParam::Constant => true,
+        BlockParam::At(index) => params.get(index).map_or(false, is_pinned),
+        BlockParam::Hash(index) => params.get(index).map_or(false, is_hash),
+        BlockParam::Filter => params.get(0).map_or(false, |filter| {
+            filter.get("blockHash").map_or(false, is_hash)
+                || ["fromBlock", "toBlock"]
+                    .iter()
+                    .all(|bound| filter.get(bound).map_or(false, is_pinned))
+        }),
+    };
+    pinned.then(|| format!("{}:{}", method, params))
+}
+
+/// A block number or hash, or an EIP-1898 object holding one, anything else being a tag
+fn is_pinned(block: &Value) -> bool {
+    match block {
+        Value::String(s) => s.starts_with("0x") && s.len() > 2,
+        Value::Object(map) => map
+            .get("blockHash")
+            .or_else(|| map.get("blockNumber"))
+            .map_or(false, is_pinned),
+        _ => false,
+    }
+}
+
+fn is_hash(value: &Value) -> bool {
+    value
+        .as_str()
+        .map_or(false, |s| s.starts_with("0x") && s.len() == 66)
+}
+
+/// Blocks or txs not mined yet come with a null number
+fn is_pending(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => ["number", "blockNumber", "blockHash"]
+            .iter()
+            .any(|key| map.get(*key).map_or(false, Value::is_null)),
+        Value::Array(values) => values.iter().any(is_pending),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_cache_key() {
+        let pinned = json!(["0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed", "0x10a1c45"]);
+        assert!(cache_key("eth_getBalance", &pinned).is_some());
+
+        let latest = json!(["0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed", "latest"]);
+        assert!(cache_key("eth_getBalance", &latest).is_none());
+
+        // no block means latest
+        let call = json!([{ "to": "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed" }]);
+        assert!(cache_key("eth_call", &call).is_none());
+        let hash = "0x88df016429689c079f3b2f6ad39fa052532c56795b733da78a91ebe6a713944b";
+        let at_hash = json!([call[0], { "blockHash": hash }]);
+        assert!(cache_key("eth_call", &at_hash).is_some());
+
+        // block tags nested in objects, e.g. `eth_getLogs` filters
+        let filter = json!([{ "fromBlock": "0x1", "toBlock": "latest" }]);
+        assert!(cache_key("eth_getLogs", &filter).is_none());
+        let open_ended = json!([{ "fromBlock": "0x1" }]);
+        assert!(cache_key("eth_getLogs", &open_ended).is_none());
+        let bounded = json!([{ "fromBlock": "0x1", "toBlock": "0x2" }]);
+        assert!(cache_key("eth_getLogs", &bounded).is_some());
+        assert!(cache_key("eth_getLogs", &json!([{ "blockHash": hash }])).is_some());
+
+        assert!(cache_key("eth_getBlockByHash", &json!([hash, false])).is_some());
+        assert!(cache_key("eth_getTransactionReceipt", &json!([hash])).is_none());
+        assert!(cache_key("eth_getTransactionByHash", &json!([hash])).is_none());
+        assert!(cache_key("eth_chainId", &json!([])).is_some());
+        assert!(cache_key("eth_blockNumber", &json!([])).is_none());
+        assert!(cache_key("eth_sendRawTransaction", &json!(["0x00"])).is_none());
+    }
+
+    #[test]
+    fn test_pending_results() {
+        assert!(is_pending(&json!({ "hash": "0x01", "blockNumber": null })));
+        assert!(is_pending(
+            &json!([{ "number": "0x1" }, { "number": null }])
+        ));
+        assert!(!is_pending(
+            &json!({ "number": "0x1", "blockHash": "0x02" })
+        ));
+        assert!(!is_pending(&json!("0x01")));
+    }
+}
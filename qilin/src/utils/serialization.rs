@@ -2,7 +2,10 @@ use dashmap::DashMap;
 use ethers::prelude::*;
 use ethers::providers::{Provider, Ws};
 use ethers::types::U256;
-use qilin_cfmms::pool::{Pool, PoolVariant};
+use qilin_cfmms::{
+    pool::{Pool, PoolVariant},
+    registry::PoolRegistry,
+};
 use serde::Serialize;
 use serde_json;
 use std::collections::BTreeMap;
@@ -32,14 +35,14 @@ impl std::fmt::Display for ReadError {
 
 impl Error for ReadError {}
 
-pub fn write_pool_data<T>(dash: &DashMap<Address, T>, hash_addr: bool) -> BTreeMap<Address, T>
+pub fn write_pool_data<T>(
+    entries: impl IntoIterator<Item = (Address, T)>,
+    hash_addr: bool,
+) -> BTreeMap<Address, T>
 where
     T: Clone + Debug + Serialize,
 {
-    let btree_map: BTreeMap<_, _> = dash
-        .iter()
-        .map(|entry| (*entry.key(), entry.value().clone()))
-        .collect();
+    let btree_map: BTreeMap<_, _> = entries.into_iter().collect();
 
     let json_data = serde_json::to_string(&btree_map).unwrap();
 
@@ -58,7 +61,7 @@ where
 
 pub async fn read_pool_data(
     provider: Arc<Provider<Ws>>,
-) -> Result<(DashMap<Address, Pool>, DashMap<Address, Vec<Pool>>), ReadError> {
+) -> Result<(PoolRegistry, DashMap<Address, Vec<Pool>>), ReadError> {
     let pool_json_data = match fs::read_to_string("./src/assets/all_pools.json") {
         Ok(data) => data,
         Err(_) => return Err(ReadError::FileNotFound),
@@ -73,19 +76,20 @@ pub async fn read_pool_data(
     let hash_pool_btree_map: BTreeMap<Address, Vec<Pool>> =
         serde_json::from_str(&hash_json_data).map_err(ReadError::JsonParsingError)?;
 
-    let pool_dash_map: DashMap<Address, Pool> = DashMap::new();
-    for (addr, _pool) in pool_btree_map {
+    let registry = PoolRegistry::new();
+    for (_, _pool) in pool_btree_map {
         let pool = pool_initializer(&_pool, provider.clone()).await.unwrap();
 
-        pool_dash_map.insert(addr, pool);
+        registry.insert(pool);
     }
+    registry.commit(None);
 
     let hash_pool_dash_map: DashMap<Address, Vec<Pool>> = DashMap::new();
     for (_hash, _pool) in hash_pool_btree_map {
         hash_pool_dash_map.insert(_hash, _pool);
     }
 
-    Ok((pool_dash_map, hash_pool_dash_map))
+    Ok((registry, hash_pool_dash_map))
 }
 
 pub async fn pool_initializer(_pool: &Pool, provider: Arc<Provider<Ws>>) -> Option<Pool> {
@@ -0,0 +1,159 @@
+//! State diff processing on the hot path of every new mempool tx
+//!
+//! `extract_arb_opportunities` is left out, it resolves balance slots through a live websocket
+//! provider.
+
+use std::{collections::BTreeMap, collections::BTreeSet, sync::Arc};
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use ethers::{
+    providers::{MockProvider, Provider},
+    types::{AccountDiff, Address, ChangedType, Diff, H256, U256},
+};
+use fork_database::{
+    backend_supervisor::{BackendRuntime, SupervisorConfig},
+    blockchain_db::{BlockchainDb, BlockchainDbMeta},
+    forked_db::ForkedDatabase,
+    shared_backend::SharedBackend,
+    storage_layout::{mapping_slot, slot_key, WETH_BALANCE_OF_SLOT},
+};
+use parking_lot::RwLock;
+use qilin_cfmms::{
+    pool::{Pool, PoolVariant},
+    registry::PoolView,
+};
+use strategies::sandwich::utils::{
+    constants::get_weth_address,
+    state_diff::{extract_pools, to_cache_db},
+};
+
+/// A 32 byte word, parses as a balance, nonce and code alike
+const WORD: &str = "0x0000000000000000000000000000000000000000000000000000000000000001";
+/// Storage slots changed per account, roughly what a router swap touches
+const SLOTS_PER_ACCOUNT: u64 = 4;
+/// Requests `to_cache_db` makes per account: nonce, balance and code
+const REQUESTS_PER_ACCOUNT: usize = 3;
+
+fn changed(from: u64, to: u64) -> Diff<H256> {
+    Diff::Changed(ChangedType {
+        from: H256::from_low_u64_be(from),
+        to: H256::from_low_u64_be(to),
+    })
+}
+
+fn account_diff(storage: BTreeMap<H256, Diff<H256>>) -> AccountDiff {
+    AccountDiff {
+        balance: Diff::Same,
+        nonce: Diff::Same,
+        code: Diff::Same,
+        storage,
+    }
+}
+
+/// State diff of a tx touching `accounts` contracts
+fn synthetic_diff(accounts: u64) -> BTreeMap<Address, AccountDiff> {
+    (0..accounts)
+        .map(|i| {
+            let storage = (0..SLOTS_PER_ACCOUNT)
+                .map(|slot| (H256::from_low_u64_be(slot), changed(i, i + 1)))
+                .collect();
+            (Address::from_low_u64_be(i + 1), account_diff(storage))
+        })
+        .collect()
+}
+
+/// State diff of a tx swapping through `count` V2 pools, along with the known pools
+fn synthetic_pool_diff(count: u64) -> (BTreeMap<Address, AccountDiff>, PoolView) {
+    let weth = get_weth_address();
+    let mut all_pools = vec![];
+    let mut diffs = BTreeMap::new();
+    let mut weth_storage = BTreeMap::new();
+
+    for i in 0..count {
+        let address = Address::from_low_u64_be(i + 1);
+        let token = Address::from_low_u64_be(u64::MAX - i);
+        all_pools.push(Pool::new_empty_pool(
+            address,
+            weth,
+            token,
+            U256::from(3000),
+            PoolVariant::UniswapV2,
+        ));
+
+        // weth `balanceOf` slot of the pool
+        let balance_slot = slot_key(mapping_slot(address, WETH_BALANCE_OF_SLOT));
+        weth_storage.insert(balance_slot, changed(100, 200));
+
+        let reserves = (0..2).map(|slot| (H256::from_low_u64_be(slot), changed(1, 2)));
+        diffs.insert(address, account_diff(reserves.collect()));
+    }
+    diffs.insert(weth, account_diff(weth_storage));
+
+    (diffs, all_pools.into_iter().collect())
+}
+
+fn mocked_forked_db() -> (
+    Arc<RwLock<ForkedDatabase>>,
+    Provider<MockProvider>,
+    MockProvider,
+) {
+    let (provider, mock) = Provider::mocked();
+    let meta = BlockchainDbMeta {
+        cfg_env: Default::default(),
+        block_env: Default::default(),
+        hosts: BTreeSet::new(),
+    };
+    let db = BlockchainDb::new(meta, None);
+    let config = SupervisorConfig::default().with_runtime(BackendRuntime::Dedicated);
+    let (backend, _handle) =
+        SharedBackend::spawn_supervised(provider.clone(), db.clone(), None, config);
+    let forked_db = Arc::new(RwLock::new(ForkedDatabase::new(backend, db)));
+    (forked_db, provider, mock)
+}
+
+fn bench_to_cache_db(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (forked_db, provider, mock) = mocked_forked_db();
+    let provider = Arc::new(provider);
+
+    let mut group = c.benchmark_group("to_cache_db");
+    for accounts in [10u64, 50, 200] {
+        let diff = synthetic_diff(accounts);
+        group.bench_with_input(BenchmarkId::from_parameter(accounts), &diff, |b, diff| {
+            b.iter_batched(
+                || {
+                    for _ in 0..diff.len() * REQUESTS_PER_ACCOUNT {
+                        mock.push::<String, _>(WORD.to_string()).unwrap();
+                    }
+                },
+                |_| {
+                    rt.block_on(to_cache_db(diff, None, &provider, &forked_db))
+                        .unwrap();
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_extract_pools(c: &mut Criterion) {
+    let mut group = c.benchmark_group("extract_pools");
+    for count in [1u64, 10, 100] {
+        let (diffs, all_pools) = synthetic_pool_diff(count);
+        let victim = ethers::types::Transaction::default();
+        group.bench_with_input(BenchmarkId::new("sandwich", count), &count, |b, _| {
+            b.iter(|| extract_pools(&diffs, &all_pools).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("collectors", count), &count, |b, _| {
+            b.iter(|| {
+                collectors::state_diff::extract_sandwich_opportunities(&victim, &diffs, &all_pools)
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_to_cache_db, bench_extract_pools);
+criterion_main!(benches);
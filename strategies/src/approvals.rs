@@ -0,0 +1,502 @@
+//! Token allowances of the executor
+//!
+//! A bundle pulling tokens through a router or pool reverts if the executor never approved that
+//! spender. The [ApprovalManager] reads allowances on the fork db, remembers spenders already
+//! approved so they aren't read again, and puts the missing approvals in front of a bundle, either
+//! as plain `approve` calls or through Permit2, which needs each token approved only once. The
+//! allowances belong to the executor contract, so the approvals are txs of the searcher calling
+//! the executor's [entry point](ExecutorEntryPoint), which makes the `approve` call itself. With a
+//! [permit signer](ApprovalManager::with_permit_signer) the Permit2 grants are signed permits
+//! instead, see [executor_permit].
+
+use std::{collections::HashMap, fmt::Debug};
+
+use ethers::{
+    abi::{self, Token},
+    signers::LocalWallet,
+    types::{Address, Bytes, H160, U256},
+    utils::id,
+};
+use fork_database::utils::{h160_to_b160, ru256_to_u256, RefDb};
+use revm::{
+    db::DatabaseRef,
+    primitives::{Env, ExecutionResult, Output, TransactTo, TxEnv, KECCAK_EMPTY},
+    EVM,
+};
+use thiserror::Error;
+
+use crate::permits::executor_permit;
+
+/// Permit2, at the same address on every chain it's deployed to
+pub const PERMIT2: Address = H160([
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x22, 0xd4, 0x73, 0x03, 0x0f, 0x11, 0x6d, 0xde, 0xe9, 0xf6, 0xb4,
+    0x3a, 0xc7, 0x8b, 0xa3,
+]);
+/// Entry point of the executor forwarding a call, `execute(target, data)`
+pub const EXECUTE: &str = "execute(address,bytes)";
+const APPROVAL_GAS: u64 = 100_000;
+const CALL_GAS: u64 = 100_000;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ApprovalError {
+    #[error("Simulation error: {0}")]
+    Simulation(String),
+    #[error("call to {0:?} reverted")]
+    Reverted(Address),
+    #[error("{0:?} has no code")]
+    NoCode(Address),
+    #[error("Signing error: {0}")]
+    Signing(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ApprovalMode {
+    /// `approve(spender, max)` on the token for every spender
+    #[default]
+    Direct,
+    /// the token approves Permit2 once, Permit2 grants each spender
+    Permit2 {
+        /// unix time the Permit2 grants expire at
+        expiration: u64,
+    },
+}
+
+/// `amount` of `token` a bundle lets `spender` pull from the executor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Spend {
+    pub token: Address,
+    pub spender: Address,
+    pub amount: U256,
+}
+
+/// Calldata of the executor making the call `data` to `target`
+pub type ExecutorEntryPoint = fn(Address, Bytes) -> Bytes;
+
+/// Encodes the call for the executor's [EXECUTE] entry point
+pub fn execute_call(target: Address, data: Bytes) -> Bytes {
+    [
+        id(EXECUTE).to_vec(),
+        abi::encode(&[Token::Address(target), Token::Bytes(data.to_vec())]),
+    ]
+    .concat()
+    .into()
+}
+
+#[derive(Debug, Clone)]
+pub struct ApprovalManager {
+    executor: Address,
+    /// EOA sending the approval txs to the executor
+    searcher: Address,
+    entry_point: ExecutorEntryPoint,
+    /// operator of the executor signing its Permit2 grants
+    permit_signer: Option<LocalWallet>,
+    mode: ApprovalMode,
+    /// known allowances by `(token, spender)`, Permit2 grants under `(token, spender)` as well
+    allowances: HashMap<(Address, Address), U256>,
+}
+
+impl ApprovalManager {
+    pub fn new(executor: Address, searcher: Address) -> Self {
+        Self {
+            executor,
+            searcher,
+            entry_point: execute_call,
+            permit_signer: None,
+            mode: ApprovalMode::default(),
+            allowances: HashMap::new(),
+        }
+    }
+
+    pub fn with_mode(mut self, mode: ApprovalMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Encode the forwarded calls for another executor than one exposing [EXECUTE]
+    pub fn with_entry_point(mut self, entry_point: ExecutorEntryPoint) -> Self {
+        self.entry_point = entry_point;
+        self
+    }
+
+    /// Grant spenders through permits `wallet` signs for the executor, in [ApprovalMode::Permit2]
+    pub fn with_permit_signer(mut self, wallet: LocalWallet) -> Self {
+        self.permit_signer = Some(wallet);
+        self
+    }
+
+    pub fn executor(&self) -> Address {
+        self.executor
+    }
+
+    /// Allowance of `spender` on `token`, from the cache or read on `db`
+    pub fn allowance<DB>(
+        &mut self,
+        db: &DB,
+        env: &Env,
+        token: Address,
+        spender: Address,
+    ) -> Result<U256, ApprovalError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        if let Some(allowance) = self.allowances.get(&(token, spender)) {
+            return Ok(*allowance);
+        }
+        let allowance = match self.mode {
+            ApprovalMode::Direct => self.read_allowance(db, env, token, spender)?,
+            ApprovalMode::Permit2 { .. } => self.read_permit2_allowance(db, env, token, spender)?,
+        };
+        // only full approvals are cached, partial ones get used up by our own bundles
+        if allowance == U256::MAX || self.is_max_grant(allowance) {
+            self.allowances.insert((token, spender), allowance);
+        }
+        Ok(allowance)
+    }
+
+    /// Approval txs for the `spends` that aren't covered yet, to go in front of the bundle
+    pub fn missing_approvals<DB>(
+        &mut self,
+        db: &DB,
+        env: &Env,
+        spends: &[Spend],
+    ) -> Result<Vec<TxEnv>, ApprovalError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        let mut txs = vec![];
+        let mut permit2_approved: Vec<Address> = vec![];
+        for spend in spends {
+            if self.allowance(db, env, spend.token, spend.spender)? >= spend.amount {
+                continue;
+            }
+            match self.mode {
+                ApprovalMode::Direct => {
+                    txs.push(self.approve_tx(spend.token, spend.spender));
+                }
+                ApprovalMode::Permit2 { expiration } => {
+                    if !permit2_approved.contains(&spend.token)
+                        && self.read_allowance(db, env, spend.token, PERMIT2)? < spend.amount
+                    {
+                        txs.push(self.approve_tx(spend.token, PERMIT2));
+                        permit2_approved.push(spend.token);
+                    }
+                    txs.push(self.permit2_grant_tx(db, env, spend, expiration)?);
+                }
+            }
+        }
+        Ok(txs)
+    }
+
+    /// Put the approvals `bundle` is missing in front of it, returns how many were added
+    pub fn insert_approvals<DB>(
+        &mut self,
+        db: &DB,
+        env: &Env,
+        spends: &[Spend],
+        bundle: &mut Vec<TxEnv>,
+    ) -> Result<usize, ApprovalError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        let approvals = self.missing_approvals(db, env, spends)?;
+        let added = approvals.len();
+        bundle.splice(0..0, approvals);
+        Ok(added)
+    }
+
+    /// A bundle granting `spender` landed, it doesn't need to be approved nor read again
+    pub fn approved(&mut self, token: Address, spender: Address) {
+        self.allowances.insert((token, spender), U256::MAX);
+    }
+
+    /// Forget what's known of `spender` on `token`, e.g. after a revoke
+    pub fn invalidate(&mut self, token: Address, spender: Address) {
+        self.allowances.remove(&(token, spender));
+    }
+
+    pub fn is_approved(&self, token: Address, spender: Address) -> bool {
+        self.allowances.contains_key(&(token, spender))
+    }
+
+    /// Permit2 grants are capped at uint160
+    fn is_max_grant(&self, allowance: U256) -> bool {
+        matches!(self.mode, ApprovalMode::Permit2 { .. }) && allowance == max_uint160()
+    }
+
+    fn read_allowance<DB>(
+        &self,
+        db: &DB,
+        env: &Env,
+        token: Address,
+        spender: Address,
+    ) -> Result<U256, ApprovalError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        let data = [
+            id("allowance(address,address)").to_vec(),
+            abi::encode(&[Token::Address(self.executor), Token::Address(spender)]),
+        ]
+        .concat();
+        let output = call(db, env, token, data)?.ok_or(ApprovalError::Reverted(token))?;
+        Ok(word(&output, 0))
+    }
+
+    /// Amount of the Permit2 grant, zero once expired
+    fn read_permit2_allowance<DB>(
+        &self,
+        db: &DB,
+        env: &Env,
+        token: Address,
+        spender: Address,
+    ) -> Result<U256, ApprovalError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        let data = [
+            id("allowance(address,address,address)").to_vec(),
+            abi::encode(&[
+                Token::Address(self.executor),
+                Token::Address(token),
+                Token::Address(spender),
+            ]),
+        ]
+        .concat();
+        let output = call(db, env, PERMIT2, data)?.ok_or(ApprovalError::Reverted(PERMIT2))?;
+        let expiration = word(&output, 1);
+        let now = ru256_to_u256(env.block.timestamp);
+        if expiration < now {
+            return Ok(U256::zero());
+        }
+        Ok(word(&output, 0))
+    }
+
+    fn approve_tx(&self, token: Address, spender: Address) -> TxEnv {
+        let data = [
+            id("approve(address,uint256)").to_vec(),
+            abi::encode(&[Token::Address(spender), Token::Uint(U256::MAX)]),
+        ]
+        .concat();
+        self.tx(token, data)
+    }
+
+    /// A permit submitted by the searcher if the executor signs through it, otherwise the
+    /// executor's own approval on Permit2
+    fn permit2_grant_tx<DB>(
+        &self,
+        db: &DB,
+        env: &Env,
+        spend: &Spend,
+        expiration: u64,
+    ) -> Result<TxEnv, ApprovalError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        let Some(wallet) = &self.permit_signer else {
+            return Ok(self.permit2_approve_tx(spend.token, spend.spender, expiration));
+        };
+        let permit = executor_permit(
+            db,
+            env,
+            wallet,
+            self.executor,
+            spend.token,
+            spend.spender,
+            max_uint160(),
+            expiration,
+        )?;
+        Ok(permit.tx(self.searcher))
+    }
+
+    fn permit2_approve_tx(&self, token: Address, spender: Address, expiration: u64) -> TxEnv {
+        let data = [
+            id("approve(address,address,uint160,uint48)").to_vec(),
+            abi::encode(&[
+                Token::Address(token),
+                Token::Address(spender),
+                Token::Uint(max_uint160()),
+                Token::Uint(U256::from(expiration)),
+            ]),
+        ]
+        .concat();
+        self.tx(PERMIT2, data)
+    }
+
+    /// The searcher having the executor call `to`, the nonce is left to whoever signs the bundle
+    fn tx(&self, to: Address, data: Vec<u8>) -> TxEnv {
+        TxEnv {
+            caller: h160_to_b160(self.searcher),
+            transact_to: TransactTo::Call(h160_to_b160(self.executor)),
+            data: (self.entry_point)(to, data.into()).0,
+            gas_limit: APPROVAL_GAS,
+            ..Default::default()
+        }
+    }
+}
+
+fn max_uint160() -> U256 {
+    (U256::one() << 160) - 1
+}
+
+/// Whether `address` has code on `db`
+pub(crate) fn has_code<DB>(db: &DB, address: Address) -> Result<bool, ApprovalError>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let info = db
+        .basic(h160_to_b160(address))
+        .map_err(|e| ApprovalError::Simulation(format!("{:?}", e)))?;
+    Ok(info.map_or(false, |info| info.code_hash != KECCAK_EMPTY))
+}
+
+/// Read only call on `db`, `None` if it reverted. Calling an account without code would
+/// "succeed" with an empty output, that's an error instead.
+pub(crate) fn call<DB>(
+    db: &DB,
+    env: &Env,
+    to: Address,
+    data: Vec<u8>,
+) -> Result<Option<Bytes>, ApprovalError>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    if !has_code(db, to)? {
+        return Err(ApprovalError::NoCode(to));
+    }
+    let mut evm = EVM::new();
+    evm.env = env.clone();
+    evm.env.block.basefee = Default::default();
+    evm.env.tx = TxEnv {
+        transact_to: TransactTo::Call(h160_to_b160(to)),
+        data: data.into(),
+        gas_limit: CALL_GAS,
+        ..Default::default()
+    };
+    evm.database(RefDb(db));
+    let result = evm
+        .transact_ref()
+        .map_err(|e| ApprovalError::Simulation(format!("{:?}", e)))?;
+    Ok(match result.result {
+        ExecutionResult::Success {
+            output: Output::Call(output),
+            ..
+        } => Some(output.into()),
+        _ => None,
+    })
+}
+
+/// `index`th 32 byte word of an abi encoded output, zero if missing
+pub(crate) fn word(output: &Bytes, index: usize) -> U256 {
+    output
+        .get(index * 32..(index + 1) * 32)
+        .map(U256::from_big_endian)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::{
+        db::{CacheDB, EmptyDB},
+        primitives::{AccountInfo, Bytecode},
+    };
+
+    /// Answers every call with a zero word, i.e. no allowance
+    fn zero_contract() -> AccountInfo {
+        AccountInfo {
+            // PUSH1 0x20 PUSH1 0x00 RETURN
+            code: Some(Bytecode::new_raw(vec![0x60, 0x20, 0x60, 0x00, 0xf3].into())),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_missing_approvals_are_inserted() {
+        let (executor, searcher, token, router) = (
+            Address::from_low_u64_be(1),
+            Address::from_low_u64_be(5),
+            Address::from_low_u64_be(2),
+            Address::from_low_u64_be(3),
+        );
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(h160_to_b160(token), zero_contract());
+        db.insert_account_info(h160_to_b160(PERMIT2), zero_contract());
+        let env = Env::default();
+        let spend = Spend {
+            token,
+            spender: router,
+            amount: U256::from(1_000),
+        };
+
+        let mut manager = ApprovalManager::new(executor, searcher);
+        let mut bundle = vec![TxEnv::default()];
+        assert_eq!(
+            manager
+                .insert_approvals(&db, &env, &[spend], &mut bundle)
+                .unwrap(),
+            1
+        );
+        assert_eq!(bundle.len(), 2);
+        // the searcher has the executor approve the router
+        assert_eq!(bundle[0].caller, h160_to_b160(searcher));
+        assert_eq!(
+            bundle[0].transact_to,
+            TransactTo::Call(h160_to_b160(executor))
+        );
+        let approve = [
+            id("approve(address,uint256)").to_vec(),
+            abi::encode(&[Token::Address(router), Token::Uint(U256::MAX)]),
+        ]
+        .concat();
+        assert_eq!(
+            Bytes::from(bundle[0].data.to_vec()),
+            execute_call(token, approve.into())
+        );
+
+        // once landed the spender is cached and not approved again
+        manager.approved(token, router);
+        assert!(manager
+            .missing_approvals(&db, &env, &[spend])
+            .unwrap()
+            .is_empty());
+
+        // through Permit2 the token approves Permit2 too, once per token
+        let mut permit2 = ApprovalManager::new(executor, searcher)
+            .with_mode(ApprovalMode::Permit2 { expiration: 1 });
+        let other = Spend {
+            spender: Address::from_low_u64_be(4),
+            ..spend
+        };
+        let txs = permit2
+            .missing_approvals(&db, &env, &[spend, other])
+            .unwrap();
+        let targets: Vec<_> = txs.iter().map(|tx| tx.data[16..36].to_vec()).collect();
+        assert_eq!(
+            targets,
+            vec![
+                token.as_bytes().to_vec(),
+                PERMIT2.as_bytes().to_vec(),
+                PERMIT2.as_bytes().to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_allowance_of_missing_token() {
+        let db = CacheDB::new(EmptyDB::default());
+        let token = Address::from_low_u64_be(2);
+        let mut manager = ApprovalManager::new(Address::from_low_u64_be(1), Address::zero());
+        assert_eq!(
+            manager.allowance(&db, &Env::default(), token, Address::from_low_u64_be(3)),
+            Err(ApprovalError::NoCode(token))
+        );
+    }
+}
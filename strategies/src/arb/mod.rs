@@ -4,11 +4,16 @@ pub mod v3;
 use argmin::core::observers::{ObserverMode, SlogLogger};
 use argmin::core::{CostFunction, Error, Executor};
 use argmin::solver::brent::BrentOpt;
-use ethers::types::{I256, U256};
+use collectors::pair_discovery::Factory;
+use ethers::types::{Address, I256, U256};
 use qilin_cfmms::batch_requests::uniswap_v3::UniswapV3TickData;
-use qilin_cfmms::pool::{Pool, PoolType};
+use qilin_cfmms::pool::{Pool, PoolType, PoolVariant};
+use qilin_cfmms::registry::PoolRegistry;
 use std::sync::Arc;
 
+use crate::fee_tiers::FeeTiers;
+use crate::split_route::quote_leg;
+
 #[derive(Debug)]
 struct ArbPool {
     token0_decimals: u8,
@@ -210,8 +215,8 @@ impl ArbPool {
         let mut _solver = BrentOpt::new(0.0, 0.0);
 
         let init_param: f64;
-        
-        // since reserve from univ3 pool is virtual reserve, which bounded the 
+
+        // since reserve from univ3 pool is virtual reserve, which bounded the
         // range significantly. Here, we'll use repay pool's reserve to bound
         // the searching range if the borrowing pool is univ3
         match borrow_0_buy_1 {
@@ -227,19 +232,16 @@ impl ArbPool {
                     }
                 };
             }
-            false => {
-                match borrowing_pool.pool_type {
-                    PoolType::UniswapV3(_) => {
-                        init_param = cost.repay_pool_reserve_1 * 0.025;
-                        _solver = BrentOpt::new(1 as f64, cost.repay_pool_reserve_1);
-                    }
-                    PoolType::UniswapV2(_) => {
-                        init_param = cost.borrowing_pool_reserve_1 * 0.025;
-                        _solver = BrentOpt::new(1 as f64, cost.borrowing_pool_reserve_1);
-                    }
-
+            false => match borrowing_pool.pool_type {
+                PoolType::UniswapV3(_) => {
+                    init_param = cost.repay_pool_reserve_1 * 0.025;
+                    _solver = BrentOpt::new(1 as f64, cost.repay_pool_reserve_1);
                 }
-            }
+                PoolType::UniswapV2(_) => {
+                    init_param = cost.borrowing_pool_reserve_1 * 0.025;
+                    _solver = BrentOpt::new(1 as f64, cost.borrowing_pool_reserve_1);
+                }
+            },
         }
 
         let executor = Executor::new(cost, _solver);
@@ -254,6 +256,51 @@ impl ArbPool {
     }
 }
 
+/// Borrowing and repay pools of an arb selling `token_in` for `token_out`
+///
+/// Every pool of the pair that `factories` deployed and `registry` tracks is a candidate, V3 ones
+/// on all of their fee tiers, see [FeeTiers], rather than whichever tier was found first. Each is
+/// quoted for `probe_in`: the one paying the most is borrowed from, the one paying the least
+/// repaid. `None` with fewer than two candidates.
+pub fn select_arb_pools(
+    registry: &PoolRegistry,
+    factories: &[Factory],
+    token_in: Address,
+    token_out: Address,
+    probe_in: U256,
+) -> Option<(Pool, Pool)> {
+    let (token_0, token_1) = if token_in < token_out {
+        (token_in, token_out)
+    } else {
+        (token_out, token_in)
+    };
+    let mut quotes = vec![];
+    for factory in factories {
+        match factory.variant {
+            PoolVariant::UniswapV2 => {
+                let address = factory.pool_address(token_0, token_1, U256::zero());
+                if let Some(pool) = registry.get(&address) {
+                    quotes.extend(quote_leg(&pool, &[], token_in, probe_in));
+                }
+            }
+            PoolVariant::UniswapV3 => quotes.extend(
+                FeeTiers::from_registry(registry, factory, token_in, token_out)
+                    .quotes(token_in, probe_in),
+            ),
+        }
+    }
+    if quotes.len() < 2 {
+        return None;
+    }
+    let borrow = quotes.iter().max_by_key(|quote| quote.amount_out)?.pool;
+    let repay = quotes
+        .iter()
+        .filter(|quote| quote.pool.address != borrow.address)
+        .min_by_key(|quote| quote.amount_out)?
+        .pool;
+    Some((borrow, repay))
+}
+
 impl CostFunction for ArbPool {
     type Param = f64;
     type Output = f64;
@@ -474,7 +521,8 @@ mod test {
         uniswap_v3_router_1::uni_v3_swap_router_1_contract, usdt::usdt_contract,
         weth::weth_contract,
     };
-    use qilin_cfmms::pool::{Pool, PoolType, PoolVariant};
+    use qilin_cfmms::pool::{Pool, PoolVariant};
+    use qilin_cfmms::test_utils::{v2_pool, v3_pool};
 
     abigen! {
         V2_POOL,
@@ -515,6 +563,45 @@ mod test {
 
         Ok((provider, anvil))
     }
+    #[test]
+    fn test_select_arb_pools() {
+        let factories = Factory::mainnet();
+        let (weth, usdc) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let tier = |fee: u32, liquidity: u128| {
+            let address = factories[1].pool_address(weth, usdc, U256::from(fee));
+            v3_pool(address, weth, usdc, fee, liquidity)
+        };
+        // the pair trades above the V3 pools
+        let pair = v2_pool(
+            factories[0].pool_address(weth, usdc, U256::zero()),
+            weth,
+            usdc,
+            (U256::exp10(21), U256::exp10(21) * 2),
+        );
+
+        let registry = PoolRegistry::new();
+        registry.insert(pair);
+        // only a shallow tier, the arb doesn't have a second pool
+        let shallow = tier(10_000, 10u128.pow(20));
+        registry.insert(shallow);
+        let probe = U256::exp10(17);
+        let (borrow, repay) = select_arb_pools(&registry, &factories, weth, usdc, probe).unwrap();
+        assert_eq!(borrow.address, pair.address);
+        assert_eq!(repay.address, shallow.address);
+
+        // a cheaper tier is found as well, the most expensive one is repaid
+        registry.insert(tier(500, 10u128.pow(22)));
+        let (_, repay) = select_arb_pools(&registry, &factories, weth, usdc, probe).unwrap();
+        assert_eq!(repay.address, shallow.address);
+        registry.remove(&shallow.address);
+        let (borrow, repay) = select_arb_pools(&registry, &factories, weth, usdc, probe).unwrap();
+        assert_eq!(borrow.address, pair.address);
+        assert_eq!(repay.swap_fee, U256::from(500));
+
+        registry.remove(&pair.address);
+        assert!(select_arb_pools(&registry, &factories, weth, usdc, probe).is_none());
+    }
+
     #[tokio::test]
     async fn test_calc_optimal_arb() -> Result<()> {
         let (anvil_provider, _anvil) = setup().await.unwrap();
@@ -598,53 +685,48 @@ mod test {
         .await
         .unwrap();
 
-
-        let uni_v3_pool = match v3_pool.pool_type {
-            PoolType::UniswapV3(pool) => pool,
-            _ => panic!("Wrong pool type"),
-        };
-
         let temp_v2_pool = V2_POOL::new(
             "0x0d4a11d5EEaaC28EC3F61d100daF4d40471f1852".parse::<H160>()?,
             client.clone(),
         );
         let v2_reserve = temp_v2_pool.get_reserves().call().await?;
 
-        let uni_v2_pool = match v2_pool.pool_type {
-            PoolType::UniswapV2(pool) => pool,
-            _ => panic!("Wrong pool type"),
-        };
-
-        let v3_amount_out = uni_v3_pool
-            .simulate_swap(
-                "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
-                    .parse::<H160>()
-                    .unwrap(),
-                U256::from(parse_units("5.0", "ether").unwrap()),
+        // every tier of the pair competes with the V2 pair
+        let registry = PoolRegistry::new();
+        registry.insert(v2_pool);
+        registry.insert(v3_pool);
+        for fee in [100u32, 3_000, 10_000] {
+            let address = Factory::mainnet()[1].pool_address(
+                v3_pool.token_0,
+                v3_pool.token_1,
+                U256::from(fee),
+            );
+            if let Some(tier) = Pool::new(
                 client.clone(),
+                address,
+                v3_pool.token_0,
+                v3_pool.token_1,
+                U256::from(fee),
+                PoolVariant::UniswapV3,
             )
             .await
-            .unwrap();
-
-        let v2_amount_out = uni_v2_pool
-            .simulate_swap(
-                "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
-                    .parse::<H160>()
-                    .unwrap(),
-                U256::from(parse_units("5.0", "ether").unwrap()),
-            );
-
-            let  borrow_pool: Pool;
-            let  repay_pool: Pool;
-        if v3_amount_out > v2_amount_out {
-            borrow_pool = v3_pool;
-            repay_pool = v2_pool;
-            log::info!("Borrowing from V3");
-        } else {
-            borrow_pool = v2_pool;
-            repay_pool = v3_pool;
-            log::info!("Borrowing from V2");
+            {
+                registry.insert(tier);
+            }
         }
+        let (borrow_pool, repay_pool) = select_arb_pools(
+            &registry,
+            &Factory::mainnet(),
+            v3_pool.token_0,
+            v3_pool.token_1,
+            U256::from(parse_units("5.0", "ether").unwrap()),
+        )
+        .unwrap();
+        log::info!(
+            "Borrowing from {:?}, repaying {:?}",
+            borrow_pool.address,
+            repay_pool.address
+        );
 
         let (amt, _) =
             ArbPool::calc_optimal_arb(client.clone(), &borrow_pool, &repay_pool, true).await;
@@ -0,0 +1,906 @@
+use std::fmt::Debug;
+
+use collectors::wrapped_tokens::{value_deltas, TokenValuation};
+use ethers::{
+    providers::Middleware,
+    types::{Address, BlockNumber, Transaction, H256, I256, U256},
+};
+use fork_database::{
+    delegation::{apply_authorizations, DelegationDb, SignedAuthorization, PER_EMPTY_ACCOUNT_COST},
+    inspectors::{Asset, BalanceDeltaInspector},
+    remote_sim::{Divergence, RemoteSimulator, StateOverrides},
+    sim_cache::{bundle_hash, state_hash, BundleKey, SimCache, StateId},
+    sim_env::SimOutcome,
+    utils::{h160_to_b160, ru256_to_u256, tx_to_tx_env, RefDb},
+};
+use hashbrown::{HashMap, HashSet};
+use log::{debug, warn};
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{keccak256, Env, B160},
+    EVM,
+};
+use thiserror::Error;
+
+const BPS: i64 = 10_000;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BundleCheckError {
+    #[error("Coinbase payment {paid} below the minimum {min}")]
+    CoinbasePaymentTooLow { paid: I256, min: U256 },
+    #[error("Net profit {profit} below the minimum {min}")]
+    ProfitTooLow { profit: I256, min: I256 },
+    #[error("Unexpected outflow of {delta} {asset:?} from the executor")]
+    UnexpectedOutflow { asset: Asset, delta: I256 },
+}
+
+/// Minimums a simulated bundle has to meet before it's submitted
+#[derive(Debug, Clone)]
+pub struct BundleCheck {
+    /// profit after gas and bribe, in wei
+    pub min_net_profit: I256,
+    /// what the block builder has to receive, in wei
+    pub min_coinbase_payment: U256,
+    /// assets the profit is counted in, 1:1 with ETH, the executor may spend those
+    pub profit_assets: Vec<Asset>,
+}
+
+impl BundleCheck {
+    pub fn new(weth: B160) -> Self {
+        Self {
+            min_net_profit: I256::zero(),
+            min_coinbase_payment: U256::zero(),
+            profit_assets: vec![Asset::Eth, Asset::Token(weth)],
+        }
+    }
+
+    pub fn with_min_net_profit(mut self, min_net_profit: I256) -> Self {
+        self.min_net_profit = min_net_profit;
+        self
+    }
+
+    pub fn with_min_coinbase_payment(mut self, min_coinbase_payment: U256) -> Self {
+        self.min_coinbase_payment = min_coinbase_payment;
+        self
+    }
+
+    /// Checks the balance changes of a simulated bundle
+    ///
+    /// Returns:
+    /// Ok(I256): net profit of the bundle
+    /// Err(BundleCheckError): the first minimum the bundle doesn't meet
+    pub fn check(&self, deltas: &BundleDeltas) -> Result<I256, BundleCheckError> {
+        if deltas.coinbase < I256::from_raw(self.min_coinbase_payment) {
+            return Err(BundleCheckError::CoinbasePaymentTooLow {
+                paid: deltas.coinbase,
+                min: self.min_coinbase_payment,
+            });
+        }
+
+        if let Some((asset, delta)) = deltas
+            .executor
+            .iter()
+            .find(|(asset, delta)| !self.profit_assets.contains(asset) && delta.is_negative())
+        {
+            return Err(BundleCheckError::UnexpectedOutflow {
+                asset: *asset,
+                delta: *delta,
+            });
+        }
+
+        let profit = deltas.net_profit(&self.profit_assets);
+        if profit < self.min_net_profit {
+            return Err(BundleCheckError::ProfitTooLow {
+                profit,
+                min: self.min_net_profit,
+            });
+        }
+
+        Ok(profit)
+    }
+}
+
+/// Balance changes over a whole simulated bundle
+#[derive(Debug, Clone, Default)]
+pub struct BundleDeltas {
+    /// coinbase balance change, includes the fees of every tx in the bundle
+    pub coinbase: I256,
+    /// searcher EOA's ETH balance change, gas and direct bribes included
+    pub searcher_eth: I256,
+    /// executor contract's balance changes
+    pub executor: HashMap<Asset, I256>,
+}
+
+impl BundleDeltas {
+    /// Combines the coinbase and searcher balances read from the db around the simulation with
+    /// the executor deltas recorded by `inspector`
+    pub fn new(
+        coinbase: (U256, U256),
+        searcher: (U256, U256),
+        executor: B160,
+        inspector: &BalanceDeltaInspector,
+    ) -> Self {
+        let diff = |(before, after): (U256, U256)| I256::from_raw(after) - I256::from_raw(before);
+        Self {
+            coinbase: diff(coinbase),
+            searcher_eth: diff(searcher),
+            executor: inspector.deltas_of(executor).into_iter().collect(),
+        }
+    }
+
+    /// The executor's deltas in share tokens counted in their underlying, e.g. WETH vault shares
+    /// in WETH, see [value_deltas]
+    pub fn valued(mut self, valuations: &HashMap<Address, TokenValuation>) -> Self {
+        if !valuations.is_empty() {
+            self.executor = value_deltas(&self.executor, valuations);
+        }
+        self
+    }
+
+    /// Searcher ETH change plus the executor's change in `profit_assets`
+    pub fn net_profit(&self, profit_assets: &[Asset]) -> I256 {
+        profit_assets
+            .iter()
+            .filter_map(|asset| self.executor.get(asset))
+            .fold(self.searcher_eth, |profit, delta| profit + *delta)
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum BundleValidationError {
+    #[error("Bundle is empty")]
+    Empty,
+    #[error("Tx {index} ({hash:?}) appears more than once")]
+    DuplicateTx { index: usize, hash: H256 },
+    #[error("Tx {index} from {sender:?} has nonce {nonce}, the sender is at {expected}")]
+    BadNonce {
+        index: usize,
+        sender: Address,
+        nonce: U256,
+        expected: u64,
+    },
+    #[error("Tx {index} gas limit {gas_limit} exceeds the {remaining} gas left in the block")]
+    BlockGasExceeded {
+        index: usize,
+        gas_limit: u64,
+        remaining: u64,
+    },
+    #[error("Tx {index} ({hash:?}) is invalid: {reason}")]
+    Invalid {
+        index: usize,
+        hash: H256,
+        reason: String,
+    },
+    #[error("Tx {index} ({hash:?}) reverted and isn't allowed to")]
+    Reverted { index: usize, hash: H256 },
+    #[error(transparent)]
+    Check(#[from] BundleCheckError),
+    #[error("Local profit {local} disagrees with the expected {expected}")]
+    ProfitMismatch { local: I256, expected: I256 },
+    #[error("Simulation error: {0}")]
+    Simulation(String),
+}
+
+/// Outcome of a single tx in a [BundleValidation]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxValidation {
+    pub hash: H256,
+    pub gas_used: u64,
+    pub success: bool,
+}
+
+/// A bundle that passed [BundleValidator::validate]
+#[derive(Debug, Clone)]
+pub struct BundleValidation {
+    pub txs: Vec<TxValidation>,
+    pub gas_used: u64,
+    pub deltas: BundleDeltas,
+    pub profit: I256,
+    /// results of the txs, for [RemoteSimulator::cross_check], their state is committed to the
+    /// sandbox and left empty
+    pub outcomes: Vec<SimOutcome>,
+}
+
+impl BundleValidation {
+    /// What the block builder receives, fees and direct payments
+    pub fn coinbase_payment(&self) -> I256 {
+        self.deltas.coinbase
+    }
+}
+
+/// Authorization lists of the set-code txs of a bundle by tx hash, each applied right before its
+/// tx, see [apply_authorizations]
+pub type Authorizations = HashMap<H256, Vec<SignedAuthorization>>;
+
+/// Validations of bundles, keyed by the state they ran on and their txs, see
+/// [BundleValidator::validate_cached]
+pub type ValidationCache = SimCache<BundleValidation>;
+
+/// Local stand-in for `eth_callBundle`, run right before a bundle goes to the relays
+///
+/// The bundle is executed the way a builder would, in order on top of the target block's parent,
+/// rejecting duplicate txs, out of order or stale nonces, txs that don't fit the block gas limit
+/// and reverts not marked as allowed. The balance changes then go through the [BundleCheck] and
+/// the profit has to match what the strategy expected, within `profit_tolerance_bps`. A bundle
+/// failing any of this would fail at the builder too, only burning relay rate limit.
+#[derive(Debug, Clone)]
+pub struct BundleValidator {
+    pub check: BundleCheck,
+    /// contract the profit accrues in
+    pub executor: B160,
+    /// EOA paying for gas
+    pub searcher: B160,
+    /// tracked for wraps and unwraps, see [BalanceDeltaInspector::with_weth]
+    pub weth: Option<B160>,
+    /// allowed deviation of the local from the expected profit, relative to the expected one
+    pub profit_tolerance_bps: u64,
+    /// share tokens the executor may end up holding, valued in their underlying
+    pub valuations: HashMap<Address, TokenValuation>,
+}
+
+impl BundleValidator {
+    pub fn new(check: BundleCheck, executor: B160, searcher: B160) -> Self {
+        Self {
+            check,
+            executor,
+            searcher,
+            weth: None,
+            profit_tolerance_bps: 100,
+            valuations: HashMap::new(),
+        }
+    }
+
+    pub fn with_weth(mut self, weth: B160) -> Self {
+        self.weth = Some(weth);
+        self
+    }
+
+    pub fn with_profit_tolerance_bps(mut self, profit_tolerance_bps: u64) -> Self {
+        self.profit_tolerance_bps = profit_tolerance_bps;
+        self
+    }
+
+    /// Value the executor's deltas in share tokens with `valuations`, read at the target block's
+    /// parent, see [collectors::wrapped_tokens::valuation]
+    pub fn with_valuations(mut self, valuations: HashMap<Address, TokenValuation>) -> Self {
+        self.valuations = valuations;
+        self
+    }
+
+    /// Validate `txs` over `db`, which has to be pinned at the parent of the target block, with
+    /// `env` set up for the target block. Nothing is committed to `db`.
+    ///
+    /// Arguments:
+    /// * `reverting`: hashes of txs allowed to revert, as sent to the relay
+    /// * `expected_profit`: net profit the strategy computed for the bundle
+    pub fn validate<DB>(
+        &self,
+        db: &DB,
+        env: &Env,
+        txs: &[Transaction],
+        reverting: &[H256],
+        expected_profit: I256,
+    ) -> Result<BundleValidation, BundleValidationError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        let validation = self.execute(db, env, txs, &Authorizations::new(), reverting)?;
+        self.expect(validation, expected_profit)
+    }
+
+    /// [BundleValidator::validate] on `db`, holding `state`, unless the same txs with the same
+    /// `reverting` ran on it before. Only bundles passing the [BundleCheck] are cached, the
+    /// expected profit is compared on every call. The set-code txs among `txs` run with their
+    /// `authorizations` applied.
+    #[allow(clippy::too_many_arguments)]
+    pub fn validate_cached<DB>(
+        &self,
+        cache: &ValidationCache,
+        state: StateId,
+        db: &DB,
+        env: &Env,
+        txs: &[Transaction],
+        authorizations: &Authorizations,
+        reverting: &[H256],
+        expected_profit: I256,
+    ) -> Result<BundleValidation, BundleValidationError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        let tx_envs: Vec<_> = txs.iter().map(tx_to_tx_env).collect();
+        // a tx allowed to revert passes where the same tx that isn't doesn't
+        let mut bundle = bundle_hash(&tx_envs).as_bytes().to_vec();
+        for hash in reverting {
+            bundle.extend_from_slice(hash.as_bytes());
+        }
+        // the hash of a set-code tx covers its authorizations, its tx env doesn't
+        for tx in txs
+            .iter()
+            .filter(|tx| authorizations.contains_key(&tx.hash))
+        {
+            bundle.extend_from_slice(tx.hash.as_bytes());
+        }
+        let key = BundleKey {
+            state: state_hash(env, state),
+            bundle: keccak256(&bundle),
+        };
+        let validation = cache.get_or_simulate(key, || {
+            self.execute(db, env, txs, authorizations, reverting)
+        })?;
+        self.expect((*validation).clone(), expected_profit)
+    }
+
+    /// `validation` if its profit is within `profit_tolerance_bps` of `expected_profit`
+    fn expect(
+        &self,
+        validation: BundleValidation,
+        expected_profit: I256,
+    ) -> Result<BundleValidation, BundleValidationError> {
+        if !within_tolerance(
+            validation.profit,
+            expected_profit,
+            self.profit_tolerance_bps,
+        ) {
+            return Err(BundleValidationError::ProfitMismatch {
+                local: validation.profit,
+                expected: expected_profit,
+            });
+        }
+        Ok(validation)
+    }
+
+    /// Runs `txs` and checks the outcome with the [BundleCheck], the profit isn't compared
+    fn execute<DB>(
+        &self,
+        db: &DB,
+        env: &Env,
+        txs: &[Transaction],
+        authorizations: &Authorizations,
+        reverting: &[H256],
+    ) -> Result<BundleValidation, BundleValidationError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        if txs.is_empty() {
+            return Err(BundleValidationError::Empty);
+        }
+        let mut seen = HashSet::new();
+        for (index, tx) in txs.iter().enumerate() {
+            if !seen.insert(tx.hash) {
+                return Err(BundleValidationError::DuplicateTx {
+                    index,
+                    hash: tx.hash,
+                });
+            }
+        }
+
+        let mut sandbox = CacheDB::new(DelegationDb::new(RefDb(db)));
+        let balance = |db: &CacheDB<DelegationDb<RefDb<'_, DB>>>, address: B160| {
+            db.basic(address)
+                .map(|info| {
+                    info.map(|info| ru256_to_u256(info.balance))
+                        .unwrap_or_default()
+                })
+                .map_err(|e| BundleValidationError::Simulation(format!("{:?}", e)))
+        };
+        let coinbase = env.block.coinbase;
+        let coinbase_before = balance(&sandbox, coinbase)?;
+        let searcher_before = balance(&sandbox, self.searcher)?;
+
+        let mut inspector = BalanceDeltaInspector::new([self.executor]);
+        if let Some(weth) = self.weth {
+            inspector = inspector.with_weth(weth);
+        }
+        let block_gas_limit = ru256_to_u256(env.block.gas_limit).low_u64();
+        let mut gas_used = 0u64;
+        let mut results = Vec::with_capacity(txs.len());
+        let mut outcomes = Vec::with_capacity(txs.len());
+
+        for (index, tx) in txs.iter().enumerate() {
+            let sender = h160_to_b160(tx.from);
+            let expected = sandbox
+                .basic(sender)
+                .map_err(|e| BundleValidationError::Simulation(format!("{:?}", e)))?
+                .map_or(0, |info| info.nonce);
+            if tx.nonce != U256::from(expected) {
+                return Err(BundleValidationError::BadNonce {
+                    index,
+                    sender: tx.from,
+                    nonce: tx.nonce,
+                    expected,
+                });
+            }
+
+            let remaining = block_gas_limit.saturating_sub(gas_used);
+            if tx.gas > U256::from(remaining) {
+                return Err(BundleValidationError::BlockGasExceeded {
+                    index,
+                    gas_limit: tx.gas.low_u64(),
+                    remaining,
+                });
+            }
+
+            let mut tx_env = tx_to_tx_env(tx);
+            // revm doesn't charge the authorizations' intrinsic gas
+            let mut intrinsic = 0;
+            if let Some(authorizations) = authorizations.get(&tx.hash) {
+                let applied = apply_authorizations(
+                    &mut sandbox,
+                    env.cfg.chain_id.to(),
+                    tx.from,
+                    authorizations,
+                );
+                for (authorization, applied) in authorizations.iter().zip(applied) {
+                    if let Err(e) = applied {
+                        debug!(
+                            "Authorization {:?} of tx {:?} skipped: {}",
+                            authorization.auth, tx.hash, e
+                        );
+                    }
+                }
+                // the sender's own increment is left to the EVM, its nonce was checked above
+                tx_env.nonce = None;
+                intrinsic = authorizations.len() as u64 * PER_EMPTY_ACCOUNT_COST;
+            }
+
+            let mut evm = EVM::new();
+            evm.env = env.clone();
+            evm.env.tx = tx_env;
+            // resolves the designators just written to the sandbox
+            evm.database(DelegationDb::new(&mut sandbox));
+            let result =
+                evm.inspect_commit(&mut inspector)
+                    .map_err(|e| BundleValidationError::Invalid {
+                        index,
+                        hash: tx.hash,
+                        reason: format!("{:?}", e),
+                    })?;
+
+            if !result.is_success() && !reverting.contains(&tx.hash) {
+                return Err(BundleValidationError::Reverted {
+                    index,
+                    hash: tx.hash,
+                });
+            }
+            gas_used += result.gas_used() + intrinsic;
+            results.push(TxValidation {
+                hash: tx.hash,
+                gas_used: result.gas_used() + intrinsic,
+                success: result.is_success(),
+            });
+            outcomes.push(SimOutcome {
+                result,
+                state: Default::default(),
+                transient: Default::default(),
+                base: None,
+            });
+        }
+
+        let deltas = BundleDeltas::new(
+            (coinbase_before, balance(&sandbox, coinbase)?),
+            (searcher_before, balance(&sandbox, self.searcher)?),
+            self.executor,
+            &inspector,
+        )
+        .valued(&self.valuations);
+        let profit = self.check.check(&deltas)?;
+
+        Ok(BundleValidation {
+            txs: results,
+            gas_used,
+            deltas,
+            profit,
+            outcomes,
+        })
+    }
+
+    /// [BundleValidator::validate], then the same txs on `remote` if the node serves
+    /// `eth_simulateV1`, see [RemoteSimulator::connect], on top of the block `db` is pinned at.
+    /// Divergences are logged and returned, they don't fail the bundle and neither does the
+    /// remote call failing.
+    pub async fn validate_cross_checked<DB, M>(
+        &self,
+        db: &DB,
+        env: &Env,
+        txs: &[Transaction],
+        reverting: &[H256],
+        expected_profit: I256,
+        remote: Option<(&RemoteSimulator<M>, BlockNumber)>,
+    ) -> Result<(BundleValidation, Vec<Divergence>), BundleValidationError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+        M: Middleware,
+    {
+        let validation = self.validate(db, env, txs, reverting, expected_profit)?;
+        let Some((remote, base)) = remote else {
+            return Ok((validation, vec![]));
+        };
+        let tx_envs: Vec<_> = txs.iter().map(tx_to_tx_env).collect();
+        let divergences = match remote
+            .cross_check(
+                env,
+                &tx_envs,
+                &validation.outcomes,
+                base,
+                &StateOverrides::new(),
+            )
+            .await
+        {
+            Ok(divergences) => divergences,
+            Err(e) => {
+                warn!("Failed to cross check the bundle remotely: {}", e);
+                vec![]
+            }
+        };
+        Ok((validation, divergences))
+    }
+}
+
+fn within_tolerance(local: I256, expected: I256, tolerance_bps: u64) -> bool {
+    let deviation = (local - expected).abs();
+    deviation * I256::from(BPS) <= expected.abs() * I256::from(tolerance_bps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wei(n: i64) -> I256 {
+        I256::from(n)
+    }
+
+    #[test]
+    fn test_bundle_check() {
+        let weth = B160::from_low_u64_be(1);
+        let check = BundleCheck::new(weth)
+            .with_min_net_profit(wei(100))
+            .with_min_coinbase_payment(U256::from(50));
+
+        // 1000 weth gained, 600 wei of gas and priority fee paid by the searcher
+        let mut deltas = BundleDeltas {
+            coinbase: wei(400),
+            searcher_eth: wei(-600),
+            executor: [(Asset::Token(weth), wei(1000))].into_iter().collect(),
+        };
+        assert_eq!(check.check(&deltas), Ok(wei(400)));
+
+        // paying the builder from the executor counts against the profit
+        deltas.executor.insert(Asset::Eth, wei(-350));
+        assert_eq!(
+            check.check(&deltas),
+            Err(BundleCheckError::ProfitTooLow {
+                profit: wei(50),
+                min: wei(100),
+            })
+        );
+        deltas.executor.remove(&Asset::Eth);
+
+        let token = Asset::Token(B160::from_low_u64_be(2));
+        deltas.executor.insert(token, wei(-1));
+        assert_eq!(
+            check.check(&deltas),
+            Err(BundleCheckError::UnexpectedOutflow {
+                asset: token,
+                delta: wei(-1),
+            })
+        );
+        deltas.executor.remove(&token);
+
+        deltas.coinbase = wei(10);
+        assert!(matches!(
+            check.check(&deltas),
+            Err(BundleCheckError::CoinbasePaymentTooLow { .. })
+        ));
+        deltas.coinbase = wei(400);
+
+        // WETH spent on vault shares worth 1.05 WETH each
+        let vault = Address::from_low_u64_be(3);
+        let shares = TokenValuation {
+            token: vault,
+            model: collectors::wrapped_tokens::BalanceModel::Erc4626Shares {
+                asset: Address::from(weth.0),
+            },
+            underlying: Address::from(weth.0),
+            stored: collectors::wrapped_tokens::Rate::ONE,
+            value: collectors::wrapped_tokens::Rate::new(U256::from(105), U256::from(100)),
+        };
+        deltas.executor = [
+            (Asset::Token(weth), wei(-1000)),
+            (Asset::Token(B160(vault.0)), wei(2000)),
+        ]
+        .into_iter()
+        .collect();
+        assert!(matches!(
+            check.check(&deltas),
+            Err(BundleCheckError::ProfitTooLow { .. })
+        ));
+        let valued = deltas.valued(&HashMap::from([(vault, shares)]));
+        assert_eq!(valued.executor.len(), 1);
+        assert_eq!(check.check(&valued), Ok(wei(500)));
+    }
+
+    #[test]
+    fn test_bundle_validator() {
+        use ethers::types::H160;
+        use revm::db::EmptyDB;
+        use revm::primitives::{AccountInfo, U256 as rU256};
+
+        let searcher = H160::from_low_u64_be(0x1000);
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            h160_to_b160(searcher),
+            AccountInfo {
+                balance: rU256::from(1_000_000u64),
+                ..Default::default()
+            },
+        );
+        let env = Env::default();
+        // pays the builder directly, gas is free in the default env
+        let payment = |nonce: u64| Transaction {
+            hash: H256::from_low_u64_be(nonce + 1),
+            from: searcher,
+            to: Some(H160(env.block.coinbase.0)),
+            value: U256::from(1_000),
+            gas: U256::from(21_000),
+            gas_price: Some(U256::zero()),
+            nonce: U256::from(nonce),
+            ..Default::default()
+        };
+
+        let validator = BundleValidator::new(
+            BundleCheck::new(B160::from_low_u64_be(1)).with_min_net_profit(wei(-2_000)),
+            B160::from_low_u64_be(0x2000),
+            h160_to_b160(searcher),
+        )
+        .with_profit_tolerance_bps(1_000);
+
+        let bundle = [payment(0), payment(1)];
+        let validation = validator
+            .validate(&db, &env, &bundle, &[], wei(-2_000))
+            .unwrap();
+        assert_eq!(validation.coinbase_payment(), wei(2_000));
+        assert_eq!(validation.gas_used, 42_000);
+
+        // out of order nonces
+        assert!(matches!(
+            validator.validate(&db, &env, &[payment(1), payment(0)], &[], wei(-2_000)),
+            Err(BundleValidationError::BadNonce { index: 0, .. })
+        ));
+        assert!(matches!(
+            validator.validate(&db, &env, &[payment(0), payment(0)], &[], wei(-2_000)),
+            Err(BundleValidationError::DuplicateTx { index: 1, .. })
+        ));
+        // strategy expected the bundle to make money
+        assert!(matches!(
+            validator.validate(&db, &env, &bundle, &[], wei(500)),
+            Err(BundleValidationError::ProfitMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_cached_validation() {
+        use ethers::types::H160;
+        use revm::db::EmptyDB;
+        use revm::primitives::{AccountInfo, B256, U256 as rU256};
+
+        let searcher = H160::from_low_u64_be(0x1000);
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            h160_to_b160(searcher),
+            AccountInfo {
+                balance: rU256::from(1_000_000u64),
+                ..Default::default()
+            },
+        );
+        let env = Env::default();
+        let payment = Transaction {
+            hash: H256::from_low_u64_be(1),
+            from: searcher,
+            to: Some(H160(env.block.coinbase.0)),
+            value: U256::from(1_000),
+            gas: U256::from(21_000),
+            gas_price: Some(U256::zero()),
+            ..Default::default()
+        };
+        let validator = BundleValidator::new(
+            BundleCheck::new(B160::from_low_u64_be(1)).with_min_net_profit(wei(-1_000)),
+            B160::from_low_u64_be(0x2000),
+            h160_to_b160(searcher),
+        );
+        let cache = ValidationCache::new(16);
+        let head = StateId::Fork {
+            fork: 1,
+            block_hash: B256::from_low_u64_be(100),
+        };
+        let validate = |state, reverting: &[H256], expected| {
+            validator.validate_cached(
+                &cache,
+                state,
+                &db,
+                &env,
+                &[payment.clone()],
+                &Authorizations::new(),
+                reverting,
+                expected,
+            )
+        };
+
+        validate(head, &[], wei(-1_000)).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (0, 1));
+        // a relay retry on the same head isn't executed again, the profit is still compared
+        assert_eq!(validate(head, &[], wei(-1_000)).unwrap().gas_used, 21_000);
+        assert!(matches!(
+            validate(head, &[], wei(500)),
+            Err(BundleValidationError::ProfitMismatch { .. })
+        ));
+        assert_eq!((cache.hits(), cache.misses()), (2, 1));
+
+        // a new head or other reverting txs run again
+        let next = StateId::Fork {
+            fork: 2,
+            block_hash: B256::from_low_u64_be(101),
+        };
+        validate(next, &[], wei(-1_000)).unwrap();
+        validate(head, &[payment.hash], wei(-1_000)).unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (2, 3));
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn test_set_code_validation() {
+        use ethers::signers::{LocalWallet, Signer};
+        use ethers::types::H160;
+        use fork_database::delegation::Authorization;
+        use revm::db::EmptyDB;
+        use revm::primitives::{bytes, AccountInfo, Bytecode, U256 as rU256};
+
+        let wallet: LocalWallet =
+            "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+                .parse()
+                .unwrap();
+        let searcher = wallet.address();
+        let delegate = H160::from_low_u64_be(0xde);
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            h160_to_b160(searcher),
+            AccountInfo {
+                balance: rU256::from(1_000_000u64),
+                ..Default::default()
+            },
+        );
+        // PUSH1 0x2a PUSH1 0 SSTORE STOP
+        db.insert_account_info(
+            h160_to_b160(delegate),
+            AccountInfo {
+                code: Some(Bytecode::new_raw(bytes::Bytes::from_static(&[
+                    0x60, 0x2a, 0x60, 0x00, 0x55, 0x00,
+                ]))),
+                ..Default::default()
+            },
+        );
+        let env = Env::default();
+        // the searcher delegates to itself, signing for the nonce after the tx's
+        let auth = Authorization {
+            chain_id: U256::from(env.cfg.chain_id.to::<u64>()),
+            address: delegate,
+            nonce: 1,
+        };
+        let signature = wallet.sign_hash(auth.signing_hash()).unwrap();
+        let authorization = SignedAuthorization {
+            auth,
+            y_parity: (signature.v - 27) as u8,
+            r: signature.r,
+            s: signature.s,
+        };
+        let set_code = Transaction {
+            hash: H256::from_low_u64_be(1),
+            from: searcher,
+            to: Some(searcher),
+            gas: U256::from(100_000),
+            gas_price: Some(U256::zero()),
+            transaction_type: Some(4u64.into()),
+            ..Default::default()
+        };
+        let validator = BundleValidator::new(
+            BundleCheck::new(B160::from_low_u64_be(1)).with_min_net_profit(wei(-1)),
+            B160::from_low_u64_be(0x2000),
+            h160_to_b160(searcher),
+        );
+        let cache = ValidationCache::new(16);
+        let state = StateId::Fork {
+            fork: 1,
+            block_hash: revm::primitives::B256::from_low_u64_be(100),
+        };
+
+        // without its authorization the call to the EOA runs no code
+        let plain = validator
+            .validate(&db, &env, &[set_code.clone()], &[], wei(0))
+            .unwrap();
+        assert_eq!(plain.gas_used, 21_000);
+
+        let authorizations = Authorizations::from([(set_code.hash, vec![authorization])]);
+        let delegated = validator
+            .validate_cached(
+                &cache,
+                state,
+                &db,
+                &env,
+                &[set_code],
+                &authorizations,
+                &[],
+                wei(0),
+            )
+            .unwrap();
+        // the delegate's SSTORE ran in the EOA, the authorization's gas is charged
+        assert!(delegated.txs[0].success);
+        assert!(delegated.gas_used > 21_000 + PER_EMPTY_ACCOUNT_COST + 20_000);
+    }
+
+    #[tokio::test]
+    async fn test_cross_checked_validation() {
+        use ethers::providers::Provider;
+        use ethers::types::H160;
+        use fork_database::remote_sim::DivergenceKind;
+        use revm::db::EmptyDB;
+        use revm::primitives::{AccountInfo, U256 as rU256};
+        use serde_json::json;
+        use std::sync::Arc;
+
+        let searcher = H160::from_low_u64_be(0x1000);
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            h160_to_b160(searcher),
+            AccountInfo {
+                balance: rU256::from(1_000_000u64),
+                ..Default::default()
+            },
+        );
+        let env = Env::default();
+        let payment = Transaction {
+            hash: H256::from_low_u64_be(1),
+            from: searcher,
+            to: Some(H160(env.block.coinbase.0)),
+            value: U256::from(1_000),
+            gas: U256::from(21_000),
+            gas_price: Some(U256::zero()),
+            ..Default::default()
+        };
+        let validator = BundleValidator::new(
+            BundleCheck::new(B160::from_low_u64_be(1)).with_min_net_profit(wei(-1_000)),
+            B160::from_low_u64_be(0x2000),
+            h160_to_b160(searcher),
+        );
+
+        let (provider, mock) = Provider::mocked();
+        // responses are popped last in first out, the probe goes first
+        mock.push(json!([{ "calls": [{ "status": "0x0", "gasUsed": "0x5208" }] }]))
+            .unwrap();
+        mock.push(json!([])).unwrap();
+        let remote = RemoteSimulator::connect(Arc::new(provider)).await.unwrap();
+
+        // the payment reverts on the node, the fork is behind
+        let (validation, divergences) = validator
+            .validate_cross_checked(
+                &db,
+                &env,
+                &[payment],
+                &[],
+                wei(-1_000),
+                Some((&remote, BlockNumber::Latest)),
+            )
+            .await
+            .unwrap();
+        assert_eq!(validation.outcomes.len(), 1);
+        assert_eq!(
+            divergences.into_iter().map(|d| d.kind).collect::<Vec<_>>(),
+            vec![DivergenceKind::Status {
+                local: true,
+                remote: false
+            }]
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Merging opportunities that target the same block into one bundle
+//!
+//! Opportunities on disjoint pools can share a bundle in any order. Opportunities sharing a pool
+//! move each other's prices, so their order matters and every ordering of such a group is
+//! simulated, up to [MAX_PERMUTED]. The merged bundle is only kept when its simulated profit beats
+//! submitting the opportunities as separate bundles.
+
+use std::collections::BTreeSet;
+
+use ethers::types::{Address, I256};
+
+/// Groups sharing pools larger than this aren't permuted, they're ordered by standalone profit
+pub const MAX_PERMUTED: usize = 5;
+
+/// An opportunity considered for merging
+#[derive(Debug, Clone)]
+pub struct MergeCandidate<T> {
+    pub opportunity: T,
+    /// pools the opportunity trades, two candidates sharing one depend on each other's order
+    pub pools: BTreeSet<Address>,
+    /// simulated profit when submitted on its own
+    pub profit: I256,
+}
+
+impl<T> MergeCandidate<T> {
+    pub fn new(opportunity: T, pools: impl IntoIterator<Item = Address>, profit: I256) -> Self {
+        Self {
+            opportunity,
+            pools: pools.into_iter().collect(),
+            profit,
+        }
+    }
+
+    fn conflicts_with(&self, other: &Self) -> bool {
+        !self.pools.is_disjoint(&other.pools)
+    }
+}
+
+/// How the candidates should be submitted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergePlan {
+    /// One bundle holding the candidates in this order, indexes into the candidates
+    Merged { order: Vec<usize>, profit: I256 },
+    /// Separate bundles, their standalone profits sum to `profit`
+    Separate { profit: I256 },
+}
+
+impl MergePlan {
+    pub fn profit(&self) -> I256 {
+        match self {
+            MergePlan::Merged { profit, .. } | MergePlan::Separate { profit } => *profit,
+        }
+    }
+}
+
+/// Find the best way to submit `candidates` for the same block
+///
+/// `simulate` runs the given candidates in order as one bundle and returns its profit, `None` if
+/// the bundle fails. Candidates with a non-positive standalone profit are never merged.
+pub fn plan_merge<T, F>(candidates: &[MergeCandidate<T>], mut simulate: F) -> MergePlan
+where
+    F: FnMut(&[&MergeCandidate<T>]) -> Option<I256>,
+{
+    let profitable: Vec<usize> = (0..candidates.len())
+        .filter(|i| candidates[*i].profit > I256::zero())
+        .collect();
+    let separate = profitable
+        .iter()
+        .fold(I256::zero(), |sum, i| sum + candidates[*i].profit);
+    if profitable.len() < 2 {
+        return MergePlan::Separate { profit: separate };
+    }
+
+    let mut order = vec![];
+    for group in conflict_groups(candidates, &profitable) {
+        order.extend(best_group_order(candidates, group, &mut simulate));
+    }
+
+    let bundle: Vec<&MergeCandidate<T>> = order.iter().map(|i| &candidates[*i]).collect();
+    match simulate(&bundle) {
+        Some(profit) if profit > separate => MergePlan::Merged { order, profit },
+        _ => MergePlan::Separate { profit: separate },
+    }
+}
+
+/// Split `indexes` into groups connected through shared pools, most profitable group first
+fn conflict_groups<T>(candidates: &[MergeCandidate<T>], indexes: &[usize]) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = vec![];
+    for &i in indexes {
+        let (mut joined, rest): (Vec<_>, Vec<_>) = groups.into_iter().partition(|group| {
+            group
+                .iter()
+                .any(|j| candidates[i].conflicts_with(&candidates[*j]))
+        });
+        let mut group: Vec<usize> = joined.drain(..).flatten().collect();
+        group.push(i);
+        groups = rest;
+        groups.push(group);
+    }
+
+    let group_profit = |group: &Vec<usize>| {
+        group
+            .iter()
+            .fold(I256::zero(), |sum, i| sum + candidates[*i].profit)
+    };
+    groups.sort_by_key(|group| std::cmp::Reverse(group_profit(group)));
+    groups
+}
+
+/// Order within a group, by simulating each permutation when the group is small enough
+fn best_group_order<T, F>(
+    candidates: &[MergeCandidate<T>],
+    mut group: Vec<usize>,
+    simulate: &mut F,
+) -> Vec<usize>
+where
+    F: FnMut(&[&MergeCandidate<T>]) -> Option<I256>,
+{
+    group.sort_by_key(|i| std::cmp::Reverse(candidates[*i].profit));
+    if group.len() < 2 || group.len() > MAX_PERMUTED {
+        return group;
+    }
+
+    let mut best: Option<(I256, Vec<usize>)> = None;
+    for order in permutations(&group) {
+        let bundle: Vec<&MergeCandidate<T>> = order.iter().map(|i| &candidates[*i]).collect();
+        if let Some(profit) = simulate(&bundle) {
+            if best.as_ref().map_or(true, |(best, _)| profit > *best) {
+                best = Some((profit, order));
+            }
+        }
+    }
+    best.map(|(_, order)| order).unwrap_or(group)
+}
+
+fn permutations(items: &[usize]) -> Vec<Vec<usize>> {
+    if items.len() <= 1 {
+        return vec![items.to_vec()];
+    }
+    let mut all = vec![];
+    for (i, first) in items.iter().enumerate() {
+        let mut rest = items.to_vec();
+        rest.remove(i);
+        for mut tail in permutations(&rest) {
+            tail.insert(0, *first);
+            all.push(tail);
+        }
+    }
+    all
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(n: u64) -> Address {
+        Address::from_low_u64_be(n)
+    }
+
+    #[test]
+    fn test_merge_orders_dependent_candidates() {
+        let candidates = [
+            MergeCandidate::new("a", [pool(1)], I256::from(100)),
+            MergeCandidate::new("b", [pool(1), pool(2)], I256::from(80)),
+            MergeCandidate::new("c", [pool(3)], I256::from(50)),
+        ];
+
+        // `b` only keeps its profit when it runs before `a` on the shared pool
+        let simulate = |bundle: &[&MergeCandidate<&str>]| {
+            let names: Vec<&str> = bundle.iter().map(|c| c.opportunity).collect();
+            let b_first =
+                names.iter().position(|n| *n == "b") < names.iter().position(|n| *n == "a");
+            let profit = bundle
+                .iter()
+                .map(|c| c.profit)
+                .fold(I256::zero(), |a, b| a + b);
+            Some(if b_first {
+                profit + I256::from(10)
+            } else {
+                profit - I256::from(60)
+            })
+        };
+
+        let plan = plan_merge(&candidates, simulate);
+        assert_eq!(
+            plan,
+            MergePlan::Merged {
+                order: vec![1, 0, 2],
+                profit: I256::from(240)
+            }
+        );
+    }
+
+    #[test]
+    fn test_merge_splits_when_combined_is_worse() {
+        let candidates = [
+            MergeCandidate::new("a", [pool(1)], I256::from(100)),
+            MergeCandidate::new("b", [pool(1)], I256::from(80)),
+        ];
+        // the second trade on the pool always eats most of the first one's profit
+        let plan = plan_merge(&candidates, |_| Some(I256::from(120)));
+        assert_eq!(
+            plan,
+            MergePlan::Separate {
+                profit: I256::from(180)
+            }
+        );
+    }
+}
@@ -0,0 +1,300 @@
+//! Splitting the bot's inventory between strategies
+//!
+//! Each token's inventory (WETH, stables) is shared by every strategy. A strategy gets at most
+//! its configured share of it, scaled down by a fractional Kelly bet computed from its own hit
+//! rate and average win/loss, so a strategy that keeps losing is starved before the
+//! [RiskManager](crate::risk::RiskManager) breaker has to trip. Bundles are sized with
+//! [CapitalAllocator::reserve], which never hands out more than the strategy's allocation.
+
+use std::collections::HashMap;
+
+use ethers::types::{Address, I256, U256};
+use parking_lot::Mutex;
+use thiserror::Error;
+
+use crate::risk::BundleOutcome;
+
+const BPS: u64 = 10_000;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum CapitalError {
+    #[error("No inventory configured for token {0:?}")]
+    NoInventory(Address),
+    #[error("Strategy {0} has no capital left in token {1:?}")]
+    Exhausted(String, Address),
+}
+
+/// How much of the inventory a strategy may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StrategyCap {
+    /// share of each token's inventory
+    pub max_share_bps: u64,
+    /// hard cap per bundle, in the bundle's token
+    pub max_per_bundle: Option<U256>,
+}
+
+impl Default for StrategyCap {
+    fn default() -> Self {
+        Self {
+            max_share_bps: 2_500,
+            max_per_bundle: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CapitalConfig {
+    /// strategy specific caps, falls back to `default_cap`
+    pub caps: HashMap<String, StrategyCap>,
+    pub default_cap: StrategyCap,
+    /// multiplier on the full Kelly fraction, half Kelly by default
+    pub kelly_multiplier_bps: u64,
+    /// landed bundles needed before Kelly sizing kicks in
+    pub min_samples: u64,
+    /// fraction of its cap used before `min_samples` is reached
+    pub bootstrap_fraction_bps: u64,
+}
+
+impl Default for CapitalConfig {
+    fn default() -> Self {
+        Self {
+            caps: HashMap::new(),
+            default_cap: StrategyCap::default(),
+            kelly_multiplier_bps: 5_000,
+            min_samples: 20,
+            bootstrap_fraction_bps: 2_000,
+        }
+    }
+}
+
+/// Hit rate and payoffs of a strategy's landed bundles
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StrategyRecord {
+    pub wins: u64,
+    pub losses: u64,
+    pub total_won: U256,
+    pub total_lost: U256,
+}
+
+impl StrategyRecord {
+    pub fn samples(&self) -> u64 {
+        self.wins + self.losses
+    }
+
+    /// Full Kelly fraction `p - (1 - p) / b` in bps, `b` being the average win over the average
+    /// loss. Zero for a strategy without an edge.
+    pub fn kelly_bps(&self) -> u64 {
+        if self.wins == 0 {
+            return 0;
+        }
+        let p = self.wins as f64 / self.samples() as f64;
+        if self.total_lost.is_zero() {
+            // losses that cost nothing, the bet is only bounded by the hit rate
+            return (p * BPS as f64) as u64;
+        }
+        let avg_win = u256_to_f64(self.total_won) / self.wins as f64;
+        let avg_loss = u256_to_f64(self.total_lost) / self.losses as f64;
+        let kelly = p - (1.0 - p) / (avg_win / avg_loss);
+        (kelly.clamp(0.0, 1.0) * BPS as f64) as u64
+    }
+}
+
+#[derive(Debug, Default)]
+struct CapitalState {
+    inventory: HashMap<Address, U256>,
+    records: HashMap<String, StrategyRecord>,
+    /// capital held by bundles in flight, per strategy and token
+    reserved: HashMap<(String, Address), U256>,
+}
+
+/// Allocates inventory across strategies, see the [module docs](self)
+#[derive(Debug, Default)]
+pub struct CapitalAllocator {
+    config: CapitalConfig,
+    state: Mutex<CapitalState>,
+}
+
+impl CapitalAllocator {
+    pub fn new(config: CapitalConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(CapitalState::default()),
+        }
+    }
+
+    /// Seed the hit rates, e.g. from past runs
+    pub fn with_records(self, records: impl IntoIterator<Item = (String, StrategyRecord)>) -> Self {
+        self.state.lock().records.extend(records);
+        self
+    }
+
+    pub fn config(&self) -> &CapitalConfig {
+        &self.config
+    }
+
+    /// Set the available inventory of `token`, e.g. after a balance update
+    pub fn set_inventory(&self, token: Address, amount: U256) {
+        self.state.lock().inventory.insert(token, amount);
+    }
+
+    pub fn record(&self, strategy: &str) -> StrategyRecord {
+        self.state
+            .lock()
+            .records
+            .get(strategy)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Feed the outcome of a bundle, only landed bundles move the hit rate
+    pub fn record_outcome(&self, strategy: &str, outcome: BundleOutcome) {
+        let BundleOutcome::Landed(pnl) = outcome else {
+            return;
+        };
+        let mut state = self.state.lock();
+        let record = state.records.entry(strategy.to_string()).or_default();
+        if pnl > I256::zero() {
+            record.wins += 1;
+            record.total_won = record.total_won.saturating_add(pnl.unsigned_abs());
+        } else {
+            record.losses += 1;
+            record.total_lost = record.total_lost.saturating_add(pnl.unsigned_abs());
+        }
+    }
+
+    /// Share of each token's inventory `strategy` may hold, in bps
+    pub fn allocation_bps(&self, strategy: &str) -> u64 {
+        self.allocation_bps_locked(&self.state.lock(), strategy)
+    }
+
+    /// Capital of `token` `strategy` can still reserve
+    pub fn available(&self, strategy: &str, token: Address) -> Result<U256, CapitalError> {
+        let state = self.state.lock();
+        self.available_locked(&state, strategy, token)
+    }
+
+    /// Reserve up to `wanted` of `token` for a bundle of `strategy` and return the amount the
+    /// bundle may use, capped by the strategy's allocation and its per bundle cap
+    pub fn reserve(
+        &self,
+        strategy: &str,
+        token: Address,
+        wanted: U256,
+    ) -> Result<U256, CapitalError> {
+        let mut state = self.state.lock();
+        let mut granted = wanted.min(self.available_locked(&state, strategy, token)?);
+        if let Some(max) = self.cap(strategy).max_per_bundle {
+            granted = granted.min(max);
+        }
+        if granted.is_zero() {
+            return Err(CapitalError::Exhausted(strategy.to_string(), token));
+        }
+        let reserved = state
+            .reserved
+            .entry((strategy.to_string(), token))
+            .or_default();
+        *reserved = reserved.saturating_add(granted);
+        Ok(granted)
+    }
+
+    /// Release capital reserved with [CapitalAllocator::reserve] once the bundle has landed or
+    /// been dropped
+    pub fn release(&self, strategy: &str, token: Address, amount: U256) {
+        let mut state = self.state.lock();
+        if let Some(reserved) = state.reserved.get_mut(&(strategy.to_string(), token)) {
+            *reserved = reserved.saturating_sub(amount);
+        }
+    }
+
+    fn available_locked(
+        &self,
+        state: &CapitalState,
+        strategy: &str,
+        token: Address,
+    ) -> Result<U256, CapitalError> {
+        let inventory = state
+            .inventory
+            .get(&token)
+            .copied()
+            .ok_or(CapitalError::NoInventory(token))?;
+        let allocation = inventory * self.allocation_bps_locked(state, strategy) / BPS;
+        let reserved = state
+            .reserved
+            .get(&(strategy.to_string(), token))
+            .copied()
+            .unwrap_or_default();
+        Ok(allocation.saturating_sub(reserved))
+    }
+
+    fn allocation_bps_locked(&self, state: &CapitalState, strategy: &str) -> u64 {
+        let cap = self.cap(strategy);
+        let record = state.records.get(strategy).copied().unwrap_or_default();
+        let fraction = if record.samples() < self.config.min_samples {
+            self.config.bootstrap_fraction_bps
+        } else {
+            (record.kelly_bps() * self.config.kelly_multiplier_bps / BPS).min(BPS)
+        };
+        cap.max_share_bps * fraction / BPS
+    }
+
+    fn cap(&self, strategy: &str) -> StrategyCap {
+        self.config
+            .caps
+            .get(strategy)
+            .copied()
+            .unwrap_or(self.config.default_cap)
+    }
+}
+
+fn u256_to_f64(value: U256) -> f64 {
+    value.to_string().parse().unwrap_or(f64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kelly_sizing() {
+        let weth = Address::from_low_u64_be(1);
+        let allocator = CapitalAllocator::new(CapitalConfig {
+            default_cap: StrategyCap {
+                max_share_bps: 5_000,
+                max_per_bundle: Some(U256::from(400)),
+            },
+            min_samples: 4,
+            ..Default::default()
+        });
+        allocator.set_inventory(weth, U256::from(10_000));
+
+        // bootstrap: 20% of a 50% cap
+        assert_eq!(allocator.allocation_bps("sando"), 1_000);
+        assert_eq!(
+            allocator.reserve("sando", weth, U256::from(5_000)),
+            Ok(U256::from(400))
+        );
+        assert_eq!(
+            allocator.available("sando", weth),
+            Ok(U256::from(1_000 - 400))
+        );
+        allocator.release("sando", weth, U256::from(400));
+
+        // 3 wins of 2 against a loss of 1: kelly = 0.75 - 0.25 / 2 = 62.5%, halved
+        for _ in 0..3 {
+            allocator.record_outcome("sando", BundleOutcome::Landed(I256::from(2)));
+        }
+        allocator.record_outcome("sando", BundleOutcome::Landed(I256::from(-1)));
+        allocator.record_outcome("sando", BundleOutcome::Failed);
+        assert_eq!(allocator.record("sando").kelly_bps(), 6_250);
+        assert_eq!(allocator.allocation_bps("sando"), 5_000 * 3_125 / BPS);
+
+        // a strategy without an edge gets nothing
+        for _ in 0..4 {
+            allocator.record_outcome("arb", BundleOutcome::Landed(I256::from(-1)));
+        }
+        assert_eq!(
+            allocator.reserve("arb", weth, U256::from(100)),
+            Err(CapitalError::Exhausted("arb".to_string(), weth))
+        );
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use ethers::{
+    providers::Middleware,
+    types::{Address, TransactionReceipt, U256},
+};
+use log::debug;
+use parking_lot::Mutex;
+use qilin_cfmms::events::swap_event_topics;
+
+use crate::inclusion::{best_bribe, InclusionModel};
+
+const BPS: u64 = 10_000;
+/// Granularity of the shares tried by [BribePolicy::decide_ev]
+const EV_STEP_BPS: u64 = 50;
+
+#[derive(Debug, Clone)]
+pub struct CompetitionConfig {
+    /// Blocks competitor activity is remembered for
+    pub window_blocks: u64,
+    /// Searcher contracts known upfront, more are learned from sandwich patterns
+    pub known_searchers: HashSet<Address>,
+    /// Our own contracts, never counted as competition
+    pub own: HashSet<Address>,
+}
+
+impl Default for CompetitionConfig {
+    fn default() -> Self {
+        Self {
+            window_blocks: 50,
+            known_searchers: HashSet::new(),
+            own: HashSet::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CompetitionState {
+    latest_block: u64,
+    /// pool => blocks a competitor traded it in
+    pools: HashMap<Address, BTreeSet<u64>>,
+    /// block => priority fees per gas competitors paid in it
+    fees: BTreeMap<u64, Vec<U256>>,
+    learned: HashSet<Address>,
+}
+
+/// Estimates per pool competitive pressure from landed blocks
+///
+/// A receipt counts as competition when it's sent to a known searcher contract, or when the same
+/// EOA trades a pool through the same contract right before and after another sender's tx on that
+/// pool (a sandwich), in which case the contract is learned as a searcher. Routers are called by
+/// many EOAs and aren't learned that way. With the block's base fee known, the priority fees the
+/// competitors paid are kept too, to price bundles against, see [InclusionModel].
+#[derive(Debug, Default)]
+pub struct CompetitionTracker {
+    config: CompetitionConfig,
+    state: Mutex<CompetitionState>,
+}
+
+impl CompetitionTracker {
+    pub fn new(config: CompetitionConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(CompetitionState::default()),
+        }
+    }
+
+    /// Fetch the receipts and base fee of `block` and
+    /// [observe](CompetitionTracker::observe_block_with_base_fee) them
+    pub async fn observe_from_provider<M: Middleware>(
+        &self,
+        provider: &M,
+        block: u64,
+    ) -> Result<usize, M::Error> {
+        let receipts = provider.get_block_receipts(block).await?;
+        let base_fee = provider
+            .get_block(block)
+            .await?
+            .and_then(|header| header.base_fee_per_gas);
+        Ok(self.observe(block, base_fee, &receipts))
+    }
+
+    /// Record competitor activity in the receipts of `block`, in block order. Returns the number
+    /// of competitor txs found.
+    pub fn observe_block(&self, block: u64, receipts: &[TransactionReceipt]) -> usize {
+        self.observe(block, None, receipts)
+    }
+
+    /// [CompetitionTracker::observe_block], also keeping the priority fees competitors paid over
+    /// `base_fee`
+    pub fn observe_block_with_base_fee(
+        &self,
+        block: u64,
+        base_fee: U256,
+        receipts: &[TransactionReceipt],
+    ) -> usize {
+        self.observe(block, Some(base_fee), receipts)
+    }
+
+    /// Priority fees per gas competitors paid in the window, for
+    /// [InclusionModel::set_competitor_fees]
+    pub fn competitor_fees(&self) -> Vec<U256> {
+        self.state.lock().fees.values().flatten().copied().collect()
+    }
+
+    fn observe(
+        &self,
+        block: u64,
+        base_fee: Option<U256>,
+        receipts: &[TransactionReceipt],
+    ) -> usize {
+        let mut state = self.state.lock();
+
+        // pools swapped on, token transfers and other logs aren't trades
+        let swaps = swap_event_topics();
+        let touched: Vec<BTreeSet<Address>> = receipts
+            .iter()
+            .map(|r| {
+                r.logs
+                    .iter()
+                    .filter(|log| log.topics.first().map_or(false, |t| swaps.contains(t)))
+                    .map(|log| log.address)
+                    .collect()
+            })
+            .collect();
+
+        for sandwicher in find_sandwichers(receipts, &touched) {
+            if !self.config.own.contains(&sandwicher) && state.learned.insert(sandwicher) {
+                debug!("Learned searcher contract {:?}", sandwicher);
+            }
+        }
+
+        let mut competitors = 0;
+        for (receipt, pools) in receipts.iter().zip(&touched) {
+            let Some(to) = receipt.to else {
+                continue;
+            };
+            if self.config.own.contains(&to)
+                || !(self.config.known_searchers.contains(&to) || state.learned.contains(&to))
+            {
+                continue;
+            }
+            competitors += 1;
+            for pool in pools {
+                state.pools.entry(*pool).or_default().insert(block);
+            }
+            if let Some((base_fee, price)) = base_fee.zip(receipt.effective_gas_price) {
+                let fees = state.fees.entry(block).or_default();
+                fees.push(price.saturating_sub(base_fee));
+            }
+        }
+
+        state.latest_block = state.latest_block.max(block);
+        let oldest = state
+            .latest_block
+            .saturating_sub(self.config.window_blocks.saturating_sub(1));
+        state.pools.retain(|_, blocks| {
+            blocks.retain(|b| *b >= oldest);
+            !blocks.is_empty()
+        });
+        state.fees = state.fees.split_off(&oldest);
+
+        competitors
+    }
+
+    /// Share of recent blocks in which a competitor traded `pool`, between 0 and 1
+    pub fn pressure(&self, pool: Address) -> f64 {
+        let state = self.state.lock();
+        let blocks = state.pools.get(&pool).map_or(0, |b| b.len());
+        (blocks as f64 / self.config.window_blocks as f64).min(1.0)
+    }
+
+    pub fn is_searcher(&self, contract: Address) -> bool {
+        self.config.known_searchers.contains(&contract)
+            || self.state.lock().learned.contains(&contract)
+    }
+
+    /// Bribe for an opportunity on `pool` under `policy`, given the current pressure on it
+    pub fn bribe(&self, policy: &BribePolicy, pool: Address, gross_profit: U256) -> BribeDecision {
+        policy.decide(gross_profit, self.pressure(pool))
+    }
+}
+
+/// Contracts one EOA traded a pool through both right before and after another sender on it
+fn find_sandwichers(
+    receipts: &[TransactionReceipt],
+    touched: &[BTreeSet<Address>],
+) -> Vec<Address> {
+    let mut sandwichers = vec![];
+    for (i, front) in receipts.iter().enumerate() {
+        let Some(contract) = front.to else {
+            continue;
+        };
+        for pool in &touched[i] {
+            // next two txs on the same pool
+            let mut next = (i + 1..receipts.len()).filter(|j| touched[*j].contains(pool));
+            let (Some(victim), Some(back)) = (next.next(), next.next()) else {
+                continue;
+            };
+            if receipts[back].to == Some(contract)
+                && receipts[back].from == front.from
+                && receipts[victim].to != Some(contract)
+                && receipts[victim].from != front.from
+            {
+                sandwichers.push(contract);
+            }
+        }
+    }
+    sandwichers
+}
+
+/// What to pay the block builder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BribeDecision {
+    /// Pay `bribe`, `bps` of the gross profit
+    Bid { bribe: U256, bps: u64 },
+    /// Too contested to be worth it
+    Skip,
+}
+
+/// Share of gross profit paid to the builder, scaled with competitive pressure
+#[derive(Debug, Clone, Copy)]
+pub struct BribePolicy {
+    /// Share paid on an uncontested pool
+    pub base_bps: u64,
+    /// Share paid at full pressure
+    pub max_bps: u64,
+    /// Pressure at or above which opportunities are skipped, `None` to always bid
+    pub skip_pressure: Option<f64>,
+}
+
+impl Default for BribePolicy {
+    fn default() -> Self {
+        Self {
+            base_bps: 5_000,
+            max_bps: 9_900,
+            skip_pressure: None,
+        }
+    }
+}
+
+impl BribePolicy {
+    pub fn decide(&self, gross_profit: U256, pressure: f64) -> BribeDecision {
+        let pressure = pressure.clamp(0.0, 1.0);
+        if self.skip_pressure.map_or(false, |skip| pressure >= skip) {
+            return BribeDecision::Skip;
+        }
+        let spread = self.max_bps.saturating_sub(self.base_bps) as f64;
+        let bps = (self.base_bps + (spread * pressure) as u64).min(BPS);
+        BribeDecision::Bid {
+            bribe: gross_profit * bps / BPS,
+            bps,
+        }
+    }
+
+    /// Same as [BribePolicy::decide], also bidding up for the chance the bundle gets
+    /// [invalidated](crate::conflicts::ConflictReport::invalidation) by pending txs. Winning the
+    /// top of the block protects against both.
+    pub fn decide_with_conflicts(
+        &self,
+        gross_profit: U256,
+        pressure: f64,
+        invalidation: f64,
+    ) -> BribeDecision {
+        let risk = 1.0 - (1.0 - pressure.clamp(0.0, 1.0)) * (1.0 - invalidation.clamp(0.0, 1.0));
+        self.decide(gross_profit, risk)
+    }
+
+    /// Bribe with the best expected value under `model` for a bundle using `gas_used`, up to
+    /// `max_bps` of the gross profit, instead of a share set by pressure. Pools at or above
+    /// `skip_pressure` are still skipped.
+    pub fn decide_ev(
+        &self,
+        model: &InclusionModel,
+        gross_profit: U256,
+        gas_used: u64,
+        pressure: f64,
+    ) -> BribeDecision {
+        let pressure = pressure.clamp(0.0, 1.0);
+        if self.skip_pressure.map_or(false, |skip| pressure >= skip) {
+            return BribeDecision::Skip;
+        }
+        match best_bribe(model, gross_profit, gas_used, self.max_bps, EV_STEP_BPS) {
+            Some(choice) if choice.expected_value > 0.0 => BribeDecision::Bid {
+                bribe: choice.bribe,
+                bps: choice.bps,
+            },
+            _ => BribeDecision::Skip,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Log, H256};
+
+    /// Swaps on `pools`, each with a transfer of the token `0xcc`
+    fn receipt(from: u64, to: u64, pools: &[Address]) -> TransactionReceipt {
+        let log = |address: Address, topic: H256| Log {
+            address,
+            topics: vec![topic],
+            ..Default::default()
+        };
+        let transfer = H256::from(ethers::utils::keccak256(
+            "Transfer(address,address,uint256)",
+        ));
+        TransactionReceipt {
+            from: Address::from_low_u64_be(from),
+            to: Some(Address::from_low_u64_be(to)),
+            logs: pools
+                .iter()
+                .flat_map(|pool| {
+                    [
+                        log(Address::from_low_u64_be(0xcc), transfer),
+                        log(*pool, swap_event_topics()[0]),
+                    ]
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sandwich_is_learned_as_competition() {
+        let pool = Address::from_low_u64_be(0xaa);
+        let other = Address::from_low_u64_be(0xbb);
+        let tracker = CompetitionTracker::new(CompetitionConfig {
+            window_blocks: 10,
+            ..Default::default()
+        });
+
+        // searcher 1 via contract 100 sandwiches sender 2 going through router 200
+        let block = [
+            receipt(1, 100, &[pool]),
+            receipt(2, 200, &[pool]),
+            receipt(1, 100, &[pool]),
+            receipt(3, 200, &[other]),
+        ];
+        assert_eq!(tracker.observe_block(1, &block), 2);
+        assert!(tracker.is_searcher(Address::from_low_u64_be(100)));
+        assert!(!tracker.is_searcher(Address::from_low_u64_be(200)));
+        assert_eq!(tracker.pressure(pool), 0.1);
+        assert_eq!(tracker.pressure(other), 0.0);
+        // the token is no pool
+        assert_eq!(tracker.pressure(Address::from_low_u64_be(0xcc)), 0.0);
+
+        assert!(tracker.competitor_fees().is_empty());
+
+        // the competitor paid 3 gwei over the base fee, the router's user isn't competition, both
+        // in the same block
+        let gwei = U256::exp10(9);
+        let priced = |from, to, price: u64| TransactionReceipt {
+            effective_gas_price: Some(gwei * price),
+            ..receipt(from, to, &[pool])
+        };
+        let block = [priced(1, 100, 13), priced(2, 200, 30)];
+        assert_eq!(tracker.observe_block_with_base_fee(1, gwei * 10, &block), 1);
+        assert_eq!(tracker.competitor_fees(), vec![gwei * 3]);
+
+        // activity falls out of the window
+        tracker.observe_block(11, &[]);
+        assert_eq!(tracker.pressure(pool), 0.0);
+        assert!(tracker.competitor_fees().is_empty());
+    }
+
+    #[test]
+    fn test_router_is_not_learned() {
+        let pool = Address::from_low_u64_be(0xaa);
+        let tracker = CompetitionTracker::new(CompetitionConfig::default());
+
+        // three users through router 200
+        let block = [
+            receipt(1, 200, &[pool]),
+            receipt(2, 300, &[pool]),
+            receipt(3, 200, &[pool]),
+        ];
+        assert_eq!(tracker.observe_block(1, &block), 0);
+        assert!(!tracker.is_searcher(Address::from_low_u64_be(200)));
+    }
+
+    #[test]
+    fn test_bribe_scales_with_pressure() {
+        let policy = BribePolicy {
+            base_bps: 5_000,
+            max_bps: 9_000,
+            skip_pressure: Some(0.8),
+        };
+        let profit = U256::from(10_000);
+
+        assert_eq!(
+            policy.decide(profit, 0.0),
+            BribeDecision::Bid {
+                bribe: U256::from(5_000),
+                bps: 5_000
+            }
+        );
+        assert_eq!(
+            policy.decide(profit, 0.5),
+            BribeDecision::Bid {
+                bribe: U256::from(7_000),
+                bps: 7_000
+            }
+        );
+        assert_eq!(policy.decide(profit, 0.9), BribeDecision::Skip);
+        assert_eq!(
+            policy.decide_with_conflicts(profit, 0.0, 0.5),
+            policy.decide(profit, 0.5)
+        );
+
+        // without the gas used there's no priority fee to size the bribe as
+        assert_eq!(
+            policy.decide_ev(&InclusionModel::default(), profit, 0, 0.0),
+            BribeDecision::Skip
+        );
+    }
+}
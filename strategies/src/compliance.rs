@@ -0,0 +1,483 @@
+//! Refusing to build bundles touching restricted addresses
+//!
+//! Every participant of a bundle, the victims' senders, the recipients of the funds and the pools
+//! traded, is checked against a [Denylist] (e.g. the OFAC SDN list's addresses) before the bundle
+//! is built. Rejections are written to the [EventLog] as an audit trail, and logged.
+
+use std::{collections::HashMap, fmt, fs, path::Path, sync::Arc};
+
+use ethers::{
+    abi::Token,
+    types::{Address, Log, Transaction, H256},
+    utils::{id, keccak256},
+};
+use log::warn;
+use thiserror::Error;
+
+use crate::event_log::{EventLog, OpportunityEvent};
+use crate::target_policy::decode_params;
+
+/// Router swaps and where their recipient sits: the parameter index, then the field within it if
+/// the parameter is a struct
+const SWAP_RECIPIENTS: &[(&str, &[usize])] = &[
+    // Uniswap V2 router
+    (
+        "swapExactTokensForTokens(uint256,uint256,address[],address,uint256)",
+        &[3],
+    ),
+    (
+        "swapTokensForExactTokens(uint256,uint256,address[],address,uint256)",
+        &[3],
+    ),
+    ("swapExactETHForTokens(uint256,address[],address,uint256)", &[2]),
+    (
+        "swapTokensForExactETH(uint256,uint256,address[],address,uint256)",
+        &[3],
+    ),
+    (
+        "swapExactTokensForETH(uint256,uint256,address[],address,uint256)",
+        &[3],
+    ),
+    ("swapETHForExactTokens(uint256,address[],address,uint256)", &[2]),
+    (
+        "swapExactTokensForTokensSupportingFeeOnTransferTokens(uint256,uint256,address[],address,uint256)",
+        &[3],
+    ),
+    (
+        "swapExactETHForTokensSupportingFeeOnTransferTokens(uint256,address[],address,uint256)",
+        &[2],
+    ),
+    (
+        "swapExactTokensForETHSupportingFeeOnTransferTokens(uint256,uint256,address[],address,uint256)",
+        &[3],
+    ),
+    // Uniswap V3 SwapRouter
+    (
+        "exactInputSingle((address,address,uint24,address,uint256,uint256,uint256,uint160))",
+        &[0, 3],
+    ),
+    ("exactInput((bytes,address,uint256,uint256,uint256))", &[0, 1]),
+    (
+        "exactOutputSingle((address,address,uint24,address,uint256,uint256,uint256,uint160))",
+        &[0, 3],
+    ),
+    ("exactOutput((bytes,address,uint256,uint256,uint256))", &[0, 1]),
+    // SwapRouter02, no deadline
+    ("swapExactTokensForTokens(uint256,uint256,address[],address)", &[3]),
+    ("swapTokensForExactTokens(uint256,uint256,address[],address)", &[3]),
+    (
+        "exactInputSingle((address,address,uint24,address,uint256,uint256,uint160))",
+        &[0, 3],
+    ),
+    ("exactInput((bytes,address,uint256,uint256))", &[0, 1]),
+    (
+        "exactOutputSingle((address,address,uint24,address,uint256,uint256,uint160))",
+        &[0, 3],
+    ),
+    ("exactOutput((bytes,address,uint256,uint256))", &[0, 1]),
+];
+
+/// SwapRouter02 placeholders for the caller and the router itself
+const MSG_SENDER: u64 = 1;
+const ADDRESS_THIS: u64 = 2;
+
+/// Recipient of the swap `tx` makes through a known router, `None` if its calldata isn't one
+pub fn swap_recipient(tx: &Transaction) -> Option<Address> {
+    let (selector, args) = (tx.input.get(..4)?, &tx.input[4..]);
+    let (signature, path) = SWAP_RECIPIENTS
+        .iter()
+        .find(|(signature, _)| id(signature) == selector)?;
+    let params = decode_params(signature, args)?;
+    let mut token = params.get(path[0])?.clone();
+    for field in &path[1..] {
+        token = match token {
+            Token::Tuple(fields) => fields.get(*field)?.clone(),
+            _ => return None,
+        };
+    }
+    let recipient = token.into_address()?;
+    if recipient == Address::from_low_u64_be(MSG_SENDER) {
+        Some(tx.from)
+    } else if recipient == Address::from_low_u64_be(ADDRESS_THIS) {
+        tx.to
+    } else {
+        Some(recipient)
+    }
+}
+
+/// Receivers of the ERC20 `Transfer`s among `logs`
+pub fn transfer_recipients(logs: &[Log]) -> impl Iterator<Item = Address> + '_ {
+    let transfer = H256::from(keccak256("Transfer(address,address,uint256)"));
+    logs.iter()
+        .filter(move |log| log.topics.len() == 3 && log.topics[0] == transfer)
+        .map(|log| Address::from(log.topics[2]))
+}
+
+#[derive(Error, Debug)]
+pub enum DenylistError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Line {line}: invalid address {value}")]
+    InvalidAddress { line: usize, value: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Role {
+    VictimSender,
+    Recipient,
+    /// contract the victim calls, e.g. a router
+    Contract,
+    Pool,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::VictimSender => "victim_sender",
+            Role::Recipient => "recipient",
+            Role::Contract => "contract",
+            Role::Pool => "pool",
+        })
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{role} {address:?} is denylisted")]
+pub struct ComplianceViolation {
+    pub address: Address,
+    pub role: Role,
+    pub source: Option<String>,
+}
+
+/// Restricted addresses, with the list each came from
+#[derive(Debug, Clone, Default)]
+pub struct Denylist {
+    entries: HashMap<Address, Option<String>>,
+}
+
+impl Denylist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads one address per line, `#` starts a comment. Entries are attributed to the file name.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, DenylistError> {
+        let path = path.as_ref();
+        let source = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        let mut list = Self::new();
+        list.extend_from_str(&fs::read_to_string(path)?, source)?;
+        Ok(list)
+    }
+
+    pub fn extend_from_str(
+        &mut self,
+        contents: &str,
+        source: Option<String>,
+    ) -> Result<(), DenylistError> {
+        for (index, line) in contents.lines().enumerate() {
+            let value = line.split('#').next().unwrap_or_default().trim();
+            if value.is_empty() {
+                continue;
+            }
+            let address = value
+                .parse::<Address>()
+                .map_err(|_| DenylistError::InvalidAddress {
+                    line: index + 1,
+                    value: value.to_string(),
+                })?;
+            self.entries.insert(address, source.clone());
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, address: Address, source: Option<String>) {
+        self.entries.insert(address, source);
+    }
+
+    pub fn contains(&self, address: &Address) -> bool {
+        self.entries.contains_key(address)
+    }
+
+    /// Where the entry for `address` came from, `None` if it isn't listed
+    pub fn source(&self, address: &Address) -> Option<Option<&str>> {
+        self.entries.get(address).map(|source| source.as_deref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Everyone a bundle touches
+#[derive(Debug, Clone, Default)]
+pub struct BundleParticipants {
+    pub participants: Vec<(Role, Address)>,
+}
+
+impl BundleParticipants {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The victim's sender, the contract it calls and, for router swaps, whoever receives the
+    /// output, see [swap_recipient]. Add the recipients of other calls with
+    /// [BundleParticipants::with_transfers] once the victim is simulated.
+    pub fn with_victim(mut self, victim: &Transaction) -> Self {
+        self.participants.push((Role::VictimSender, victim.from));
+        if let Some(to) = victim.to {
+            self.participants.push((Role::Contract, to));
+        }
+        if let Some(recipient) = swap_recipient(victim) {
+            self.participants.push((Role::Recipient, recipient));
+        }
+        self
+    }
+
+    /// Everyone receiving tokens in `logs`, e.g. the victim's simulated logs
+    pub fn with_transfers(mut self, logs: &[Log]) -> Self {
+        self.participants
+            .extend(transfer_recipients(logs).map(|recipient| (Role::Recipient, recipient)));
+        self
+    }
+
+    pub fn with_recipient(mut self, recipient: Address) -> Self {
+        self.participants.push((Role::Recipient, recipient));
+        self
+    }
+
+    pub fn with_pools(mut self, pools: impl IntoIterator<Item = Address>) -> Self {
+        self.participants
+            .extend(pools.into_iter().map(|pool| (Role::Pool, pool)));
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ComplianceGuard {
+    denylist: Arc<Denylist>,
+    events: Option<Arc<EventLog>>,
+    strategy: String,
+}
+
+impl ComplianceGuard {
+    pub fn new(denylist: Arc<Denylist>, strategy: impl Into<String>) -> Self {
+        Self {
+            denylist,
+            events: None,
+            strategy: strategy.into(),
+        }
+    }
+
+    /// Audit rejections to `events`
+    pub fn with_event_log(mut self, events: Arc<EventLog>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Fails on the first denylisted participant, every rejection is audited
+    pub fn check(
+        &self,
+        opportunity_id: &str,
+        block: u64,
+        participants: &BundleParticipants,
+    ) -> Result<(), ComplianceViolation> {
+        for (role, address) in &participants.participants {
+            let Some(source) = self.denylist.source(address) else {
+                continue;
+            };
+            let violation = ComplianceViolation {
+                address: *address,
+                role: *role,
+                source: source.map(str::to_string),
+            };
+            warn!(
+                "Refusing bundle for opportunity {} at block {}: {}",
+                opportunity_id, block, violation
+            );
+            if let Some(events) = &self.events {
+                events.emit(
+                    &self.strategy,
+                    OpportunityEvent::ComplianceRejected {
+                        opportunity_id: opportunity_id.to_string(),
+                        block,
+                        address: violation.address,
+                        role: violation.role.to_string(),
+                        source: violation.source.clone(),
+                    },
+                );
+            }
+            return Err(violation);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_log::{EventLogError, EventRecord, EventSink};
+    use parking_lot::Mutex;
+
+    #[derive(Clone, Default)]
+    struct MemSink(Arc<Mutex<Vec<serde_json::Value>>>);
+
+    impl EventSink for MemSink {
+        fn write(&self, record: &EventRecord) -> Result<(), EventLogError> {
+            self.0.lock().push(serde_json::to_value(record)?);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_denylisted_participant_is_rejected() {
+        let sanctioned = Address::from_low_u64_be(0xdead);
+        let mut denylist = Denylist::new();
+        denylist
+            .extend_from_str(
+                &format!("# sdn\n{:?} # mixer\n\n", sanctioned),
+                Some("sdn.txt".into()),
+            )
+            .unwrap();
+        assert!(matches!(
+            denylist.extend_from_str("0x12", None),
+            Err(DenylistError::InvalidAddress { line: 1, .. })
+        ));
+
+        let sink = MemSink::default();
+        let guard = ComplianceGuard::new(Arc::new(denylist), "sandwich")
+            .with_event_log(Arc::new(EventLog::new().with_sink(sink.clone())));
+
+        let victim = Transaction {
+            from: Address::from_low_u64_be(1),
+            to: Some(Address::from_low_u64_be(2)),
+            ..Default::default()
+        };
+        let clean = BundleParticipants::new()
+            .with_victim(&victim)
+            .with_pools([Address::from_low_u64_be(3)]);
+        assert!(guard.check("0x01", 1, &clean).is_ok());
+
+        let tainted = clean.with_recipient(sanctioned);
+        assert_eq!(
+            guard.check("0x02", 1, &tainted),
+            Err(ComplianceViolation {
+                address: sanctioned,
+                role: Role::Recipient,
+                source: Some("sdn.txt".into()),
+            })
+        );
+        let audit = sink.0.lock();
+        assert_eq!(audit.len(), 1);
+        assert_eq!(audit[0]["kind"], "compliance_rejected");
+        assert_eq!(audit[0]["role"], "recipient");
+    }
+
+    #[test]
+    fn test_swap_recipient() {
+        let (sender, router, recipient) = (
+            Address::from_low_u64_be(0x10),
+            Address::from_low_u64_be(0x20),
+            Address::from_low_u64_be(0x30),
+        );
+        let call = |signature: &str, params: &[Token]| Transaction {
+            from: sender,
+            to: Some(router),
+            input: [&id(signature)[..], &ethers::abi::encode(params)]
+                .concat()
+                .into(),
+            ..Default::default()
+        };
+        let path = Token::Array(vec![
+            Token::Address(Address::from_low_u64_be(0x40)),
+            Token::Address(Address::from_low_u64_be(0x50)),
+        ]);
+
+        let v2 = call(
+            "swapExactTokensForTokens(uint256,uint256,address[],address,uint256)",
+            &[
+                Token::Uint(1.into()),
+                Token::Uint(0.into()),
+                path,
+                Token::Address(recipient),
+                Token::Uint(u64::MAX.into()),
+            ],
+        );
+        assert_eq!(swap_recipient(&v2), Some(recipient));
+
+        // SwapRouter02 paying the caller
+        let v3 = call(
+            "exactInputSingle((address,address,uint24,address,uint256,uint256,uint160))",
+            &[Token::Tuple(vec![
+                Token::Address(Address::from_low_u64_be(0x40)),
+                Token::Address(Address::from_low_u64_be(0x50)),
+                Token::Uint(500.into()),
+                Token::Address(Address::from_low_u64_be(MSG_SENDER)),
+                Token::Uint(1.into()),
+                Token::Uint(0.into()),
+                Token::Uint(0.into()),
+            ])],
+        );
+        assert_eq!(swap_recipient(&v3), Some(sender));
+
+        let unknown = call("deposit()", &[]);
+        assert_eq!(swap_recipient(&unknown), None);
+        let participants = BundleParticipants::new().with_victim(&v2);
+        assert_eq!(
+            participants.participants,
+            vec![
+                (Role::VictimSender, sender),
+                (Role::Contract, router),
+                (Role::Recipient, recipient),
+            ]
+        );
+
+        // SwapRouter02 `swapTokensForExactTokens`, 1 WETH for at most 1900 USDC
+        let router02 = Transaction {
+            from: sender,
+            to: Some(router),
+            input: ethers::utils::hex::decode(concat!(
+                "42712a67",
+                "0000000000000000000000000000000000000000000000000de0b6b3a7640000",
+                "00000000000000000000000000000000000000000000000000000000713fb300",
+                "0000000000000000000000000000000000000000000000000000000000000080",
+                "0000000000000000000000006b75d8af000000e20b7a7ddf000ba900b4009a80",
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "000000000000000000000000a0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+                "000000000000000000000000c02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+            ))
+            .unwrap()
+            .into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            swap_recipient(&router02),
+            Some(
+                "0x6b75d8AF000000e20B7a7DDf000Ba900b4009A80"
+                    .parse()
+                    .unwrap()
+            )
+        );
+
+        // falls back to who the victim's simulation paid
+        let transfer = Log {
+            topics: vec![
+                H256::from(keccak256("Transfer(address,address,uint256)")),
+                H256::from(router),
+                H256::from(recipient),
+            ],
+            ..Default::default()
+        };
+        let participants = BundleParticipants::new()
+            .with_victim(&unknown)
+            .with_transfers(&[transfer]);
+        assert!(participants
+            .participants
+            .contains(&(Role::Recipient, recipient)));
+    }
+}
@@ -0,0 +1,186 @@
+//! Predicting whether pending txs invalidate our bundle
+//!
+//! A bundle is simulated on top of the current state, but the builder is free to place other
+//! pending txs before it. Any of them writing a slot our bundle also writes (pool reserves, a token
+//! balance we move, our own nonce) changes what the bundle sees and can make it revert. The write
+//! sets come from the state diffs of the traced txs, the chance each pending tx lands ahead of us
+//! is combined into the probability the bundle gets invalidated, which the
+//! [bribe](crate::competition::BribePolicy::decide_with_conflicts) and
+//! [submission](crate::submission::SubmissionPolicy) policies take into account.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use ethers::types::{AccountDiff, Address, Diff, H256};
+
+/// What a tx or bundle writes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteSet {
+    pub slots: BTreeSet<(Address, H256)>,
+    /// accounts whose nonce changes, i.e. the senders
+    pub nonces: BTreeSet<Address>,
+}
+
+impl WriteSet {
+    /// Writes of a state diff. Balances are left out, every tx changes the coinbase's and a
+    /// conflicting ETH transfer rarely breaks a bundle.
+    pub fn from_diff(diff: &BTreeMap<Address, AccountDiff>) -> Self {
+        let mut set = Self::default();
+        for (address, account) in diff {
+            if !matches!(account.nonce, Diff::Same) {
+                set.nonces.insert(*address);
+            }
+            for (slot, value) in &account.storage {
+                if !matches!(value, Diff::Same) {
+                    set.slots.insert((*address, *slot));
+                }
+            }
+        }
+        set
+    }
+
+    /// Writes of a bundle, one diff per tx
+    pub fn from_diffs<'a>(
+        diffs: impl IntoIterator<Item = &'a BTreeMap<Address, AccountDiff>>,
+    ) -> Self {
+        let mut set = Self::default();
+        for diff in diffs {
+            set.extend(Self::from_diff(diff));
+        }
+        set
+    }
+
+    pub fn extend(&mut self, other: WriteSet) {
+        self.slots.extend(other.slots);
+        self.nonces.extend(other.nonces);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty() && self.nonces.is_empty()
+    }
+
+    /// Slots and nonces both sets write
+    pub fn overlap(&self, other: &WriteSet) -> WriteSet {
+        WriteSet {
+            slots: self.slots.intersection(&other.slots).copied().collect(),
+            nonces: self.nonces.intersection(&other.nonces).copied().collect(),
+        }
+    }
+}
+
+/// A known pending tx competing for the same block
+#[derive(Debug, Clone)]
+pub struct PendingWrites {
+    pub hash: H256,
+    pub writes: WriteSet,
+    /// chance the tx makes it into the target block, e.g. from its priority fee
+    pub inclusion: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConflictReport {
+    /// chance at least one conflicting tx lands ahead of the bundle, between 0 and 1
+    pub invalidation: f64,
+    /// pending txs writing what the bundle writes, with the overlap
+    pub conflicts: Vec<(H256, WriteSet)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ConflictAnalyzer {
+    /// chance a conflicting tx in the same block is placed before the bundle. Bundles usually
+    /// land near the top, so lower than a coin flip.
+    pub placed_before: f64,
+}
+
+impl Default for ConflictAnalyzer {
+    fn default() -> Self {
+        Self { placed_before: 0.3 }
+    }
+}
+
+impl ConflictAnalyzer {
+    /// Chance `pending` txs invalidate a bundle writing `ours`, assuming they land independently
+    pub fn analyze(&self, ours: &WriteSet, pending: &[PendingWrites]) -> ConflictReport {
+        let mut survives = 1.0;
+        let mut conflicts = vec![];
+        for tx in pending {
+            let overlap = ours.overlap(&tx.writes);
+            if overlap.is_empty() {
+                continue;
+            }
+            survives *= 1.0 - tx.inclusion.clamp(0.0, 1.0) * self.placed_before.clamp(0.0, 1.0);
+            conflicts.push((tx.hash, overlap));
+        }
+        ConflictReport {
+            invalidation: 1.0 - survives,
+            conflicts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{ChangedType, U256};
+
+    fn diff(address: Address, slots: &[u64], sender: bool) -> BTreeMap<Address, AccountDiff> {
+        let changed = |from: u64, to: u64| {
+            Diff::Changed(ChangedType {
+                from: H256::from_low_u64_be(from),
+                to: H256::from_low_u64_be(to),
+            })
+        };
+        let account = AccountDiff {
+            balance: Diff::Same,
+            nonce: if sender {
+                Diff::Changed(ChangedType {
+                    from: U256::zero(),
+                    to: U256::one(),
+                })
+            } else {
+                Diff::Same
+            },
+            code: Diff::Same,
+            storage: slots
+                .iter()
+                .map(|slot| (H256::from_low_u64_be(*slot), changed(0, 1)))
+                .collect(),
+        };
+        BTreeMap::from([(address, account)])
+    }
+
+    #[test]
+    fn test_invalidation_probability() {
+        let pool = Address::from_low_u64_be(1);
+        let ours = WriteSet::from_diff(&diff(pool, &[8], false));
+        let pending = vec![
+            PendingWrites {
+                hash: H256::from_low_u64_be(1),
+                writes: WriteSet::from_diff(&diff(pool, &[8, 9], true)),
+                inclusion: 1.0,
+            },
+            // another pool, no conflict
+            PendingWrites {
+                hash: H256::from_low_u64_be(2),
+                writes: WriteSet::from_diff(&diff(Address::from_low_u64_be(2), &[8], false)),
+                inclusion: 1.0,
+            },
+            PendingWrites {
+                hash: H256::from_low_u64_be(3),
+                writes: WriteSet::from_diff(&diff(pool, &[8], false)),
+                inclusion: 0.5,
+            },
+        ];
+
+        let analyzer = ConflictAnalyzer { placed_before: 0.5 };
+        let report = analyzer.analyze(&ours, &pending);
+        // 1 - (1 - 0.5) * (1 - 0.25)
+        assert!((report.invalidation - 0.625).abs() < 1e-9);
+        assert_eq!(report.conflicts.len(), 2);
+        assert_eq!(
+            report.conflicts[0].1.slots,
+            BTreeSet::from([(pool, H256::from_low_u64_be(8))])
+        );
+
+        assert_eq!(analyzer.analyze(&ours, &pending[1..2]).invalidation, 0.0);
+    }
+}
@@ -0,0 +1,487 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use artemis::types::Strategy;
+use async_trait::async_trait;
+use collectors::cow_collector::{CowOrder, OrderKind};
+use ethers::{
+    providers::Middleware,
+    types::{Address, U256},
+};
+use log::debug;
+use qilin_cfmms::{
+    address_book::AddressBook, batch_requests::uniswap_v3::UniswapV3TickData, pool::Pool,
+    registry::PoolRegistry,
+};
+
+use crate::compliance::{BundleParticipants, ComplianceGuard};
+use crate::pricing::{pools_for_pair, quote_exact_in, quote_exact_out};
+use crate::scorer::{OpportunityScorer, Payouts};
+use crate::split_route::{plan_split, SplitLeg, SplitRoute, DEFAULT_SPLIT_PARTS};
+use crate::types::{Action, Event};
+
+/// A CoW order that can be settled against our pools with surplus left over
+#[derive(Debug, Clone)]
+pub struct CowOpportunity {
+    pub order: CowOrder,
+    /// pools the `sell_token` goes through, a single one unless splitting a sell order pays
+    pub route: SplitRoute,
+    /// what the order gets on top of its limit price, in [CowOpportunity::surplus_token]: the
+    /// extra buy token for sell orders, the unspent sell token for buy orders
+    pub surplus: U256,
+    /// the order's `fee_amount` of `sell_token`, paid by the owner on top of `sell_amount` and
+    /// kept by the settlement to cover its gas
+    pub fee: U256,
+}
+
+impl CowOpportunity {
+    /// amount of `sell_token` routed through the pools, the full `sell_amount` for sell orders
+    pub fn amount_in(&self) -> U256 {
+        self.route.amount_in()
+    }
+
+    /// amount of `buy_token` the pools return, the exact `buy_amount` for buy orders
+    pub fn amount_out(&self) -> U256 {
+        self.route.amount_out()
+    }
+
+    pub fn surplus_token(&self) -> Address {
+        match self.order.kind {
+            OrderKind::Sell => self.order.buy_token,
+            OrderKind::Buy => self.order.sell_token,
+        }
+    }
+}
+
+/// Solver-style matcher, routes CoW orders through a single hop of the pool graph, splitting sell
+/// orders across the pools of their pair when that gets more out
+pub struct CowMatcher<M> {
+    provider: Arc<M>,
+    /// orders expiring before `now + min_validity` seconds are skipped
+    pub min_validity: u32,
+    /// orders whose `fee_amount` is below this, in sell token, don't pay for their settlement
+    pub min_fee: U256,
+    /// gas of one more leg, see [crate::split_route::LEG_GAS], in sell token
+    pub leg_cost: U256,
+    /// Uniswap V3 QuoterV2 buy orders on V3 pools are quoted exact-out against
+    pub quoter: Address,
+}
+
+impl<M> CowMatcher<M>
+where
+    M: Middleware + 'static,
+{
+    pub fn new(provider: Arc<M>, min_validity: u32) -> Self {
+        Self {
+            provider,
+            min_validity,
+            min_fee: U256::zero(),
+            leg_cost: U256::zero(),
+            quoter: AddressBook::mainnet()
+                .quoters
+                .uniswap_v3_v2
+                .map(|quoter| quoter.address)
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn with_min_fee(mut self, min_fee: U256) -> Self {
+        self.min_fee = min_fee;
+        self
+    }
+
+    pub fn with_leg_cost(mut self, leg_cost: U256) -> Self {
+        self.leg_cost = leg_cost;
+        self
+    }
+
+    pub fn with_quoter(mut self, quoter: Address) -> Self {
+        self.quoter = quoter;
+        self
+    }
+
+    /// Find the pool giving the best settlement for `order`, if any pool beats its limit price.
+    /// Sell orders are quoted exact-in for their `sell_amount`, buy orders exact-out for their
+    /// `buy_amount` with at most `sell_amount` in. The pair's pools are read from the last view of
+    /// `registry`, with their ticks.
+    pub async fn match_order(
+        &self,
+        order: &CowOrder,
+        registry: &PoolRegistry,
+        now: u32,
+    ) -> Option<CowOpportunity> {
+        let candidates = pools_for_pair(&registry.view(), order.sell_token, order.buy_token)
+            .into_iter()
+            .map(|pool| {
+                let ticks = registry.ticks(&pool.address).unwrap_or_default();
+                (pool, ticks)
+            })
+            .collect();
+        self.match_against(order, candidates, now).await
+    }
+
+    /// [CowMatcher::match_order] against the `candidates` trading the order's pair, V3 pools with
+    /// their initialized ticks
+    pub async fn match_against(
+        &self,
+        order: &CowOrder,
+        candidates: Vec<(Pool, Vec<UniswapV3TickData>)>,
+        now: u32,
+    ) -> Option<CowOpportunity> {
+        if order.valid_to < now.saturating_add(self.min_validity) {
+            return None;
+        }
+
+        // only fill-or-kill orders are settled for now, partial fills need a sizing pass
+        if order.partially_fillable {
+            return None;
+        }
+
+        if order.fee_amount < self.min_fee {
+            return None;
+        }
+
+        let mut best: Option<CowOpportunity> = None;
+        for (pool, _) in &candidates {
+            let Some(opportunity) = self.settle_through(order, *pool).await else {
+                continue;
+            };
+
+            debug!(
+                "CoW order {} ({:?}) settles through {:?} for {} in, {} out",
+                order.uid,
+                order.kind,
+                pool.address,
+                opportunity.amount_in(),
+                opportunity.amount_out()
+            );
+
+            if best
+                .as_ref()
+                .map_or(true, |b| opportunity.surplus > b.surplus)
+            {
+                best = Some(opportunity);
+            }
+        }
+
+        match (order.kind, best) {
+            (OrderKind::Sell, Some(single)) if candidates.len() > 1 => {
+                Some(self.split_sell(order, &candidates, single))
+            }
+            (_, best) => best,
+        }
+    }
+
+    /// `single` or, if spreading the sell order across `candidates` gets more out net of the extra
+    /// legs' [CowMatcher::leg_cost], the split
+    fn split_sell(
+        &self,
+        order: &CowOrder,
+        candidates: &[(Pool, Vec<UniswapV3TickData>)],
+        single: CowOpportunity,
+    ) -> CowOpportunity {
+        // the leg cost in buy token, at the single pool's price
+        let leg_cost = self.leg_cost * single.amount_out() / order.sell_amount.max(U256::one());
+        let Some(route) = plan_split(
+            candidates,
+            order.sell_token,
+            order.sell_amount,
+            leg_cost,
+            DEFAULT_SPLIT_PARTS,
+        ) else {
+            return single;
+        };
+        let extra_legs = U256::from(route.legs.len().saturating_sub(1));
+        if route.legs.len() < 2
+            || route.amount_out() <= order.buy_amount
+            || route.amount_out().saturating_sub(leg_cost * extra_legs) <= single.amount_out()
+        {
+            return single;
+        }
+        debug!(
+            "CoW order {} split across {} pools for {} out",
+            order.uid,
+            route.legs.len(),
+            route.amount_out()
+        );
+        CowOpportunity {
+            surplus: route.amount_out() - order.buy_amount,
+            route,
+            ..single
+        }
+    }
+
+    async fn settle_through(&self, order: &CowOrder, pool: Pool) -> Option<CowOpportunity> {
+        let (amount_in, amount_out, surplus) = match order.kind {
+            OrderKind::Sell => {
+                let amount_out = quote_exact_in(
+                    &pool,
+                    order.sell_token,
+                    order.sell_amount,
+                    self.provider.clone(),
+                )
+                .await?;
+                if amount_out <= order.buy_amount {
+                    return None;
+                }
+                (order.sell_amount, amount_out, amount_out - order.buy_amount)
+            }
+            OrderKind::Buy => {
+                let amount_in = quote_exact_out(
+                    &pool,
+                    order.sell_token,
+                    order.buy_amount,
+                    order.sell_amount,
+                    self.quoter,
+                    self.provider.clone(),
+                )
+                .await?;
+                if amount_in >= order.sell_amount {
+                    return None;
+                }
+                (amount_in, order.buy_amount, order.sell_amount - amount_in)
+            }
+        };
+
+        Some(CowOpportunity {
+            order: order.clone(),
+            route: SplitRoute {
+                token_in: order.sell_token,
+                legs: vec![SplitLeg {
+                    pool,
+                    amount_in,
+                    amount_out,
+                }],
+            },
+            surplus,
+            fee: order.fee_amount,
+        })
+    }
+}
+
+/// Matches every [Event::NewCowOrder] against the pool graph and emits the settlements found
+pub struct CowStrategy<M> {
+    pub matcher: CowMatcher<M>,
+    pub registry: Arc<PoolRegistry>,
+    /// refuses settlements whose owner or pools are denylisted, none by default
+    pub compliance: Option<ComplianceGuard>,
+    /// drops settlements whose fee isn't worth it once valued in WETH, or routed through pools too
+    /// shallow for their legs, none by default
+    pub scorer: Option<Arc<OpportunityScorer<M>>>,
+    /// last block seen, for the audit trail
+    block: u64,
+}
+
+impl<M> CowStrategy<M> {
+    pub fn new(matcher: CowMatcher<M>, registry: Arc<PoolRegistry>) -> Self {
+        Self {
+            matcher,
+            registry,
+            compliance: None,
+            scorer: None,
+            block: 0,
+        }
+    }
+
+    /// Check every settlement's participants against `guard` before it's emitted
+    pub fn with_compliance(mut self, guard: ComplianceGuard) -> Self {
+        self.compliance = Some(guard);
+        self
+    }
+
+    /// Only settle orders whose fee `scorer` values at its `min_profit` or more, on pools deep
+    /// enough for the route
+    pub fn with_scorer(mut self, scorer: Arc<OpportunityScorer<M>>) -> Self {
+        self.scorer = Some(scorer);
+        self
+    }
+
+    /// Whether the compliance guard, if any, lets `opportunity` be settled
+    fn is_compliant(&self, opportunity: &CowOpportunity) -> bool {
+        let Some(guard) = &self.compliance else {
+            return true;
+        };
+        let participants = BundleParticipants::new()
+            .with_recipient(opportunity.order.owner)
+            .with_pools(opportunity.route.legs.iter().map(|leg| leg.pool.address));
+        guard
+            .check(&opportunity.order.uid, self.block, &participants)
+            .is_ok()
+    }
+}
+
+#[async_trait]
+impl<M> Strategy<Event, Action> for CowStrategy<M>
+where
+    M: Middleware + 'static,
+{
+    async fn sync_state(&mut self) -> anyhow::Result<()> {
+        // pools are synced by the setup
+        Ok(())
+    }
+
+    async fn process_event(&mut self, event: Event) -> Vec<Action> {
+        let order = match event {
+            Event::NewCowOrder(order) => order,
+            Event::NewBlock(payload) => {
+                if let Some(number) = payload.block_hash.number {
+                    self.block = number.as_u64();
+                }
+                return vec![];
+            }
+            _ => return vec![],
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() as u32)
+            .unwrap_or_default();
+        let Some(opportunity) = self
+            .matcher
+            .match_order(&order, &self.registry, now)
+            .await
+            .filter(|opportunity| self.is_compliant(opportunity))
+        else {
+            return vec![];
+        };
+        if let Some(scorer) = &self.scorer {
+            // the settlement keeps the order's fee, its gas is paid by the batch
+            let payouts = opportunity.route.legs.iter().fold(
+                Payouts::new(U256::zero()).with(order.sell_token, opportunity.fee),
+                |payouts, leg| {
+                    let zero_for_one = leg.pool.token_0 == order.sell_token;
+                    payouts.through(leg.pool, zero_for_one, leg.amount_in)
+                },
+            );
+            match scorer.score(&payouts, &self.registry).await {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    debug!("CoW order {} isn't worth settling", order.uid);
+                    return vec![];
+                }
+                Err(e) => {
+                    debug!(
+                        "Not settling CoW order {}, fee not priced: {}",
+                        order.uid, e
+                    );
+                    return vec![];
+                }
+            }
+        }
+        vec![Action::SettleCowOrder(opportunity)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::{Http, Provider};
+    use qilin_cfmms::test_utils::{address, v2_pool};
+
+    fn order(kind: OrderKind, sell_amount: U256, buy_amount: U256) -> CowOrder {
+        CowOrder {
+            uid: "0x01".into(),
+            owner: Address::from_low_u64_be(9),
+            sell_token: Address::from_low_u64_be(1),
+            buy_token: Address::from_low_u64_be(2),
+            sell_amount,
+            buy_amount,
+            fee_amount: U256::exp10(15),
+            kind,
+            partially_fillable: false,
+            valid_to: 1_000,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_match_order() {
+        let provider = Arc::new(Provider::<Http>::try_from("http://localhost:8545").unwrap());
+        let matcher = CowMatcher::new(provider, 0);
+        let reserve = U256::exp10(18) * 1000;
+        let pools = vec![(
+            v2_pool(address(0xfee), address(1), address(2), (reserve, reserve)),
+            vec![],
+        )];
+        let ether = U256::exp10(18);
+
+        // sells exactly 1, the extra buy token is the surplus
+        let sell = order(OrderKind::Sell, ether, ether * 99 / 100);
+        let matched = matcher
+            .match_against(&sell, pools.clone(), 0)
+            .await
+            .unwrap();
+        assert_eq!(matched.amount_in(), ether);
+        assert_eq!(matched.surplus, matched.amount_out() - sell.buy_amount);
+        assert_eq!(matched.surplus_token(), sell.buy_token);
+        assert_eq!(matched.fee, sell.fee_amount);
+
+        // buys exactly 0.99, the unspent sell token is the surplus
+        let buy = order(OrderKind::Buy, ether, ether * 99 / 100);
+        let matched = matcher.match_against(&buy, pools.clone(), 0).await.unwrap();
+        assert_eq!(matched.amount_out(), buy.buy_amount);
+        assert_eq!(
+            Some(matched.amount_in()),
+            qilin_math::v2::get_amount_in(buy.buy_amount, reserve, reserve)
+        );
+        assert_eq!(matched.surplus, buy.sell_amount - matched.amount_in());
+        assert_eq!(matched.surplus_token(), buy.sell_token);
+
+        // the limit price isn't reached
+        let greedy = order(OrderKind::Buy, ether, ether);
+        assert!(matcher
+            .match_against(&greedy, pools.clone(), 0)
+            .await
+            .is_none());
+
+        // a second pool of the pair takes part of a large sell order
+        let mut deeper = pools.clone();
+        let second = v2_pool(address(7), address(1), address(2), (reserve, reserve));
+        deeper.push((second, vec![]));
+        let large = order(OrderKind::Sell, ether * 100, ether * 50);
+        let single = matcher
+            .match_against(&large, pools.clone(), 0)
+            .await
+            .unwrap();
+        let split = matcher
+            .match_against(&large, deeper.clone(), 0)
+            .await
+            .unwrap();
+        assert_eq!(split.route.legs.len(), 2);
+        assert_eq!(split.amount_in(), large.sell_amount);
+        assert!(split.surplus > single.surplus);
+        // unless the extra leg costs more than it gains
+        let costly = CowMatcher::new(matcher.provider.clone(), 0).with_leg_cost(ether * 20);
+        let kept = costly.match_against(&large, deeper, 0).await.unwrap();
+        assert_eq!(kept.route.legs.len(), 1);
+
+        // the fee doesn't pay for the settlement
+        let matcher = matcher.with_min_fee(U256::exp10(16));
+        assert!(matcher.match_against(&buy, pools, 0).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_denylisted_owner_is_not_settled() {
+        use crate::compliance::Denylist;
+
+        let provider = Arc::new(Provider::<Http>::try_from("http://localhost:8545").unwrap());
+        let reserve = U256::exp10(18) * 1000;
+        let pools = vec![(
+            v2_pool(address(0xfee), address(1), address(2), (reserve, reserve)),
+            vec![],
+        )];
+        let ether = U256::exp10(18);
+        let sell = order(OrderKind::Sell, ether, ether * 99 / 100);
+        let mut denylist = Denylist::new();
+        denylist.insert(sell.owner, None);
+
+        let strategy =
+            CowStrategy::new(CowMatcher::new(provider, 0), Arc::new(PoolRegistry::new()));
+        let matched = strategy
+            .matcher
+            .match_against(&sell, pools, 0)
+            .await
+            .unwrap();
+        assert!(strategy.is_compliant(&matched));
+        let strategy = strategy.with_compliance(ComplianceGuard::new(Arc::new(denylist), "cow"));
+        assert!(!strategy.is_compliant(&matched));
+    }
+}
@@ -0,0 +1,346 @@
+//! Cross-chain price dislocations, detection only
+//!
+//! The same asset trades on several chains, each read through its own fork backend. When the price
+//! on one chain exceeds another's by more than it costs to bridge the asset over, the difference
+//! can be captured by buying on the cheap chain and selling on the expensive one. Bridging takes
+//! minutes, so nothing is executed: [CrossChainMonitor::scan] reports the [Dislocation]s and
+//! writes them to the [EventLog] for someone to act on.
+
+use std::{collections::HashMap, sync::Arc};
+
+use ethers::types::{Address, U256};
+use fork_database::{
+    storage_layout::{unpack, Field, V2_RESERVES, V2_RESERVES_SLOT, V3_SLOT0, V3_SLOT0_SLOT},
+    utils::{h160_to_b160, ru256_to_u256, u256_to_ru256},
+};
+use log::{info, warn};
+use qilin_cfmms::pool::PoolVariant;
+use revm::db::DatabaseRef;
+use thiserror::Error;
+
+use crate::{
+    arb::u256_2_f64,
+    event_log::{EventLog, OpportunityEvent},
+};
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum CrossChainError {
+    #[error("No backend for chain {0}")]
+    UnknownChain(String),
+    #[error("Database error: {0}")]
+    Database(String),
+    #[error("Pool {0:?} has no liquidity")]
+    EmptyPool(Address),
+}
+
+/// A pool quoting the asset on one chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Venue {
+    pub chain: String,
+    pub pool: Address,
+    pub variant: PoolVariant,
+    /// whether the asset is `token0` of the pool, the other token is the quote
+    pub asset_is_token_0: bool,
+    pub asset_decimals: u8,
+    pub quote_decimals: u8,
+}
+
+impl Venue {
+    pub fn new(chain: impl Into<String>, pool: Address, variant: PoolVariant) -> Self {
+        Self {
+            chain: chain.into(),
+            pool,
+            variant,
+            asset_is_token_0: true,
+            asset_decimals: 18,
+            quote_decimals: 18,
+        }
+    }
+
+    pub fn with_asset_token_1(mut self) -> Self {
+        self.asset_is_token_0 = false;
+        self
+    }
+
+    pub fn with_decimals(mut self, asset_decimals: u8, quote_decimals: u8) -> Self {
+        self.asset_decimals = asset_decimals;
+        self.quote_decimals = quote_decimals;
+        self
+    }
+}
+
+/// What moving the asset from one chain to another costs
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BridgeCost {
+    /// bridge fee and slippage, relative to the amount bridged
+    pub cost_bps: f64,
+    /// gas on both chains and flat bridge fees, in quote units
+    pub fixed_cost: f64,
+}
+
+/// Asset bought on `buy_chain` and sold on `sell_chain`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dislocation {
+    pub asset: String,
+    pub buy_chain: String,
+    pub sell_chain: String,
+    /// quote per asset
+    pub buy_price: f64,
+    pub sell_price: f64,
+    pub spread_bps: f64,
+    /// spread left after bridging [CrossChainMonitor::trade_size] of the asset
+    pub net_bps: f64,
+}
+
+impl Dislocation {
+    pub fn opportunity_id(&self) -> String {
+        format!("{}:{}->{}", self.asset, self.buy_chain, self.sell_chain)
+    }
+}
+
+pub struct CrossChainMonitor<DB> {
+    backends: HashMap<String, DB>,
+    assets: Vec<(String, Vec<Venue>)>,
+    bridges: HashMap<(String, String), BridgeCost>,
+    /// asset amount the fixed bridge costs are spread over
+    pub trade_size: f64,
+    event_log: Option<Arc<EventLog>>,
+}
+
+impl<DB> CrossChainMonitor<DB>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    pub fn new() -> Self {
+        Self {
+            backends: HashMap::new(),
+            assets: vec![],
+            bridges: HashMap::new(),
+            trade_size: 1.0,
+            event_log: None,
+        }
+    }
+
+    pub fn with_chain(mut self, chain: impl Into<String>, backend: DB) -> Self {
+        self.backends.insert(chain.into(), backend);
+        self
+    }
+
+    pub fn with_asset(mut self, asset: impl Into<String>, venues: Vec<Venue>) -> Self {
+        self.assets.push((asset.into(), venues));
+        self
+    }
+
+    /// Only chain pairs with a bridge are compared, in the direction of the bridge
+    pub fn with_bridge(
+        mut self,
+        from: impl Into<String>,
+        to: impl Into<String>,
+        cost: BridgeCost,
+    ) -> Self {
+        self.bridges.insert((from.into(), to.into()), cost);
+        self
+    }
+
+    pub fn with_trade_size(mut self, trade_size: f64) -> Self {
+        self.trade_size = trade_size;
+        self
+    }
+
+    pub fn with_event_log(mut self, event_log: Arc<EventLog>) -> Self {
+        self.event_log = Some(event_log);
+        self
+    }
+
+    /// Spot price of the asset in quote units, read from the venue's chain
+    pub fn price(&self, venue: &Venue) -> Result<f64, CrossChainError> {
+        let db = self
+            .backends
+            .get(&venue.chain)
+            .ok_or_else(|| CrossChainError::UnknownChain(venue.chain.clone()))?;
+        let storage = |slot: u64| {
+            db.storage(h160_to_b160(venue.pool), u256_to_ru256(U256::from(slot)))
+                .map(ru256_to_u256)
+                .map_err(|e| CrossChainError::Database(format!("{:?}", e)))
+        };
+        let member = |word: U256, field: &Field| unpack(word, field.offset * 8, field.ty.bits());
+
+        // token1 per token0, in raw units
+        let price_1_per_0 = match venue.variant {
+            PoolVariant::UniswapV2 => {
+                let word = storage(V2_RESERVES_SLOT)?;
+                let (reserve_0, reserve_1) =
+                    (member(word, &V2_RESERVES[0]), member(word, &V2_RESERVES[1]));
+                if reserve_0.is_zero() || reserve_1.is_zero() {
+                    return Err(CrossChainError::EmptyPool(venue.pool));
+                }
+                u256_2_f64(reserve_1) / u256_2_f64(reserve_0)
+            }
+            PoolVariant::UniswapV3 => {
+                let sqrt_price = member(storage(V3_SLOT0_SLOT)?, &V3_SLOT0[0]);
+                if sqrt_price.is_zero() {
+                    return Err(CrossChainError::EmptyPool(venue.pool));
+                }
+                (u256_2_f64(sqrt_price) / 2f64.powi(96)).powi(2)
+            }
+        };
+        let raw = if venue.asset_is_token_0 {
+            price_1_per_0
+        } else {
+            1.0 / price_1_per_0
+        };
+        Ok(raw * 10f64.powi(venue.asset_decimals as i32 - venue.quote_decimals as i32))
+    }
+
+    /// Compare the asset prices across chains and report every dislocation exceeding the bridge
+    /// costs, most profitable first. Venues that can't be read are skipped.
+    pub fn scan(&self) -> Vec<Dislocation> {
+        let mut found = vec![];
+        for (asset, venues) in &self.assets {
+            let prices: Vec<(&str, f64)> = venues
+                .iter()
+                .filter_map(|venue| match self.price(venue) {
+                    Ok(price) => Some((venue.chain.as_str(), price)),
+                    Err(e) => {
+                        warn!("Skipping {} on {}: {}", asset, venue.chain, e);
+                        None
+                    }
+                })
+                .collect();
+
+            for &(buy_chain, buy_price) in &prices {
+                for &(sell_chain, sell_price) in &prices {
+                    if buy_chain == sell_chain || sell_price <= buy_price {
+                        continue;
+                    }
+                    let key = (buy_chain.to_string(), sell_chain.to_string());
+                    let Some(bridge) = self.bridges.get(&key) else {
+                        continue;
+                    };
+                    let spread_bps = (sell_price / buy_price - 1.0) * 10_000.0;
+                    let fixed_bps = bridge.fixed_cost
+                        / (self.trade_size * buy_price).max(f64::MIN_POSITIVE)
+                        * 10_000.0;
+                    let net_bps = spread_bps - bridge.cost_bps - fixed_bps;
+                    if net_bps <= 0.0 {
+                        continue;
+                    }
+                    found.push(Dislocation {
+                        asset: asset.clone(),
+                        buy_chain: buy_chain.to_string(),
+                        sell_chain: sell_chain.to_string(),
+                        buy_price,
+                        sell_price,
+                        spread_bps,
+                        net_bps,
+                    });
+                }
+            }
+        }
+        found.sort_by(|a, b| b.net_bps.total_cmp(&a.net_bps));
+
+        for dislocation in &found {
+            info!(
+                "{} is {:.1} bps cheaper on {} than on {}, {:.1} bps after bridging",
+                dislocation.asset,
+                dislocation.spread_bps,
+                dislocation.buy_chain,
+                dislocation.sell_chain,
+                dislocation.net_bps
+            );
+            if let Some(event_log) = &self.event_log {
+                event_log.emit(
+                    "cross_chain",
+                    OpportunityEvent::CrossChainDislocation {
+                        opportunity_id: dislocation.opportunity_id(),
+                        asset: dislocation.asset.clone(),
+                        buy_chain: dislocation.buy_chain.clone(),
+                        sell_chain: dislocation.sell_chain.clone(),
+                        spread_bps: dislocation.spread_bps,
+                        net_bps: dislocation.net_bps,
+                    },
+                );
+            }
+        }
+        found
+    }
+}
+
+impl<DB> Default for CrossChainMonitor<DB>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fork_database::storage_layout::SlotValue;
+    use revm::db::{CacheDB, EmptyDB};
+
+    fn v2_chain(pool: Address, reserve_0: u128, reserve_1: u128) -> CacheDB<EmptyDB> {
+        let mut db = CacheDB::new(EmptyDB::default());
+        let word = V2_RESERVES[0].encode(U256::zero(), SlotValue::Uint(reserve_0.into()));
+        let word = V2_RESERVES[1].encode(word, SlotValue::Uint(reserve_1.into()));
+        db.insert_account_storage(
+            h160_to_b160(pool),
+            u256_to_ru256(U256::from(V2_RESERVES_SLOT)),
+            u256_to_ru256(word),
+        )
+        .unwrap();
+        db
+    }
+
+    #[test]
+    fn test_scan_flags_dislocations_above_bridge_cost() {
+        let pool = Address::from_low_u64_be(1);
+        let usdc = |amount: u128| amount * 1_000_000;
+        let eth = |amount: u128| amount * 10u128.pow(18);
+        // WETH / USDC, WETH is token1 and USDC has 6 decimals
+        let venue = |chain: &str| {
+            Venue::new(chain, pool, PoolVariant::UniswapV2)
+                .with_asset_token_1()
+                .with_decimals(18, 6)
+        };
+        let monitor = CrossChainMonitor::new()
+            .with_chain("mainnet", v2_chain(pool, usdc(2_000_000), eth(1_000)))
+            .with_chain("arbitrum", v2_chain(pool, usdc(2_020_000), eth(1_000)))
+            .with_chain("base", v2_chain(pool, usdc(2_001_000), eth(1_000)))
+            .with_asset(
+                "WETH",
+                vec![venue("mainnet"), venue("arbitrum"), venue("base")],
+            )
+            .with_bridge(
+                "mainnet",
+                "arbitrum",
+                BridgeCost {
+                    cost_bps: 10.0,
+                    fixed_cost: 20.0,
+                },
+            )
+            .with_bridge(
+                "mainnet",
+                "base",
+                BridgeCost {
+                    cost_bps: 10.0,
+                    fixed_cost: 0.0,
+                },
+            )
+            .with_trade_size(10.0);
+
+        let price = monitor.price(&venue("mainnet")).unwrap();
+        assert!((price - 2_000.0).abs() < 1e-6, "{}", price);
+
+        // 100 bps spread, minus 10 bps and $20 on a $20k trade. Base's 5 bps don't cover the fee.
+        let found = monitor.scan();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].opportunity_id(), "WETH:mainnet->arbitrum");
+        assert!((found[0].spread_bps - 100.0).abs() < 1e-6);
+        assert!((found[0].net_bps - 80.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,159 @@
+//! Making sure a victim is only worked on once across strategy instances and bot replicas
+//!
+//! Before building a bundle on a victim, a strategy [claims](DedupRegistry::claim) the victim's
+//! hash under its name. Claims expire after a TTL (a couple of blocks is enough, the victim is
+//! mined or dropped by then), so a crashed replica never blocks a victim for long.
+//! [MemoryDedup] covers strategies sharing a process, [RedisDedup] (behind the `redis` feature)
+//! replicas running anywhere.
+
+#[cfg(feature = "redis")]
+pub mod redis;
+
+#[cfg(feature = "redis")]
+pub use self::redis::RedisDedup;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use ethers::types::H256;
+use parking_lot::Mutex;
+use thiserror::Error;
+
+/// Claims live for about two mainnet blocks by default
+pub const DEFAULT_CLAIM_TTL: Duration = Duration::from_secs(24);
+
+#[derive(Error, Debug)]
+pub enum DedupError {
+    #[error("Registry unavailable: {0}")]
+    Backend(String),
+}
+
+#[async_trait]
+pub trait DedupRegistry: Debug + Send + Sync {
+    /// Claim `victim` for `strategy`, `false` if another instance already holds it. Claiming
+    /// again from the same instance succeeds and extends the claim.
+    async fn claim(&self, victim: H256, strategy: &str) -> Result<bool, DedupError>;
+
+    /// Give the claim up early, e.g. when the opportunity turned out unprofitable
+    async fn release(&self, victim: H256, strategy: &str) -> Result<(), DedupError>;
+}
+
+/// In-process registry
+#[derive(Debug)]
+pub struct MemoryDedup {
+    instance: String,
+    ttl: Duration,
+    claims: Mutex<HashMap<(H256, String), (String, Instant)>>,
+}
+
+impl MemoryDedup {
+    /// `instance` identifies the claimant, strategies sharing a registry need distinct ones
+    pub fn new(instance: impl Into<String>) -> Self {
+        Self {
+            instance: instance.into(),
+            ttl: DEFAULT_CLAIM_TTL,
+            claims: Default::default(),
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// A handle claiming as `instance` on the same claims
+    pub fn for_instance(self: &Arc<Self>, instance: impl Into<String>) -> InstanceDedup {
+        InstanceDedup {
+            registry: self.clone(),
+            instance: instance.into(),
+        }
+    }
+
+    fn claim_as(&self, instance: &str, victim: H256, strategy: &str) -> bool {
+        let now = Instant::now();
+        let mut claims = self.claims.lock();
+        claims.retain(|_, (_, expiry)| *expiry > now);
+        let key = (victim, strategy.to_string());
+        match claims.get(&key) {
+            Some((owner, _)) if owner != instance => false,
+            _ => {
+                claims.insert(key, (instance.to_string(), now + self.ttl));
+                true
+            }
+        }
+    }
+
+    fn release_as(&self, instance: &str, victim: H256, strategy: &str) {
+        let mut claims = self.claims.lock();
+        let key = (victim, strategy.to_string());
+        if claims
+            .get(&key)
+            .map_or(false, |(owner, _)| owner == instance)
+        {
+            claims.remove(&key);
+        }
+    }
+}
+
+#[async_trait]
+impl DedupRegistry for MemoryDedup {
+    async fn claim(&self, victim: H256, strategy: &str) -> Result<bool, DedupError> {
+        Ok(self.claim_as(&self.instance, victim, strategy))
+    }
+
+    async fn release(&self, victim: H256, strategy: &str) -> Result<(), DedupError> {
+        self.release_as(&self.instance, victim, strategy);
+        Ok(())
+    }
+}
+
+/// A [MemoryDedup] shared by several instances, see [MemoryDedup::for_instance]
+#[derive(Debug, Clone)]
+pub struct InstanceDedup {
+    registry: Arc<MemoryDedup>,
+    instance: String,
+}
+
+#[async_trait]
+impl DedupRegistry for InstanceDedup {
+    async fn claim(&self, victim: H256, strategy: &str) -> Result<bool, DedupError> {
+        Ok(self.registry.claim_as(&self.instance, victim, strategy))
+    }
+
+    async fn release(&self, victim: H256, strategy: &str) -> Result<(), DedupError> {
+        self.registry.release_as(&self.instance, victim, strategy);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_dedup() {
+        let registry = Arc::new(MemoryDedup::new("a").with_ttl(Duration::from_millis(50)));
+        let other = registry.for_instance("b");
+        let victim = H256::from_low_u64_be(1);
+
+        assert!(registry.claim(victim, "sandwich").await.unwrap());
+        // re-claims extend, other instances are turned away
+        assert!(registry.claim(victim, "sandwich").await.unwrap());
+        assert!(!other.claim(victim, "sandwich").await.unwrap());
+        // the key includes the strategy
+        assert!(other.claim(victim, "backrun").await.unwrap());
+
+        // only the owner can release
+        other.release(victim, "sandwich").await.unwrap();
+        assert!(!other.claim(victim, "sandwich").await.unwrap());
+        registry.release(victim, "sandwich").await.unwrap();
+        assert!(other.claim(victim, "sandwich").await.unwrap());
+
+        // claims expire
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(registry.claim(victim, "backrun").await.unwrap());
+    }
+}
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ethers::types::H256;
+use redis::aio::ConnectionManager;
+
+use super::{DedupError, DedupRegistry, DEFAULT_CLAIM_TTL};
+
+/// Takes the claim if free, or extends it if this instance already holds it, in one round trip
+const CLAIM_SCRIPT: &str = r#"
+if redis.call("SET", KEYS[1], ARGV[1], "NX", "PX", ARGV[2]) then
+    return 1
+end
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    redis.call("PEXPIRE", KEYS[1], ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+/// Deletes the claim only if this instance holds it
+const RELEASE_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Claims stored as `<prefix>:<strategy>:<victim>` keys holding the claiming instance, expiring
+/// with the claim
+#[derive(Clone)]
+pub struct RedisDedup {
+    connection: ConnectionManager,
+    instance: String,
+    prefix: String,
+    ttl: Duration,
+}
+
+impl fmt::Debug for RedisDedup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisDedup")
+            .field("instance", &self.instance)
+            .field("prefix", &self.prefix)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+impl RedisDedup {
+    /// Connect to `url`, e.g. `redis://127.0.0.1/`. `instance` has to be unique per replica.
+    /// The connection is re-established on its own after a drop.
+    pub async fn connect(url: &str, instance: impl Into<String>) -> Result<Self, DedupError> {
+        let client = redis::Client::open(url).map_err(|e| DedupError::Backend(e.to_string()))?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .map_err(|e| DedupError::Backend(e.to_string()))?;
+        Ok(Self {
+            connection,
+            instance: instance.into(),
+            prefix: "qilin:dedup".to_string(),
+            ttl: DEFAULT_CLAIM_TTL,
+        })
+    }
+
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    fn key(&self, victim: H256, strategy: &str) -> String {
+        format!("{}:{}:{:?}", self.prefix, strategy, victim)
+    }
+}
+
+#[async_trait]
+impl DedupRegistry for RedisDedup {
+    async fn claim(&self, victim: H256, strategy: &str) -> Result<bool, DedupError> {
+        let claimed: i64 = redis::Script::new(CLAIM_SCRIPT)
+            .key(self.key(victim, strategy))
+            .arg(&self.instance)
+            .arg(self.ttl.as_millis() as u64)
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(|e| DedupError::Backend(e.to_string()))?;
+        Ok(claimed == 1)
+    }
+
+    async fn release(&self, victim: H256, strategy: &str) -> Result<(), DedupError> {
+        redis::Script::new(RELEASE_SCRIPT)
+            .key(self.key(victim, strategy))
+            .arg(&self.instance)
+            .invoke_async::<_, ()>(&mut self.connection.clone())
+            .await
+            .map_err(|e| DedupError::Backend(e.to_string()))
+    }
+}
@@ -0,0 +1,202 @@
+//! State shared between instances through Redis
+//!
+//! A fleet of regional instances keeps rediscovering the same things: which slot holds a token's
+//! balances, whether a token is taxed or a honeypot, which pools exist, how well each route lands.
+//! [DistributedState] publishes what one instance learned so the others can skip the work. Every
+//! kind of state is one Redis hash under the configured prefix, values are JSON, and nothing in
+//! it is authoritative: instances treat what they read as a cache, like their own.
+
+use ethers::types::{Address, U256};
+use parking_lot::Mutex;
+use qilin_cfmms::{pool::Pool, registry::PoolRegistry};
+use redis::Commands;
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+
+use crate::{
+    submission::{LandingStats, Route},
+    token_tax::TaxProfile,
+};
+
+const SLOTS: &str = "balance_slots";
+const TOKEN_SAFETY: &str = "token_safety";
+const POOLS: &str = "pools";
+const LANDING: &str = "landing";
+
+const ROUTES: [Route; 3] = [Route::PublicMempool, Route::PrivateRpc, Route::Bundle];
+
+#[derive(Error, Debug)]
+pub enum DistributedError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[error("Malformed value: {0}")]
+    Serde(#[from] serde_json::Error),
+}
+
+fn route_name(route: Route) -> &'static str {
+    match route {
+        Route::PublicMempool => "public_mempool",
+        Route::PrivateRpc => "private_rpc",
+        Route::Bundle => "bundle",
+    }
+}
+
+pub struct DistributedState {
+    connection: Mutex<redis::Connection>,
+    prefix: String,
+}
+
+impl DistributedState {
+    /// Connect to `url`, e.g. `redis://127.0.0.1/`
+    pub fn connect(url: &str) -> Result<Self, DistributedError> {
+        let connection = redis::Client::open(url)?.get_connection()?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            prefix: "qilin".to_string(),
+        })
+    }
+
+    /// Instances only share state under the same prefix, e.g. one per chain
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    fn key(&self, kind: &str) -> String {
+        format!("{}:{}", self.prefix, kind)
+    }
+
+    fn get<T: DeserializeOwned>(
+        &self,
+        kind: &str,
+        field: &str,
+    ) -> Result<Option<T>, DistributedError> {
+        let value: Option<String> = self.connection.lock().hget(self.key(kind), field)?;
+        Ok(value
+            .map(|value| serde_json::from_str(&value))
+            .transpose()?)
+    }
+
+    fn set<T: Serialize>(
+        &self,
+        kind: &str,
+        field: &str,
+        value: &T,
+    ) -> Result<(), DistributedError> {
+        let value = serde_json::to_string(value)?;
+        self.connection
+            .lock()
+            .hset::<_, _, _, ()>(self.key(kind), field, value)?;
+        Ok(())
+    }
+
+    /// Slot of the `balanceOf` mapping of `token`, as found by the slot finder
+    pub fn balance_slot(&self, token: Address) -> Result<Option<U256>, DistributedError> {
+        self.get(SLOTS, &format!("{:?}", token))
+    }
+
+    pub fn put_balance_slot(&self, token: Address, slot: U256) -> Result<(), DistributedError> {
+        self.set(SLOTS, &format!("{:?}", token), &slot)
+    }
+
+    /// Tax profile another instance measured for `token`
+    pub fn tax_profile(&self, token: Address) -> Result<Option<TaxProfile>, DistributedError> {
+        self.get(TOKEN_SAFETY, &format!("{:?}", token))
+    }
+
+    pub fn put_tax_profile(&self, profile: &TaxProfile) -> Result<(), DistributedError> {
+        self.set(TOKEN_SAFETY, &format!("{:?}", profile.token), profile)
+    }
+
+    /// Publish every pool of `registry`, returns how many were written
+    pub fn publish_pools(&self, registry: &PoolRegistry) -> Result<usize, DistributedError> {
+        let pools = registry
+            .view()
+            .iter()
+            .map(|pool| Ok((format!("{:?}", pool.address), serde_json::to_string(pool)?)))
+            .collect::<Result<Vec<(String, String)>, serde_json::Error>>()?;
+        if pools.is_empty() {
+            return Ok(0);
+        }
+        self.connection
+            .lock()
+            .hset_multiple::<_, _, _, ()>(self.key(POOLS), &pools)?;
+        Ok(pools.len())
+    }
+
+    /// Add the pools published by other instances that `registry` doesn't track yet, returns how
+    /// many were added. Pools already tracked keep their local state, it's likely fresher. The
+    /// added pools show up in the views from the next [PoolRegistry::commit].
+    pub fn import_pools(&self, registry: &PoolRegistry) -> Result<usize, DistributedError> {
+        let pools: Vec<String> = self.connection.lock().hvals(self.key(POOLS))?;
+        let mut added = 0;
+        for pool in pools {
+            let pool: Pool = serde_json::from_str(&pool)?;
+            if registry.insert_new(pool) {
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// Count a submission through `route` towards the fleet's landing stats
+    pub fn record_landing(&self, route: Route, landed: bool) -> Result<(), DistributedError> {
+        let key = self.key(LANDING);
+        let name = route_name(route);
+        let mut pipe = redis::pipe();
+        pipe.hincr(&key, format!("{}:submitted", name), 1).ignore();
+        if landed {
+            pipe.hincr(&key, format!("{}:landed", name), 1).ignore();
+        }
+        pipe.query::<()>(&mut *self.connection.lock())?;
+        Ok(())
+    }
+
+    /// Landing stats of the whole fleet
+    pub fn landing_stats(&self) -> Result<LandingStats, DistributedError> {
+        let key = self.key(LANDING);
+        let stats = LandingStats::new();
+        let mut connection = self.connection.lock();
+        for route in ROUTES {
+            let name = route_name(route);
+            let (submitted, landed): (Option<u64>, Option<u64>) = redis::pipe()
+                .hget(&key, format!("{}:submitted", name))
+                .hget(&key, format!("{}:landed", name))
+                .query(&mut *connection)?;
+            stats.add(route, submitted.unwrap_or(0), landed.unwrap_or(0));
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token_tax::TaxSample;
+
+    #[test]
+    fn test_shared_values_roundtrip() {
+        let profile = TaxProfile {
+            token: Address::from_low_u64_be(1),
+            pool: Address::from_low_u64_be(2),
+            samples: vec![TaxSample {
+                weth_in: U256::exp10(18),
+                buy_bps: 300,
+                sell_bps: None,
+            }],
+            max_buy: Some(U256::exp10(20)),
+            max_sell: None,
+        };
+        let json = serde_json::to_string(&profile).unwrap();
+        assert_eq!(serde_json::from_str::<TaxProfile>(&json).unwrap(), profile);
+
+        // the landing fields of the routes don't collide
+        let names: std::collections::HashSet<_> = ROUTES.iter().map(|r| route_name(*r)).collect();
+        assert_eq!(names.len(), ROUTES.len());
+
+        let stats = LandingStats::new();
+        stats.add(Route::Bundle, 10, 4);
+        stats.add(Route::Bundle, 2, 5);
+        assert_eq!(stats.counts(Route::Bundle), (12, 6));
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use parking_lot::Mutex;
+
+use super::{EventLogError, EventRecord, EventSink};
+
+/// Default size a log file grows to before it's rotated
+pub const DEFAULT_MAX_FILE_BYTES: u64 = 64 * 1024 * 1024;
+/// Default number of rotated files kept around
+pub const DEFAULT_MAX_FILES: usize = 16;
+
+#[derive(Debug)]
+struct JsonlFile {
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+/// Appends records as JSON lines to `<dir>/<prefix>.jsonl`
+///
+/// Once the file grows past `max_file_bytes` it's renamed to `<prefix>.<n>.jsonl`, `n` increasing
+/// with every rotation, and only the `max_files` most recent rotated files are kept.
+#[derive(Debug)]
+pub struct JsonlSink {
+    dir: PathBuf,
+    prefix: String,
+    max_file_bytes: u64,
+    max_files: usize,
+    file: Mutex<JsonlFile>,
+}
+
+impl JsonlSink {
+    pub fn new(dir: impl Into<PathBuf>, prefix: impl Into<String>) -> Result<Self, EventLogError> {
+        let dir = dir.into();
+        let prefix = prefix.into();
+        fs::create_dir_all(&dir)?;
+        let file = open(&dir.join(format!("{}.jsonl", prefix)))?;
+        Ok(Self {
+            dir,
+            prefix,
+            max_file_bytes: DEFAULT_MAX_FILE_BYTES,
+            max_files: DEFAULT_MAX_FILES,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn with_max_file_bytes(mut self, max_file_bytes: u64) -> Self {
+        self.max_file_bytes = max_file_bytes;
+        self
+    }
+
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.max_files = max_files;
+        self
+    }
+
+    /// Path of the file currently written to
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(format!("{}.jsonl", self.prefix))
+    }
+
+    /// Rotated files, oldest first
+    pub fn rotated_files(&self) -> Result<Vec<PathBuf>, EventLogError> {
+        let mut rotated: Vec<(u64, PathBuf)> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let index = name
+                    .strip_prefix(&format!("{}.", self.prefix))?
+                    .strip_suffix(".jsonl")?
+                    .parse()
+                    .ok()?;
+                Some((index, entry.path()))
+            })
+            .collect();
+        rotated.sort();
+        Ok(rotated.into_iter().map(|(_, path)| path).collect())
+    }
+
+    fn rotate(&self, file: &mut JsonlFile) -> Result<(), EventLogError> {
+        file.writer.flush()?;
+
+        let rotated = self.rotated_files()?;
+        let next = rotated
+            .last()
+            .and_then(|path| rotation_index(path))
+            .map_or(0, |index| index + 1);
+        fs::rename(
+            self.path(),
+            self.dir.join(format!("{}.{}.jsonl", self.prefix, next)),
+        )?;
+
+        // the file just rotated is the newest one
+        let excess = (rotated.len() + 1).saturating_sub(self.max_files);
+        for path in rotated.iter().take(excess) {
+            fs::remove_file(path)?;
+        }
+
+        *file = open(&self.path())?;
+        Ok(())
+    }
+}
+
+impl EventSink for JsonlSink {
+    fn write(&self, record: &EventRecord) -> Result<(), EventLogError> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock();
+        if file.written > 0 && file.written + line.len() as u64 > self.max_file_bytes {
+            self.rotate(&mut file)?;
+        }
+        file.writer.write_all(&line)?;
+        file.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), EventLogError> {
+        self.file.lock().writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for JsonlSink {
+    fn drop(&mut self) {
+        let _ = self.file.get_mut().writer.flush();
+    }
+}
+
+fn open(path: &Path) -> Result<JsonlFile, EventLogError> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let written = file.metadata()?.len();
+    Ok(JsonlFile {
+        writer: BufWriter::new(file),
+        written,
+    })
+}
+
+fn rotation_index(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.rsplit('.').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::OpportunityEvent;
+    use super::*;
+    use ethers::types::I256;
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("qilin-event-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let sink = JsonlSink::new(&dir, "events")
+            .unwrap()
+            .with_max_file_bytes(1)
+            .with_max_files(2);
+        for i in 0..4 {
+            let record = EventRecord::new(
+                "arb",
+                OpportunityEvent::Detected {
+                    opportunity_id: i.to_string(),
+                    block: i,
+                    victim: None,
+                    pools: vec![],
+                    expected_profit: I256::from(i as i64),
+                },
+            );
+            sink.write(&record).unwrap();
+        }
+        sink.flush().unwrap();
+
+        // every record gets its own file, the oldest rotated one is gone
+        let rotated = sink.rotated_files().unwrap();
+        assert_eq!(rotated.len(), 2);
+        assert_eq!(rotation_index(&rotated[0]), Some(1));
+
+        let current = fs::read_to_string(sink.path()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(current.trim()).unwrap();
+        assert_eq!(line["opportunity_id"], "3");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
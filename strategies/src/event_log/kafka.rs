@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use rdkafka::{
+    config::ClientConfig,
+    producer::{BaseRecord, DefaultProducerContext, Producer, ThreadedProducer},
+};
+
+use super::{EventLogError, EventRecord, EventSink};
+
+/// Time given to in flight messages on flush
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Publishes records to `<topic_prefix>.<topic>` Kafka topics, keyed by opportunity id so all
+/// records of an opportunity land on the same partition, in order
+pub struct KafkaSink {
+    producer: ThreadedProducer<DefaultProducerContext>,
+    topic_prefix: String,
+}
+
+impl KafkaSink {
+    pub fn new(brokers: &str, topic_prefix: impl Into<String>) -> Result<Self, EventLogError> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "5000")
+            .create()
+            .map_err(|e| EventLogError::Sink(e.to_string()))?;
+        Ok(Self {
+            producer,
+            topic_prefix: topic_prefix.into(),
+        })
+    }
+}
+
+impl EventSink for KafkaSink {
+    fn write(&self, record: &EventRecord) -> Result<(), EventLogError> {
+        let payload = serde_json::to_vec(record)?;
+        let topic = format!("{}.{}", self.topic_prefix, record.event.topic().name());
+        self.producer
+            .send(
+                BaseRecord::to(&topic)
+                    .key(record.event.opportunity_id())
+                    .payload(&payload),
+            )
+            .map_err(|(e, _)| EventLogError::Sink(e.to_string()))
+    }
+
+    fn flush(&self) -> Result<(), EventLogError> {
+        self.producer
+            .flush(FLUSH_TIMEOUT)
+            .map_err(|e| EventLogError::Sink(e.to_string()))
+    }
+}
@@ -0,0 +1,253 @@
+//! Structured log of every opportunity, simulation and submission decision, for offline analysis
+//!
+//! Strategies emit [OpportunityEvent]s through an [EventLog], which stamps them into versioned
+//! [EventRecord]s and fans them out to the configured [EventSink]s.
+
+pub mod jsonl;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "nats")]
+pub mod nats;
+
+pub use jsonl::JsonlSink;
+#[cfg(feature = "kafka")]
+pub use kafka::KafkaSink;
+#[cfg(feature = "nats")]
+pub use nats::NatsSink;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ethers::types::{Address, H256, I256};
+use log::warn;
+use serde::{Serialize, Serializer};
+use thiserror::Error;
+
+/// Bumped on every breaking change to the record layout
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum EventLogError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    SerdeJson(#[from] serde_json::Error),
+    #[error("Sink error: {0}")]
+    Sink(String),
+}
+
+/// What happened to an opportunity, identified by `opportunity_id` across records
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OpportunityEvent {
+    Detected {
+        opportunity_id: String,
+        block: u64,
+        victim: Option<H256>,
+        pools: Vec<Address>,
+        #[serde(serialize_with = "as_decimal")]
+        expected_profit: I256,
+    },
+    Simulated {
+        opportunity_id: String,
+        block: u64,
+        success: bool,
+        #[serde(serialize_with = "as_decimal")]
+        profit: I256,
+        gas_used: u64,
+        error: Option<String>,
+        latency_us: u64,
+    },
+    Decision {
+        opportunity_id: String,
+        target_block: u64,
+        submitted: bool,
+        /// why the opportunity was dropped, or the relays it was sent to
+        reason: Option<String>,
+        bundle_hash: Option<H256>,
+    },
+    Filled {
+        opportunity_id: String,
+        block: u64,
+        bundle_hash: Option<H256>,
+        #[serde(serialize_with = "as_decimal")]
+        realized_profit: I256,
+    },
+    /// a bundle participant is on the compliance denylist, nothing was built
+    ComplianceRejected {
+        opportunity_id: String,
+        block: u64,
+        address: Address,
+        /// what the address is to the bundle, e.g. `victim_sender`
+        role: String,
+        /// where the denylist entry came from
+        source: Option<String>,
+    },
+    /// the asset trades higher on `sell_chain` than on `buy_chain` by more than bridging costs,
+    /// reported for manual execution
+    CrossChainDislocation {
+        opportunity_id: String,
+        asset: String,
+        buy_chain: String,
+        sell_chain: String,
+        spread_bps: f64,
+        net_bps: f64,
+    },
+}
+
+impl OpportunityEvent {
+    pub fn opportunity_id(&self) -> &str {
+        match self {
+            OpportunityEvent::Detected { opportunity_id, .. }
+            | OpportunityEvent::Simulated { opportunity_id, .. }
+            | OpportunityEvent::Decision { opportunity_id, .. }
+            | OpportunityEvent::Filled { opportunity_id, .. }
+            | OpportunityEvent::ComplianceRejected { opportunity_id, .. }
+            | OpportunityEvent::CrossChainDislocation { opportunity_id, .. } => opportunity_id,
+        }
+    }
+
+    pub fn topic(&self) -> EventTopic {
+        match self {
+            OpportunityEvent::Detected { .. }
+            | OpportunityEvent::Simulated { .. }
+            | OpportunityEvent::CrossChainDislocation { .. } => EventTopic::Opportunity,
+            OpportunityEvent::Decision { .. } | OpportunityEvent::ComplianceRejected { .. } => {
+                EventTopic::Bundle
+            }
+            OpportunityEvent::Filled { .. } => EventTopic::Fill,
+        }
+    }
+}
+
+/// Message bus topic an event is published on, appended to the sink's topic prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventTopic {
+    Opportunity,
+    Bundle,
+    Fill,
+}
+
+impl EventTopic {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventTopic::Opportunity => "opportunity",
+            EventTopic::Bundle => "bundle",
+            EventTopic::Fill => "fill",
+        }
+    }
+}
+
+/// A single line of the event log
+#[derive(Debug, Clone, Serialize)]
+pub struct EventRecord {
+    pub schema_version: u32,
+    pub timestamp_ms: u64,
+    pub strategy: String,
+    #[serde(flatten)]
+    pub event: OpportunityEvent,
+}
+
+impl EventRecord {
+    pub fn new(strategy: impl Into<String>, event: OpportunityEvent) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        Self {
+            schema_version: SCHEMA_VERSION,
+            timestamp_ms,
+            strategy: strategy.into(),
+            event,
+        }
+    }
+}
+
+/// Destination of [EventRecord]s
+pub trait EventSink: Send + Sync {
+    fn write(&self, record: &EventRecord) -> Result<(), EventLogError>;
+
+    fn flush(&self) -> Result<(), EventLogError> {
+        Ok(())
+    }
+}
+
+/// Fans records out to its sinks, a failing sink is logged and never fails the strategy
+#[derive(Default)]
+pub struct EventLog {
+    sinks: Vec<Box<dyn EventSink>>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_sink(mut self, sink: impl EventSink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.sinks.is_empty()
+    }
+
+    pub fn emit(&self, strategy: &str, event: OpportunityEvent) {
+        if self.sinks.is_empty() {
+            return;
+        }
+        let record = EventRecord::new(strategy, event);
+        for sink in &self.sinks {
+            if let Err(e) = sink.write(&record) {
+                warn!("Failed to write event record: {:?}", e);
+            }
+        }
+    }
+
+    pub fn flush(&self) {
+        for sink in &self.sinks {
+            if let Err(e) = sink.flush() {
+                warn!("Failed to flush event sink: {:?}", e);
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for EventLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventLog")
+            .field("sinks", &self.sinks.len())
+            .finish()
+    }
+}
+
+/// Amounts go out as decimal strings, JSON numbers lose precision past 2^53
+fn as_decimal<S: Serializer>(value: &I256, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_layout() {
+        let record = EventRecord::new(
+            "sandwich",
+            OpportunityEvent::Simulated {
+                opportunity_id: "0x01".into(),
+                block: 17444939,
+                success: true,
+                profit: I256::from(-42),
+                gas_used: 180_000,
+                error: None,
+                latency_us: 950,
+            },
+        );
+        let value = serde_json::to_value(&record).unwrap();
+        assert_eq!(value["schema_version"], SCHEMA_VERSION);
+        assert_eq!(value["strategy"], "sandwich");
+        assert_eq!(value["kind"], "simulated");
+        assert_eq!(value["profit"], "-42");
+        assert_eq!(value["gas_used"], 180_000);
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::mpsc;
+use std::time::Duration;
+
+use log::warn;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+use super::{EventLogError, EventRecord, EventSink};
+
+/// Time given to in flight messages on flush
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+enum Command {
+    Publish(String, Vec<u8>),
+    /// answered once every record written before it was handed to the client and flushed
+    Flush(mpsc::Sender<Result<(), String>>),
+}
+
+/// Publishes records on `<subject_prefix>.<topic>` NATS subjects
+///
+/// Has to be created from within a tokio runtime. Records are published in the order they were
+/// written by a single task on it, strategies never wait on the bus but on [EventSink::flush].
+pub struct NatsSink {
+    commands: UnboundedSender<Command>,
+    subject_prefix: String,
+}
+
+impl NatsSink {
+    pub async fn connect(
+        url: &str,
+        subject_prefix: impl Into<String>,
+    ) -> Result<Self, EventLogError> {
+        let client = async_nats::connect(url)
+            .await
+            .map_err(|e| EventLogError::Sink(e.to_string()))?;
+        let (commands, mut pending) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(command) = pending.recv().await {
+                match command {
+                    Command::Publish(subject, payload) => {
+                        if let Err(e) = client.publish(subject, payload.into()).await {
+                            warn!("Failed to publish event record: {:?}", e);
+                        }
+                    }
+                    Command::Flush(done) => {
+                        let flushed = client.flush().await.map_err(|e| format!("{:?}", e));
+                        let _ = done.send(flushed);
+                    }
+                }
+            }
+        });
+        Ok(Self {
+            commands,
+            subject_prefix: subject_prefix.into(),
+        })
+    }
+
+    fn send(&self, command: Command) -> Result<(), EventLogError> {
+        self.commands
+            .send(command)
+            .map_err(|_| EventLogError::Sink("nats publisher stopped".to_string()))
+    }
+}
+
+impl EventSink for NatsSink {
+    fn write(&self, record: &EventRecord) -> Result<(), EventLogError> {
+        let payload = serde_json::to_vec(record)?;
+        let subject = format!("{}.{}", self.subject_prefix, record.event.topic().name());
+        self.send(Command::Publish(subject, payload))
+    }
+
+    /// Waits for the records written so far to be published and flushed, up to [FLUSH_TIMEOUT]
+    fn flush(&self) -> Result<(), EventLogError> {
+        let (done, flushed) = mpsc::channel();
+        self.send(Command::Flush(done))?;
+        tokio::task::block_in_place(|| flushed.recv_timeout(FLUSH_TIMEOUT))
+            .map_err(|e| EventLogError::Sink(e.to_string()))?
+            .map_err(EventLogError::Sink)
+    }
+}
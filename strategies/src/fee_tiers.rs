@@ -0,0 +1,135 @@
+//! All fee tiers of a V3 pair
+//!
+//! A V3 factory deploys one pool per pair and fee tier, a trade routed through whichever of them
+//! happened to be found first often isn't the best fill. [FeeTiers] looks up every tier of the
+//! pair in the registry, with its ticks, and quotes the trade on each of them: either through the
+//! single best tier or split across tiers, see [split_route].
+
+use collectors::pair_discovery::Factory;
+use ethers::types::{Address, U256};
+use qilin_cfmms::{
+    batch_requests::uniswap_v3::UniswapV3TickData, pool::Pool, registry::PoolRegistry,
+};
+
+use crate::split_route::{self, plan_split, quote_leg, SplitLeg, SplitRoute};
+
+/// Fee tiers of Uniswap V3, in hundredths of a bp
+pub const V3_FEE_TIERS: [u32; 4] = [100, 500, 3_000, 10_000];
+#[derive(Debug, Clone, Default)]
+pub struct FeeTiers {
+    /// tiers found, lowest fee first, with their initialized ticks
+    tiers: Vec<(Pool, Vec<UniswapV3TickData>)>,
+}
+
+impl FeeTiers {
+    /// The tiers of the pair `factory` deployed that `registry` tracks
+    pub fn from_registry(
+        registry: &PoolRegistry,
+        factory: &Factory,
+        token_a: Address,
+        token_b: Address,
+    ) -> Self {
+        let (token_0, token_1) = if token_a < token_b {
+            (token_a, token_b)
+        } else {
+            (token_b, token_a)
+        };
+        let tiers = V3_FEE_TIERS
+            .iter()
+            .filter_map(|fee| {
+                let address = factory.pool_address(token_0, token_1, U256::from(*fee));
+                let pool = registry.get(&address)?;
+                Some((pool, registry.ticks(&address).unwrap_or_default()))
+            })
+            .collect();
+        Self { tiers }
+    }
+
+    pub fn from_pools(tiers: Vec<(Pool, Vec<UniswapV3TickData>)>) -> Self {
+        Self { tiers }
+    }
+
+    pub fn pools(&self) -> impl Iterator<Item = &Pool> {
+        self.tiers.iter().map(|(pool, _)| pool)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiers.is_empty()
+    }
+
+    /// Quote of `amount_in` of `token_in` on each tier that can fill it
+    pub fn quotes(&self, token_in: Address, amount_in: U256) -> Vec<SplitLeg> {
+        self.tiers
+            .iter()
+            .filter_map(|(pool, ticks)| quote_leg(pool, ticks, token_in, amount_in))
+            .collect()
+    }
+
+    /// The single tier getting the most out of `amount_in`
+    pub fn best(&self, token_in: Address, amount_in: U256) -> Option<SplitLeg> {
+        self.quotes(token_in, amount_in)
+            .into_iter()
+            .max_by_key(|quote| quote.amount_out)
+    }
+
+    /// `amount_in` split in `parts` chunks across the tiers, see [split_route::split]
+    pub fn split(&self, token_in: Address, amount_in: U256, parts: usize) -> Vec<SplitLeg> {
+        split_route::split(&self.tiers, token_in, amount_in, parts)
+    }
+
+    /// The split if it gets more out net of `leg_cost` per tier than the best single tier,
+    /// otherwise that tier alone, see [plan_split]
+    pub fn plan(
+        &self,
+        token_in: Address,
+        amount_in: U256,
+        leg_cost: U256,
+        parts: usize,
+    ) -> Option<SplitRoute> {
+        plan_split(&self.tiers, token_in, amount_in, leg_cost, parts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qilin_cfmms::test_utils::v3_pool;
+
+    #[test]
+    fn test_best_tier_and_split() {
+        let factory = Factory::mainnet()[1];
+        let (weth, usdc) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let tier = |fee: u32, liquidity: u128| {
+            let address = factory.pool_address(weth, usdc, U256::from(fee));
+            v3_pool(address, weth, usdc, fee, liquidity)
+        };
+        let registry = PoolRegistry::new();
+        // a deep 0.3% pool and a shallower 0.05% one
+        registry.insert(tier(3_000, 10u128.pow(24)));
+        registry.insert(tier(500, 10u128.pow(22)));
+
+        let tiers = FeeTiers::from_registry(&registry, &factory, usdc, weth);
+        assert_eq!(tiers.len(), 2);
+        assert_eq!(tiers.pools().next().unwrap().swap_fee, U256::from(500));
+
+        // small trades go through the cheap tier, large ones through the deep one
+        let small = tiers.best(weth, U256::exp10(15)).unwrap();
+        assert_eq!(small.pool.swap_fee, U256::from(500));
+        let large = U256::exp10(21);
+        assert_eq!(
+            tiers.best(weth, large).unwrap().pool.swap_fee,
+            U256::from(3_000)
+        );
+
+        // the first chunks of a large trade still get more out of the cheap tier
+        let plan = tiers.plan(weth, large, U256::zero(), 1_000).unwrap();
+        assert_eq!(plan.legs.len(), 2);
+        assert_eq!(plan.amount_in(), large);
+        assert!(plan.amount_out() > tiers.best(weth, large).unwrap().amount_out);
+        assert!(tiers.best(Address::from_low_u64_be(3), large).is_none());
+    }
+}
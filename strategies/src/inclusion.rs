@@ -0,0 +1,265 @@
+//! Inclusion probability of a bribe
+//!
+//! A fixed share of the profit overpays on quiet blocks and underpays on busy ones. The
+//! [InclusionModel] estimates how likely a bundle paying a given priority fee per gas is to land,
+//! from what competing searchers paid for the pools we trade and from how our own bundles fared.
+//! [BribePolicy::decide_ev](crate::competition::BribePolicy::decide_ev) then pays the bribe with
+//! the best expected value, see
+//! [SubmissionPolicy::decide](crate::submission::SubmissionPolicy::decide).
+//!
+//! Public tips `eth_feeHistory` reports for recent blocks only tell what ordinary txs pay, a
+//! contested bundle has to outbid other bundles, not them. They are a fallback until competitor
+//! fees are known, see
+//! [CompetitionTracker::competitor_fees](crate::competition::CompetitionTracker::competitor_fees).
+//! Direct coinbase payments of competitors don't show in receipts and aren't counted.
+
+use std::collections::VecDeque;
+
+use ethers::{
+    providers::Middleware,
+    types::{BlockNumber, FeeHistory, U256},
+};
+
+/// Percentiles of priority fees requested from `eth_feeHistory`
+pub const DEFAULT_PERCENTILES: [f64; 7] = [10.0, 25.0, 50.0, 75.0, 90.0, 95.0, 99.0];
+/// Blocks of fee history kept
+const MAX_BLOCKS: usize = 20;
+/// Bundle outcomes kept
+const MAX_OBSERVATIONS: usize = 1_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Landing {
+    priority_fee: U256,
+    landed: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct InclusionModel {
+    percentiles: Vec<f64>,
+    /// rewards at `percentiles` of the last blocks, oldest first
+    blocks: VecDeque<Vec<U256>>,
+    /// priority fees per gas competing searchers paid
+    competitor_fees: Vec<U256>,
+    landings: VecDeque<Landing>,
+    /// weight of the fee history estimate, in observed bundles
+    prior_weight: f64,
+}
+
+impl Default for InclusionModel {
+    fn default() -> Self {
+        Self::new(DEFAULT_PERCENTILES.to_vec())
+    }
+}
+
+impl InclusionModel {
+    /// `percentiles` have to be sorted, as `eth_feeHistory` expects them
+    pub fn new(percentiles: Vec<f64>) -> Self {
+        Self {
+            percentiles,
+            blocks: VecDeque::new(),
+            competitor_fees: vec![],
+            landings: VecDeque::new(),
+            prior_weight: 10.0,
+        }
+    }
+
+    pub fn with_prior_weight(mut self, weight: f64) -> Self {
+        self.prior_weight = weight.max(0.0);
+        self
+    }
+
+    pub fn percentiles(&self) -> &[f64] {
+        &self.percentiles
+    }
+
+    /// Fetch the fee history of the last `blocks` blocks
+    pub async fn update<M: Middleware>(
+        &mut self,
+        provider: &M,
+        blocks: u64,
+    ) -> Result<(), M::Error> {
+        let history = provider
+            .fee_history(blocks, BlockNumber::Latest, &self.percentiles)
+            .await?;
+        self.on_fee_history(&history);
+        Ok(())
+    }
+
+    pub fn on_fee_history(&mut self, history: &FeeHistory) {
+        for rewards in &history.reward {
+            if rewards.len() == self.percentiles.len() {
+                self.blocks.push_back(rewards.clone());
+            }
+        }
+        while self.blocks.len() > MAX_BLOCKS {
+            self.blocks.pop_front();
+        }
+    }
+
+    /// Priority fees per gas competitors paid recently, replacing the previous ones. Once there
+    /// are any, the fee history is no longer used.
+    pub fn set_competitor_fees(&mut self, mut fees: Vec<U256>) {
+        fees.sort();
+        self.competitor_fees = fees;
+    }
+
+    /// Whether a bundle paying `priority_fee` per gas landed
+    pub fn record(&mut self, priority_fee: U256, landed: bool) {
+        self.landings.push_back(Landing {
+            priority_fee,
+            landed,
+        });
+        while self.landings.len() > MAX_OBSERVATIONS {
+            self.landings.pop_front();
+        }
+    }
+
+    /// Share of competitor fees below `priority_fee`, ties counting half, between 0 and 1. Without
+    /// competitor fees, the share of recent blocks' gas paying less.
+    pub fn market_probability(&self, priority_fee: U256) -> f64 {
+        if !self.competitor_fees.is_empty() {
+            let below = self
+                .competitor_fees
+                .partition_point(|fee| *fee < priority_fee);
+            let ties = self.competitor_fees[below..].partition_point(|fee| *fee == priority_fee);
+            return (below as f64 + ties as f64 / 2.0) / self.competitor_fees.len() as f64;
+        }
+        if self.blocks.is_empty() {
+            return 0.5;
+        }
+        let sum: f64 = self
+            .blocks
+            .iter()
+            .map(|rewards| self.percentile_of(rewards, priority_fee))
+            .sum();
+        sum / self.blocks.len() as f64 / 100.0
+    }
+
+    /// Probability a bundle paying `priority_fee` per gas lands
+    ///
+    /// Our bundles paying within a factor of two of `priority_fee` are counted on top of the fee
+    /// history estimate, which weighs as much as `prior_weight` of them.
+    pub fn probability(&self, priority_fee: U256) -> f64 {
+        let prior = self.market_probability(priority_fee);
+        let (low, high) = (priority_fee / 2, priority_fee.saturating_mul(U256::from(2)));
+        let (seen, landed) = self
+            .landings
+            .iter()
+            .filter(|l| l.priority_fee >= low && l.priority_fee <= high)
+            .fold((0.0, 0.0), |(seen, landed), l| {
+                (seen + 1.0, landed + if l.landed { 1.0 } else { 0.0 })
+            });
+        ((landed + prior * self.prior_weight) / (seen + self.prior_weight).max(f64::EPSILON))
+            .clamp(0.0, 1.0)
+    }
+
+    /// Where `fee` falls among a block's reward percentiles, interpolated between them
+    fn percentile_of(&self, rewards: &[U256], fee: U256) -> f64 {
+        let as_f64 = |v: U256| v.min(U256::from(u128::MAX)).as_u128() as f64;
+        let fee_f = as_f64(fee);
+        let mut prev = (0.0, 0.0);
+        for (reward, pct) in rewards.iter().zip(&self.percentiles) {
+            let reward = as_f64(*reward);
+            if fee_f < reward {
+                let span = reward - prev.0;
+                if span <= 0.0 {
+                    return prev.1;
+                }
+                return prev.1 + (pct - prev.1) * (fee_f - prev.0) / span;
+            }
+            prev = (reward, *pct);
+        }
+        // above the highest percentile, outbidding the rest is all but certain
+        if fee_f > prev.0 {
+            100.0
+        } else {
+            prev.1
+        }
+    }
+}
+
+/// Bribe with the best expected value, see [best_bribe]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BribeChoice {
+    pub bribe: U256,
+    pub bps: u64,
+    pub probability: f64,
+    /// `probability * (gross_profit - bribe)`, in wei
+    pub expected_value: f64,
+}
+
+/// Tries shares of `gross_profit` from 0 to `max_bps` by `step_bps` and keeps the one with the
+/// best expected value, the bribe paid over `gas_used` as priority fee. `None` without gas.
+pub fn best_bribe(
+    model: &InclusionModel,
+    gross_profit: U256,
+    gas_used: u64,
+    max_bps: u64,
+    step_bps: u64,
+) -> Option<BribeChoice> {
+    if gas_used == 0 {
+        return None;
+    }
+    let profit = gross_profit.min(U256::from(u128::MAX)).as_u128() as f64;
+    let mut best: Option<BribeChoice> = None;
+    let mut bps = 0;
+    while bps <= max_bps.min(10_000) {
+        let bribe = gross_profit * bps / 10_000;
+        let probability = model.probability(bribe / gas_used);
+        let kept = profit - bribe.min(U256::from(u128::MAX)).as_u128() as f64;
+        let choice = BribeChoice {
+            bribe,
+            bps,
+            probability,
+            expected_value: probability * kept,
+        };
+        if best.map_or(true, |b| choice.expected_value > b.expected_value) {
+            best = Some(choice);
+        }
+        bps += step_bps.max(1);
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gwei(n: u64) -> U256 {
+        U256::exp10(9) * n
+    }
+
+    #[test]
+    fn test_inclusion_probability() {
+        let mut model = InclusionModel::new(vec![25.0, 50.0, 90.0]).with_prior_weight(4.0);
+        model.on_fee_history(&FeeHistory {
+            base_fee_per_gas: vec![],
+            gas_used_ratio: vec![],
+            oldest_block: U256::zero(),
+            reward: vec![vec![gwei(1), gwei(2), gwei(10)]; 2],
+        });
+        assert_eq!(model.market_probability(U256::zero()), 0.0);
+        assert_eq!(model.market_probability(gwei(2)), 0.5);
+        assert_eq!(model.market_probability(gwei(6)), 0.7);
+        assert_eq!(model.market_probability(gwei(100)), 1.0);
+
+        // our bundles at 2 gwei all landed, more than the fee history suggests
+        for _ in 0..4 {
+            model.record(gwei(2), true);
+        }
+        assert_eq!(model.probability(gwei(2)), 0.75);
+
+        // the best bribe balances landing odds against what's left of the profit
+        let choice = best_bribe(&model, U256::exp10(18), 100_000, 9_900, 100).unwrap();
+        assert!(choice.bps > 0 && choice.bps < 9_900);
+        assert!(choice.expected_value > 0.0);
+        assert!(best_bribe(&model, U256::exp10(18), 0, 9_900, 100).is_none());
+
+        // competing bundles paid far more than public tips, the fee history no longer counts
+        model.set_competitor_fees(vec![gwei(50), gwei(20), gwei(20), gwei(80)]);
+        assert_eq!(model.market_probability(gwei(10)), 0.0);
+        assert_eq!(model.market_probability(gwei(20)), 0.25);
+        assert_eq!(model.market_probability(gwei(60)), 0.75);
+        assert_eq!(model.market_probability(gwei(100)), 1.0);
+    }
+}
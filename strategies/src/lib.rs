@@ -1,3 +1,33 @@
+pub mod approvals;
 pub mod arb;
+pub mod bundle_check;
+pub mod bundle_merge;
+pub mod capital;
+pub mod competition;
+pub mod compliance;
+pub mod conflicts;
+pub mod cow;
+pub mod cross_chain;
+pub mod dedup;
+#[cfg(feature = "distributed")]
+pub mod distributed;
+pub mod event_log;
+pub mod fee_tiers;
+pub mod inclusion;
+pub mod permits;
+pub mod placement;
+pub mod pnl;
+pub mod pool_filter;
+pub mod pricing;
+pub mod report;
+pub mod revert_reason;
+pub mod risk;
 pub mod sandwich;
+pub mod scorer;
+pub mod split_route;
+pub mod submission;
+pub mod target_policy;
+pub mod token_tax;
 pub mod types;
+pub mod user_op;
+pub mod victim;
@@ -0,0 +1,445 @@
+//! Signed permits instead of approval txs
+//!
+//! An approval tx in front of a bundle costs a tx's intrinsic gas on top of the approval itself.
+//! Tokens implementing EIP-2612, and every token once it approved Permit2, take a signature
+//! instead: the executor contract submits the [Permit] as a call within its own tx. The domain
+//! separator and nonce are read on the fork db, and [verify_permit] runs the permit on a sandbox
+//! to check the signature is accepted before the bundle relies on it.
+//!
+//! The tokens to spend are the executor's, and a contract can't sign. Permit2 checks signatures of
+//! contract owners through EIP-1271 `isValidSignature` instead of `ecrecover`, so
+//! [executor_permit] has the searcher sign a Permit2 grant the executor owns, which the executor
+//! accepts as its operator's. EIP-2612 tokens only `ecrecover`, their permits need an EOA owner.
+
+use std::fmt::Debug;
+
+use ethers::{
+    abi::{self, Token},
+    signers::{LocalWallet, Signer},
+    types::{Address, Bytes, Signature, H256, U256},
+    utils::{id, keccak256},
+};
+use fork_database::utils::{h160_to_b160, RefDb};
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{Env, ExecutionResult, TransactTo, TxEnv},
+    EVM,
+};
+
+use crate::approvals::{call, has_code, word, ApprovalError, PERMIT2};
+
+const PERMIT_GAS: u64 = 150_000;
+
+fn permit_typehash() -> [u8; 32] {
+    keccak256("Permit(address owner,address spender,uint256 value,uint256 nonce,uint256 deadline)")
+}
+
+fn permit_details_typehash() -> [u8; 32] {
+    keccak256("PermitDetails(address token,uint160 amount,uint48 expiration,uint48 nonce)")
+}
+
+fn permit_single_typehash() -> [u8; 32] {
+    keccak256(
+        "PermitSingle(PermitDetails details,address spender,uint256 sigDeadline)\
+         PermitDetails(address token,uint160 amount,uint48 expiration,uint48 nonce)",
+    )
+}
+
+/// Standard EIP-712 domain separator, for tokens without a `DOMAIN_SEPARATOR()` getter
+pub fn domain_separator(
+    name: &str,
+    version: &str,
+    chain_id: U256,
+    verifying_contract: Address,
+) -> H256 {
+    let typehash = keccak256(
+        "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)",
+    );
+    H256(keccak256(abi::encode(&[
+        Token::FixedBytes(typehash.to_vec()),
+        Token::FixedBytes(keccak256(name).to_vec()),
+        Token::FixedBytes(keccak256(version).to_vec()),
+        Token::Uint(chain_id),
+        Token::Address(verifying_contract),
+    ])))
+}
+
+/// `keccak256(0x1901 || domain_separator || struct_hash)`, what gets signed
+pub fn typed_digest(domain_separator: H256, struct_hash: [u8; 32]) -> H256 {
+    let mut preimage = Vec::with_capacity(66);
+    preimage.extend_from_slice(&[0x19, 0x01]);
+    preimage.extend_from_slice(domain_separator.as_bytes());
+    preimage.extend_from_slice(&struct_hash);
+    H256(keccak256(preimage))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PermitKind {
+    /// `permit` of the token itself
+    Eip2612 { value: U256, deadline: U256 },
+    /// a Permit2 `PermitSingle`, Permit2 has to be approved on the token already
+    Permit2 {
+        amount: U256,
+        expiration: u64,
+        sig_deadline: U256,
+    },
+}
+
+/// A signed permit letting `spender` pull `token` from `owner`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Permit {
+    pub token: Address,
+    pub owner: Address,
+    pub spender: Address,
+    pub nonce: U256,
+    pub kind: PermitKind,
+    pub signature: Signature,
+}
+
+impl Permit {
+    /// Contract the permit is submitted to
+    pub fn target(&self) -> Address {
+        match self.kind {
+            PermitKind::Eip2612 { .. } => self.token,
+            PermitKind::Permit2 { .. } => PERMIT2,
+        }
+    }
+
+    /// Calldata of the permit call, for the executor to make before its swaps
+    pub fn calldata(&self) -> Bytes {
+        let data = match &self.kind {
+            PermitKind::Eip2612 { value, deadline } => [
+                id("permit(address,address,uint256,uint256,uint8,bytes32,bytes32)").to_vec(),
+                abi::encode(&[
+                    Token::Address(self.owner),
+                    Token::Address(self.spender),
+                    Token::Uint(*value),
+                    Token::Uint(*deadline),
+                    Token::Uint(U256::from(self.signature.v)),
+                    Token::FixedBytes(word_bytes(self.signature.r)),
+                    Token::FixedBytes(word_bytes(self.signature.s)),
+                ]),
+            ]
+            .concat(),
+            PermitKind::Permit2 {
+                amount,
+                expiration,
+                sig_deadline,
+            } => [
+                id("permit(address,((address,uint160,uint48,uint48),address,uint256),bytes)")
+                    .to_vec(),
+                abi::encode(&[
+                    Token::Address(self.owner),
+                    Token::Tuple(vec![
+                        Token::Tuple(vec![
+                            Token::Address(self.token),
+                            Token::Uint(*amount),
+                            Token::Uint(U256::from(*expiration)),
+                            Token::Uint(self.nonce),
+                        ]),
+                        Token::Address(self.spender),
+                        Token::Uint(*sig_deadline),
+                    ]),
+                    Token::Bytes(self.signature.to_vec()),
+                ]),
+            ]
+            .concat(),
+        };
+        data.into()
+    }
+
+    /// The permit as a call of its own, e.g. to simulate it
+    pub fn tx(&self, caller: Address) -> TxEnv {
+        TxEnv {
+            caller: h160_to_b160(caller),
+            transact_to: TransactTo::Call(h160_to_b160(self.target())),
+            data: self.calldata().to_vec().into(),
+            gas_limit: PERMIT_GAS,
+            ..Default::default()
+        }
+    }
+}
+
+/// The hash `owner` signs for `kind`, given the domain separator of the contract verifying it
+pub fn permit_digest(
+    domain_separator: H256,
+    token: Address,
+    owner: Address,
+    spender: Address,
+    nonce: U256,
+    kind: &PermitKind,
+) -> H256 {
+    let struct_hash = match kind {
+        PermitKind::Eip2612 { value, deadline } => keccak256(abi::encode(&[
+            Token::FixedBytes(permit_typehash().to_vec()),
+            Token::Address(owner),
+            Token::Address(spender),
+            Token::Uint(*value),
+            Token::Uint(nonce),
+            Token::Uint(*deadline),
+        ])),
+        PermitKind::Permit2 {
+            amount,
+            expiration,
+            sig_deadline,
+        } => {
+            let details = keccak256(abi::encode(&[
+                Token::FixedBytes(permit_details_typehash().to_vec()),
+                Token::Address(token),
+                Token::Uint(*amount),
+                Token::Uint(U256::from(*expiration)),
+                Token::Uint(nonce),
+            ]));
+            keccak256(abi::encode(&[
+                Token::FixedBytes(permit_single_typehash().to_vec()),
+                Token::FixedBytes(details.to_vec()),
+                Token::Address(spender),
+                Token::Uint(*sig_deadline),
+            ]))
+        }
+    };
+    typed_digest(domain_separator, struct_hash)
+}
+
+/// Sign a permit of `owner` through `wallet` with an already known domain separator and nonce
+///
+/// `owner` is `wallet` itself or, for Permit2, a contract validating `wallet`'s signatures
+/// through EIP-1271.
+pub fn sign_permit(
+    wallet: &LocalWallet,
+    owner: Address,
+    domain_separator: H256,
+    token: Address,
+    spender: Address,
+    nonce: U256,
+    kind: PermitKind,
+) -> Result<Permit, ApprovalError> {
+    if matches!(kind, PermitKind::Eip2612 { .. }) && owner != wallet.address() {
+        return Err(ApprovalError::Signing(
+            "EIP-2612 permits are only signed by their owner".to_string(),
+        ));
+    }
+    let digest = permit_digest(domain_separator, token, owner, spender, nonce, &kind);
+    let signature = wallet
+        .sign_hash(digest)
+        .map_err(|e| ApprovalError::Signing(e.to_string()))?;
+    Ok(Permit {
+        token,
+        owner,
+        spender,
+        nonce,
+        kind,
+        signature,
+    })
+}
+
+/// Sign a permit of `owner` through `wallet`, reading the domain separator and nonce on `db`, see
+/// [sign_permit]
+pub fn build_permit<DB>(
+    db: &DB,
+    env: &Env,
+    wallet: &LocalWallet,
+    owner: Address,
+    token: Address,
+    spender: Address,
+    kind: PermitKind,
+) -> Result<Permit, ApprovalError>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let (verifier, nonce) = match kind {
+        PermitKind::Eip2612 { .. } => {
+            let data = [
+                id("nonces(address)").to_vec(),
+                abi::encode(&[Token::Address(owner)]),
+            ]
+            .concat();
+            let output = call(db, env, token, data)?.ok_or(ApprovalError::Reverted(token))?;
+            (token, word(&output, 0))
+        }
+        PermitKind::Permit2 { .. } => {
+            let data = [
+                id("allowance(address,address,address)").to_vec(),
+                abi::encode(&[
+                    Token::Address(owner),
+                    Token::Address(token),
+                    Token::Address(spender),
+                ]),
+            ]
+            .concat();
+            let output = call(db, env, PERMIT2, data)?.ok_or(ApprovalError::Reverted(PERMIT2))?;
+            (PERMIT2, word(&output, 2))
+        }
+    };
+    let output = call(db, env, verifier, id("DOMAIN_SEPARATOR()").to_vec())?
+        .ok_or(ApprovalError::Reverted(verifier))?;
+    let mut separator = [0u8; 32];
+    word(&output, 0).to_big_endian(&mut separator);
+    sign_permit(wallet, owner, H256(separator), token, spender, nonce, kind)
+}
+
+/// Permit2 grant of `amount` of the `executor`'s `token` to `spender`, signed by its operator
+/// `wallet`. `token` has to have approved Permit2 already.
+#[allow(clippy::too_many_arguments)]
+pub fn executor_permit<DB>(
+    db: &DB,
+    env: &Env,
+    wallet: &LocalWallet,
+    executor: Address,
+    token: Address,
+    spender: Address,
+    amount: U256,
+    expiration: u64,
+) -> Result<Permit, ApprovalError>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let kind = PermitKind::Permit2 {
+        amount,
+        expiration,
+        sig_deadline: U256::from(expiration),
+    };
+    build_permit(db, env, wallet, executor, token, spender, kind)
+}
+
+/// Whether `permit` is accepted on `db`: it executes and leaves the spender with an allowance.
+/// Nothing is committed to `db`.
+pub fn verify_permit<DB>(db: &DB, env: &Env, permit: &Permit) -> Result<bool, ApprovalError>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    // nothing to verify the signature
+    if !has_code(db, permit.target())? {
+        return Ok(false);
+    }
+    let mut sandbox = CacheDB::new(RefDb(db));
+    let mut evm = EVM::new();
+    evm.env = env.clone();
+    evm.env.block.basefee = Default::default();
+    // anyone may submit a permit
+    evm.env.tx = permit.tx(Address::zero());
+    evm.database(&mut sandbox);
+    let result = evm
+        .transact_commit()
+        .map_err(|e| ApprovalError::Simulation(format!("{:?}", e)))?;
+    if !matches!(result, ExecutionResult::Success { .. }) {
+        return Ok(false);
+    }
+
+    let (target, data, expected) = match &permit.kind {
+        PermitKind::Eip2612 { value, .. } => (
+            permit.token,
+            [
+                id("allowance(address,address)").to_vec(),
+                abi::encode(&[Token::Address(permit.owner), Token::Address(permit.spender)]),
+            ]
+            .concat(),
+            *value,
+        ),
+        PermitKind::Permit2 { amount, .. } => (
+            PERMIT2,
+            [
+                id("allowance(address,address,address)").to_vec(),
+                abi::encode(&[
+                    Token::Address(permit.owner),
+                    Token::Address(permit.token),
+                    Token::Address(permit.spender),
+                ]),
+            ]
+            .concat(),
+            *amount,
+        ),
+    };
+    let allowance = call(&sandbox, env, target, data)?
+        .map(|output| word(&output, 0))
+        .unwrap_or_default();
+    Ok(allowance >= expected)
+}
+
+fn word_bytes(value: U256) -> Vec<u8> {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    bytes.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::db::EmptyDB;
+
+    #[test]
+    fn test_permits_recover_to_owner() {
+        let wallet: LocalWallet =
+            "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+                .parse()
+                .unwrap();
+        let (token, router) = (Address::from_low_u64_be(2), Address::from_low_u64_be(3));
+        let domain = domain_separator("Token", "1", U256::one(), token);
+
+        let eip2612 = sign_permit(
+            &wallet,
+            wallet.address(),
+            domain,
+            token,
+            router,
+            U256::zero(),
+            PermitKind::Eip2612 {
+                value: U256::MAX,
+                deadline: U256::from(1_700_000_000u64),
+            },
+        )
+        .unwrap();
+        let digest = permit_digest(
+            domain,
+            token,
+            wallet.address(),
+            router,
+            U256::zero(),
+            &eip2612.kind,
+        );
+        assert_eq!(eip2612.signature.recover(digest).unwrap(), wallet.address());
+        assert_eq!(eip2612.target(), token);
+        assert_eq!(
+            &eip2612.calldata()[..4],
+            &id("permit(address,address,uint256,uint256,uint8,bytes32,bytes32)")
+        );
+
+        // owned by the executor, which checks the signature through EIP-1271
+        let executor = Address::from_low_u64_be(1);
+        let permit2 = sign_permit(
+            &wallet,
+            executor,
+            domain,
+            token,
+            router,
+            U256::from(7),
+            PermitKind::Permit2 {
+                amount: U256::from(1_000),
+                expiration: 1_700_000_000,
+                sig_deadline: U256::from(1_700_000_000u64),
+            },
+        )
+        .unwrap();
+        assert_eq!(permit2.target(), PERMIT2);
+        assert_eq!(permit2.owner, executor);
+        assert_ne!(permit2.signature, eip2612.signature);
+        // EIP-2612 tokens only take the owner's own signature
+        assert!(sign_permit(
+            &wallet,
+            executor,
+            domain,
+            token,
+            router,
+            U256::zero(),
+            eip2612.kind.clone(),
+        )
+        .is_err());
+
+        // nothing is deployed, the permit call succeeds but grants nothing
+        let db = CacheDB::new(EmptyDB::default());
+        assert!(!verify_permit(&db, &Env::default(), &eip2612).unwrap());
+    }
+}
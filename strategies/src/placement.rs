@@ -0,0 +1,172 @@
+//! Where in the block a bundle should land
+//!
+//! At the top of the block a bundle sees the state we simulated against, but builders sell that
+//! spot dearly. At the tail it runs after whatever the builder included first, cheaper but the
+//! opportunity may have shrunk or reverted by then. [simulate_placements] runs the bundle both ways,
+//! the tail one behind the pending txs expected to land first, and [PlacementReport::preferred]
+//! picks the placement to request, so the profit the bribe is sized from matches where the
+//! bundle will execute.
+
+use ethers::types::{I256, U256};
+use fork_database::{
+    errors::{DatabaseError, DatabaseResult},
+    multi_block::{BlockLeg, MultiBlockSim},
+    storage_layout::{mapping_slot, WETH_BALANCE_OF_SLOT},
+    utils::{b160_to_h160, ru256_to_u256, u256_to_ru256, RefDb},
+};
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{Env, TxEnv, B160, U256 as rU256},
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Placement {
+    #[serde(rename = "top")]
+    TopOfBlock,
+    #[serde(rename = "tail")]
+    TailOfBlock,
+}
+
+/// Profit of a bundle in each placement, `None` where one of its txs fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlacementReport {
+    pub top: Option<I256>,
+    pub tail: Option<I256>,
+    /// gas used by the bundle at the top, what the bribe is usually sized against
+    pub top_gas: u64,
+    pub tail_gas: u64,
+}
+
+impl PlacementReport {
+    pub fn profit(&self, placement: Placement) -> Option<I256> {
+        match placement {
+            Placement::TopOfBlock => self.top,
+            Placement::TailOfBlock => self.tail,
+        }
+    }
+
+    /// The more profitable placement once the top of block premium is paid, ties go to the top
+    /// where the simulation is most trustworthy. `None` if the bundle fails or loses both ways.
+    pub fn preferred(&self, top_premium: U256) -> Option<(Placement, I256)> {
+        let top = self.top.map(|profit| profit - I256::from_raw(top_premium));
+        let best = match (top, self.tail) {
+            (Some(top), Some(tail)) if tail > top => (Placement::TailOfBlock, tail),
+            (Some(top), _) => (Placement::TopOfBlock, top),
+            (None, Some(tail)) => (Placement::TailOfBlock, tail),
+            (None, None) => return None,
+        };
+        (best.1 > I256::zero()).then_some(best)
+    }
+}
+
+/// Simulate `bundle` at the top of the block of `env` and behind `ahead`, the txs expected to land
+/// before a tail bundle. Profit is what `value` reads from the state, e.g. the WETH balance of
+/// the searcher contract, compared to the state before the block.
+pub fn simulate_placements<DB, F>(
+    db: &DB,
+    env: &Env,
+    bundle: &[TxEnv],
+    ahead: &[TxEnv],
+    value: F,
+) -> DatabaseResult<PlacementReport>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+    F: Fn(&CacheDB<RefDb<'_, DB>>) -> DatabaseResult<U256>,
+{
+    let before = I256::from_raw(value(&CacheDB::new(RefDb(db)))?);
+    let run = |ahead: &[TxEnv]| -> DatabaseResult<(Option<I256>, u64)> {
+        let mut leg = BlockLeg::new();
+        for tx in ahead {
+            leg = leg.with_theirs(tx.clone());
+        }
+        for tx in bundle {
+            leg = leg.with_ours(tx.clone());
+        }
+        let mut sim = MultiBlockSim::new(db, env.clone());
+        let outcome = sim.run_leg(leg)?;
+        if !outcome.succeeded() {
+            return Ok((None, outcome.gas_used));
+        }
+        let after = I256::from_raw(value(sim.state())?);
+        let gas_cost = U256::from(outcome.gas_used) * ru256_to_u256(env.block.basefee);
+        Ok((
+            Some(after - before - I256::from_raw(gas_cost)),
+            outcome.gas_used,
+        ))
+    };
+
+    let (top, top_gas) = run(&[])?;
+    let (tail, tail_gas) = run(ahead)?;
+    Ok(PlacementReport {
+        top,
+        tail,
+        top_gas,
+        tail_gas,
+    })
+}
+
+/// [simulate_placements] valuing the bundle by the ETH `executor` holds and its WETH, if `weth`
+/// is given, read from the WETH9 `balanceOf` mapping
+pub fn simulate_executor_placements<DB>(
+    db: &DB,
+    env: &Env,
+    bundle: &[TxEnv],
+    ahead: &[TxEnv],
+    executor: B160,
+    weth: Option<B160>,
+) -> DatabaseResult<PlacementReport>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    let weth_slot = u256_to_ru256(mapping_slot(b160_to_h160(executor), WETH_BALANCE_OF_SLOT));
+    simulate_placements(db, env, bundle, ahead, |state| {
+        let read = |e: DB::Error| DatabaseError::msg(format!("{:?}", e));
+        let eth = state
+            .basic(executor)
+            .map_err(read)?
+            .map_or(rU256::ZERO, |info| info.balance);
+        let weth = match weth {
+            Some(weth) => state.storage(weth, weth_slot).map_err(read)?,
+            None => rU256::ZERO,
+        };
+        Ok(ru256_to_u256(eth.saturating_add(weth)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preferred_placement() {
+        let report = PlacementReport {
+            top: Some(I256::from(1_000)),
+            tail: Some(I256::from(700)),
+            top_gas: 0,
+            tail_gas: 0,
+        };
+        assert_eq!(
+            report.preferred(U256::from(200)),
+            Some((Placement::TopOfBlock, I256::from(800)))
+        );
+        assert_eq!(
+            report.preferred(U256::from(400)),
+            Some((Placement::TailOfBlock, I256::from(700)))
+        );
+
+        // reverting at the tail, and not worth the premium at the top
+        let report = PlacementReport {
+            tail: None,
+            ..report
+        };
+        assert_eq!(report.preferred(U256::from(1_000)), None);
+
+        assert_eq!(
+            serde_json::to_string(&Placement::TailOfBlock).unwrap(),
+            "\"tail\""
+        );
+    }
+}
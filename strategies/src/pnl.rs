@@ -0,0 +1,349 @@
+//! PnL ledger surviving reorgs
+//!
+//! A bundle seen in a block isn't landed for good until the block is final, a reorg can drop it
+//! again. The [PnlLedger] keeps landed bundles as unconfirmed until their block has enough
+//! confirmations, or beacon finality reaches it, and marks them reorged when a [ReorgEvent]
+//! removes their block. Their txs can still be re-included in the new chain, so they are kept for
+//! as many blocks as a landed bundle waits for its confirmations. Only finalized profit should be
+//! fed to the [RiskManager](crate::risk::RiskManager) and
+//! [CapitalAllocator](crate::capital::CapitalAllocator). A [PnlReport] values the totals in USD
+//! through the [PriceOracle]. The ledger is kept across restarts with [PnlLedger::save] and
+//! [PnlLedger::load].
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use ethers::{
+    providers::Middleware,
+    types::{H256, I256},
+};
+use fork_database::reorg::ReorgEvent;
+use log::warn;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::pricing::{PriceOracle, PricingError};
+
+/// Confirmations a landed bundle needs by default
+pub const DEFAULT_CONFIRMATIONS: u64 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryStatus {
+    /// submitted, not seen in a block
+    Pending,
+    /// in block `number` with `hash`, which may still be reorged out
+    Landed {
+        number: u64,
+        hash: H256,
+    },
+    /// was in block `number`, which a reorg removed, the txs may land again
+    Reorged {
+        number: u64,
+    },
+    Finalized {
+        number: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub strategy: String,
+    pub target_block: u64,
+    pub status: EntryStatus,
+    /// realized profit of the landed bundle, zero while pending
+    pub profit: I256,
+    /// profit the bundle was simulated to make when it was submitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected_profit: Option<I256>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PnlLedger {
+    entries: BTreeMap<H256, LedgerEntry>,
+    confirmations: u64,
+}
+
+impl Default for PnlLedger {
+    fn default() -> Self {
+        Self::new(DEFAULT_CONFIRMATIONS)
+    }
+}
+
+impl PnlLedger {
+    pub fn new(confirmations: u64) -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            confirmations: confirmations.max(1),
+        }
+    }
+
+    /// Ledger [saved](PnlLedger::save) at `path`, an empty one if there is none yet
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write the ledger to `path`, a crash mid write leaves the previous file intact
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn confirmations(&self) -> u64 {
+        self.confirmations
+    }
+
+    pub fn entry(&self, bundle: &H256) -> Option<&LedgerEntry> {
+        self.entries.get(bundle)
+    }
+
+    /// The bundle was sent for blocks up to `target_block`, simulated to make `expected_profit`
+    pub fn submitted(
+        &mut self,
+        bundle: H256,
+        strategy: impl Into<String>,
+        target_block: u64,
+        expected_profit: Option<I256>,
+    ) {
+        self.entries.entry(bundle).or_insert_with(|| LedgerEntry {
+            strategy: strategy.into(),
+            target_block,
+            status: EntryStatus::Pending,
+            profit: I256::zero(),
+            expected_profit,
+        });
+    }
+
+    /// The bundle's txs were seen in block `number` with `hash`, making the realized `profit`
+    pub fn landed(&mut self, bundle: H256, number: u64, hash: H256, profit: I256) -> bool {
+        let Some(entry) = self.entries.get_mut(&bundle) else {
+            return false;
+        };
+        if matches!(entry.status, EntryStatus::Finalized { .. }) {
+            return false;
+        }
+        entry.status = EntryStatus::Landed { number, hash };
+        entry.profit = profit;
+        true
+    }
+
+    /// New head `number`, finalizes the bundles with enough confirmations
+    pub fn on_block(&mut self, number: u64) -> Vec<H256> {
+        let confirmations = self.confirmations;
+        self.finalize(|landed| number + 1 >= landed + confirmations)
+    }
+
+    /// Beacon finality reached block `number`
+    pub fn on_finalized(&mut self, number: u64) -> Vec<H256> {
+        self.finalize(|landed| landed <= number)
+    }
+
+    /// Reorged for the bundles whose block `event` removed, their profit isn't counted until they
+    /// land again
+    pub fn on_reorg(&mut self, event: &ReorgEvent) -> Vec<H256> {
+        let mut reverted = vec![];
+        for (bundle, entry) in self.entries.iter_mut() {
+            match entry.status {
+                EntryStatus::Landed { number, hash } if event.removes(number, hash) => {
+                    entry.status = EntryStatus::Reorged { number };
+                    entry.profit = I256::zero();
+                    reverted.push(*bundle);
+                }
+                EntryStatus::Finalized { number } if number > event.common_ancestor => {
+                    warn!(
+                        "Reorg of depth {} removed block {} of finalized bundle {:?}",
+                        event.depth(),
+                        number,
+                        bundle
+                    );
+                }
+                _ => {}
+            }
+        }
+        reverted
+    }
+
+    /// Drop pending bundles whose target block is before `number`, and reorged ones that didn't
+    /// land again within the confirmations of their removed block, they can't land anymore
+    pub fn expire(&mut self, number: u64) -> Vec<H256> {
+        let confirmations = self.confirmations;
+        let expired: Vec<H256> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| match e.status {
+                EntryStatus::Pending => e.target_block < number,
+                EntryStatus::Reorged { number: removed } => number >= removed + confirmations,
+                _ => false,
+            })
+            .map(|(bundle, _)| *bundle)
+            .collect();
+        for bundle in &expired {
+            self.entries.remove(bundle);
+        }
+        expired
+    }
+
+    /// Profit of finalized bundles, of `strategy` or of all of them
+    pub fn realized(&self, strategy: Option<&str>) -> I256 {
+        self.sum(strategy, |status| {
+            matches!(status, EntryStatus::Finalized { .. })
+        })
+    }
+
+    /// Profit of landed bundles that may still be reorged out
+    pub fn unconfirmed(&self, strategy: Option<&str>) -> I256 {
+        self.sum(strategy, |status| {
+            matches!(status, EntryStatus::Landed { .. })
+        })
+    }
+
+    fn finalize(&mut self, is_final: impl Fn(u64) -> bool) -> Vec<H256> {
+        let mut finalized = vec![];
+        for (bundle, entry) in self.entries.iter_mut() {
+            if let EntryStatus::Landed { number, .. } = entry.status {
+                if is_final(number) {
+                    entry.status = EntryStatus::Finalized { number };
+                    finalized.push(*bundle);
+                }
+            }
+        }
+        finalized
+    }
+
+    fn sum(&self, strategy: Option<&str>, counts: impl Fn(&EntryStatus) -> bool) -> I256 {
+        self.entries
+            .values()
+            .filter(|e| strategy.map_or(true, |s| e.strategy == s) && counts(&e.status))
+            .fold(I256::zero(), |sum, e| sum.saturating_add(e.profit))
+    }
+}
+
+/// Totals of a [PnlLedger], in WETH and in USD
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PnlReport {
+    pub realized: I256,
+    pub unconfirmed: I256,
+    pub realized_usd: f64,
+    pub unconfirmed_usd: f64,
+}
+
+impl PnlReport {
+    /// Realized and unconfirmed profit in `ledger`, of `strategy` or of all of them, converted
+    /// through the `oracle`'s ETH / USD feed
+    pub async fn of<M>(
+        ledger: &Mutex<PnlLedger>,
+        strategy: Option<&str>,
+        oracle: &PriceOracle<M>,
+    ) -> Result<Self, PricingError<M>>
+    where
+        M: Middleware + 'static,
+    {
+        let (realized, unconfirmed) = {
+            let ledger = ledger.lock();
+            (ledger.realized(strategy), ledger.unconfirmed(strategy))
+        };
+        Ok(Self {
+            realized,
+            unconfirmed,
+            realized_usd: to_usd(oracle, realized).await?,
+            unconfirmed_usd: to_usd(oracle, unconfirmed).await?,
+        })
+    }
+}
+
+async fn to_usd<M>(oracle: &PriceOracle<M>, weth: I256) -> Result<f64, PricingError<M>>
+where
+    M: Middleware + 'static,
+{
+    let usd = oracle.weth_to_usd(weth.unsigned_abs()).await?;
+    Ok(if weth.is_negative() { -usd } else { usd })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fork_database::reorg::ReorgWatcher;
+
+    #[test]
+    fn test_reorged_bundle_is_reverted() {
+        let block = |n: u64, fork: u64| H256::from_low_u64_be(n * 100 + fork);
+        let (ours, theirs) = (H256::from_low_u64_be(1), H256::from_low_u64_be(2));
+        let mut watcher = ReorgWatcher::default();
+        let mut ledger = PnlLedger::new(3);
+        for n in 1..=10 {
+            watcher.on_block(n, block(n, 0), block(n - 1, 0));
+        }
+        ledger.submitted(ours, "sandwich", 10, Some(I256::from(120)));
+        ledger.submitted(theirs, "arb", 8, None);
+        assert!(ledger.landed(ours, 10, block(10, 0), I256::from(100)));
+        assert!(ledger.landed(theirs, 8, block(8, 0), I256::from(-5)));
+        assert_eq!(ledger.on_block(10), vec![theirs]);
+        assert_eq!(ledger.unconfirmed(None), I256::from(100));
+        assert_eq!(ledger.realized(Some("arb")), I256::from(-5));
+        // the expectation is kept apart from what the bundle made
+        assert_eq!(
+            ledger.entry(&ours).unwrap().expected_profit,
+            Some(I256::from(120))
+        );
+
+        // block 10 is replaced before our bundle is confirmed
+        let event = watcher.on_block(10, block(10, 1), block(9, 0)).unwrap();
+        assert_eq!(ledger.on_reorg(&event), vec![ours]);
+        assert_eq!(
+            ledger.entry(&ours).unwrap().status,
+            EntryStatus::Reorged { number: 10 }
+        );
+        assert_eq!(ledger.unconfirmed(None), I256::zero());
+        // past its target, but its txs can still be re-included
+        assert!(ledger.expire(11).is_empty());
+
+        // re-included in the new chain, and finalized by the beacon chain
+        assert!(ledger.landed(ours, 11, block(11, 1), I256::from(90)));
+        assert_eq!(ledger.on_finalized(11), vec![ours]);
+        assert_eq!(ledger.realized(None), I256::from(85));
+        assert!(!ledger.landed(ours, 12, block(12, 1), I256::from(1)));
+
+        // a reorged bundle that doesn't land again is dropped after the confirmations
+        let dropped = H256::from_low_u64_be(3);
+        ledger.submitted(dropped, "arb", 12, None);
+        assert!(ledger.landed(dropped, 12, block(12, 1), I256::from(7)));
+        let event = ReorgEvent {
+            common_ancestor: 11,
+            removed: vec![(12, block(12, 1))],
+            new_head: (12, block(12, 2)),
+        };
+        assert_eq!(ledger.on_reorg(&event), vec![dropped]);
+        assert!(ledger.expire(14).is_empty());
+        assert_eq!(ledger.expire(15), vec![dropped]);
+        assert!(ledger.entry(&dropped).is_none());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("qilin-pnl-{}.json", std::process::id()));
+        assert_eq!(PnlLedger::load(&path).unwrap().realized(None), I256::zero());
+
+        let mut ledger = PnlLedger::new(2);
+        let (finalized, landed) = (H256::from_low_u64_be(1), H256::from_low_u64_be(2));
+        ledger.submitted(finalized, "arb", 10, None);
+        ledger.submitted(landed, "sandwich", 11, Some(I256::from(9)));
+        ledger.landed(finalized, 10, H256::from_low_u64_be(10), I256::from(-5));
+        ledger.landed(landed, 11, H256::from_low_u64_be(11), I256::from(7));
+        ledger.on_finalized(10);
+        ledger.save(&path).unwrap();
+
+        let loaded = PnlLedger::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.confirmations(), 2);
+        assert_eq!(loaded.realized(None), I256::from(-5));
+        assert_eq!(loaded.unconfirmed(Some("sandwich")), I256::from(7));
+        assert_eq!(loaded.entry(&landed), ledger.entry(&landed));
+    }
+}
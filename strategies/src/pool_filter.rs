@@ -0,0 +1,399 @@
+//! Per strategy pool filters, applied to opportunities as they leave detection
+//!
+//! Operators tune what each strategy may trade without code changes, e.g. only bluechip pools for
+//! sandwiches, a pipeline file sets a [PoolFilterConfig] per [Opportunity::kind]. Strategies
+//! missing from the file trade every pool.
+//!
+//! A pool's factory, its liquidity and when its tokens were first seen aren't stored in the
+//! [Pool], detection keeps them in a [PoolMetadata], updated with [PoolMetadata::observe] as pools
+//! come up and with [PoolMetadata::record_deployment] as new pools are discovered. Rules needing
+//! metadata a pool lacks reject it. Unknown keys in the file are an error, a misspelled rule
+//! would otherwise let everything through.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use collectors::{
+    opportunity::{Opportunity, SandwichOpportunity},
+    pair_discovery::{Deployment, Factory},
+};
+use ethers::types::{Address, U256};
+use qilin_cfmms::pool::{Pool, PoolType};
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PoolFilterError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// Why a pool is excluded from a strategy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterReason {
+    DeniedPool(Address),
+    NotAllowedPool(Address),
+    NotAllowedFactory(Address),
+    /// liquidity of the pool, `None` if unknown
+    LowLiquidity(Option<U256>),
+    /// token and its age in blocks, `None` if unknown
+    YoungToken(Address, Option<u64>),
+}
+
+/// Filter of one strategy, JSON
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolFilterConfig {
+    /// pools the strategy may trade, any pool if empty
+    pub allow_pools: Vec<Address>,
+    pub deny_pools: Vec<Address>,
+    /// in the unit [PoolMetadata::liquidity] is measured in, e.g. WETH
+    pub min_liquidity: Option<U256>,
+    /// blocks both tokens must have been around for
+    pub min_token_age_blocks: Option<u64>,
+    /// factories the pools must come from, any factory if empty
+    pub allow_factories: Vec<Address>,
+}
+
+/// Pipeline file, JSON, filters keyed by strategy, e.g. `"sandwich"` or `"two_pool_arb"`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    pub strategies: HashMap<String, PoolFilterConfig>,
+}
+
+/// What detection knows about pools beyond the [Pool] itself
+#[derive(Debug, Clone, Default)]
+pub struct PoolMetadata {
+    pub block_number: u64,
+    /// factory that created the pool
+    pub factories: HashMap<Address, Address>,
+    pub liquidity: HashMap<Address, U256>,
+    /// block a token was first seen in
+    pub token_first_seen: HashMap<Address, u64>,
+}
+
+impl PoolMetadata {
+    pub fn new(block_number: u64) -> Self {
+        Self {
+            block_number,
+            ..Default::default()
+        }
+    }
+
+    /// Record the factory of a pool `deployment` creates and its tokens as first seen in
+    /// `block_number`
+    pub fn record_deployment(&mut self, deployment: &Deployment, block_number: u64) {
+        for token in deployment.tokens() {
+            self.token_first_seen.entry(token).or_insert(block_number);
+        }
+        if let Deployment::Pool { factory, pool, .. } = deployment {
+            self.factories.insert(pool.address, *factory);
+        }
+    }
+
+    /// Bring `pools` up to `block_number`: their liquidity in WETH, the one of `factories` each was
+    /// deployed by and their tokens, first seen now unless recorded before. Token ages only count
+    /// from when the bot first saw the token.
+    pub fn observe<'a>(
+        &mut self,
+        pools: impl IntoIterator<Item = &'a Pool>,
+        factories: &[Factory],
+        weth: Address,
+        block_number: u64,
+    ) {
+        self.block_number = self.block_number.max(block_number);
+        for pool in pools {
+            if let Some(factory) = factories.iter().find(|factory| {
+                factory.variant == pool.pool_variant
+                    && factory.pool_address(pool.token_0, pool.token_1, pool.swap_fee)
+                        == pool.address
+            }) {
+                self.factories.insert(pool.address, factory.address);
+            }
+            match weth_liquidity(pool, weth) {
+                Some(liquidity) => self.liquidity.insert(pool.address, liquidity),
+                None => self.liquidity.remove(&pool.address),
+            };
+            for token in [pool.token_0, pool.token_1] {
+                self.token_first_seen.entry(token).or_insert(block_number);
+            }
+        }
+    }
+
+    fn token_age(&self, token: Address) -> Option<u64> {
+        self.token_first_seen
+            .get(&token)
+            .map(|first_seen| self.block_number.saturating_sub(*first_seen))
+    }
+}
+
+/// WETH side of `pool`'s reserves, the virtual reserves of the current range for V3 pools, `None`
+/// if it doesn't trade WETH
+pub fn weth_liquidity(pool: &Pool, weth: Address) -> Option<U256> {
+    if weth != pool.token_0 && weth != pool.token_1 {
+        return None;
+    }
+    let weth_is_0 = weth == pool.token_0;
+    match pool.pool_type {
+        PoolType::UniswapV2(pair) => Some(U256::from(if weth_is_0 {
+            pair.reserve_0
+        } else {
+            pair.reserve_1
+        })),
+        PoolType::UniswapV3(v3) => {
+            if v3.sqrt_price.is_zero() {
+                return None;
+            }
+            let (liquidity, q96) = (U256::from(v3.liquidity), U256::one() << 96);
+            Some(if weth_is_0 {
+                liquidity * q96 / v3.sqrt_price
+            } else {
+                liquidity * v3.sqrt_price / q96
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PoolFilter {
+    allow_pools: HashSet<Address>,
+    deny_pools: HashSet<Address>,
+    min_liquidity: Option<U256>,
+    min_token_age_blocks: Option<u64>,
+    allow_factories: HashSet<Address>,
+}
+
+impl PoolFilter {
+    pub fn from_config(config: PoolFilterConfig) -> Self {
+        Self {
+            allow_pools: config.allow_pools.into_iter().collect(),
+            deny_pools: config.deny_pools.into_iter().collect(),
+            min_liquidity: config.min_liquidity,
+            min_token_age_blocks: config.min_token_age_blocks,
+            allow_factories: config.allow_factories.into_iter().collect(),
+        }
+    }
+
+    /// Why `pool` must not be traded, `None` if it may. Denials win over allowances.
+    pub fn check(&self, pool: &Pool, metadata: &PoolMetadata) -> Option<FilterReason> {
+        if self.deny_pools.contains(&pool.address) {
+            return Some(FilterReason::DeniedPool(pool.address));
+        }
+        if !self.allow_pools.is_empty() && !self.allow_pools.contains(&pool.address) {
+            return Some(FilterReason::NotAllowedPool(pool.address));
+        }
+        if !self.allow_factories.is_empty() {
+            match metadata.factories.get(&pool.address) {
+                Some(factory) if self.allow_factories.contains(factory) => {}
+                Some(factory) => return Some(FilterReason::NotAllowedFactory(*factory)),
+                None => return Some(FilterReason::NotAllowedFactory(Address::zero())),
+            }
+        }
+        if let Some(min) = self.min_liquidity {
+            let liquidity = metadata.liquidity.get(&pool.address).copied();
+            if liquidity.map_or(true, |liquidity| liquidity < min) {
+                return Some(FilterReason::LowLiquidity(liquidity));
+            }
+        }
+        if let Some(min) = self.min_token_age_blocks {
+            for token in [pool.token_0, pool.token_1] {
+                let age = metadata.token_age(token);
+                if age.map_or(true, |age| age < min) {
+                    return Some(FilterReason::YoungToken(token, age));
+                }
+            }
+        }
+        None
+    }
+
+    pub fn is_allowed(&self, pool: &Pool, metadata: &PoolMetadata) -> bool {
+        self.check(pool, metadata).is_none()
+    }
+}
+
+/// The [PoolFilter]s of every strategy
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    filters: HashMap<String, PoolFilter>,
+}
+
+impl Pipeline {
+    pub fn from_config(config: PipelineConfig) -> Self {
+        Self {
+            filters: config
+                .strategies
+                .into_iter()
+                .map(|(strategy, filter)| (strategy, PoolFilter::from_config(filter)))
+                .collect(),
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, PoolFilterError> {
+        let config: PipelineConfig = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Ok(Self::from_config(config))
+    }
+
+    pub fn filter_of(&self, strategy: &str) -> Option<&PoolFilter> {
+        self.filters.get(strategy)
+    }
+
+    /// Why `opportunity` must be dropped, the first pool its strategy's filter rejects
+    pub fn check(
+        &self,
+        opportunity: &Opportunity,
+        metadata: &PoolMetadata,
+    ) -> Option<FilterReason> {
+        let filter = self.filter_of(opportunity.kind())?;
+        opportunity
+            .pools()
+            .into_iter()
+            .find_map(|pool| filter.check(pool, metadata))
+    }
+
+    /// Keep the opportunities all of whose pools pass their strategy's filter
+    pub fn retain(&self, opportunities: &mut Vec<Opportunity>, metadata: &PoolMetadata) {
+        opportunities.retain(|opportunity| match self.check(opportunity, metadata) {
+            Some(reason) => {
+                log::debug!("Dropping {} opportunity: {:?}", opportunity.kind(), reason);
+                false
+            }
+            None => true,
+        });
+    }
+
+    /// Keep the sandwiches on pools passing the `"sandwich"` filter
+    pub fn retain_sandwichable(
+        &self,
+        sandwiches: &mut Vec<SandwichOpportunity>,
+        metadata: &PoolMetadata,
+    ) {
+        if let Some(filter) = self.filter_of("sandwich") {
+            sandwiches.retain(|sandwich| filter.is_allowed(&sandwich.pool, metadata));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use collectors::opportunity::TwoPoolArbOpportunity;
+    use ethers::types::{Transaction, H256};
+    use qilin_cfmms::test_utils::{address, empty_pool as pool, v2_pool, v3_pool};
+
+    #[test]
+    fn test_pipeline_filters() {
+        let (bluechip, other, factory) = (pool(10), pool(11), address(20));
+        let metadata = PoolMetadata {
+            block_number: 1_000,
+            factories: HashMap::from([(bluechip.address, factory), (other.address, address(21))]),
+            liquidity: HashMap::from([
+                (bluechip.address, U256::from(100)),
+                (other.address, U256::from(5)),
+            ]),
+            token_first_seen: HashMap::from([(address(1), 0), (address(2), 900)]),
+        };
+
+        let config: PipelineConfig = serde_json::from_value(serde_json::json!({
+            "strategies": {
+                "sandwich": {
+                    "allow_factories": [format!("{:?}", factory)],
+                    "min_liquidity": "0x32",
+                },
+                "two_pool_arb": { "min_token_age_blocks": 200 },
+            }
+        }))
+        .unwrap();
+        let pipeline = Pipeline::from_config(config);
+        let sandwich = pipeline.filter_of("sandwich").unwrap();
+        assert!(sandwich.is_allowed(&bluechip, &metadata));
+        assert_eq!(
+            sandwich.check(&other, &metadata),
+            Some(FilterReason::NotAllowedFactory(address(21)))
+        );
+
+        let victim = Transaction {
+            hash: H256::from_low_u64_be(7),
+            ..Default::default()
+        };
+        let sandwich_on =
+            |pool, is_weth_input| SandwichOpportunity::new(victim.clone(), pool, is_weth_input);
+        let mut opportunities = vec![
+            Opportunity::TwoPoolArb(TwoPoolArbOpportunity {
+                trigger: Some(victim.hash),
+                moved: bluechip,
+                counter: other,
+                token_in: bluechip.token_1,
+                base: None,
+            }),
+            Opportunity::Sandwich(sandwich_on(bluechip, true)),
+            Opportunity::Sandwich(sandwich_on(other, false)),
+        ];
+        // token 2 is only 100 blocks old
+        assert_eq!(
+            pipeline.check(&opportunities[0], &metadata),
+            Some(FilterReason::YoungToken(address(2), Some(100)))
+        );
+        pipeline.retain(&mut opportunities, &metadata);
+        assert_eq!(opportunities.len(), 1);
+        assert!(matches!(&opportunities[0], Opportunity::Sandwich(s) if s.pool == bluechip));
+        let mut sandwiches = vec![sandwich_on(bluechip, true), sandwich_on(other, false)];
+        pipeline.retain_sandwichable(&mut sandwiches, &metadata);
+        assert_eq!(sandwiches, vec![sandwich_on(bluechip, true)]);
+
+        // strategies without a filter trade everything
+        assert!(pipeline.filter_of("multi_hop_arb").is_none());
+
+        // a typo doesn't silently allow every pool
+        let typo = serde_json::from_value::<PipelineConfig>(serde_json::json!({
+            "strategies": { "sandwich": { "allow_factory": [format!("{:?}", factory)] } }
+        }));
+        assert!(typo.is_err());
+    }
+
+    #[test]
+    fn test_observe_pools() {
+        let factory = Factory::mainnet()[0];
+        let (weth, token) = (address(1), address(2));
+        let reserve = U256::exp10(18) * 40;
+        let pair = v2_pool(
+            factory.pool_address(weth, token, U256::zero()),
+            weth,
+            token,
+            (reserve, reserve * 3),
+        );
+        let unknown = v2_pool(address(10), weth, token, (reserve, reserve));
+        let v3 = v3_pool(address(11), weth, token, 3_000, 10u128.pow(20));
+
+        let mut metadata = PoolMetadata::new(100);
+        metadata.observe([&pair, &unknown, &v3], &[factory], weth, 150);
+        assert_eq!(metadata.block_number, 150);
+        assert_eq!(
+            metadata.factories.get(&pair.address),
+            Some(&factory.address)
+        );
+        assert!(metadata.factories.get(&unknown.address).is_none());
+        assert_eq!(metadata.liquidity.get(&pair.address), Some(&reserve));
+        // at a price of 1 both virtual reserves are the liquidity
+        assert_eq!(metadata.liquidity.get(&v3.address), Some(&U256::exp10(20)));
+        assert_eq!(weth_liquidity(&pair, address(3)), None);
+
+        // tokens a discovered pool trades count from its deployment
+        let young = address(3);
+        let deployment = Deployment::Pool {
+            tx: H256::zero(),
+            factory: factory.address,
+            pool: v2_pool(address(12), weth, young, (reserve, reserve)),
+        };
+        metadata.record_deployment(&deployment, 120);
+        metadata.observe([], &[factory], weth, 170);
+        assert_eq!(metadata.token_age(young), Some(50));
+        assert_eq!(metadata.token_age(token), Some(20));
+        assert_eq!(metadata.factories.get(&address(12)), Some(&factory.address));
+    }
+}
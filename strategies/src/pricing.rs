@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::arb::u256_2_f64;
+use ethers::{
+    prelude::{abigen, ContractError},
+    providers::Middleware,
+    types::{Address, U256},
+};
+use log::warn;
+use qilin_cfmms::{
+    pool::{Pool, PoolType},
+    registry::PoolView,
+};
+use thiserror::Error;
+
+abigen!(
+    ChainlinkAggregator,
+    r#"[
+        function latestRoundData() external view returns (uint80 roundId, int256 answer, uint256 startedAt, uint256 updatedAt, uint80 answeredInRound)
+        function decimals() external view returns (uint8)
+    ]"#
+);
+
+abigen!(
+    UniswapV3QuoterV2,
+    r#"[
+        function quoteExactOutputSingle((address,address,uint256,uint24,uint160) params) external returns (uint256 amountIn, uint160 sqrtPriceX96After, uint32 initializedTicksCrossed, uint256 gasEstimate)
+    ]"#
+);
+
+/// Chainlink ETH / USD aggregator on mainnet
+pub const CHAINLINK_ETH_USD: &str = "0x5f4eC3Df9cbd43714FE2740f5E3616155c5b8419";
+
+#[derive(Error, Debug)]
+pub enum PricingError<M>
+where
+    M: Middleware,
+{
+    #[error("Middleware error")]
+    MiddlewareError(<M as Middleware>::Error),
+    #[error("Contract error")]
+    ContractError(#[from] ContractError<M>),
+    #[error("No route from token {0:?} to WETH")]
+    NoRoute(Address),
+    #[error("Chainlink answer is not positive")]
+    InvalidFeedAnswer,
+    #[error("Pool price deviates {0} bps from the Chainlink feed")]
+    PriceDeviation(u64),
+}
+
+/// How a token amount was converted into WETH
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriceSource {
+    Identity,
+    DirectPool(Address),
+    TwoHop(Address, Address),
+}
+
+/// A token amount valued in WETH and USD
+#[derive(Debug, Clone, Copy)]
+pub struct Valuation {
+    pub weth: U256,
+    pub usd: f64,
+    pub source: PriceSource,
+}
+
+/// A Chainlink `token / ETH` feed used to sanity check pool derived prices
+#[derive(Debug, Clone, Copy)]
+pub struct TokenFeed {
+    pub aggregator: Address,
+    pub token_decimals: u8,
+}
+
+/// Values arbitrary token amounts in WETH (best on-chain route through the pool graph) and USD
+pub struct PriceOracle<M> {
+    provider: Arc<M>,
+    weth: Address,
+    eth_usd_feed: Address,
+    /// intermediate tokens tried for two-hop routes, e.g. USDC / USDT / DAI
+    pub hop_tokens: Vec<Address>,
+    pub token_feeds: HashMap<Address, TokenFeed>,
+    /// max pool vs. Chainlink deviation before a valuation is rejected
+    pub max_deviation_bps: u64,
+}
+
+impl<M> PriceOracle<M>
+where
+    M: Middleware + 'static,
+{
+    pub fn new(provider: Arc<M>, weth: Address, eth_usd_feed: Address) -> Self {
+        Self {
+            provider,
+            weth,
+            eth_usd_feed,
+            hop_tokens: Vec::new(),
+            token_feeds: HashMap::new(),
+            max_deviation_bps: 500,
+        }
+    }
+
+    /// Value `amount` of `token` in WETH and USD
+    pub async fn value(
+        &self,
+        token: Address,
+        amount: U256,
+        pools: &PoolView,
+    ) -> Result<Valuation, PricingError<M>> {
+        let (weth, source) = self.checked_value_in_weth(token, amount, pools).await?;
+        let usd = self.weth_to_usd(weth).await?;
+        Ok(Valuation { weth, usd, source })
+    }
+
+    /// [PriceOracle::value_in_weth], rejected if the token's Chainlink feed, if any, disagrees by
+    /// more than `max_deviation_bps`
+    pub async fn checked_value_in_weth(
+        &self,
+        token: Address,
+        amount: U256,
+        pools: &PoolView,
+    ) -> Result<(U256, PriceSource), PricingError<M>> {
+        let (weth, source) = self.value_in_weth(token, amount, pools).await?;
+
+        if let Some(feed) = self.token_feeds.get(&token) {
+            let reference = self.feed_value_in_weth(feed, amount).await?;
+            let deviation = deviation_bps(weth, reference);
+            if deviation > self.max_deviation_bps {
+                return Err(PricingError::PriceDeviation(deviation));
+            }
+        }
+        Ok((weth, source))
+    }
+
+    /// Best WETH output for `amount` of `token`, trying direct pools first and two-hop routes through `hop_tokens`
+    pub async fn value_in_weth(
+        &self,
+        token: Address,
+        amount: U256,
+        pools: &PoolView,
+    ) -> Result<(U256, PriceSource), PricingError<M>> {
+        if token == self.weth {
+            return Ok((amount, PriceSource::Identity));
+        }
+
+        let mut best: Option<(U256, PriceSource)> = None;
+
+        for pool in pools_for_pair(pools, token, self.weth) {
+            if let Some(out) = quote_exact_in(&pool, token, amount, self.provider.clone()).await {
+                if best.map_or(true, |(b, _)| out > b) {
+                    best = Some((out, PriceSource::DirectPool(pool.address)));
+                }
+            }
+        }
+
+        for hop in self.hop_tokens.iter().filter(|hop| **hop != token) {
+            for first in pools_for_pair(pools, token, *hop) {
+                let mid = match quote_exact_in(&first, token, amount, self.provider.clone()).await {
+                    Some(mid) if !mid.is_zero() => mid,
+                    _ => continue,
+                };
+                for second in pools_for_pair(pools, *hop, self.weth) {
+                    if let Some(out) =
+                        quote_exact_in(&second, *hop, mid, self.provider.clone()).await
+                    {
+                        if best.map_or(true, |(b, _)| out > b) {
+                            best = Some((out, PriceSource::TwoHop(first.address, second.address)));
+                        }
+                    }
+                }
+            }
+        }
+
+        best.ok_or(PricingError::NoRoute(token))
+    }
+
+    /// Convert a WETH amount into USD using the Chainlink ETH / USD feed
+    pub async fn weth_to_usd(&self, weth: U256) -> Result<f64, PricingError<M>> {
+        let (answer, decimals) = self.latest_answer(self.eth_usd_feed).await?;
+        let eth_usd = u256_2_f64(answer) / 10f64.powi(decimals as i32);
+        Ok(u256_2_f64(weth) / 1e18 * eth_usd)
+    }
+
+    async fn feed_value_in_weth(
+        &self,
+        feed: &TokenFeed,
+        amount: U256,
+    ) -> Result<U256, PricingError<M>> {
+        let (answer, decimals) = self.latest_answer(feed.aggregator).await?;
+        Ok(feed_value(amount, feed.token_decimals, answer, decimals))
+    }
+
+    async fn latest_answer(&self, aggregator: Address) -> Result<(U256, u8), PricingError<M>> {
+        let feed = ChainlinkAggregator::new(aggregator, self.provider.clone());
+        let (_, answer, _, _, _) = feed.latest_round_data().call().await?;
+        let decimals = feed.decimals().call().await?;
+
+        if answer.is_negative() || answer.is_zero() {
+            return Err(PricingError::InvalidFeedAnswer);
+        }
+        Ok((answer.into_raw(), decimals))
+    }
+}
+
+/// All pools trading `token_a` against `token_b`
+pub fn pools_for_pair(pools: &PoolView, token_a: Address, token_b: Address) -> Vec<Pool> {
+    pools
+        .iter()
+        .filter(|pool| {
+            (pool.token_0 == token_a && pool.token_1 == token_b)
+                || (pool.token_0 == token_b && pool.token_1 == token_a)
+        })
+        .copied()
+        .collect()
+}
+
+/// Quote `amount_in` of `token_in` through the pool's current state
+pub async fn quote_exact_in<M>(
+    pool: &Pool,
+    token_in: Address,
+    amount_in: U256,
+    provider: Arc<M>,
+) -> Option<U256>
+where
+    M: Middleware + 'static,
+{
+    match &pool.pool_type {
+        PoolType::UniswapV2(v2) => Some(v2.simulate_swap(token_in, amount_in)),
+        PoolType::UniswapV3(v3) => match v3.simulate_swap(token_in, amount_in, provider).await {
+            Ok(amount_out) => Some(amount_out),
+            Err(e) => {
+                warn!("Error simulating V3 swap on {:?}: {:?}", pool.address, e);
+                None
+            }
+        },
+    }
+}
+
+/// Smallest amount of `token_in`, at most `max_in`, the pool swaps for `amount_out`: V2's
+/// `getAmountIn` on the pair's reserves, V3's `quoteExactOutputSingle` on the `quoter`. `None` if
+/// `max_in` falls short
+pub async fn quote_exact_out<M>(
+    pool: &Pool,
+    token_in: Address,
+    amount_out: U256,
+    max_in: U256,
+    quoter: Address,
+    provider: Arc<M>,
+) -> Option<U256>
+where
+    M: Middleware + 'static,
+{
+    if amount_out.is_zero() {
+        return Some(U256::zero());
+    }
+    let token_out = if token_in == pool.token_0 {
+        pool.token_1
+    } else {
+        pool.token_0
+    };
+    let amount_in = match &pool.pool_type {
+        PoolType::UniswapV2(v2) => {
+            let (reserve_0, reserve_1) = (U256::from(v2.reserve_0), U256::from(v2.reserve_1));
+            let (reserve_in, reserve_out) = if token_in == pool.token_0 {
+                (reserve_0, reserve_1)
+            } else {
+                (reserve_1, reserve_0)
+            };
+            qilin_math::v2::get_amount_in(amount_out, reserve_in, reserve_out)?
+        }
+        PoolType::UniswapV3(v3) => {
+            let quoter = UniswapV3QuoterV2::new(quoter, provider);
+            let params = (token_in, token_out, amount_out, v3.fee, U256::zero());
+            match quoter.quote_exact_output_single(params).call().await {
+                Ok((amount_in, _, _, _)) => amount_in,
+                Err(e) => {
+                    warn!(
+                        "Error quoting V3 exact output on {:?}: {:?}",
+                        pool.address, e
+                    );
+                    return None;
+                }
+            }
+        }
+    };
+    (amount_in <= max_in).then_some(amount_in)
+}
+
+/// WETH value of `amount` of a token with `token_decimals` at a `token / ETH` feed `answer` given
+/// in `feed_decimals`
+pub fn feed_value(amount: U256, token_decimals: u8, answer: U256, feed_decimals: u8) -> U256 {
+    let value = amount * answer;
+    let value = if feed_decimals <= 18 {
+        value * U256::exp10(18 - feed_decimals as usize)
+    } else {
+        value / U256::exp10(feed_decimals as usize - 18)
+    };
+    value / U256::exp10(token_decimals as usize)
+}
+
+/// Relative difference of `value` against `reference`, in basis points
+pub fn deviation_bps(value: U256, reference: U256) -> u64 {
+    if reference.is_zero() {
+        return if value.is_zero() { 0 } else { u64::MAX };
+    }
+    let diff = if value > reference {
+        value - reference
+    } else {
+        reference - value
+    };
+    let bps = diff * U256::from(10_000) / reference;
+    if bps > U256::from(u64::MAX) {
+        u64::MAX
+    } else {
+        bps.as_u64()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::{Http, Provider};
+    use qilin_cfmms::test_utils::{address, v2_pool};
+
+    #[test]
+    fn test_deviation_bps() {
+        assert_eq!(deviation_bps(U256::from(100), U256::from(100)), 0);
+        assert_eq!(deviation_bps(U256::from(105), U256::from(100)), 500);
+        assert_eq!(deviation_bps(U256::from(95), U256::from(100)), 500);
+        assert_eq!(deviation_bps(U256::from(1), U256::zero()), u64::MAX);
+    }
+
+    #[test]
+    fn test_feed_value() {
+        // 1000 USDC at 0.0005 ETH, from an 18 decimals feed and an 8 decimals one
+        let usdc = U256::exp10(6) * 1000;
+        let weth = U256::exp10(17) * 5;
+        assert_eq!(feed_value(usdc, 6, U256::exp10(14) * 5, 18), weth);
+        assert_eq!(feed_value(usdc, 6, U256::from(50_000), 8), weth);
+        assert_eq!(feed_value(usdc, 6, U256::exp10(16) * 5, 20), weth);
+    }
+
+    #[tokio::test]
+    async fn test_quote_exact_out() {
+        let (token_0, token_1) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let reserve = U256::exp10(18) * 1000;
+        let pool = v2_pool(address(0xfee), token_0, token_1, (reserve, reserve));
+        let provider = Arc::new(Provider::<Http>::try_from("http://localhost:8545").unwrap());
+        let amount_out = U256::exp10(18);
+        let max_in = U256::exp10(18) * 2;
+
+        let quoter = Address::zero();
+        let amount_in =
+            quote_exact_out(&pool, token_0, amount_out, max_in, quoter, provider.clone())
+                .await
+                .unwrap();
+        assert_eq!(
+            Some(amount_in),
+            qilin_math::v2::get_amount_in(amount_out, reserve, reserve)
+        );
+        let short = quote_exact_in(&pool, token_0, amount_in - 1, provider.clone()).await;
+        assert!(short.unwrap() < amount_out);
+
+        // doesn't buy the amount within the budget
+        assert_eq!(
+            quote_exact_out(
+                &pool,
+                token_0,
+                amount_out,
+                U256::exp10(17),
+                quoter,
+                provider
+            )
+            .await,
+            None
+        );
+    }
+}
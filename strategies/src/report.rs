@@ -0,0 +1,548 @@
+//! Explainability reports of submitted bundles, for postmortems
+//!
+//! An [OpportunityReport] holds everything that went into a bundle in one file: the victim as
+//! decoded, an excerpt of the state diff that triggered it, the pools before and after, every
+//! size the optimizer tried and the simulation of each tx. [ReportStore] writes one per bundle as
+//! JSON, and as a standalone HTML page if asked to, and adds the realized PnL once the bundle's
+//! fate is known.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use collectors::{diff_explain::DiffExplanation, opportunity::Opportunity};
+use ethers::types::{Address, Bytes, Transaction, H256, I256, U256};
+use qilin_cfmms::pool::Pool;
+use revm::primitives::{ExecutionResult, Output};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+/// Bumped on every breaking change to the report layout
+pub const REPORT_SCHEMA_VERSION: u32 = 1;
+/// Accounts of the state diff kept in a report
+pub const MAX_DIFF_ACCOUNTS: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum ReportError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VictimDecoding {
+    pub hash: H256,
+    pub from: Address,
+    pub to: Option<Address>,
+    #[serde(with = "decimal")]
+    pub value: U256,
+    pub selector: Option<String>,
+    /// e.g. `swapExactETHForTokens`, if the calldata could be decoded
+    pub function: Option<String>,
+    /// decoded arguments, rendered
+    pub args: Vec<String>,
+}
+
+impl VictimDecoding {
+    pub fn from_tx(tx: &Transaction) -> Self {
+        Self {
+            hash: tx.hash,
+            from: tx.from,
+            to: tx.to,
+            value: tx.value,
+            selector: (tx.input.len() >= 4)
+                .then(|| format!("0x{}", ethers::utils::hex::encode(&tx.input[..4]))),
+            function: None,
+            args: vec![],
+        }
+    }
+
+    pub fn with_function(mut self, function: impl Into<String>, args: Vec<String>) -> Self {
+        self.function = Some(function.into());
+        self.args = args;
+        self
+    }
+}
+
+/// One changed account of the state diff, see [DiffExplanation]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountExcerpt {
+    pub address: Address,
+    pub label: String,
+    /// `(what, from, to)`
+    pub changes: Vec<(String, String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolStates {
+    pub before: Pool,
+    pub after: Pool,
+}
+
+/// A frontrun size the optimizer evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SearchStep {
+    #[serde(with = "decimal")]
+    pub amount_in: U256,
+    /// `None` where the bundle doesn't work, e.g. the victim reverts
+    #[serde(with = "option_decimal")]
+    pub profit: Option<I256>,
+}
+
+/// Outcome of one simulated tx of the bundle
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimTrace {
+    /// e.g. `frontrun`, `victim`, `backrun`
+    pub label: String,
+    pub success: bool,
+    pub gas_used: u64,
+    pub logs: usize,
+    /// return or revert data
+    pub output: Bytes,
+    pub halt: Option<String>,
+}
+
+impl SimTrace {
+    pub fn from_result(label: impl Into<String>, result: &ExecutionResult) -> Self {
+        let mut trace = Self {
+            label: label.into(),
+            success: result.is_success(),
+            gas_used: result.gas_used(),
+            logs: 0,
+            output: Bytes::default(),
+            halt: None,
+        };
+        match result {
+            ExecutionResult::Success { logs, output, .. } => {
+                trace.logs = logs.len();
+                trace.output = match output {
+                    Output::Call(data) | Output::Create(data, _) => data.clone().into(),
+                };
+            }
+            ExecutionResult::Revert { output, .. } => trace.output = output.clone().into(),
+            ExecutionResult::Halt { reason, .. } => trace.halt = Some(format!("{:?}", reason)),
+        }
+        trace
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpportunityReport {
+    pub schema_version: u32,
+    pub opportunity_id: String,
+    pub strategy: String,
+    /// unix timestamp
+    pub created_at: u64,
+    pub target_block: u64,
+    pub bundle_hash: Option<H256>,
+    pub opportunity: Opportunity,
+    pub victim: Option<VictimDecoding>,
+    pub state_diff: Vec<AccountExcerpt>,
+    pub pools: Vec<PoolStates>,
+    pub search: Vec<SearchStep>,
+    pub simulations: Vec<SimTrace>,
+    /// set once the bundle landed or expired, zero for the latter
+    #[serde(with = "option_decimal")]
+    pub realized_profit: Option<I256>,
+}
+
+impl OpportunityReport {
+    pub fn new(
+        opportunity_id: impl Into<String>,
+        strategy: impl Into<String>,
+        target_block: u64,
+        opportunity: Opportunity,
+    ) -> Self {
+        Self {
+            schema_version: REPORT_SCHEMA_VERSION,
+            opportunity_id: opportunity_id.into(),
+            strategy: strategy.into(),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            target_block,
+            bundle_hash: None,
+            opportunity,
+            victim: None,
+            state_diff: vec![],
+            pools: vec![],
+            search: vec![],
+            simulations: vec![],
+            realized_profit: None,
+        }
+    }
+
+    pub fn with_bundle_hash(mut self, bundle_hash: H256) -> Self {
+        self.bundle_hash = Some(bundle_hash);
+        self
+    }
+
+    pub fn with_victim(mut self, victim: VictimDecoding) -> Self {
+        self.victim = Some(victim);
+        self
+    }
+
+    /// Keeps the first [MAX_DIFF_ACCOUNTS] accounts of the explanation
+    pub fn with_state_diff(mut self, explanation: &DiffExplanation) -> Self {
+        self.state_diff = explanation
+            .accounts
+            .iter()
+            .take(MAX_DIFF_ACCOUNTS)
+            .map(|account| AccountExcerpt {
+                address: account.address,
+                label: account.label.clone(),
+                changes: account
+                    .changes
+                    .iter()
+                    .map(|c| (c.what.clone(), c.from.clone(), c.to.clone()))
+                    .collect(),
+            })
+            .collect();
+        self
+    }
+
+    pub fn with_pool(mut self, before: Pool, after: Pool) -> Self {
+        self.pools.push(PoolStates { before, after });
+        self
+    }
+
+    /// E.g. collected through [optimize_v2_sandwich_traced](qilin_math::sandwich::optimize_v2_sandwich_traced)
+    pub fn with_search(mut self, search: Vec<SearchStep>) -> Self {
+        self.search = search;
+        self
+    }
+
+    pub fn with_simulation(mut self, trace: SimTrace) -> Self {
+        self.simulations.push(trace);
+        self
+    }
+
+    /// File name, without extension, of the report
+    pub fn file_stem(&self) -> String {
+        match self.bundle_hash {
+            Some(hash) => format!("{:?}", hash),
+            None => self.opportunity_id.clone(),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, ReportError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Standalone page, no scripts or external styles
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+        let title = format!("{} {}", self.strategy, self.file_stem());
+        let _ = write!(
+            html,
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title></head><body>",
+            escape(&title)
+        );
+        let _ = write!(html, "<h1>{}</h1>", escape(&title));
+        let summary = [
+            ("Opportunity", self.opportunity_id.clone()),
+            ("Kind", self.opportunity.kind().to_string()),
+            ("Target block", self.target_block.to_string()),
+            (
+                "Realized profit",
+                self.realized_profit
+                    .map_or("pending".into(), |profit| profit.to_string()),
+            ),
+        ];
+        table(
+            &mut html,
+            "Summary",
+            &["", ""],
+            summary.iter().map(|(k, v)| vec![k.to_string(), v.clone()]),
+        );
+
+        if let Some(victim) = &self.victim {
+            let rows = [
+                ("Hash", format!("{:?}", victim.hash)),
+                ("From", format!("{:?}", victim.from)),
+                ("To", format!("{:?}", victim.to)),
+                ("Value", victim.value.to_string()),
+                (
+                    "Function",
+                    victim
+                        .function
+                        .clone()
+                        .or_else(|| victim.selector.clone())
+                        .unwrap_or_default(),
+                ),
+                ("Arguments", victim.args.join(", ")),
+            ];
+            table(
+                &mut html,
+                "Victim",
+                &["", ""],
+                rows.iter().map(|(k, v)| vec![k.to_string(), v.clone()]),
+            );
+        }
+        table(
+            &mut html,
+            "State diff",
+            &["Account", "Change", "From", "To"],
+            self.state_diff.iter().flat_map(|account| {
+                account.changes.iter().map(move |(what, from, to)| {
+                    vec![
+                        account.label.clone(),
+                        what.clone(),
+                        from.clone(),
+                        to.clone(),
+                    ]
+                })
+            }),
+        );
+        table(
+            &mut html,
+            "Pools",
+            &["Pool", "Before", "After"],
+            self.pools.iter().map(|states| {
+                vec![
+                    format!("{:?}", states.before.address),
+                    format!("{:?}", states.before.pool_type),
+                    format!("{:?}", states.after.pool_type),
+                ]
+            }),
+        );
+        table(
+            &mut html,
+            "Optimizer search",
+            &["Amount in", "Profit"],
+            self.search.iter().map(|step| {
+                vec![
+                    step.amount_in.to_string(),
+                    step.profit
+                        .map_or("invalid".into(), |profit| profit.to_string()),
+                ]
+            }),
+        );
+        table(
+            &mut html,
+            "Simulations",
+            &["Tx", "Success", "Gas used", "Logs", "Output"],
+            self.simulations.iter().map(|trace| {
+                vec![
+                    trace.label.clone(),
+                    trace.success.to_string(),
+                    trace.gas_used.to_string(),
+                    trace.logs.to_string(),
+                    trace
+                        .halt
+                        .clone()
+                        .unwrap_or_else(|| trace.output.to_string()),
+                ]
+            }),
+        );
+        html.push_str("</body></html>");
+        html
+    }
+}
+
+/// Directory of reports, next to the PnL records of the bundles
+#[derive(Debug, Clone)]
+pub struct ReportStore {
+    dir: PathBuf,
+    html: bool,
+}
+
+impl ReportStore {
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self, ReportError> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, html: false })
+    }
+
+    /// Also write an HTML page for every report
+    pub fn with_html(mut self, html: bool) -> Self {
+        self.html = html;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Write `report`, returns the path of its JSON file
+    pub fn save(&self, report: &OpportunityReport) -> Result<PathBuf, ReportError> {
+        let path = self.dir.join(format!("{}.json", report.file_stem()));
+        fs::write(&path, report.to_json()?)?;
+        if self.html {
+            fs::write(
+                self.dir.join(format!("{}.html", report.file_stem())),
+                report.to_html(),
+            )?;
+        }
+        Ok(path)
+    }
+
+    pub fn load(&self, file_stem: &str) -> Result<OpportunityReport, ReportError> {
+        let path = self.dir.join(format!("{}.json", file_stem));
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Add the realized profit to a saved report
+    pub fn record_pnl(
+        &self,
+        file_stem: &str,
+        realized_profit: I256,
+    ) -> Result<OpportunityReport, ReportError> {
+        let mut report = self.load(file_stem)?;
+        report.realized_profit = Some(realized_profit);
+        self.save(&report)?;
+        Ok(report)
+    }
+}
+
+fn table<I>(html: &mut String, title: &str, header: &[&str], rows: I)
+where
+    I: IntoIterator<Item = Vec<String>>,
+{
+    let _ = write!(html, "<h2>{}</h2><table border=\"1\"><tr>", escape(title));
+    for column in header {
+        let _ = write!(html, "<th>{}</th>", escape(column));
+    }
+    html.push_str("</tr>");
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in row {
+            let _ = write!(html, "<td>{}</td>", escape(&cell));
+        }
+        html.push_str("</tr>");
+    }
+    html.push_str("</table>");
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Amounts go out as decimal strings, JSON numbers lose precision past 2^53
+mod decimal {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<U256, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        U256::from_dec_str(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Optional signed amounts as decimal strings, e.g. for `#[serde(with = "...")]` on profits
+pub mod option_decimal {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<I256>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.serialize_some(&value.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<I256>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|value| I256::from_dec_str(&value).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use collectors::opportunity::SandwichOpportunity;
+    use qilin_cfmms::pool::PoolVariant;
+    use qilin_math::sandwich::optimize_v2_sandwich_traced;
+    use qilin_math::tax::TaxRates;
+
+    #[test]
+    fn test_report_roundtrip() {
+        let pool = Pool::new_empty_pool(
+            Address::from_low_u64_be(10),
+            Address::from_low_u64_be(1),
+            Address::from_low_u64_be(2),
+            U256::zero(),
+            PoolVariant::UniswapV2,
+        );
+        let victim = Transaction {
+            hash: H256::from_low_u64_be(7),
+            input: vec![0x7f, 0xf3, 0x6a, 0xb5, 0x00].into(),
+            ..Default::default()
+        };
+        let ether = |n: u64| U256::exp10(18) * n;
+        let mut search = vec![];
+        optimize_v2_sandwich_traced(
+            ether(1_000),
+            ether(2_000_000),
+            ether(50),
+            U256::zero(),
+            ether(10),
+            TaxRates::default(),
+            |amount_in, profit| search.push(SearchStep { amount_in, profit }),
+        )
+        .unwrap();
+        assert!(!search.is_empty());
+
+        let report = OpportunityReport::new(
+            "0x01",
+            "sandwich",
+            17_000_001,
+            Opportunity::Sandwich(SandwichOpportunity::new(victim.clone(), pool, true)),
+        )
+        .with_bundle_hash(H256::from_low_u64_be(9))
+        .with_victim(
+            VictimDecoding::from_tx(&victim)
+                .with_function("swapExactETHForTokens", vec!["0".into()]),
+        )
+        .with_pool(pool, pool)
+        .with_search(search)
+        .with_simulation(SimTrace::from_result(
+            "backrun",
+            &ExecutionResult::Revert {
+                gas_used: 50_000,
+                output: vec![0xde, 0xad].into(),
+            },
+        ));
+        assert_eq!(
+            report.victim.as_ref().unwrap().selector.as_deref(),
+            Some("0x7ff36ab5")
+        );
+
+        let dir = std::env::temp_dir().join(format!("qilin-reports-{}", std::process::id()));
+        let store = ReportStore::open(&dir).unwrap().with_html(true);
+        store.save(&report).unwrap();
+        let stem = report.file_stem();
+        let landed = store.record_pnl(&stem, I256::from(-42)).unwrap();
+        let html = fs::read_to_string(dir.join(format!("{}.html", stem))).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join(format!("{}.json", stem))).unwrap())
+                .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            landed,
+            OpportunityReport {
+                realized_profit: Some(I256::from(-42)),
+                ..report
+            }
+        );
+        assert_eq!(json["realized_profit"], "-42");
+        assert!(html.contains("swapExactETHForTokens") && html.contains("<td>-42</td>"));
+    }
+}
@@ -0,0 +1,284 @@
+//! Naming the reverts of known routers and pools
+//!
+//! Simulations mostly revert in a handful of ways: the trade moved the price past a limit, the
+//! output came short of the minimum, the state we simulated on is stale, or the opportunity is
+//! simply gone. [decode_revert] maps revert data from Uniswap V2/V3, Curve, Balancer, Aave and the
+//! major routers to a name and a [Remedy], so sizing can shrink the trade instead of giving up, and
+//! give up instead of retrying what can't succeed.
+
+use ethers::{
+    abi::{self, ParamType},
+    types::U256,
+    utils::{hex, id},
+};
+
+/// What to do about a revert
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Remedy {
+    /// a price limit, slippage or ratio check failed, a smaller trade may pass
+    ReduceSize,
+    /// the state the simulation ran on is stale, resync and try again
+    Resync,
+    /// won't pass at any size, e.g. an expired deadline or a paused market
+    Abandon,
+    /// not in the dictionary
+    Unknown,
+}
+
+impl Remedy {
+    /// Size to try next after reverting at `size`, `None` to give up
+    pub fn retry_size(&self, size: U256) -> Option<U256> {
+        match self {
+            Remedy::ReduceSize => Some(size / 2).filter(|size| !size.is_zero()),
+            Remedy::Resync => Some(size),
+            Remedy::Abandon | Remedy::Unknown => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedRevert {
+    /// the revert string, custom error signature or panic, e.g. `V3: SPL`
+    pub name: String,
+    pub protocol: Option<&'static str>,
+    pub remedy: Remedy,
+}
+
+/// `Error(string)` reasons, the protocol and what they mean for us
+const REVERT_STRINGS: &[(&str, &str, Remedy)] = &[
+    // Uniswap V2 pair and router
+    (
+        "UniswapV2: INSUFFICIENT_OUTPUT_AMOUNT",
+        "Uniswap V2",
+        Remedy::ReduceSize,
+    ),
+    (
+        "UniswapV2: INSUFFICIENT_INPUT_AMOUNT",
+        "Uniswap V2",
+        Remedy::ReduceSize,
+    ),
+    (
+        "UniswapV2: INSUFFICIENT_LIQUIDITY",
+        "Uniswap V2",
+        Remedy::ReduceSize,
+    ),
+    ("UniswapV2: K", "Uniswap V2", Remedy::Resync),
+    ("UniswapV2: LOCKED", "Uniswap V2", Remedy::Abandon),
+    (
+        "UniswapV2Router: INSUFFICIENT_OUTPUT_AMOUNT",
+        "Uniswap V2",
+        Remedy::ReduceSize,
+    ),
+    (
+        "UniswapV2Router: EXCESSIVE_INPUT_AMOUNT",
+        "Uniswap V2",
+        Remedy::ReduceSize,
+    ),
+    ("UniswapV2Router: EXPIRED", "Uniswap V2", Remedy::Abandon),
+    (
+        "TransferHelper: TRANSFER_FROM_FAILED",
+        "Uniswap V2",
+        Remedy::Abandon,
+    ),
+    // Uniswap V3 pool and periphery, the pool uses short codes
+    ("SPL", "Uniswap V3", Remedy::ReduceSize),
+    ("IIA", "Uniswap V3", Remedy::Resync),
+    ("AS", "Uniswap V3", Remedy::Abandon),
+    ("LOK", "Uniswap V3", Remedy::Abandon),
+    ("STF", "Uniswap V3", Remedy::Abandon),
+    ("Too little received", "Uniswap V3", Remedy::ReduceSize),
+    ("Too much requested", "Uniswap V3", Remedy::ReduceSize),
+    ("Transaction too old", "Uniswap V3", Remedy::Abandon),
+    // Curve
+    (
+        "Exchange resulted in fewer coins than expected",
+        "Curve",
+        Remedy::ReduceSize,
+    ),
+    ("Slippage", "Curve", Remedy::ReduceSize),
+    // Balancer V2 error codes
+    ("BAL#304", "Balancer", Remedy::ReduceSize),
+    ("BAL#305", "Balancer", Remedy::ReduceSize),
+    ("BAL#507", "Balancer", Remedy::ReduceSize),
+    ("BAL#508", "Balancer", Remedy::Abandon),
+    // Aave V3 error codes
+    ("26", "Aave", Remedy::Abandon),
+    ("45", "Aave", Remedy::Abandon),
+];
+
+/// Custom errors, by signature
+const CUSTOM_ERRORS: &[(&str, &str, Remedy)] = &[
+    // Uniswap Universal Router
+    (
+        "V2TooLittleReceived()",
+        "Universal Router",
+        Remedy::ReduceSize,
+    ),
+    (
+        "V2TooMuchRequested()",
+        "Universal Router",
+        Remedy::ReduceSize,
+    ),
+    (
+        "V3TooLittleReceived()",
+        "Universal Router",
+        Remedy::ReduceSize,
+    ),
+    (
+        "V3TooMuchRequested()",
+        "Universal Router",
+        Remedy::ReduceSize,
+    ),
+    ("V2InvalidPath()", "Universal Router", Remedy::Abandon),
+    ("InsufficientETH()", "Universal Router", Remedy::Abandon),
+    (
+        "InsufficientToken()",
+        "Universal Router",
+        Remedy::ReduceSize,
+    ),
+    (
+        "TransactionDeadlinePassed()",
+        "Universal Router",
+        Remedy::Abandon,
+    ),
+    // Permit2
+    ("AllowanceExpired(uint256)", "Permit2", Remedy::Abandon),
+    ("InsufficientAllowance(uint256)", "Permit2", Remedy::Abandon),
+    // 1inch
+    ("ReturnAmountIsNotEnough()", "1inch", Remedy::ReduceSize),
+    // Uniswap V4
+    (
+        "PriceLimitAlreadyExceeded(uint160,uint160)",
+        "Uniswap V4",
+        Remedy::Resync,
+    ),
+    ("PoolNotInitialized()", "Uniswap V4", Remedy::Abandon),
+];
+
+/// Decode `output`, the data of a reverted call
+pub fn decode_revert(output: &[u8]) -> DecodedRevert {
+    if output.is_empty() {
+        // bare `require`s and `assert`s of vyper pools, Curve's slippage checks revert like this
+        return DecodedRevert {
+            name: "empty revert".to_string(),
+            protocol: None,
+            remedy: Remedy::Unknown,
+        };
+    }
+
+    if let Some(reason) = decode_error_string(output) {
+        return match REVERT_STRINGS.iter().find(|(known, ..)| *known == reason) {
+            Some((_, protocol, remedy)) => DecodedRevert {
+                name: short_name(protocol, &reason),
+                protocol: Some(protocol),
+                remedy: *remedy,
+            },
+            None => DecodedRevert {
+                name: reason,
+                protocol: None,
+                remedy: Remedy::Unknown,
+            },
+        };
+    }
+
+    if let Some(code) = decode_panic(output) {
+        return DecodedRevert {
+            name: format!("Panic({:#x})", code),
+            protocol: None,
+            // overflows mostly come from oversized amounts
+            remedy: if code == U256::from(0x11) {
+                Remedy::ReduceSize
+            } else {
+                Remedy::Unknown
+            },
+        };
+    }
+
+    if output.len() >= 4 {
+        if let Some((signature, protocol, remedy)) = CUSTOM_ERRORS
+            .iter()
+            .find(|(signature, ..)| id(signature) == output[..4])
+        {
+            return DecodedRevert {
+                name: signature.to_string(),
+                protocol: Some(protocol),
+                remedy: *remedy,
+            };
+        }
+    }
+
+    DecodedRevert {
+        name: format!("0x{}", hex::encode(output)),
+        protocol: None,
+        remedy: Remedy::Unknown,
+    }
+}
+
+/// Decodes a solidity `Error(string)` revert
+pub fn decode_error_string(output: &[u8]) -> Option<String> {
+    if output.len() < 4 || output[..4] != id("Error(string)") {
+        return None;
+    }
+    abi::decode(&[ParamType::String], &output[4..])
+        .ok()?
+        .pop()?
+        .into_string()
+}
+
+/// Decodes a solidity `Panic(uint256)` revert
+pub fn decode_panic(output: &[u8]) -> Option<U256> {
+    if output.len() < 4 || output[..4] != id("Panic(uint256)") {
+        return None;
+    }
+    abi::decode(&[ParamType::Uint(256)], &output[4..])
+        .ok()?
+        .pop()?
+        .into_uint()
+}
+
+/// Prefixes V3's short codes with the protocol, `SPL` alone says little in a log
+fn short_name(protocol: &str, reason: &str) -> String {
+    if protocol == "Uniswap V3" && reason.len() <= 3 {
+        format!("V3: {}", reason)
+    } else {
+        reason.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::Token;
+
+    fn revert_with(reason: &str) -> Vec<u8> {
+        let mut data = id("Error(string)").to_vec();
+        data.extend(abi::encode(&[Token::String(reason.into())]));
+        data
+    }
+
+    #[test]
+    fn test_decode_revert() {
+        let spl = decode_revert(&revert_with("SPL"));
+        assert_eq!(spl.name, "V3: SPL");
+        assert_eq!(spl.remedy, Remedy::ReduceSize);
+
+        let output = decode_revert(&revert_with("UniswapV2Router: INSUFFICIENT_OUTPUT_AMOUNT"));
+        assert_eq!(output.protocol, Some("Uniswap V2"));
+        assert_eq!(
+            output.remedy.retry_size(U256::from(10)),
+            Some(U256::from(5))
+        );
+
+        let deadline = decode_revert(&id("TransactionDeadlinePassed()"));
+        assert_eq!(deadline.remedy, Remedy::Abandon);
+        assert_eq!(deadline.remedy.retry_size(U256::from(10)), None);
+
+        let mut overflow = id("Panic(uint256)").to_vec();
+        overflow.extend(abi::encode(&[Token::Uint(U256::from(0x11))]));
+        assert_eq!(decode_revert(&overflow).name, "Panic(0x11)");
+        assert_eq!(decode_revert(&overflow).remedy, Remedy::ReduceSize);
+
+        assert_eq!(decode_revert(&revert_with("nope")).remedy, Remedy::Unknown);
+        assert_eq!(decode_revert(&[0xde, 0xad]).name, "0xdead");
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+
+use ethers::types::{Address, I256, U256};
+use log::{info, warn};
+use parking_lot::Mutex;
+use thiserror::Error;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RiskError {
+    #[error("Strategy {0} is disabled: {1}")]
+    StrategyDisabled(String, String),
+    #[error("Per-block loss limit reached")]
+    BlockLossLimit,
+    #[error("Per-day loss limit reached")]
+    DailyLossLimit,
+    #[error("Exposure limit reached for token {0:?}")]
+    ExposureLimit(Address),
+}
+
+/// Global risk limits, losses are denominated in wei
+#[derive(Debug, Clone)]
+pub struct RiskConfig {
+    pub max_loss_per_block: U256,
+    pub max_loss_per_day: U256,
+    /// token specific exposure caps, falls back to `default_max_exposure`
+    pub max_exposure: HashMap<Address, U256>,
+    pub default_max_exposure: U256,
+    /// a strategy is disabled after that many failed or unprofitable bundles in a row
+    pub max_consecutive_failures: u32,
+}
+
+impl Default for RiskConfig {
+    fn default() -> Self {
+        Self {
+            max_loss_per_block: U256::exp10(17),
+            max_loss_per_day: U256::exp10(18),
+            max_exposure: HashMap::new(),
+            default_max_exposure: U256::exp10(19),
+            max_consecutive_failures: 5,
+        }
+    }
+}
+
+/// What happened to a submitted bundle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleOutcome {
+    /// bundle landed, with the realized profit in wei (negative for a loss)
+    Landed(I256),
+    /// bundle reverted, was rejected, or failed simulation at the relay
+    Failed,
+    /// bundle simply wasn't included, doesn't count against the strategy
+    NotIncluded,
+}
+
+#[derive(Debug, Clone, Default)]
+struct StrategyHealth {
+    consecutive_failures: u32,
+    disabled: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct RiskState {
+    block: u64,
+    block_pnl: I256,
+    day: u64,
+    day_pnl: I256,
+    exposure: HashMap<Address, U256>,
+    strategies: HashMap<String, StrategyHealth>,
+}
+
+/// Enforces loss limits, per token exposure and a per strategy circuit breaker
+///
+/// Strategies call [RiskManager::check] before submitting and [RiskManager::record_outcome] once
+/// the bundle's fate is known. A tripped breaker stays open until [RiskManager::enable_strategy] is
+/// called by an operator.
+#[derive(Debug, Default)]
+pub struct RiskManager {
+    config: RiskConfig,
+    state: Mutex<RiskState>,
+}
+
+impl RiskManager {
+    pub fn new(config: RiskConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(RiskState::default()),
+        }
+    }
+
+    pub fn config(&self) -> &RiskConfig {
+        &self.config
+    }
+
+    /// Checks whether `strategy` may open `amount` of exposure in `token` at `block`
+    pub fn check(
+        &self,
+        strategy: &str,
+        block: u64,
+        timestamp: u64,
+        token: Address,
+        amount: U256,
+    ) -> Result<(), RiskError> {
+        let mut state = self.state.lock();
+        self.check_locked(&mut state, strategy, block, timestamp, token, amount)
+    }
+
+    fn check_locked(
+        &self,
+        state: &mut RiskState,
+        strategy: &str,
+        block: u64,
+        timestamp: u64,
+        token: Address,
+        amount: U256,
+    ) -> Result<(), RiskError> {
+        state.roll(block, timestamp);
+
+        if let Some(reason) = state
+            .strategies
+            .get(strategy)
+            .and_then(|health| health.disabled.clone())
+        {
+            return Err(RiskError::StrategyDisabled(strategy.to_string(), reason));
+        }
+
+        if loss(state.block_pnl) >= self.config.max_loss_per_block {
+            return Err(RiskError::BlockLossLimit);
+        }
+        if loss(state.day_pnl) >= self.config.max_loss_per_day {
+            return Err(RiskError::DailyLossLimit);
+        }
+
+        let current = state.exposure.get(&token).copied().unwrap_or_default();
+        if current.saturating_add(amount) > self.max_exposure(&token) {
+            return Err(RiskError::ExposureLimit(token));
+        }
+
+        Ok(())
+    }
+
+    /// Like [RiskManager::check], and reserves the exposure on success. Both happen under one
+    /// lock, concurrent reservations can't overshoot the limit together.
+    pub fn reserve(
+        &self,
+        strategy: &str,
+        block: u64,
+        timestamp: u64,
+        token: Address,
+        amount: U256,
+    ) -> Result<(), RiskError> {
+        let mut state = self.state.lock();
+        self.check_locked(&mut state, strategy, block, timestamp, token, amount)?;
+        let exposure = state.exposure.entry(token).or_default();
+        *exposure = exposure.saturating_add(amount);
+        Ok(())
+    }
+
+    /// Releases exposure reserved with [RiskManager::reserve]
+    pub fn release(&self, token: Address, amount: U256) {
+        let mut state = self.state.lock();
+        if let Some(exposure) = state.exposure.get_mut(&token) {
+            *exposure = exposure.saturating_sub(amount);
+        }
+    }
+
+    /// Records a bundle outcome, updating the loss windows and the strategy's breaker
+    pub fn record_outcome(
+        &self,
+        strategy: &str,
+        block: u64,
+        timestamp: u64,
+        outcome: BundleOutcome,
+    ) {
+        let mut state = self.state.lock();
+        state.roll(block, timestamp);
+
+        let failed = match outcome {
+            BundleOutcome::Landed(pnl) => {
+                state.block_pnl = state.block_pnl.saturating_add(pnl);
+                state.day_pnl = state.day_pnl.saturating_add(pnl);
+                pnl <= I256::zero()
+            }
+            BundleOutcome::Failed => true,
+            BundleOutcome::NotIncluded => return,
+        };
+
+        let max_failures = self.config.max_consecutive_failures;
+        let health = state.strategies.entry(strategy.to_string()).or_default();
+        if !failed {
+            health.consecutive_failures = 0;
+            return;
+        }
+
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= max_failures && health.disabled.is_none() {
+            let reason = format!("{} consecutive failed bundles", health.consecutive_failures);
+            warn!("Circuit breaker tripped for {}: {}", strategy, reason);
+            health.disabled = Some(reason);
+        }
+    }
+
+    /// Manually disables a strategy
+    pub fn disable_strategy(&self, strategy: &str, reason: impl Into<String>) {
+        let mut state = self.state.lock();
+        state
+            .strategies
+            .entry(strategy.to_string())
+            .or_default()
+            .disabled = Some(reason.into());
+    }
+
+    /// Re-enables a strategy after its breaker tripped, meant to be driven by an operator
+    pub fn enable_strategy(&self, strategy: &str) {
+        let mut state = self.state.lock();
+        if let Some(health) = state.strategies.get_mut(strategy) {
+            info!("Re-enabling strategy {}", strategy);
+            *health = StrategyHealth::default();
+        }
+    }
+
+    pub fn is_enabled(&self, strategy: &str) -> bool {
+        self.state
+            .lock()
+            .strategies
+            .get(strategy)
+            .map_or(true, |health| health.disabled.is_none())
+    }
+
+    fn max_exposure(&self, token: &Address) -> U256 {
+        self.config
+            .max_exposure
+            .get(token)
+            .copied()
+            .unwrap_or(self.config.default_max_exposure)
+    }
+}
+
+impl RiskState {
+    /// Resets the loss windows when a new block or day starts
+    fn roll(&mut self, block: u64, timestamp: u64) {
+        if block > self.block {
+            self.block = block;
+            self.block_pnl = I256::zero();
+        }
+        let day = timestamp / SECONDS_PER_DAY;
+        if day > self.day {
+            self.day = day;
+            self.day_pnl = I256::zero();
+        }
+    }
+}
+
+/// The loss part of a pnl, zero for profits
+fn loss(pnl: I256) -> U256 {
+    if pnl.is_negative() {
+        pnl.unsigned_abs()
+    } else {
+        U256::zero()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RiskConfig {
+        RiskConfig {
+            max_loss_per_block: U256::from(100),
+            max_loss_per_day: U256::from(150),
+            max_exposure: HashMap::new(),
+            default_max_exposure: U256::from(1_000),
+            max_consecutive_failures: 2,
+        }
+    }
+
+    #[test]
+    fn test_loss_limits() {
+        let risk = RiskManager::new(config());
+        let token = Address::random();
+
+        risk.record_outcome("sandwich", 1, 0, BundleOutcome::Landed(I256::from(-100)));
+        assert_eq!(
+            risk.check("arb", 1, 0, token, U256::one()),
+            Err(RiskError::BlockLossLimit)
+        );
+
+        // next block resets the block window but not the daily one
+        assert!(risk.check("arb", 2, 12, token, U256::one()).is_ok());
+        risk.record_outcome("arb", 2, 12, BundleOutcome::Landed(I256::from(-60)));
+        assert_eq!(
+            risk.check("arb", 3, 24, token, U256::one()),
+            Err(RiskError::DailyLossLimit)
+        );
+
+        // new day
+        assert!(risk
+            .check("arb", 4, SECONDS_PER_DAY, token, U256::one())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_exposure_and_breaker() {
+        let risk = RiskManager::new(config());
+        let token = Address::random();
+
+        risk.reserve("arb", 1, 0, token, U256::from(900)).unwrap();
+        assert_eq!(
+            risk.check("arb", 1, 0, token, U256::from(200)),
+            Err(RiskError::ExposureLimit(token))
+        );
+        risk.release(token, U256::from(900));
+        assert!(risk.check("arb", 1, 0, token, U256::from(200)).is_ok());
+
+        // concurrent reservations never overshoot the limit together
+        let reserved = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| risk.reserve("arb", 1, 0, token, U256::from(300)).is_ok()))
+                .collect();
+            threads
+                .into_iter()
+                .filter(|thread| thread.join().unwrap())
+                .count()
+        });
+        assert_eq!(reserved, 3);
+        risk.release(token, U256::from(900));
+
+        risk.record_outcome("arb", 1, 0, BundleOutcome::Failed);
+        risk.record_outcome("arb", 1, 0, BundleOutcome::NotIncluded);
+        assert!(risk.is_enabled("arb"));
+        risk.record_outcome("arb", 2, 12, BundleOutcome::Landed(I256::zero()));
+        assert!(!risk.is_enabled("arb"));
+        assert!(matches!(
+            risk.check("arb", 2, 12, token, U256::one()),
+            Err(RiskError::StrategyDisabled(_, _))
+        ));
+
+        // stays disabled until re-enabled manually
+        risk.record_outcome("arb", 3, 24, BundleOutcome::Landed(I256::from(10)));
+        assert!(!risk.is_enabled("arb"));
+        risk.enable_strategy("arb");
+        assert!(risk.is_enabled("arb"));
+    }
+}
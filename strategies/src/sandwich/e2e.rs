@@ -0,0 +1,276 @@
+//! End-to-end sandwich pipeline against an anvil mainnet fork
+//!
+//! Runs state diff -> extract_pools -> optimizer -> payload building -> local simulation for a
+//! synthetic victim swap and checks the realized profit against the optimizer's prediction.
+//! Needs `anvil` in `$PATH` and an archive `HTTP_RPC`, run with `cargo test --features e2e`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::optimizer::{get_amount_out, optimize_v2_sandwich};
+use super::utils::constants::get_weth_address;
+use super::utils::contract_deployer::deploy_contract_to_anvil;
+use super::utils::state_diff::{extract_pools, get_from_txs, to_cache_db};
+use super::utils::tx_builder::sandwicher::build_v2_payload;
+
+use dotenv::dotenv;
+use env_logger::Env;
+use ethers::{
+    core::utils::{parse_units, Anvil},
+    middleware::SignerMiddleware,
+    prelude::{abigen, LocalWallet},
+    providers::{Http, Middleware, Provider, Ws},
+    types::{Address, BlockId, BlockNumber, Bytes, Transaction, I256, U256},
+};
+use eyre::Result;
+use fork_database::{
+    forked_db::ForkedDatabase,
+    inspectors::{Asset, BalanceDeltaInspector},
+    setup_fork_db,
+    sim_env::SimEnv,
+    utils::{h160_to_b160, u256_to_ru256},
+};
+use parking_lot::RwLock;
+use qilin_cfmms::{
+    pool::{Pool, PoolType},
+    registry::PoolView,
+};
+use revm::{
+    primitives::{Env as EvmEnv, TransactTo, TxEnv, B160},
+    EVM,
+};
+
+abigen!(
+    UniswapV2Router,
+    r#"[
+        function swapExactETHForTokens(uint256 amountOutMin, address[] calldata path, address to, uint256 deadline) external payable returns (uint256[] memory amounts)
+    ]"#
+);
+
+const INIT_BLOCK: u64 = 17444939;
+const V2_ROUTER: &str = "0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D";
+const USDC: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+const USDC_WETH_V2: &str = "0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc";
+/// Binance 8, funded both on the fork and on the remote node used for tracing
+const VICTIM: &str = "0xF977814e90dA44bFA03b6295A0616a897441aceC";
+/// Victim's slippage bound, the optimizer must respect it
+const VICTIM_SLIPPAGE_BPS: u64 = 100;
+/// Allowed gap between predicted and realized profit
+const PROFIT_TOLERANCE_BPS: u64 = 1;
+
+#[tokio::test]
+async fn test_sandwich_end_to_end() -> Result<()> {
+    let _ = env_logger::Builder::from_env(Env::default().default_filter_or("info")).try_init();
+
+    dotenv().ok();
+    let mainnet_http_url = env::var("HTTP_RPC")?;
+
+    // note: spawn() will panic if spawn is called without anvil being available in the user’s $PATH
+    let anvil = Anvil::new()
+        .fork(mainnet_http_url.clone())
+        .fork_block_number(INIT_BLOCK)
+        .spawn();
+
+    let provider = Arc::new(Provider::<Ws>::connect(anvil.ws_endpoint()).await?);
+    let remote = Arc::new(Provider::<Http>::try_from(mainnet_http_url)?);
+
+    let wallet: LocalWallet = anvil.keys()[0].clone().into();
+    let client = Arc::new(SignerMiddleware::new(provider.clone(), wallet.clone()));
+    let sandwich = deploy_contract_to_anvil(client.clone()).await?;
+
+    let (fork_db, backend) = setup_fork_db(provider.clone(), anvil.endpoint()).await;
+    let fork_db = Arc::new(RwLock::new(fork_db));
+    let all_pools = load_pools(provider.clone()).await?;
+
+    let weth = get_weth_address();
+    let usdc = Address::from_str(USDC)?;
+    let pair = Address::from_str(USDC_WETH_V2)?;
+    let router = Address::from_str(V2_ROUTER)?;
+    let victim = Address::from_str(VICTIM)?;
+
+    // synthetic victim: ETH -> USDC through the V2 router with a 1% slippage bound
+    let pool = all_pools
+        .get(&pair)
+        .ok_or(eyre::eyre!("USDC / WETH pair missing from test data"))?;
+    let (reserve_weth, reserve_usdc) = weth_reserves(&pool, weth)?;
+    let victim_in = U256::from(parse_units("50.0", "ether")?);
+    let quote = get_amount_out(victim_in, reserve_weth, reserve_usdc);
+    let victim_min_out = quote * (10_000 - VICTIM_SLIPPAGE_BPS) / 10_000;
+
+    let router_contract = UniswapV2Router::new(router, provider.clone());
+    let victim_calldata = router_contract
+        .swap_exact_eth_for_tokens(victim_min_out, vec![weth, usdc], victim, U256::MAX)
+        .calldata()
+        .ok_or(eyre::eyre!("Error encoding victim swap"))?;
+    let victim_tx = Transaction {
+        from: victim,
+        to: Some(router),
+        value: victim_in,
+        input: victim_calldata.clone(),
+        gas: U256::from(300_000),
+        ..Default::default()
+    };
+
+    // state diff -> sandwichable pools
+    let state_diffs = get_from_txs(
+        &remote,
+        &vec![victim_tx],
+        BlockNumber::Number(INIT_BLOCK.into()),
+    )
+    .await
+    .ok_or(eyre::eyre!("Error tracing victim swap"))?;
+    let sandwichable = extract_pools(&state_diffs, &all_pools)
+        .ok_or(eyre::eyre!("Victim swap doesn't touch WETH"))?;
+    let target = sandwichable
+        .iter()
+        .find(|p| p.pool.address == pair)
+        .ok_or(eyre::eyre!("USDC / WETH pair not extracted"))?;
+    assert!(target.is_weth_input);
+
+    to_cache_db(
+        &state_diffs,
+        Some(BlockId::Number(BlockNumber::Number(INIT_BLOCK.into()))),
+        &remote,
+        &fork_db,
+    )
+    .await?;
+
+    // optimizer, bounded by the WETH the deployer funded the contract with
+    let max_in = U256::from(parse_units("100.0", "ether")?);
+    let plan = optimize_v2_sandwich(
+        reserve_weth,
+        reserve_usdc,
+        victim_in,
+        victim_min_out,
+        max_in,
+    )
+    .ok_or(eyre::eyre!("No profitable sandwich found"))?;
+    log::info!("Sandwich plan: {:?}", plan);
+    assert!(plan.victim_out >= victim_min_out);
+
+    // payloads
+    let weth_is_0 = pool.token_0 == weth;
+    let frontrun = build_v2_payload(
+        weth,
+        pair,
+        plan.frontrun_in,
+        plan.frontrun_out,
+        if weth_is_0 { 1 } else { 0 },
+    );
+    let backrun = build_v2_payload(
+        usdc,
+        pair,
+        plan.frontrun_out,
+        plan.backrun_out,
+        if weth_is_0 { 0 } else { 1 },
+    );
+
+    // local simulation of the whole bundle on the fork database
+    let mut db = fork_db.write();
+    let env = SimEnv::next_block(&db)?;
+    let searcher = h160_to_b160(client.address());
+    let contract = h160_to_b160(sandwich.address());
+    let mut inspector = BalanceDeltaInspector::new([contract]).with_weth(h160_to_b160(weth));
+
+    let bundle = [
+        (searcher, contract, frontrun, U256::zero()),
+        (
+            h160_to_b160(victim),
+            h160_to_b160(router),
+            victim_calldata,
+            victim_in,
+        ),
+        (searcher, contract, backrun, U256::zero()),
+    ];
+    for (i, (caller, to, data, value)) in bundle.into_iter().enumerate() {
+        let success = transact(&mut db, &env, &mut inspector, caller, to, data, value)?;
+        assert!(success, "bundle tx {} reverted", i);
+    }
+
+    let realized = inspector.delta(contract, Asset::Token(h160_to_b160(weth)));
+    log::info!("Predicted profit {}, realized {}", plan.profit, realized);
+
+    let gap = (realized - plan.profit).unsigned_abs();
+    let tolerance = plan.profit.unsigned_abs() * PROFIT_TOLERANCE_BPS / 10_000;
+    assert!(realized > I256::zero());
+    assert!(
+        gap <= tolerance,
+        "realized profit {} deviates from predicted {}",
+        realized,
+        plan.profit
+    );
+    // every fork read was served, none by a restarted fetch loop
+    assert!(backend.is_healthy());
+    assert_eq!(backend.restarts(), 0);
+
+    Ok(())
+}
+
+/// Load the test pools from `test_data/all_pools.json` with fresh state from the fork
+async fn load_pools(provider: Arc<Provider<Ws>>) -> Result<PoolView> {
+    let data = fs::read_to_string("./src/sandwich/test_data/all_pools.json")?;
+    let pools: BTreeMap<Address, Pool> = serde_json::from_str(&data)?;
+
+    let mut all_pools = vec![];
+    for (address, pool) in pools {
+        if let Some(pool) = Pool::new(
+            provider.clone(),
+            address,
+            pool.token_0,
+            pool.token_1,
+            pool.swap_fee,
+            pool.pool_variant,
+        )
+        .await
+        {
+            all_pools.push(pool);
+        }
+    }
+    Ok(all_pools.into_iter().collect())
+}
+
+/// `(weth reserve, other token reserve)` of a V2 pool
+fn weth_reserves(pool: &Pool, weth: Address) -> Result<(U256, U256)> {
+    let v2 = match pool.pool_type {
+        PoolType::UniswapV2(v2) => v2,
+        _ => return Err(eyre::eyre!("{:?} is not a V2 pool", pool.address)),
+    };
+    let (reserve_0, reserve_1) = (U256::from(v2.reserve_0), U256::from(v2.reserve_1));
+    if pool.token_0 == weth {
+        Ok((reserve_0, reserve_1))
+    } else {
+        Ok((reserve_1, reserve_0))
+    }
+}
+
+/// Execute and commit a single tx, returns whether it succeeded
+fn transact(
+    db: &mut ForkedDatabase,
+    env: &EvmEnv,
+    inspector: &mut BalanceDeltaInspector,
+    caller: B160,
+    to: B160,
+    data: Bytes,
+    value: U256,
+) -> Result<bool> {
+    let mut evm = EVM::new();
+    evm.env = env.clone();
+    evm.env.tx = TxEnv {
+        caller,
+        transact_to: TransactTo::Call(to),
+        data: data.0,
+        value: u256_to_ru256(value),
+        gas_limit: 500_000,
+        gas_price: env.block.basefee,
+        ..Default::default()
+    };
+    evm.database(db);
+
+    let result = evm
+        .inspect_commit(inspector)
+        .map_err(|e| eyre::eyre!("EVM error: {:?}", e))?;
+    Ok(result.is_success())
+}
@@ -1,25 +1,60 @@
 pub mod abi;
+pub mod optimizer;
 pub mod state;
 pub mod utils;
+pub mod variants;
+pub mod warm_start;
 
-use std::sync::Arc;
+#[cfg(all(test, feature = "e2e"))]
+mod e2e;
 
-use crate::sandwich::state::BotState;
+use std::sync::Arc;
 
-use dashmap::DashMap;
+use crate::compliance::{BundleParticipants, ComplianceGuard};
+use crate::dedup::{DedupRegistry, MemoryDedup};
+use crate::pool_filter::{Pipeline, PoolMetadata};
+use crate::sandwich::optimizer::SandwichPlan;
+use crate::sandwich::state::{get_sandy_addr, BotState};
+use crate::sandwich::utils::constants::get_weth_address;
+use crate::sandwich::utils::state_diff::{extract_pools, SandwichablePool};
+use crate::sandwich::variants::{best_of, VariantBundle, VariantSim};
+use crate::sandwich::warm_start::{PairState, WarmStart};
+use crate::target_policy::TargetPolicy;
+use crate::token_tax::{measure_token_tax, TaxProfile};
+
+use collectors::pair_discovery::Factory;
 use parking_lot::RwLock;
-use qilin_cfmms::pool::Pool;
+use qilin_cfmms::{
+    pool::{Pool, PoolType},
+    registry::PoolRegistry,
+    v3_ticks::{prefetch_tick_range, tick_range},
+};
+use std::collections::{BTreeMap, HashMap};
 
 use ethers::{
     middleware::SignerMiddleware,
     providers::{JsonRpcClient, Middleware, PubsubClient},
     signers::Signer,
-    types::{Address, U64},
+    types::{AccountDiff, Address, Transaction, H256, U256, U64},
 };
 use eyre::Result;
-use fork_database::forked_db::ForkedDatabase;
+use fork_database::{
+    forked_db::ForkedDatabase,
+    pending_block::{BlockTemplate, InclusionPolicy, PendingBase},
+    utils::h160_to_b160,
+};
+use qilin_math::gas::L1FeeParams;
+use revm::{
+    db::DatabaseRef,
+    primitives::{Env, B160},
+};
+use std::fmt::Debug;
 
-type AllPools = Arc<RwLock<DashMap<Address, Pool>>>;
+/// Name victims are claimed under in the [DedupRegistry]
+pub const STRATEGY_NAME: &str = "sandwich";
+/// Price move in bps either way of a touched V3 pool's tick whose ticks are prefetched, see
+/// [RustySandoStrategy::prefetch_v3_ticks]
+pub const V3_PREFETCH_MOVE_BPS: u32 = 500;
 
 /// Sandwich strategy directly ported from RustySando repo
 /// https://github.com/mouseless-eth/rusty-sando
@@ -29,8 +64,28 @@ pub struct RustySandoStrategy<M, S> {
     pub wallet: Arc<SignerMiddleware<Arc<M>, S>>,
     pub inception_block: U64,
     pub sandwich_state: Arc<BotState>,
-    pub all_pools: AllPools,
+    /// executor contract the bundles trade through
+    pub sandwich_contract: Address,
+    pub registry: Arc<PoolRegistry>,
     pub fork_db: Arc<RwLock<ForkedDatabase>>,
+    /// pending txs replayed before the victim and our bundle, see [InclusionPolicy]
+    pub pending_policy: InclusionPolicy,
+    /// victims claimed before working on them, so replicas don't sandwich the same one
+    pub dedup: Arc<dyn DedupRegistry>,
+    /// pools the operator lets the strategy trade, see [Pipeline]
+    pub pipeline: Arc<Pipeline>,
+    /// what the pipeline's filter knows about the pools, shared with pool discovery
+    pub pool_metadata: Arc<RwLock<PoolMetadata>>,
+    /// victims not to be seen next to, drainers and emergency actions, see [TargetPolicy]
+    pub target_policy: Arc<TargetPolicy>,
+    /// refuses victims whose bundle would touch a denylisted address, none by default
+    pub compliance: Option<ComplianceGuard>,
+    /// L1 fee parameters on OP-stack chains, charged on our txs when picking a variant
+    pub l1_fee: Option<L1FeeParams>,
+    /// transfer tax of the tokens sandwiched so far, measured once on the fork
+    pub token_taxes: Arc<RwLock<HashMap<Address, TaxProfile>>>,
+    /// last frontrun size per victim, see [WarmStart]
+    pub warm_start: Arc<RwLock<WarmStart>>,
     // TODO: add bundle sender
 }
 
@@ -58,7 +113,7 @@ where
         init_block: U64,
         provider: Arc<M>,
         wallet: Arc<SignerMiddleware<Arc<M>, S>>,
-        all_pools: AllPools,
+        registry: Arc<PoolRegistry>,
         fork_db: Arc<RwLock<ForkedDatabase>>,
         test: bool,
         sandwich_address: Option<Address>,
@@ -71,15 +126,343 @@ where
             wallet,
             inception_block: init_block,
             sandwich_state,
-            all_pools,
+            sandwich_contract: get_sandy_addr(test, sandwich_address),
+            registry,
             fork_db,
+            pending_policy: InclusionPolicy::default(),
+            dedup: Arc::new(MemoryDedup::new(STRATEGY_NAME)),
+            pipeline: Arc::new(Pipeline::default()),
+            pool_metadata: Arc::new(RwLock::new(PoolMetadata::default())),
+            target_policy: Arc::new(TargetPolicy::default()),
+            compliance: None,
+            l1_fee: None,
+            token_taxes: Arc::new(RwLock::new(HashMap::new())),
+            warm_start: Arc::new(RwLock::new(WarmStart::new())),
         })
     }
+
+    /// Template txs to replay before our bundle in [RustySandoStrategy::best_variant_on_pending]
+    pub fn with_pending_policy(mut self, policy: InclusionPolicy) -> Self {
+        self.pending_policy = policy;
+        self
+    }
+
+    /// Share victims with other instances or replicas through `dedup`
+    pub fn with_dedup(mut self, dedup: Arc<dyn DedupRegistry>) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Only trade the pools the `"sandwich"` filter of `pipeline` allows, judged on `metadata`
+    pub fn with_pipeline(
+        mut self,
+        pipeline: Arc<Pipeline>,
+        metadata: Arc<RwLock<PoolMetadata>>,
+    ) -> Self {
+        self.pipeline = pipeline;
+        self.pool_metadata = metadata;
+        self
+    }
+
+    /// Skip the victims `policy` flags instead of the built in patterns alone
+    pub fn with_target_policy(mut self, policy: Arc<TargetPolicy>) -> Self {
+        self.target_policy = policy;
+        self
+    }
+
+    /// Check every victim's bundle participants against `guard` before claiming it
+    pub fn with_compliance(mut self, guard: ComplianceGuard) -> Self {
+        self.compliance = Some(guard);
+        self
+    }
+
+    /// Charge our txs their L1 data fee on top of their gas, see [L1FeeParams]
+    pub fn with_l1_fee(mut self, l1_fee: L1FeeParams) -> Self {
+        self.l1_fee = Some(l1_fee);
+        self
+    }
+
+    /// Pools the victim's `state_diffs` trade through that the pipeline lets the strategy trade,
+    /// the pools' metadata brought up to `block_number` first
+    pub fn sandwichable_pools(
+        &self,
+        state_diffs: &BTreeMap<Address, AccountDiff>,
+        block_number: u64,
+    ) -> Option<Vec<SandwichablePool>> {
+        let mut pools = extract_pools(state_diffs, &self.registry.view())?;
+        let Some(filter) = self.pipeline.filter_of(STRATEGY_NAME) else {
+            self.prefetch_v3_ticks(&pools);
+            return Some(pools);
+        };
+        let mut metadata = self.pool_metadata.write();
+        metadata.observe(
+            pools.iter().map(|sandwichable| &sandwichable.pool),
+            &Factory::mainnet(),
+            get_weth_address(),
+            block_number,
+        );
+        pools.retain(
+            |sandwichable| match filter.check(&sandwichable.pool, &metadata) {
+                Some(reason) => {
+                    log::debug!(
+                        "Not sandwiching {:?}: {:?}",
+                        sandwichable.pool.address,
+                        reason
+                    );
+                    false
+                }
+                None => true,
+            },
+        );
+        self.prefetch_v3_ticks(&pools);
+        Some(pools)
+    }
+
+    /// Load the ticks a swap moving the price of the V3 `pools` by up to [V3_PREFETCH_MOVE_BPS]
+    /// crosses into the fork db on the prefetch lane, see [prefetch_tick_range], so simulating
+    /// the sandwich doesn't wait on one tick at a time
+    pub fn prefetch_v3_ticks(&self, pools: &[SandwichablePool]) {
+        let db = self.fork_db.read();
+        for sandwichable in pools {
+            let PoolType::UniswapV3(v3) = &sandwichable.pool.pool_type else {
+                continue;
+            };
+            let (lower, upper) = tick_range(v3.tick, V3_PREFETCH_MOVE_BPS);
+            match prefetch_tick_range(
+                db.backend(),
+                sandwichable.pool.address,
+                v3.tick_spacing,
+                lower,
+                upper,
+            ) {
+                Ok(ticks) => log::debug!(
+                    "Prefetched {} ticks of {:?}",
+                    ticks.len(),
+                    sandwichable.pool.address
+                ),
+                Err(e) => log::warn!(
+                    "Failed to prefetch ticks of {:?}: {:?}",
+                    sandwichable.pool.address,
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Claim `victim` before building a bundle on it trading `pools` at `block`. `false` if the
+    /// [target policy](TargetPolicy) flags it, the [compliance guard](ComplianceGuard) refuses one
+    /// of the bundle's participants, or another instance works on it.
+    pub async fn claim_victim(
+        &self,
+        victim: &Transaction,
+        pools: &[SandwichablePool],
+        block: u64,
+    ) -> Result<bool> {
+        if let Some(flag) = self.target_policy.check(victim) {
+            log::debug!("Not targeting {:?}: {:?}", victim.hash, flag);
+            return Ok(false);
+        }
+        if let Some(guard) = &self.compliance {
+            let participants = BundleParticipants::new()
+                .with_victim(victim)
+                .with_pools(pools.iter().map(|sandwichable| sandwichable.pool.address));
+            if guard
+                .check(&format!("{:?}", victim.hash), block, &participants)
+                .is_err()
+            {
+                return Ok(false);
+            }
+        }
+        Ok(self.dedup.claim(victim.hash, STRATEGY_NAME).await?)
+    }
+
+    /// Give `victim` up, e.g. when no profitable sandwich was found
+    pub async fn release_victim(&self, victim: H256) -> Result<()> {
+        Ok(self.dedup.release(victim, STRATEGY_NAME).await?)
+    }
+
+    /// Transfer tax of the token `pool` pairs with WETH, measured on the fork the first time the
+    /// token is seen, see [measure_token_tax]
+    pub fn token_tax(&self, env: &Env, pool: &Pool) -> Result<TaxProfile> {
+        let weth = get_weth_address();
+        let token = if pool.token_0 == weth {
+            pool.token_1
+        } else {
+            pool.token_0
+        };
+        if let Some(profile) = self.token_taxes.read().get(&token) {
+            return Ok(profile.clone());
+        }
+        let profile = measure_token_tax(&*self.fork_db.read(), env, token, pool, weth)?;
+        self.token_taxes.write().insert(token, profile.clone());
+        Ok(profile)
+    }
+
+    /// Frontrun size for `victim` buying the token of `sandwichable` with `victim_in` WETH at
+    /// `block`, sized against the token's measured transfer tax and kept under the largest buy
+    /// and sell the token let through. `None` if the token can't be sold back or no size pays.
+    #[allow(clippy::too_many_arguments)]
+    pub fn plan_sandwich(
+        &self,
+        env: &Env,
+        victim: H256,
+        block: u64,
+        sandwichable: &SandwichablePool,
+        victim_in: U256,
+        victim_min_out: U256,
+        max_frontrun_in: U256,
+    ) -> Result<Option<SandwichPlan>> {
+        let pool = &sandwichable.pool;
+        let PoolType::UniswapV2(v2) = pool.pool_type else {
+            return Ok(None);
+        };
+        let profile = self.token_tax(env, pool)?;
+        if profile.is_sell_blocked() {
+            log::debug!("Not sandwiching {:?}: selling is blocked", profile.token);
+            return Ok(None);
+        }
+        let max_frontrun_in = [profile.max_buy, profile.max_sell]
+            .into_iter()
+            .flatten()
+            .fold(max_frontrun_in, U256::min);
+
+        let (reserve_0, reserve_1) = (U256::from(v2.reserve_0), U256::from(v2.reserve_1));
+        let state = if pool.token_0 == get_weth_address() {
+            PairState::new(pool.address, reserve_0, reserve_1)
+        } else {
+            PairState::new(pool.address, reserve_1, reserve_0)
+        };
+        let (plan, _) = self.warm_start.write().optimize(
+            victim,
+            block,
+            state,
+            victim_in,
+            victim_min_out,
+            max_frontrun_in,
+            profile.rates(),
+        );
+        Ok(plan)
+    }
+
+    /// Simulate the `variants` of a bundle around one victim on the fork, see
+    /// [variants::build_variants], and pick the one with the best expected value given the
+    /// probability `victim_inclusion` of the victim landing. `None` if none is profitable.
+    pub fn best_variant(
+        &self,
+        env: &Env,
+        variants: Vec<VariantBundle>,
+        victim_inclusion: f64,
+    ) -> Result<Option<(VariantBundle, VariantSim)>> {
+        let db = self.fork_db.read();
+        self.select_on(&*db, env, variants, victim_inclusion)
+    }
+
+    /// [RustySandoStrategy::best_variant] simulated at the end of the builder's `template` on
+    /// top of block `head`, after the template txs the [pending policy](InclusionPolicy) expects
+    /// ahead of our bundle
+    pub fn best_variant_on_pending(
+        &self,
+        env: &Env,
+        head: H256,
+        template: &BlockTemplate,
+        variants: Vec<VariantBundle>,
+        victim_inclusion: f64,
+    ) -> Result<Option<(VariantBundle, VariantSim)>> {
+        let db = self.fork_db.read();
+        let base = PendingBase::build_with_policy(&*db, env, head, template, self.pending_policy)?;
+        log::debug!(
+            "Simulating on {} template txs, {} left for after our bundle",
+            base.included().len(),
+            base.excluded().len()
+        );
+        self.select_on(base.state(), base.env(), variants, victim_inclusion)
+    }
+
+    /// [RustySandoStrategy::best_variant] over several frontrun sizes, e.g. around the
+    /// [optimizer]'s plan, with `build` making the variants of a size. Sizes are simulated in
+    /// parallel, each on its own fork of `base_snapshot`, see [ForkedDatabase::sweep], a snapshot
+    /// best taken once per block and shared by the block's victims. `None` if no size pays.
+    pub fn best_sized_variant<F>(
+        &self,
+        env: &Env,
+        base_snapshot: U256,
+        sizes: Vec<U256>,
+        build: F,
+        victim_inclusion: f64,
+    ) -> Result<Option<(U256, VariantBundle, VariantSim)>>
+    where
+        F: Fn(U256) -> Vec<VariantBundle> + Sync,
+    {
+        let (contract, searcher, weth) = self.sim_accounts();
+        let db = self.fork_db.read();
+        let swept = db
+            .sweep(base_snapshot, sizes, |size, sandbox| {
+                let variants = build(*size);
+                best_of(
+                    &*sandbox,
+                    env,
+                    variants,
+                    contract,
+                    searcher,
+                    weth,
+                    victim_inclusion,
+                    self.l1_fee.as_ref(),
+                )
+            })
+            .ok_or_else(|| eyre::eyre!("No snapshot {} to sweep sizes on", base_snapshot))?;
+
+        let mut best: Option<(U256, VariantBundle, VariantSim)> = None;
+        for (size, found) in swept {
+            let Some((bundle, sim)) = found? else {
+                continue;
+            };
+            let better = best.as_ref().map_or(true, |(_, _, best)| {
+                sim.expected_value(victim_inclusion) > best.expected_value(victim_inclusion)
+            });
+            if better {
+                best = Some((size, bundle, sim));
+            }
+        }
+        Ok(best)
+    }
+
+    fn select_on<DB>(
+        &self,
+        db: &DB,
+        env: &Env,
+        variants: Vec<VariantBundle>,
+        victim_inclusion: f64,
+    ) -> Result<Option<(VariantBundle, VariantSim)>>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        let (contract, searcher, weth) = self.sim_accounts();
+        Ok(best_of(
+            db,
+            env,
+            variants,
+            contract,
+            searcher,
+            weth,
+            victim_inclusion,
+            self.l1_fee.as_ref(),
+        )?)
+    }
+
+    /// Executor the profit accrues in, searcher paying for gas, and WETH
+    fn sim_accounts(&self) -> (B160, B160, B160) {
+        (
+            h160_to_b160(self.sandwich_contract),
+            h160_to_b160(self.wallet.address()),
+            h160_to_b160(get_weth_address()),
+        )
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::utils::state_diff::{extract_pools, get_from_txs, to_cache_db};
+    use super::utils::state_diff::{get_from_txs, to_cache_db};
     use super::*;
 
     use dotenv::dotenv;
@@ -144,16 +527,18 @@ mod tests {
 
         let pool_btree_map: BTreeMap<Address, Pool> = serde_json::from_str(&pool_json_data)?;
 
-        let all_pools: DashMap<Address, Pool> = DashMap::new();
-        for (addr, _pool) in pool_btree_map {
+        let registry = PoolRegistry::new();
+        for (_, _pool) in pool_btree_map {
             let pool = pool_initializer(&_pool, provider.clone()).await.unwrap();
 
-            all_pools.insert(addr, pool);
+            registry.insert(pool);
         }
+        registry.commit(Some(INIT_BLOCK));
 
         // setup fork database
-        let fork_db =
+        let (fork_db, backend) =
             fork_database::setup_fork_db(provider.clone(), mainnet_http_url.to_string()).await;
+        eyre::ensure!(backend.is_healthy(), "fork backend isn't running");
 
         // setup wallet and client for sandwich contract deployment
         let wallet: LocalWallet = anvil.keys()[0].clone().into();
@@ -168,7 +553,7 @@ mod tests {
             block_num,
             provider.clone(),
             client.clone(),
-            Arc::new(RwLock::new(all_pools)),
+            Arc::new(registry),
             Arc::new(RwLock::new(fork_db)),
             true,
             Some(contract.address()),
@@ -178,7 +563,7 @@ mod tests {
         Ok(rusty)
     }
 
-    #[tokio::test]
+    #[tokio::test(flavor = "multi_thread")]
     async fn test_rusty_sando_strategy() -> Result<()> {
         let rusty = setup().await?;
 
@@ -207,7 +592,7 @@ mod tests {
         .await
         .unwrap();
 
-        let sandwitch_pools = extract_pools(&res, &rusty.all_pools.clone().read()).unwrap();
+        let sandwitch_pools = rusty.sandwichable_pools(&res, INIT_BLOCK).unwrap();
 
         assert_eq!(sandwitch_pools.len(), 1);
         // Uniswap V3 USDC 3 Pool Address: 0x88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640
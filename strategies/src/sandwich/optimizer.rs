@@ -0,0 +1,9 @@
+//! Sandwich sizing lives in [qilin_math] so it can run outside of the bot too
+
+pub use qilin_math::sandwich::{
+    optimize_v2_sandwich, optimize_v2_sandwich_from, optimize_v2_sandwich_taxed,
+    optimize_v2_sandwich_within, simulate_v2_sandwich, simulate_v2_sandwich_taxed, SandwichPlan,
+    SearchHint,
+};
+pub use qilin_math::tax::TaxRates;
+pub use qilin_math::v2::get_amount_out;
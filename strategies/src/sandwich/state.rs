@@ -200,7 +200,7 @@ pub fn get_searcher_wallet() -> LocalWallet {
         .expect("Failed to parse private key")
 }
 
-fn get_sandy_addr(test: bool, sandwich_address: Option<Address>) -> Address {
+pub(crate) fn get_sandy_addr(test: bool, sandwich_address: Option<Address>) -> Address {
     let sandy_addr: Address;
     if test {
         sandy_addr = match sandwich_address {
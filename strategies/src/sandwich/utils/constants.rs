@@ -3,10 +3,14 @@
 use std::str::FromStr;
 
 use ethers::prelude::*;
+use qilin_cfmms::address_book::builtin_book;
 
-// Return weth address
+// Return weth address, from the mainnet address book
 pub fn get_weth_address() -> Address {
-    Address::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap()
+    builtin_book("mainnet")
+        .expect("mainnet is built in")
+        .weth
+        .address
 }
 
 // Return the ethdev address (used if we need funds)
@@ -1,11 +1,11 @@
 // use crate::{prelude::Pool, utils};
-use dashmap::DashMap;
+use collectors::state_diff::{slot_increased, storage_keys, WETH};
 use ethers::prelude::*;
 use fork_database::forked_db::ForkedDatabase;
 use futures::stream::FuturesUnordered;
 use log;
 use parking_lot::RwLock;
-use qilin_cfmms::pool::Pool;
+use qilin_cfmms::{pool::Pool, registry::PoolView};
 use revm::primitives::{AccountInfo, Bytecode};
 use std::{
     collections::{btree_map::Entry, BTreeMap},
@@ -97,46 +97,30 @@ where
 ///
 /// Arguments:
 /// * `state_diffs`: BTreeMap of Address and AccountDiff
-/// * `all_pools`: HashMap of Address and Pool
+/// * `pools`: view of the registry pools
 ///
 /// Returns:
 /// Some(Vec<SandwichablePool>): Vec of pools that have been interacted with
 /// None: If state_diffs is empty
 pub fn extract_pools(
     state_diffs: &BTreeMap<Address, AccountDiff>,
-    all_pools: &DashMap<Address, Pool>,
+    pools: &PoolView,
 ) -> Option<Vec<SandwichablePool>> {
-    // capture all addresses that have a state change and are also a pool
-    let touched_pools: Vec<Pool> = state_diffs
-        .keys()
-        .filter_map(|e| all_pools.get(e).map(|p| (*p.value()).clone()))
-        .collect();
-
     // find direction of swap based on state diff (does weth have state changes?)
-    let weth_state_diff = &state_diffs
-        .get(
-            &"0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
-                .parse::<Address>()
-                .unwrap(),
-        )?
-        .storage;
+    let weth_state_diff = &state_diffs.get(&WETH)?.storage;
+    let keys = storage_keys();
 
     let mut sandwichable_pools: Vec<SandwichablePool> = vec![];
 
-    // find storage mapping index for each pool
-    for pool in touched_pools {
-        // find mapping storage location
-        let storage_key = TxHash::from(ethers::utils::keccak256(abi::encode(&[
-            abi::Token::Address(pool.address),
-            abi::Token::Uint(U256::from(3)),
-        ])));
-        let is_weth_input = match weth_state_diff.get(&storage_key)? {
-            Diff::Changed(c) => {
-                let from = U256::from(c.from.to_fixed_bytes());
-                let to = U256::from(c.to.to_fixed_bytes());
-                to > from
-            }
-            _ => continue,
+    // capture all addresses that have a state change and are also a pool
+    for address in state_diffs.keys() {
+        let Some(pool) = pools.get(address) else {
+            continue;
+        };
+        // find mapping storage location, hashed once per pool
+        let storage_key = keys.weth_balance_key(*address);
+        let Some(is_weth_input) = slot_increased(weth_state_diff.get(&storage_key)?) else {
+            continue;
         };
         sandwichable_pools.push(SandwichablePool::new(pool, is_weth_input));
     }
@@ -191,12 +175,8 @@ where
                 }
             };
 
-            Ok::<(AccountDiff, Address, U256, U256, Bytes), ProviderError>((
-                acc_diff.clone(),
-                *address,
-                nonce,
-                balance,
-                code,
+            Ok::<(&AccountDiff, Address, U256, U256, Bytes), ProviderError>((
+                acc_diff, *address, nonce, balance, code,
             ))
         };
 
@@ -211,7 +191,7 @@ where
                 cache_db.insert_account_info(address.0.into(), info);
 
                 acc_diff.storage.iter().for_each(|(slot, storage_diff)| {
-                    let slot_value: U256 = match storage_diff.to_owned() {
+                    let slot_value: U256 = match storage_diff {
                         Diff::Changed(v) => v.from.0.into(),
                         Diff::Died(v) => v.0.into(),
                         _ => {
@@ -1,4 +1,5 @@
 pub mod braindance;
 pub mod sandwich;
+pub mod sandwicher;
 
 pub use sandwich::*;
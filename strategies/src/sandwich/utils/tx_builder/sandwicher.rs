@@ -0,0 +1,48 @@
+use ethers::prelude::*;
+
+use crate::sandwich::utils::encode_packed::{encode_packed, PackedToken, TakeLastXBytes};
+
+/// Encode the packed payload consumed by the Sandwicher.sol fallback
+///
+/// Arguments:
+/// * `token_in`: token sent into the pair
+/// * `pair`: Uniswap V2 pair swapped on
+/// * `amount_in`: amount of `token_in` transferred to the pair
+/// * `amount_out`: amount requested from the pair
+/// * `token_out_no`: index of the token received, 0 if it is the pair's token0
+pub fn build_v2_payload(
+    token_in: Address,
+    pair: Address,
+    amount_in: U256,
+    amount_out: U256,
+    token_out_no: u8,
+) -> Bytes {
+    let (payload, _) = encode_packed(&[
+        PackedToken::Address(token_in),
+        PackedToken::Address(pair),
+        PackedToken::NumberWithShift(amount_in, TakeLastXBytes(128)),
+        PackedToken::NumberWithShift(amount_out, TakeLastXBytes(128)),
+        PackedToken::NumberWithShift(U256::from(token_out_no), TakeLastXBytes(8)),
+    ]);
+    payload.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_v2_payload() {
+        let token = Address::from_low_u64_be(1);
+        let pair = Address::from_low_u64_be(2);
+        let payload = build_v2_payload(token, pair, U256::from(3), U256::from(4), 1);
+
+        // 20 + 20 + 16 + 16 + 1
+        assert_eq!(payload.len(), 73);
+        assert_eq!(&payload[..20], token.as_bytes());
+        assert_eq!(&payload[20..40], pair.as_bytes());
+        assert_eq!(payload[55], 3);
+        assert_eq!(payload[71], 4);
+        assert_eq!(payload[72], 1);
+    }
+}
@@ -0,0 +1,512 @@
+//! Sandwich bundle variants
+//!
+//! A sandwich is only worth something if the victim lands right between the frontrun and the
+//! backrun, a replaced, uncled or reverting victim invalidates the whole bundle. The backrun-only
+//! variant doesn't need the victim: the victim is left out of the bundle and lands on its own, or
+//! not, and the backrun trades against whatever pool state the txs landing before it leave. Both
+//! variants are simulated and the one with the better expected value, given how likely the victim
+//! is to land, gets submitted.
+//!
+//! Approvals the executor is missing for the bundle's swaps go in front of it, see
+//! [VariantBundle::with_approvals].
+
+use std::fmt::Debug;
+
+use ethers::types::{Transaction, H256, I256, U256};
+use fork_database::{
+    delegation::DelegationDb,
+    inspectors::{Asset, BalanceDeltaInspector},
+    utils::{ru256_to_u256, tx_to_tx_env, RefDb},
+};
+use qilin_math::gas::L1FeeParams;
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{Env, TxEnv, B160},
+    EVM,
+};
+use thiserror::Error;
+
+use crate::approvals::{ApprovalError, ApprovalManager, Spend};
+
+const BPS: i64 = 10_000;
+/// Unsigned encoding of a tx without its calldata, at most, for the L1 fee bound
+const TX_ENVELOPE_SIZE: usize = 128;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum VariantError {
+    #[error("Simulation error: {0}")]
+    Simulation(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BundleVariant {
+    /// frontrun, victim, backrun, the victim must land
+    Sandwich,
+    /// backrun alone, after the victim if it lands
+    BackrunOnly,
+}
+
+/// A bundle built around a victim, see [BundleVariant]
+#[derive(Debug, Clone)]
+pub struct VariantBundle {
+    pub variant: BundleVariant,
+    /// approvals of the executor, sent first
+    pub approvals: Vec<TxEnv>,
+    pub frontrun: Option<TxEnv>,
+    /// only part of the bundle for sandwiches, see [VariantBundle::bundle_victim]
+    pub victim: Transaction,
+    pub backrun: TxEnv,
+}
+
+impl VariantBundle {
+    pub fn sandwich(frontrun: TxEnv, victim: Transaction, backrun: TxEnv) -> Self {
+        Self {
+            variant: BundleVariant::Sandwich,
+            approvals: vec![],
+            frontrun: Some(frontrun),
+            victim,
+            backrun,
+        }
+    }
+
+    pub fn backrun_only(victim: Transaction, backrun: TxEnv) -> Self {
+        Self {
+            variant: BundleVariant::BackrunOnly,
+            approvals: vec![],
+            frontrun: None,
+            victim,
+            backrun,
+        }
+    }
+
+    /// Put the approvals `spends` are missing in front of the bundle
+    pub fn with_approvals<DB>(
+        mut self,
+        approvals: &mut ApprovalManager,
+        db: &DB,
+        env: &Env,
+        spends: &[Spend],
+    ) -> Result<Self, ApprovalError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        self.approvals = approvals.missing_approvals(db, env, spends)?;
+        Ok(self)
+    }
+
+    pub fn victim_required(&self) -> bool {
+        self.variant == BundleVariant::Sandwich
+    }
+
+    /// Our txs to sign, in bundle order, with the victim going after the frontrun if any
+    pub fn own_txs(&self) -> Vec<TxEnv> {
+        let mut txs = self.approvals.clone();
+        txs.extend(self.frontrun.clone());
+        txs.push(self.backrun.clone());
+        txs
+    }
+
+    /// Hash of the victim when it goes into the bundle, the backrun-only variant doesn't send it
+    pub fn bundle_victim(&self) -> Option<H256> {
+        self.victim_required().then_some(self.victim.hash)
+    }
+}
+
+/// Sandwich bundle plus, when a backrun against the post-victim state is available, its
+/// backrun-only fallback
+pub fn build_variants(
+    frontrun: TxEnv,
+    victim: Transaction,
+    backrun: TxEnv,
+    fallback_backrun: Option<TxEnv>,
+) -> Vec<VariantBundle> {
+    let mut variants = vec![VariantBundle::sandwich(frontrun, victim.clone(), backrun)];
+    if let Some(fallback) = fallback_backrun {
+        variants.push(VariantBundle::backrun_only(victim, fallback));
+    }
+    variants
+}
+
+/// Simulated outcome of a [VariantBundle], profits are in WETH net of the searcher's gas
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VariantSim {
+    pub variant: BundleVariant,
+    /// Profit when the victim lands, `None` if the bundle fails
+    pub with_victim: Option<I256>,
+    /// Profit when it doesn't, always `None` for sandwiches since they are dropped then
+    pub without_victim: Option<I256>,
+}
+
+impl VariantSim {
+    /// Profit weighted by `victim_inclusion`, the probability the victim lands in the target block
+    pub fn expected_value(&self, victim_inclusion: f64) -> I256 {
+        let landed_bps = (victim_inclusion.clamp(0.0, 1.0) * BPS as f64) as i64;
+        let weighted = |profit: Option<I256>, bps: i64| {
+            profit.unwrap_or_default() * I256::from(bps) / I256::from(BPS)
+        };
+        weighted(self.with_victim, landed_bps) + weighted(self.without_victim, BPS - landed_bps)
+    }
+}
+
+/// Simulate `bundle` on a sandbox over `db`, for the victim landing and, if it isn't required, not
+/// landing. Nothing is committed to `db`.
+///
+/// Arguments:
+/// * `contract`: searcher contract whose WETH balance delta is the profit
+/// * `searcher`: EOA paying for the frontrun and backrun gas, at their effective gas price
+/// * `l1_fee`: L1 fee parameters on OP-stack chains, the searcher's txs are charged an upper
+///   bound of their L1 data fee on top of their execution gas
+pub fn simulate_variant<DB>(
+    db: &DB,
+    env: &Env,
+    bundle: &VariantBundle,
+    contract: B160,
+    searcher: B160,
+    weth: B160,
+    l1_fee: Option<&L1FeeParams>,
+) -> Result<VariantSim, VariantError>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let victim = tx_to_tx_env(&bundle.victim);
+    let mut with_victim = bundle.approvals.clone();
+    if let Some(frontrun) = &bundle.frontrun {
+        with_victim.push(frontrun.clone());
+    }
+    with_victim.push(victim);
+    with_victim.push(bundle.backrun.clone());
+
+    let run = |txs: &[TxEnv]| {
+        run_bundle(
+            db,
+            env,
+            txs,
+            contract,
+            searcher,
+            weth,
+            bundle.victim_required(),
+            l1_fee,
+        )
+    };
+    let without_victim = if bundle.victim_required() {
+        None
+    } else {
+        let mut txs = bundle.approvals.clone();
+        txs.push(bundle.backrun.clone());
+        run(&txs)?
+    };
+
+    Ok(VariantSim {
+        variant: bundle.variant,
+        with_victim: run(&with_victim)?,
+        without_victim,
+    })
+}
+
+/// [simulate_variant] each of `variants` and keep the one [select_variant] picks, `None` if none is
+/// profitable. Variants with more of our txs pay more L1 fees with `l1_fee` set.
+pub fn best_of<DB>(
+    db: &DB,
+    env: &Env,
+    variants: Vec<VariantBundle>,
+    contract: B160,
+    searcher: B160,
+    weth: B160,
+    victim_inclusion: f64,
+    l1_fee: Option<&L1FeeParams>,
+) -> Result<Option<(VariantBundle, VariantSim)>, VariantError>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let sims = variants
+        .iter()
+        .map(|bundle| simulate_variant(db, env, bundle, contract, searcher, weth, l1_fee))
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some(best) = select_variant(&sims, victim_inclusion).copied() else {
+        return Ok(None);
+    };
+    Ok(variants.into_iter().zip(sims).find(|(_, sim)| *sim == best))
+}
+
+/// Executes `txs` in order, returns the net profit or `None` if a tx that has to succeed didn't
+fn run_bundle<DB>(
+    db: &DB,
+    env: &Env,
+    txs: &[TxEnv],
+    contract: B160,
+    searcher: B160,
+    weth: B160,
+    victim_required: bool,
+    l1_fee: Option<&L1FeeParams>,
+) -> Result<Option<I256>, VariantError>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let mut sandbox = CacheDB::new(DelegationDb::new(RefDb(db)));
+    let mut inspector = BalanceDeltaInspector::new([contract]).with_weth(weth);
+    let mut gas_cost = U256::zero();
+
+    for tx in txs {
+        let mut evm = EVM::new();
+        evm.env = env.clone();
+        evm.env.tx = tx.clone();
+        evm.database(&mut sandbox);
+
+        let result = evm
+            .inspect_commit(&mut inspector)
+            .map_err(|e| VariantError::Simulation(format!("{:?}", e)))?;
+
+        let ours = tx.caller == searcher;
+        if ours {
+            gas_cost += U256::from(result.gas_used()) * effective_gas_price(env, tx);
+            if let Some(l1_fee) = l1_fee {
+                gas_cost += l1_fee.l1_fee_upper_bound(TX_ENVELOPE_SIZE + tx.data.len());
+            }
+        }
+        if !result.is_success() && (ours || victim_required) {
+            return Ok(None);
+        }
+    }
+
+    let profit = inspector.delta(contract, Asset::Token(weth));
+    Ok(Some(profit - I256::from_raw(gas_cost)))
+}
+
+/// Price per gas `tx` pays in the block of `env`, tip included, and at least the basefee it has to
+/// pay to be included
+fn effective_gas_price(env: &Env, tx: &TxEnv) -> U256 {
+    let basefee = ru256_to_u256(env.block.basefee);
+    let max_fee = ru256_to_u256(tx.gas_price);
+    let price = match tx.gas_priority_fee {
+        Some(tip) => max_fee.min(basefee + ru256_to_u256(tip)),
+        None => max_fee,
+    };
+    price.max(basefee)
+}
+
+/// Variant with the best positive expected value
+pub fn select_variant(sims: &[VariantSim], victim_inclusion: f64) -> Option<&VariantSim> {
+    sims.iter()
+        .map(|sim| (sim, sim.expected_value(victim_inclusion)))
+        .filter(|(_, ev)| *ev > I256::zero())
+        .max_by_key(|(_, ev)| *ev)
+        .map(|(sim, _)| sim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::primitives::U256 as rU256;
+
+    #[test]
+    fn test_select_variant() {
+        let sandwich = VariantSim {
+            variant: BundleVariant::Sandwich,
+            with_victim: Some(I256::from(1_000)),
+            without_victim: None,
+        };
+        let backrun_only = VariantSim {
+            variant: BundleVariant::BackrunOnly,
+            with_victim: Some(I256::from(400)),
+            without_victim: Some(I256::from(100)),
+        };
+        let sims = [sandwich, backrun_only];
+
+        assert_eq!(sandwich.expected_value(0.5), I256::from(500));
+        assert_eq!(backrun_only.expected_value(0.5), I256::from(250));
+
+        // a likely victim favours the sandwich, an unlikely one the fallback
+        assert_eq!(
+            select_variant(&sims, 0.9).map(|s| s.variant),
+            Some(BundleVariant::Sandwich)
+        );
+        assert_eq!(
+            select_variant(&sims, 0.1).map(|s| s.variant),
+            Some(BundleVariant::BackrunOnly)
+        );
+
+        let failing = VariantSim {
+            variant: BundleVariant::Sandwich,
+            with_victim: None,
+            without_victim: None,
+        };
+        assert!(select_variant(&[failing], 1.0).is_none());
+    }
+
+    #[test]
+    fn test_effective_gas_price() {
+        let mut env = Env::default();
+        env.block.basefee = rU256::from(10);
+        let tx = |max_fee: u64, tip: Option<u64>| TxEnv {
+            gas_price: rU256::from(max_fee),
+            gas_priority_fee: tip.map(rU256::from),
+            ..Default::default()
+        };
+
+        assert_eq!(effective_gas_price(&env, &tx(100, Some(3))), U256::from(13));
+        assert_eq!(effective_gas_price(&env, &tx(12, Some(3))), U256::from(12));
+        assert_eq!(effective_gas_price(&env, &tx(20, None)), U256::from(20));
+        assert_eq!(effective_gas_price(&env, &tx(0, None)), U256::from(10));
+    }
+
+    #[test]
+    fn test_backrun_only_leaves_victim_out() {
+        let victim = Transaction {
+            hash: H256::from_low_u64_be(1),
+            ..Default::default()
+        };
+        let variants = build_variants(
+            TxEnv::default(),
+            victim,
+            TxEnv::default(),
+            Some(TxEnv::default()),
+        );
+
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[0].bundle_victim(), Some(H256::from_low_u64_be(1)));
+        assert_eq!(variants[0].own_txs().len(), 2);
+        assert_eq!(variants[1].bundle_victim(), None);
+        assert_eq!(variants[1].own_txs().len(), 1);
+    }
+
+    #[test]
+    fn test_l1_fee_picks_the_variant() {
+        use ethers::{types::Address, utils::keccak256};
+        use revm::{
+            db::EmptyDB,
+            primitives::{AccountInfo, Bytecode, TransactTo},
+        };
+
+        let (contract, searcher, weth, pool) = (
+            B160::from_low_u64_be(10),
+            B160::from_low_u64_be(11),
+            B160::from_low_u64_be(12),
+            B160::from_low_u64_be(13),
+        );
+        // every call to `weth` pays the contract 1000 wei, out of `pool`
+        let mut code = vec![0x7f];
+        code.extend(rU256::from(1_000u64).to_be_bytes::<32>());
+        code.extend([0x60, 0x00, 0x52]);
+        for word in [contract, pool] {
+            code.push(0x7f);
+            code.extend([0u8; 12]);
+            code.extend(word.0);
+        }
+        code.push(0x7f);
+        code.extend(keccak256("Transfer(address,address,uint256)"));
+        code.extend([0x60, 0x20, 0x60, 0x00, 0xa3, 0x00]);
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            weth,
+            AccountInfo::new(rU256::ZERO, 0, Bytecode::new_raw(code.into())),
+        );
+
+        let call = |data: Vec<u8>| TxEnv {
+            caller: searcher,
+            transact_to: TransactTo::Call(weth),
+            data: data.into(),
+            gas_limit: 100_000,
+            ..Default::default()
+        };
+        let victim = Transaction {
+            hash: H256::from_low_u64_be(1),
+            from: Address::from_low_u64_be(14),
+            to: Some(Address::from_low_u64_be(15)),
+            gas: U256::from(21_000),
+            ..Default::default()
+        };
+        // the frontrun makes as much again, but carries a lot of calldata
+        let variants = || {
+            build_variants(
+                call(vec![0; 2_000]),
+                victim.clone(),
+                call(vec![]),
+                Some(call(vec![])),
+            )
+        };
+        let best = |l1_fee: Option<&L1FeeParams>| {
+            best_of(
+                &db,
+                &Env::default(),
+                variants(),
+                contract,
+                searcher,
+                weth,
+                1.0,
+                l1_fee,
+            )
+            .unwrap()
+            .map(|(bundle, _)| bundle.variant)
+        };
+
+        assert_eq!(best(None), Some(BundleVariant::Sandwich));
+        // posting the frontrun to L1 costs more than it makes
+        let l1_fee = L1FeeParams {
+            l1_base_fee: U256::from(62_500),
+            base_fee_scalar: 1,
+            ..Default::default()
+        };
+        assert_eq!(best(Some(&l1_fee)), Some(BundleVariant::BackrunOnly));
+    }
+
+    #[test]
+    fn test_node_faults_fail_the_simulation() {
+        use ethers::types::Address;
+        use fork_database::{
+            blockchain_db::{BlockchainDb, BlockchainDbMeta},
+            faults::{FaultConfig, FaultInjector, FaultyDb},
+            forked_db::ForkedDatabase,
+        };
+        use revm::{db::EmptyDB, primitives::TransactTo};
+
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: Default::default(),
+        };
+        let fork = |config: FaultConfig| {
+            let backend =
+                FaultyDb::new(CacheDB::new(EmptyDB::default()), FaultInjector::new(config));
+            ForkedDatabase::new(backend, BlockchainDb::new(meta.clone(), None))
+        };
+        let (contract, searcher, weth) = (
+            B160::from_low_u64_be(10),
+            B160::from_low_u64_be(11),
+            B160::from_low_u64_be(12),
+        );
+        let victim = Transaction {
+            hash: H256::from_low_u64_be(1),
+            from: Address::from_low_u64_be(13),
+            to: Some(Address::from_low_u64_be(14)),
+            gas: U256::from(21_000),
+            ..Default::default()
+        };
+        let backrun = TxEnv {
+            caller: searcher,
+            transact_to: TransactTo::Call(contract),
+            gas_limit: 100_000,
+            ..Default::default()
+        };
+        let bundle = VariantBundle::backrun_only(victim, backrun);
+        let simulate = |db: &ForkedDatabase<_>| {
+            simulate_variant(db, &Env::default(), &bundle, contract, searcher, weth, None)
+        };
+
+        let healthy = simulate(&fork(FaultConfig::default())).unwrap();
+        assert_eq!(healthy.with_victim, Some(I256::zero()));
+        // a node that stops answering fails the simulation rather than pricing the bundle on
+        // empty state
+        let dropping = fork(FaultConfig {
+            drop_rate: 1.0,
+            ..Default::default()
+        });
+        assert!(matches!(
+            simulate(&dropping),
+            Err(VariantError::Simulation(_))
+        ));
+    }
+}
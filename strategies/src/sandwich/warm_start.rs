@@ -0,0 +1,282 @@
+//! Carrying sandwich sizes from block to block
+//!
+//! A victim that stays pending is re-optimized on every new block. Unless the block moved its
+//! pool much, the optimum barely moves either, so [WarmStart] remembers the last result per victim
+//! and searches only around it. The full search runs again for new victims, for victims whose
+//! pool changed, and once the pool's reserves moved past `max_reserve_move_bps`.
+
+use std::collections::HashMap;
+
+use ethers::types::{Address, H256, U256};
+
+use super::optimizer::{
+    optimize_v2_sandwich_taxed, optimize_v2_sandwich_within, SandwichPlan, SearchHint, TaxRates,
+};
+
+/// Reserve move past which the previous optimum isn't trusted anymore
+pub const DEFAULT_MAX_RESERVE_MOVE_BPS: u32 = 100;
+/// Sizes searched around the previous optimum
+pub const DEFAULT_WINDOW_BPS: u32 = 500;
+/// Blocks a result is kept for without the victim being optimized again
+pub const DEFAULT_MAX_AGE_BLOCKS: u64 = 5;
+
+/// Reserves of the pair a sandwich trades on, weth side first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PairState {
+    pub pool: Address,
+    pub reserve_weth: U256,
+    pub reserve_token: U256,
+}
+
+impl PairState {
+    pub fn new(pool: Address, reserve_weth: U256, reserve_token: U256) -> Self {
+        Self {
+            pool,
+            reserve_weth,
+            reserve_token,
+        }
+    }
+
+    /// Largest relative move of either reserve since `prev`, in bps
+    fn moved_bps(&self, prev: &PairState) -> u64 {
+        let moved = |now: U256, then: U256| {
+            if then.is_zero() {
+                return u64::MAX;
+            }
+            let diff = if now > then { now - then } else { then - now };
+            (diff.saturating_mul(U256::from(10_000)) / then)
+                .try_into()
+                .unwrap_or(u64::MAX)
+        };
+        moved(self.reserve_weth, prev.reserve_weth)
+            .max(moved(self.reserve_token, prev.reserve_token))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchKind {
+    /// searched around the previous block's optimum
+    Warm,
+    Full,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct WarmEntry {
+    state: PairState,
+    plan: SandwichPlan,
+    block: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct WarmStart {
+    entries: HashMap<H256, WarmEntry>,
+    max_reserve_move_bps: u32,
+    window_bps: u32,
+    max_age_blocks: u64,
+}
+
+impl Default for WarmStart {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            max_reserve_move_bps: DEFAULT_MAX_RESERVE_MOVE_BPS,
+            window_bps: DEFAULT_WINDOW_BPS,
+            max_age_blocks: DEFAULT_MAX_AGE_BLOCKS,
+        }
+    }
+}
+
+impl WarmStart {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_reserve_move_bps(mut self, bps: u32) -> Self {
+        self.max_reserve_move_bps = bps;
+        self
+    }
+
+    pub fn with_window_bps(mut self, bps: u32) -> Self {
+        self.window_bps = bps;
+        self
+    }
+
+    pub fn with_max_age_blocks(mut self, blocks: u64) -> Self {
+        self.max_age_blocks = blocks;
+        self
+    }
+
+    /// Last optimum found for `victim`
+    pub fn previous(&self, victim: &H256) -> Option<&SandwichPlan> {
+        self.entries.get(victim).map(|entry| &entry.plan)
+    }
+
+    /// Optimal sandwich of `victim` on `state` in `block`, warm started if a previous result
+    /// applies. Unprofitable victims are forgotten.
+    #[allow(clippy::too_many_arguments)]
+    pub fn optimize(
+        &mut self,
+        victim: H256,
+        block: u64,
+        state: PairState,
+        victim_in: U256,
+        victim_min_out: U256,
+        max_frontrun_in: U256,
+        tax: TaxRates,
+    ) -> (Option<SandwichPlan>, SearchKind) {
+        let hint = self
+            .entries
+            .get(&victim)
+            .filter(|prev| {
+                prev.state.pool == state.pool
+                    && prev.block < block
+                    && state.moved_bps(&prev.state) <= self.max_reserve_move_bps as u64
+            })
+            .map(|prev| SearchHint {
+                frontrun_in: prev.plan.frontrun_in,
+                window_bps: self.window_bps,
+            });
+
+        let warm = hint.and_then(|hint| {
+            optimize_v2_sandwich_within(
+                state.reserve_weth,
+                state.reserve_token,
+                victim_in,
+                victim_min_out,
+                max_frontrun_in,
+                tax,
+                hint,
+            )
+        });
+        // no hint, or the optimum may lie outside its window
+        let (plan, kind) = match warm {
+            Some(plan) => (plan, SearchKind::Warm),
+            None => (
+                optimize_v2_sandwich_taxed(
+                    state.reserve_weth,
+                    state.reserve_token,
+                    victim_in,
+                    victim_min_out,
+                    max_frontrun_in,
+                    tax,
+                ),
+                SearchKind::Full,
+            ),
+        };
+
+        match plan {
+            Some(plan) => {
+                self.entries
+                    .insert(victim, WarmEntry { state, plan, block });
+            }
+            None => {
+                self.entries.remove(&victim);
+            }
+        }
+        (plan, kind)
+    }
+
+    /// Forget `victim`, e.g. once it landed or was replaced
+    pub fn remove(&mut self, victim: &H256) {
+        self.entries.remove(victim);
+    }
+
+    /// New block `number`, drops results not refreshed for too long
+    pub fn on_block(&mut self, number: u64) {
+        let max_age = self.max_age_blocks;
+        self.entries
+            .retain(|_, entry| entry.block + max_age >= number);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandwich::optimizer::get_amount_out;
+
+    fn ether(n: u64) -> U256 {
+        U256::exp10(18) * n
+    }
+
+    #[test]
+    fn test_warm_start_across_blocks() {
+        let (victim, pool) = (H256::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let (reserve_weth, reserve_token) = (ether(1_000), ether(2_000_000));
+        let victim_in = ether(50);
+        let min_out = get_amount_out(victim_in, reserve_weth, reserve_token) * 98 / 100;
+        let mut warm = WarmStart::new();
+        let optimize = |warm: &mut WarmStart, block: u64, state: PairState| {
+            warm.optimize(
+                victim,
+                block,
+                state,
+                victim_in,
+                min_out,
+                ether(1_000),
+                TaxRates::default(),
+            )
+        };
+
+        let (first, kind) = optimize(
+            &mut warm,
+            1,
+            PairState::new(pool, reserve_weth, reserve_token),
+        );
+        assert_eq!(kind, SearchKind::Full);
+        assert!(first.is_some());
+
+        // a small trade on the pair, the previous optimum is reused as the start
+        let nudged = PairState::new(pool, reserve_weth + ether(1), reserve_token - ether(1_990));
+        let (second, kind) = optimize(&mut warm, 2, nudged);
+        assert_eq!(kind, SearchKind::Warm);
+        assert_eq!(
+            second,
+            optimize_v2_sandwich_taxed(
+                nudged.reserve_weth,
+                nudged.reserve_token,
+                victim_in,
+                min_out,
+                ether(1_000),
+                TaxRates::default()
+            )
+        );
+
+        // a window too narrow for the optimum falls back to the full search
+        let mut narrow = WarmStart::new().with_window_bps(1);
+        optimize(
+            &mut narrow,
+            1,
+            PairState::new(pool, reserve_weth, reserve_token),
+        );
+        let shifted = PairState::new(pool, reserve_weth + ether(4), reserve_token - ether(7_960));
+        let (fallback, kind) = optimize(&mut narrow, 2, shifted);
+        assert_eq!(kind, SearchKind::Full);
+        assert_eq!(
+            fallback,
+            optimize_v2_sandwich_taxed(
+                shifted.reserve_weth,
+                shifted.reserve_token,
+                victim_in,
+                min_out,
+                ether(1_000),
+                TaxRates::default()
+            )
+        );
+
+        // a large one moves the pair past the threshold
+        let moved = PairState::new(pool, reserve_weth * 2, reserve_token / 2);
+        let (_, kind) = optimize(&mut warm, 3, moved);
+        assert_eq!(kind, SearchKind::Full);
+
+        warm.on_block(3 + DEFAULT_MAX_AGE_BLOCKS + 1);
+        assert!(warm.is_empty());
+    }
+}
@@ -0,0 +1,225 @@
+//! Ranking opportunities by what they pay, valued in WETH
+//!
+//! Opportunities earn in whatever token they trade: a CoW settlement keeps its order's fee in the
+//! sell token, a sandwich or an arb ends up with WETH. The [OpportunityScorer] values every payout
+//! through the [PriceOracle] so they compare, takes the gas off, and drops those not worth
+//! `min_profit`. Before any of that, every swap is checked against its pool's
+//! [DepthChart](qilin_cfmms::depth::DepthChart): a pool that can't take the swap within
+//! `max_impact_bps` is too shallow to be worth simulating.
+
+use std::sync::Arc;
+
+use ethers::{
+    providers::Middleware,
+    types::{Address, U256},
+};
+use log::debug;
+use qilin_cfmms::{pool::Pool, registry::PoolRegistry};
+
+use crate::pricing::{PriceOracle, PricingError};
+
+/// Net profit below which an opportunity isn't worth a bundle, in wei
+pub const DEFAULT_MIN_PROFIT: u128 = 1_000_000_000_000_000;
+/// Price impact a swap may have on its pool, fees included
+pub const DEFAULT_MAX_IMPACT_BPS: u64 = 500;
+/// Sizes sampled per depth chart, up to `2 ** 15` bps of the input reserve
+const DEPTH_LEVELS: usize = 16;
+
+/// A swap an opportunity makes
+#[derive(Debug, Clone, Copy)]
+pub struct Leg {
+    pub pool: Pool,
+    pub zero_for_one: bool,
+    pub amount_in: U256,
+}
+
+/// What an opportunity pays out, what landing it costs in WETH, and the swaps it makes
+#[derive(Debug, Clone)]
+pub struct Payouts {
+    pub tokens: Vec<(Address, U256)>,
+    pub gas_cost: U256,
+    pub legs: Vec<Leg>,
+}
+
+impl Payouts {
+    pub fn new(gas_cost: U256) -> Self {
+        Self {
+            tokens: vec![],
+            gas_cost,
+            legs: vec![],
+        }
+    }
+
+    pub fn with(mut self, token: Address, amount: U256) -> Self {
+        self.tokens.push((token, amount));
+        self
+    }
+
+    /// Swap `amount_in` of `token_0` (`zero_for_one`) or `token_1` on `pool`
+    pub fn through(mut self, pool: Pool, zero_for_one: bool, amount_in: U256) -> Self {
+        self.legs.push(Leg {
+            pool,
+            zero_for_one,
+            amount_in,
+        });
+        self
+    }
+}
+
+pub struct OpportunityScorer<M> {
+    oracle: Arc<PriceOracle<M>>,
+    /// net profit in WETH an opportunity has to reach
+    pub min_profit: U256,
+    pub max_impact_bps: u64,
+}
+
+impl<M> OpportunityScorer<M>
+where
+    M: Middleware + 'static,
+{
+    pub fn new(oracle: Arc<PriceOracle<M>>) -> Self {
+        Self {
+            oracle,
+            min_profit: U256::from(DEFAULT_MIN_PROFIT),
+            max_impact_bps: DEFAULT_MAX_IMPACT_BPS,
+        }
+    }
+
+    pub fn with_min_profit(mut self, min_profit: U256) -> Self {
+        self.min_profit = min_profit;
+        self
+    }
+
+    pub fn with_max_impact_bps(mut self, bps: u64) -> Self {
+        self.max_impact_bps = bps;
+        self
+    }
+
+    pub fn oracle(&self) -> &Arc<PriceOracle<M>> {
+        &self.oracle
+    }
+
+    /// Net profit of `payouts` in WETH, priced on the pools of `registry`. `None` if one of its
+    /// legs is too shallow, see [OpportunityScorer::is_shallow], or it falls short of
+    /// `min_profit`.
+    pub async fn score(
+        &self,
+        payouts: &Payouts,
+        registry: &PoolRegistry,
+    ) -> Result<Option<U256>, PricingError<M>> {
+        if let Some(leg) = payouts
+            .legs
+            .iter()
+            .find(|leg| self.is_shallow(leg, registry))
+        {
+            debug!(
+                "Pool {:?} can't take {} within {} bps",
+                leg.pool.address, leg.amount_in, self.max_impact_bps
+            );
+            return Ok(None);
+        }
+        let pools = registry.view();
+        let mut value = U256::zero();
+        for (token, amount) in payouts.tokens.iter().filter(|(_, a)| !a.is_zero()) {
+            let (weth, _) = self
+                .oracle
+                .checked_value_in_weth(*token, *amount, &pools)
+                .await?;
+            value = value.saturating_add(weth);
+        }
+        let profit = value.saturating_sub(payouts.gas_cost);
+        Ok((!profit.is_zero() && profit >= self.min_profit).then_some(profit))
+    }
+
+    /// Whether `leg`'s pool moves more than `max_impact_bps` before taking its amount in, V3
+    /// pools walking the ticks `registry` knows of
+    pub fn is_shallow(&self, leg: &Leg, registry: &PoolRegistry) -> bool {
+        let ticks = registry.ticks(&leg.pool.address).unwrap_or_default();
+        leg.pool
+            .depth_chart_with_ticks(leg.zero_for_one, DEPTH_LEVELS, &ticks)
+            .is_shallow(leg.amount_in, self.max_impact_bps)
+    }
+
+    /// The `candidates` worth landing with their net profit, best first. Those that can't be
+    /// priced are dropped.
+    pub async fn rank<T>(
+        &self,
+        candidates: Vec<(T, Payouts)>,
+        registry: &PoolRegistry,
+    ) -> Vec<(T, U256)> {
+        let mut ranked = vec![];
+        for (candidate, payouts) in candidates {
+            match self.score(&payouts, registry).await {
+                Ok(Some(profit)) => ranked.push((candidate, profit)),
+                Ok(None) => {}
+                Err(e) => debug!("Not scoring {:?}: {}", payouts.tokens, e),
+            }
+        }
+        ranked.sort_by(|(_, a), (_, b)| b.cmp(a));
+        ranked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::{Http, Provider};
+    use qilin_cfmms::{
+        registry::PoolRegistry,
+        test_utils::{address, v2_pool},
+    };
+
+    #[tokio::test]
+    async fn test_rank_values_payouts_in_weth() {
+        let (weth, usdc) = (address(1), address(2));
+        let registry = PoolRegistry::new();
+        // 2000 USDC per WETH
+        let deep = v2_pool(
+            address(0xfee),
+            weth,
+            usdc,
+            (U256::exp10(18) * 1_000, U256::exp10(6) * 2_000_000),
+        );
+        let shallow = v2_pool(
+            address(0xbad),
+            weth,
+            usdc,
+            (U256::exp10(18), U256::exp10(6) * 2_000),
+        );
+        registry.insert(deep);
+        registry.commit(None);
+        let provider = Arc::new(Provider::<Http>::try_from("http://localhost:8545").unwrap());
+        let oracle = Arc::new(PriceOracle::new(provider, weth, Address::zero()));
+        let scorer = OpportunityScorer::new(oracle).with_min_profit(U256::exp10(16));
+
+        let gas = U256::exp10(15);
+        let candidates = vec![
+            (
+                "weth",
+                Payouts::new(gas)
+                    .with(weth, U256::exp10(17))
+                    .through(deep, true, U256::exp10(18)),
+            ),
+            (
+                "shallow",
+                Payouts::new(gas).with(weth, U256::exp10(18)).through(
+                    shallow,
+                    true,
+                    U256::exp10(18),
+                ),
+            ),
+            // ~0.25 WETH
+            ("usdc", Payouts::new(gas).with(usdc, U256::exp10(6) * 500)),
+            ("dust", Payouts::new(gas).with(weth, U256::exp10(15) * 5)),
+            (
+                "unpriced",
+                Payouts::new(gas).with(address(3), U256::exp10(18)),
+            ),
+        ];
+        let ranked = scorer.rank(candidates, &registry).await;
+
+        let order: Vec<&str> = ranked.iter().map(|(name, _)| *name).collect();
+        assert_eq!(order, vec!["usdc", "weth"]);
+        assert_eq!(ranked[1].1, U256::exp10(17) - gas);
+    }
+}
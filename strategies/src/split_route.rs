@@ -0,0 +1,302 @@
+//! One leg split across several pools of a pair
+//!
+//! Trading a size through a single pool pays its whole price impact, spreading it over the pools
+//! of the pair, V2 pairs and V3 pools alike, pays less. The output of each pool is concave in its
+//! input, so [split] sending every chunk of the trade to the pool paying the most for it on top of
+//! what it already got is the optimal split, down to the size of a chunk. V3 pools are quoted
+//! across their initialized ticks, a leg never gets more than the liquidity it walks through.
+//! Every extra leg costs a swap's gas though, so [plan_split] only keeps the legs that pay for
+//! themselves. V2 legs go through the executor's own swap, V3 legs through a router the executor
+//! calls, see [SplitRoute::payloads].
+
+use ethers::{
+    abi::AbiEncode,
+    types::{Address, Bytes, U256},
+};
+use qilin_cfmms::{
+    batch_requests::uniswap_v3::UniswapV3TickData,
+    bindings::uniswap_v3_router_2::{ExactInputSingleCall, ExactInputSingleParams},
+    pool::{Pool, PoolType},
+};
+
+use crate::approvals::{ExecutorEntryPoint, Spend};
+use crate::sandwich::utils::tx_builder::sandwicher::build_v2_payload;
+
+/// Gas of one more swap through the executor, transfer and `swap` call
+pub const LEG_GAS: u64 = 60_000;
+/// Chunks a trade is split in by default
+pub const DEFAULT_SPLIT_PARTS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SplitLeg {
+    pub pool: Pool,
+    pub amount_in: U256,
+    /// expected out, the executor asks for exactly this much
+    pub amount_out: U256,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SplitRoute {
+    pub token_in: Address,
+    pub legs: Vec<SplitLeg>,
+}
+
+impl SplitRoute {
+    pub fn amount_in(&self) -> U256 {
+        self.legs
+            .iter()
+            .fold(U256::zero(), |sum, leg| sum + leg.amount_in)
+    }
+
+    pub fn amount_out(&self) -> U256 {
+        self.legs
+            .iter()
+            .fold(U256::zero(), |sum, leg| sum + leg.amount_out)
+    }
+
+    pub fn gas(&self) -> u64 {
+        LEG_GAS * self.legs.len() as u64
+    }
+
+    /// Executor payload of every leg, in order. V3 legs are `exactInputSingle` calls on `router`
+    /// paying out to `executor`, made through its `entry_point`, the router pulls the tokens in
+    /// with the allowances of [SplitRoute::spends].
+    pub fn payloads(
+        &self,
+        executor: Address,
+        router: Address,
+        entry_point: ExecutorEntryPoint,
+    ) -> Vec<Bytes> {
+        self.legs
+            .iter()
+            .map(|leg| match leg.pool.pool_type {
+                PoolType::UniswapV2(_) => {
+                    let token_out_no = u8::from(self.token_in == leg.pool.token_0);
+                    build_v2_payload(
+                        self.token_in,
+                        leg.pool.address,
+                        leg.amount_in,
+                        leg.amount_out,
+                        token_out_no,
+                    )
+                }
+                PoolType::UniswapV3(pool) => {
+                    let call = ExactInputSingleCall {
+                        params: ExactInputSingleParams {
+                            token_in: self.token_in,
+                            token_out: self.token_out(&leg.pool),
+                            fee: pool.fee,
+                            recipient: executor,
+                            amount_in: leg.amount_in,
+                            amount_out_minimum: leg.amount_out,
+                            sqrt_price_limit_x96: U256::zero(),
+                        },
+                    };
+                    entry_point(router, call.encode().into())
+                }
+            })
+            .collect()
+    }
+
+    /// What the V3 legs let `router` pull from the executor
+    pub fn spends(&self, router: Address) -> Vec<Spend> {
+        let amount = self
+            .legs
+            .iter()
+            .filter(|leg| matches!(leg.pool.pool_type, PoolType::UniswapV3(_)))
+            .fold(U256::zero(), |sum, leg| sum + leg.amount_in);
+        if amount.is_zero() {
+            return vec![];
+        }
+        vec![Spend {
+            token: self.token_in,
+            spender: router,
+            amount,
+        }]
+    }
+
+    fn token_out(&self, pool: &Pool) -> Address {
+        if self.token_in == pool.token_0 {
+            pool.token_1
+        } else {
+            pool.token_0
+        }
+    }
+}
+
+/// `amount_in` of `token_in` through `pool` alone, `None` if it doesn't trade `token_in` or can't
+/// fill it
+pub fn quote_leg(
+    pool: &Pool,
+    ticks: &[UniswapV3TickData],
+    token_in: Address,
+    amount_in: U256,
+) -> Option<SplitLeg> {
+    if token_in != pool.token_0 && token_in != pool.token_1 {
+        return None;
+    }
+    let amount_out = pool.quote_with_ticks(token_in == pool.token_0, amount_in, ticks)?;
+    Some(SplitLeg {
+        pool: *pool,
+        amount_in,
+        amount_out,
+    })
+}
+
+/// `amount_in` split in `parts` chunks across `pools`, each chunk sent to the pool paying the most
+/// for it on top of what it already got. V2 pools go with no ticks. Pools getting nothing are left
+/// out, nothing is returned if the pools can't take the whole amount.
+pub fn split(
+    pools: &[(Pool, Vec<UniswapV3TickData>)],
+    token_in: Address,
+    amount_in: U256,
+    parts: usize,
+) -> Vec<SplitLeg> {
+    let parts = parts.max(1);
+    let chunk = amount_in / parts;
+    let mut filled: Vec<(U256, U256)> = vec![(U256::zero(), U256::zero()); pools.len()];
+    for part in 0..parts {
+        // the last chunk takes the rounding remainder
+        let size = if part + 1 == parts {
+            amount_in - chunk * (parts - 1)
+        } else {
+            chunk
+        };
+        if size.is_zero() {
+            continue;
+        }
+        let best = pools
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (pool, ticks))| {
+                let (in_so_far, out_so_far) = filled[i];
+                let leg = quote_leg(pool, ticks, token_in, in_so_far + size)?;
+                Some((i, leg.amount_out.saturating_sub(out_so_far), leg.amount_out))
+            })
+            .max_by_key(|(_, marginal, _)| *marginal);
+        let Some((i, _, out)) = best else {
+            // no pool can take more
+            return vec![];
+        };
+        filled[i] = (filled[i].0 + size, out);
+    }
+    pools
+        .iter()
+        .zip(filled)
+        .filter(|(_, (amount_in, _))| !amount_in.is_zero())
+        .map(|((pool, _), (amount_in, amount_out))| SplitLeg {
+            pool: *pool,
+            amount_in,
+            amount_out,
+        })
+        .collect()
+}
+
+/// Split of `amount_in` of `token_in` across `pools` with the most out net of gas, `leg_cost`
+/// being [LEG_GAS] priced in the output token. Legs are dropped, smallest first, as long as that
+/// improves the output net of gas, and the best single pool is kept if it beats the split. `None`
+/// if no pool can fill `token_in`.
+pub fn plan_split(
+    pools: &[(Pool, Vec<UniswapV3TickData>)],
+    token_in: Address,
+    amount_in: U256,
+    leg_cost: U256,
+    parts: usize,
+) -> Option<SplitRoute> {
+    let net = |legs: &[SplitLeg]| {
+        legs.iter()
+            .fold(U256::zero(), |sum, leg| sum + leg.amount_out)
+            .saturating_sub(leg_cost * U256::from(legs.len()))
+    };
+    let mut used: Vec<(Pool, Vec<UniswapV3TickData>)> = pools.to_vec();
+    let mut best = split(&used, token_in, amount_in, parts);
+    while best.len() > 1 {
+        let smallest = best.iter().min_by_key(|leg| leg.amount_in).unwrap().pool;
+        used.retain(|(pool, _)| pool.address != smallest.address);
+        let fewer = split(&used, token_in, amount_in, parts);
+        if fewer.is_empty() || net(&fewer) < net(&best) {
+            break;
+        }
+        best = fewer;
+    }
+
+    let single = pools
+        .iter()
+        .filter_map(|(pool, ticks)| quote_leg(pool, ticks, token_in, amount_in))
+        .max_by_key(|leg| leg.amount_out);
+    let legs = match single {
+        Some(single) if best.is_empty() || net(&[single]) >= net(&best) => vec![single],
+        _ if best.is_empty() => return None,
+        _ => best,
+    };
+    Some(SplitRoute { token_in, legs })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::approvals::execute_call;
+    use qilin_cfmms::test_utils::{address, v2_pool, v3_pool};
+
+    #[test]
+    fn test_split_across_protocols() {
+        let (weth, token) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let e18 = 10u128.pow(18);
+        let reserve = U256::from(1_200 * e18);
+        let v2 = v2_pool(address(10), weth, token, (reserve, reserve));
+        // same price, a bit shallower in range, 0.3% as well
+        let v3 = v3_pool(address(11), weth, token, 3_000, 1_000 * e18);
+        let pools = [(v2, vec![]), (v3, vec![])];
+
+        let amount_in = U256::from(100 * e18);
+        let route = plan_split(&pools, weth, amount_in, U256::zero(), 100).unwrap();
+        assert_eq!(route.legs.len(), 2);
+        assert_eq!(route.amount_in(), amount_in);
+        assert!(route.legs[0].amount_in > route.legs[1].amount_in);
+        let v2_alone = quote_leg(&v2, &[], weth, amount_in).unwrap();
+        assert!(route.amount_out() > v2_alone.amount_out);
+
+        // the V3 leg goes through the router, which pulls its input from the executor
+        let (executor, router) = (Address::from_low_u64_be(20), Address::from_low_u64_be(21));
+        let payloads = route.payloads(executor, router, execute_call);
+        assert_eq!(payloads.len(), 2);
+        assert_eq!(payloads[0].len(), 73);
+        assert_eq!(
+            &payloads[1][..4],
+            &ethers::utils::id(crate::approvals::EXECUTE)
+        );
+        assert_eq!(
+            route.spends(router),
+            vec![Spend {
+                token: weth,
+                spender: router,
+                amount: route.legs[1].amount_in,
+            }]
+        );
+
+        // the V3 range ends a bit below the price, only what's left in range goes through it
+        let boundary = UniswapV3TickData {
+            initialized: true,
+            tick: -100,
+            liquidity_net: 1_000 * e18 as i128,
+        };
+        let ranged = [(v2, vec![]), (v3, vec![boundary])];
+        let capped = plan_split(&ranged, weth, amount_in, U256::zero(), 100).unwrap();
+        assert!(capped.legs[1].amount_in < route.legs[1].amount_in);
+        assert!(capped.amount_out() < route.amount_out());
+
+        // too expensive to split, the V2 pair alone
+        let single = plan_split(&pools, weth, amount_in, U256::from(10 * e18), 100).unwrap();
+        assert_eq!(single.legs, vec![v2_alone]);
+        assert_eq!(single.gas(), LEG_GAS);
+        assert!(single.spends(router).is_empty());
+        assert!(plan_split(
+            &pools,
+            Address::from_low_u64_be(3),
+            amount_in,
+            U256::zero(),
+            100
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,392 @@
+//! Choosing how an opportunity is sent out
+//!
+//! Bundles are the safe default, but a backrun or arbitrage nobody else is chasing can land just
+//! as well through the public mempool, paying a priority fee instead of a bribe. Public txs are
+//! escalated block by block, like a fee bump replacement, up to a share of the profit. Bundles pay
+//! the bribe with the best expected value under the [InclusionModel], bid up for the chance
+//! pending txs invalidate them, see [SubmissionPolicy::conflict_premium], and their outcomes are
+//! fed back into it, see [LandingStats::record_plan].
+
+use std::collections::HashMap;
+
+use ethers::types::U256;
+use parking_lot::Mutex;
+
+use crate::competition::{BribeDecision, BribePolicy};
+use crate::inclusion::InclusionModel;
+
+const BPS: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpportunityKind {
+    /// needs the victim between frontrun and backrun, only a bundle guarantees that
+    Sandwich,
+    /// only needs to land after its target
+    Backrun,
+    /// no ordering requirement at all
+    Arbitrage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Route {
+    PublicMempool,
+    /// a private tx endpoint of a builder, no ordering guarantee
+    PrivateRpc,
+    Bundle,
+}
+
+/// Priority fees of successive public resubmissions, one per block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeEscalation {
+    pub fees: Vec<U256>,
+}
+
+impl FeeEscalation {
+    /// Fee for the `attempt`th block, `None` once the escalation is exhausted
+    pub fn fee(&self, attempt: usize) -> Option<U256> {
+        self.fees.get(attempt).copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubmissionPlan {
+    pub route: Route,
+    /// set for [Route::PublicMempool]
+    pub escalation: Option<FeeEscalation>,
+    /// paid to the builder, set for [Route::Bundle]
+    pub bribe: Option<U256>,
+}
+
+/// What the policy needs to know about an opportunity
+#[derive(Debug, Clone, Copy)]
+pub struct SubmissionRequest {
+    pub kind: OpportunityKind,
+    /// gross profit in wei
+    pub profit: U256,
+    pub gas: u64,
+    /// competitive pressure on the traded pools, see
+    /// [CompetitionTracker::pressure](crate::competition::CompetitionTracker::pressure)
+    pub pressure: f64,
+    /// chance pending txs invalidate the opportunity, see
+    /// [ConflictAnalyzer](crate::conflicts::ConflictAnalyzer)
+    pub invalidation: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RouteCounts {
+    submitted: u64,
+    landed: u64,
+}
+
+/// Landing statistics per route, fed with the outcome of every submission
+#[derive(Debug, Default)]
+pub struct LandingStats {
+    routes: Mutex<HashMap<Route, RouteCounts>>,
+}
+
+impl LandingStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Outcome of `plan` for a bundle using `gas`, also recorded in `model` for bundles so the
+    /// bribes it suggests follow how ours fare
+    pub fn record_plan(
+        &self,
+        plan: &SubmissionPlan,
+        gas: u64,
+        landed: bool,
+        model: &mut InclusionModel,
+    ) {
+        self.record(plan.route, landed);
+        if let (Some(bribe), true) = (plan.bribe, gas > 0) {
+            model.record(bribe / gas, landed);
+        }
+    }
+
+    pub fn record(&self, route: Route, landed: bool) {
+        let mut routes = self.routes.lock();
+        let counts = routes.entry(route).or_default();
+        counts.submitted += 1;
+        if landed {
+            counts.landed += 1;
+        }
+    }
+
+    /// Add counts gathered elsewhere, e.g. by other instances
+    pub fn add(&self, route: Route, submitted: u64, landed: u64) {
+        let mut routes = self.routes.lock();
+        let counts = routes.entry(route).or_default();
+        counts.submitted += submitted;
+        counts.landed += landed.min(submitted);
+    }
+
+    /// Submissions through `route` and how many of them landed
+    pub fn counts(&self, route: Route) -> (u64, u64) {
+        let counts = self.routes.lock().get(&route).copied().unwrap_or_default();
+        (counts.submitted, counts.landed)
+    }
+
+    /// Share of submissions through `route` that landed, 0.5 without any data
+    pub fn landing_rate(&self, route: Route) -> f64 {
+        let counts = self.routes.lock().get(&route).copied().unwrap_or_default();
+        // Laplace smoothing, a couple of lucky submissions shouldn't decide the route
+        (counts.landed + 1) as f64 / (counts.submitted + 2) as f64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SubmissionPolicy {
+    /// pressure above which nothing goes through the public mempool
+    pub public_max_pressure: f64,
+    /// whether a private RPC is configured
+    pub private_rpc: bool,
+    /// share of the profit public priority fees may reach
+    pub max_fee_share_bps: u64,
+    /// fee increase per block, at least geth's 10% replacement bump
+    pub escalation_step_bps: u64,
+    /// blocks a public tx is resubmitted for
+    pub escalation_blocks: usize,
+    /// priority fee, per gas, of the first public submission
+    pub min_priority_fee: U256,
+    /// invalidation chance above which only bundles are used, reverted txs still pay for gas
+    pub unprotected_max_invalidation: f64,
+    /// sizes bundle bribes, see [BribePolicy::decide_ev]
+    pub bribes: BribePolicy,
+}
+
+impl Default for SubmissionPolicy {
+    fn default() -> Self {
+        Self {
+            public_max_pressure: 0.1,
+            private_rpc: false,
+            max_fee_share_bps: 5_000,
+            escalation_step_bps: 1_250,
+            escalation_blocks: 3,
+            min_priority_fee: U256::exp10(9),
+            unprotected_max_invalidation: 0.1,
+            bribes: BribePolicy::default(),
+        }
+    }
+}
+
+impl SubmissionPolicy {
+    pub fn with_private_rpc(mut self, private_rpc: bool) -> Self {
+        self.private_rpc = private_rpc;
+        self
+    }
+
+    pub fn with_bribes(mut self, bribes: BribePolicy) -> Self {
+        self.bribes = bribes;
+        self
+    }
+
+    /// Routes an opportunity of `kind` may take at `pressure`, safest first
+    pub fn allowed_routes(&self, kind: OpportunityKind, pressure: f64) -> Vec<Route> {
+        let public = pressure <= self.public_max_pressure;
+        match kind {
+            OpportunityKind::Sandwich => vec![Route::Bundle],
+            OpportunityKind::Backrun if public => vec![Route::Bundle, Route::PublicMempool],
+            OpportunityKind::Backrun => vec![Route::Bundle],
+            OpportunityKind::Arbitrage => {
+                let mut routes = vec![Route::Bundle];
+                if self.private_rpc {
+                    routes.push(Route::PrivateRpc);
+                }
+                if public {
+                    routes.push(Route::PublicMempool);
+                }
+                routes
+            }
+        }
+    }
+
+    /// Allowed route with the best landing rate, ties go to the safer route. Public submissions
+    /// that can't afford the initial priority fee fall back to the next route, opportunities
+    /// likely to be invalidated only go out as bundles. Bundles bribe with the best expected
+    /// value under `model`, plus [Self::conflict_premium], and aren't sent if no bribe has any.
+    /// `None` if no route is left.
+    pub fn decide(
+        &self,
+        request: &SubmissionRequest,
+        stats: &LandingStats,
+        model: &InclusionModel,
+    ) -> Option<SubmissionPlan> {
+        let mut best: Option<(f64, SubmissionPlan)> = None;
+        for route in self.allowed_routes(request.kind, request.pressure) {
+            if route != Route::Bundle && request.invalidation > self.unprotected_max_invalidation {
+                continue;
+            }
+            let (mut escalation, mut bribe) = (None, None);
+            match route {
+                Route::PublicMempool => match self.escalation(request) {
+                    Some(fees) => escalation = Some(fees),
+                    None => continue,
+                },
+                Route::Bundle => {
+                    let (profit, gas) = (request.profit, request.gas);
+                    let decision = self.bribes.decide_ev(model, profit, gas, request.pressure);
+                    let BribeDecision::Bid { bribe: paid, .. } = decision else {
+                        continue;
+                    };
+                    let Some(premium) = self.conflict_premium(request) else {
+                        continue;
+                    };
+                    let max = profit * self.bribes.max_bps / BPS;
+                    bribe = Some(paid.saturating_add(premium).min(max));
+                }
+                Route::PrivateRpc => {}
+            }
+            let rate = stats.landing_rate(route);
+            if best.as_ref().map_or(true, |(best, _)| rate > *best) {
+                let plan = SubmissionPlan {
+                    route,
+                    escalation,
+                    bribe,
+                };
+                best = Some((rate, plan));
+            }
+        }
+        best.map(|(_, plan)| plan)
+    }
+
+    /// What a bundle bids on top for the chance pending txs invalidate it, the share
+    /// [BribePolicy::decide_with_conflicts] adds to the one the pressure alone calls for. `None`
+    /// if the combined risk is too high to send the bundle at all.
+    pub fn conflict_premium(&self, request: &SubmissionRequest) -> Option<U256> {
+        let with_conflicts = self.bribes.decide_with_conflicts(
+            request.profit,
+            request.pressure,
+            request.invalidation,
+        );
+        let BribeDecision::Bid { bribe, .. } = with_conflicts else {
+            return None;
+        };
+        match self.bribes.decide(request.profit, request.pressure) {
+            BribeDecision::Bid { bribe: base, .. } => Some(bribe.saturating_sub(base)),
+            BribeDecision::Skip => None,
+        }
+    }
+
+    /// Per block priority fees for a public submission, `None` if even the first one would eat
+    /// more than [Self::max_fee_share_bps] of the profit
+    pub fn escalation(&self, request: &SubmissionRequest) -> Option<FeeEscalation> {
+        if request.gas == 0 {
+            return None;
+        }
+        let max_fee = request.profit * self.max_fee_share_bps / BPS / request.gas;
+        if max_fee < self.min_priority_fee {
+            return None;
+        }
+
+        let mut fees = Vec::with_capacity(self.escalation_blocks);
+        let mut fee = self.min_priority_fee;
+        for _ in 0..self.escalation_blocks {
+            fees.push(fee);
+            if fee == max_fee {
+                break;
+            }
+            fee = (fee * (BPS + self.escalation_step_bps) / BPS).min(max_fee);
+        }
+        Some(FeeEscalation { fees })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(kind: OpportunityKind, pressure: f64) -> SubmissionRequest {
+        SubmissionRequest {
+            kind,
+            // 0.01 ETH over 200k gas caps the fee at 25 gwei
+            profit: U256::exp10(16),
+            gas: 200_000,
+            pressure,
+            invalidation: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_routes() {
+        let policy = SubmissionPolicy {
+            min_priority_fee: U256::exp10(10),
+            escalation_step_bps: 10_000,
+            ..Default::default()
+        };
+        let stats = LandingStats::new();
+        for _ in 0..10 {
+            stats.record(Route::PublicMempool, true);
+            stats.record(Route::Bundle, false);
+        }
+        // competing bundles pay 10 gwei
+        let mut model = InclusionModel::default();
+        model.set_competitor_fees(vec![U256::exp10(10)]);
+
+        // sandwiches never go public
+        let sandwich = request(OpportunityKind::Sandwich, 0.0);
+        let plan = policy.decide(&sandwich, &stats, &model).unwrap();
+        assert_eq!(plan.route, Route::Bundle);
+        // just outbidding them, 10.25 gwei over 200k gas
+        assert_eq!(plan.bribe, Some(sandwich.profit * 2_050 / 10_000));
+
+        let plan = policy
+            .decide(&request(OpportunityKind::Backrun, 0.0), &stats, &model)
+            .unwrap();
+        assert_eq!(plan.route, Route::PublicMempool);
+        assert_eq!(plan.bribe, None);
+        assert_eq!(
+            plan.escalation.unwrap().fees,
+            vec![U256::exp10(10), U256::exp10(10) * 2, U256::exp10(9) * 25]
+        );
+
+        // contested backruns stay private
+        let plan = policy
+            .decide(&request(OpportunityKind::Backrun, 0.5), &stats, &model)
+            .unwrap();
+        assert_eq!(plan.route, Route::Bundle);
+
+        // likely to revert, keep the bundle's revert protection and bid up for the top of the
+        // block, by the 74.5% the conflicts call for over the 50% the pressure alone does
+        let mut likely_invalidated = request(OpportunityKind::Backrun, 0.0);
+        likely_invalidated.invalidation = 0.5;
+        let plan = policy.decide(&likely_invalidated, &stats, &model).unwrap();
+        assert_eq!(plan.route, Route::Bundle);
+        assert_eq!(
+            plan.bribe,
+            Some(likely_invalidated.profit * (2_050 + 2_450) / 10_000)
+        );
+
+        // too risky to send at all
+        let skipping = SubmissionPolicy {
+            bribes: BribePolicy {
+                skip_pressure: Some(0.4),
+                ..Default::default()
+            },
+            ..policy.clone()
+        };
+        assert_eq!(skipping.decide(&likely_invalidated, &stats, &model), None);
+
+        // nothing to bribe with, the bundle isn't worth sending
+        let worthless = SubmissionRequest {
+            profit: U256::zero(),
+            ..sandwich
+        };
+        assert_eq!(policy.decide(&worthless, &stats, &model), None);
+    }
+
+    #[test]
+    fn test_outcomes_feed_the_inclusion_model() {
+        let stats = LandingStats::new();
+        let mut model = InclusionModel::default().with_prior_weight(0.0);
+        let plan = SubmissionPlan {
+            route: Route::Bundle,
+            escalation: None,
+            bribe: Some(U256::exp10(15)),
+        };
+        stats.record_plan(&plan, 100_000, true, &mut model);
+        assert_eq!(stats.counts(Route::Bundle), (1, 1));
+        // 10 gwei per gas landed
+        assert_eq!(model.probability(U256::exp10(10)), 1.0);
+    }
+}
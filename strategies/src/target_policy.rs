@@ -0,0 +1,285 @@
+//! Keeping drainers and emergency actions out of sandwich targets
+//!
+//! Some pending txs are profitable to sandwich or backrun, but not something the bot should be
+//! seen next to: a drainer cashing in stolen approvals, a third party submitting someone's permit,
+//! a protocol pulling funds in an emergency. [TargetPolicy] flags those before any simulation.
+//! Built in patterns cover the common cases, a policy file adds contracts and selectors to deny
+//! and exempts known operators from the built in patterns.
+
+use std::{collections::HashSet, fs, path::Path};
+
+use ethers::{
+    abi::{self, param_type::Reader, Token},
+    types::{Address, Transaction},
+    utils::{hex, id},
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Calls where funds move on someone else's authority, flagged when the sender isn't the owner
+/// (the `address` parameter at `owner`)
+const THIRD_PARTY_PULLS: &[(&str, usize)] = &[
+    ("transferFrom(address,address,uint256)", 0),
+    (
+        "permit(address,address,uint256,uint256,uint8,bytes32,bytes32)",
+        0,
+    ),
+    // Permit2
+    (
+        "permit(address,((address,uint160,uint48,uint48),address,uint256),bytes)",
+        0,
+    ),
+    (
+        "permitTransferFrom(((address,uint256),uint256,uint256),(address,uint256),address,bytes)",
+        2,
+    ),
+];
+
+/// Functions a protocol only calls when something went wrong
+const EMERGENCY_FUNCTIONS: &[&str] = &[
+    "pause()",
+    "emergencyPause()",
+    "emergencyWithdraw()",
+    "emergencyWithdraw(uint256)",
+    "emergencyExit()",
+    "shutdown()",
+    "kill()",
+    "rescueTokens(address,address,uint256)",
+];
+
+const SET_APPROVAL_FOR_ALL: &str = "setApprovalForAll(address,bool)";
+
+#[derive(Error, Debug)]
+pub enum PolicyError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid selector {0}, expected 4 hex bytes or a function signature")]
+    InvalidSelector(String),
+}
+
+/// Why a tx is excluded from targeting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetFlag {
+    /// calls a function moving funds on behalf of another owner, or grants blanket approval
+    DrainerPattern(&'static str),
+    EmergencyFunction(&'static str),
+    DeniedContract(Address),
+    DeniedSelector([u8; 4]),
+}
+
+/// Policy file, JSON. Selectors are either 4 hex bytes or a function signature.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TargetPolicyConfig {
+    pub deny_contracts: Vec<Address>,
+    pub deny_selectors: Vec<String>,
+    /// contracts the built in patterns don't apply to, e.g. marketplace operators
+    pub allow_contracts: Vec<Address>,
+    pub allow_selectors: Vec<String>,
+    /// whether to apply the built in patterns, on unless turned off
+    pub builtin_patterns: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TargetPolicy {
+    deny_contracts: HashSet<Address>,
+    deny_selectors: HashSet<[u8; 4]>,
+    allow_contracts: HashSet<Address>,
+    allow_selectors: HashSet<[u8; 4]>,
+    builtin_patterns: bool,
+}
+
+impl Default for TargetPolicy {
+    fn default() -> Self {
+        Self {
+            deny_contracts: HashSet::new(),
+            deny_selectors: HashSet::new(),
+            allow_contracts: HashSet::new(),
+            allow_selectors: HashSet::new(),
+            builtin_patterns: true,
+        }
+    }
+}
+
+impl TargetPolicy {
+    pub fn from_config(config: TargetPolicyConfig) -> Result<Self, PolicyError> {
+        let selectors = |selectors: Vec<String>| {
+            selectors
+                .into_iter()
+                .map(|s| parse_selector(&s))
+                .collect::<Result<HashSet<_>, _>>()
+        };
+        Ok(Self {
+            deny_contracts: config.deny_contracts.into_iter().collect(),
+            deny_selectors: selectors(config.deny_selectors)?,
+            allow_contracts: config.allow_contracts.into_iter().collect(),
+            allow_selectors: selectors(config.allow_selectors)?,
+            builtin_patterns: config.builtin_patterns.unwrap_or(true),
+        })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, PolicyError> {
+        let config: TargetPolicyConfig = serde_json::from_str(&fs::read_to_string(path)?)?;
+        Self::from_config(config)
+    }
+
+    /// Why `tx` must not be targeted, `None` if it may. Explicit denials win over allowances,
+    /// allowances over the built in patterns.
+    pub fn check(&self, tx: &Transaction) -> Option<TargetFlag> {
+        let to = tx.to?;
+        if self.deny_contracts.contains(&to) {
+            return Some(TargetFlag::DeniedContract(to));
+        }
+        if tx.input.len() < 4 {
+            return None;
+        }
+        let selector: [u8; 4] = tx.input[..4].try_into().unwrap();
+        if self.deny_selectors.contains(&selector) {
+            return Some(TargetFlag::DeniedSelector(selector));
+        }
+        if !self.builtin_patterns
+            || self.allow_contracts.contains(&to)
+            || self.allow_selectors.contains(&selector)
+        {
+            return None;
+        }
+        builtin_flag(tx, selector)
+    }
+
+    pub fn is_allowed(&self, tx: &Transaction) -> bool {
+        self.check(tx).is_none()
+    }
+}
+
+fn builtin_flag(tx: &Transaction, selector: [u8; 4]) -> Option<TargetFlag> {
+    let args = &tx.input[4..];
+    for &(signature, owner) in THIRD_PARTY_PULLS {
+        if id(signature) != selector {
+            continue;
+        }
+        let owner = decode_params(signature, args)
+            .and_then(|params| params.get(owner).cloned())
+            .and_then(Token::into_address);
+        return match owner {
+            Some(owner) if owner == tx.from => None,
+            _ => Some(TargetFlag::DrainerPattern(signature)),
+        };
+    }
+    if id(SET_APPROVAL_FOR_ALL) == selector {
+        let approved = decode_params(SET_APPROVAL_FOR_ALL, args)
+            .and_then(|params| params.get(1).cloned())
+            .and_then(Token::into_bool);
+        return (approved != Some(false))
+            .then_some(TargetFlag::DrainerPattern(SET_APPROVAL_FOR_ALL));
+    }
+    EMERGENCY_FUNCTIONS
+        .iter()
+        .find(|signature| id(signature) == selector)
+        .map(|signature| TargetFlag::EmergencyFunction(*signature))
+}
+
+pub(crate) fn decode_params(signature: &str, args: &[u8]) -> Option<Vec<Token>> {
+    let params = &signature[signature.find('(')? + 1..signature.len() - 1];
+    // split on the commas outside of tuples
+    let (mut depth, mut start, mut types) = (0, 0, vec![]);
+    for (i, c) in params.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                types.push(Reader::read(&params[start..i]).ok()?);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < params.len() {
+        types.push(Reader::read(&params[start..]).ok()?);
+    }
+    abi::decode(&types, args).ok()
+}
+
+fn parse_selector(selector: &str) -> Result<[u8; 4], PolicyError> {
+    if selector.contains('(') {
+        return Ok(id(selector));
+    }
+    hex::decode(selector.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| PolicyError::InvalidSelector(selector.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::U256;
+
+    fn call(from: Address, to: Address, signature: &str, params: &[Token]) -> Transaction {
+        let mut input = id(signature).to_vec();
+        input.extend(abi::encode(params));
+        Transaction {
+            from,
+            to: Some(to),
+            input: input.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_target_policy() {
+        let (user, drainer, token) = (
+            Address::from_low_u64_be(1),
+            Address::from_low_u64_be(2),
+            Address::from_low_u64_be(3),
+        );
+        let transfer_from = |from: Address| {
+            call(
+                from,
+                token,
+                "transferFrom(address,address,uint256)",
+                &[
+                    Token::Address(user),
+                    Token::Address(drainer),
+                    Token::Uint(U256::one()),
+                ],
+            )
+        };
+        let policy = TargetPolicy::default();
+        assert!(policy.is_allowed(&transfer_from(user)));
+        assert_eq!(
+            policy.check(&transfer_from(drainer)),
+            Some(TargetFlag::DrainerPattern(
+                "transferFrom(address,address,uint256)"
+            ))
+        );
+        let pause = call(drainer, token, "pause()", &[]);
+        assert_eq!(
+            policy.check(&pause),
+            Some(TargetFlag::EmergencyFunction("pause()"))
+        );
+
+        let config: TargetPolicyConfig = serde_json::from_value(serde_json::json!({
+            "deny_selectors": ["0xdeadbeef"],
+            "allow_contracts": [format!("{:?}", token)],
+        }))
+        .unwrap();
+        let policy = TargetPolicy::from_config(config).unwrap();
+        // the operator is allowed, explicit denials still apply
+        assert!(policy.is_allowed(&transfer_from(drainer)));
+        let denied = Transaction {
+            input: vec![0xde, 0xad, 0xbe, 0xef].into(),
+            ..transfer_from(user)
+        };
+        assert_eq!(
+            policy.check(&denied),
+            Some(TargetFlag::DeniedSelector([0xde, 0xad, 0xbe, 0xef]))
+        );
+
+        assert!(matches!(
+            parse_selector("0x1234"),
+            Err(PolicyError::InvalidSelector(_))
+        ));
+    }
+}
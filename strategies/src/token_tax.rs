@@ -0,0 +1,383 @@
+//! Fee-on-transfer detection by round tripping a token through its WETH pair on the fork
+//!
+//! A probe account is funded with WETH in a sandbox over the fork db, buys the token straight from
+//! the V2 pair and sends it back, at several sizes. What arrives compared to what the pair math
+//! promises gives the buy and sell tax, sizes that revert give the transfer limits.
+
+use std::fmt::Debug;
+
+use ethers::{
+    abi::{self, Token},
+    types::{Address, Bytes, U256},
+    utils::id,
+};
+use fork_database::{
+    storage_layout::{mapping_slot, WETH_BALANCE_OF_SLOT},
+    utils::{b160_to_h160, h160_to_b160, u256_to_ru256, RefDb},
+};
+use qilin_cfmms::pool::{Pool, PoolType};
+use qilin_math::{tax::TaxRates, v2::get_amount_out};
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{Env, ExecutionResult, Output, TransactTo, TxEnv, B160},
+    EVM,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::approvals::word;
+
+/// Trade sizes probed, in bps of the pair's WETH reserve
+pub const DEFAULT_PROBE_SIZES_BPS: &[u64] = &[10, 100, 500];
+const BPS: u64 = 10_000;
+const PROBE_GAS: u64 = 1_000_000;
+/// Funds the probe, nobody holds keys to it
+const PROBE: [u8; 20] = [
+    0x7a, 0x70, 0x70, 0x70, 0x70, 0x70, 0x70, 0x70, 0x70, 0x70, 0x70, 0x70, 0x70, 0x70, 0x70, 0x70,
+    0x70, 0x70, 0x70, 0x7a,
+];
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TaxError {
+    #[error("Pool {0:?} is not a V2 pair against WETH")]
+    UnsupportedPool(Address),
+    #[error("Simulation error: {0}")]
+    Simulation(String),
+    #[error("No probe size could be bought")]
+    Untradeable,
+}
+
+/// Measured taxes and limits at one trade size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaxSample {
+    pub weth_in: U256,
+    pub buy_bps: u64,
+    /// `None` if selling the bought amount back reverted
+    pub sell_bps: Option<u64>,
+}
+
+/// Transfer behaviour of a token, as seen through one of its pairs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaxProfile {
+    pub token: Address,
+    pub pool: Address,
+    pub samples: Vec<TaxSample>,
+    /// Largest probed buy that went through, if a bigger one reverted
+    pub max_buy: Option<U256>,
+    /// Largest probed sell that went through, if a bigger one reverted
+    pub max_sell: Option<U256>,
+}
+
+impl TaxProfile {
+    /// Worst buy and sell tax over all sizes, what sizing should assume
+    pub fn rates(&self) -> TaxRates {
+        let buy = self.samples.iter().map(|s| s.buy_bps).max().unwrap_or(0);
+        let sell = self
+            .samples
+            .iter()
+            .map(|s| s.sell_bps.unwrap_or(BPS))
+            .max()
+            .unwrap_or(0);
+        TaxRates::new(buy, sell)
+    }
+
+    pub fn is_taxed(&self) -> bool {
+        self.rates().is_taxed()
+    }
+
+    /// Whether selling is blocked at every probed size, e.g. a honeypot
+    pub fn is_sell_blocked(&self) -> bool {
+        !self.samples.is_empty() && self.samples.iter().all(|s| s.sell_bps.is_none())
+    }
+}
+
+/// Measure the buy and sell tax of `token` by round tripping it through `pool` at
+/// [DEFAULT_PROBE_SIZES_BPS] of the WETH reserve. Nothing is committed to `db`.
+pub fn measure_token_tax<DB>(
+    db: &DB,
+    env: &Env,
+    token: Address,
+    pool: &Pool,
+    weth: Address,
+) -> Result<TaxProfile, TaxError>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    measure_token_tax_at(db, env, token, pool, weth, DEFAULT_PROBE_SIZES_BPS)
+}
+
+/// [measure_token_tax] at custom sizes, in bps of the WETH reserve
+pub fn measure_token_tax_at<DB>(
+    db: &DB,
+    env: &Env,
+    token: Address,
+    pool: &Pool,
+    weth: Address,
+    sizes_bps: &[u64],
+) -> Result<TaxProfile, TaxError>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let weth_is_0 = match pool.pool_type {
+        PoolType::UniswapV2(_) if pool.token_0 == weth && pool.token_1 == token => true,
+        PoolType::UniswapV2(_) if pool.token_1 == weth && pool.token_0 == token => false,
+        _ => return Err(TaxError::UnsupportedPool(pool.address)),
+    };
+
+    let mut profile = TaxProfile {
+        token,
+        pool: pool.address,
+        samples: vec![],
+        max_buy: None,
+        max_sell: None,
+    };
+    let mut buy_reverted = false;
+    let mut sell_reverted = false;
+
+    for size in sizes_bps {
+        let mut probe = Probe::new(db, env, weth, token, pool.address, weth_is_0);
+        let (reserve_weth, reserve_token) = probe.reserves()?;
+        let weth_in = reserve_weth * *size / BPS;
+        if weth_in.is_zero() {
+            continue;
+        }
+
+        let expected = get_amount_out(weth_in, reserve_weth, reserve_token);
+        let Some(bought) = probe.buy(weth_in, expected)? else {
+            buy_reverted = true;
+            continue;
+        };
+        profile.max_buy = Some(weth_in);
+
+        let sell_bps = match probe.sell(bought)? {
+            Some(received) => {
+                profile.max_sell = Some(weth_in);
+                Some(shortfall_bps(bought, received))
+            }
+            None => {
+                sell_reverted = true;
+                None
+            }
+        };
+        profile.samples.push(TaxSample {
+            weth_in,
+            buy_bps: shortfall_bps(expected, bought),
+            sell_bps,
+        });
+    }
+
+    if profile.samples.is_empty() {
+        return Err(TaxError::Untradeable);
+    }
+    // limits only mean something if a size was actually refused
+    if !buy_reverted {
+        profile.max_buy = None;
+    }
+    if !sell_reverted {
+        profile.max_sell = None;
+    }
+    Ok(profile)
+}
+
+/// How much of `expected` didn't arrive, in bps
+fn shortfall_bps(expected: U256, received: U256) -> u64 {
+    if expected.is_zero() || received >= expected {
+        return 0;
+    }
+    ((expected - received) * BPS / expected).as_u64()
+}
+
+/// Probe account trading against the pair in a sandbox
+struct Probe<'a, DB: DatabaseRef> {
+    sandbox: CacheDB<RefDb<'a, DB>>,
+    env: Env,
+    weth: B160,
+    token: B160,
+    pair: B160,
+    weth_is_0: bool,
+}
+
+impl<'a, DB> Probe<'a, DB>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    fn new(
+        db: &'a DB,
+        env: &Env,
+        weth: Address,
+        token: Address,
+        pair: Address,
+        weth_is_0: bool,
+    ) -> Self {
+        let mut env = env.clone();
+        // the probe pays no gas, it only holds WETH
+        env.block.basefee = Default::default();
+        Self {
+            sandbox: CacheDB::new(RefDb(db)),
+            env,
+            weth: h160_to_b160(weth),
+            token: h160_to_b160(token),
+            pair: h160_to_b160(pair),
+            weth_is_0,
+        }
+    }
+
+    fn probe() -> B160 {
+        B160(PROBE)
+    }
+
+    /// `(weth reserve, token reserve)` of the pair
+    fn reserves(&self) -> Result<(U256, U256), TaxError> {
+        let output = self
+            .call(self.pair, id("getReserves()").to_vec())?
+            .ok_or_else(|| TaxError::Simulation("getReserves reverted".to_string()))?;
+        let reserve_0 = word(&output, 0);
+        let reserve_1 = word(&output, 1);
+        Ok(if self.weth_is_0 {
+            (reserve_0, reserve_1)
+        } else {
+            (reserve_1, reserve_0)
+        })
+    }
+
+    fn balance_of(&self, token: B160, owner: B160) -> Result<U256, TaxError> {
+        let data = [
+            id("balanceOf(address)").to_vec(),
+            abi::encode(&[Token::Address(b160_to_h160(owner))]),
+        ]
+        .concat();
+        let output = self
+            .call(token, data)?
+            .ok_or_else(|| TaxError::Simulation("balanceOf reverted".to_string()))?;
+        Ok(word(&output, 0))
+    }
+
+    /// Buy `expected` token for `weth_in` straight from the pair, returns what the probe got
+    fn buy(&mut self, weth_in: U256, expected: U256) -> Result<Option<U256>, TaxError> {
+        let slot = mapping_slot(b160_to_h160(Self::probe()), WETH_BALANCE_OF_SLOT);
+        self.sandbox
+            .insert_account_storage(self.weth, u256_to_ru256(slot), u256_to_ru256(weth_in))
+            .map_err(|e| TaxError::Simulation(format!("{:?}", e)))?;
+
+        if self.transfer(self.weth, self.pair, weth_in)?.is_none() {
+            return Err(TaxError::Simulation("WETH transfer reverted".to_string()));
+        }
+        let (amount_0_out, amount_1_out) = if self.weth_is_0 {
+            (U256::zero(), expected)
+        } else {
+            (expected, U256::zero())
+        };
+        let swap = [
+            id("swap(uint256,uint256,address,bytes)").to_vec(),
+            abi::encode(&[
+                Token::Uint(amount_0_out),
+                Token::Uint(amount_1_out),
+                Token::Address(b160_to_h160(Self::probe())),
+                Token::Bytes(vec![]),
+            ]),
+        ]
+        .concat();
+        if self.transact(self.pair, swap)?.is_none() {
+            return Ok(None);
+        }
+        self.balance_of(self.token, Self::probe()).map(Some)
+    }
+
+    /// Send `amount` token back into the pair, returns what the pair received
+    fn sell(&mut self, amount: U256) -> Result<Option<U256>, TaxError> {
+        let before = self.balance_of(self.token, self.pair)?;
+        if self.transfer(self.token, self.pair, amount)?.is_none() {
+            return Ok(None);
+        }
+        let after = self.balance_of(self.token, self.pair)?;
+        Ok(Some(after.saturating_sub(before)))
+    }
+
+    fn transfer(&mut self, token: B160, to: B160, amount: U256) -> Result<Option<Bytes>, TaxError> {
+        let data = [
+            id("transfer(address,uint256)").to_vec(),
+            abi::encode(&[Token::Address(b160_to_h160(to)), Token::Uint(amount)]),
+        ]
+        .concat();
+        self.transact(token, data)
+    }
+
+    /// Read only call, nothing is committed
+    fn call(&self, to: B160, data: Vec<u8>) -> Result<Option<Bytes>, TaxError> {
+        let mut evm = EVM::new();
+        evm.env = self.env.clone();
+        evm.env.tx = self.tx(to, data);
+        evm.database(RefDb(&self.sandbox));
+        let result = evm
+            .transact_ref()
+            .map_err(|e| TaxError::Simulation(format!("{:?}", e)))?;
+        Ok(call_output(result.result))
+    }
+
+    fn transact(&mut self, to: B160, data: Vec<u8>) -> Result<Option<Bytes>, TaxError> {
+        let mut evm = EVM::new();
+        evm.env = self.env.clone();
+        evm.env.tx = self.tx(to, data);
+        evm.database(&mut self.sandbox);
+        let result = evm
+            .transact_commit()
+            .map_err(|e| TaxError::Simulation(format!("{:?}", e)))?;
+        Ok(call_output(result))
+    }
+
+    fn tx(&self, to: B160, data: Vec<u8>) -> TxEnv {
+        TxEnv {
+            caller: Self::probe(),
+            transact_to: TransactTo::Call(to),
+            data: data.into(),
+            gas_limit: PROBE_GAS,
+            gas_price: self.env.block.basefee,
+            ..Default::default()
+        }
+    }
+}
+
+fn call_output(result: ExecutionResult) -> Option<Bytes> {
+    match result {
+        ExecutionResult::Success {
+            output: Output::Call(output),
+            ..
+        } => Some(output.into()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tax_profile_rates() {
+        let profile = TaxProfile {
+            token: Address::zero(),
+            pool: Address::zero(),
+            samples: vec![
+                TaxSample {
+                    weth_in: U256::from(1),
+                    buy_bps: 300,
+                    sell_bps: Some(500),
+                },
+                TaxSample {
+                    weth_in: U256::from(10),
+                    buy_bps: 310,
+                    sell_bps: Some(490),
+                },
+            ],
+            max_buy: None,
+            max_sell: None,
+        };
+        assert_eq!(profile.rates(), TaxRates::new(310, 500));
+        assert!(!profile.is_sell_blocked());
+
+        assert_eq!(shortfall_bps(U256::from(1_000), U256::from(970)), 300);
+        assert_eq!(shortfall_bps(U256::from(1_000), U256::from(1_001)), 0);
+    }
+}
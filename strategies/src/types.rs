@@ -1,10 +1,16 @@
-use collectors::types::{BlockPayload, NewTx};
+use crate::cow::CowOpportunity;
+use collectors::cow_collector::CowOrder;
+use collectors::pair_discovery::Deployment;
+use collectors::types::{BlockPayload, CancelledTx, MempoolEvent, NewTx};
 
 /// Core Event implementation for the strategies
 #[derive(Debug, Clone)]
 pub enum Event {
     NewBlock(BlockPayload),
     NewMempoolTx(NewTx),
+    CancelledMempoolTx(CancelledTx),
+    NewCowOrder(CowOrder),
+    NewDeployment(Deployment),
 }
 
 impl From<BlockPayload> for Event {
@@ -19,8 +25,32 @@ impl From<NewTx> for Event {
     }
 }
 
+impl From<CancelledTx> for Event {
+    fn from(cancel: CancelledTx) -> Self {
+        Self::CancelledMempoolTx(cancel)
+    }
+}
+
+impl From<MempoolEvent> for Event {
+    fn from(event: MempoolEvent) -> Self {
+        match event {
+            MempoolEvent::NewTx(tx) => Self::NewMempoolTx(tx),
+            MempoolEvent::Cancel(cancel) => Self::CancelledMempoolTx(cancel),
+            MempoolEvent::Deployment(deployment) => Self::NewDeployment(deployment),
+        }
+    }
+}
+
+impl From<CowOrder> for Event {
+    fn from(order: CowOrder) -> Self {
+        Self::NewCowOrder(order)
+    }
+}
+
 /// Core Action implementation for the strategies
 #[derive(Debug, Clone)]
 pub enum Action {
     SubmitTx,
+    /// settle a CoW order through one of our pools
+    SettleCowOrder(CowOpportunity),
 }
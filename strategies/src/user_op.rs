@@ -0,0 +1,285 @@
+//! Simulating ERC-4337 user operations
+//!
+//! A bundler earns the `actualGasCost` the EntryPoint charges each op, paid from the sender's
+//! deposit or, for sponsored ops, the paymaster's, minus the gas of the `handleOps` tx itself.
+//! What's actually charged is only known after execution: the paymaster's `postOp` may change
+//! it, and a reverting `postOp` is retried in `postOpReverted` mode or fails the whole bundle.
+//! [UserOpSimulator] runs `handleOps` over the fork db and reads what happened from the
+//! EntryPoint's events and the beneficiary's balance.
+
+use ethers::{
+    abi::{AbiEncode, RawLog},
+    contract::{abigen, EthError, EthLogDecode},
+    types::{Address, Bytes, H256, I256, U256},
+};
+use fork_database::{
+    inspectors::{Asset, BalanceDeltaInspector},
+    utils::{b160_to_h160, h160_to_b160, ru256_to_u256, u256_to_ru256},
+};
+use revm::{
+    db::{CacheDB, DatabaseRef},
+    primitives::{Env, ExecutionResult, TransactTo, TxEnv},
+    EVM,
+};
+use thiserror::Error;
+
+abigen!(
+    EntryPoint,
+    r#"[
+        struct UserOperation { address sender; uint256 nonce; bytes initCode; bytes callData; uint256 callGasLimit; uint256 verificationGasLimit; uint256 preVerificationGas; uint256 maxFeePerGas; uint256 maxPriorityFeePerGas; bytes paymasterAndData; bytes signature; }
+        function handleOps(UserOperation[] ops, address beneficiary) external
+        event UserOperationEvent(bytes32 indexed userOpHash, address indexed sender, address indexed paymaster, uint256 nonce, bool success, uint256 actualGasCost, uint256 actualGasUsed)
+        event UserOperationRevertReason(bytes32 indexed userOpHash, address indexed sender, uint256 nonce, bytes revertReason)
+        error FailedOp(uint256 opIndex, string reason)
+    ]"#
+);
+
+/// EntryPoint v0.6, at the same address on every chain
+pub const ENTRY_POINT_V06: &str = "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789";
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum UserOpError {
+    /// Validation or a `postOp` failing twice rejects the whole bundle
+    #[error("Op {index} failed: {reason}")]
+    FailedOp { index: usize, reason: String },
+    #[error("handleOps reverted: {0}")]
+    Reverted(Bytes),
+    #[error("handleOps halted: {0}")]
+    Halted(String),
+    #[error("Simulation error: {0}")]
+    Simulation(String),
+}
+
+/// What happened to one op
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserOpOutcome {
+    pub user_op_hash: H256,
+    pub sender: Address,
+    /// set for sponsored ops, the paymaster paid for the gas
+    pub paymaster: Option<Address>,
+    /// whether the op's own call succeeded, it's charged either way
+    pub success: bool,
+    /// what the EntryPoint charged the payer, after `postOp`
+    pub actual_gas_cost: U256,
+    pub actual_gas_used: U256,
+    pub revert_reason: Option<Bytes>,
+}
+
+impl UserOpOutcome {
+    pub fn payer(&self) -> Address {
+        self.paymaster.unwrap_or(self.sender)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandleOpsOutcome {
+    pub ops: Vec<UserOpOutcome>,
+    /// gas of the `handleOps` tx
+    pub gas_used: u64,
+    /// ETH the beneficiary received from the EntryPoint
+    pub revenue: U256,
+    /// what the bundler pays for the `handleOps` tx
+    pub gas_cost: U256,
+}
+
+impl HandleOpsOutcome {
+    /// Bundler profit, negative when the ops don't cover the tx
+    pub fn profit(&self) -> I256 {
+        I256::from_raw(self.revenue) - I256::from_raw(self.gas_cost)
+    }
+
+    /// Share of the revenue paid by paymasters
+    pub fn sponsored_cost(&self) -> U256 {
+        self.ops
+            .iter()
+            .filter(|op| op.paymaster.is_some())
+            .fold(U256::zero(), |sum, op| sum + op.actual_gas_cost)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UserOpSimulator {
+    pub entry_point: Address,
+    /// EOA sending `handleOps`
+    pub bundler: Address,
+    /// receives the ops' gas payments, the bundler by default
+    pub beneficiary: Address,
+}
+
+impl UserOpSimulator {
+    pub fn new(bundler: Address) -> Self {
+        Self {
+            entry_point: ENTRY_POINT_V06.parse().unwrap(),
+            bundler,
+            beneficiary: bundler,
+        }
+    }
+
+    pub fn with_entry_point(mut self, entry_point: Address) -> Self {
+        self.entry_point = entry_point;
+        self
+    }
+
+    pub fn with_beneficiary(mut self, beneficiary: Address) -> Self {
+        self.beneficiary = beneficiary;
+        self
+    }
+
+    /// Run `handleOps(ops)` on top of `db` in the block of `env`, paying `gas_price` per gas.
+    /// Nothing is committed to `db`.
+    pub fn simulate<DB>(
+        &self,
+        db: DB,
+        env: &Env,
+        ops: Vec<UserOperation>,
+        gas_limit: u64,
+        gas_price: U256,
+    ) -> Result<HandleOpsOutcome, UserOpError>
+    where
+        DB: DatabaseRef,
+        DB::Error: std::fmt::Debug,
+    {
+        let data = HandleOpsCall {
+            ops,
+            beneficiary: self.beneficiary,
+        }
+        .encode();
+
+        let mut evm = EVM::new();
+        evm.database(CacheDB::new(db));
+        evm.env = env.clone();
+        evm.env.tx = TxEnv {
+            caller: h160_to_b160(self.bundler),
+            transact_to: TransactTo::Call(h160_to_b160(self.entry_point)),
+            data: data.into(),
+            gas_limit,
+            gas_price: u256_to_ru256(gas_price),
+            // the simulation shouldn't depend on the bundler's nonce
+            nonce: None,
+            ..Default::default()
+        };
+
+        let beneficiary = h160_to_b160(self.beneficiary);
+        let mut inspector = BalanceDeltaInspector::new([beneficiary]);
+        let result = evm
+            .inspect(&mut inspector)
+            .map_err(|e| UserOpError::Simulation(format!("{:?}", e)))?;
+
+        let (gas_used, logs) = match result.result {
+            ExecutionResult::Success { gas_used, logs, .. } => (gas_used, logs),
+            ExecutionResult::Revert { output, .. } => {
+                let output: Bytes = output.into();
+                return Err(match FailedOp::decode_with_selector(&output) {
+                    Some(failed) => UserOpError::FailedOp {
+                        index: failed.op_index.as_usize(),
+                        reason: failed.reason,
+                    },
+                    None => UserOpError::Reverted(output),
+                });
+            }
+            ExecutionResult::Halt { reason, .. } => {
+                return Err(UserOpError::Halted(format!("{:?}", reason)))
+            }
+        };
+
+        let mut outcomes: Vec<UserOpOutcome> = vec![];
+        for log in logs {
+            if b160_to_h160(log.address) != self.entry_point {
+                continue;
+            }
+            let raw = RawLog {
+                topics: log.topics.iter().map(|topic| H256(topic.0)).collect(),
+                data: log.data.to_vec(),
+            };
+            match EntryPointEvents::decode_log(&raw) {
+                // the revert reason is emitted before the op's event
+                Ok(EntryPointEvents::UserOperationRevertReasonFilter(reason)) => {
+                    outcomes.push(UserOpOutcome {
+                        user_op_hash: H256(reason.user_op_hash),
+                        sender: reason.sender,
+                        paymaster: None,
+                        success: false,
+                        actual_gas_cost: U256::zero(),
+                        actual_gas_used: U256::zero(),
+                        revert_reason: Some(reason.revert_reason),
+                    });
+                }
+                Ok(EntryPointEvents::UserOperationEventFilter(event)) => {
+                    let hash = H256(event.user_op_hash);
+                    let index = match outcomes.last() {
+                        Some(last) if last.user_op_hash == hash => outcomes.len() - 1,
+                        _ => {
+                            outcomes.push(UserOpOutcome {
+                                user_op_hash: hash,
+                                sender: event.sender,
+                                paymaster: None,
+                                success: true,
+                                actual_gas_cost: U256::zero(),
+                                actual_gas_used: U256::zero(),
+                                revert_reason: None,
+                            });
+                            outcomes.len() - 1
+                        }
+                    };
+                    let outcome = &mut outcomes[index];
+                    outcome.paymaster = Some(event.paymaster).filter(|p| !p.is_zero());
+                    outcome.success = event.success;
+                    outcome.actual_gas_cost = event.actual_gas_cost;
+                    outcome.actual_gas_used = event.actual_gas_used;
+                }
+                _ => {}
+            }
+        }
+
+        let revenue = inspector.delta(beneficiary, Asset::Eth);
+        let gas_cost = {
+            let price = ru256_to_u256(evm.env.effective_gas_price());
+            price * gas_used
+        };
+        Ok(HandleOpsOutcome {
+            ops: outcomes,
+            gas_used,
+            revenue: if revenue > I256::zero() {
+                revenue.into_raw()
+            } else {
+                U256::zero()
+            },
+            gas_cost,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outcome_accounting() {
+        let paymaster = Address::from_low_u64_be(9);
+        let op = |paymaster: Option<Address>, cost: u64| UserOpOutcome {
+            user_op_hash: H256::zero(),
+            sender: Address::from_low_u64_be(1),
+            paymaster,
+            success: true,
+            actual_gas_cost: U256::from(cost),
+            actual_gas_used: U256::zero(),
+            revert_reason: None,
+        };
+        let outcome = HandleOpsOutcome {
+            ops: vec![op(None, 300), op(Some(paymaster), 500)],
+            gas_used: 100,
+            revenue: U256::from(800),
+            gas_cost: U256::from(1_000),
+        };
+        assert_eq!(outcome.ops[1].payer(), paymaster);
+        assert_eq!(outcome.sponsored_cost(), U256::from(500));
+        assert_eq!(outcome.profit(), I256::from(-200));
+
+        let failed = FailedOp {
+            op_index: U256::from(1),
+            reason: "AA50 postOp revert".into(),
+        };
+        let decoded = FailedOp::decode_with_selector(&failed.encode_with_selector()).unwrap();
+        assert_eq!(decoded.reason, "AA50 postOp revert");
+    }
+}
@@ -0,0 +1,279 @@
+use ethers::{
+    types::{Bytes, Transaction, U256},
+    utils::{hex, id},
+};
+use fork_database::{
+    delegation::DelegationDb,
+    sim_env::unsigned,
+    utils::{h160_to_b160, ru256_to_u256, tx_to_tx_env},
+};
+use revm::{
+    db::DatabaseRef,
+    primitives::{Env, ExecutionResult},
+    EVM,
+};
+use thiserror::Error;
+
+use crate::revert_reason::decode_error_string;
+
+/// Revert strings routers use for a passed deadline
+const DEADLINE_REASONS: &[&str] = &["UniswapV2Router: EXPIRED", "Transaction too old", "EXPIRED"];
+
+/// Why a victim can't be included in a bundle
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum VictimRejection {
+    #[error("Nonce mismatch: tx has {tx}, sender is at {account}")]
+    NonceMismatch { tx: u64, account: u64 },
+    #[error("Insufficient balance: {required} required, {available} available")]
+    InsufficientBalance { required: U256, available: U256 },
+    #[error("Max fee {max_fee} below the block basefee {basefee}")]
+    FeeTooLow { max_fee: U256, basefee: U256 },
+    #[error("Invalid tx: {0}")]
+    Invalid(String),
+    #[error("Deadline expired")]
+    ExpiredDeadline,
+    #[error("Reverted: {0}")]
+    Reverted(String),
+    #[error("Halted: {0}")]
+    Halted(String),
+    #[error("Simulation error: {0}")]
+    Simulation(String),
+}
+
+/// Simulates `victim` standalone on top of `db`, in the block described by `env`, to make sure it
+/// would land before spending a bundle on it. Nothing is committed to `db`.
+///
+/// Cheap checks (nonce, balance, fee) are done upfront so the common stale victim cases don't need
+/// an EVM run, which then runs [unsigned] from the tx's `from` without checking them again. A
+/// sender with code, e.g. a delegated EOA, isn't rejected, and calls into delegated EOAs run the
+/// delegate's code through [DelegationDb].
+///
+/// Returns:
+/// Ok(u64): gas used by the victim
+/// Err(VictimRejection): reason the victim would fail
+pub fn validate_victim<DB>(db: DB, env: &Env, victim: &Transaction) -> Result<u64, VictimRejection>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    let account = db
+        .basic(h160_to_b160(victim.from))
+        .map_err(|e| VictimRejection::Simulation(format!("{:?}", e)))?
+        .unwrap_or_default();
+
+    // fields come straight from the mempool, anything not fitting a u64 can't be a valid tx
+    if victim.nonce > U256::from(u64::MAX) || victim.gas > U256::from(u64::MAX) {
+        return Err(VictimRejection::Invalid(format!(
+            "nonce {} or gas {} out of range",
+            victim.nonce, victim.gas
+        )));
+    }
+    if victim.nonce.low_u64() != account.nonce {
+        return Err(VictimRejection::NonceMismatch {
+            tx: victim.nonce.low_u64(),
+            account: account.nonce,
+        });
+    }
+
+    let max_fee = victim
+        .max_fee_per_gas
+        .or(victim.gas_price)
+        .unwrap_or_default();
+    let basefee = ru256_to_u256(env.block.basefee);
+    if max_fee < basefee {
+        return Err(VictimRejection::FeeTooLow { max_fee, basefee });
+    }
+
+    let required = max_fee
+        .checked_mul(victim.gas)
+        .and_then(|fee| fee.checked_add(victim.value))
+        .ok_or_else(|| VictimRejection::Invalid("max cost overflows".to_string()))?;
+    let available = ru256_to_u256(account.balance);
+    if available < required {
+        return Err(VictimRejection::InsufficientBalance {
+            required,
+            available,
+        });
+    }
+
+    let mut evm = EVM::new();
+    evm.database(DelegationDb::new(db));
+    let mut victim_env = env.clone();
+    victim_env.tx = tx_to_tx_env(victim);
+    evm.env = unsigned(victim_env, h160_to_b160(victim.from));
+
+    let result = evm
+        .transact_ref()
+        .map_err(|e| VictimRejection::Simulation(format!("{:?}", e)))?;
+
+    match result.result {
+        ExecutionResult::Success { gas_used, .. } => Ok(gas_used),
+        ExecutionResult::Revert { output, .. } => Err(classify_revert(&output.into())),
+        ExecutionResult::Halt { reason, .. } => {
+            Err(VictimRejection::Halted(format!("{:?}", reason)))
+        }
+    }
+}
+
+/// Maps a victim's revert data to a [VictimRejection]
+pub fn classify_revert(output: &Bytes) -> VictimRejection {
+    if output.len() >= 4 && output[..4] == id("TransactionDeadlinePassed()") {
+        return VictimRejection::ExpiredDeadline;
+    }
+
+    match decode_error_string(output) {
+        Some(reason) if DEADLINE_REASONS.contains(&reason.as_str()) => {
+            VictimRejection::ExpiredDeadline
+        }
+        Some(reason) => VictimRejection::Reverted(reason),
+        None => VictimRejection::Reverted(format!("0x{}", hex::encode(output))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{abi, types::Address};
+    use revm::{
+        db::{CacheDB, EmptyDB},
+        primitives::{AccountInfo, U256 as rU256},
+    };
+
+    fn revert_with(reason: &str) -> Bytes {
+        let mut data = id("Error(string)").to_vec();
+        data.extend(abi::encode(&[abi::Token::String(reason.into())]));
+        data.into()
+    }
+
+    #[test]
+    fn test_classify_revert() {
+        assert_eq!(
+            classify_revert(&revert_with("UniswapV2Router: EXPIRED")),
+            VictimRejection::ExpiredDeadline
+        );
+        assert_eq!(
+            classify_revert(&id("TransactionDeadlinePassed()").to_vec().into()),
+            VictimRejection::ExpiredDeadline
+        );
+        assert_eq!(
+            classify_revert(&revert_with("UniswapV2Router: INSUFFICIENT_OUTPUT_AMOUNT")),
+            VictimRejection::Reverted("UniswapV2Router: INSUFFICIENT_OUTPUT_AMOUNT".into())
+        );
+        assert_eq!(
+            classify_revert(&vec![0xde, 0xad].into()),
+            VictimRejection::Reverted("0xdead".into())
+        );
+    }
+
+    #[test]
+    fn test_validate_victim() {
+        let from = Address::from_low_u64_be(1);
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            h160_to_b160(from),
+            AccountInfo {
+                balance: rU256::from(10u64.pow(18)),
+                nonce: 3,
+                ..Default::default()
+            },
+        );
+
+        let mut env = Env::default();
+        env.block.basefee = rU256::from(10u64);
+
+        let transfer = Transaction {
+            from,
+            to: Some(Address::from_low_u64_be(2)),
+            nonce: U256::from(3),
+            gas: U256::from(21_000),
+            gas_price: Some(U256::from(10)),
+            value: U256::from(1_000),
+            ..Default::default()
+        };
+        assert_eq!(validate_victim(&db, &env, &transfer), Ok(21_000));
+
+        // a sender with code, EIP-3607 would reject it
+        let delegated = Address::from_low_u64_be(3);
+        db.insert_account_info(
+            h160_to_b160(delegated),
+            AccountInfo {
+                balance: rU256::from(10u64.pow(18)),
+                code: Some(revm::primitives::Bytecode::new_raw(vec![0x00].into())),
+                ..Default::default()
+            },
+        );
+        let from_code = Transaction {
+            from: delegated,
+            nonce: U256::zero(),
+            ..transfer.clone()
+        };
+        assert_eq!(validate_victim(&db, &env, &from_code), Ok(21_000));
+
+        // calling into a delegated EOA runs the delegate's code, not the designator
+        let (wallet, delegate) = (Address::from_low_u64_be(4), Address::from_low_u64_be(5));
+        db.insert_account_info(
+            h160_to_b160(wallet),
+            AccountInfo {
+                code: Some(fork_database::delegation::delegation_code(delegate)),
+                ..Default::default()
+            },
+        );
+        db.insert_account_info(
+            h160_to_b160(delegate),
+            AccountInfo {
+                code: Some(revm::primitives::Bytecode::new_raw(vec![0x00].into())),
+                ..Default::default()
+            },
+        );
+        let to_wallet = Transaction {
+            to: Some(wallet),
+            ..transfer.clone()
+        };
+        assert_eq!(validate_victim(&db, &env, &to_wallet), Ok(21_000));
+
+        let stale = Transaction {
+            nonce: U256::from(2),
+            ..transfer.clone()
+        };
+        assert_eq!(
+            validate_victim(&db, &env, &stale),
+            Err(VictimRejection::NonceMismatch { tx: 2, account: 3 })
+        );
+
+        let underpriced = Transaction {
+            gas_price: Some(U256::from(9)),
+            ..transfer.clone()
+        };
+        assert!(matches!(
+            validate_victim(&db, &env, &underpriced),
+            Err(VictimRejection::FeeTooLow { .. })
+        ));
+
+        let too_large = Transaction {
+            value: U256::exp10(18),
+            ..transfer.clone()
+        };
+        assert!(matches!(
+            validate_victim(&db, &env, &too_large),
+            Err(VictimRejection::InsufficientBalance { .. })
+        ));
+
+        // attacker chosen fields are rejected, not overflowed
+        let overflowing = Transaction {
+            gas_price: Some(U256::MAX),
+            ..transfer.clone()
+        };
+        assert!(matches!(
+            validate_victim(&db, &env, &overflowing),
+            Err(VictimRejection::Invalid(_))
+        ));
+        let huge_nonce = Transaction {
+            nonce: U256::MAX,
+            ..transfer
+        };
+        assert!(matches!(
+            validate_victim(&db, &env, &huge_nonce),
+            Err(VictimRejection::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,46 @@
+//! Permits against tokens deployed on mainnet
+//!
+//! Needs a `WSS_RPC` and an archive `HTTP_RPC`, run with `cargo test --features e2e`.
+#![cfg(feature = "e2e")]
+
+use std::env;
+use std::sync::Arc;
+
+use dotenv::dotenv;
+use ethers::{
+    providers::{Provider, Ws},
+    signers::{LocalWallet, Signer},
+    types::{Address, U256},
+};
+use fork_database::{setup_fork_db, sim_env::SimEnv};
+use strategies::permits::{build_permit, verify_permit, PermitKind};
+
+const USDC: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+const V2_ROUTER: &str = "0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D";
+
+#[tokio::test]
+async fn test_eip2612_permit_is_accepted() -> eyre::Result<()> {
+    dotenv().ok();
+    let provider = Arc::new(Provider::<Ws>::connect(env::var("WSS_RPC")?).await?);
+    let (db, backend) = setup_fork_db(provider, env::var("HTTP_RPC")?).await;
+    let env = SimEnv::next_block(&db)?;
+
+    let wallet: LocalWallet = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+        .parse()
+        .unwrap();
+    let (usdc, router): (Address, Address) = (USDC.parse()?, V2_ROUTER.parse()?);
+    let kind = PermitKind::Eip2612 {
+        value: U256::from(1_000_000),
+        deadline: U256::MAX,
+    };
+
+    let permit = build_permit(&db, &env, &wallet, wallet.address(), usdc, router, kind)?;
+    assert!(verify_permit(&db, &env, &permit)?);
+
+    // a permit signed for another spender grants nothing to this one
+    let mut forged = permit.clone();
+    forged.spender = Address::from_low_u64_be(1);
+    assert!(!verify_permit(&db, &env, &forged)?);
+    assert!(backend.is_healthy());
+    Ok(())
+}